@@ -0,0 +1,486 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal ADB client for serving an OTA to a device in recovery's sideload
+//! mode. This implements the ADB wire protocol directly, so it talks to adbd on
+//! the device instead of going through an ADB server on the host. Simple
+//! commands can also be run on devices that allow shell access.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use num_bigint_dig::{ModInverse, ToBigInt};
+use num_traits::{Pow, ToPrimitive};
+use rsa::{traits::PublicKeyParts, BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+use thiserror::Error;
+
+use crate::stream::{FromReader, ToWriter};
+
+const A_CNXN: u32 = 0x4e584e43;
+const A_AUTH: u32 = 0x48545541;
+const A_OPEN: u32 = 0x4e45504f;
+const A_OKAY: u32 = 0x59414b4f;
+const A_CLSE: u32 = 0x45534c43;
+const A_WRTE: u32 = 0x45545257;
+
+/// Protocol version that no longer requires data checksums. The checksum is
+/// still sent for older devices.
+const A_VERSION: u32 = 0x01000001;
+
+const AUTH_TOKEN: u32 = 1;
+const AUTH_SIGNATURE: u32 = 2;
+const AUTH_RSAPUBLICKEY: u32 = 3;
+
+/// Maximum payload size that we advertise and accept.
+const MAX_PAYLOAD: u32 = 256 * 1024;
+
+/// ADB only supports 2048-bit keys.
+const ADB_KEY_SIZE: usize = 256;
+
+/// Local ID of the one stream that is ever opened.
+const LOCAL_ID: u32 = 1;
+
+/// Block size requested from recovery. This matches `adb sideload`.
+pub const SIDELOAD_BLOCK_SIZE: u64 = 64 * 1024;
+
+const SIDELOAD_SUCCESS: &[u8; 8] = b"DONEDONE";
+const SIDELOAD_FAILURE: &[u8; 8] = b"FAILFAIL";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid magic for command {0:#010x}: {1:#010x}")]
+    InvalidMagic(u32, u32),
+    #[error("Message payload too large: {0} bytes")]
+    PayloadTooLarge(u32),
+    #[error("Unexpected command: {0:#010x}")]
+    UnexpectedCommand(u32),
+    #[error("Device requires authentication, but no key was provided")]
+    AuthRequired,
+    #[error("Device rejected the ADB key")]
+    AuthRejected,
+    #[error("Unsupported key size for ADB: {0} bits (must be 2048 bits)")]
+    UnsupportedKeySize(usize),
+    #[error("Device refused to open service: {0:?}")]
+    ServiceRejected(String),
+    #[error("Device closed the stream")]
+    StreamClosed,
+    #[error("Invalid sideload block request: {0:?}")]
+    InvalidBlockRequest([u8; 8]),
+    #[error("Sideload block #{0} is out of bounds")]
+    BlockOutOfBounds(u64),
+    #[error("Device failed to install the sideloaded package")]
+    SideloadFailed,
+    #[error("RSA error")]
+    Rsa(#[from] rsa::Error),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A single ADB protocol message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub command: u32,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(command: u32, arg0: u32, arg1: u32, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            command,
+            arg0,
+            arg1,
+            data: data.into(),
+        }
+    }
+}
+
+impl<R: Read> FromReader<R> for Message {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let command = reader.read_u32::<LittleEndian>()?;
+        let arg0 = reader.read_u32::<LittleEndian>()?;
+        let arg1 = reader.read_u32::<LittleEndian>()?;
+        let data_length = reader.read_u32::<LittleEndian>()?;
+        let _data_check = reader.read_u32::<LittleEndian>()?;
+        let magic = reader.read_u32::<LittleEndian>()?;
+
+        if magic != !command {
+            return Err(Error::InvalidMagic(command, magic));
+        } else if data_length > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLarge(data_length));
+        }
+
+        let mut data = vec![0u8; data_length as usize];
+        reader.read_exact(&mut data)?;
+
+        Ok(Self {
+            command,
+            arg0,
+            arg1,
+            data,
+        })
+    }
+}
+
+impl<W: Write> ToWriter<W> for Message {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        let data_length = self
+            .data
+            .len()
+            .to_u32()
+            .filter(|&n| n <= MAX_PAYLOAD)
+            .ok_or(Error::PayloadTooLarge(u32::MAX))?;
+        let data_check = self
+            .data
+            .iter()
+            .fold(0u32, |sum, &b| sum.wrapping_add(b.into()));
+
+        // Write the header in one go since some transports are message based.
+        let mut header = Vec::with_capacity(24);
+        header.write_u32::<LittleEndian>(self.command)?;
+        header.write_u32::<LittleEndian>(self.arg0)?;
+        header.write_u32::<LittleEndian>(self.arg1)?;
+        header.write_u32::<LittleEndian>(data_length)?;
+        header.write_u32::<LittleEndian>(data_check)?;
+        header.write_u32::<LittleEndian>(!self.command)?;
+
+        writer.write_all(&header)?;
+        writer.write_all(&self.data)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Encode a public key in the format used by `~/.android/adbkey.pub`.
+pub fn encode_public_key(key: &RsaPublicKey) -> Result<String> {
+    if key.size() != ADB_KEY_SIZE {
+        return Err(Error::UnsupportedKeySize(key.size() * 8));
+    }
+
+    // This is the same precomputation as AVB public keys, but in little
+    // endian.
+    //   n0inv = -1 / n[0] (mod 2 ^ 32)
+    //   rr = (2 ^ (key size in bits)) ^ 2 (mod N)
+    let b = BigUint::from(2u64.pow(32));
+    let n0inv = b.to_bigint().unwrap() - key.n().mod_inverse(&b).unwrap();
+    let r = BigUint::from(2u32).pow(key.n().bits());
+    let rrmodn = r.modpow(&BigUint::from(2u32), key.n());
+
+    let mut data = vec![];
+    data.write_u32::<LittleEndian>((ADB_KEY_SIZE / 4) as u32)?;
+    data.write_u32::<LittleEndian>(n0inv.to_u32().unwrap())?;
+
+    for value in [key.n(), &rrmodn] {
+        let mut raw = value.to_bytes_le();
+        raw.resize(ADB_KEY_SIZE, 0);
+        data.extend_from_slice(&raw);
+    }
+
+    data.write_u32::<LittleEndian>(key.e().to_u32().unwrap_or(0))?;
+
+    Ok(format!("{} avbroot", STANDARD.encode(data)))
+}
+
+/// Connection to adbd on a device.
+pub struct AdbConnection<S: Read + Write> {
+    inner: S,
+    max_payload: usize,
+    banner: String,
+}
+
+impl<S: Read + Write> AdbConnection<S> {
+    /// Perform the connection handshake. If the device requires
+    /// authentication, the token is signed with `key`. If the device does not
+    /// recognize the key, the public key is sent so that the user can accept it
+    /// on the device.
+    pub fn connect(mut inner: S, key: Option<&RsaPrivateKey>) -> Result<Self> {
+        Message::new(A_CNXN, A_VERSION, MAX_PAYLOAD, b"host::\0".as_slice())
+            .to_writer(&mut inner)?;
+
+        let mut sent_signature = false;
+        let mut sent_public_key = false;
+
+        loop {
+            let message = Message::from_reader(&mut inner)?;
+
+            match message.command {
+                A_CNXN => {
+                    let banner = String::from_utf8_lossy(&message.data);
+
+                    return Ok(Self {
+                        inner,
+                        max_payload: message.arg1.clamp(1, MAX_PAYLOAD) as usize,
+                        banner: banner.trim_end_matches('\0').to_owned(),
+                    });
+                }
+                A_AUTH if message.arg0 == AUTH_TOKEN => {
+                    let key = key.ok_or(Error::AuthRequired)?;
+
+                    let response = if !sent_signature {
+                        sent_signature = true;
+
+                        // adbd verifies the token as if it were a SHA-1 digest.
+                        let scheme = Pkcs1v15Sign::new::<Sha1>();
+                        let signature = key.sign(scheme, &message.data)?;
+
+                        Message::new(A_AUTH, AUTH_SIGNATURE, 0, signature)
+                    } else if !sent_public_key {
+                        sent_public_key = true;
+
+                        let mut public_key = encode_public_key(&key.to_public_key())?;
+                        public_key.push('\0');
+
+                        Message::new(A_AUTH, AUTH_RSAPUBLICKEY, 0, public_key)
+                    } else {
+                        return Err(Error::AuthRejected);
+                    };
+
+                    response.to_writer(&mut inner)?;
+                }
+                c => return Err(Error::UnexpectedCommand(c)),
+            }
+        }
+    }
+
+    /// Banner sent by the device, eg. `sideload::` in sideload mode.
+    pub fn banner(&self) -> &str {
+        &self.banner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn send(&mut self, message: &Message) -> Result<()> {
+        message.to_writer(&mut self.inner)
+    }
+
+    fn recv(&mut self) -> Result<Message> {
+        Message::from_reader(&mut self.inner)
+    }
+
+    /// Open a stream to a service on the device. Only one stream can be open
+    /// at a time.
+    pub fn open(&mut self, service: &str) -> Result<AdbStream<'_, S>> {
+        let mut data = service.as_bytes().to_vec();
+        data.push(b'\0');
+        self.send(&Message::new(A_OPEN, LOCAL_ID, 0, data))?;
+
+        loop {
+            let message = self.recv()?;
+
+            match message.command {
+                A_OKAY if message.arg1 == LOCAL_ID => {
+                    return Ok(AdbStream {
+                        conn: self,
+                        remote_id: message.arg0,
+                        buf: vec![],
+                        buf_pos: 0,
+                        closed: false,
+                    });
+                }
+                A_CLSE => return Err(Error::ServiceRejected(service.to_owned())),
+                c => return Err(Error::UnexpectedCommand(c)),
+            }
+        }
+    }
+}
+
+/// A stream to a service on the device. Writes block until the device
+/// acknowledges the data.
+///
+/// Errors relating to the protocol are returned as [`io::Error`] with
+/// [`io::ErrorKind::InvalidData`] and wrap an [`Error`].
+pub struct AdbStream<'a, S: Read + Write> {
+    conn: &'a mut AdbConnection<S>,
+    remote_id: u32,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    closed: bool,
+}
+
+impl<S: Read + Write> AdbStream<'_, S> {
+    /// Handle a message for this stream. Returns true if it was an `OKAY`.
+    fn handle(&mut self, message: Message) -> Result<bool> {
+        match message.command {
+            A_OKAY => Ok(true),
+            A_WRTE => {
+                if self.buf_pos == self.buf.len() {
+                    self.buf.clear();
+                    self.buf_pos = 0;
+                }
+                self.buf.extend_from_slice(&message.data);
+
+                self.conn
+                    .send(&Message::new(A_OKAY, LOCAL_ID, self.remote_id, vec![]))?;
+
+                Ok(false)
+            }
+            A_CLSE => {
+                self.closed = true;
+                Ok(false)
+            }
+            c => Err(Error::UnexpectedCommand(c)),
+        }
+    }
+
+    fn read_internal(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.buf_pos == self.buf.len() && !self.closed {
+            let message = self.conn.recv()?;
+            self.handle(message)?;
+        }
+
+        let n = buf.len().min(self.buf.len() - self.buf_pos);
+        buf[..n].copy_from_slice(&self.buf[self.buf_pos..][..n]);
+        self.buf_pos += n;
+
+        Ok(n)
+    }
+
+    fn write_internal(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.closed {
+            return Err(Error::StreamClosed);
+        }
+
+        let n = buf.len().min(self.conn.max_payload);
+        let message = Message::new(A_WRTE, LOCAL_ID, self.remote_id, &buf[..n]);
+        self.conn.send(&message)?;
+
+        // The device may send more data before acknowledging ours.
+        loop {
+            let message = self.conn.recv()?;
+            if self.handle(message)? {
+                break;
+            } else if self.closed {
+                return Err(Error::StreamClosed);
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl<S: Read + Write> Read for AdbStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_internal(buf).map_err(to_io_error)
+    }
+}
+
+impl<S: Read + Write> Write for AdbStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_internal(buf).map_err(to_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serve `size` bytes from `reader` to a device in sideload mode. Recovery
+/// requests [`SIDELOAD_BLOCK_SIZE`] blocks by index, possibly more than once and
+/// in any order, so only the requested blocks are read. `progress` is called
+/// with the number of distinct blocks sent and the total number of blocks.
+///
+/// This returns successfully only if the device reports that it installed the
+/// package.
+pub fn sideload<S: Read + Write>(
+    conn: &mut AdbConnection<S>,
+    mut reader: impl Read + Seek,
+    size: u64,
+    mut progress: impl FnMut(u64, u64),
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let service = format!("sideload-host:{size}:{SIDELOAD_BLOCK_SIZE}");
+    let mut stream = conn.open(&service)?;
+
+    let num_blocks = size.div_ceil(SIDELOAD_BLOCK_SIZE);
+    let mut sent = vec![false; num_blocks as usize];
+    let mut num_sent = 0;
+    let mut buf = vec![0u8; SIDELOAD_BLOCK_SIZE as usize];
+
+    loop {
+        if cancel_signal.load(Ordering::SeqCst) {
+            return Err(
+                io::Error::new(io::ErrorKind::Interrupted, "Received cancel signal").into(),
+            );
+        }
+
+        let mut request = [0u8; 8];
+        stream.read_exact(&mut request)?;
+
+        if &request == SIDELOAD_SUCCESS {
+            return Ok(());
+        } else if &request == SIDELOAD_FAILURE {
+            return Err(Error::SideloadFailed);
+        }
+
+        let block = std::str::from_utf8(&request)
+            .ok()
+            .and_then(|s| s.trim_end_matches('\0').parse::<u64>().ok())
+            .ok_or(Error::InvalidBlockRequest(request))?;
+        if block >= num_blocks {
+            return Err(Error::BlockOutOfBounds(block));
+        }
+
+        let offset = block * SIDELOAD_BLOCK_SIZE;
+        let to_send = (size - offset).min(SIDELOAD_BLOCK_SIZE) as usize;
+
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut buf[..to_send])?;
+        stream.write_all(&buf[..to_send])?;
+
+        if !sent[block as usize] {
+            sent[block as usize] = true;
+            num_sent += 1;
+            progress(num_sent, num_blocks);
+        }
+    }
+}
+
+/// Run a command on the device with the `exec:` service. Unlike `shell:`, no
+/// pty is allocated, so binary data passes through unmodified. `input` is
+/// written to the command's stdin, but the protocol cannot signal EOF without
+/// closing the stream, so the command must stop reading on its own, eg. with
+/// `dd count=<n>`. The output is returned after the command exits. The exit
+/// status is not reported by the service.
+///
+/// This requires adbd to allow shell access, which is not the case in stock
+/// recovery's sideload mode.
+pub fn exec<S: Read + Write>(
+    conn: &mut AdbConnection<S>,
+    command: &str,
+    input: &[u8],
+) -> Result<Vec<u8>> {
+    let mut stream = conn.open(&format!("exec:{command}"))?;
+    stream.write_all(input)?;
+
+    let mut output = vec![];
+    stream.read_to_end(&mut output)?;
+
+    Ok(output)
+}
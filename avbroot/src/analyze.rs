@@ -0,0 +1,300 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Reports for how well the partitions in an OTA are compressed. This is meant
+//! to help decide whether recompressing an image with a different format is
+//! worthwhile.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Cursor, Read, Seek},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use bzip2::read::BzDecoder;
+use serde::Serialize;
+use thiserror::Error;
+use xz2::read::XzDecoder;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    format::{
+        bootimage::BootImage,
+        compression::{self, CompressedFormat, CompressedReader},
+        ota,
+        payload::{self, PayloadHeader},
+    },
+    protobuf::chromeos_update_engine::{mod_InstallOperation::Type, PartitionUpdate},
+    stream::{FromReader, SectionReader},
+};
+
+/// Partitions that are parsed as boot images to report on their ramdisks.
+const BOOT_PARTITIONS: &[&str] = &[
+    "boot",
+    "init_boot",
+    "recovery",
+    "vendor_boot",
+    "vendor_kernel_boot",
+];
+
+/// Formats that sizes are estimated for.
+const ESTIMATE_FORMATS: &[CompressedFormat] = &[
+    CompressedFormat::Gzip,
+    CompressedFormat::Lz4Legacy,
+    CompressedFormat::Xz,
+];
+
+/// Maximum number of operations per partition that are decompressed to sample
+/// the partition data.
+const SAMPLE_OPERATIONS: usize = 8;
+
+/// Maximum number of bytes sampled from each operation.
+const SAMPLE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unsupported operation type in partition {0:?}: {1:?}")]
+    UnsupportedOperation(String, Type),
+    #[error("Missing field in partition {0:?}: {1}")]
+    MissingField(String, &'static str),
+    #[error("Compression error")]
+    Compression(#[from] compression::Error),
+    #[error("Payload error")]
+    Payload(#[from] payload::Error),
+    #[error("Zip error")]
+    Zip(#[from] ZipError),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SizeEstimate {
+    pub format: CompressedFormat,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RamdiskCompressionReport {
+    /// Index of the ramdisk within the boot image.
+    pub index: usize,
+    /// Current compression format. This is [`CompressedFormat::None`] if the
+    /// format is not recognized.
+    pub format: CompressedFormat,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// These are exact because ramdisks are always compressed in full.
+    pub estimates: Vec<SizeEstimate>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PartitionCompressionReport {
+    pub name: String,
+    /// Size of the partition image.
+    pub size: u64,
+    /// Total size of the partition's operation data in the payload.
+    pub payload_size: u64,
+    /// Sorted list of the partition's operation types, eg. `REPLACE_XZ`.
+    pub operation_types: Vec<String>,
+    /// Estimates for the total payload size of the partition. These are
+    /// extrapolated from a sample of the partition's operations.
+    pub estimates: Vec<SizeEstimate>,
+    /// Only populated for partitions that contain a boot image.
+    pub ramdisks: Vec<RamdiskCompressionReport>,
+}
+
+fn estimate_all(data: &[u8]) -> Result<Vec<SizeEstimate>> {
+    ESTIMATE_FORMATS
+        .iter()
+        .map(|&format| -> Result<SizeEstimate> {
+            let size = compression::estimate_size(data, format)?;
+            Ok(SizeEstimate { format, size })
+        })
+        .collect()
+}
+
+/// Read up to [`SAMPLE_SIZE`] bytes of the decompressed data from an operation.
+fn sample_operation(
+    mut reader: impl Read + Seek,
+    header: &PayloadHeader,
+    partition: &PartitionUpdate,
+    index: usize,
+) -> Result<Vec<u8>> {
+    let op = &partition.operations[index];
+    let missing = |field| Error::MissingField(partition.partition_name.clone(), field);
+
+    let data_offset = op.data_offset.ok_or_else(|| missing("data_offset"))?;
+    let data_length = op.data_length.ok_or_else(|| missing("data_length"))?;
+
+    let raw_reader = SectionReader::new(reader, header.blob_offset + data_offset, data_length)?;
+
+    let mut data = vec![];
+
+    match op.type_pb {
+        Type::REPLACE => raw_reader.take(SAMPLE_SIZE).read_to_end(&mut data)?,
+        Type::REPLACE_BZ => BzDecoder::new(raw_reader)
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut data)?,
+        Type::REPLACE_XZ => XzDecoder::new(raw_reader)
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut data)?,
+        t => {
+            return Err(Error::UnsupportedOperation(
+                partition.partition_name.clone(),
+                t,
+            ))
+        }
+    };
+
+    Ok(data)
+}
+
+/// Estimate the payload size of a partition in each format. Operations without
+/// data (ZERO and DISCARD) are assumed to cost nothing.
+fn estimate_partition(
+    mut reader: impl Read + Seek,
+    header: &PayloadHeader,
+    partition: &PartitionUpdate,
+) -> Result<Vec<SizeEstimate>> {
+    let block_size = u64::from(header.manifest.block_size);
+    let data_ops = partition
+        .operations
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| op.data_length.is_some())
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let data_size = data_ops
+        .iter()
+        .flat_map(|&i| &partition.operations[i].dst_extents)
+        .map(|e| e.num_blocks.unwrap_or(0) * block_size)
+        .sum::<u64>();
+
+    let step = (data_ops.len() / SAMPLE_OPERATIONS).max(1);
+    let mut sample = vec![];
+
+    for &i in data_ops.iter().step_by(step).take(SAMPLE_OPERATIONS) {
+        sample.extend(sample_operation(&mut reader, header, partition, i)?);
+    }
+
+    let mut estimates = estimate_all(&sample)?;
+
+    if !sample.is_empty() {
+        for estimate in &mut estimates {
+            let size = u128::from(estimate.size) * u128::from(data_size) / sample.len() as u128;
+            estimate.size = size as u64;
+        }
+    }
+
+    Ok(estimates)
+}
+
+fn report_ramdisks(data: &[u8]) -> Result<Vec<RamdiskCompressionReport>> {
+    let Ok(boot_image) = BootImage::from_reader(Cursor::new(data)) else {
+        return Ok(vec![]);
+    };
+
+    let ramdisks = match &boot_image {
+        BootImage::V0Through2(b) => vec![&b.ramdisk],
+        BootImage::V3Through4(b) => vec![&b.ramdisk],
+        BootImage::VendorV3Through4(b) => b.ramdisks.iter().collect(),
+    };
+
+    ramdisks
+        .into_iter()
+        .enumerate()
+        .filter(|(_, r)| !r.is_empty())
+        .map(|(index, ramdisk)| -> Result<RamdiskCompressionReport> {
+            let mut reader = CompressedReader::new(Cursor::new(ramdisk), true)?;
+            let mut uncompressed = vec![];
+            reader.read_to_end(&mut uncompressed)?;
+
+            Ok(RamdiskCompressionReport {
+                index,
+                format: reader.format(),
+                compressed_size: ramdisk.len() as u64,
+                uncompressed_size: uncompressed.len() as u64,
+                estimates: estimate_all(&uncompressed)?,
+            })
+        })
+        .collect()
+}
+
+/// Report how well each partition in an OTA zip is compressed, sorted by
+/// partition name. Ramdisks are only inspected for the partitions listed in
+/// [`BOOT_PARTITIONS`].
+pub fn compression_report(
+    mut reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<PartitionCompressionReport>> {
+    let (payload_offset, payload_size) = {
+        let mut zip = ZipArchive::new(&mut reader)?;
+        let entry = zip.by_name(ota::PATH_PAYLOAD)?;
+        (entry.data_start(), entry.size())
+    };
+
+    let mut payload_reader = SectionReader::new(reader, payload_offset, payload_size)?;
+    let header = PayloadHeader::from_reader(&mut payload_reader)?;
+
+    let mut partitions = header.manifest.partitions.iter().collect::<Vec<_>>();
+    partitions.sort_by(|a, b| a.partition_name.cmp(&b.partition_name));
+
+    let mut reports = vec![];
+
+    for partition in partitions {
+        if cancel_signal.load(Ordering::SeqCst) {
+            return Err(
+                io::Error::new(io::ErrorKind::Interrupted, "Received cancel signal").into(),
+            );
+        }
+
+        let name = &partition.partition_name;
+        let size = payload::partition_size(partition, header.manifest.block_size)?;
+        let operation_types = partition
+            .operations
+            .iter()
+            .map(|op| format!("{:?}", op.type_pb))
+            .collect::<BTreeSet<_>>();
+
+        let ramdisks = if BOOT_PARTITIONS.contains(&name.as_str()) {
+            let mut writer = Cursor::new(Vec::new());
+
+            for op in &partition.operations {
+                payload::apply_operation(
+                    &mut payload_reader,
+                    &mut writer,
+                    header.manifest.block_size,
+                    header.blob_offset,
+                    op,
+                    true,
+                    cancel_signal,
+                )?;
+            }
+
+            report_ramdisks(writer.get_ref())?
+        } else {
+            vec![]
+        };
+
+        reports.push(PartitionCompressionReport {
+            name: name.clone(),
+            size,
+            payload_size: partition
+                .operations
+                .iter()
+                .filter_map(|op| op.data_length)
+                .sum(),
+            operation_types: operation_types.into_iter().collect(),
+            estimates: estimate_partition(&mut payload_reader, &header, partition)?,
+            ramdisks,
+        });
+    }
+
+    Ok(reports)
+}
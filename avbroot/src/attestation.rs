@@ -0,0 +1,178 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Values that a device reports through remote attestation after installing an
+//! OTA. These can be computed ahead of time to allowlist a build before it is
+//! rolled out.
+
+use std::{
+    io::{self, Cursor, Read, Seek},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use ring::digest::{Context, Digest};
+use thiserror::Error;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    format::{
+        avb::{self, Descriptor, Header},
+        ota,
+        payload::{self, PayloadHeader},
+    },
+    stream::{FromReader, SectionReader, ToWriter},
+};
+
+/// Name of the partition containing the top-level vbmeta image.
+const ROOT_PARTITION: &str = "vbmeta";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Partition not found in payload: {0:?}")]
+    MissingPartition(String),
+    #[error("Chained partition {0:?} has a chain partition descriptor for {1:?}")]
+    NestedChainPartition(String, String),
+    #[error("Expected vbmeta digest {0}, but have {1}")]
+    VbmetaDigestMismatch(String, String),
+    #[error("AVB error")]
+    Avb(#[from] avb::Error),
+    #[error("Payload error")]
+    Payload(#[from] payload::Error),
+    #[error("Zip error")]
+    Zip(#[from] ZipError),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Read a partition image from the payload into memory. Only full OTAs are
+/// supported because the operations are applied to an empty image.
+fn extract_partition(
+    mut reader: impl Read + Seek,
+    header: &PayloadHeader,
+    name: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<u8>> {
+    let partition = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == name)
+        .ok_or_else(|| Error::MissingPartition(name.to_owned()))?;
+
+    let mut writer = Cursor::new(Vec::new());
+
+    for op in &partition.operations {
+        payload::apply_operation(
+            &mut reader,
+            &mut writer,
+            header.manifest.block_size,
+            header.blob_offset,
+            op,
+            true,
+            cancel_signal,
+        )?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+/// Get the raw vbmeta header, authentication block, and auxiliary block from a
+/// vbmeta partition image or an image with an appended vbmeta footer. Any
+/// padding after the auxiliary block is excluded, just like libavb.
+fn raw_vbmeta(data: &[u8]) -> Result<(Header, &[u8])> {
+    let mut reader = Cursor::new(data);
+    let (header, footer, _) = avb::load_image(&mut reader)?;
+
+    // The header parser rejects block sizes that don't match the fields, so
+    // the re-serialized size is always the same as the original size.
+    let mut size_writer = Cursor::new(Vec::new());
+    header.to_writer(&mut size_writer)?;
+    let size = size_writer.into_inner().len();
+
+    let start = footer.map_or(0, |f| f.vbmeta_offset) as usize;
+    let raw = data
+        .get(start..start + size)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+    Ok((header, raw))
+}
+
+/// Compute the vbmeta digest that a device reports after booting the OTA, ie.
+/// the `ro.boot.vbmeta.digest` property and the `verifiedBootHash` field in
+/// key attestation records. This is the SHA-256 digest of the top-level vbmeta
+/// image followed by the vbmeta image of each chained partition, in descriptor
+/// order, which is the same as `avbtool calculate_vbmeta_digest`.
+///
+/// The OTA must be a full OTA that contains the `vbmeta` partition and every
+/// partition that it chains to.
+pub fn expected_vbmeta_digest(
+    mut reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Digest> {
+    let (payload_offset, payload_size) = {
+        let mut zip = ZipArchive::new(&mut reader)?;
+        let entry = zip.by_name(ota::PATH_PAYLOAD)?;
+        (entry.data_start(), entry.size())
+    };
+
+    let mut payload_reader = SectionReader::new(reader, payload_offset, payload_size)?;
+    let header = PayloadHeader::from_reader(&mut payload_reader)?;
+
+    let root_data = extract_partition(&mut payload_reader, &header, ROOT_PARTITION, cancel_signal)?;
+    let (root_header, root_raw) = raw_vbmeta(&root_data)?;
+
+    let mut context = Context::new(&ring::digest::SHA256);
+    context.update(root_raw);
+
+    for descriptor in &root_header.descriptors {
+        let Descriptor::ChainPartition(d) = descriptor else {
+            continue;
+        };
+
+        let data = extract_partition(
+            &mut payload_reader,
+            &header,
+            &d.partition_name,
+            cancel_signal,
+        )?;
+        let (chained_header, chained_raw) = raw_vbmeta(&data)?;
+
+        // libavb refuses to boot if a chained partition chains further.
+        if let Some(nested) = chained_header.descriptors.iter().find_map(|d| match d {
+            Descriptor::ChainPartition(c) => Some(&c.partition_name),
+            _ => None,
+        }) {
+            return Err(Error::NestedChainPartition(
+                d.partition_name.clone(),
+                nested.clone(),
+            ));
+        }
+
+        context.update(chained_raw);
+    }
+
+    Ok(context.finish())
+}
+
+/// Check that the vbmeta digest from [`expected_vbmeta_digest()`] matches
+/// `expected`, like a value that was allowlisted before the OTA was built.
+pub fn verify_vbmeta_digest(
+    reader: impl Read + Seek,
+    expected: &[u8],
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let digest = expected_vbmeta_digest(reader, cancel_signal)?;
+
+    if digest.as_ref() != expected {
+        return Err(Error::VbmetaDigestMismatch(
+            hex::encode(expected),
+            hex::encode(digest),
+        ));
+    }
+
+    Ok(())
+}
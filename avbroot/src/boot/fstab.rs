@@ -0,0 +1,94 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Editing of the fstab files in a ramdisk.
+
+use crate::format::cpio::CpioEntryNew;
+
+use super::{load_ramdisk, save_ramdisk, Result, TextFile};
+
+/// Apply `transform` to the contents of every `fstab.*` file in a ramdisk. The
+/// transform receives the whole file and may operate on individual lines if
+/// needed. Lines keep their original line endings, so the transform should
+/// preserve any trailing `\r`. Files are written back in their original
+/// encoding, which may be UTF-8 or UTF-16 with a byte order mark. Only the file
+/// contents are changed, so the mode and ownership of the entries are
+/// preserved. The ramdisk is recompressed in its original format.
+pub fn patch_fstab(data: &[u8], transform: impl Fn(&str) -> String) -> Result<Vec<u8>> {
+    let (mut entries, format) = load_ramdisk(data, None)?;
+
+    patch_fstab_entries(&mut entries, transform)?;
+
+    save_ramdisk(&entries, format)
+}
+
+/// Same as [`patch_fstab()`], but for an already loaded ramdisk.
+pub(super) fn patch_fstab_entries(
+    entries: &mut [CpioEntryNew],
+    transform: impl Fn(&str) -> String,
+) -> Result<()> {
+    for entry in entries {
+        let file_name = entry.name.rsplit(|&c| c == b'/').next().unwrap_or_default();
+        if !entry.is_file() || !file_name.starts_with(b"fstab.") {
+            continue;
+        }
+
+        let mut text = TextFile::decode(&entry.content, "fstab", &entry.name)?;
+        text.content = transform(&text.content);
+
+        entry.content = text.encode();
+    }
+
+    Ok(())
+}
+
+/// Apply `transform` to every fs_mgr flag (the fifth field) of each entry in
+/// an fstab file. Flags for which `transform` returns [`None`] are removed.
+/// Comments, the whitespace between fields, and line endings are left as is.
+pub(super) fn patch_fstab_flags(
+    content: &str,
+    transform: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut result = String::with_capacity(content.len());
+
+    for line in content.split_inclusive('\n') {
+        let mut fields = vec![];
+        let mut start = None;
+
+        for (i, c) in line.char_indices() {
+            if c.is_whitespace() {
+                if let Some(s) = start.take() {
+                    fields.push(s..i);
+                }
+            } else if start.is_none() {
+                start = Some(i);
+            }
+        }
+        if let Some(s) = start {
+            fields.push(s..line.len());
+        }
+
+        let is_comment = line.trim_start().starts_with('#');
+        let Some(range) = fields.get(4).filter(|_| !is_comment) else {
+            result.push_str(line);
+            continue;
+        };
+
+        let flags = line[range.clone()]
+            .split(',')
+            .filter_map(&transform)
+            .collect::<Vec<_>>();
+
+        result.push_str(&line[..range.start]);
+        if flags.is_empty() {
+            result.push_str("defaults");
+        } else {
+            result.push_str(&flags.join(","));
+        }
+        result.push_str(&line[range.end..]);
+    }
+
+    result
+}
@@ -0,0 +1,1819 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+pub mod fstab;
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read, Seek, Write},
+    iter,
+    num::ParseIntError,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use flate2::read::GzDecoder;
+use regex::bytes::Regex;
+use ring::digest::Context;
+use rsa::RsaPrivateKey;
+use thiserror::Error;
+use x509_cert::Certificate;
+use xz2::{
+    stream::{Check, Stream},
+    write::XzEncoder,
+};
+use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    crypto,
+    format::{
+        avb::{self, Descriptor},
+        bootimage::{self, BootImage, BootImageExt, RamdiskMeta, VendorBootImageV3Through4},
+        compression::{self, CompressedFormat, CompressedReader, CompressedWriter},
+        cpio::{self, CpioEntryNew},
+        dtbo::{self, DtboImage},
+    },
+    stream::{self, CountingWriter, FromReader, HashingWriter, SectionReader, ToWriter},
+    util::EscapedString,
+    warning::{Severity, WarningCode, WarningCollector},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Boot image has no vbmeta footer")]
+    NoFooter,
+    #[error("No hash descriptor found in vbmeta footer")]
+    NoHashDescriptor,
+    #[error("Found multiple hash descriptors in vbmeta footer")]
+    MultipleHashDescriptors,
+    #[error("Ramdisk is {0} bytes, but only {1} bytes fit in the partition")]
+    RamdiskTooLarge(u64, u64),
+    #[error("Validation error: {0}")]
+    Validation(String),
+    #[error("Failed to parse Magisk version from line: {0:?}")]
+    ParseMagiskVersion(String, #[source] ParseIntError),
+    #[error("Failed to determine Magisk version from: {0:?}")]
+    FindMagiskVersion(PathBuf),
+    #[error("AVB error")]
+    Avb(#[from] avb::Error),
+    #[error("Boot image error")]
+    BootImage(#[from] bootimage::Error),
+    #[error("Compression error")]
+    Compression(#[from] compression::Error),
+    #[error("Crypto error")]
+    Crypto(#[from] crypto::Error),
+    #[error("CPIO error")]
+    Cpio(#[from] cpio::Error),
+    #[error("DTBO error")]
+    Dtbo(#[from] dtbo::Error),
+    #[error("XZ stream error")]
+    XzStream(#[from] xz2::stream::Error),
+    #[error("Zip error")]
+    Zip(#[from] ZipError),
+    #[error("I/O error")]
+    IoError(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Load a ramdisk's cpio entries. Uncompressed ramdisks are supported since
+/// they can be produced by [`RamdiskCompressionPatcher`], but only if they
+/// start with a cpio header. Data in an unknown format is still rejected.
+///
+/// Recoverable decompression errors, like a bad checksum at the end of an
+/// otherwise complete stream or a bad gzip header CRC, are ignored and
+/// reported to `warnings`, if specified. These used to go unnoticed because the
+/// cpio parser stops reading at the trailer entry.
+///
+/// Some vendor ramdisks also have data after the compressed stream that fails
+/// to decompress. If the cpio archive up to the trailer entry can still be
+/// read, the rest is ignored and reported to `warnings` too. Other errors are
+/// fatal.
+fn load_ramdisk(
+    data: &[u8],
+    warnings: Option<&WarningCollector>,
+) -> Result<(Vec<CpioEntryNew>, CompressedFormat)> {
+    let raw_reader = Cursor::new(data);
+    let mut reader = CompressedReader::new_lenient(raw_reader, cpio::has_magic(data))?;
+    let format = reader.format();
+    let (decompressed, error) = match reader.decompress_all() {
+        Ok(result) => result,
+        Err(e) => {
+            let raw_reader = Cursor::new(data);
+            let reader = CompressedReader::new_lenient(raw_reader, cpio::has_magic(data))?;
+            let Ok(entries) = cpio::load(reader, false) else {
+                return Err(e.into());
+            };
+
+            if let Some(warnings) = warnings {
+                warnings.emit(
+                    WarningCode::RamdiskTrailingData,
+                    Severity::Medium,
+                    format!("Ignoring data after cpio trailer in {format:?} ramdisk: {e}"),
+                );
+            }
+
+            return Ok((entries, format));
+        }
+    };
+
+    if let (Some(e), Some(warnings)) = (error, warnings) {
+        warnings.emit(
+            WarningCode::RamdiskChecksumMismatch,
+            Severity::Medium,
+            format!("Ignoring error in {format:?} ramdisk: {e}"),
+        );
+    }
+
+    let entries = cpio::load(Cursor::new(decompressed), false)?;
+
+    Ok((entries, format))
+}
+
+fn save_ramdisk(entries: &[CpioEntryNew], format: CompressedFormat) -> Result<Vec<u8>> {
+    // Rough estimate of the uncompressed size (110-byte header, name, content,
+    // and alignment) so that small ramdisks don't allocate a full LZ4 block.
+    let size_hint = entries
+        .iter()
+        .map(|e| 110 + e.name.len() + e.content.len() + 8)
+        .sum::<usize>()
+        + 128;
+
+    let raw_writer = Cursor::new(vec![]);
+    let mut writer = CompressedWriter::with_size_hint(raw_writer, format, size_hint)?;
+    cpio::save(&mut writer, entries, false)?;
+
+    let raw_writer = writer.finish()?;
+    Ok(raw_writer.into_inner())
+}
+
+/// Load the kernel modules from a vendor_boot DLKM ramdisk fragment. The
+/// compression format is autodetected. Only regular files with a `.ko`
+/// extension are returned. The entry names are the paths within the ramdisk.
+pub fn load_kernel_modules(data: &[u8]) -> Result<Vec<CpioEntryNew>> {
+    let (entries, _) = load_ramdisk(data, None)?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|e| e.is_file() && e.name.ends_with(b".ko"))
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TextEncoding {
+    Utf8,
+    Utf8Bom,
+    Utf16LeBom,
+    Utf16BeBom,
+}
+
+/// A text file from a ramdisk, decoded to UTF-8 for editing. The original
+/// encoding and byte order mark are restored when encoding it again, so lines
+/// that were not changed are written back byte for byte. Only encodings that
+/// can be round-tripped exactly are accepted.
+struct TextFile {
+    encoding: TextEncoding,
+    /// The line ending used by most of the lines. This is only used for lines
+    /// that are inserted. Existing lines keep their own line endings.
+    line_ending: &'static str,
+    content: String,
+}
+
+impl TextFile {
+    fn decode(data: &[u8], kind: &str, name: &[u8]) -> Result<Self> {
+        let error = |reason: &str| {
+            Error::Validation(format!("{kind} {reason}: {}", EscapedString::new(name)))
+        };
+
+        if data.starts_with(b"\xff\xfe\0\0") || data.starts_with(b"\0\0\xfe\xff") {
+            return Err(error("is UTF-32, which is not supported"));
+        }
+
+        let (encoding, content) = if let Some(d) = data.strip_prefix(b"\xef\xbb\xbf") {
+            (TextEncoding::Utf8Bom, d)
+        } else if let Some(d) = data.strip_prefix(b"\xff\xfe") {
+            (TextEncoding::Utf16LeBom, d)
+        } else if let Some(d) = data.strip_prefix(b"\xfe\xff") {
+            (TextEncoding::Utf16BeBom, d)
+        } else {
+            (TextEncoding::Utf8, data)
+        };
+
+        let content = match encoding {
+            TextEncoding::Utf8 | TextEncoding::Utf8Bom => std::str::from_utf8(content)
+                .map_err(|_| error("is not valid UTF-8"))?
+                .to_owned(),
+            TextEncoding::Utf16LeBom | TextEncoding::Utf16BeBom => {
+                if content.len() % 2 != 0 {
+                    return Err(error("is not valid UTF-16"));
+                }
+
+                let units = content.chunks_exact(2).map(|c| {
+                    let c = [c[0], c[1]];
+                    if encoding == TextEncoding::Utf16LeBom {
+                        u16::from_le_bytes(c)
+                    } else {
+                        u16::from_be_bytes(c)
+                    }
+                });
+
+                char::decode_utf16(units)
+                    .collect::<std::result::Result<String, _>>()
+                    .map_err(|_| error("is not valid UTF-16"))?
+            }
+        };
+
+        let lf = content.matches('\n').count();
+        let crlf = content.matches("\r\n").count();
+        let line_ending = if crlf * 2 > lf { "\r\n" } else { "\n" };
+
+        Ok(Self {
+            encoding,
+            line_ending,
+            content,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self.encoding {
+            TextEncoding::Utf8 => self.content.as_bytes().to_vec(),
+            TextEncoding::Utf8Bom => {
+                let mut data = b"\xef\xbb\xbf".to_vec();
+                data.extend_from_slice(self.content.as_bytes());
+                data
+            }
+            TextEncoding::Utf16LeBom => iter::once(0xfeff)
+                .chain(self.content.encode_utf16())
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+            TextEncoding::Utf16BeBom => iter::once(0xfeff)
+                .chain(self.content.encode_utf16())
+                .flat_map(u16::to_be_bytes)
+                .collect(),
+        }
+    }
+}
+
+/// Whether a ramdisk entry is a build properties file, like `prop.default` or
+/// its older name, `default.prop`.
+fn is_prop_file(entry: &CpioEntryNew) -> bool {
+    let file_name = entry.name.rsplit(|&c| c == b'/').next().unwrap_or_default();
+
+    entry.is_file() && (file_name == b"prop.default" || file_name.ends_with(b".prop"))
+}
+
+/// Set build properties in a ramdisk's `prop.default` and `*.prop` files.
+/// Existing `key=value` lines for a property are replaced in place in every
+/// file that contains them. Properties that don't exist in any file are
+/// appended to the first one. All other lines, including comments, are left
+/// as is, including their line endings. Appended lines use the line ending that
+/// is most common in the file. Files are written back in their original
+/// encoding. Only the file contents are changed, so the mode and ownership of
+/// the entries are preserved. The ramdisk is recompressed in its original
+/// format.
+pub fn patch_props(data: &[u8], changes: &BTreeMap<String, String>) -> Result<Vec<u8>> {
+    let (mut entries, format) = load_ramdisk(data, None)?;
+    let mut found = BTreeSet::new();
+    let mut first = None;
+
+    for (index, entry) in entries.iter_mut().enumerate() {
+        if !is_prop_file(entry) {
+            continue;
+        }
+
+        let mut text = TextFile::decode(&entry.content, "Properties file", &entry.name)?;
+        let mut new_content = String::with_capacity(text.content.len());
+
+        for line in text.content.split_inclusive('\n') {
+            let key = line.split_once('=').map(|(k, _)| k.trim());
+
+            if let Some((key, value)) = key.and_then(|k| changes.get_key_value(k)) {
+                new_content.push_str(&format!("{key}={value}"));
+                if line.ends_with("\r\n") {
+                    new_content.push_str("\r\n");
+                } else if line.ends_with('\n') {
+                    new_content.push('\n');
+                }
+                found.insert(key.as_str());
+            } else {
+                new_content.push_str(line);
+            }
+        }
+
+        if new_content != text.content {
+            text.content = new_content;
+            entry.content = text.encode();
+        }
+
+        if first.is_none() {
+            first = Some((index, text));
+        }
+    }
+
+    if found.len() != changes.len() {
+        let Some((index, mut text)) = first else {
+            return Err(Error::Validation(
+                "Ramdisk has no properties files".to_owned(),
+            ));
+        };
+
+        if !text.content.is_empty() && !text.content.ends_with('\n') {
+            text.content.push_str(text.line_ending);
+        }
+
+        for (key, value) in changes {
+            if !found.contains(key.as_str()) {
+                text.content
+                    .push_str(&format!("{key}={value}{}", text.line_ending));
+            }
+        }
+
+        entries[index].content = text.encode();
+    }
+
+    save_ramdisk(&entries, format)
+}
+
+/// Convert a ramdisk to a canonical form for comparisons. The ramdisk is
+/// decompressed, the entries are sorted by name, the mtimes are zeroed, and the
+/// inodes are reassigned. The result is an uncompressed cpio archive, so two
+/// ramdisks with the same contents produce identical output regardless of their
+/// original compression format or entry order.
+pub fn canonicalize_ramdisk(data: &[u8]) -> Result<Vec<u8>> {
+    let (mut entries, _) = load_ramdisk(data, None)?;
+
+    for entry in &mut entries {
+        entry.mtime = 0;
+    }
+
+    cpio::sort(&mut entries);
+    cpio::reassign_inodes(&mut entries);
+
+    save_ramdisk(&entries, CompressedFormat::None)
+}
+
+/// Extract the kernel config embedded in a kernel image by
+/// `CONFIG_IKCONFIG`. The kernel image may be compressed. Returns [`None`] if
+/// the kernel does not contain a config.
+pub fn kernel_config(kernel: &[u8]) -> Option<String> {
+    const IKCFG_START: &[u8] = b"IKCFG_ST";
+
+    let mut reader = CompressedReader::new(Cursor::new(kernel), true).ok()?;
+    let mut data = vec![];
+    reader.read_to_end(&mut data).ok()?;
+
+    let offset = memchr::memmem::find(&data, IKCFG_START)? + IKCFG_START.len();
+    let mut config = String::new();
+    GzDecoder::new(&data[offset..])
+        .read_to_string(&mut config)
+        .ok()?;
+
+    Some(config)
+}
+
+/// Check if a kernel config enables the decompressor for a ramdisk format.
+pub fn kernel_supports_ramdisk_format(config: &str, format: CompressedFormat) -> bool {
+    let option = match format {
+        CompressedFormat::None => return true,
+        CompressedFormat::Gzip => "CONFIG_RD_GZIP",
+        CompressedFormat::Lz4Legacy => "CONFIG_RD_LZ4",
+        // The kernel's LZ4 decompressor only supports the legacy format.
+        CompressedFormat::Lz4Frame => return false,
+        CompressedFormat::Xz => "CONFIG_RD_XZ",
+    };
+
+    config
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .any(|(k, v)| k == option && v == "y")
+}
+
+pub trait BootImagePatcher {
+    fn patch(&self, boot_image: &mut BootImage, cancel_signal: &Arc<AtomicBool>) -> Result<()>;
+}
+
+/// Options for [`MagiskRootPatcher`] that correspond to the environment
+/// variables read by the Magisk app's `boot_patch.sh`. The defaults match what
+/// the Magisk app uses on modern devices. `PATCHVBMETAFLAG` has no equivalent
+/// because avbroot re-signs the images instead of disabling AVB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MagiskOptions {
+    /// `KEEPVERITY`. If false, the dm-verity and AVB flags are removed from
+    /// the ramdisk's fstab files and `verity_key` is deleted.
+    pub keep_verity: bool,
+    /// `KEEPFORCEENCRYPT`. If false, the forced encryption flags in the
+    /// ramdisk's fstab files are replaced with `encryptable`.
+    pub keep_force_encrypt: bool,
+    /// `RECOVERYMODE`. This is for devices that boot recovery from the boot
+    /// image, where Magisk must only start when booting with the recovery key
+    /// combo.
+    pub recovery_mode: bool,
+    /// `LEGACYSAR`. If true, `skip_initramfs` is replaced with
+    /// `want_initramfs` in the kernel so that legacy system-as-root devices
+    /// boot from the ramdisk.
+    pub legacy_sar: bool,
+}
+
+impl Default for MagiskOptions {
+    fn default() -> Self {
+        Self {
+            keep_verity: true,
+            keep_force_encrypt: true,
+            recovery_mode: false,
+            legacy_sar: false,
+        }
+    }
+}
+
+impl fmt::Display for MagiskOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "KEEPVERITY={} KEEPFORCEENCRYPT={} RECOVERYMODE={} LEGACYSAR={}",
+            self.keep_verity, self.keep_force_encrypt, self.recovery_mode, self.legacy_sar,
+        )
+    }
+}
+
+impl MagiskOptions {
+    /// fs_mgr flags that magiskboot removes when verity is not kept.
+    const VERITY_FLAGS: &[&str] = &[
+        "verifyatboot",
+        "verify",
+        "avb_keys",
+        "avb",
+        "support_scfs",
+        "fsverity",
+    ];
+    /// fs_mgr flags that magiskboot replaces with `encryptable` when forced
+    /// encryption is not kept.
+    const FORCE_ENCRYPT_FLAGS: &[&str] = &["forceencrypt", "forcefdeorfbe", "fileencryption"];
+
+    /// Patch a single fs_mgr flag the same way as `magiskboot cpio patch`. The
+    /// value of a replaced flag, if any, is kept.
+    fn patch_fstab_flag(&self, flag: &str) -> Option<String> {
+        let (name, value) = match flag.split_once('=') {
+            Some((n, v)) => (n, Some(v)),
+            None => (flag, None),
+        };
+
+        if !self.keep_verity && Self::VERITY_FLAGS.contains(&name) {
+            None
+        } else if !self.keep_force_encrypt && Self::FORCE_ENCRYPT_FLAGS.contains(&name) {
+            match value {
+                Some(v) => Some(format!("encryptable={v}")),
+                None => Some("encryptable".to_owned()),
+            }
+        } else {
+            Some(flag.to_owned())
+        }
+    }
+}
+
+/// Replace `skip_initramfs` with `want_initramfs` in a possibly compressed
+/// kernel image, like magiskboot does for legacy system-as-root devices. The
+/// kernel is recompressed in its original format.
+fn patch_legacy_sar_kernel(kernel: &mut Vec<u8>) -> Result<()> {
+    const SKIP: &[u8] = b"skip_initramfs";
+    const WANT: &[u8] = b"want_initramfs";
+
+    let mut reader = CompressedReader::new(Cursor::new(kernel.as_slice()), true)?;
+    let format = reader.format();
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+
+    let offsets = memchr::memmem::find_iter(&data, SKIP).collect::<Vec<_>>();
+    if offsets.is_empty() {
+        return Err(Error::Validation(format!(
+            "Kernel does not contain {}",
+            EscapedString::new(SKIP),
+        )));
+    }
+
+    for offset in offsets {
+        data[offset..offset + WANT.len()].copy_from_slice(WANT);
+    }
+
+    *kernel = if format == CompressedFormat::None {
+        data
+    } else {
+        let mut writer = CompressedWriter::new(Cursor::new(vec![]), format)?;
+        writer.write_all(&data)?;
+        writer.finish()?.into_inner()
+    };
+
+    Ok(())
+}
+
+/// Root a boot image with Magisk.
+pub struct MagiskRootPatcher {
+    apk_path: PathBuf,
+    version: u32,
+    preinit_device: Option<String>,
+    random_seed: u64,
+    options: MagiskOptions,
+    refuse_repatch: bool,
+    warnings: WarningCollector,
+}
+
+impl MagiskRootPatcher {
+    // - Versions <25102 are not supported because they're missing commit
+    //   1f8c063dc64806c4f7320ed66c785ff7bc116383, which would leave devices
+    //   that use Android 13 GKIs unable to boot into recovery
+    // - Versions 25207 through 25210 are not supported because they used the
+    //   RULESDEVICE config option, which stored the writable block device as an
+    //   rdev major/minor pair, which was not consistent across reboots and was
+    //   replaced by PREINITDEVICE
+    const VERS_SUPPORTED: &[Range<u32>] = &[25102..25207, 25211..26400];
+    const VER_PREINIT_DEVICE: Range<u32> =
+        25211..Self::VERS_SUPPORTED[Self::VERS_SUPPORTED.len() - 1].end;
+    const VER_RANDOM_SEED: Range<u32> = 25211..26103;
+
+    pub fn new(
+        path: &Path,
+        preinit_device: Option<&str>,
+        random_seed: Option<u64>,
+        options: MagiskOptions,
+        ignore_compatibility: bool,
+        refuse_repatch: bool,
+        warnings: &WarningCollector,
+    ) -> Result<Self> {
+        let version = Self::get_version(path)?;
+
+        if !Self::VERS_SUPPORTED.iter().any(|v| v.contains(&version)) {
+            let msg = format!(
+                "Unsupported Magisk version {} (supported: {:?})",
+                version,
+                Self::VERS_SUPPORTED,
+            );
+
+            if ignore_compatibility {
+                warnings.emit(WarningCode::MagiskUnsupportedVersion, Severity::High, msg);
+            } else {
+                return Err(Error::Validation(msg));
+            }
+        }
+
+        if preinit_device.is_none() && Self::VER_PREINIT_DEVICE.contains(&version) {
+            let msg = format!(
+                "Magisk version {} ({:?}) requires a preinit device to be specified",
+                version,
+                Self::VER_PREINIT_DEVICE,
+            );
+
+            if ignore_compatibility {
+                warnings.emit(WarningCode::MagiskMissingPreinitDevice, Severity::High, msg);
+            } else {
+                return Err(Error::Validation(msg));
+            }
+        }
+
+        Ok(Self {
+            apk_path: path.to_owned(),
+            version,
+            preinit_device: preinit_device.map(|d| d.to_owned()),
+            // Use a hardcoded random seed by default to ensure byte-for-byte
+            // reproducibility.
+            random_seed: random_seed.unwrap_or(0xfedcba9876543210),
+            options,
+            refuse_repatch,
+            warnings: warnings.clone(),
+        })
+    }
+
+    fn get_version(path: &Path) -> Result<u32> {
+        let reader = File::open(path)?;
+        let reader = BufReader::new(reader);
+        let mut zip = ZipArchive::new(reader)?;
+        let entry = zip.by_name("assets/util_functions.sh")?;
+        let mut entry = BufReader::new(entry);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let n = entry.read_line(&mut line)?;
+            if n == 0 {
+                return Err(Error::FindMagiskVersion(path.to_owned()));
+            }
+
+            if let Some(suffix) = line.trim_end().strip_prefix("MAGISK_VER_CODE=") {
+                let version = suffix
+                    .parse()
+                    .map_err(|e| Error::ParseMagiskVersion(suffix.to_owned(), e))?;
+                return Ok(version);
+            }
+        }
+    }
+
+    /// Compare old and new ramdisk entry lists, creating the Magisk `.backup/`
+    /// directory structure. `.backup/.rmlist` will contain a sorted list of
+    /// NULL-terminated strings, listing which files were newly added or
+    /// changed. The old entries for changed files will be added to the new
+    /// entries as `.backup/<path>`.
+    ///
+    /// Both lists and entries within the lists may be mutated.
+    fn apply_magisk_backup(old_entries: &mut [CpioEntryNew], new_entries: &mut Vec<CpioEntryNew>) {
+        cpio::sort(old_entries);
+        cpio::sort(new_entries);
+
+        let mut rm_list = vec![];
+        let mut to_back_up = vec![];
+
+        let mut old_iter = old_entries.iter().peekable();
+        let mut new_iter = new_entries.iter().peekable();
+
+        loop {
+            match (old_iter.peek(), new_iter.peek()) {
+                (Some(&old), Some(&new)) => match old.name.cmp(&new.name) {
+                    Ordering::Less => {
+                        to_back_up.push(old);
+                        old_iter.next();
+                    }
+                    Ordering::Equal => {
+                        if old.content != new.content {
+                            to_back_up.push(old);
+                        }
+                        old_iter.next();
+                        new_iter.next();
+                    }
+                    Ordering::Greater => {
+                        rm_list.extend(&new.name);
+                        rm_list.push(b'\0');
+                        new_iter.next();
+                    }
+                },
+                (Some(old), None) => {
+                    to_back_up.push(old);
+                    old_iter.next();
+                }
+                (None, Some(new)) => {
+                    rm_list.extend(&new.name);
+                    rm_list.push(b'\0');
+                    new_iter.next();
+                }
+                (None, None) => break,
+            }
+        }
+
+        // Intentially using 000 permissions to match Magisk.
+        new_entries.push(CpioEntryNew::new_directory(b".backup"));
+
+        for old_entry in to_back_up {
+            let mut new_entry = old_entry.clone();
+            new_entry.name = b".backup/".to_vec();
+            new_entry.name.extend(&old_entry.name);
+            new_entries.push(new_entry);
+        }
+
+        {
+            // Intentially using 000 permissions to match Magisk.
+            let mut entry = CpioEntryNew::new_file(b".backup/.rmlist");
+            entry.content = rm_list;
+            new_entries.push(entry);
+        }
+    }
+
+    /// Undo a previous Magisk patch, similar to `magiskboot cpio restore`. The
+    /// entries listed in `.backup/.rmlist` are removed, the entries in
+    /// `.backup/` are moved back to their original paths, and `.backup/` itself
+    /// is removed. Returns false and leaves the entries untouched if the
+    /// ramdisk was not patched by Magisk.
+    pub fn restore_magisk_backup(entries: &mut Vec<CpioEntryNew>) -> bool {
+        if !entries.iter().any(|e| e.name == b".backup/.magisk") {
+            return false;
+        }
+
+        let rm_list = entries
+            .iter()
+            .find(|e| e.name == b".backup/.rmlist")
+            .map(|e| e.content.as_slice())
+            .unwrap_or_default()
+            .split(|b| *b == b'\0')
+            .filter(|n| !n.is_empty())
+            .map(|n| n.to_vec())
+            .collect::<HashSet<_>>();
+
+        let mut restored = vec![];
+        let mut backups = vec![];
+
+        for entry in entries.drain(..) {
+            if let Some(name) = entry.name.strip_prefix(b".backup/") {
+                if name != b".rmlist" && name != b".magisk" {
+                    let mut backup = entry.clone();
+                    backup.name = name.to_vec();
+                    backups.push(backup);
+                }
+            } else if entry.name != b".backup" && !rm_list.contains(&entry.name) {
+                restored.push(entry);
+            }
+        }
+
+        for backup in backups {
+            restored.retain(|e| e.name != backup.name);
+            restored.push(backup);
+        }
+
+        cpio::sort(&mut restored);
+        *entries = restored;
+
+        true
+    }
+}
+
+impl BootImagePatcher for MagiskRootPatcher {
+    fn patch(&self, boot_image: &mut BootImage, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+        let zip_reader = File::open(&self.apk_path)?;
+        let mut zip = ZipArchive::new(BufReader::new(zip_reader))?;
+
+        // Load the first ramdisk. If it doesn't exist, we have to generate one
+        // from scratch.
+        let ramdisk = match boot_image {
+            BootImage::V0Through2(b) => Some(&b.ramdisk),
+            BootImage::V3Through4(b) => Some(&b.ramdisk),
+            BootImage::VendorV3Through4(b) => b.ramdisks.first(),
+        };
+        let (mut entries, ramdisk_format) = match ramdisk {
+            Some(r) if !r.is_empty() => load_ramdisk(r, Some(&self.warnings))?,
+            _ => (vec![], CompressedFormat::Lz4Legacy),
+        };
+
+        // If the ramdisk was already patched, eg. because the input is an OTA
+        // that avbroot previously patched, then the stock ramdisk is restored
+        // first. Otherwise, the previous Magisk files would be backed up as if
+        // they were stock files.
+        if Self::restore_magisk_backup(&mut entries) {
+            if self.refuse_repatch {
+                return Err(Error::Validation(
+                    "Boot image is already patched by Magisk".to_owned(),
+                ));
+            }
+
+            self.warnings.emit(
+                WarningCode::MagiskRepatched,
+                Severity::Low,
+                "Removed previous Magisk patch from boot image before re-patching",
+            );
+        }
+
+        let mut old_entries = entries.clone();
+
+        // Patch the stock files the same way as `magiskboot cpio patch`. The
+        // originals are backed up along with the other changed files.
+        if !self.options.keep_verity {
+            entries.retain(|e| e.name != b"verity_key");
+        }
+        if !self.options.keep_verity || !self.options.keep_force_encrypt {
+            fstab::patch_fstab_entries(&mut entries, |content| {
+                fstab::patch_fstab_flags(content, |flag| self.options.patch_fstab_flag(flag))
+            })?;
+        }
+
+        // Create the Magisk directory structure.
+        for (path, perms) in [
+            (b"overlay.d".as_slice(), 0o750),
+            (b"overlay.d/sbin".as_slice(), 0o750),
+        ] {
+            let mut entry = CpioEntryNew::new_directory(path);
+            entry.mode |= perms;
+            entries.push(entry);
+        }
+
+        // Delete the original init.
+        entries.retain(|e| e.name != b"init");
+
+        // Add magiskinit.
+        {
+            let mut zip_entry = zip.by_name("lib/arm64-v8a/libmagiskinit.so")?;
+            let mut data = vec![];
+            zip_entry.read_to_end(&mut data)?;
+
+            let mut entry = CpioEntryNew::new_file(b"init");
+            entry.mode |= 0o750;
+            entry.content = data;
+            entries.push(entry);
+        }
+
+        // Add xz-compressed magisk32 and magisk64.
+        let mut xz_files = HashMap::<&str, &[u8]>::new();
+        xz_files.insert(
+            "lib/armeabi-v7a/libmagisk32.so",
+            b"overlay.d/sbin/magisk32.xz",
+        );
+        xz_files.insert(
+            "lib/arm64-v8a/libmagisk64.so",
+            b"overlay.d/sbin/magisk64.xz",
+        );
+
+        // Add stub apk, which only exists after Magisk commit
+        // ad0e6511e11ebec65aa9b5b916e1397342850319.
+        if zip.file_names().any(|n| n == "assets/stub.apk") {
+            xz_files.insert("assets/stub.apk", b"overlay.d/sbin/stub.xz");
+        }
+
+        for (source, target) in xz_files {
+            let reader = zip.by_name(source)?;
+            let raw_writer = Cursor::new(vec![]);
+            let stream = Stream::new_easy_encoder(9, Check::Crc32)?;
+            let mut writer = XzEncoder::new_stream(raw_writer, stream);
+
+            stream::copy(reader, &mut writer, cancel_signal)?;
+
+            let raw_writer = writer.finish()?;
+            let mut entry = CpioEntryNew::new_file(target);
+            entry.mode |= 0o644;
+            entry.content = raw_writer.into_inner();
+            entries.push(entry);
+        }
+
+        // Create Magisk .backup directory structure.
+        Self::apply_magisk_backup(&mut old_entries, &mut entries);
+
+        // Create Magisk config.
+        let mut magisk_config = String::new();
+        magisk_config.push_str(&format!("KEEPVERITY={}\n", self.options.keep_verity));
+        magisk_config.push_str(&format!(
+            "KEEPFORCEENCRYPT={}\n",
+            self.options.keep_force_encrypt,
+        ));
+        magisk_config.push_str("PATCHVBMETAFLAG=false\n");
+        magisk_config.push_str(&format!("RECOVERYMODE={}\n", self.options.recovery_mode));
+
+        if Self::VER_PREINIT_DEVICE.contains(&self.version) {
+            magisk_config.push_str(&format!(
+                "PREINITDEVICE={}\n",
+                self.preinit_device.as_ref().unwrap(),
+            ));
+        }
+
+        // Magisk normally saves the original SHA1 digest in its config file. It
+        // uses this to find the original image in /data/magisk_backup_<sha1> to
+        // restore the stock boot image for uninstallation purposes. This is a
+        // feature we cannot ever use, so just use a dummy value.
+        magisk_config.push_str("SHA1=0000000000000000000000000000000000000000\n");
+
+        if Self::VER_RANDOM_SEED.contains(&self.version) {
+            magisk_config.push_str(&format!("RANDOMSEED={:#x}\n", self.random_seed));
+        }
+
+        {
+            // Intentially using 000 permissions to match Magisk.
+            let mut entry = CpioEntryNew::new_file(b".backup/.magisk");
+            entry.content = magisk_config.into_bytes();
+            entries.push(entry);
+        }
+
+        if self.options.legacy_sar {
+            match boot_image {
+                BootImage::V0Through2(b) => patch_legacy_sar_kernel(&mut b.kernel)?,
+                BootImage::V3Through4(b) => patch_legacy_sar_kernel(&mut b.kernel)?,
+                BootImage::VendorV3Through4(_) => {
+                    return Err(Error::Validation(
+                        "Vendor boot images have no kernel to patch for legacy SAR".to_owned(),
+                    ));
+                }
+            }
+        }
+
+        // If the stock ramdisk stores SELinux labels, then the Magisk files
+        // need them too.
+        cpio::label_new_entries(&mut entries, cpio::ROOTFS_LABEL)?;
+
+        // Repack ramdisk.
+        cpio::sort(&mut entries);
+        cpio::reassign_inodes(&mut entries);
+        let new_ramdisk = save_ramdisk(&entries, ramdisk_format)?;
+
+        match boot_image {
+            BootImage::V0Through2(b) => b.ramdisk = new_ramdisk,
+            BootImage::V3Through4(b) => b.ramdisk = new_ramdisk,
+            BootImage::VendorV3Through4(b) => {
+                if b.ramdisks.is_empty() {
+                    b.ramdisks.push(new_ramdisk);
+
+                    if let Some(v4) = &mut b.v4_extra {
+                        v4.ramdisk_metas.push(RamdiskMeta {
+                            ramdisk_type: bootimage::VENDOR_RAMDISK_TYPE_NONE,
+                            ramdisk_name: String::new(),
+                            board_id: Default::default(),
+                        });
+                    }
+                } else {
+                    b.ramdisks[0] = new_ramdisk;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The ramdisk that [`OtaCertPatcher`] replaced the certificates in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OtaCertLocation {
+    /// Index of the ramdisk within the boot image.
+    pub index: usize,
+    /// Name of the ramdisk fragment. This is only set for v4 vendor boot
+    /// images.
+    pub fragment: Option<String>,
+}
+
+impl fmt::Display for OtaCertLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Patched otacerts in ramdisk #{}", self.index)?;
+
+        if let Some(name) = &self.fragment {
+            write!(f, " ({name:?})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace the OTA certificates in the vendor_boot/recovery image with the
+/// custom OTA signing certificate. If the payload is signed with a separate
+/// key, then its certificate is included too since update_engine verifies the
+/// payload against the same list of certificates.
+///
+/// For v4 vendor boot images, the recovery ramdisk fragment is patched if there
+/// is one. Otherwise, the first fragment containing otacerts is patched. Only
+/// the patched fragment is recompressed. `report` is called with the ramdisk
+/// that was patched.
+pub struct OtaCertPatcher {
+    cert: Certificate,
+    payload_cert: Option<Certificate>,
+    report: Box<dyn Fn(&OtaCertLocation) + Send + Sync>,
+    warnings: WarningCollector,
+}
+
+impl OtaCertPatcher {
+    const OTACERTS_PATH: &[u8] = b"system/etc/security/otacerts.zip";
+
+    pub fn new(
+        cert: Certificate,
+        payload_cert: Option<Certificate>,
+        report: impl Fn(&OtaCertLocation) + Send + Sync + 'static,
+        warnings: WarningCollector,
+    ) -> Self {
+        Self {
+            cert,
+            payload_cert,
+            report: Box::new(report),
+            warnings,
+        }
+    }
+
+    /// Get the order in which the ramdisks of a vendor boot image are checked
+    /// for otacerts. The recovery fragment, identified by its type or, failing
+    /// that, by its name, comes first. The rest keep their original order.
+    fn vendor_ramdisk_order(image: &VendorBootImageV3Through4) -> Vec<usize> {
+        let recovery = image.v4_extra.as_ref().and_then(|v4| {
+            let metas = &v4.ramdisk_metas;
+
+            metas
+                .iter()
+                .position(|m| m.ramdisk_type == bootimage::VENDOR_RAMDISK_TYPE_RECOVERY)
+                .or_else(|| metas.iter().position(|m| m.ramdisk_name == "recovery"))
+        });
+
+        recovery
+            .into_iter()
+            .chain((0..image.ramdisks.len()).filter(|i| Some(*i) != recovery))
+            .collect()
+    }
+
+    pub fn get_certificates(boot_image: &BootImage) -> Result<Vec<Certificate>> {
+        let mut ramdisks = vec![];
+
+        match boot_image {
+            BootImage::V0Through2(b) => ramdisks.push(&b.ramdisk),
+            BootImage::V3Through4(b) => ramdisks.push(&b.ramdisk),
+            BootImage::VendorV3Through4(b) => ramdisks.extend(b.ramdisks.iter()),
+        }
+
+        let mut certificates = vec![];
+
+        for ramdisk in ramdisks {
+            let (entries, _) = load_ramdisk(ramdisk, None)?;
+            let Some(entry) = entries.iter().find(|e| e.name == Self::OTACERTS_PATH) else {
+                continue;
+            };
+
+            let mut zip = ZipArchive::new(Cursor::new(&entry.content))?;
+
+            for index in 0..zip.len() {
+                let zip_entry = zip.by_index(index)?;
+                if !zip_entry.name().ends_with(".x509.pem") {
+                    continue;
+                }
+
+                let certificate = crypto::read_pem_cert(zip_entry)?;
+                certificates.push(certificate);
+            }
+        }
+
+        Ok(certificates)
+    }
+
+    fn patch_ramdisk(&self, data: &mut Vec<u8>) -> Result<bool> {
+        let (mut entries, ramdisk_format) = load_ramdisk(data, Some(&self.warnings))?;
+        let Some(entry) = entries.iter_mut().find(|e| e.name == Self::OTACERTS_PATH) else {
+            return Ok(false);
+        };
+
+        // Create a new otacerts archive. The old certs are ignored since
+        // flashing a stock OTA will render the device unbootable.
+        {
+            let raw_writer = Cursor::new(vec![]);
+            let mut writer = ZipWriter::new(raw_writer);
+            let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+            writer.start_file("ota.x509.pem", options)?;
+
+            crypto::write_pem_cert(&mut writer, &self.cert)?;
+
+            if let Some(cert) = &self.payload_cert {
+                writer.start_file("payload.x509.pem", options)?;
+
+                crypto::write_pem_cert(&mut writer, cert)?;
+            }
+
+            let raw_writer = writer.finish()?;
+            entry.content = raw_writer.into_inner();
+        }
+
+        // Repack ramdisk.
+        *data = save_ramdisk(&entries, ramdisk_format)?;
+
+        Ok(true)
+    }
+}
+
+impl BootImagePatcher for OtaCertPatcher {
+    fn patch(&self, boot_image: &mut BootImage, _cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+        let only_ramdisk = OtaCertLocation {
+            index: 0,
+            fragment: None,
+        };
+
+        let location = match boot_image {
+            BootImage::V0Through2(b) => self.patch_ramdisk(&mut b.ramdisk)?.then_some(only_ramdisk),
+            BootImage::V3Through4(b) => self.patch_ramdisk(&mut b.ramdisk)?.then_some(only_ramdisk),
+            BootImage::VendorV3Through4(b) => {
+                let mut location = None;
+
+                for index in Self::vendor_ramdisk_order(b) {
+                    if self.patch_ramdisk(&mut b.ramdisks[index])? {
+                        location = Some(OtaCertLocation {
+                            index,
+                            fragment: b
+                                .v4_extra
+                                .as_ref()
+                                .map(|v4| v4.ramdisk_metas[index].ramdisk_name.clone()),
+                        });
+                        break;
+                    }
+                }
+
+                location
+            }
+        };
+
+        // Fail hard if otacerts does not exist. We don't want to lock the user
+        // out of future updates if the OTA certificate mechanism has changed.
+        let Some(location) = location else {
+            return Err(Error::Validation(format!(
+                "No ramdisk contains {}",
+                EscapedString::new(Self::OTACERTS_PATH),
+            )));
+        };
+
+        (self.report)(&location);
+
+        Ok(())
+    }
+}
+
+/// How [`RamdiskCompressionPatcher`] picks the format of each ramdisk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RamdiskCompressionTarget {
+    /// Always recompress with this format.
+    Format(CompressedFormat),
+    /// Recompress with the format that produces the smallest output, out of the
+    /// formats that the kernel supports. This is only done if the output is
+    /// smaller than the original ramdisk by at least `min_savings_ratio` of the
+    /// original size. Otherwise, the original bytes are kept untouched.
+    Smallest { min_savings_ratio: f64 },
+}
+
+/// Formats that are tried for [`RamdiskCompressionTarget::Smallest`].
+const SMALLEST_CANDIDATES: [CompressedFormat; 3] = [
+    CompressedFormat::Gzip,
+    CompressedFormat::Lz4Legacy,
+    CompressedFormat::Xz,
+];
+
+/// What [`RamdiskCompressionPatcher`] did with a single ramdisk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RamdiskCompressionDecision {
+    /// Index of the ramdisk within the boot image.
+    pub index: usize,
+    pub original_format: CompressedFormat,
+    pub original_size: usize,
+    /// This is the same as the original format if the ramdisk was kept.
+    pub format: CompressedFormat,
+    /// This is the same as the original size if the ramdisk was kept.
+    pub size: usize,
+    /// Whether the original bytes were kept untouched.
+    pub kept: bool,
+}
+
+impl fmt::Display for RamdiskCompressionDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.kept {
+            write!(
+                f,
+                "Kept ramdisk #{} as {:?} ({} bytes)",
+                self.index, self.original_format, self.original_size,
+            )
+        } else {
+            write!(
+                f,
+                "Recompressed ramdisk #{} from {:?} ({} bytes) to {:?} ({} bytes)",
+                self.index, self.original_format, self.original_size, self.format, self.size,
+            )
+        }
+    }
+}
+
+/// Recompress the ramdisks in a boot image with a different format. This should
+/// be applied after all other patchers. `report` is called with the decision
+/// made for each non-empty ramdisk.
+///
+/// If the boot image contains a kernel with an embedded config, it is checked
+/// for the decompressor needed for the new format. Otherwise, a warning is
+/// emitted since there is no way to check if the kernel can boot the image.
+/// For [`RamdiskCompressionTarget::Smallest`], only the original format is
+/// tried in that case.
+pub struct RamdiskCompressionPatcher {
+    target: RamdiskCompressionTarget,
+    report: Box<dyn Fn(&RamdiskCompressionDecision) + Send + Sync>,
+    warnings: WarningCollector,
+}
+
+impl RamdiskCompressionPatcher {
+    pub fn new(
+        target: RamdiskCompressionTarget,
+        report: impl Fn(&RamdiskCompressionDecision) + Send + Sync + 'static,
+        warnings: WarningCollector,
+    ) -> Self {
+        Self {
+            target,
+            report: Box::new(report),
+            warnings,
+        }
+    }
+
+    /// Get the formats to try for every ramdisk. An empty list means that only
+    /// each ramdisk's original format can be used.
+    fn candidate_formats(&self, kernel: Option<&Vec<u8>>) -> Result<Vec<CompressedFormat>> {
+        let config = kernel
+            .filter(|k| !k.is_empty())
+            .and_then(|k| kernel_config(k));
+
+        match self.target {
+            RamdiskCompressionTarget::Format(CompressedFormat::None) => {
+                Ok(vec![CompressedFormat::None])
+            }
+            RamdiskCompressionTarget::Format(format) => {
+                match config {
+                    Some(config) => {
+                        if !kernel_supports_ramdisk_format(&config, format) {
+                            return Err(Error::Validation(format!(
+                                "Kernel does not support {format:?} ramdisks",
+                            )));
+                        }
+                    }
+                    None => {
+                        self.warnings.emit(
+                            WarningCode::RamdiskCompressionUnchecked,
+                            Severity::Medium,
+                            format!("Cannot check if the kernel supports {format:?} ramdisks"),
+                        );
+                    }
+                }
+
+                Ok(vec![format])
+            }
+            RamdiskCompressionTarget::Smallest { .. } => match config {
+                Some(config) => Ok(SMALLEST_CANDIDATES
+                    .into_iter()
+                    .filter(|f| kernel_supports_ramdisk_format(&config, *f))
+                    .collect()),
+                None => {
+                    self.warnings.emit(
+                        WarningCode::RamdiskCompressionUnchecked,
+                        Severity::Low,
+                        "Cannot check which ramdisk formats the kernel supports; \
+                        only the original formats will be tried"
+                            .to_owned(),
+                    );
+
+                    Ok(vec![])
+                }
+            },
+        }
+    }
+}
+
+impl BootImagePatcher for RamdiskCompressionPatcher {
+    fn patch(&self, boot_image: &mut BootImage, _cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+        let (kernel, ramdisks) = match boot_image {
+            BootImage::V0Through2(b) => (Some(&b.kernel), vec![&mut b.ramdisk]),
+            BootImage::V3Through4(b) => (Some(&b.kernel), vec![&mut b.ramdisk]),
+            BootImage::VendorV3Through4(b) => (None, b.ramdisks.iter_mut().collect()),
+        };
+
+        let candidates = self.candidate_formats(kernel)?;
+
+        for (index, ramdisk) in ramdisks.into_iter().enumerate() {
+            if ramdisk.is_empty() {
+                continue;
+            }
+
+            let (entries, original_format) = load_ramdisk(ramdisk, Some(&self.warnings))?;
+            let original_size = ramdisk.len();
+
+            let new_ramdisk = match self.target {
+                RamdiskCompressionTarget::Format(format) => {
+                    if format != original_format {
+                        Some((format, save_ramdisk(&entries, format)?))
+                    } else {
+                        None
+                    }
+                }
+                RamdiskCompressionTarget::Smallest { min_savings_ratio } => {
+                    let formats = if candidates.is_empty() {
+                        vec![original_format]
+                    } else {
+                        candidates.clone()
+                    };
+                    let mut smallest: Option<(CompressedFormat, Vec<u8>)> = None;
+
+                    for format in formats {
+                        let data = save_ramdisk(&entries, format)?;
+
+                        if smallest
+                            .as_ref()
+                            .map_or(true, |(_, s)| data.len() < s.len())
+                        {
+                            smallest = Some((format, data));
+                        }
+                    }
+
+                    let max_size = original_size as f64 * (1.0 - min_savings_ratio);
+
+                    smallest.filter(|(_, data)| {
+                        data.len() < original_size && data.len() as f64 <= max_size
+                    })
+                }
+            };
+
+            let decision = match new_ramdisk {
+                Some((format, data)) => {
+                    let decision = RamdiskCompressionDecision {
+                        index,
+                        original_format,
+                        original_size,
+                        format,
+                        size: data.len(),
+                        kept: false,
+                    };
+                    *ramdisk = data;
+
+                    decision
+                }
+                None => RamdiskCompressionDecision {
+                    index,
+                    original_format,
+                    original_size,
+                    format: original_format,
+                    size: original_size,
+                    kept: true,
+                },
+            };
+
+            (self.report)(&decision);
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace the boot image with a prepatched boot image if it is compatible.
+///
+/// An image is compatible if all the non-size-related header fields are
+/// identical and the set of included sections (eg. kernel, dtb) are the same.
+/// The only exception is the number of ramdisk sections, which is allowed to be
+/// higher than the original image.
+pub struct PrepatchedImagePatcher {
+    prepatched: PathBuf,
+    fatal_level: u8,
+    warnings: WarningCollector,
+}
+
+impl PrepatchedImagePatcher {
+    const MIN_LEVEL: u8 = 0;
+    const MAX_LEVEL: u8 = 2;
+
+    // We compile without Unicode support so we have to use [0-9] instead of \d.
+    const VERSION_REGEX: &str = r"Linux version ([0-9]+\.[0-9]+).[0-9]+-(android[0-9]+)-([0-9]+)-";
+
+    pub fn new(prepatched: &Path, fatal_level: u8, warnings: WarningCollector) -> Self {
+        Self {
+            prepatched: prepatched.to_owned(),
+            fatal_level,
+            warnings,
+        }
+    }
+
+    fn get_kmi_version(kernel: &[u8]) -> Result<Option<String>> {
+        let mut decompressed = vec![];
+        {
+            let raw_reader = Cursor::new(kernel);
+            let mut reader = CompressedReader::new(raw_reader, true)?;
+            reader.read_to_end(&mut decompressed)?;
+        }
+
+        Ok(find_kmi_version(Self::VERSION_REGEX, &decompressed))
+    }
+}
+
+/// Find the first match of `regex`, which must capture the kernel version, the
+/// Android release, and the KMI generation, and join the captures into a KMI
+/// version string, like `5.15-android14-11`.
+fn find_kmi_version(regex: &str, data: &[u8]) -> Option<String> {
+    let regex = Regex::new(regex).unwrap();
+    let captures = regex.captures(data)?;
+
+    let kmi_version = captures
+        .iter()
+        // Capture #0 is the entire match.
+        .skip(1)
+        .flatten()
+        .map(|c| c.as_bytes())
+        // Our regexes only match ASCII bytes.
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    Some(kmi_version)
+}
+
+impl BootImagePatcher for PrepatchedImagePatcher {
+    fn patch(&self, boot_image: &mut BootImage, _cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+        let prepatched_image = {
+            let raw_reader = compression::open_standalone(&self.prepatched)?;
+            BootImage::from_reader(BufReader::new(raw_reader))?
+        };
+
+        // Level 0: Warnings that don't affect booting
+        // Level 1: Warnings that may affect booting
+        // Level 2: Warnings that are very likely to affect booting
+        let mut issues = [vec![], vec![], vec![]];
+
+        macro_rules! check {
+            ($level:literal, $old:expr, $new:expr $(,)?) => {
+                let old_val = $old;
+                let new_val = $new;
+
+                if old_val != new_val {
+                    issues[$level].push(format!(
+                        "Field differs: {} ({:?}) -> {} ({:?})",
+                        stringify!($old),
+                        old_val,
+                        stringify!($new),
+                        new_val,
+                    ));
+                }
+            };
+        }
+
+        let old_kernel;
+        let new_kernel;
+
+        match (&boot_image, &prepatched_image) {
+            (BootImage::V0Through2(old), BootImage::V0Through2(new)) => {
+                check!(2, old.header_version(), new.header_version());
+                check!(2, old.kernel_addr, new.kernel_addr);
+                check!(2, old.ramdisk_addr, new.ramdisk_addr);
+                check!(2, old.second_addr, new.second_addr);
+                check!(2, old.tags_addr, new.tags_addr);
+                check!(2, old.page_size, new.page_size);
+                check!(0, old.os_version, new.os_version);
+                check!(0, &old.name, &new.name);
+                check!(1, &old.cmdline, &new.cmdline);
+                check!(0, &old.id, &new.id);
+                check!(1, &old.extra_cmdline, &new.extra_cmdline);
+                check!(2, old.kernel.is_empty(), new.kernel.is_empty());
+                check!(2, old.second.is_empty(), new.second.is_empty());
+
+                if let (Some(old_v1), Some(new_v1)) = (&old.v1_extra, &new.v1_extra) {
+                    check!(2, old_v1.recovery_dtbo_offset, new_v1.recovery_dtbo_offset);
+                    check!(
+                        2,
+                        old_v1.recovery_dtbo.is_empty(),
+                        new_v1.recovery_dtbo.is_empty(),
+                    );
+                }
+
+                if let (Some(old_v2), Some(new_v2)) = (&old.v2_extra, &new.v2_extra) {
+                    check!(2, old_v2.dtb_addr, new_v2.dtb_addr);
+                    check!(2, old_v2.dtb.is_empty(), new_v2.dtb.is_empty());
+                }
+
+                // We allow adding a ramdisk.
+                if !old.ramdisk.is_empty() || new.ramdisk.is_empty() {
+                    check!(2, old.ramdisk.is_empty(), new.ramdisk.is_empty());
+                }
+
+                old_kernel = if old.kernel.is_empty() {
+                    None
+                } else {
+                    Some(&old.kernel)
+                };
+                new_kernel = if new.kernel.is_empty() {
+                    None
+                } else {
+                    Some(&new.kernel)
+                };
+            }
+            (BootImage::V3Through4(old), BootImage::V3Through4(new)) => {
+                check!(2, old.header_version(), new.header_version());
+                check!(0, old.os_version, new.os_version);
+                check!(0, old.reserved, new.reserved);
+                check!(1, &old.cmdline, &new.cmdline);
+                check!(2, old.page_size, new.page_size);
+                check!(2, old.kernel.is_empty(), new.kernel.is_empty());
+
+                // We allow adding a ramdisk.
+                if !old.ramdisk.is_empty() || new.ramdisk.is_empty() {
+                    check!(2, old.ramdisk.is_empty(), new.ramdisk.is_empty());
+                }
+
+                old_kernel = if old.kernel.is_empty() {
+                    None
+                } else {
+                    Some(&old.kernel)
+                };
+                new_kernel = if new.kernel.is_empty() {
+                    None
+                } else {
+                    Some(&new.kernel)
+                };
+            }
+            (BootImage::VendorV3Through4(old), BootImage::VendorV3Through4(new)) => {
+                check!(2, old.page_size, new.page_size);
+                check!(2, old.kernel_addr, new.kernel_addr);
+                check!(2, old.ramdisk_addr, new.ramdisk_addr);
+                check!(1, &old.cmdline, &new.cmdline);
+                check!(2, old.tags_addr, new.tags_addr);
+                check!(0, &old.name, &new.name);
+                check!(2, old.dtb.is_empty(), new.dtb.is_empty());
+                check!(2, old.dtb_addr, new.dtb_addr);
+                check!(2, old.ramdisks.len(), new.ramdisks.len());
+
+                if let (Some(old_v4), Some(new_v4)) = (&old.v4_extra, &new.v4_extra) {
+                    check!(2, &old_v4.ramdisk_metas, &new_v4.ramdisk_metas);
+                    check!(2, &old_v4.bootconfig, &new_v4.bootconfig);
+                }
+
+                old_kernel = None;
+                new_kernel = None;
+            }
+            _ => {
+                return Err(Error::Validation(
+                    "Boot image and prepatched image are different boot image types".to_owned(),
+                ));
+            }
+        }
+
+        if let (Some(old), Some(new)) = (old_kernel, new_kernel) {
+            let old_kmi_version = Self::get_kmi_version(old)?;
+            let new_kmi_version = Self::get_kmi_version(new)?;
+
+            check!(2, old_kmi_version, new_kmi_version);
+        }
+
+        let mut warnings = vec![];
+        let mut errors = vec![];
+
+        for level in Self::MIN_LEVEL..self.fatal_level {
+            warnings.extend(&issues[level as usize]);
+        }
+        for level in self.fatal_level..=Self::MAX_LEVEL {
+            errors.extend(&issues[level as usize]);
+        }
+
+        if !warnings.is_empty() {
+            let mut msg =
+                "The prepatched boot image may not be compatible with the original:".to_owned();
+            for warning in warnings {
+                msg.push_str("\n- ");
+                msg.push_str(warning);
+            }
+
+            self.warnings
+                .emit(WarningCode::PrepatchedIncompatible, Severity::Medium, msg);
+        }
+
+        if !errors.is_empty() {
+            let mut msg =
+                "The prepatched boot image is not compatible with the original:".to_owned();
+            for error in errors {
+                msg.push_str("\n- ");
+                msg.push_str(error);
+            }
+
+            return Err(Error::Validation(msg));
+        }
+
+        *boot_image = prepatched_image;
+
+        Ok(())
+    }
+}
+
+/// A kernel module whose `vermagic` does not match the kernel's KMI version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KmiMismatch {
+    /// Path of the module in the ramdisk.
+    pub module: String,
+    /// Kernel release from the module's `vermagic`.
+    pub release: String,
+}
+
+impl fmt::Display for KmiMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.module, self.release)
+    }
+}
+
+/// Result of comparing the KMI version of a GKI kernel against the kernel
+/// modules in a vendor boot image's ramdisks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KmiCheck {
+    /// KMI version of the kernel, like `5.15-android14-11`.
+    pub kernel_kmi: String,
+    /// Number of modules with a `vermagic` that were compared.
+    pub modules_checked: usize,
+    pub mismatches: Vec<KmiMismatch>,
+}
+
+/// Compare the KMI version of the GKI kernel in `boot_image` against the
+/// `vermagic` of every kernel module in `vendor_boot_image`'s ramdisks.
+/// Returns [`None`] if `boot_image` does not contain a GKI kernel or if
+/// `vendor_boot_image` is not a vendor boot image. Modules without a `vermagic`
+/// are skipped.
+pub fn check_kmi(
+    boot_image: &BootImage,
+    vendor_boot_image: &BootImage,
+) -> Result<Option<KmiCheck>> {
+    const VERMAGIC_REGEX: &str = r"vermagic=([^ \x00]+)";
+    const RELEASE_REGEX: &str = r"^([0-9]+\.[0-9]+)\.[0-9]+-(android[0-9]+)-([0-9]+)-";
+
+    let kernel = match boot_image {
+        BootImage::V0Through2(b) => &b.kernel,
+        BootImage::V3Through4(b) => &b.kernel,
+        BootImage::VendorV3Through4(_) => return Ok(None),
+    };
+    let BootImage::VendorV3Through4(vendor) = vendor_boot_image else {
+        return Ok(None);
+    };
+
+    if kernel.is_empty() {
+        return Ok(None);
+    }
+    let Some(kernel_kmi) = PrepatchedImagePatcher::get_kmi_version(kernel)? else {
+        return Ok(None);
+    };
+
+    let regex = Regex::new(VERMAGIC_REGEX).unwrap();
+    let mut modules_checked = 0;
+    let mut mismatches = vec![];
+
+    for ramdisk in vendor.ramdisks.iter().filter(|r| !r.is_empty()) {
+        for entry in load_kernel_modules(ramdisk)? {
+            let Some(captures) = regex.captures(&entry.content) else {
+                continue;
+            };
+            let release = &captures[1];
+
+            if find_kmi_version(RELEASE_REGEX, release).as_ref() != Some(&kernel_kmi) {
+                mismatches.push(KmiMismatch {
+                    module: EscapedString::new_unquoted(&entry.name).to_string(),
+                    release: String::from_utf8_lossy(release).into_owned(),
+                });
+            }
+
+            modules_checked += 1;
+        }
+    }
+
+    Ok(Some(KmiCheck {
+        kernel_kmi,
+        modules_checked,
+        mismatches,
+    }))
+}
+
+/// Run each patcher against the boot image with the vbmeta footer stripped off
+/// and then re-sign the image.
+pub fn patch_boot(
+    mut reader: impl Read + Seek,
+    writer: impl Write + Seek,
+    key: &RsaPrivateKey,
+    patchers: &[Box<dyn BootImagePatcher + Send>],
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let (header, footer, image_size) = avb::load_image(&mut reader)?;
+    let Some(footer) = footer else {
+        return Err(Error::NoFooter);
+    };
+    let footer_end = footer_end(&mut reader, image_size)?;
+
+    let section_reader = SectionReader::new(reader, 0, footer.original_image_size)?;
+    let mut boot_image = BootImage::from_reader(section_reader)?;
+    let orig_kernel_digest = match &boot_image {
+        BootImage::V3Through4(b) => Some(ring::digest::digest(&ring::digest::SHA256, &b.kernel)),
+        _ => None,
+    };
+    // Only keep the ID in sync if it was correct to begin with. Some vendor
+    // tools store unrelated data in this field.
+    let orig_id_valid = match &boot_image {
+        BootImage::V0Through2(b) => b.compute_id(b.id_algorithm())? == b.id,
+        _ => false,
+    };
+
+    for patcher in patchers {
+        patcher.patch(&mut boot_image, cancel_signal)?;
+    }
+
+    if let BootImage::V0Through2(b) = &mut boot_image {
+        if orig_id_valid {
+            b.update_id()?;
+        }
+    }
+
+    // The GKI boot signature covers the kernel. If a patcher modified it, the
+    // signature must be regenerated or it will be rejected by anything that
+    // checks it.
+    if let (BootImage::V3Through4(b), Some(digest)) = (&mut boot_image, orig_kernel_digest) {
+        let new_digest = ring::digest::digest(&ring::digest::SHA256, &b.kernel);
+
+        if new_digest.as_ref() != digest.as_ref() {
+            b.sign(key)?;
+        }
+    }
+
+    // Fail with a clear error instead of a generic one about the vbmeta footer
+    // not fitting.
+    let space = boot_image_space(&header, footer_end)?;
+    let budget = bootimage::ramdisk_budget(&boot_image, space)?;
+    let ramdisk_size = boot_image.ramdisk_size();
+    if ramdisk_size > budget {
+        return Err(Error::RamdiskTooLarge(ramdisk_size, budget));
+    }
+
+    write_hashed_image(writer, header, &footer, footer_end, image_size, key, |w| {
+        boot_image.to_writer(w)?;
+        Ok(())
+    })
+}
+
+/// Block size that avbtool uses for appended vbmeta images.
+const AVB_BLOCK_SIZE: u64 = 4096;
+
+/// Get the space available for the boot image in front of the vbmeta header and
+/// footer. This assumes that the new vbmeta header is the same size as
+/// `header`, which is the case unless the AVB key size changes.
+fn boot_image_space(header: &avb::Header, footer_end: u64) -> Result<u64> {
+    let mut writer = CountingWriter::new(io::sink());
+    header.to_writer(&mut writer)?;
+    let (_, header_size) = writer.finish();
+
+    // The header is block-aligned and the footer is in a separate block.
+    let space = footer_end.saturating_sub(header_size + AVB_BLOCK_SIZE);
+
+    Ok(space / AVB_BLOCK_SIZE * AVB_BLOCK_SIZE)
+}
+
+/// Get the offset where the vbmeta footer ends. This is before the end of the
+/// image if the image was padded to a larger block size after the footer.
+fn footer_end(reader: impl Read + Seek, image_size: u64) -> Result<u64> {
+    let end = avb::find_footer(reader)?
+        .map_or(image_size, |(_, offset)| offset + avb::Footer::SIZE as u64);
+
+    Ok(end)
+}
+
+/// Write a new image with `write_image` and append the vbmeta footer, updating
+/// the hash descriptor to match. The existing salt is reused for the digest.
+/// The vbmeta header is re-signed with `key` if the original was signed. The
+/// footer is written so that it ends at `footer_end` and the rest of the image
+/// up to `image_size` is filled with zeros.
+fn write_hashed_image<W: Write + Seek>(
+    writer: W,
+    mut header: avb::Header,
+    footer: &avb::Footer,
+    footer_end: u64,
+    image_size: u64,
+    key: &RsaPrivateKey,
+    write_image: impl FnOnce(&mut HashingWriter<W>) -> Result<()>,
+) -> Result<()> {
+    let mut descriptor_iter = header.descriptors.iter_mut().filter_map(|d| {
+        if let Descriptor::Hash(h) = d {
+            Some(h)
+        } else {
+            None
+        }
+    });
+
+    let Some(descriptor) = descriptor_iter.next() else {
+        return Err(Error::NoHashDescriptor);
+    };
+
+    let mut context = Context::new(&ring::digest::SHA256);
+    context.update(&descriptor.salt);
+    let mut hashing_writer = HashingWriter::new(writer, context);
+    write_image(&mut hashing_writer)?;
+    let (mut writer, context) = hashing_writer.finish();
+
+    header.algorithm_type = crypto::validate_avb_key(key)?;
+
+    descriptor.image_size = writer.stream_position()?;
+    descriptor.hash_algorithm = "sha256".to_owned();
+    descriptor.root_digest = context.finish().as_ref().to_vec();
+
+    if descriptor_iter.next().is_some() {
+        return Err(Error::MultipleHashDescriptors);
+    }
+
+    if !header.public_key.is_empty() {
+        header.sign(key)?;
+    }
+
+    avb::write_appended_image(&mut writer, &header, footer, footer_end)?;
+
+    let padding = image_size.saturating_sub(footer_end);
+    io::copy(&mut io::repeat(0).take(padding), &mut writer)?;
+
+    Ok(())
+}
+
+/// Replace the device tree blobs of entries in a DTBO image. `replacements`
+/// maps entry indices to the new blobs. All other entries and fields are
+/// preserved, but the offsets are recomputed. If the image has a vbmeta footer,
+/// it is updated and re-signed like in [`patch_boot()`]. Otherwise, only the
+/// raw DTBO image is written.
+pub fn patch_dtbo(
+    mut reader: impl Read + Seek,
+    mut writer: impl Write + Seek,
+    key: &RsaPrivateKey,
+    replacements: &BTreeMap<usize, Vec<u8>>,
+) -> Result<()> {
+    let replace = |dtbo: &mut DtboImage| -> Result<()> {
+        for (index, data) in replacements {
+            dtbo.replace_entry(*index, data.clone())?;
+        }
+        Ok(())
+    };
+
+    let (header, footer, image_size) = match avb::load_image(&mut reader) {
+        Ok((header, Some(footer), image_size)) => (header, footer, image_size),
+        // There's no vbmeta footer, so the whole file is the DTBO image.
+        Ok((_, None, _)) | Err(avb::Error::InvalidHeaderMagic(_)) => {
+            reader.rewind()?;
+            let mut dtbo = DtboImage::from_reader(&mut reader)?;
+            replace(&mut dtbo)?;
+            dtbo.to_writer(&mut writer)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let footer_end = footer_end(&mut reader, image_size)?;
+
+    let section_reader = SectionReader::new(reader, 0, footer.original_image_size)?;
+    let mut dtbo = DtboImage::from_reader(section_reader)?;
+    replace(&mut dtbo)?;
+
+    write_hashed_image(writer, header, &footer, footer_end, image_size, key, |w| {
+        dtbo.to_writer(w)?;
+        Ok(())
+    })
+}
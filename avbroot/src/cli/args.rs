@@ -5,20 +5,33 @@
 
 use std::sync::{atomic::AtomicBool, Arc};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
-use crate::cli::{avb, boot, completion, key, ota, ramdisk};
+use crate::{
+    cli::{
+        avb, bench, boot, completion, device, dtbo, key, misc, ota, qcdt, ramdisk, selftest,
+        warning, wizard,
+    },
+    crypto,
+};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum Command {
     Avb(avb::AvbCli),
+    Bench(bench::BenchCli),
     Boot(boot::BootCli),
     Completion(completion::CompletionCli),
+    Device(device::DeviceCli),
+    Dtbo(dtbo::DtboCli),
     Key(key::KeyCli),
+    Misc(misc::MiscCli),
     Ota(ota::OtaCli),
+    Qcdt(qcdt::QcdtCli),
     Ramdisk(ramdisk::RamdiskCli),
+    SelfTest(selftest::SelfTestCli),
+    Wizard(wizard::WizardCli),
     /// (Deprecated: Use `avbroot ota patch` instead.)
     Patch(ota::PatchCli),
     /// (Deprecated: Use `avbroot ota extract` instead.)
@@ -32,18 +45,37 @@ pub enum Command {
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// (Testing only) Write zero-filled signatures instead of signing.
+    ///
+    /// The output is invalid and will not boot or install. This also requires
+    /// the AVBROOT_UNSAFE_NO_SIGN environment variable to be set to 1.
+    #[arg(long, global = true, hide = true)]
+    pub unsafe_no_sign: bool,
 }
 
 pub fn main(cancel_signal: &Arc<AtomicBool>) -> Result<()> {
     let cli = Cli::parse();
 
+    if cli.unsafe_no_sign {
+        crypto::enable_unsafe_no_sign().context("Failed to enable unsigned mode")?;
+        warning!("Unsigned mode is enabled: All signatures are replaced with zeros");
+    }
+
     match cli.command {
         Command::Avb(c) => avb::avb_main(&c, cancel_signal),
+        Command::Bench(c) => bench::bench_main(&c, cancel_signal),
         Command::Boot(c) => boot::boot_main(&c),
         Command::Completion(c) => completion::completion_main(&c),
+        Command::Device(c) => device::device_main(&c),
+        Command::Dtbo(c) => dtbo::dtbo_main(&c),
         Command::Key(c) => key::key_main(&c),
+        Command::Misc(c) => misc::misc_main(&c),
         Command::Ota(c) => ota::ota_main(&c, cancel_signal),
+        Command::Qcdt(c) => qcdt::qcdt_main(&c),
         Command::Ramdisk(c) => ramdisk::ramdisk_main(&c),
+        Command::SelfTest(c) => selftest::selftest_main(&c, cancel_signal),
+        Command::Wizard(c) => wizard::wizard_main(&c, cancel_signal),
         // Deprecated aliases.
         Command::Patch(c) => ota::patch_subcommand(&c, cancel_signal),
         Command::Extract(c) => ota::extract_subcommand(&c, cancel_signal),
@@ -4,10 +4,10 @@
  */
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     ffi::OsStr,
     fs::{self, File},
-    io::{self, BufReader},
+    io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
     str,
     sync::{atomic::AtomicBool, Arc},
@@ -19,11 +19,41 @@ use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use rsa::RsaPublicKey;
 
 use crate::{
-    cli::{status, warning},
-    format::avb::{self, Descriptor},
+    cli::{
+        key::{self, PassphraseGroup},
+        status, warning,
+    },
+    crypto,
+    format::{
+        avb::{self, Descriptor},
+        compression::{self, CompressedFormat},
+        sparse::RawAndSparseWriter,
+    },
     stream::PSeekFile,
+    warning::{Severity, WarningCode, WarningCollector},
 };
 
+/// Block size of the sparse images written by `update-hashtree`. This is what
+/// img2simg uses by default.
+const SPARSE_BLOCK_SIZE: u32 = 4096;
+
+/// Find the image for a partition. Compressed `.img.gz` and `.img.xz` files are
+/// used if the uncompressed `.img` file does not exist.
+fn find_image(directory: &Path, name: &str) -> PathBuf {
+    let path = directory.join(format!("{name}.img"));
+
+    if !path.exists() {
+        for ext in ["gz", "xz"] {
+            let compressed = directory.join(format!("{name}.img.{ext}"));
+            if compressed.exists() {
+                return compressed;
+            }
+        }
+    }
+
+    path
+}
+
 fn ensure_name_is_safe(name: &str) -> Result<()> {
     if Path::new(name).file_name() != Some(OsStr::new(name)) {
         bail!("Unsafe partition name: {name}");
@@ -32,15 +62,32 @@ fn ensure_name_is_safe(name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Get the name of the partition that a descriptor refers to. If `slot_suffix`
+/// is set, the name is resolved the same way libavb does when booting that slot.
+fn descriptor_target(descriptor: &Descriptor, slot_suffix: Option<&str>) -> Option<String> {
+    match slot_suffix {
+        Some(s) => descriptor.partition_name_for_slot(s),
+        None => descriptor.partition_name().map(|n| n.to_owned()),
+    }
+}
+
 /// Recursively verify an image's vbmeta header and all of the chained images.
 /// `seen` is used to prevent cycles. `descriptors` will contain all of the hash
-/// and hashtree descriptors that need to be verified.
+/// and hashtree descriptors that need to be verified. If `skip_missing` is true,
+/// images that don't exist are skipped instead of causing an error. If
+/// `slot_suffix` is set, the images that the descriptors refer to are looked up
+/// with the A/B slot suffix appended, unless the descriptor opts out of it.
+/// Signed headers whose parent does not list a trusted key are reported to
+/// `warnings`.
 pub fn verify_headers(
     directory: &Path,
     name: &str,
+    slot_suffix: Option<&str>,
     expected_key: Option<&RsaPublicKey>,
+    skip_missing: bool,
     seen: &mut HashSet<String>,
     descriptors: &mut HashMap<String, Descriptor>,
+    warnings: &WarningCollector,
 ) -> Result<()> {
     if !seen.insert(name.to_owned()) {
         return Ok(());
@@ -48,9 +95,18 @@ pub fn verify_headers(
 
     ensure_name_is_safe(name)?;
 
-    let path = directory.join(format!("{name}.img"));
-    let raw_reader =
-        File::open(&path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let path = find_image(directory, name);
+    let raw_reader = match compression::open_standalone(&path) {
+        Ok(f) => f,
+        // Partial OTAs only contain a subset of the chained partitions.
+        Err(compression::Error::IoError(e))
+            if skip_missing && e.kind() == io::ErrorKind::NotFound =>
+        {
+            status!("Skipping {name}: partition image does not exist");
+            return Ok(());
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?,
+    };
     let (header, _, _) = avb::load_image(BufReader::new(raw_reader))
         .with_context(|| format!("Failed to load vbmeta structures: {path:?}"))?;
 
@@ -69,25 +125,29 @@ pub fn verify_headers(
                 bail!("{prefix}, but is signed by an untrusted key");
             }
         } else {
-            warning!("{prefix}, but parent does not list a trusted key");
+            warnings.emit(
+                WarningCode::SignatureTrustUnknown,
+                Severity::Medium,
+                format!("{prefix}, but parent does not list a trusted key"),
+            );
         }
     } else {
         status!("{name} has an unsigned vbmeta header");
     }
 
     for descriptor in &header.descriptors {
-        let Some(target_name) = descriptor.partition_name() else {
+        let Some(target_name) = descriptor_target(descriptor, slot_suffix) else {
             continue;
         };
 
         match descriptor {
             avb::Descriptor::Hashtree(_) | avb::Descriptor::Hash(_) => {
-                if let Some(prev) = descriptors.get(target_name) {
+                if let Some(prev) = descriptors.get(&target_name) {
                     if prev != descriptor {
                         bail!("{name} descriptor does not match previous encounter");
                     }
                 } else {
-                    descriptors.insert(target_name.to_owned(), descriptor.clone());
+                    descriptors.insert(target_name, descriptor.clone());
                 }
             }
             avb::Descriptor::ChainPartition(d) => {
@@ -95,7 +155,16 @@ pub fn verify_headers(
                     format!("Failed to decode chained public key for: {target_name}")
                 })?;
 
-                verify_headers(directory, target_name, Some(&target_key), seen, descriptors)?;
+                verify_headers(
+                    directory,
+                    &target_name,
+                    slot_suffix,
+                    Some(&target_key),
+                    skip_missing,
+                    seen,
+                    descriptors,
+                    warnings,
+                )?;
             }
             _ => {}
         }
@@ -104,55 +173,175 @@ pub fn verify_headers(
     Ok(())
 }
 
+fn verify_descriptor(
+    directory: &Path,
+    name: &str,
+    descriptor: &Descriptor,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    ensure_name_is_safe(name)?;
+
+    let path = find_image(directory, name);
+    let reader = match compression::open_standalone(&path) {
+        Ok(f) => f,
+        // Some devices, like bluejay, have vbmeta descriptors that refer to
+        // partitions that exist on the device, but not in the OTA.
+        Err(compression::Error::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+            warnings.emit(
+                WarningCode::PartitionImageMissing,
+                Severity::Medium,
+                format!("Partition image does not exist: {path:?}"),
+            );
+            return Ok(());
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?,
+    };
+
+    match descriptor {
+        Descriptor::Hashtree(d) => {
+            status!("Verifying hashtree descriptor for: {name}");
+            d.verify(
+                || Ok(Box::new(BufReader::new(reader.clone()))),
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to verify hashtree descriptor for: {name}"))?;
+        }
+        Descriptor::Hash(d) => {
+            status!("Verifying hash descriptor for: {name}");
+            d.verify(BufReader::new(reader), cancel_signal)
+                .with_context(|| format!("Failed to verify hash descriptor for: {name}"))?;
+        }
+        _ => unreachable!("Non-verifiable descriptor: {descriptor:?}"),
+    }
+
+    Ok(())
+}
+
 pub fn verify_descriptors(
     directory: &Path,
     descriptors: &HashMap<String, Descriptor>,
+    warnings: &WarningCollector,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
     descriptors
         .par_iter()
         .map(|(name, descriptor)| {
-            let path = directory.join(format!("{name}.img"));
-            let reader = match File::open(&path).map(PSeekFile::new) {
-                Ok(f) => f,
-                // Some devices, like bluejay, have vbmeta descriptors that
-                // refer to partitions that exist on the device, but not in the
-                // OTA.
-                Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                    warning!("Partition image does not exist: {path:?}");
-                    return Ok(());
-                }
-                Err(e) => {
-                    Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?
-                }
+            verify_descriptor(directory, name, descriptor, warnings, cancel_signal)
+        })
+        .collect()
+}
+
+/// Like [`verify_descriptors()`], but verify every descriptor instead of
+/// stopping at the first failure. The results are sorted by partition name.
+pub fn verify_descriptors_each(
+    directory: &Path,
+    descriptors: &HashMap<String, Descriptor>,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Vec<(String, Result<()>)> {
+    let mut results = descriptors
+        .par_iter()
+        .map(|(name, descriptor)| {
+            let result = verify_descriptor(directory, name, descriptor, warnings, cancel_signal);
+            (name.clone(), result)
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+
+    results
+}
+
+/// Convert the slot-agnostic `<partition>.img` files for `images` in
+/// `directory`, like the ones written by `avbroot ota extract`, into images for
+/// each A/B slot in `slot_suffixes`. The vbmeta chain starting from `root` is
+/// used to find the partitions that libavb loads without a slot suffix (see
+/// [`Descriptor::uses_ab_suffix()`]). Those images are left as-is. Every other
+/// image is copied for all but the first slot and then renamed for the first
+/// slot, so the image data, including any hash trees and FEC data, is only
+/// produced once. Returns the names of the images that now exist.
+pub fn write_slot_images(
+    directory: &Path,
+    images: &BTreeSet<String>,
+    root: &str,
+    slot_suffixes: &[&str],
+) -> Result<Vec<String>> {
+    let Some((first_suffix, other_suffixes)) = slot_suffixes.split_first() else {
+        bail!("No slot suffixes specified");
+    };
+
+    let mut unslotted = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![root.to_owned()];
+
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) || !images.contains(&name) {
+            continue;
+        }
+
+        ensure_name_is_safe(&name)?;
+
+        let path = directory.join(format!("{name}.img"));
+        let file =
+            File::open(&path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+        let header = match avb::load_image(BufReader::new(file)) {
+            Ok((header, _, _)) => header,
+            // Images covered by a hash descriptor may not have a footer.
+            Err(avb::Error::InvalidHeaderMagic(_)) if name != root => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to load vbmeta structures: {path:?}"));
+            }
+        };
+
+        for descriptor in &header.descriptors {
+            let Some(target_name) = descriptor.partition_name() else {
+                continue;
             };
 
-            match descriptor {
-                Descriptor::Hashtree(d) => {
-                    status!("Verifying hashtree descriptor for: {name}");
-                    d.verify(
-                        || Ok(Box::new(BufReader::new(reader.clone()))),
-                        cancel_signal,
-                    )
-                    .with_context(|| format!("Failed to verify hashtree descriptor for: {name}"))?;
-                }
-                Descriptor::Hash(d) => {
-                    status!("Verifying hash descriptor for: {name}");
-                    d.verify(BufReader::new(reader), cancel_signal)
-                        .with_context(|| format!("Failed to verify hash descriptor for: {name}"))?;
-                }
-                _ => unreachable!("Non-verifiable descriptor: {descriptor:?}"),
+            if !descriptor.uses_ab_suffix() {
+                unslotted.insert(target_name.to_owned());
+            }
+            if let Descriptor::ChainPartition(_) = descriptor {
+                pending.push(target_name.to_owned());
             }
+        }
+    }
 
-            Ok(())
-        })
-        .collect()
+    let mut written = vec![];
+
+    for name in images {
+        ensure_name_is_safe(name)?;
+
+        if unslotted.contains(name) {
+            written.push(name.clone());
+            continue;
+        }
+
+        let path = directory.join(format!("{name}.img"));
+
+        for suffix in other_suffixes {
+            let slot_path = directory.join(format!("{name}{suffix}.img"));
+            fs::copy(&path, &slot_path)
+                .with_context(|| format!("Failed to copy image: {path:?} -> {slot_path:?}"))?;
+            written.push(format!("{name}{suffix}"));
+        }
+
+        let slot_path = directory.join(format!("{name}{first_suffix}.img"));
+        fs::rename(&path, &slot_path)
+            .with_context(|| format!("Failed to rename image: {path:?} -> {slot_path:?}"))?;
+        written.push(format!("{name}{first_suffix}"));
+    }
+
+    written.sort();
+
+    Ok(written)
 }
 
 pub fn avb_main(cli: &AvbCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
     match &cli.command {
         AvbCommand::Dump(c) => {
-            let raw_reader = File::open(&c.input)
+            let raw_reader = compression::open_standalone(&c.input)
                 .with_context(|| format!("Failed to open for reading: {:?}", c.input))?;
             let reader = BufReader::new(raw_reader);
             let (header, footer, image_size) = avb::load_image(reader)
@@ -174,27 +363,84 @@ pub fn avb_main(cli: &AvbCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
             };
 
             let directory = c.input.parent().unwrap_or_else(|| Path::new("."));
-            let name = c
-                .input
+            // Strip the compression extension, if any, before the `.img`.
+            let input = if CompressedFormat::from_extension(&c.input) != CompressedFormat::None {
+                c.input.with_extension("")
+            } else {
+                c.input.clone()
+            };
+            let name = input
                 .file_stem()
                 .with_context(|| format!("Path is not a file: {:?}", c.input))?
                 .to_str()
                 .ok_or_else(|| anyhow!("Invalid UTF-8: {:?}", c.input))?;
 
+            let (_, slot_suffix) = avb::split_slot_suffix(name);
+
             let mut seen = HashSet::<String>::new();
             let mut descriptors = HashMap::<String, Descriptor>::new();
+            let warnings = WarningCollector::new(|w| warning!("{w}"));
 
             verify_headers(
                 directory,
                 name,
+                slot_suffix,
                 public_key.as_ref(),
+                false,
                 &mut seen,
                 &mut descriptors,
+                &warnings,
             )?;
-            verify_descriptors(directory, &descriptors, cancel_signal)?;
+            verify_descriptors(directory, &descriptors, &warnings, cancel_signal)?;
 
             status!("Successfully verified all vbmeta signatures and hashes");
         }
+        AvbCommand::UpdateHashtree(c) => {
+            let passphrase = key::get_passphrase(&c.passphrase, &c.key);
+            let private_key = crypto::read_key_file(&c.key, &passphrase, c.p12_alias.as_deref())
+                .with_context(|| format!("Failed to load key: {:?}", c.key))?;
+
+            let reader = File::open(&c.input)
+                .map(PSeekFile::new)
+                .with_context(|| format!("Failed to open for reading: {:?}", c.input))?;
+            let raw_writer = File::create(&c.output)
+                .map(BufWriter::new)
+                .with_context(|| format!("Failed to open for writing: {:?}", c.output))?;
+            let update = |writer: &mut dyn Write| {
+                avb::update_hashtree_image(
+                    || Ok(Box::new(BufReader::new(reader.clone()))),
+                    writer,
+                    &private_key,
+                    c.fec_roots,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to update hash tree: {:?}", c.input))
+            };
+
+            // The sparse image is generated from the same write pass instead
+            // of converting the raw image afterwards.
+            if let Some(path) = &c.output_sparse {
+                let sparse_writer = File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+                let mut writer =
+                    RawAndSparseWriter::new(raw_writer, sparse_writer, SPARSE_BLOCK_SIZE)?;
+
+                update(&mut writer)?;
+
+                writer
+                    .finish()
+                    .with_context(|| format!("Failed to finish sparse image: {path:?}"))?;
+            } else {
+                let mut writer = raw_writer;
+
+                update(&mut writer)?;
+
+                writer
+                    .flush()
+                    .with_context(|| format!("Failed to flush output: {:?}", c.output))?;
+            }
+        }
     }
 
     Ok(())
@@ -209,6 +455,10 @@ struct DumpCli {
 }
 
 /// Verify vbmeta signatures.
+///
+/// The chained and hashed images are loaded from the same directory as the
+/// input. If the input file name has an A/B slot suffix (eg. vbmeta_a.img),
+/// the images are looked up with the same suffix, like libavb does.
 #[derive(Debug, Parser)]
 struct VerifyCli {
     /// Path to input image.
@@ -223,10 +473,52 @@ struct VerifyCli {
     public_key: Option<PathBuf>,
 }
 
+/// Recompute the hash tree of an image and sign it again.
+///
+/// The input must have a vbmeta footer and a hashtree descriptor. The hash
+/// tree, FEC data, and vbmeta header are rewritten after the original image
+/// data and the output stays the same size as the input.
+#[derive(Debug, Parser)]
+struct UpdateHashtreeCli {
+    /// Path to input image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Path to private key for signing the vbmeta header.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    key: PathBuf,
+
+    #[command(flatten)]
+    passphrase: PassphraseGroup,
+
+    /// Alias of the entry to use if the key is a PKCS#12 bundle.
+    #[arg(long, value_name = "ALIAS")]
+    p12_alias: Option<String>,
+
+    /// Add FEC data with this many parity bytes per codeword.
+    ///
+    /// This is ignored if the image already has FEC data. Android uses 2. The
+    /// command fails if the FEC data does not fit in the image.
+    #[arg(long, value_name = "ROOTS")]
+    fec_roots: Option<u32>,
+
+    /// Also write the output as a sparse image for fastboot.
+    ///
+    /// The raw and sparse images are written at the same time and contain the
+    /// same data. The image size must be a multiple of 4096 bytes.
+    #[arg(long, value_name = "FILE", value_parser)]
+    output_sparse: Option<PathBuf>,
+}
+
 #[derive(Debug, Subcommand)]
 enum AvbCommand {
     Dump(DumpCli),
     Verify(VerifyCli),
+    UpdateHashtree(UpdateHashtreeCli),
 }
 
 /// Show information about AVB-protected images.
@@ -0,0 +1,481 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Cursor},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{value_parser, Parser};
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use xz2::read::XzDecoder;
+
+use crate::{
+    cli::{selftest, status},
+    crypto::{self, PassphraseSource},
+    format::{
+        avb::AlgorithmType,
+        compression::{CompressedFormat, CompressedReader, CompressedWriter},
+        payload::CompressedPartitionWriter,
+    },
+    protobuf::chromeos_update_engine::PartitionUpdate,
+    stream::{self, HashingReader},
+};
+
+/// Size of the OTA used for the patch time estimate.
+pub const TYPICAL_OTA_SIZE: u64 = 2560 * 1024 * 1024;
+
+/// Total size of the images in a typical OTA that are extracted, patched, and
+/// recompressed. This covers the boot images and vbmeta images.
+const TYPICAL_PATCHED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Total size of the ramdisks inside the patched boot images.
+const TYPICAL_RAMDISK_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Number of RSA signatures created when patching: the vbmeta images, the
+/// payload metadata and payload signatures, and the whole-file signature.
+const TYPICAL_SIGNATURES: u32 = 6;
+
+/// Number of signatures per RSA signing iteration.
+const SIGNATURES_PER_ITERATION: u32 = 4;
+
+/// Size of the input for the untimed warmup run of each benchmark.
+const WARMUP_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Cpu,
+    Io,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub resource: Resource,
+    /// Number of bytes processed per iteration. For RSA signing, this is the
+    /// number of signatures instead.
+    pub amount: u64,
+    /// Median duration of an iteration in seconds.
+    pub seconds: f64,
+}
+
+impl Measurement {
+    /// Throughput in units of [`Self::amount`] per second.
+    pub fn rate(&self) -> f64 {
+        self.amount as f64 / self.seconds.max(f64::MIN_POSITIVE)
+    }
+
+    fn display_value(&self) -> String {
+        if self.name == "rsa4096_sign" {
+            format!("{:.2} ms/signature", 1000.0 / self.rate())
+        } else {
+            format!("{:.1} MiB/s", self.rate() / 1024.0 / 1024.0)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Estimate {
+    pub ota_size: u64,
+    pub cpu_seconds: f64,
+    pub io_seconds: f64,
+    pub total_seconds: f64,
+    pub bound: Resource,
+}
+
+#[derive(Debug, Serialize)]
+struct BenchReport {
+    size: u64,
+    iterations: u32,
+    measurements: Vec<Measurement>,
+    estimate: Estimate,
+}
+
+/// Generate `size` bytes of test data that roughly resembles partition images.
+/// A quarter of the blocks are zeros, a quarter are incompressible, and the
+/// rest are repetitive with some noise. The data is the same on every run.
+pub fn test_data(size: usize) -> Vec<u8> {
+    const TEXT: &[u8] = b"ro.build.fingerprint=avbroot/bench/bench:14/AP1A/1:user/release-keys\n";
+
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut data = vec![0u8; size];
+
+    for (i, block) in data.chunks_mut(4096).enumerate() {
+        match i % 4 {
+            0 => {}
+            1 => block.iter_mut().for_each(|b| *b = next() as u8),
+            _ => {
+                for (j, b) in block.iter_mut().enumerate() {
+                    *b = TEXT[j % TEXT.len()];
+                }
+                for _ in 0..block.len() / 64 {
+                    let n = next();
+                    block[n as usize % block.len()] = (n >> 32) as u8;
+                }
+            }
+        }
+    }
+
+    data
+}
+
+fn check_cancel(cancel_signal: &Arc<AtomicBool>) -> io::Result<()> {
+    if cancel_signal.load(Ordering::SeqCst) {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Received cancel signal",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run `f` once on a small input to warm up caches and lazily initialized
+/// state, then run it `iterations` times on the full input and return the
+/// median duration. The warmup input is a prefix of the full input.
+fn measure(
+    data: &[u8],
+    iterations: u32,
+    cancel_signal: &Arc<AtomicBool>,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<f64> {
+    f(&data[..data.len().min(WARMUP_SIZE)])?;
+
+    let mut durations = vec![];
+
+    for _ in 0..iterations {
+        check_cancel(cancel_signal)?;
+
+        let start = Instant::now();
+        f(data)?;
+        durations.push(start.elapsed());
+    }
+
+    durations.sort();
+
+    Ok(durations
+        .get(durations.len() / 2)
+        .copied()
+        .unwrap_or(Duration::ZERO)
+        .as_secs_f64())
+}
+
+fn compress(
+    data: &[u8],
+    format: CompressedFormat,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<u8>> {
+    let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format)
+        .with_context(|| format!("Failed to create {format:?} compressor"))?;
+    stream::copy(data, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to compress {format:?} data"))?;
+    let raw_writer = writer
+        .finish()
+        .with_context(|| format!("Failed to finalize {format:?} data"))?;
+
+    Ok(raw_writer.into_inner())
+}
+
+fn decompress(
+    data: &[u8],
+    format: CompressedFormat,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let reader = CompressedReader::new(Cursor::new(data), false)
+        .with_context(|| format!("Failed to detect {format:?} data"))?;
+    stream::copy(reader, io::sink(), cancel_signal)
+        .with_context(|| format!("Failed to decompress {format:?} data"))?;
+
+    Ok(())
+}
+
+/// Compress data the same way as partition images in `payload.bin`.
+fn compress_payload(data: &[u8], cancel_signal: &Arc<AtomicBool>) -> Result<Vec<u8>> {
+    let mut writer = CompressedPartitionWriter::new(Cursor::new(Vec::new()), 4096)
+        .context("Failed to create payload compressor")?;
+    stream::copy(data, &mut writer, cancel_signal).context("Failed to compress payload data")?;
+
+    let mut partition = PartitionUpdate::default();
+    let raw_writer = writer
+        .finish(&mut partition)
+        .context("Failed to finalize payload data")?;
+
+    Ok(raw_writer.into_inner())
+}
+
+/// Measure each primitive that patching depends on. `size` bytes of test data
+/// are used for each benchmark and the disk benchmarks write to a temporary
+/// file in `dir`. `on_result` is called after each benchmark completes.
+///
+/// Everything runs on a single thread. The disk read benchmark reads data that
+/// was just written, so it may be served from the page cache.
+pub fn run_benchmarks(
+    size: usize,
+    iterations: u32,
+    dir: &Path,
+    mut on_result: impl FnMut(&Measurement),
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<Measurement>> {
+    let data = test_data(size);
+    let size = size as u64;
+    let mut measurements = vec![];
+
+    let mut record = |name, resource, amount, seconds| {
+        let measurement = Measurement {
+            name,
+            resource,
+            amount,
+            seconds,
+        };
+        on_result(&measurement);
+        measurements.push(measurement);
+    };
+
+    let seconds = measure(&data, iterations, cancel_signal, |d| {
+        let reader = HashingReader::new(d, ring::digest::Context::new(&ring::digest::SHA256));
+        stream::copy(reader, io::sink(), cancel_signal).context("Failed to hash data")?;
+        Ok(())
+    })?;
+    record("sha256", Resource::Cpu, size, seconds);
+
+    for (compress_name, decompress_name, format) in [
+        ("gzip_compress", "gzip_decompress", CompressedFormat::Gzip),
+        (
+            "lz4_compress",
+            "lz4_decompress",
+            CompressedFormat::Lz4Legacy,
+        ),
+        ("xz_compress", "xz_decompress", CompressedFormat::Xz),
+    ] {
+        let seconds = measure(&data, iterations, cancel_signal, |d| {
+            compress(d, format, cancel_signal).map(|_| ())
+        })?;
+        record(compress_name, Resource::Cpu, size, seconds);
+
+        let compressed = compress(&data, format, cancel_signal)?;
+        let warmup = compress(&data[..data.len().min(WARMUP_SIZE)], format, cancel_signal)?;
+        let seconds = measure(&compressed, iterations, cancel_signal, |d| {
+            // The warmup run gets a complete stream instead of a truncated one.
+            let d = if d.len() == compressed.len() {
+                d
+            } else {
+                &warmup[..]
+            };
+            decompress(d, format, cancel_signal)
+        })?;
+        record(decompress_name, Resource::Cpu, size, seconds);
+    }
+
+    let seconds = measure(&data, iterations, cancel_signal, |d| {
+        compress_payload(d, cancel_signal).map(|_| ())
+    })?;
+    record("payload_xz_compress", Resource::Cpu, size, seconds);
+
+    let compressed = compress_payload(&data, cancel_signal)?;
+    let warmup = compress_payload(&data[..data.len().min(WARMUP_SIZE)], cancel_signal)?;
+    let seconds = measure(&compressed, iterations, cancel_signal, |d| {
+        let d = if d.len() == compressed.len() {
+            d
+        } else {
+            &warmup[..]
+        };
+        stream::copy(XzDecoder::new(d), io::sink(), cancel_signal)
+            .context("Failed to decompress payload data")?;
+        Ok(())
+    })?;
+    record("payload_xz_decompress", Resource::Cpu, size, seconds);
+
+    let temp_file = NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {dir:?}"))?;
+
+    let seconds = measure(&data, iterations, cancel_signal, |d| {
+        let file = temp_file
+            .reopen()
+            .context("Failed to open temporary file")?;
+        file.set_len(0)
+            .context("Failed to truncate temporary file")?;
+
+        let mut writer = BufWriter::new(file);
+        stream::copy(d, &mut writer, cancel_signal).context("Failed to write temporary file")?;
+        let file = writer
+            .into_inner()
+            .context("Failed to flush temporary file")?;
+        file.sync_all().context("Failed to sync temporary file")?;
+
+        Ok(())
+    })?;
+    record("disk_write", Resource::Io, size, seconds);
+
+    let seconds = measure(&data, iterations, cancel_signal, |_| {
+        let file = File::open(temp_file.path()).context("Failed to open temporary file")?;
+        stream::copy(file, io::sink(), cancel_signal).context("Failed to read temporary file")?;
+        Ok(())
+    })?;
+    record("disk_read", Resource::Io, size, seconds);
+
+    let key = crypto::read_pem_key(
+        selftest::KEY_4096.as_bytes(),
+        &PassphraseSource::Prompt(String::new()),
+    )
+    .context("Failed to load bundled key")?;
+    let digest = AlgorithmType::Sha256Rsa4096.hash(&data[..data.len().min(4096)]);
+
+    let seconds = measure(&[], iterations, cancel_signal, |_| {
+        for _ in 0..SIGNATURES_PER_ITERATION {
+            AlgorithmType::Sha256Rsa4096
+                .sign(&key, &digest)
+                .context("Failed to sign digest")?;
+        }
+        Ok(())
+    })?;
+    record(
+        "rsa4096_sign",
+        Resource::Cpu,
+        SIGNATURES_PER_ITERATION.into(),
+        seconds,
+    );
+
+    Ok(measurements)
+}
+
+/// Estimate how long patching an OTA of `ota_size` bytes takes, based on the
+/// measured rates. Patching reads the input once, writes the output once, and
+/// reads the output back once to verify it. The whole payload is hashed for
+/// the payload signature and the whole file is hashed for the zip signature.
+/// Only the boot and vbmeta images are decompressed and recompressed.
+///
+/// This is a rough single-threaded estimate. Benchmarks that are missing from
+/// `measurements` are not counted.
+pub fn estimate(measurements: &[Measurement], ota_size: u64) -> Estimate {
+    let seconds = |name: &str, amount: u64| {
+        measurements
+            .iter()
+            .find(|m| m.name == name)
+            .map_or(0.0, |m| amount as f64 / m.rate())
+    };
+
+    let cpu_seconds = seconds("sha256", 2 * ota_size)
+        + seconds("payload_xz_decompress", TYPICAL_PATCHED_SIZE)
+        + seconds("payload_xz_compress", TYPICAL_PATCHED_SIZE)
+        + seconds("lz4_decompress", TYPICAL_RAMDISK_SIZE)
+        + seconds("lz4_compress", TYPICAL_RAMDISK_SIZE)
+        + seconds("rsa4096_sign", TYPICAL_SIGNATURES.into());
+    let io_seconds = seconds("disk_read", 2 * ota_size) + seconds("disk_write", ota_size);
+
+    Estimate {
+        ota_size,
+        cpu_seconds,
+        io_seconds,
+        total_seconds: cpu_seconds + io_seconds,
+        bound: if cpu_seconds >= io_seconds {
+            Resource::Cpu
+        } else {
+            Resource::Io
+        },
+    }
+}
+
+pub fn bench_main(cli: &BenchCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let temp_dir = cli.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let Ok(size) = usize::try_from(cli.size * 1024 * 1024) else {
+        bail!("Size is too large: {} MiB", cli.size);
+    };
+
+    let measurements = run_benchmarks(
+        size,
+        cli.iterations,
+        &temp_dir,
+        |m| {
+            if !cli.json {
+                status!("{}: {}", m.name, m.display_value());
+            }
+        },
+        cancel_signal,
+    )?;
+    let estimate = estimate(&measurements, TYPICAL_OTA_SIZE);
+
+    if cli.json {
+        let report = BenchReport {
+            size: cli.size * 1024 * 1024,
+            iterations: cli.iterations,
+            measurements,
+            estimate,
+        };
+        let data = serde_json::to_string_pretty(&report)
+            .context("Failed to serialize benchmark report")?;
+        println!("{data}");
+    } else {
+        status!(
+            "Estimated time to patch a {:.1} GiB OTA: {:.0}s ({:.0}s CPU, {:.0}s I/O, {})",
+            estimate.ota_size as f64 / 1024.0 / 1024.0 / 1024.0,
+            estimate.total_seconds,
+            estimate.cpu_seconds,
+            estimate.io_seconds,
+            match estimate.bound {
+                Resource::Cpu => "CPU-bound",
+                Resource::Io => "I/O-bound",
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Measure the performance of the operations used for patching.
+///
+/// This measures SHA-256 hashing, compression and decompression in each format
+/// that avbroot writes, sequential disk I/O in the temporary directory, and
+/// RSA-4096 signing. The results are used to estimate how long patching a
+/// typical 2.5 GiB OTA takes and whether it is limited by the CPU or by I/O.
+#[derive(Debug, Parser)]
+pub struct BenchCli {
+    /// Amount of test data per benchmark in MiB.
+    #[arg(
+        long,
+        value_name = "MiB",
+        default_value_t = 64,
+        value_parser = value_parser!(u64).range(1..=4096)
+    )]
+    pub size: u64,
+
+    /// Number of measured runs per benchmark.
+    ///
+    /// The median duration is reported.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 3,
+        value_parser = value_parser!(u32).range(1..)
+    )]
+    pub iterations: u32,
+
+    /// Directory for the disk benchmark's temporary file.
+    ///
+    /// The default is the system temporary directory. This should be the same
+    /// directory that is passed to `ota patch --temp-dir`.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Print the results as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
@@ -7,33 +7,108 @@ use std::{
     fs::{self, File},
     io::{self, BufReader, BufWriter, Cursor, Write},
     path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 
 use crate::{
-    format::{avb::Header, bootimage::BootImage, compression::CompressedReader, cpio},
+    boot::{BootImagePatcher, RamdiskCompressionPatcher, RamdiskCompressionTarget},
+    cli::{status, warning},
+    format::{
+        avb::Header,
+        bootimage::{BootContainer, BootImage, OptionalSection},
+        compression::{self, CompressedFormat, CompressedReader},
+        cpio,
+    },
     stream::{FromReader, ToWriter},
+    util,
+    warning::WarningCollector,
 };
 
-fn read_image(path: &Path) -> Result<BootImage> {
-    let file = File::open(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+/// Compression format for patched ramdisks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamdiskCompression {
+    /// Keep the original format.
+    Auto,
+    /// Use the smallest format if it is sufficiently smaller.
+    Smallest,
+    Format(CompressedFormat),
+}
+
+impl RamdiskCompression {
+    pub fn target(self, min_savings_ratio: f64) -> Option<RamdiskCompressionTarget> {
+        match self {
+            Self::Auto => None,
+            Self::Smallest => Some(RamdiskCompressionTarget::Smallest { min_savings_ratio }),
+            Self::Format(f) => Some(RamdiskCompressionTarget::Format(f)),
+        }
+    }
+}
+
+fn parse_optional_section(s: &str) -> Result<OptionalSection> {
+    match s {
+        "second" => Ok(OptionalSection::Second),
+        "recovery_dtbo" => Ok(OptionalSection::RecoveryDtbo),
+        "boot_signature" => Ok(OptionalSection::BootSignature),
+        _ => bail!("Unknown optional section: {s}"),
+    }
+}
+
+pub fn parse_ramdisk_compression(s: &str) -> Result<RamdiskCompression> {
+    let format = match s {
+        "auto" => return Ok(RamdiskCompression::Auto),
+        "smallest" => return Ok(RamdiskCompression::Smallest),
+        "none" => CompressedFormat::None,
+        "gzip" => CompressedFormat::Gzip,
+        "lz4_legacy" => CompressedFormat::Lz4Legacy,
+        "xz" => CompressedFormat::Xz,
+        "lz4" | "zstd" => bail!("{s} ramdisk compression is not supported"),
+        _ => bail!("Unknown ramdisk compression format: {s}"),
+    };
+
+    Ok(RamdiskCompression::Format(format))
+}
+
+pub fn parse_min_savings_ratio(s: &str) -> Result<f64> {
+    let ratio = s
+        .parse::<f64>()
+        .with_context(|| format!("Invalid ratio: {s}"))?;
+
+    if !(0.0..1.0).contains(&ratio) {
+        bail!("Ratio must be at least 0 and less than 1: {s}");
+    }
+
+    Ok(ratio)
+}
+
+fn read_image(path: &Path) -> Result<(BootImage, BootContainer)> {
+    let file = compression::open_standalone(path)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
     let reader = BufReader::new(file);
-    let image = BootImage::from_reader(reader)
+    let (image, container) = BootContainer::load(reader)
         .with_context(|| format!("Failed to read boot image: {path:?}"))?;
 
-    Ok(image)
+    if container != BootContainer::None {
+        status!("Boot image is wrapped in container: {container:?}");
+    }
+
+    Ok((image, container))
 }
 
-fn write_image(path: &Path, image: &BootImage) -> Result<()> {
-    let file =
-        File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
-    let mut writer = BufWriter::new(file);
-    image
-        .to_writer(&mut writer)
+/// Write a boot image. If `path` has a `.gz` or `.xz` extension, the output is
+/// compressed.
+fn write_image(path: &Path, image: &BootImage, container: &BootContainer) -> Result<()> {
+    let mut writer = compression::create_standalone(path, CompressedFormat::from_extension(path))
+        .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+    container
+        .save(&mut writer, image)
         .with_context(|| format!("Failed to write boot image: {path:?}"))?;
-    writer.flush()?;
+    writer
+        .finish()
+        .and_then(|mut w| w.flush())
+        .with_context(|| format!("Failed to flush: {path:?}"))?;
 
     Ok(())
 }
@@ -75,16 +150,24 @@ fn read_text_if_exists(path: &Path) -> Result<Option<String>> {
     Ok(Some(data))
 }
 
-fn read_avb_header_if_exists(path: &Path) -> Result<Option<Header>> {
-    let file = match File::open(path) {
-        Ok(f) => f,
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
-        Err(e) => Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?,
+/// Read one or more concatenated vbmeta headers. GKI 2.0 boot signatures
+/// contain more than one.
+fn read_avb_headers_if_exists(path: &Path) -> Result<Vec<Header>> {
+    let Some(data) = read_data_if_exists(path)? else {
+        return Ok(vec![]);
     };
-    let header = Header::from_reader(BufReader::new(file))
-        .with_context(|| format!("Failed to read vbmeta header: {path:?}"))?;
 
-    Ok(Some(header))
+    let mut reader = Cursor::new(&data);
+    let mut headers = vec![];
+
+    while !util::is_zero(&data[reader.position() as usize..]) {
+        let header = Header::from_reader(&mut reader)
+            .with_context(|| format!("Failed to read vbmeta header: {path:?}"))?;
+
+        headers.push(header);
+    }
+
+    Ok(headers)
 }
 
 fn write_data_if_not_empty(path: &Path, data: &[u8]) -> Result<()> {
@@ -104,10 +187,18 @@ fn write_text_if_not_empty(path: &Path, text: &str) -> Result<()> {
     Ok(())
 }
 
-fn write_avb_header(path: &Path, header: &Header) -> Result<()> {
+fn write_avb_headers<'a>(path: &Path, headers: impl Iterator<Item = &'a Header>) -> Result<()> {
     let file =
         File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
-    header.to_writer(BufWriter::new(file))?;
+    let mut writer = BufWriter::new(file);
+
+    for header in headers {
+        header.to_writer(&mut writer)?;
+    }
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush: {path:?}"))?;
 
     Ok(())
 }
@@ -123,9 +214,13 @@ fn display_info(cli: &BootCli, image: &BootImage) {
 }
 
 fn unpack_subcommand(boot_cli: &BootCli, cli: &UnpackCli) -> Result<()> {
-    let image = read_image(&cli.input)?;
+    let (image, container) = read_image(&cli.input)?;
     display_info(boot_cli, &image);
 
+    if container != BootContainer::None {
+        warning!("Container is not preserved when unpacking. Packed image will be unwrapped");
+    }
+
     write_header(&cli.output_header, &image)?;
 
     let mut kernel = None;
@@ -151,7 +246,9 @@ fn unpack_subcommand(boot_cli: &BootCli, cli: &UnpackCli) -> Result<()> {
         BootImage::V3Through4(b) => {
             kernel = Some(&b.kernel);
             if let Some(v4) = &b.v4_extra {
-                vts_signature = v4.signature.as_ref();
+                if v4.signature.is_some() {
+                    vts_signature = Some(v4);
+                }
             }
             ramdisks.push(&b.ramdisk);
         }
@@ -176,8 +273,8 @@ fn unpack_subcommand(boot_cli: &BootCli, cli: &UnpackCli) -> Result<()> {
     if let Some(data) = dtb {
         write_data_if_not_empty(&cli.output_dtb, data)?;
     }
-    if let Some(header) = vts_signature {
-        write_avb_header(&cli.output_vts_signature, header)?;
+    if let Some(v4) = vts_signature {
+        write_avb_headers(&cli.output_vts_signature, v4.signatures())?;
     }
     if let Some(text) = bootconfig {
         write_text_if_not_empty(&cli.output_bootconfig, text)?;
@@ -193,6 +290,13 @@ fn unpack_subcommand(boot_cli: &BootCli, cli: &UnpackCli) -> Result<()> {
     Ok(())
 }
 
+fn recompute_id(image: &mut BootImage) -> Result<()> {
+    match image {
+        BootImage::V0Through2(b) => b.update_id().context("Failed to compute boot image ID"),
+        _ => bail!("Only v0 through v2 boot images have an ID field"),
+    }
+}
+
 fn pack_subcommand(boot_cli: &BootCli, cli: &PackCli) -> Result<()> {
     let mut image = read_header(&cli.input_header)?;
 
@@ -200,7 +304,7 @@ fn pack_subcommand(boot_cli: &BootCli, cli: &PackCli) -> Result<()> {
     let second = read_data_if_exists(&cli.input_second)?;
     let recovery_dtbo = read_data_if_exists(&cli.input_recovery_dtbo)?;
     let dtb = read_data_if_exists(&cli.input_dtb)?;
-    let vts_signature = read_avb_header_if_exists(&cli.input_vts_signature)?;
+    let vts_signatures = read_avb_headers_if_exists(&cli.input_vts_signature)?;
     let bootconfig = read_text_if_exists(&cli.input_bootconfig)?;
     let mut ramdisks = vec![];
 
@@ -233,7 +337,9 @@ fn pack_subcommand(boot_cli: &BootCli, cli: &PackCli) -> Result<()> {
         BootImage::V3Through4(b) => {
             b.kernel = kernel.unwrap_or_default();
             if let Some(v4) = &mut b.v4_extra {
-                v4.signature = vts_signature;
+                let mut iter = vts_signatures.into_iter();
+                v4.signature = iter.next();
+                v4.extra_signatures = iter.collect();
             }
             if ramdisks.len() > 1 {
                 bail!("Image type only supports a single ramdisk");
@@ -249,31 +355,56 @@ fn pack_subcommand(boot_cli: &BootCli, cli: &PackCli) -> Result<()> {
         }
     }
 
+    if let Some(target) = cli.ramdisk_compression.target(cli.ramdisk_min_savings) {
+        let warnings = WarningCollector::new(|w| warning!("{w}"));
+        let cancel_signal = Arc::new(AtomicBool::new(false));
+
+        RamdiskCompressionPatcher::new(target, |d| status!("{d}"), warnings)
+            .patch(&mut image, &cancel_signal)
+            .with_context(|| format!("Failed to recompress ramdisks: {target:?}"))?;
+    }
+
+    if cli.recompute_id {
+        recompute_id(&mut image)?;
+    }
+
     display_info(boot_cli, &image);
-    write_image(&cli.output, &image)?;
+    write_image(&cli.output, &image, &BootContainer::None)?;
 
     Ok(())
 }
 
 fn repack_subcommand(boot_cli: &BootCli, cli: &RepackCli) -> Result<()> {
-    let image = read_image(&cli.input)?;
+    let (mut image, container) = read_image(&cli.input)?;
+
+    if !cli.strip.is_empty() {
+        let saved = image
+            .strip(&cli.strip)
+            .with_context(|| format!("Failed to strip sections: {:?}", cli.strip))?;
+        status!("Stripping sections saved {saved} bytes");
+    }
+
+    if cli.recompute_id {
+        recompute_id(&mut image)?;
+    }
+
     display_info(boot_cli, &image);
-    write_image(&cli.output, &image)?;
+    write_image(&cli.output, &image, &container)?;
 
     Ok(())
 }
 
 fn info_subcommand(boot_cli: &BootCli, cli: &InfoCli) -> Result<()> {
-    let image = read_image(&cli.input)?;
+    let (image, _) = read_image(&cli.input)?;
     display_info(boot_cli, &image);
 
     Ok(())
 }
 
 pub fn magisk_info_subcommand(cli: &MagiskInfoCli) -> Result<()> {
-    let raw_reader = File::open(&cli.image)
+    let raw_reader = compression::open_standalone(&cli.image)
         .with_context(|| format!("Failed to open for reading: {:?}", cli.image))?;
-    let boot_image = BootImage::from_reader(BufReader::new(raw_reader))
+    let (boot_image, _) = BootContainer::load(BufReader::new(raw_reader))
         .with_context(|| format!("Failed to load boot image: {:?}", cli.image))?;
 
     let mut ramdisks = vec![];
@@ -438,6 +569,42 @@ struct PackCli {
         default_value = "bootconfig.txt"
     )]
     input_bootconfig: PathBuf,
+
+    /// Recompress the ramdisks with a different format.
+    ///
+    /// The format can be auto, smallest, none, gzip, lz4_legacy, or xz. auto
+    /// keeps the ramdisks as they are. smallest picks the format with the
+    /// smallest output, but keeps the original ramdisk if the savings are
+    /// below --ramdisk-min-savings. If the kernel has an embedded config, it is
+    /// checked for the corresponding decompressors.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "auto",
+        value_parser = parse_ramdisk_compression
+    )]
+    ramdisk_compression: RamdiskCompression,
+
+    /// Minimum savings needed for --ramdisk-compression smallest.
+    ///
+    /// This is a ratio of the original ramdisk size. For example, 0.05 means
+    /// that a ramdisk is only recompressed if the result is at least 5%
+    /// smaller.
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value = "0.05",
+        value_parser = parse_min_savings_ratio
+    )]
+    ramdisk_min_savings: f64,
+
+    /// Recompute the header ID from the image sections.
+    ///
+    /// This only applies to v0 through v2 boot images. The ID is computed with
+    /// SHA-256 if the existing ID looks like a SHA-256 digest and SHA-1
+    /// otherwise.
+    #[arg(long)]
+    recompute_id: bool,
 }
 
 /// Repack a boot image.
@@ -450,6 +617,23 @@ struct RepackCli {
     /// Path to output boot image.
     #[arg(short, long, value_name = "FILE", value_parser)]
     output: PathBuf,
+
+    /// Remove an optional section to make the image smaller.
+    ///
+    /// The section can be second, recovery_dtbo, or boot_signature. This can
+    /// be specified multiple times. Required sections, like the kernel and the
+    /// ramdisks, cannot be removed. If the existing v0-v2 header ID is valid,
+    /// it is recomputed.
+    #[arg(long, value_name = "SECTION", value_parser = parse_optional_section)]
+    strip: Vec<OptionalSection>,
+
+    /// Recompute the header ID from the image sections.
+    ///
+    /// This only applies to v0 through v2 boot images. The ID is computed with
+    /// SHA-256 if the existing ID looks like a SHA-256 digest and SHA-1
+    /// otherwise.
+    #[arg(long)]
+    recompute_id: bool,
 }
 
 /// Display boot image header information.
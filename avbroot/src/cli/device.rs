@@ -0,0 +1,1000 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Cursor, Read, Seek, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adb,
+    cli::{
+        misc::{device_command, shell_quote},
+        ota, status, warning, wizard,
+    },
+    fastboot::{self, Fastboot, FastbootConnection},
+    format::sparse,
+    protobuf::chromeos_update_engine::PartitionInfo,
+    stream::{self, HashingWriter, ReadSeek},
+};
+
+/// Directory containing the device's partition block devices.
+const BY_NAME_DIR: &str = "/dev/block/by-name";
+
+/// Partitions that are checked by default if they are in the OTA. These are
+/// the partitions that avbroot may modify. None of them are dynamic partitions,
+/// so they are written directly to the inactive slot, even with Virtual A/B.
+const DEFAULT_PARTITIONS: &[&str] = &[
+    "boot",
+    "init_boot",
+    "recovery",
+    "vendor_boot",
+    "dtbo",
+    "vbmeta",
+    "vbmeta_system",
+    "vbmeta_vendor",
+];
+
+/// update_engine status after an update was installed and the bootloader was
+/// told to switch to the new slot on the next boot.
+const STATUS_NEED_REBOOT: &str = "UPDATED_NEED_REBOOT";
+
+/// Command for querying the update_engine status. `update_engine_client` has no
+/// option for printing the status and exiting. With `--follow`, it prints the
+/// current status right after connecting to update_engine, but then keeps
+/// running until an update finishes, so it is stopped after a few seconds.
+const UPDATE_ENGINE_STATUS_COMMAND: &str = "timeout 5 update_engine_client --follow 2>&1";
+
+/// Name of the manifest written by [`save_stock_images()`].
+const STOCK_MANIFEST_NAME: &str = "stock_images.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StockImage {
+    /// Path of the image, relative to the manifest.
+    file: String,
+    size: u64,
+    sha256: String,
+}
+
+/// List of the images written by [`save_stock_images()`], keyed by partition
+/// name without the slot suffix.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StockManifest {
+    images: BTreeMap<String, StockImage>,
+}
+
+/// Run a command on the device and return its output.
+fn run_command(target: &DeviceGroup, command: &str) -> Result<String> {
+    let mut conn = ota::connect_adb(&target.device, target.adb_key.as_deref())?;
+
+    let output = adb::exec(&mut conn, &device_command(command, target.su), &[])
+        .with_context(|| format!("Failed to run command on device: {command:?}"))?;
+
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Get the suffix of the slot that is currently booted.
+fn active_slot_suffix(target: &DeviceGroup) -> Result<&'static str> {
+    let output = run_command(target, "getprop ro.boot.slot_suffix")?;
+
+    match output.trim() {
+        "_a" => Ok("_a"),
+        "_b" => Ok("_b"),
+        s => bail!("Device does not use A/B slots: ro.boot.slot_suffix={s:?}"),
+    }
+}
+
+/// Get the suffix of the slot that is not currently booted.
+fn inactive_slot_suffix(target: &DeviceGroup) -> Result<&'static str> {
+    match active_slot_suffix(target)? {
+        "_a" => Ok("_b"),
+        _ => Ok("_a"),
+    }
+}
+
+/// Parse the status name from the output of `update_engine_client --follow`,
+/// which logs a line like `onStatusUpdate(UPDATED_NEED_REBOOT (6), 1)` for each
+/// status change. The last status is returned.
+pub fn parse_update_engine_status(output: &str) -> Option<&str> {
+    let (_, rest) = output.rsplit_once("onStatusUpdate(")?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_uppercase() && c != '_')
+        .unwrap_or(rest.len());
+
+    Some(&rest[..end]).filter(|s| !s.is_empty())
+}
+
+/// Parse the digest from the output of `sha256sum`.
+fn parse_sha256sum(output: &str) -> Option<String> {
+    let digest = output.split_whitespace().next()?;
+
+    if digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(digest.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Compute the SHA-256 digest of the first `size` bytes of a block device.
+fn device_digest(target: &DeviceGroup, path: &str, size: u64, block_size: u32) -> Result<String> {
+    let command = format!(
+        "dd if={} bs={block_size} count={} 2>/dev/null | sha256sum",
+        shell_quote(path),
+        size / u64::from(block_size),
+    );
+    let output = run_command(target, &command)?;
+
+    parse_sha256sum(&output)
+        .ok_or_else(|| anyhow!("Failed to compute digest of {path:?} on device: {output:?}"))
+}
+
+/// Get the size of a block device.
+fn device_size(target: &DeviceGroup, path: &str) -> Result<u64> {
+    let output = run_command(
+        target,
+        &format!("blockdev --getsize64 {}", shell_quote(path)),
+    )?;
+
+    output
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Failed to get size of {path:?} on device: {output:?}"))
+}
+
+/// Write `data` to the start of a block device. The exec service never reports
+/// the exit status, so the caller must read the data back to check that it was
+/// written.
+fn write_device(target: &DeviceGroup, path: &str, data: &[u8]) -> Result<()> {
+    let mut conn = ota::connect_adb(&target.device, target.adb_key.as_deref())?;
+
+    // Unlike dd, head does not stop early if the socket returns short reads.
+    let command = format!("head -c {} > {} && sync", data.len(), shell_quote(path));
+    adb::exec(&mut conn, &device_command(&command, target.su), data)
+        .with_context(|| format!("Failed to write {path:?} on device"))?;
+
+    Ok(())
+}
+
+/// Save unmodified copies of the images in `input_streams` listed in `names`
+/// to `dir` for restoring with `avbroot device restore`. The images are written
+/// as-is, including their original AVB footers. The streams are rewound
+/// afterwards.
+pub fn save_stock_images<'a>(
+    dir: &Path,
+    input_streams: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
+    names: impl IntoIterator<Item = &'a str>,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create directory: {dir:?}"))?;
+
+    let mut manifest = StockManifest::default();
+
+    for name in names {
+        let reader = input_streams
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Image was not extracted: {name}"))?;
+        let file_name = format!("{name}.img");
+        let path = dir.join(&file_name);
+
+        status!("Saving stock image: {path:?}");
+
+        let file =
+            File::create(&path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
+        let mut writer = HashingWriter::new(
+            BufWriter::new(file),
+            ring::digest::Context::new(&ring::digest::SHA256),
+        );
+
+        reader.rewind()?;
+        let size = stream::copy(&mut *reader, &mut writer, cancel_signal)
+            .and_then(|size| writer.flush().map(|_| size))
+            .with_context(|| format!("Failed to write stock image: {path:?}"))?;
+        reader.rewind()?;
+
+        let (_, context) = writer.finish();
+
+        manifest.images.insert(
+            name.to_owned(),
+            StockImage {
+                file: file_name,
+                size,
+                sha256: hex::encode(context.finish()),
+            },
+        );
+    }
+
+    let path = dir.join(STOCK_MANIFEST_NAME);
+    let data = toml_edit::ser::to_string_pretty(&manifest)
+        .with_context(|| format!("Failed to serialize manifest: {path:?}"))?;
+    fs::write(&path, data).with_context(|| format!("Failed to write manifest: {path:?}"))?;
+
+    Ok(())
+}
+
+fn verify_staged_subcommand(cli: &VerifyStagedCli) -> Result<()> {
+    let (_, _, _, header) = ota::open_ota_payload(&cli.input)?;
+    let block_size = header.manifest.block_size;
+
+    let all_partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect::<BTreeSet<_>>();
+
+    let names = if cli.partition.is_empty() {
+        DEFAULT_PARTITIONS
+            .iter()
+            .filter(|p| all_partitions.contains(*p))
+            .map(|p| p.to_string())
+            .collect::<BTreeSet<_>>()
+    } else {
+        cli.partition.iter().cloned().collect()
+    };
+    if names.is_empty() {
+        bail!("OTA contains none of the default partitions to check");
+    }
+
+    let suffix = inactive_slot_suffix(&cli.device)?;
+    status!("Checking inactive slot: {suffix}");
+
+    let mut mismatched = vec![];
+
+    for name in &names {
+        let partition = header
+            .manifest
+            .partitions
+            .iter()
+            .find(|p| &p.partition_name == name)
+            .ok_or_else(|| anyhow!("Partition not found in OTA: {name}"))?;
+        let Some(PartitionInfo {
+            size: Some(size),
+            hash: Some(hash),
+        }) = &partition.new_partition_info
+        else {
+            bail!("OTA does not list the size and digest of partition: {name}");
+        };
+        if size % u64::from(block_size) != 0 {
+            bail!("Size of {name} ({size}) is not aligned to the block size ({block_size})");
+        }
+
+        let path = format!("{BY_NAME_DIR}/{name}{suffix}");
+        let expected = hex::encode(hash);
+        let actual = device_digest(&cli.device, &path, *size, block_size)?;
+
+        if actual == expected {
+            status!("{name}{suffix}: OK ({actual})");
+        } else {
+            warning!("{name}{suffix}: Expected {expected}, but have {actual}");
+            mismatched.push(format!("{name}{suffix}"));
+        }
+    }
+
+    let output = run_command(&cli.device, UPDATE_ENGINE_STATUS_COMMAND)?;
+    let update_status = parse_update_engine_status(&output)
+        .ok_or_else(|| anyhow!("Failed to parse update_engine status: {output:?}"))?;
+    let armed = update_status == STATUS_NEED_REBOOT;
+
+    if armed {
+        status!("update_engine status: {update_status}");
+    } else {
+        warning!("update_engine status: {update_status}: Slot switch is not armed");
+    }
+
+    if !mismatched.is_empty() {
+        bail!("Partitions do not match the OTA: {}", mismatched.join(", "));
+    } else if !armed {
+        bail!("update_engine is not waiting for a reboot into the new slot");
+    }
+
+    status!("Inactive slot matches the OTA");
+
+    Ok(())
+}
+
+fn restore_subcommand(cli: &RestoreCli) -> Result<()> {
+    let manifest_path = cli.dir.join(STOCK_MANIFEST_NAME);
+    let data = fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Failed to read manifest: {manifest_path:?}"))?;
+    let manifest: StockManifest = toml_edit::de::from_str(&data)
+        .with_context(|| format!("Failed to parse manifest: {manifest_path:?}"))?;
+
+    let names = if cli.partition.is_empty() {
+        manifest.images.keys().cloned().collect::<BTreeSet<_>>()
+    } else {
+        cli.partition.iter().cloned().collect()
+    };
+    if names.is_empty() {
+        bail!("No images to restore");
+    }
+
+    // Check every image before anything is flashed so that an incomplete
+    // backup can't leave the device with a mix of stock and patched images.
+    let mut images = vec![];
+
+    for name in &names {
+        let image = manifest
+            .images
+            .get(name)
+            .ok_or_else(|| anyhow!("Image not found in manifest: {name}"))?;
+        if Path::new(&image.file).file_name() != Some(OsStr::new(&image.file)) {
+            bail!("Unsafe image path in manifest: {:?}", image.file);
+        }
+
+        let path = cli.dir.join(&image.file);
+        let data = fs::read(&path).with_context(|| format!("Failed to read: {path:?}"))?;
+        let digest = hex::encode(ring::digest::digest(&ring::digest::SHA256, &data));
+
+        if data.len() as u64 != image.size || digest != image.sha256 {
+            bail!("Image does not match manifest: {path:?}");
+        }
+
+        images.push((name, image, data));
+    }
+
+    // The stock images are signed with the OEM's key, not the custom key
+    // that the bootloader trusts.
+    let device_state = run_command(&cli.device, "getprop ro.boot.vbmeta.device_state")?;
+    if device_state.trim() == "locked" {
+        bail!("Stock images cannot be flashed while the bootloader is locked");
+    }
+
+    let suffix = if cli.inactive_slot {
+        inactive_slot_suffix(&cli.device)?
+    } else {
+        active_slot_suffix(&cli.device)?
+    };
+    status!("Restoring to slot: {suffix}");
+
+    for (name, image, _) in &images {
+        let path = format!("{BY_NAME_DIR}/{name}{suffix}");
+        let size = device_size(&cli.device, &path)?;
+
+        if image.size > size {
+            bail!(
+                "{name} image ({} bytes) is larger than {path:?} ({size} bytes)",
+                image.size,
+            );
+        }
+    }
+
+    let mut mismatched = vec![];
+
+    for (name, image, data) in &images {
+        let path = format!("{BY_NAME_DIR}/{name}{suffix}");
+        status!("Flashing {name}{suffix}");
+
+        write_device(&cli.device, &path, data)?;
+
+        let block_size = [4096, 512]
+            .into_iter()
+            .find(|b| image.size % u64::from(*b) == 0)
+            .unwrap_or(1);
+        let actual = device_digest(&cli.device, &path, image.size, block_size)?;
+
+        if actual == image.sha256 {
+            status!("{name}{suffix}: OK ({actual})");
+        } else {
+            warning!(
+                "{name}{suffix}: Expected {}, but have {actual}",
+                image.sha256
+            );
+            mismatched.push(format!("{name}{suffix}"));
+        }
+    }
+
+    if !mismatched.is_empty() {
+        bail!(
+            "Partitions do not match the stock images: {}",
+            mismatched.join(", ")
+        );
+    }
+
+    status!("Restored stock images to slot: {suffix}");
+
+    Ok(())
+}
+
+/// Step of a `device flash` plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlashAction {
+    Erase(String),
+    Flash {
+        partition: String,
+        path: PathBuf,
+    },
+    /// Slot name without the leading underscore.
+    SetActive(String),
+    Reboot,
+}
+
+impl fmt::Display for FlashAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Erase(partition) => write!(f, "erase {partition}"),
+            Self::Flash { partition, path } => write!(f, "flash {partition} from {path:?}"),
+            Self::SetActive(slot) => write!(f, "set active slot to {slot}"),
+            Self::Reboot => write!(f, "reboot"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FlashOptions {
+    /// Slot to flash, with or without the leading underscore. The default is
+    /// the device's current slot.
+    pub slot: Option<String>,
+    /// Images to flash. The default is every image in the directory.
+    pub partitions: Vec<String>,
+    /// `avb_pkmd.bin` to flash to the `avb_custom_key` partition.
+    pub avb_custom_key: Option<PathBuf>,
+    pub set_active: bool,
+    pub reboot: bool,
+}
+
+/// Partition that holds the custom AVB public key on Pixel devices.
+const AVB_CUSTOM_KEY_PARTITION: &str = "avb_custom_key";
+
+/// Block size of the sparse images that an image is split into if it does not
+/// fit in the device's download buffer. This matches the fastboot tool.
+const SPARSE_BLOCK_SIZE: u32 = 4096;
+
+/// Maximum number of bytes to read back from the device at a time.
+const FETCH_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+fn file_size(path: &Path) -> Result<u64> {
+    let metadata = fs::metadata(path).with_context(|| format!("Failed to stat: {path:?}"))?;
+
+    Ok(metadata.len())
+}
+
+fn is_sparse_image(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open: {path:?}"))?;
+    let mut magic = [0u8; 4];
+
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(u32::from_le_bytes(magic) == sparse::SPARSE_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to read: {path:?}")),
+    }
+}
+
+/// Build the list of actions for flashing the `<name>.img` files in `dir`.
+/// Only read-only commands are sent to the device. Images are flashed to the
+/// selected slot if the partition is slotted. Names that already end in `_a`
+/// or `_b`, like the output of `--ab-images`, are flashed as-is. Images that
+/// don't fit in the device's download buffer are split into sparse images by
+/// [`execute_flash()`].
+pub fn plan_flash(
+    device: &mut dyn Fastboot,
+    dir: &Path,
+    options: &FlashOptions,
+) -> Result<Vec<FlashAction>> {
+    // Not every bootloader reports this. Flashing will fail anyway if it is
+    // actually locked.
+    if let Ok(unlocked) = device.getvar("unlocked") {
+        if unlocked.trim() == "no" {
+            bail!("Bootloader is locked");
+        }
+    }
+
+    let mut images = BTreeMap::new();
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to list directory: {dir:?}"))? {
+        let entry = entry.with_context(|| format!("Failed to list directory: {dir:?}"))?;
+        let path = entry.path();
+
+        if !path.is_file() || path.extension() != Some(OsStr::new("img")) {
+            continue;
+        }
+
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            images.insert(name.to_owned(), path.to_owned());
+        }
+    }
+
+    if !options.partitions.is_empty() {
+        let missing = options
+            .partitions
+            .iter()
+            .filter(|p| !images.contains_key(*p))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!("Images not found in {dir:?}: {missing:?}");
+        }
+
+        images.retain(|name, _| options.partitions.contains(name));
+    }
+
+    if images.is_empty() && options.avb_custom_key.is_none() {
+        bail!("No images to flash in {dir:?}");
+    }
+
+    let slot = match &options.slot {
+        Some(s) => Some(s.clone()),
+        None => device.getvar("current-slot").ok(),
+    }
+    .map(|s| s.trim().trim_start_matches('_').to_owned())
+    .filter(|s| !s.is_empty());
+
+    let max_download_size = device
+        .getvar("max-download-size")
+        .ok()
+        .and_then(|v| fastboot::parse_int(&v));
+
+    let mut actions = vec![];
+
+    if let Some(path) = &options.avb_custom_key {
+        // fastbootd refuses to touch the key, which is only writable from the
+        // bootloader.
+        if device
+            .getvar("is-userspace")
+            .map_or(false, |v| v.trim() == "yes")
+        {
+            bail!("{AVB_CUSTOM_KEY_PARTITION} can only be flashed from the bootloader");
+        }
+
+        actions.push(FlashAction::Erase(AVB_CUSTOM_KEY_PARTITION.to_owned()));
+        actions.push(FlashAction::Flash {
+            partition: AVB_CUSTOM_KEY_PARTITION.to_owned(),
+            path: path.clone(),
+        });
+    }
+
+    for (name, path) in images {
+        let partition = if name.ends_with("_a") || name.ends_with("_b") {
+            name
+        } else {
+            let has_slot = device
+                .getvar(&format!("has-slot:{name}"))
+                .map_or(false, |v| v.trim() == "yes");
+
+            match (&slot, has_slot) {
+                (Some(s), true) => format!("{name}_{s}"),
+                (None, true) => bail!("Cannot determine slot for partition: {name}"),
+                (_, false) => name,
+            }
+        };
+
+        actions.push(FlashAction::Flash { partition, path });
+    }
+
+    for action in &actions {
+        let FlashAction::Flash { partition, path } = action else {
+            continue;
+        };
+        let size = file_size(path)?;
+
+        if let Some(max_size) = max_download_size {
+            if size > max_size {
+                if is_sparse_image(path)? {
+                    bail!(
+                        "{path:?} ({size} bytes) is a sparse image that exceeds \
+                        max download size ({max_size} bytes)"
+                    );
+                } else if max_size < sparse::RAW_SEGMENT_OVERHEAD + u64::from(SPARSE_BLOCK_SIZE) {
+                    bail!(
+                        "{path:?} ({size} bytes) exceeds max download size ({max_size} bytes), \
+                        which is too small to split the image"
+                    );
+                }
+            }
+        }
+
+        let partition_size = device
+            .getvar(&format!("partition-size:{partition}"))
+            .ok()
+            .and_then(|v| fastboot::parse_int(&v));
+
+        if let Some(partition_size) = partition_size {
+            if size > partition_size {
+                bail!("{path:?} ({size} bytes) exceeds {partition} ({partition_size} bytes)");
+            }
+        }
+    }
+
+    if options.set_active {
+        let slot = slot.ok_or_else(|| anyhow!("Cannot determine slot to set as active"))?;
+        actions.push(FlashAction::SetActive(slot));
+    }
+
+    if options.reboot {
+        actions.push(FlashAction::Reboot);
+    }
+
+    Ok(actions)
+}
+
+/// Send and flash an image. If the image is larger than `max_download_size`,
+/// it is split into sparse images that each contain the raw data for a range
+/// of blocks. The image is streamed from disk in either case.
+fn flash_image(
+    device: &mut dyn Fastboot,
+    partition: &str,
+    path: &Path,
+    size: u64,
+    max_download_size: Option<u64>,
+) -> Result<()> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open: {path:?}"))?;
+
+    let Some(max_size) = max_download_size.filter(|s| size > *s) else {
+        device
+            .download(&mut file, size)
+            .with_context(|| format!("Failed to send image: {path:?}"))?;
+        device
+            .flash(partition)
+            .with_context(|| format!("Failed to flash: {partition}"))?;
+
+        return Ok(());
+    };
+
+    let block_size = u64::from(SPARSE_BLOCK_SIZE);
+    let total_blocks = u32::try_from((size + block_size - 1) / block_size)
+        .with_context(|| format!("{path:?} is too large to split: {size} bytes"))?;
+    // Downloads are limited to 32-bit sizes anyway.
+    let segment_blocks = max_size
+        .min(u32::MAX.into())
+        .checked_sub(sparse::RAW_SEGMENT_OVERHEAD)
+        .map(|s| (s / block_size) as u32)
+        .filter(|b| *b > 0)
+        .ok_or_else(|| anyhow!("Max download size is too small to split image: {max_size}"))?;
+    let num_segments =
+        (u64::from(total_blocks) + u64::from(segment_blocks) - 1) / u64::from(segment_blocks);
+    let mut start = 0;
+
+    for i in 0..num_segments {
+        let num_blocks = segment_blocks.min(total_blocks - start);
+        let (before, after) =
+            sparse::raw_segment(SPARSE_BLOCK_SIZE, total_blocks, start, num_blocks)?;
+
+        // The last block is padded with zeros.
+        let raw_size = u64::from(num_blocks) * block_size;
+        let data_size = raw_size.min(size - u64::from(start) * block_size);
+        let segment_size = before.len() as u64 + raw_size + after.len() as u64;
+
+        let mut reader = Cursor::new(before)
+            .chain((&mut file).take(data_size))
+            .chain(io::repeat(0).take(raw_size - data_size))
+            .chain(Cursor::new(after));
+
+        status!("{partition}: Sending sparse image {}/{num_segments}", i + 1);
+
+        device
+            .download(&mut reader, segment_size)
+            .with_context(|| format!("Failed to send image: {path:?}"))?;
+        device
+            .flash(partition)
+            .with_context(|| format!("Failed to flash: {partition}"))?;
+
+        start += num_blocks;
+    }
+
+    Ok(())
+}
+
+/// Read back the first `size` bytes of a partition and compare them against
+/// the image. Both sides are hashed in chunks, so neither needs to fit in
+/// memory. Returns `false` if the device does not support reading partitions.
+fn verify_image(
+    device: &mut dyn Fastboot,
+    partition: &str,
+    path: &Path,
+    size: u64,
+) -> Result<bool> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open: {path:?}"))?;
+    let mut expected = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut actual = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0u8; size.min(FETCH_CHUNK_SIZE) as usize];
+    let mut offset = 0;
+
+    while offset < size {
+        let n = (size - offset).min(FETCH_CHUNK_SIZE);
+        let Some(data) = device
+            .fetch(partition, offset, n)
+            .with_context(|| format!("Failed to read back: {partition}"))?
+        else {
+            return Ok(false);
+        };
+
+        file.read_exact(&mut buf[..n as usize])
+            .with_context(|| format!("Failed to read: {path:?}"))?;
+        expected.update(&buf[..n as usize]);
+        actual.update(&data);
+
+        offset += n;
+    }
+
+    let expected = expected.finish();
+    let actual = actual.finish();
+
+    if expected.as_ref() != actual.as_ref() {
+        bail!(
+            "{partition}: Expected {}, but have {}",
+            hex::encode(expected),
+            hex::encode(actual),
+        );
+    }
+
+    status!("{partition}: OK ({})", hex::encode(actual));
+
+    Ok(true)
+}
+
+/// Run the actions from [`plan_flash()`]. Each flashed partition is read back
+/// and compared against the image if the device supports it. Returns the list
+/// of partitions that could not be verified.
+pub fn execute_flash(device: &mut dyn Fastboot, actions: &[FlashAction]) -> Result<Vec<String>> {
+    let max_download_size = device
+        .getvar("max-download-size")
+        .ok()
+        .and_then(|v| fastboot::parse_int(&v));
+    let mut unverified = vec![];
+
+    for action in actions {
+        status!("Running: {action}");
+
+        match action {
+            FlashAction::Erase(partition) => device
+                .erase(partition)
+                .with_context(|| format!("Failed to erase: {partition}"))?,
+            FlashAction::Flash { partition, path } => {
+                let size = file_size(path)?;
+
+                flash_image(device, partition, path, size, max_download_size)?;
+
+                if !verify_image(device, partition, path, size)? {
+                    warning!("{partition}: Device does not support reading back partitions");
+                    unverified.push(partition.clone());
+                }
+            }
+            FlashAction::SetActive(slot) => device
+                .set_active(slot)
+                .with_context(|| format!("Failed to set active slot: {slot}"))?,
+            FlashAction::Reboot => device.reboot().context("Failed to reboot")?,
+        }
+    }
+
+    Ok(unverified)
+}
+
+#[cfg(target_os = "linux")]
+fn connect_fastboot_usb(serial: Option<&str>) -> Result<Box<dyn Fastboot>> {
+    status!("Connecting to USB device: {}", serial.unwrap_or("<any>"));
+
+    let transport =
+        fastboot::UsbTransport::open(serial).context("Failed to open fastboot USB device")?;
+
+    Ok(Box::new(FastbootConnection::new(transport)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_fastboot_usb(_serial: Option<&str>) -> Result<Box<dyn Fastboot>> {
+    bail!("Fastboot over USB is only supported on Linux");
+}
+
+/// Connect to a device with an address in the form `usb[:<serial>]` or
+/// `<host>[:<port>]`.
+fn connect_fastboot(device: &str) -> Result<Box<dyn Fastboot>> {
+    if let Some(serial) = device
+        .strip_prefix("usb")
+        .filter(|s| s.is_empty() || s.starts_with(':'))
+    {
+        return connect_fastboot_usb(serial.strip_prefix(':'));
+    }
+
+    let (host, port) = ota::parse_device_address(device, fastboot::FASTBOOT_DEFAULT_PORT);
+    status!("Connecting to {host} port {port}");
+
+    let stream = TcpStream::connect((host, port))
+        .with_context(|| format!("Failed to connect to device: {device}"))?;
+    stream
+        .set_nodelay(true)
+        .context("Failed to disable Nagle's algorithm")?;
+
+    let conn = FastbootConnection::connect(stream)
+        .with_context(|| format!("Failed to connect to fastboot: {device}"))?;
+
+    Ok(Box::new(conn))
+}
+
+fn flash_subcommand(cli: &FlashCli) -> Result<()> {
+    let mut conn = connect_fastboot(&cli.device)?;
+
+    let options = FlashOptions {
+        slot: cli.slot.clone(),
+        partitions: cli.partition.clone(),
+        avb_custom_key: cli.avb_custom_key.clone(),
+        set_active: cli.set_active,
+        reboot: cli.reboot,
+    };
+    let actions = plan_flash(&mut *conn, &cli.images, &options)?;
+
+    status!("Planned actions:");
+    for action in &actions {
+        println!("  {action}");
+    }
+
+    if cli.dry_run {
+        return Ok(());
+    }
+
+    if !cli.yes && !wizard::prompt_yes_no("Flash the device now?", false)? {
+        bail!("Flashing cancelled");
+    }
+
+    let unverified = execute_flash(&mut *conn, &actions)?;
+    if !unverified.is_empty() {
+        warning!("Partitions were not verified: {}", unverified.join(", "));
+    }
+
+    status!("Flashed all images");
+
+    Ok(())
+}
+
+pub fn device_main(cli: &DeviceCli) -> Result<()> {
+    match &cli.command {
+        DeviceCommand::VerifyStaged(c) => verify_staged_subcommand(c),
+        DeviceCommand::Restore(c) => restore_subcommand(c),
+        DeviceCommand::Flash(c) => flash_subcommand(c),
+    }
+}
+
+#[derive(Debug, Args)]
+struct DeviceGroup {
+    /// Address of the device in the form <host>[:<port>].
+    ///
+    /// The default port is 5555. adbd must allow shell access. Reading the
+    /// partitions and querying update_engine normally requires --su.
+    #[arg(short, long, value_name = "ADDRESS")]
+    device: String,
+
+    /// ADB private key for authenticating with the device.
+    ///
+    /// The default is ~/.android/adbkey if it exists.
+    #[arg(long, value_name = "FILE", value_parser)]
+    adb_key: Option<PathBuf>,
+
+    /// Run the commands on the device with su.
+    #[arg(long)]
+    su: bool,
+}
+
+/// Verify the inactive slot after installing an OTA, before rebooting.
+///
+/// The partitions in the inactive slot are hashed on the device and compared
+/// against the digests in the OTA's payload. The status of update_engine is
+/// also checked to make sure that the device will switch to the inactive slot
+/// on the next boot. The device is not modified.
+#[derive(Debug, Parser)]
+struct VerifyStagedCli {
+    /// Path to the patched OTA zip that was installed.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    #[command(flatten)]
+    device: DeviceGroup,
+
+    /// Partition to check.
+    ///
+    /// This option can be specified multiple times. The default is every
+    /// partition in the OTA that avbroot may modify.
+    #[arg(short, long, value_name = "PARTITION")]
+    partition: Vec<String>,
+}
+
+/// Flash stock images saved by `ota patch --save-stock-images`.
+///
+/// This is useful for temporarily unrooting without downloading the OTA
+/// again. The images are written to the active slot over ADB and then read
+/// back to verify their digests. This requires root access on the device and
+/// an unlocked bootloader because the stock images are not signed with the
+/// custom AVB key.
+#[derive(Debug, Parser)]
+struct RestoreCli {
+    /// Directory containing the stock images and their manifest.
+    #[arg(long, value_name = "DIR", value_parser)]
+    dir: PathBuf,
+
+    #[command(flatten)]
+    device: DeviceGroup,
+
+    /// Partition to restore.
+    ///
+    /// This option can be specified multiple times. The default is every image
+    /// in the manifest.
+    #[arg(short, long, value_name = "PARTITION")]
+    partition: Vec<String>,
+
+    /// Restore to the inactive slot instead of the active slot.
+    #[arg(long)]
+    inactive_slot: bool,
+}
+
+/// Flash patched images to a device in fastboot mode.
+///
+/// Every <name>.img file in the directory is flashed to the current slot, or
+/// the slot specified by --slot, if the partition is slotted. Files that
+/// already have an _a or _b suffix are flashed as-is. The plan is printed and
+/// must be confirmed before anything is written. After each image is flashed,
+/// it is read back and compared if the device supports fetching partitions.
+/// Images that don't fit in the device's download buffer are split into sparse
+/// images.
+///
+/// Fastboot over USB is only supported on Linux and requires write access to
+/// the device node in /dev/bus/usb. Fastboot over TCP is available in
+/// fastbootd and in some bootloaders.
+#[derive(Debug, Parser)]
+struct FlashCli {
+    /// Directory containing the images to flash.
+    #[arg(long, value_name = "DIR", value_parser)]
+    images: PathBuf,
+
+    /// Address of the device in the form usb[:<serial>] or <host>[:<port>].
+    ///
+    /// The default is the only fastboot device connected over USB. The default
+    /// TCP port is 5554.
+    #[arg(short, long, value_name = "ADDRESS", default_value = "usb")]
+    device: String,
+
+    /// Slot to flash instead of the current slot.
+    #[arg(long, value_name = "SLOT", value_parser = ["a", "b", "_a", "_b"])]
+    slot: Option<String>,
+
+    /// Partition to flash.
+    ///
+    /// This option can be specified multiple times. The default is every image
+    /// in the directory.
+    #[arg(short, long, value_name = "PARTITION")]
+    partition: Vec<String>,
+
+    /// Erase avb_custom_key and flash this public key (avb_pkmd.bin) to it.
+    ///
+    /// This is only needed the first time the device is set up with a custom
+    /// AVB key and only works in the bootloader, not fastbootd. Bootloaders
+    /// usually only support fastboot over USB.
+    #[arg(long, value_name = "FILE", value_parser)]
+    avb_custom_key: Option<PathBuf>,
+
+    /// Mark the flashed slot as active.
+    #[arg(long)]
+    set_active: bool,
+
+    /// Reboot the device after flashing.
+    #[arg(long)]
+    reboot: bool,
+
+    /// Print the plan without flashing anything.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Don't ask for confirmation before flashing.
+    #[arg(short, long)]
+    yes: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum DeviceCommand {
+    VerifyStaged(VerifyStagedCli),
+    Restore(RestoreCli),
+    Flash(FlashCli),
+}
+
+/// Inspect, restore, or flash a device over ADB or fastboot.
+#[derive(Debug, Parser)]
+pub struct DeviceCli {
+    #[command(subcommand)]
+    command: DeviceCommand,
+}
@@ -0,0 +1,186 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    format::dtbo::DtboImage,
+    stream::{FromReader, ToWriter},
+};
+
+fn read_image(path: &Path) -> Result<DtboImage> {
+    let file = File::open(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let image = DtboImage::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to read DTBO image: {path:?}"))?;
+
+    Ok(image)
+}
+
+fn write_image(path: &Path, image: &DtboImage) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
+    let mut writer = BufWriter::new(file);
+    image
+        .to_writer(&mut writer)
+        .with_context(|| format!("Failed to write DTBO image: {path:?}"))?;
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush: {path:?}"))?;
+
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<DtboImage> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest TOML: {path:?}"))?;
+    let image = toml_edit::de::from_str(&data)
+        .with_context(|| format!("Failed to parse manifest TOML: {path:?}"))?;
+
+    Ok(image)
+}
+
+fn write_manifest(path: &Path, image: &DtboImage) -> Result<()> {
+    let data = toml_edit::ser::to_string_pretty(image)
+        .with_context(|| format!("Failed to serialize manifest TOML: {path:?}"))?;
+    fs::write(path, data).with_context(|| format!("Failed to write manifest TOML: {path:?}"))?;
+
+    Ok(())
+}
+
+fn entry_path(prefix: &Path, index: usize) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(format!("{index}.dtb"));
+    path.into()
+}
+
+fn display_info(cli: &DtboCli, image: &DtboImage) {
+    if !cli.quiet {
+        if cli.debug {
+            println!("{image:#?}");
+        } else {
+            print!("{image}");
+        }
+    }
+}
+
+fn unpack_subcommand(dtbo_cli: &DtboCli, cli: &UnpackCli) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    display_info(dtbo_cli, &image);
+
+    write_manifest(&cli.output_manifest, &image)?;
+
+    for (i, entry) in image.entries.iter().enumerate() {
+        let path = entry_path(&cli.output_entry_prefix, i);
+        fs::write(&path, &entry.data)
+            .with_context(|| format!("Failed to write device tree: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn pack_subcommand(dtbo_cli: &DtboCli, cli: &PackCli) -> Result<()> {
+    let mut image = read_manifest(&cli.input_manifest)?;
+
+    for (i, entry) in image.entries.iter_mut().enumerate() {
+        let path = entry_path(&cli.input_entry_prefix, i);
+        entry.data =
+            fs::read(&path).with_context(|| format!("Failed to read device tree: {path:?}"))?;
+    }
+
+    display_info(dtbo_cli, &image);
+    write_image(&cli.output, &image)?;
+
+    Ok(())
+}
+
+fn info_subcommand(dtbo_cli: &DtboCli, cli: &InfoCli) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    display_info(dtbo_cli, &image);
+
+    Ok(())
+}
+
+pub fn dtbo_main(cli: &DtboCli) -> Result<()> {
+    match &cli.command {
+        DtboCommand::Unpack(c) => unpack_subcommand(cli, c),
+        DtboCommand::Pack(c) => pack_subcommand(cli, c),
+        DtboCommand::Info(c) => info_subcommand(cli, c),
+    }
+}
+
+/// Unpack a DTBO image.
+///
+/// Each device tree is written to <prefix><index>.dtb. The remaining fields
+/// are written to the manifest TOML. A vbmeta footer, if present, is ignored.
+#[derive(Debug, Parser)]
+struct UnpackCli {
+    /// Path to input DTBO image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output manifest TOML.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "dtbo.toml")]
+    output_manifest: PathBuf,
+
+    /// Path prefix for output device tree blobs.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "entry.")]
+    output_entry_prefix: PathBuf,
+}
+
+/// Pack a DTBO image.
+///
+/// The entries listed in the manifest TOML are packed in order. The device
+/// tree offsets, sizes, and the total size are recomputed.
+#[derive(Debug, Parser)]
+struct PackCli {
+    /// Path to output DTBO image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Path to input manifest TOML.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "dtbo.toml")]
+    input_manifest: PathBuf,
+
+    /// Path prefix for input device tree blobs.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "entry.")]
+    input_entry_prefix: PathBuf,
+}
+
+/// Display DTBO image information.
+#[derive(Debug, Parser)]
+struct InfoCli {
+    /// Path to input DTBO image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+enum DtboCommand {
+    Unpack(UnpackCli),
+    Pack(PackCli),
+    Info(InfoCli),
+}
+
+/// Pack or unpack device tree overlay (DTBO) images.
+#[derive(Debug, Parser)]
+pub struct DtboCli {
+    #[command(subcommand)]
+    command: DtboCommand,
+
+    /// Don't print DTBO image information.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Print DTBO image information in debug format.
+    #[arg(short, long, global = true)]
+    debug: bool,
+}
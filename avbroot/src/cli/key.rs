@@ -7,18 +7,19 @@ use std::{
     ffi::OsString,
     fs,
     path::{Path, PathBuf},
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Args, Parser, Subcommand};
 
 use crate::{
     crypto::{self, PassphraseSource},
     format::avb,
+    util,
 };
 
-fn get_passphrase(group: &PassphraseGroup, key_path: &Path) -> PassphraseSource {
+pub(crate) fn get_passphrase(group: &PassphraseGroup, key_path: &Path) -> PassphraseSource {
     if let Some(v) = &group.pass_env_var {
         PassphraseSource::EnvVar(v.clone())
     } else if let Some(p) = &group.pass_file {
@@ -43,9 +44,37 @@ pub fn key_main(cli: &KeyCli) -> Result<()> {
             let private_key = crypto::read_pem_key_file(&c.key, &passphrase)
                 .with_context(|| format!("Failed to load key: {:?}", c.key))?;
 
-            let validity = Duration::from_secs(c.validity * 24 * 60 * 60);
-            let cert = crypto::generate_cert(&private_key, rand::random(), validity, &c.subject)
-                .context("Failed to generate certificate")?;
+            let not_before = match c.not_before {
+                Some(t) => UNIX_EPOCH + Duration::from_secs(t),
+                None if c.reproducible && !util::is_clock_fixed() => {
+                    bail!(
+                        "--reproducible requires --not-before or {} to be set",
+                        util::SOURCE_DATE_EPOCH_ENV,
+                    );
+                }
+                None => util::now().context("Failed to get current time")?,
+            };
+            let not_after = match c.not_after {
+                Some(t) => UNIX_EPOCH + Duration::from_secs(t),
+                None => not_before + Duration::from_secs(c.validity * 24 * 60 * 60),
+            };
+            let serial = if let Some(s) = c.serial {
+                s
+            } else if c.reproducible {
+                crypto::serial_from_public_key(&private_key.to_public_key())
+                    .context("Failed to derive serial number")?
+            } else {
+                rand::random()
+            };
+
+            let cert = crypto::generate_cert_with_validity(
+                &private_key,
+                serial,
+                not_before,
+                not_after,
+                &c.subject,
+            )
+            .context("Failed to generate certificate")?;
 
             crypto::write_pem_cert_file(&c.output, &cert)
                 .with_context(|| format!("Failed to write certificate: {:?}", c.output))?;
@@ -90,7 +119,7 @@ struct PublicKeyInputGroup {
 }
 
 #[derive(Debug, Args)]
-struct PassphraseGroup {
+pub(crate) struct PassphraseGroup {
     /// Environment variable containing private key passphrase.
     #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass")]
     pass_env_var: Option<OsString>,
@@ -132,8 +161,33 @@ struct GenerateCertCli {
     subject: String,
 
     /// Certificate validity in days.
+    ///
+    /// This is ignored if --not-after is specified.
     #[arg(short, long, default_value = "10000")]
     validity: u64,
+
+    /// Start of the validity period as a Unix timestamp.
+    ///
+    /// The default is the current time, or SOURCE_DATE_EPOCH if it is set.
+    #[arg(long, value_name = "TIMESTAMP")]
+    not_before: Option<u64>,
+
+    /// End of the validity period as a Unix timestamp.
+    #[arg(long, value_name = "TIMESTAMP", conflicts_with = "validity")]
+    not_after: Option<u64>,
+
+    /// Certificate serial number.
+    ///
+    /// The default is a random number, unless --reproducible is specified.
+    #[arg(long, value_name = "NUMBER", conflicts_with = "reproducible")]
+    serial: Option<u64>,
+
+    /// Generate an identical certificate every time.
+    ///
+    /// The serial number is derived from the public key. The start of the
+    /// validity period must be fixed with --not-before or SOURCE_DATE_EPOCH.
+    #[arg(long)]
+    reproducible: bool,
 }
 
 /// Extract the AVB public key from a private key or certificate.
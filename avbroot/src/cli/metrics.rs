@@ -0,0 +1,280 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Prometheus metrics for `ota patch`. The metrics are written in the text
+//! exposition format when patching finishes, which is meant to be picked up by
+//! node_exporter's textfile collector. This is only built with the `metrics`
+//! feature.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use tempfile::NamedTempFile;
+
+use crate::protobuf::build::tools::releasetools::OtaMetadata;
+
+/// Name of the counter that is carried over from the previous metrics file.
+const PATCHES_TOTAL: &str = "avbroot_patches_total";
+
+/// Escape a label value for the text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Get the build ID (eg. `UQ1A.240105.004`) from a build fingerprint, which has
+/// the form `<brand>/<product>/<device>:<release>/<build id>/<incremental>:...`.
+fn build_id(fingerprint: &str) -> Option<&str> {
+    fingerprint.split('/').nth(3)
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Read the values of [`PATCHES_TOTAL`] for each result from an existing
+/// metrics file so that the counter keeps increasing across runs.
+fn previous_totals(path: &Path) -> (u64, u64) {
+    let mut success = 0;
+    let mut failure = 0;
+
+    let Ok(data) = fs::read_to_string(path) else {
+        return (success, failure);
+    };
+
+    for line in data.lines() {
+        let Some(rest) = line.strip_prefix(PATCHES_TOTAL) else {
+            continue;
+        };
+        let Some((labels, value)) = rest.rsplit_once(' ') else {
+            continue;
+        };
+        let Ok(value) = value.parse::<u64>() else {
+            continue;
+        };
+
+        if labels.contains("result=\"success\"") {
+            success += value;
+        } else if labels.contains("result=\"failure\"") {
+            failure += value;
+        }
+    }
+
+    (success, failure)
+}
+
+#[derive(Default)]
+struct State {
+    device: String,
+    build_id: String,
+    current_stage: Option<(&'static str, Instant)>,
+    stages: Vec<(&'static str, Duration)>,
+    input_bytes: u64,
+    output_bytes: u64,
+    reference_hits: u64,
+    reference_misses: u64,
+}
+
+/// Metrics collected while patching a single OTA.
+#[derive(Default)]
+pub struct PatchMetrics {
+    state: Mutex<State>,
+}
+
+impl PatchMetrics {
+    /// Set the `device` and `build_id` labels from the OTA's postcondition.
+    pub fn set_build(&self, metadata: &OtaMetadata) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(p) = &metadata.postcondition {
+            if let Some(device) = p.device.first() {
+                state.device = device.clone();
+            }
+            if let Some(id) = p.build.first().and_then(|f| build_id(f)) {
+                state.build_id = id.to_owned();
+            }
+        }
+    }
+
+    /// End the current stage, if any, and start timing a new stage.
+    pub fn start_stage(&self, name: &'static str) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some((prev, start)) = state.current_stage.take() {
+            state.stages.push((prev, start.elapsed()));
+        }
+
+        state.current_stage = Some((name, Instant::now()));
+    }
+
+    /// End the current stage without starting a new one.
+    pub fn end_stage(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some((prev, start)) = state.current_stage.take() {
+            state.stages.push((prev, start.elapsed()));
+        }
+    }
+
+    pub fn set_input_bytes(&self, size: u64) {
+        self.state.lock().unwrap().input_bytes = size;
+    }
+
+    pub fn set_output_bytes(&self, size: u64) {
+        self.state.lock().unwrap().output_bytes = size;
+    }
+
+    /// Set the number of images that were and were not reused from the
+    /// reference OTA.
+    pub fn set_reference_images(&self, hits: u64, misses: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.reference_hits = hits;
+        state.reference_misses = misses;
+    }
+
+    fn render(&self, success: bool, previous: (u64, u64)) -> String {
+        self.end_stage();
+
+        let state = self.state.lock().unwrap();
+        let labels = format!(
+            "device=\"{}\",build_id=\"{}\"",
+            escape_label(&state.device),
+            escape_label(&state.build_id),
+        );
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (prev_success, prev_failure) = previous;
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            PATCHES_TOTAL,
+            "counter",
+            "Number of OTAs patched.",
+        );
+        for (result, count) in [
+            ("success", prev_success + u64::from(success)),
+            ("failure", prev_failure + u64::from(!success)),
+        ] {
+            writeln!(out, "{PATCHES_TOTAL}{{result=\"{result}\"}} {count}").unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "avbroot_patch_success",
+            "gauge",
+            "Whether the last patch succeeded.",
+        );
+        writeln!(
+            out,
+            "avbroot_patch_success{{{labels}}} {}",
+            u8::from(success)
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "avbroot_patch_last_run_timestamp_seconds",
+            "gauge",
+            "Unix time when the last patch finished.",
+        );
+        writeln!(
+            out,
+            "avbroot_patch_last_run_timestamp_seconds{{{labels}}} {timestamp}",
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "avbroot_patch_stage_duration_seconds",
+            "gauge",
+            "Time spent in each stage of the last patch.",
+        );
+        for (stage, duration) in &state.stages {
+            writeln!(
+                out,
+                "avbroot_patch_stage_duration_seconds{{{labels},stage=\"{stage}\"}} {:.3}",
+                duration.as_secs_f64(),
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "avbroot_patch_bytes",
+            "gauge",
+            "Size of the input and output OTAs of the last patch.",
+        );
+        for (direction, size) in [("input", state.input_bytes), ("output", state.output_bytes)] {
+            writeln!(
+                out,
+                "avbroot_patch_bytes{{{labels},direction=\"{direction}\"}} {size}",
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "avbroot_patch_reference_images",
+            "gauge",
+            "Images that were reused from or not found in the reference OTA.",
+        );
+        for (result, count) in [
+            ("hit", state.reference_hits),
+            ("miss", state.reference_misses),
+        ] {
+            writeln!(
+                out,
+                "avbroot_patch_reference_images{{{labels},result=\"{result}\"}} {count}",
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// Write the metrics to `path`. The file is replaced atomically so that
+    /// the textfile collector never sees a partially written file.
+    pub fn write_textfile(&self, path: &Path, success: bool) -> Result<()> {
+        let data = self.render(success, previous_totals(path));
+
+        let dir = match path.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temporary file in {dir:?}"))?;
+        file.write_all(data.as_bytes())
+            .with_context(|| format!("Failed to write metrics: {:?}", file.path()))?;
+
+        // NamedTempFile uses 600 permissions, but the collector usually runs
+        // as a different user.
+        #[cfg(unix)]
+        {
+            use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+
+            file.as_file()
+                .set_permissions(Permissions::from_mode(0o644))
+                .with_context(|| format!("Failed to set permissions: {:?}", file.path()))?;
+        }
+
+        file.persist(path)
+            .with_context(|| format!("Failed to write metrics: {path:?}"))?;
+
+        Ok(())
+    }
+}
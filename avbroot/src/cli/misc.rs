@@ -0,0 +1,283 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Cursor, Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    adb,
+    cli::{ota, status},
+    format::bcb::{BootloaderMessage, Layout},
+};
+
+fn parse_layout(s: &str) -> Result<Layout> {
+    match s {
+        "legacy" => Ok(Layout::Legacy),
+        "current" => Ok(Layout::Current),
+        _ => bail!("Unknown bootloader message layout: {s}"),
+    }
+}
+
+/// Quote a string for `sh`.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Wrap a command for the device's shell, optionally running it with `su`.
+pub fn device_command(command: &str, su: bool) -> String {
+    if su {
+        format!("su -c {}", shell_quote(command))
+    } else {
+        command.to_owned()
+    }
+}
+
+fn read_device(target: &DeviceGroup, size: usize) -> Result<Vec<u8>> {
+    let device = target.device.as_deref().unwrap();
+    let mut conn = ota::connect_adb(device, target.adb_key.as_deref())?;
+
+    let command = format!(
+        "dd if={} bs={size} count=1 2>/dev/null",
+        shell_quote(&target.misc_path),
+    );
+    let data = adb::exec(&mut conn, &device_command(&command, target.su), &[])
+        .with_context(|| format!("Failed to read {:?} on device", target.misc_path))?;
+    if data.len() != size {
+        bail!(
+            "Expected {size} bytes from {:?} on device, but got {}",
+            target.misc_path,
+            data.len(),
+        );
+    }
+
+    Ok(data)
+}
+
+fn write_device(target: &DeviceGroup, data: &[u8]) -> Result<()> {
+    let device = target.device.as_deref().unwrap();
+    let mut conn = ota::connect_adb(device, target.adb_key.as_deref())?;
+
+    // A block size of 1 ensures that short reads from the socket can't cause dd
+    // to write less than the full message. The exec service never reports the
+    // exit status, so the data is read back afterwards to check that it was
+    // written.
+    let command = format!(
+        "dd of={} bs=1 count={} 2>/dev/null && sync",
+        shell_quote(&target.misc_path),
+        data.len(),
+    );
+    adb::exec(&mut conn, &device_command(&command, target.su), data)
+        .with_context(|| format!("Failed to write {:?} on device", target.misc_path))?;
+    drop(conn);
+
+    if read_device(target, data.len())? != data {
+        bail!("Data read back from {:?} does not match", target.misc_path);
+    }
+
+    Ok(())
+}
+
+fn print_message(message: &BootloaderMessage, layout: Layout) {
+    println!("Command: {:?}", message.command);
+    println!("Status: {:?}", message.status);
+    println!("Recovery arguments:");
+    for arg in message.recovery_args() {
+        println!("- {arg:?}");
+    }
+    println!("Stage: {:?}", message.stage);
+    if layout == Layout::Legacy {
+        println!("Slot suffix: {:?}", message.slot_suffix);
+    }
+}
+
+pub fn misc_main(cli: &MiscCli) -> Result<()> {
+    match &cli.command {
+        MiscCommand::ReadBcb(c) => {
+            let size = c.layout.size();
+
+            let data = if let Some(path) = &c.input {
+                let mut data = vec![0u8; size];
+                File::open(path)
+                    .and_then(|mut f| f.read_exact(&mut data))
+                    .with_context(|| format!("Failed to read: {path:?}"))?;
+                data
+            } else {
+                read_device(&c.device, size)?
+            };
+
+            let message = BootloaderMessage::from_reader_layout(Cursor::new(data), c.layout)
+                .context("Failed to parse bootloader message")?;
+
+            print_message(&message, c.layout);
+        }
+        MiscCommand::WriteBcb(c) => {
+            let message = BootloaderMessage {
+                command: c.command.clone(),
+                status: c.status.clone(),
+                recovery: c.recovery.replace(r"\n", "\n"),
+                stage: c.stage.clone(),
+                slot_suffix: c.slot_suffix.clone(),
+            };
+
+            let mut data = vec![];
+            message
+                .to_writer_layout(&mut data, c.layout)
+                .context("Failed to build bootloader message")?;
+
+            if let Some(path) = &c.output {
+                // Existing files are not truncated so that a dump of the whole
+                // misc partition can be updated in place.
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)
+                    .and_then(|mut f| f.write_all(&data))
+                    .with_context(|| format!("Failed to write: {path:?}"))?;
+            } else {
+                write_device(&c.device, &data)?;
+                status!("Wrote bootloader message to {:?}", c.device.misc_path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+struct DeviceGroup {
+    /// Address of the device in the form <host>[:<port>].
+    ///
+    /// The default port is 5555. adbd must allow shell access and have
+    /// permission to access the misc partition, eg. in a custom recovery or
+    /// with --su.
+    #[arg(short, long, value_name = "ADDRESS")]
+    device: Option<String>,
+
+    /// ADB private key for authenticating with the device.
+    ///
+    /// The default is ~/.android/adbkey if it exists.
+    #[arg(long, value_name = "FILE", value_parser, requires = "device")]
+    adb_key: Option<PathBuf>,
+
+    /// Path to the misc partition on the device.
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/dev/block/by-name/misc",
+        requires = "device"
+    )]
+    misc_path: String,
+
+    /// Run the commands on the device with su.
+    #[arg(long, requires = "device")]
+    su: bool,
+}
+
+/// Show the bootloader message from a misc partition.
+#[derive(Debug, Parser)]
+struct ReadBcbCli {
+    /// Path to misc partition image.
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        value_parser,
+        required_unless_present = "device",
+        conflicts_with = "device"
+    )]
+    input: Option<PathBuf>,
+
+    #[command(flatten)]
+    device: DeviceGroup,
+
+    /// Layout of the bootloader message.
+    ///
+    /// Use "legacy" for devices that launched with Android 7 or older and
+    /// "current" for everything else.
+    #[arg(long, value_name = "LAYOUT", default_value = "current", value_parser = parse_layout)]
+    layout: Layout,
+}
+
+/// Write a bootloader message to a misc partition.
+///
+/// For example, to boot into recovery and install an update:
+///
+/// --command boot-recovery --recovery 'recovery\n--update_package=<path>\n'
+///
+/// Fields that are not specified are left empty, so running this without any
+/// fields clears the bootloader message.
+#[derive(Debug, Parser)]
+struct WriteBcbCli {
+    /// Path to misc partition image.
+    ///
+    /// The file is created if it does not exist. Otherwise, only the beginning
+    /// of the file is overwritten.
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        value_parser,
+        required_unless_present = "device",
+        conflicts_with = "device"
+    )]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    device: DeviceGroup,
+
+    /// Command for the bootloader.
+    #[arg(long, value_name = "COMMAND", default_value = "")]
+    command: String,
+
+    /// Status field.
+    ///
+    /// This is normally only written by the bootloader.
+    #[arg(long, value_name = "STATUS", default_value = "")]
+    status: String,
+
+    /// Command line for recovery.
+    ///
+    /// Arguments are separated by newlines and the first argument must be
+    /// "recovery". Literal "\n" sequences are converted to newlines.
+    #[arg(long, value_name = "ARGS", default_value = "")]
+    recovery: String,
+
+    /// Stage of a multi-stage package install.
+    #[arg(long, value_name = "STAGE", default_value = "")]
+    stage: String,
+
+    /// Slot suffix.
+    ///
+    /// This is only supported by the legacy layout.
+    #[arg(long, value_name = "SUFFIX", default_value = "")]
+    slot_suffix: String,
+
+    /// Layout of the bootloader message.
+    ///
+    /// Use "legacy" for devices that launched with Android 7 or older and
+    /// "current" for everything else.
+    #[arg(long, value_name = "LAYOUT", default_value = "current", value_parser = parse_layout)]
+    layout: Layout,
+}
+
+#[derive(Debug, Subcommand)]
+enum MiscCommand {
+    ReadBcb(ReadBcbCli),
+    WriteBcb(WriteBcbCli),
+}
+
+/// Read or write the bootloader control block in the misc partition.
+#[derive(Debug, Parser)]
+pub struct MiscCli {
+    #[command(subcommand)]
+    command: MiscCommand,
+}
@@ -5,11 +5,21 @@
 
 pub mod args;
 pub mod avb;
+pub mod bench;
 pub mod boot;
 pub mod completion;
+pub mod device;
+pub mod dtbo;
 pub mod key;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod misc;
 pub mod ota;
+pub mod qcdt;
 pub mod ramdisk;
+pub mod selftest;
+pub mod temp;
+pub mod wizard;
 
 macro_rules! status {
     ($($arg:tt)*) => {
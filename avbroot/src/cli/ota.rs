@@ -5,47 +5,84 @@
 
 use std::{
     borrow::Cow,
-    collections::{BTreeSet, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
     ffi::{OsStr, OsString},
-    fmt::Display,
+    fmt::{self, Display},
     fs::{self, File},
     io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc, Mutex},
-    time::Instant,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use clap::{value_parser, ArgAction, Args, Parser, Subcommand};
 use phf::phf_map;
-use rayon::prelude::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use regex::Regex;
 use rsa::RsaPrivateKey;
-use tempfile::{NamedTempFile, TempDir};
+use serde::Serialize;
 use topological_sort::TopologicalSort;
 use x509_cert::Certificate;
+use xz2::read::XzDecoder;
 use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::{
-    boot::{self, BootImagePatcher, MagiskRootPatcher, OtaCertPatcher, PrepatchedImagePatcher},
-    cli::{self, status, warning},
-    crypto::{self, PassphraseSource},
+    adb::{self, AdbConnection},
+    attestation,
+    boot::{
+        self, BootImagePatcher, MagiskOptions, MagiskRootPatcher, OtaCertPatcher,
+        PrepatchedImagePatcher, RamdiskCompressionPatcher, RamdiskCompressionTarget,
+    },
+    cli::{
+        self,
+        boot::{parse_min_savings_ratio, parse_ramdisk_compression, RamdiskCompression},
+        device, status,
+        temp::{self, TempPolicy, TempStage},
+        warning,
+    },
+    crypto::{self, OtaCertIssue, PassphraseSource, RsaPadding, SignatureFormat},
     format::{
         avb::Header,
-        avb::{self, AlgorithmType, Descriptor},
-        bootimage::BootImage,
+        avb::{
+            self, ChainPartitionDescriptor, Descriptor, HashDescriptor, KernelCmdlineDescriptor,
+            PropertyDescriptor,
+        },
+        bootimage::{self, BootImage, BootImageExt},
+        compression, filesystem,
         ota::{self, SigningWriter, ZipEntry},
         padding,
-        payload::{self, CompressedPartitionWriter, PayloadHeader, PayloadWriter},
+        payload::{
+            self, CompressedPartitionWriter, ExtentDesc, ExtractOptions, OperationDesc,
+            PayloadHeader, PayloadWriter, RawPayloadHeader,
+        },
+        vintf,
     },
+    pipeline,
     protobuf::{
-        build::tools::releasetools::OtaMetadata, chromeos_update_engine::DeltaArchiveManifest,
+        build::tools::releasetools::OtaMetadata,
+        chromeos_update_engine::{
+            mod_InstallOperation::Type, DeltaArchiveManifest, Extent, InstallOperation,
+            PartitionInfo,
+        },
     },
     stream::{
-        self, CountingWriter, FromReader, HolePunchingWriter, PSeekFile, ReadSeek, SectionReader,
-        ToWriter,
+        self, CountingReader, CountingWriter, FromReader, HashingReader, HolePunchingWriter,
+        PSeekFile, ReadSeek, SectionReader, ToWriter,
     },
+    util,
+    warning::{Severity, Warning, WarningCode, WarningCollector},
 };
 
+#[cfg(feature = "metrics")]
+use crate::cli::metrics::PatchMetrics;
+
+const ADB_DEFAULT_PORT: u16 = 5555;
+
 static PARTITION_PRIORITIES: phf::Map<&'static str, &[&'static str]> = phf_map! {
     // The kernel is always in boot
     "@gki_kernel" => &["boot"],
@@ -72,7 +109,8 @@ fn sorted<T: Ord>(iter: impl Iterator<Item = T>) -> Vec<T> {
 
 /// Get the set of partitions, grouped by type, based on the priorities listed
 /// in [`PARTITION_PRIORITIES`]. The result also includes every vbmeta partition
-/// prefixed with `@vbmeta:`.
+/// prefixed with `@vbmeta:`. For partial updates, types with no matching
+/// partition are omitted instead of causing an error.
 pub fn get_partitions_by_type(manifest: &DeltaArchiveManifest) -> Result<HashMap<String, String>> {
     let all_partitions = manifest
         .partitions
@@ -82,10 +120,13 @@ pub fn get_partitions_by_type(manifest: &DeltaArchiveManifest) -> Result<HashMap
     let mut by_type = HashMap::new();
 
     for (&t, candidates) in &PARTITION_PRIORITIES {
-        let &partition = candidates
-            .iter()
-            .find(|p| all_partitions.contains(*p))
-            .ok_or_else(|| anyhow!("Cannot find partition of type: {t}"))?;
+        let Some(&partition) = candidates.iter().find(|p| all_partitions.contains(*p)) else {
+            if manifest.partial_update == Some(true) {
+                continue;
+            }
+
+            bail!("Cannot find partition of type: {t}");
+        };
 
         by_type.insert(t.to_owned(), partition.to_owned());
     }
@@ -115,6 +156,20 @@ pub fn get_required_images(
         .collect::<HashSet<_>>();
     let by_type = get_partitions_by_type(manifest)?;
     let mut images = HashMap::new();
+    let mut missing = vec![];
+
+    // Partitions in the payload have no A/B slot suffix, but the boot partition
+    // can be specified with one to target a specific slot.
+    let boot_partition = match avb::split_slot_suffix(boot_partition) {
+        (base, Some(_)) if !all_partitions.contains(boot_partition) => base,
+        _ => boot_partition,
+    };
+
+    // Describe a partition type by its candidates for the error message.
+    let describe = |name: &str| match PARTITION_PRIORITIES.get(name) {
+        Some(candidates) => format!("{name} ({})", candidates.join(" or ")),
+        None => name.to_owned(),
+    };
 
     for (k, v) in &by_type {
         if k == "@otacerts" || k.starts_with("@vbmeta:") {
@@ -122,16 +177,30 @@ pub fn get_required_images(
         }
     }
 
+    if !by_type.contains_key("@otacerts") {
+        missing.push(describe("@otacerts"));
+    }
+
+    // Partial updates don't necessarily include the root vbmeta image, but it
+    // always needs to be re-signed.
+    if manifest.partial_update == Some(true) && !all_partitions.contains("vbmeta") {
+        missing.push(describe("vbmeta"));
+    }
+
     if with_root {
         if by_type.contains_key(boot_partition) {
             images.insert("@rootpatch".to_owned(), by_type[boot_partition].clone());
         } else if all_partitions.contains(boot_partition) {
             images.insert("@rootpatch".to_owned(), boot_partition.to_owned());
         } else {
-            bail!("Boot partition not found: {boot_partition}");
+            missing.push(describe(boot_partition));
         }
     }
 
+    if !missing.is_empty() {
+        bail!("Required partitions not found in OTA: {}", joined(missing));
+    }
+
     Ok(images)
 }
 
@@ -158,7 +227,7 @@ fn open_input_streams(
         if let Some(path) = external_images.get(name) {
             status!("Opening external image: {name}: {path:?}");
 
-            let file = File::open(path)
+            let file = compression::open_standalone(path)
                 .with_context(|| format!("Failed to open external image: {path:?}"))?;
             input_streams.insert(name.clone(), Box::new(file));
         } else {
@@ -175,23 +244,37 @@ fn open_input_streams(
 }
 
 /// Patch the boot images listed in `required_images`. An [`OtaCertPatcher`] is
-/// always applied to the `@otacerts` image to insert `cert_ota` into the
-/// trusted certificate list. If `root_patcher` is specified, then it is used to
-/// patch the `@rootpatch` image. If the original image is signed, then it will
-/// be re-signed with `key_avb`.
+/// always applied to the `@otacerts` image to insert `cert_ota` and
+/// `cert_payload`, if specified, into the trusted certificate list. If
+/// `root_patcher` is specified, then it is used to patch the `@rootpatch`
+/// image. If `ramdisk_target` is specified, then the ramdisks of every patched
+/// image are recompressed after all other patches are applied. If the original
+/// image is signed, then it will be re-signed with `key_avb`.
+#[allow(clippy::too_many_arguments)]
 fn patch_boot_images(
     required_images: &HashMap<String, String>,
     input_streams: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
     root_patcher: Option<Box<dyn BootImagePatcher + Send>>,
+    ramdisk_target: Option<RamdiskCompressionTarget>,
     key_avb: &RsaPrivateKey,
     cert_ota: &Certificate,
+    cert_payload: Option<&Certificate>,
+    warnings: &WarningCollector,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
     let mut boot_patchers = HashMap::<&str, Vec<Box<dyn BootImagePatcher + Send>>>::new();
     boot_patchers
         .entry(&required_images["@otacerts"])
         .or_default()
-        .push(Box::new(OtaCertPatcher::new(cert_ota.clone())));
+        .push(Box::new(OtaCertPatcher::new(
+            cert_ota.clone(),
+            cert_payload.cloned(),
+            {
+                let name = required_images["@otacerts"].clone();
+                move |l| status!("{name}: {l}")
+            },
+            warnings.clone(),
+        )));
 
     if let Some(p) = root_patcher {
         boot_patchers
@@ -200,6 +283,20 @@ fn patch_boot_images(
             .push(p);
     }
 
+    // This must run last so that the size check in boot::patch_boot() applies
+    // to the final ramdisks.
+    if let Some(target) = ramdisk_target {
+        for (name, patchers) in &mut boot_patchers {
+            let name = name.to_string();
+
+            patchers.push(Box::new(RamdiskCompressionPatcher::new(
+                target,
+                move |d| status!("{name}: {d}"),
+                warnings.clone(),
+            )));
+        }
+    }
+
     status!(
         "Patching boot images: {}",
         joined(sorted(boot_patchers.keys()))
@@ -233,16 +330,196 @@ fn patch_boot_images(
     Ok(())
 }
 
-/// From the set of input images (modified partitions + all vbmeta partitions)
-/// and determine the order to patch the vbmeta images so that it can be done in
-/// a single pass.
-fn get_vbmeta_patch_order(
+/// Options that determine what happens to each vbmeta image.
+#[derive(Clone, Debug, Default)]
+pub struct VbmetaOptions {
+    /// Clear header flags that disable AVB (`--clear-vbmeta-flags`).
+    pub clear_flags: bool,
+    /// Keep header flags that disable AVB (`--keep-vbmeta-flags`).
+    pub keep_flags: bool,
+    /// Images whose signatures are left untouched (`--skip-avb`).
+    pub skip_avb: HashSet<String>,
+    /// Whether kernel cmdline descriptors in the root vbmeta image are edited
+    /// (`--avb-cmdline-remove` or `--avb-cmdline-add`).
+    pub edit_cmdline: bool,
+    /// Whether property descriptors in the root vbmeta image are edited
+    /// (`--avb-delete-prop` or `--avb-prop`).
+    pub edit_props: bool,
+    /// Keep a root vbmeta image that has no descriptors as is instead of
+    /// rebuilding it (`--keep-vbmeta-stub`).
+    pub keep_stub: bool,
+}
+
+/// Descriptor edits for the root vbmeta image.
+#[derive(Clone, Debug, Default)]
+pub struct RootVbmetaEdits {
+    /// Patterns of kernel cmdline descriptors to remove.
+    pub cmdline_remove: Vec<Regex>,
+    /// Kernel cmdline descriptors to add after the removals.
+    pub cmdline_add: Vec<KernelCmdlineDescriptor>,
+    /// Keys of property descriptors to remove.
+    pub prop_delete: Vec<String>,
+    /// Property descriptors to add or replace after the removals.
+    pub prop_set: Vec<PropertyDescriptor>,
+}
+
+/// What happens to a vbmeta image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VbmetaAction {
+    /// Update the descriptors for the listed partitions and re-sign the image
+    /// with the AVB key. Kernel cmdline and property descriptors are only
+    /// edited in the root vbmeta image.
+    Sign {
+        deps: BTreeSet<String>,
+        edit_cmdline: bool,
+        edit_props: bool,
+    },
+    /// Leave the image untouched because of `--skip-avb`.
+    Skip,
+    /// Leave the image untouched because nothing it covers is modified.
+    Unchanged,
+}
+
+/// The resolved handling of a single vbmeta image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VbmetaPlanEntry {
+    pub name: String,
+    pub action: VbmetaAction,
+    /// Header flags of the original image.
+    pub old_flags: u32,
+    /// Header flags of the output image.
+    pub new_flags: u32,
+}
+
+impl Display for VbmetaPlanEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+
+        match &self.action {
+            VbmetaAction::Sign {
+                deps,
+                edit_cmdline,
+                edit_props,
+            } => {
+                write!(f, "re-sign, update {}", joined(deps))?;
+                if *edit_cmdline {
+                    write!(f, ", edit kernel cmdline")?;
+                }
+                if *edit_props {
+                    write!(f, ", edit properties")?;
+                }
+            }
+            VbmetaAction::Skip => write!(f, "pass through (--skip-avb)")?,
+            VbmetaAction::Unchanged => write!(f, "pass through (unmodified)")?,
+        }
+
+        if self.new_flags != self.old_flags {
+            write!(f, ", flags {:#x} -> {:#x}", self.old_flags, self.new_flags)?;
+        } else if self.old_flags != 0 {
+            write!(f, ", flags {:#x} kept", self.old_flags)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Make sure that every `--skip-avb` image exists and is not one that avbroot
+/// patches itself.
+pub fn check_skip_avb(
+    skip_avb: &HashSet<String>,
+    all_partitions: &HashSet<&str>,
+    required_images: &HashMap<String, String>,
+) -> Result<()> {
+    for name in sorted(skip_avb.iter()) {
+        if !all_partitions.contains(name.as_str()) {
+            bail!("Cannot skip AVB re-signing for non-existent {name} partition");
+        }
+
+        let patched = required_images
+            .iter()
+            .find(|(k, v)| *v == name && !k.starts_with("@vbmeta:"));
+        if let Some((image_type, _)) = patched {
+            bail!("Cannot skip AVB re-signing for {name}: it is patched as {image_type}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure that every `--exclude` partition exists and is not needed for the
+/// patched OTA.
+fn check_exclude(
+    exclude: &BTreeSet<String>,
+    all_partitions: &HashSet<&str>,
+    required_images: &HashMap<String, String>,
+    external_images: &HashMap<String, PathBuf>,
+) -> Result<()> {
+    for name in exclude {
+        if !all_partitions.contains(name.as_str()) {
+            bail!("Cannot exclude non-existent {name} partition");
+        } else if external_images.contains_key(name) {
+            bail!("Cannot exclude {name}: it is replaced with --replace");
+        } else if name == "vbmeta" {
+            // Partial updates must still include the root vbmeta image.
+            bail!("Cannot exclude {name}: the root vbmeta image is always re-signed");
+        }
+
+        let patched = required_images
+            .iter()
+            .find(|(k, v)| *v == name && !k.starts_with("@vbmeta:"));
+        if let Some((image_type, _)) = patched {
+            bail!("Cannot exclude {name}: it is patched as {image_type}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure that no vbmeta image in the output has a descriptor for an
+/// excluded partition, since it would no longer be updated to match. This is
+/// allowed if either the partition or the vbmeta image is in `skip_avb`.
+fn check_excluded_descriptors(
+    headers: &BTreeMap<String, Header>,
+    exclude: &BTreeSet<String>,
+    skip_avb: &HashSet<String>,
+) -> Result<()> {
+    for (name, header) in headers {
+        if skip_avb.contains(name) {
+            continue;
+        }
+
+        for descriptor in &header.descriptors {
+            let Some(partition_name) = descriptor.partition_name() else {
+                continue;
+            };
+
+            if exclude.contains(partition_name) && !skip_avb.contains(partition_name) {
+                bail!(
+                    "Cannot exclude {partition_name}: {name} has a descriptor for it; pass in \
+                    --skip-avb {partition_name} to keep the original descriptor"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_vbmeta_images(required_images: &HashMap<String, String>) -> HashSet<String> {
+    required_images
+        .iter()
+        .filter(|(n, _)| n.starts_with("@vbmeta:"))
+        .map(|(_, p)| p.clone())
+        .collect()
+}
+
+/// Load the headers of the vbmeta images from `images`.
+fn load_vbmeta_headers(
     images: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
     vbmeta_images: &HashSet<String>,
-) -> Result<Vec<(String, Header, HashSet<String>)>> {
-    let mut dep_graph = HashMap::<&str, HashSet<String>>::new();
-    let mut headers = HashMap::<&str, Header>::new();
-    let mut missing = images.keys().cloned().collect::<BTreeSet<_>>();
+    warnings: &WarningCollector,
+) -> Result<BTreeMap<String, Header>> {
+    let mut headers = BTreeMap::new();
 
     for name in vbmeta_images {
         let reader = images.get_mut(name).unwrap();
@@ -250,11 +527,239 @@ fn get_vbmeta_patch_order(
             .with_context(|| format!("Failed to load vbmeta image: {name}"))?;
 
         if let Some(f) = footer {
-            warning!("{name} is a vbmeta partition, but has a footer: {f:?}");
+            warnings.emit(
+                WarningCode::VbmetaHasFooter,
+                Severity::Low,
+                format!("{name} is a vbmeta partition, but has a footer: {f:?}"),
+            );
+        }
+
+        headers.insert(name.clone(), header);
+    }
+
+    Ok(headers)
+}
+
+/// Convert the result of loading a partition's vbmeta header to [`None`] if the
+/// partition has no vbmeta header.
+fn optional_avb_header(
+    result: std::result::Result<Header, avb::Error>,
+) -> std::result::Result<Option<Header>, avb::Error> {
+    match result {
+        Ok(h) => Ok(Some(h)),
+        Err(avb::Error::InvalidHeaderMagic(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Load the vbmeta header of a partition in the payload like
+/// [`avb::load_image()`] does, but without extracting the whole image. Only the
+/// footer search window at the end of the image and the vbmeta header itself
+/// are extracted. Returns [`None`] if the partition has no vbmeta header.
+fn load_payload_avb_header(
+    open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>>,
+    header: &PayloadHeader,
+    name: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Option<Header>> {
+    let partition = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == name)
+        .ok_or_else(|| anyhow!("Partition not found in payload: {name}"))?;
+    let image_size = payload::partition_size(partition, header.manifest.block_size)?;
+
+    let window_offset = image_size - image_size.min(avb::FOOTER_SEARCH_WINDOW);
+    let window = payload::extract_range(
+        open_payload()?,
+        header,
+        name,
+        window_offset,
+        image_size - window_offset,
+        cancel_signal,
+    )?;
+
+    let (vbmeta_offset, vbmeta_size) = match avb::find_footer(Cursor::new(&window))? {
+        Some((f, _)) if f.vbmeta_size > avb::VBMETA_MAX_SIZE => {
+            bail!("vbmeta size too large: {}", f.vbmeta_size);
+        }
+        Some((f, _)) => (f.vbmeta_offset, f.vbmeta_size),
+        // Like vbmeta partition images, the header is at the start.
+        None => (0, image_size.min(avb::VBMETA_MAX_SIZE)),
+    };
+
+    let vbmeta = payload::extract_range(
+        open_payload()?,
+        header,
+        name,
+        vbmeta_offset,
+        vbmeta_size,
+        cancel_signal,
+    )?;
+
+    Ok(optional_avb_header(Header::from_reader(Cursor::new(
+        vbmeta,
+    )))?)
+}
+
+/// Rebuild the root vbmeta image with [`rebuild_stub_vbmeta()`] if it has no
+/// descriptors, unless `--keep-vbmeta-stub` is specified. The AVB metadata of
+/// the partitions is read from `input_streams` or `external_images` if
+/// possible. Otherwise, only the AVB footer and vbmeta header of each partition
+/// are extracted from the payload. Excluded partitions are not covered. Returns
+/// whether the image was rebuilt.
+#[allow(clippy::too_many_arguments)]
+fn rebuild_stub_root_vbmeta(
+    open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+    header: &PayloadHeader,
+    input_streams: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
+    external_images: &HashMap<String, PathBuf>,
+    vbmeta_headers: &mut BTreeMap<String, Header>,
+    modified: &HashSet<String>,
+    exclude: &BTreeSet<String>,
+    options: &VbmetaOptions,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<bool> {
+    let is_stub = vbmeta_headers
+        .get("vbmeta")
+        .is_some_and(|h| h.descriptors.is_empty());
+    if !is_stub || options.keep_stub {
+        return Ok(false);
+    }
+
+    let covered = vbmeta_headers
+        .values()
+        .flat_map(|h| h.descriptors.iter().filter_map(|d| d.partition_name()))
+        .collect::<HashSet<_>>();
+    let mut images = vec![];
+
+    for p in &header.manifest.partitions {
+        let name = &p.partition_name;
+        if vbmeta_headers.contains_key(name)
+            || covered.contains(name.as_str())
+            || exclude.contains(name)
+        {
+            continue;
+        }
+
+        let avb_header = match input_streams.remove(name) {
+            Some(mut reader) => {
+                let avb_header = optional_avb_header(avb::load_image(&mut reader).map(|r| r.0))
+                    .with_context(|| format!("Failed to load vbmeta footer: {name}"))?;
+
+                if modified.contains(name) {
+                    reader.rewind()?;
+                    input_streams.insert(name.clone(), reader);
+                }
+
+                avb_header
+            }
+            None => match external_images.get(name) {
+                Some(path) => {
+                    let reader = compression::open_standalone(path)
+                        .with_context(|| format!("Failed to open external image: {path:?}"))?;
+
+                    optional_avb_header(avb::load_image(reader).map(|r| r.0))
+                        .with_context(|| format!("Failed to load vbmeta footer: {name}"))?
+                }
+                None => {
+                    status!("Reading vbmeta from original payload to rebuild vbmeta: {name}");
+
+                    load_payload_avb_header(&open_payload, header, name, cancel_signal)
+                        .with_context(|| format!("Failed to load vbmeta from payload: {name}"))?
+                }
+            },
+        };
+        images.push((name.clone(), avb_header));
+    }
+
+    let Some(added) = rebuild_stub_vbmeta(
+        "vbmeta",
+        vbmeta_headers,
+        &images,
+        modified,
+        options.keep_flags,
+    )?
+    else {
+        return Ok(false);
+    };
+
+    let message = if options.keep_flags {
+        "the header flags were kept, so verification is still disabled"
+    } else {
+        "verification is re-enabled"
+    };
+    warnings.emit(
+        WarningCode::VbmetaStubRebuilt,
+        Severity::Medium,
+        format!(
+            "vbmeta had no descriptors and was rebuilt to cover: {}; {message} (use \
+            --keep-vbmeta-stub to keep the original image)",
+            joined(added),
+        ),
+    );
+
+    Ok(true)
+}
+
+/// Make sure that a rebuilt root vbmeta image is actually written.
+fn check_rebuilt_signed(plan: &[VbmetaPlanEntry]) -> Result<()> {
+    let signed = plan
+        .iter()
+        .any(|e| e.name == "vbmeta" && matches!(e.action, VbmetaAction::Sign { .. }));
+    if !signed {
+        bail!("vbmeta was rebuilt, but cannot be re-signed because nothing it covers is modified");
+    }
+
+    Ok(())
+}
+
+/// Determine what happens to every vbmeta image in `headers` before anything
+/// is modified. `modified` is the set of all images that are written to the
+/// new payload. The images to re-sign are returned first, in the order that
+/// they must be patched so that it can be done in a single pass, followed by
+/// the images that are left untouched. Conflicting options are an error.
+pub fn plan_vbmeta(
+    headers: &BTreeMap<String, Header>,
+    modified: &HashSet<String>,
+    options: &VbmetaOptions,
+    warnings: &WarningCollector,
+) -> Result<Vec<VbmetaPlanEntry>> {
+    let mut dep_graph = HashMap::<&str, HashSet<String>>::new();
+    let mut missing = modified
+        .iter()
+        .filter(|n| !headers.contains_key(*n))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    for (name, header) in headers {
+        if options.skip_avb.contains(name) {
+            // The image is left untouched, so its descriptors for modified
+            // partitions will no longer match.
+            for descriptor in &header.descriptors {
+                let Some(partition_name) = descriptor.partition_name() else {
+                    continue;
+                };
+
+                if modified.contains(partition_name) && !headers.contains_key(partition_name) {
+                    missing.remove(partition_name);
+                    warnings.emit(
+                        WarningCode::AvbResigningSkipped,
+                        Severity::High,
+                        format!(
+                            "{partition_name} is modified, but its descriptor in {name} is not \
+                            updated because {name} is not re-signed"
+                        ),
+                    );
+                }
+            }
+
+            continue;
         }
 
-        dep_graph.insert(name, HashSet::new());
-        missing.remove(name);
+        let deps = dep_graph.entry(name.as_str()).or_default();
 
         for descriptor in &header.descriptors {
             let Some(partition_name) = descriptor.partition_name() else {
@@ -262,20 +767,24 @@ fn get_vbmeta_patch_order(
             };
 
             // Ignore partitions that are guaranteed to not be modified.
-            if images.contains_key(partition_name) {
-                dep_graph
-                    .get_mut(name.as_str())
-                    .unwrap()
-                    .insert(partition_name.to_owned());
+            if modified.contains(partition_name) {
                 missing.remove(partition_name);
+
+                // Skipped partitions keep their original descriptors, including
+                // the original public key in chain descriptors.
+                if !options.skip_avb.contains(partition_name) {
+                    deps.insert(partition_name.to_owned());
+                }
             }
         }
-
-        headers.insert(name, header);
     }
 
     if !missing.is_empty() {
-        warning!("Partitions aren't protected by AVB: {:?}", joined(missing));
+        warnings.emit(
+            WarningCode::UnprotectedPartitions,
+            Severity::Medium,
+            format!("Partitions aren't protected by AVB: {:?}", joined(missing)),
+        );
     }
 
     // Prune vbmeta images we don't need.
@@ -287,7 +796,6 @@ fn get_vbmeta_patch_order(
         match unneeded {
             Some(name) => {
                 dep_graph.remove(name.as_str());
-                headers.remove(name.as_str());
 
                 for deps in dep_graph.values_mut() {
                     deps.remove(name.as_str());
@@ -311,112 +819,619 @@ fn get_vbmeta_patch_order(
         match topo.pop() {
             Some(item) => {
                 // Only include vbmeta images that we need to modify.
-                if headers.contains_key(item.as_str()) {
-                    order.push((
-                        item.clone(),
-                        headers.remove(item.as_str()).unwrap(),
-                        dep_graph.remove(item.as_str()).unwrap(),
-                    ));
+                if let Some(deps) = dep_graph.remove(item.as_str()) {
+                    order.push((item, deps));
                 }
             }
             None => bail!("vbmeta dependency graph has cycle: {topo:?}"),
         }
     }
 
-    Ok(order)
-}
-
-/// Update vbmeta descriptors based on the footers from the specified images and
-/// then re-sign the vbmeta images.
-fn update_vbmeta_descriptors(
-    images: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
-    order: &mut [(String, Header, HashSet<String>)],
-    clear_vbmeta_flags: bool,
-    key: &RsaPrivateKey,
-    block_size: u64,
-) -> Result<()> {
-    for (name, parent_header, deps) in order {
-        if parent_header.flags != 0 {
-            if clear_vbmeta_flags {
-                parent_header.flags = 0;
-            } else {
-                bail!("{name} header flags disable AVB {:#x}", parent_header.flags);
-            }
-        }
-
-        // avbroot doesn't support any other types.
-        if parent_header.algorithm_type != AlgorithmType::Sha256Rsa4096 {
-            parent_header.algorithm_type = AlgorithmType::Sha256Rsa4096;
+    if options.clear_flags && options.keep_flags {
+        bail!("--clear-vbmeta-flags conflicts with --keep-vbmeta-flags");
+    }
 
-            status!(
-                "{} signature algorithm type changed to {:?}",
-                name,
-                parent_header.algorithm_type
+    // Kernel cmdline and property edits only apply to the root vbmeta image,
+    // which is the one that is not chained from any other vbmeta image.
+    let chained = headers
+        .values()
+        .flat_map(|h| h.descriptors.iter().filter_map(|d| d.partition_name()))
+        .collect::<HashSet<_>>();
+    let mut plan = vec![];
+
+    for (name, deps) in order {
+        let flags = headers[&name].flags;
+        let new_flags = if flags == 0 || options.keep_flags {
+            flags
+        } else if options.clear_flags {
+            0
+        } else {
+            bail!(
+                "{name} header flags disable AVB ({flags:#x}); use --clear-vbmeta-flags or \
+                --keep-vbmeta-flags"
+            );
+        };
+        let is_root = !chained.contains(name.as_str());
+
+        if new_flags != 0 {
+            warnings.emit(
+                WarningCode::VbmetaFlagsKept,
+                Severity::High,
+                format!(
+                    "{name} header flags disable AVB ({new_flags:#x}), but are kept as requested"
+                ),
             );
         }
 
-        for dep in deps.iter() {
-            // This can't fail since the descriptor must have existed for the
-            // dependency to exist.
-            let parent_descriptor = parent_header
-                .descriptors
-                .iter_mut()
-                .find(|d| d.partition_name() == Some(dep))
-                .unwrap();
+        plan.push(VbmetaPlanEntry {
+            name,
+            action: VbmetaAction::Sign {
+                deps: deps.into_iter().collect(),
+                edit_cmdline: options.edit_cmdline && is_root,
+                edit_props: options.edit_props && is_root,
+            },
+            old_flags: flags,
+            new_flags,
+        });
+    }
 
-            let reader = images.get_mut(dep).unwrap();
-            let (header, _, _) = avb::load_image(reader)
-                .with_context(|| format!("Failed to load vbmeta footer from image: {dep}"))?;
+    // Edits that would need an image to be re-signed, if it is the root.
+    let root_edit = if options.edit_cmdline {
+        Some(("--avb-cmdline-remove/--avb-cmdline-add", "kernel cmdline"))
+    } else if options.edit_props {
+        Some(("--avb-delete-prop/--avb-prop", "property"))
+    } else {
+        None
+    };
 
-            if header.public_key.is_empty() {
-                // vbmeta is unsigned. Use the existing descriptor.
-                let Some(descriptor) = header
-                    .descriptors
-                    .iter()
-                    .find(|d| d.partition_name() == Some(dep))
-                else {
-                    bail!("{name} has no descriptor for itself");
-                };
+    for (name, header) in headers {
+        if plan.iter().any(|e| e.name == *name) {
+            continue;
+        }
 
-                match (parent_descriptor, descriptor) {
-                    (Descriptor::Hash(pd), Descriptor::Hash(d)) => {
-                        *pd = d.clone();
-                    }
-                    (Descriptor::Hashtree(pd), Descriptor::Hashtree(d)) => {
-                        *pd = d.clone();
-                    }
-                    _ => {
-                        bail!("{name}'s descriptor for {dep} must match {dep}'s self descriptor");
-                    }
-                }
-            } else {
-                // vbmeta is signed; Use a chain descriptor.
-                match parent_descriptor {
-                    Descriptor::ChainPartition(d) => {
-                        d.public_key = header.public_key;
-                    }
-                    _ => {
-                        bail!("{name}'s descriptor for {dep} must be a chain descriptor");
-                    }
-                }
+        let action = if options.skip_avb.contains(name) {
+            if options.clear_flags && header.flags != 0 {
+                bail!(
+                    "--clear-vbmeta-flags conflicts with --skip-avb {name}: its header flags \
+                    ({:#x}) cannot be cleared without re-signing it",
+                    header.flags,
+                );
             }
-        }
 
-        parent_header
-            .sign(key)
-            .with_context(|| format!("Failed to sign vbmeta header for image: {name}"))?;
+            if let Some((args, kind)) = root_edit.filter(|_| !chained.contains(name.as_str())) {
+                bail!(
+                    "{args} conflicts with --skip-avb {name}: {kind} descriptors cannot be \
+                    edited without re-signing it"
+                );
+            }
 
-        let mut writer = Cursor::new(Vec::new());
-        parent_header
-            .to_writer(&mut writer)
-            .with_context(|| format!("Failed to write vbmeta image: {name}"))?;
+            VbmetaAction::Skip
+        } else {
+            if let Some((args, _)) = root_edit.filter(|_| !chained.contains(name.as_str())) {
+                bail!("{args} require re-signing {name}, but nothing it covers is modified");
+            }
 
-        padding::write_zeros(&mut writer, block_size)
+            VbmetaAction::Unchanged
+        };
+
+        plan.push(VbmetaPlanEntry {
+            name: name.clone(),
+            action,
+            old_flags: header.flags,
+            new_flags: header.flags,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// Rebuild the descriptors of the root vbmeta image `name` if it has none, like
+/// the stubs that are flashed to disable AVB. `headers` contains every vbmeta
+/// image and `images` contains the vbmeta header of every other partition in
+/// the payload, or [`None`] if it has no AVB metadata.
+///
+/// Every partition that isn't already covered by a chained vbmeta image gets a
+/// new descriptor. Signed images, including the chained vbmeta images, get a
+/// chain descriptor. Unsigned images get a copy of their own hash or hashtree
+/// descriptor. Modified images without AVB metadata get a hash descriptor with
+/// a fresh random salt. Unmodified images without AVB metadata, like firmware
+/// images, are left uncovered because a stock root vbmeta image would not
+/// cover them either. The digests and public keys of modified images are
+/// updated later when the vbmeta images are re-signed. The header flags are
+/// cleared unless `keep_flags` is true.
+///
+/// Returns the names of the partitions that the new descriptors cover, or
+/// [`None`] if the image already has descriptors.
+pub fn rebuild_stub_vbmeta(
+    name: &str,
+    headers: &mut BTreeMap<String, Header>,
+    images: &[(String, Option<Header>)],
+    modified: &HashSet<String>,
+    keep_flags: bool,
+) -> Result<Option<Vec<String>>> {
+    if !headers.get(name).is_some_and(|h| h.descriptors.is_empty()) {
+        return Ok(None);
+    }
+
+    let covered = headers
+        .iter()
+        .filter(|(n, _)| *n != name)
+        .flat_map(|(_, h)| h.descriptors.iter().filter_map(|d| d.partition_name()))
+        .map(|n| n.to_owned())
+        .collect::<HashSet<_>>();
+    let mut locations = BTreeSet::new();
+    let mut descriptors = vec![];
+
+    // Prefer the rollback index location from the chained image's own header,
+    // but every chain descriptor needs a unique, non-zero location.
+    let mut chain = |partition_name: &str, header: &Header| {
+        let mut location = header.rollback_index_location;
+        if location == 0 || locations.contains(&location) {
+            location = (1..).find(|l| !locations.contains(l)).unwrap();
+        }
+        locations.insert(location);
+
+        Descriptor::ChainPartition(ChainPartitionDescriptor {
+            rollback_index_location: location,
+            partition_name: partition_name.to_owned(),
+            public_key: header.public_key.clone(),
+            flags: 0,
+            reserved: [0u8; 60],
+        })
+    };
+
+    for (n, header) in headers.iter() {
+        if n == name || covered.contains(n) {
+            continue;
+        } else if header.public_key.is_empty() {
+            bail!("Cannot chain {n} from the rebuilt {name} image because {n} is unsigned");
+        }
+
+        descriptors.push(chain(n, header));
+    }
+
+    for (n, header) in images {
+        if n == name || covered.contains(n) || headers.contains_key(n) {
+            continue;
+        }
+
+        let descriptor = match header {
+            Some(h) if h.public_key.is_empty() => {
+                let d = h.descriptors.iter().find_map(|d| match d {
+                    Descriptor::Hash(_) | Descriptor::Hashtree(_)
+                        if d.partition_name() == Some(n.as_str()) =>
+                    {
+                        Some(d.clone())
+                    }
+                    _ => None,
+                });
+
+                d.ok_or_else(|| anyhow!("{n} has no hash or hashtree descriptor for itself"))?
+            }
+            Some(h) => chain(n, h),
+            None if modified.contains(n) => Descriptor::Hash(HashDescriptor {
+                image_size: 0,
+                hash_algorithm: "sha256".to_owned(),
+                partition_name: n.clone(),
+                salt: rand::random::<[u8; 32]>().to_vec(),
+                root_digest: vec![],
+                flags: 0,
+                reserved: [0u8; 60],
+            }),
+            None => continue,
+        };
+
+        descriptors.push(descriptor);
+    }
+
+    let added = descriptors
+        .iter()
+        .filter_map(|d| d.partition_name())
+        .map(|n| n.to_owned())
+        .collect();
+
+    let header = headers.get_mut(name).unwrap();
+    header.descriptors = descriptors;
+    if !keep_flags {
+        header.flags = 0;
+    }
+
+    Ok(Some(added))
+}
+
+/// Parse a kernel cmdline descriptor in the form `<flags>:<cmdline>`.
+fn parse_kernel_cmdline(s: &str) -> Result<KernelCmdlineDescriptor> {
+    let (flags, cmdline) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected <flags>:<cmdline>"))?;
+
+    let flags = match flags.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => flags.parse(),
+    }
+    .with_context(|| format!("Invalid flags: {flags:?}"))?;
+
+    let known = avb::KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED
+        | avb::KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_DISABLED;
+    if flags & !known != 0 {
+        bail!("Unknown flags: {:#x}", flags & !known);
+    }
+
+    Ok(KernelCmdlineDescriptor {
+        flags,
+        cmdline: cmdline.to_owned(),
+    })
+}
+
+/// Parse a property descriptor in the form `<key>=<value>`.
+fn parse_property(s: &str) -> Result<PropertyDescriptor> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <key>=<value>"))?;
+    if key.is_empty() {
+        bail!("Property key cannot be empty");
+    }
+
+    Ok(PropertyDescriptor {
+        key: key.to_owned(),
+        value: value.as_bytes().to_vec(),
+    })
+}
+
+/// Parse a SHA-256 vbmeta digest in hex.
+fn parse_vbmeta_digest(s: &str) -> Result<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(s, &mut digest)
+        .with_context(|| format!("Expected 64 hex digits: {s:?}"))?;
+
+    Ok(digest)
+}
+
+/// Parse a dtbo entry replacement in the form `<index>=<file>`.
+fn parse_dtbo_entry(s: &str) -> Result<(usize, PathBuf)> {
+    let (index, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <index>=<file>"))?;
+    let index = index
+        .parse()
+        .with_context(|| format!("Invalid entry index: {index:?}"))?;
+
+    Ok((index, PathBuf::from(path)))
+}
+
+/// Parse a signature padding scheme in the form `<format>=<padding>`.
+fn parse_signature_padding(s: &str) -> Result<(SignatureFormat, RsaPadding)> {
+    let (format, padding) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <format>=<padding>"))?;
+    let format = match format {
+        "avb" => SignatureFormat::Avb,
+        "payload" => SignatureFormat::Payload,
+        "zip" => SignatureFormat::OtaZip,
+        _ => bail!("Unknown signature format: {format}"),
+    };
+    let padding = match padding {
+        "pkcs1v15" => RsaPadding::Pkcs1v15,
+        "pss" => RsaPadding::Pss,
+        _ => bail!("Unknown RSA padding scheme: {padding}"),
+    };
+
+    format.check_padding(padding)?;
+
+    Ok((format, padding))
+}
+
+/// Message to store at the beginning of the output zip's archive comment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputComment {
+    /// Describe the avbroot version and the OTA's build.
+    Auto,
+    /// Keep the default "signed by avbroot" message.
+    None,
+    Custom(String),
+}
+
+impl OutputComment {
+    /// Get the message to pass to [`SigningWriter::set_comment_message()`] or
+    /// [`None`] to keep the default message.
+    pub fn message(&self, metadata: &OtaMetadata) -> Option<String> {
+        match self {
+            Self::Auto => Some(ota::provenance_comment(metadata)),
+            Self::None => None,
+            Self::Custom(s) => Some(s.clone()),
+        }
+    }
+}
+
+pub fn parse_output_comment(s: &str) -> Result<OutputComment> {
+    match s {
+        "auto" => Ok(OutputComment::Auto),
+        "none" => Ok(OutputComment::None),
+        _ => {
+            ota::validate_comment_message(s)?;
+            Ok(OutputComment::Custom(s.to_owned()))
+        }
+    }
+}
+
+/// Remove the kernel cmdline descriptors matching any of the `remove` patterns
+/// and then append the `add` descriptors. The order of all other descriptors is
+/// preserved.
+fn edit_kernel_cmdlines(
+    name: &str,
+    header: &mut Header,
+    remove: &[Regex],
+    add: &[KernelCmdlineDescriptor],
+) -> Result<()> {
+    for pattern in remove {
+        let removed = header.remove_kernel_cmdlines(|d| pattern.is_match(&d.cmdline));
+        if removed.is_empty() {
+            bail!("No kernel cmdline descriptor in {name} matches: {pattern}");
+        }
+
+        for d in removed {
+            status!(
+                "Removed {name} kernel cmdline descriptor: {:?} (flags: {:#x})",
+                d.cmdline,
+                d.flags,
+            );
+        }
+    }
+
+    for d in add {
+        header
+            .descriptors
+            .push(Descriptor::KernelCmdline(d.clone()));
+
+        status!(
+            "Added {name} kernel cmdline descriptor: {:?} (flags: {:#x})",
+            d.cmdline,
+            d.flags,
+        );
+    }
+
+    Ok(())
+}
+
+/// Remove and then add or replace property descriptors in a vbmeta header.
+fn edit_properties(
+    name: &str,
+    header: &mut Header,
+    delete: &[String],
+    set: &[PropertyDescriptor],
+) -> Result<()> {
+    for key in delete {
+        let removed = header.remove_properties(|d| d.key == *key);
+        if removed.is_empty() {
+            bail!("No property descriptor in {name} has key: {key:?}");
+        }
+
+        status!("Removed {name} property descriptor: {key:?}");
+    }
+
+    for d in set {
+        let old = header.set_property(d.clone());
+
+        match old {
+            Some(v) => status!(
+                "Replaced {name} property descriptor: {:?} (old value: {:?})",
+                d.key,
+                String::from_utf8_lossy(&v),
+            ),
+            None => status!("Added {name} property descriptor: {:?}", d.key),
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the size of the data in an image without AVB metadata that should be
+/// covered by a hash descriptor. Trailing zeros are excluded, like when avbtool
+/// is given the image before it was padded to the partition size, but never so
+/// many that an ext4 or erofs filesystem or a boot image would be cut short.
+/// The result is rounded up to `block_size` and never exceeds `image_size`.
+fn hashed_image_size(
+    mut reader: impl Read + Seek,
+    image_size: u64,
+    block_size: u64,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<u64> {
+    let mut size = padding::data_end(&mut reader, cancel_signal)?;
+
+    if let Some((_, declared)) = filesystem::declared_size(&mut reader)? {
+        size = size.max(declared);
+    }
+
+    reader.rewind()?;
+    if BootImage::from_reader(&mut reader).is_ok() {
+        size = size.max(reader.stream_position()?);
+    }
+
+    Ok(padding::round(size, block_size)
+        .unwrap_or(image_size)
+        .min(image_size))
+}
+
+/// Execute the re-signing part of a plan from [`plan_vbmeta()`]. The vbmeta
+/// descriptors are updated based on the footers from the specified images and
+/// then the vbmeta images are re-signed. If an image has no AVB metadata of its own,
+/// but is covered by a hash descriptor, then the digest is computed from the
+/// image. This applies to any partition, not just the ones that avbroot
+/// patches, so that eg. a raw firmware image passed to `--replace` works. If
+/// `trim_images` is true, then trailing zero padding is excluded from the
+/// hashed data (see [`hashed_image_size()`]). Otherwise, the entire image is
+/// hashed.
+#[allow(clippy::too_many_arguments)]
+fn update_vbmeta_descriptors(
+    images: &mut HashMap<String, Box<dyn ReadSeek + Send>>,
+    headers: &mut BTreeMap<String, Header>,
+    plan: &[VbmetaPlanEntry],
+    trim_images: bool,
+    root_edits: &RootVbmetaEdits,
+    key: &RsaPrivateKey,
+    block_size: u64,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let algorithm_type = crypto::validate_avb_key(key)?;
+    let mut updated = vec![];
+
+    for entry in plan {
+        let VbmetaAction::Sign {
+            deps,
+            edit_cmdline,
+            edit_props,
+        } = &entry.action
+        else {
+            continue;
+        };
+        let name = &entry.name;
+        let parent_header = headers.get_mut(name).unwrap();
+
+        parent_header.flags = entry.new_flags;
+
+        // Use the algorithm that matches the signing key.
+        if parent_header.algorithm_type != algorithm_type {
+            parent_header.algorithm_type = algorithm_type;
+
+            status!(
+                "{} signature algorithm type changed to {:?}",
+                name,
+                parent_header.algorithm_type
+            );
+        }
+
+        for dep in deps {
+            // This can't fail since the descriptor must have existed for the
+            // dependency to exist.
+            let parent_descriptor = parent_header
+                .descriptors
+                .iter_mut()
+                .find(|d| d.partition_name() == Some(dep))
+                .unwrap();
+
+            let reader = images.get_mut(dep).unwrap();
+            let header = match avb::load_image(&mut *reader) {
+                Ok((header, _, _)) => header,
+                Err(avb::Error::InvalidHeaderMagic(_)) => {
+                    let Descriptor::Hash(pd) = parent_descriptor else {
+                        bail!("{dep} has no vbmeta footer and {name}'s descriptor is not a hash");
+                    };
+
+                    // There's no vbmeta footer to take the descriptor from, so
+                    // the image itself is the hashed data.
+                    let image_size = reader.seek(SeekFrom::End(0))?;
+                    let hashed_size = if trim_images {
+                        hashed_image_size(&mut *reader, image_size, block_size, cancel_signal)
+                            .with_context(|| format!("Failed to find end of data: {dep}"))?
+                    } else {
+                        image_size
+                    };
+                    reader.rewind()?;
+
+                    pd.update(&mut *reader, hashed_size, cancel_signal)
+                        .with_context(|| format!("Failed to hash image: {dep}"))?;
+
+                    updated.push((
+                        name.clone(),
+                        dep.clone(),
+                        format!(
+                            "hash recomputed from image without vbmeta footer \
+                            ({hashed_size} of {image_size} bytes hashed)"
+                        ),
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to load vbmeta footer from image: {dep}")
+                    });
+                }
+            };
+
+            if header.public_key.is_empty() {
+                // vbmeta is unsigned. Use the existing descriptor.
+                let Some(descriptor) = header
+                    .descriptors
+                    .iter()
+                    .find(|d| d.partition_name() == Some(dep))
+                else {
+                    bail!("{name} has no descriptor for itself");
+                };
+
+                let kind = match (parent_descriptor, descriptor) {
+                    (Descriptor::Hash(pd), Descriptor::Hash(d)) => {
+                        *pd = d.clone();
+                        "hash"
+                    }
+                    (Descriptor::Hashtree(pd), Descriptor::Hashtree(d)) => {
+                        *pd = d.clone();
+                        "hashtree"
+                    }
+                    _ => {
+                        bail!("{name}'s descriptor for {dep} must match {dep}'s self descriptor");
+                    }
+                };
+
+                updated.push((
+                    name.clone(),
+                    dep.clone(),
+                    format!("{kind} copied from unsigned vbmeta footer"),
+                ));
+            } else {
+                // vbmeta is signed; Use a chain descriptor.
+                match parent_descriptor {
+                    Descriptor::ChainPartition(d) => {
+                        d.public_key = header.public_key;
+                    }
+                    _ => {
+                        bail!("{name}'s descriptor for {dep} must be a chain descriptor");
+                    }
+                }
+
+                updated.push((
+                    name.clone(),
+                    dep.clone(),
+                    "chain public key taken from signed vbmeta footer".to_owned(),
+                ));
+            }
+        }
+
+        if *edit_cmdline {
+            edit_kernel_cmdlines(
+                name,
+                parent_header,
+                &root_edits.cmdline_remove,
+                &root_edits.cmdline_add,
+            )?;
+        }
+        if *edit_props {
+            edit_properties(
+                name,
+                parent_header,
+                &root_edits.prop_delete,
+                &root_edits.prop_set,
+            )?;
+        }
+
+        parent_header
+            .sign(key)
+            .with_context(|| format!("Failed to sign vbmeta header for image: {name}"))?;
+
+        let mut writer = Cursor::new(Vec::new());
+        parent_header
+            .to_writer(&mut writer)
+            .with_context(|| format!("Failed to write vbmeta image: {name}"))?;
+
+        padding::write_zeros(&mut writer, block_size)
             .with_context(|| format!("Failed to write vbmeta padding: {name}"))?;
 
         *images.get_mut(name).unwrap() = Box::new(writer);
     }
 
+    for (name, dep, reason) in updated {
+        status!("Updated {name} descriptor for {dep}: {reason}");
+    }
+
     Ok(())
 }
 
@@ -426,11 +1441,14 @@ fn compress_image(
     mut stream: &mut Box<dyn ReadSeek + Send>,
     header: &Mutex<PayloadHeader>,
     block_size: u32,
+    temp_stage: &TempStage,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
     stream.rewind()?;
 
-    let writer = Cursor::new(Vec::new());
+    // The compressed image is written to a temporary file so that the memory
+    // usage doesn't depend on the image size.
+    let writer = BufWriter::new(temp_stage.named_file(OsStr::new(name))?);
     let mut compressed = CompressedPartitionWriter::new(writer, block_size)?;
 
     stream::copy(&mut stream, &mut compressed, cancel_signal)?;
@@ -443,23 +1461,230 @@ fn compress_image(
         .find(|p| p.partition_name == name)
         .unwrap();
     let writer = compressed.finish(partition)?;
+    header_locked.clear_unknown_data_fields(name);
+    drop(header_locked);
 
-    *stream = Box::new(writer);
+    *stream = Box::new(writer.into_inner().map_err(|e| e.into_error())?);
 
     Ok(())
 }
 
+/// The payload from a previously patched OTA. Its compressed images can be
+/// reused if the new images are identical.
+struct ReferencePayload {
+    file: PSeekFile,
+    offset: u64,
+    size: u64,
+    header: PayloadHeader,
+    /// Number of images that were and were not reused.
+    #[cfg(feature = "metrics")]
+    hits: Mutex<(u64, u64)>,
+}
+
+impl ReferencePayload {
+    fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+        let mut zip = ZipArchive::new(BufReader::new(file.clone()))
+            .with_context(|| format!("Failed to read zip: {path:?}"))?;
+        let entry = zip
+            .by_name(ota::PATH_PAYLOAD)
+            .with_context(|| format!("Failed to open zip entry: {}", ota::PATH_PAYLOAD))?;
+
+        if entry.compression() != CompressionMethod::Stored {
+            bail!("{} is not stored uncompressed", ota::PATH_PAYLOAD);
+        }
+
+        let offset = entry.data_start();
+        let size = entry.size();
+        drop(entry);
+
+        let reader = SectionReader::new(BufReader::new(file.clone()), offset, size)?;
+        let header =
+            PayloadHeader::from_reader(reader).context("Failed to load OTA payload header")?;
+
+        Ok(Self {
+            file,
+            offset,
+            size,
+            header,
+            #[cfg(feature = "metrics")]
+            hits: Mutex::default(),
+        })
+    }
+
+    fn reader(&self) -> io::Result<SectionReader<BufReader<PSeekFile>>> {
+        SectionReader::new(BufReader::new(self.file.clone()), self.offset, self.size)
+    }
+}
+
+/// Try to reuse the compressed data for an image from the reference payload
+/// instead of compressing it again. The data is only reused if its hash matches
+/// the reference manifest and it decompresses to exactly the new image, so a
+/// reference that doesn't match just results in the image being compressed
+/// normally. Returns whether the data was reused.
+fn reuse_compressed_image(
+    name: &str,
+    stream: &mut Box<dyn ReadSeek + Send>,
+    header: &Mutex<PayloadHeader>,
+    reference: &ReferencePayload,
+    temp_stage: &TempStage,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<bool> {
+    let block_size = header.lock().unwrap().manifest.block_size;
+    if reference.header.manifest.block_size != block_size {
+        return Ok(false);
+    }
+
+    let Some(ref_partition) = reference
+        .header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == name)
+    else {
+        return Ok(false);
+    };
+    let Some(PartitionInfo {
+        size: Some(size),
+        hash: Some(hash),
+    }) = &ref_partition.new_partition_info
+    else {
+        return Ok(false);
+    };
+    // Images compressed by avbroot always have a single operation.
+    let [ref_operation] = ref_partition.operations.as_slice() else {
+        return Ok(false);
+    };
+    let InstallOperation {
+        type_pb: Type::REPLACE_XZ,
+        data_offset: Some(data_offset),
+        data_length: Some(data_length),
+        data_sha256_hash: Some(data_hash),
+        ..
+    } = ref_operation
+    else {
+        return Ok(false);
+    };
+
+    let extent = Extent {
+        start_block: Some(0),
+        num_blocks: Some(size / u64::from(block_size)),
+    };
+    if size % u64::from(block_size) != 0 || ref_operation.dst_extents != [extent] {
+        return Ok(false);
+    }
+
+    // Hashing is much cheaper than compressing, so check the new image first.
+    stream.rewind()?;
+    let mut hashing_reader = HashingReader::new(
+        &mut *stream,
+        ring::digest::Context::new(&ring::digest::SHA256),
+    );
+    let new_size = stream::copy(&mut hashing_reader, io::sink(), cancel_signal)?;
+    let (_, context) = hashing_reader.finish();
+
+    if new_size != *size || context.finish().as_ref() != hash.as_slice() {
+        return Ok(false);
+    }
+
+    let offset = reference
+        .header
+        .blob_offset
+        .checked_add(*data_offset)
+        .ok_or_else(|| anyhow!("Reference data offset for {name} is too large"))?;
+    let mut reader = reference.reader()?;
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut data = BufWriter::new(temp_stage.named_file(OsStr::new(name))?);
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+
+    match stream::copy_n_inspect(
+        &mut reader,
+        &mut data,
+        *data_length,
+        |d| context.update(d),
+        cancel_signal,
+    ) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e.into()),
+        // The reference payload is truncated.
+        Err(_) => return Ok(false),
+    }
+
+    if context.finish().as_ref() != data_hash.as_slice() {
+        return Ok(false);
+    }
+
+    let mut data = data.into_inner().map_err(|e| e.into_error())?;
+    data.rewind()?;
+
+    // Make sure that the copied data really decompresses to the new image.
+    let mut hashing_reader = HashingReader::new(
+        XzDecoder::new(BufReader::new(&mut data)),
+        ring::digest::Context::new(&ring::digest::SHA256),
+    );
+
+    match stream::copy(&mut hashing_reader, io::sink(), cancel_signal) {
+        Ok(n) if n == *size => {}
+        Ok(_) => return Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e.into()),
+        Err(_) => return Ok(false),
+    }
+
+    let (_, context) = hashing_reader.finish();
+    if context.finish().as_ref() != hash.as_slice() {
+        return Ok(false);
+    }
+
+    let mut header_locked = header.lock().unwrap();
+    let partition = header_locked
+        .manifest
+        .partitions
+        .iter_mut()
+        .find(|p| p.partition_name == name)
+        .unwrap();
+    payload::prepare_replacement(partition)?;
+
+    // Like for a newly compressed image, the operation never reads from the
+    // source partition.
+    partition.old_partition_info = None;
+    partition.new_partition_info = ref_partition.new_partition_info.clone();
+    partition.operations = vec![InstallOperation {
+        // Must be manually updated by the caller.
+        data_offset: None,
+        ..ref_operation.clone()
+    }];
+    header_locked.clear_unknown_data_fields(name);
+
+    *stream = Box::new(data);
+
+    Ok(true)
+}
+
 #[allow(clippy::too_many_arguments)]
 fn patch_ota_payload(
     open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
     writer: impl Write,
     external_images: &HashMap<String, PathBuf>,
+    dtbo_entries: &BTreeMap<usize, Vec<u8>>,
     boot_partition: &str,
     root_patcher: Option<Box<dyn BootImagePatcher + Send>>,
-    clear_vbmeta_flags: bool,
+    ramdisk_target: Option<RamdiskCompressionTarget>,
+    vbmeta_options: &VbmetaOptions,
+    exclude: &BTreeSet<String>,
+    trim_images: bool,
+    root_edits: &RootVbmetaEdits,
     key_avb: &RsaPrivateKey,
-    key_ota: &RsaPrivateKey,
+    key_payload: &RsaPrivateKey,
     cert_ota: &Certificate,
+    cert_payload: Option<&Certificate>,
+    reference: Option<&ReferencePayload>,
+    jobs: NonZeroUsize,
+    save_stock_images: Option<&Path>,
+    temp_stage: &mut TempStage,
+    warnings: &WarningCollector,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<(String, u64)> {
     let header =
@@ -467,6 +1692,13 @@ fn patch_ota_payload(
     if !header.is_full_ota() {
         bail!("Payload is a delta OTA, not a full OTA");
     }
+    let unknown_paths = header.unknown_field_paths();
+    if !unknown_paths.is_empty() {
+        status!(
+            "Preserving manifest fields unknown to avbroot: {}",
+            unknown_paths.join(", "),
+        );
+    }
 
     let header = Mutex::new(header);
     let header_locked = header.lock().unwrap();
@@ -477,6 +1709,13 @@ fn patch_ota_payload(
         .map(|p| p.partition_name.as_str())
         .collect::<HashSet<_>>();
 
+    if header_locked.is_partial_update() {
+        status!(
+            "Payload is a partial update containing: {}",
+            joined(sorted(all_partitions.iter())),
+        );
+    }
+
     // Use external partition images if provided. This may be a larger set than
     // what's needed for our patches.
     for (name, path) in external_images {
@@ -488,16 +1727,27 @@ fn patch_ota_payload(
     // Determine what images need to be patched. For simplicity, we pre-read all
     // vbmeta images since they're tiny. They're discarded later if the they
     // don't need to be modified.
-    let required_images = get_required_images(
+    let mut required_images = get_required_images(
         &header_locked.manifest,
         boot_partition,
         root_patcher.is_some(),
     )?;
-    let vbmeta_images = required_images
-        .iter()
-        .filter(|(n, _)| n.starts_with("@vbmeta:"))
-        .map(|(_, p)| p.clone())
-        .collect::<HashSet<_>>();
+
+    if !dtbo_entries.is_empty() {
+        if !all_partitions.contains("dtbo") {
+            bail!("Cannot replace dtbo entries because the OTA has no dtbo partition");
+        }
+
+        required_images.insert("@dtbo".to_owned(), "dtbo".to_owned());
+    }
+
+    check_skip_avb(&vbmeta_options.skip_avb, &all_partitions, &required_images)?;
+    check_exclude(exclude, &all_partitions, &required_images, external_images)?;
+
+    // Excluded vbmeta images are left as is on the device.
+    required_images.retain(|_, p| !exclude.contains(p));
+
+    let vbmeta_images = get_vbmeta_images(&required_images);
 
     // The set of source images to be inserted into the new payload, replacing
     // what was in the original payload. Initially, this refers to either real
@@ -512,36 +1762,87 @@ fn patch_ota_payload(
         cancel_signal,
     )?;
 
+    if let Some(dir) = save_stock_images {
+        // Images from --replace are not stock images.
+        let names = required_images
+            .values()
+            .filter(|n| !external_images.contains_key(*n))
+            .map(|n| n.as_str())
+            .collect::<BTreeSet<_>>();
+
+        device::save_stock_images(dir, &mut input_streams, names, cancel_signal)
+            .with_context(|| format!("Failed to save stock images: {dir:?}"))?;
+    }
+
+    // Decide what happens to every vbmeta image before anything is patched so
+    // that conflicting options are reported early.
+    let modified = input_streams.keys().cloned().collect::<HashSet<_>>();
+    let mut vbmeta_headers = load_vbmeta_headers(&mut input_streams, &vbmeta_images, warnings)?;
+    let rebuilt = rebuild_stub_root_vbmeta(
+        &open_payload,
+        &header_locked,
+        &mut input_streams,
+        external_images,
+        &mut vbmeta_headers,
+        &modified,
+        exclude,
+        vbmeta_options,
+        warnings,
+        cancel_signal,
+    )?;
+    check_excluded_descriptors(&vbmeta_headers, exclude, &vbmeta_options.skip_avb)?;
+    let vbmeta_plan = plan_vbmeta(&vbmeta_headers, &modified, vbmeta_options, warnings)?;
+    if rebuilt {
+        check_rebuilt_signed(&vbmeta_plan)?;
+    }
+
+    status!("vbmeta plan:");
+    for entry in &vbmeta_plan {
+        status!("- {entry}");
+    }
+
+    // Get rid of input readers for vbmeta partitions we don't need to modify.
+    for entry in &vbmeta_plan {
+        if !matches!(entry.action, VbmetaAction::Sign { .. }) {
+            input_streams.remove(&entry.name);
+        }
+    }
+
     patch_boot_images(
         &required_images,
         &mut input_streams,
         root_patcher,
+        ramdisk_target,
         key_avb,
         cert_ota,
+        cert_payload,
+        warnings,
         cancel_signal,
     )?;
 
-    let mut vbmeta_order = get_vbmeta_patch_order(&mut input_streams, &vbmeta_images)?;
+    if !dtbo_entries.is_empty() {
+        status!("Replacing dtbo entries: {}", joined(dtbo_entries.keys()));
 
-    status!(
-        "Patching vbmeta images: {}",
-        joined(vbmeta_order.iter().map(|(n, _, _)| n)),
-    );
+        // This works the same whether the image came from --replace or from
+        // the original payload.
+        let reader = input_streams.remove("dtbo").unwrap();
+        let mut writer = Cursor::new(Vec::new());
 
-    // Get rid of input readers for vbmeta partitions we don't need to modify.
-    for name in &vbmeta_images {
-        // Linear search is fast enough.
-        if !vbmeta_order.iter().any(|v| v.0 == *name) {
-            input_streams.remove(name);
-        }
+        boot::patch_dtbo(reader, &mut writer, key_avb, dtbo_entries)
+            .context("Failed to patch dtbo image")?;
+
+        input_streams.insert("dtbo".to_owned(), Box::new(writer));
     }
 
     update_vbmeta_descriptors(
         &mut input_streams,
-        &mut vbmeta_order,
-        clear_vbmeta_flags,
+        &mut vbmeta_headers,
+        &vbmeta_plan,
+        trim_images,
+        root_edits,
         key_avb,
         header_locked.manifest.block_size.into(),
+        cancel_signal,
     )?;
 
     status!(
@@ -550,20 +1851,85 @@ fn patch_ota_payload(
     );
 
     let block_size = header_locked.manifest.block_size;
+    // Process the images in the order they appear in the payload so that the
+    // results are also received in that order.
+    let images = header_locked
+        .manifest
+        .partitions
+        .iter()
+        .filter_map(|p| input_streams.remove_entry(&p.partition_name))
+        .collect::<Vec<_>>();
     drop(header_locked);
 
-    input_streams
-        .par_iter_mut()
-        .map(|(name, stream)| -> Result<()> {
-            compress_image(name, stream, &header, block_size, cancel_signal)
-                .with_context(|| format!("Failed to compress image: {name}"))
-        })
-        .collect::<Result<()>>()?;
+    pipeline::run_ordered(
+        jobs,
+        images,
+        |(name, mut stream)| -> Result<_> {
+            if let Some(r) = reference {
+                let reused = reuse_compressed_image(
+                    &name,
+                    &mut stream,
+                    &header,
+                    r,
+                    temp_stage,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to check reference image: {name}"))?;
+
+                #[cfg(feature = "metrics")]
+                {
+                    let mut hits = r.hits.lock().unwrap();
+                    if reused {
+                        hits.0 += 1;
+                    } else {
+                        hits.1 += 1;
+                    }
+                }
+
+                if reused {
+                    status!("Reused compressed image from reference OTA: {name}");
+                    return Ok((name, stream));
+                }
+            }
+
+            compress_image(
+                &name,
+                &mut stream,
+                &header,
+                block_size,
+                temp_stage,
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to compress image: {name}"))?;
+
+            Ok((name, stream))
+        },
+        |(name, stream)| {
+            input_streams.insert(name, stream);
+            Ok(())
+        },
+    )?;
+
+    // This is the peak since the files are deleted as they're copied into the
+    // new payload.
+    temp_stage.update_peak_size()?;
 
     status!("Generating new OTA payload");
 
     let header_locked = header.lock().unwrap();
-    let mut payload_writer = PayloadWriter::new(writer, header_locked.clone(), key_ota.clone())
+    let mut new_header = header_locked.clone();
+
+    if !exclude.is_empty() {
+        status!("Excluding partitions from payload: {}", joined(exclude));
+
+        for name in exclude {
+            new_header
+                .remove_partition(name)
+                .with_context(|| format!("Failed to exclude partition: {name}"))?;
+        }
+    }
+
+    let mut payload_writer = PayloadWriter::new(writer, new_header, key_payload.clone())
         .context("Failed to write payload header")?;
     let mut orig_payload_reader = open_payload()?;
 
@@ -586,16 +1952,21 @@ fn patch_ota_payload(
             stream::copy_n(&mut reader, &mut payload_writer, data_length, cancel_signal)
                 .with_context(|| format!("Failed to copy from replacement image: {name}"))?;
         } else {
-            // Copy from the original payload.
-            let pi = payload_writer.partition_index().unwrap();
+            // Copy from the original payload. The partition indices differ if
+            // any partitions were excluded.
             let oi = payload_writer.operation_index().unwrap();
-            let orig_partition = &header_locked.manifest.partitions[pi];
+            let orig_partition = header_locked
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| p.partition_name == name)
+                .unwrap();
             let orig_operation = &orig_partition.operations[oi];
 
             let data_offset = orig_operation
                 .data_offset
                 .and_then(|o| o.checked_add(header_locked.blob_offset))
-                .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+                .ok_or_else(|| anyhow!("Missing data_offset in {name} operation #{oi}"))?;
 
             orig_payload_reader
                 .seek(SeekFrom::Start(data_offset))
@@ -618,18 +1989,81 @@ fn patch_ota_payload(
     Ok((properties, metadata_size))
 }
 
+/// Get the new care map ranges for a partition if it was replaced with an
+/// external image. The ranges for all other partitions are left untouched
+/// since avbroot never modifies their contents.
+fn replaced_care_map_ranges(
+    external_images: &HashMap<String, PathBuf>,
+    name: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Option<Vec<ota::BlockRange>>, ota::Error> {
+    let Some(path) = external_images.get(name) else {
+        return Ok(None);
+    };
+
+    status!("Generating care map ranges for replaced partition: {name}");
+
+    let reader = BufReader::new(File::open(path)?);
+    let ranges = ota::generate_care_map(reader, cancel_signal)?;
+
+    Ok(Some(ranges))
+}
+
+/// Copy every entry in `paths` (sanitized name to original name) that can be
+/// passed through in its compressed form (see [`ota::can_copy_raw()`]). The
+/// sanitized names of the copied entries are returned.
+pub fn copy_raw_entries(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    zip_writer: &mut ZipWriter<impl Write>,
+    paths: &BTreeMap<String, String>,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<HashSet<String>> {
+    let indices = ota::entry_indices(zip_reader).context("Failed to read OTA zip entries")?;
+    let mut raw_paths = HashSet::new();
+
+    for (path, original_path) in paths {
+        let index = indices[original_path];
+        let compression = zip_reader
+            .by_index_raw(index)
+            .with_context(|| format!("Failed to open zip entry: {original_path:?}"))?
+            .compression();
+        if !ota::can_copy_raw(path, compression) {
+            continue;
+        }
+
+        status!("Copying zip entry without recompression: {path}");
+
+        ota::copy_entry_raw(zip_reader, zip_writer, index, path, cancel_signal)
+            .with_context(|| format!("Failed to copy zip entry: {path}"))?;
+        raw_paths.insert(path.clone());
+    }
+
+    Ok(raw_paths)
+}
+
 #[allow(clippy::too_many_arguments)]
 fn patch_ota_zip(
     raw_reader: &PSeekFile,
     zip_reader: &mut ZipArchive<impl Read + Seek>,
     mut zip_writer: &mut ZipWriter<impl Write>,
     external_images: &HashMap<String, PathBuf>,
+    dtbo_entries: &BTreeMap<usize, Vec<u8>>,
     boot_partition: &str,
     mut root_patch: Option<Box<dyn BootImagePatcher + Send>>,
-    clear_vbmeta_flags: bool,
+    ramdisk_target: Option<RamdiskCompressionTarget>,
+    vbmeta_options: &VbmetaOptions,
+    exclude: &BTreeSet<String>,
+    trim_images: bool,
+    root_edits: &RootVbmetaEdits,
     key_avb: &RsaPrivateKey,
-    key_ota: &RsaPrivateKey,
+    key_payload: &RsaPrivateKey,
     cert_ota: &Certificate,
+    cert_payload: Option<&Certificate>,
+    reference: Option<&ReferencePayload>,
+    jobs: NonZeroUsize,
+    save_stock_images: Option<&Path>,
+    temp_stage: &mut TempStage,
+    warnings: &WarningCollector,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<(OtaMetadata, u64)> {
     let mut missing = BTreeSet::from([
@@ -640,13 +2074,11 @@ fn patch_ota_zip(
     ]);
 
     // Keep in sorted order for reproducibility and to guarantee that the
-    // payload is processed before its properties file.
-    let paths = zip_reader
-        .file_names()
-        .map(|p| p.to_owned())
-        .collect::<BTreeSet<_>>();
+    // payload is processed before its properties file. The output only ever
+    // uses the sanitized names.
+    let paths = ota::sanitized_entry_names(zip_reader).context("Invalid OTA zip entry names")?;
 
-    for path in &paths {
+    for path in paths.keys() {
         missing.remove(path.as_str());
     }
 
@@ -654,16 +2086,25 @@ fn patch_ota_zip(
         bail!("Missing entries in OTA zip: {:?}", joined(missing));
     }
 
+    // Compressed entries that are passed through unmodified are copied as-is
+    // before everything else. This avoids recompressing them and keeps a
+    // stored entry last for computing where the OTA metadata begins.
+    let raw_paths = copy_raw_entries(zip_reader, zip_writer, &paths, cancel_signal)?;
+
     let mut metadata_pb_raw = None;
     let mut properties = None;
     let mut payload_metadata_size = None;
     let mut entries = vec![];
     let mut last_entry_used_zip64 = false;
 
-    for path in &paths {
+    for (path, original_path) in &paths {
+        if raw_paths.contains(path.as_str()) {
+            continue;
+        }
+
         let mut reader = zip_reader
-            .by_name(path)
-            .with_context(|| format!("Failed to open zip entry: {path}"))?;
+            .by_name(original_path)
+            .with_context(|| format!("Failed to open zip entry: {original_path:?}"))?;
 
         // Android's libarchive parser is broken and only reads data descriptor
         // size fields as 64-bit integers if the central directory says the file
@@ -732,13 +2173,24 @@ fn patch_ota_zip(
                     },
                     &mut writer,
                     external_images,
+                    dtbo_entries,
                     boot_partition,
                     // There's only one payload in the OTA.
                     root_patch.take(),
-                    clear_vbmeta_flags,
+                    ramdisk_target,
+                    vbmeta_options,
+                    exclude,
+                    trim_images,
+                    root_edits,
                     key_avb,
-                    key_ota,
+                    key_payload,
                     cert_ota,
+                    cert_payload,
+                    reference,
+                    jobs,
+                    save_stock_images,
+                    temp_stage,
+                    warnings,
                     cancel_signal,
                 )
                 .with_context(|| format!("Failed to patch payload: {path}"))?;
@@ -754,6 +2206,32 @@ fn patch_ota_zip(
                     .write_all(properties.as_ref().unwrap().as_bytes())
                     .with_context(|| format!("Failed to write payload properties: {path}"))?;
             }
+            ota::PATH_CARE_MAP_PB | ota::PATH_CARE_MAP_TXT
+                if !external_images.is_empty() || !exclude.is_empty() =>
+            {
+                status!("Patching zip entry: {path}");
+
+                let mut buf = vec![];
+                reader
+                    .read_to_end(&mut buf)
+                    .with_context(|| format!("Failed to read care map: {path}"))?;
+
+                let ranges_for =
+                    |n: &str| replaced_care_map_ranges(external_images, n, cancel_signal);
+                let data = if path == ota::PATH_CARE_MAP_PB {
+                    ota::update_care_map_pb(&buf, exclude, ranges_for)
+                } else {
+                    String::from_utf8(buf)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+                        .and_then(|d| ota::update_care_map_txt(&d, exclude, ranges_for))
+                        .map(|d| d.into_bytes())
+                }
+                .with_context(|| format!("Failed to update care map: {path}"))?;
+
+                writer
+                    .write_all(&data)
+                    .with_context(|| format!("Failed to write care map: {path}"))?;
+            }
             _ => {
                 status!("Copying zip entry: {path}");
 
@@ -797,6 +2275,7 @@ fn extract_ota_zip(
     payload_size: u64,
     header: &PayloadHeader,
     images: &BTreeSet<String>,
+    options: &ExtractOptions,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
     for name in images {
@@ -810,14 +2289,27 @@ fn extract_ota_zip(
 
     status!("Extracting from the payload: {}", joined(images));
 
-    // Pre-open all output files.
+    // Pre-open all output files. They are preallocated to the final partition
+    // size since the operations may write to the extents in any order.
     let output_files = images
         .iter()
         .map(|name| {
+            let partition = header
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| p.partition_name == *name)
+                .ok_or_else(|| anyhow!("Partition not found in payload: {name}"))?;
+            let size = payload::partition_size(partition, header.manifest.block_size)
+                .with_context(|| format!("Failed to compute partition size: {name}"))?;
+
             let path = directory.join(format!("{name}.img"));
             let file = File::create(&path)
                 .map(PSeekFile::new)
                 .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+            file.set_len(size)
+                .with_context(|| format!("Failed to preallocate {size} bytes: {path:?}"))?;
+
             Ok((name.as_str(), file))
         })
         .collect::<Result<HashMap<_, _>>>()?;
@@ -825,7 +2317,7 @@ fn extract_ota_zip(
     // Extract the images. Each time we're asked to open a new file, we just
     // clone the relevant PSeekFile. We only ever have one actual kernel file
     // descriptor for each file.
-    payload::extract_images(
+    payload::extract_images_with_options(
         || {
             Ok(Box::new(SectionReader::new(
                 BufReader::new(raw_reader.clone()),
@@ -836,6 +2328,8 @@ fn extract_ota_zip(
         |name| Ok(Box::new(BufWriter::new(output_files[name].clone()))),
         header,
         images.iter().map(|n| n.as_str()),
+        options,
+        |name| status!("Extracted {name}"),
         cancel_signal,
     )
     .context("Failed to extract images from payload")?;
@@ -843,343 +2337,2549 @@ fn extract_ota_zip(
     Ok(())
 }
 
-pub fn patch_subcommand(cli: &PatchCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
-    let output = cli.output.as_ref().map_or_else(
-        || {
-            let mut s = cli.input.clone().into_os_string();
-            s.push(".patched");
-            Cow::Owned(PathBuf::from(s))
-        },
-        Cow::Borrowed,
-    );
+/// Write the whole-file signature of the output zip. The message from
+/// `output_comment` is placed at the beginning of the archive comment, before
+/// the signature, like signapk does. Without `output_comment` or with
+/// [`OutputComment::None`], the default message is kept.
+pub fn sign_output_zip<W: Write>(
+    mut signing_writer: SigningWriter<W>,
+    output_comment: Option<&OutputComment>,
+    metadata: &OtaMetadata,
+    key_ota: &RsaPrivateKey,
+    cert_ota: &Certificate,
+) -> Result<W> {
+    if let Some(message) = output_comment.and_then(|c| c.message(metadata)) {
+        signing_writer
+            .set_comment_message(&message)
+            .context("Failed to set output zip comment")?;
+    }
 
-    let passphrase_avb = if let Some(v) = &cli.pass_avb_env_var {
-        PassphraseSource::EnvVar(v.clone())
-    } else if let Some(p) = &cli.pass_avb_file {
-        PassphraseSource::File(p.clone())
-    } else {
-        PassphraseSource::Prompt(format!("Enter passphrase for {:?}: ", cli.key_avb))
-    };
-    let passphrase_ota = if let Some(v) = &cli.pass_ota_env_var {
-        PassphraseSource::EnvVar(v.clone())
-    } else if let Some(p) = &cli.pass_ota_file {
-        PassphraseSource::File(p.clone())
-    } else {
-        PassphraseSource::Prompt(format!("Enter passphrase for {:?}: ", cli.key_ota))
-    };
+    signing_writer
+        .finish(key_ota, cert_ota)
+        .context("Failed to sign output zip")
+}
 
-    let key_avb = crypto::read_pem_key_file(&cli.key_avb, &passphrase_avb)
-        .with_context(|| format!("Failed to load key: {:?}", cli.key_avb))?;
-    let key_ota = crypto::read_pem_key_file(&cli.key_ota, &passphrase_ota)
-        .with_context(|| format!("Failed to load key: {:?}", cli.key_ota))?;
-    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
-        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+/// Look for signs that the OTA was already patched by avbroot. Returns a list of
+/// the markers that were found.
+fn detect_prior_patch(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    cert_ota: &Certificate,
+) -> Result<Vec<&'static str>> {
+    let mut markers = vec![];
 
-    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
-        bail!(
-            "Private key {:?} does not match certificate {:?}",
-            cli.key_ota,
-            cli.cert_ota,
-        );
+    if ota::is_avbroot_comment(zip_reader.comment()) {
+        markers.push("avbroot archive comment");
     }
 
-    let mut external_images = HashMap::new();
-
-    for item in cli.replace.chunks_exact(2) {
-        let name = item[0]
-            .to_str()
-            .ok_or_else(|| anyhow!("Invalid partition name: {:?}", item[0]))?;
-        let path = Path::new(&item[1]);
+    if zip_reader.file_names().any(|n| n == ota::PATH_OTACERT) {
+        let entry = zip_reader
+            .by_name(ota::PATH_OTACERT)
+            .with_context(|| format!("Failed to open zip entry: {}", ota::PATH_OTACERT))?;
 
-        external_images.insert(name.to_owned(), path.to_owned());
+        // A previous OTA could have been signed with a different key, so only
+        // an exact match with the new certificate is conclusive.
+        if crypto::read_pem_cert(entry).map_or(false, |c| c == *cert_ota) {
+            markers.push("custom OTA certificate");
+        }
     }
 
-    let root_patcher: Option<Box<dyn BootImagePatcher + Send>> = if cli.root.rootless {
-        None
-    } else if let Some(magisk) = &cli.root.magisk {
-        let patcher = MagiskRootPatcher::new(
-            magisk,
-            cli.magisk_preinit_device.as_deref(),
-            cli.magisk_random_seed,
-            cli.ignore_magisk_warnings,
-            move |s| warning!("{s}"),
-        )
-        .context("Failed to create Magisk boot image patcher")?;
+    Ok(markers)
+}
 
-        Some(Box::new(patcher))
-    } else if let Some(prepatched) = &cli.root.prepatched {
-        let patcher =
-            PrepatchedImagePatcher::new(prepatched, cli.ignore_prepatched_compat + 1, move |s| {
-                warning!("{s}");
-            });
+/// Check the VINTF metadata in the OTA's `compatibility.zip` for
+/// inconsistencies. The VINTF manifests inside replaced partition images cannot
+/// be read because avbroot has no filesystem readers, so those are reported as
+/// a warning instead of being checked.
+fn check_vintf_compatibility(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    external_images: &HashMap<String, PathBuf>,
+    warnings: &WarningCollector,
+) -> Result<()> {
+    status!("Checking VINTF compatibility: {}", ota::PATH_COMPATIBILITY);
 
-        Some(Box::new(patcher))
-    } else {
-        unreachable!()
-    };
+    let mut entry = zip_reader
+        .by_name(ota::PATH_COMPATIBILITY)
+        .with_context(|| format!("Failed to open zip entry: {}", ota::PATH_COMPATIBILITY))?;
+    let mut data = vec![];
+    entry
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read zip entry: {}", ota::PATH_COMPATIBILITY))?;
 
-    let start = Instant::now();
+    let infos = vintf::load_compatibility_zip(Cursor::new(data))
+        .with_context(|| format!("Failed to parse: {}", ota::PATH_COMPATIBILITY))?;
 
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.clone()))
-        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+    vintf::check_compatibility(&infos).context("OTA's VINTF metadata is incompatible")?;
 
-    // Open the output file for reading too, so we can verify offsets later.
-    let temp_writer = NamedTempFile::with_prefix_in(
-        output
-            .file_name()
-            .unwrap_or_else(|| OsStr::new("avbroot.tmp")),
-        output.parent().unwrap_or_else(|| Path::new(".")),
-    )
-    .context("Failed to open temporary output file")?;
-    let temp_path = temp_writer.path().to_owned();
-    let hole_punching_writer = HolePunchingWriter::new(temp_writer);
-    let buffered_writer = BufWriter::new(hole_punching_writer);
-    let signing_writer = SigningWriter::new(buffered_writer);
-    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    let replaced = sorted(
+        external_images
+            .keys()
+            .filter(|n| vintf::PARTITIONS.contains(&n.as_str())),
+    );
 
-    let (metadata, payload_metadata_size) = patch_ota_zip(
-        &raw_reader,
-        &mut zip_reader,
-        &mut zip_writer,
-        &external_images,
-        &cli.boot_partition,
-        root_patcher,
-        cli.clear_vbmeta_flags,
-        &key_avb,
-        &key_ota,
-        &cert_ota,
-        cancel_signal,
-    )
-    .context("Failed to patch OTA zip")?;
+    if !replaced.is_empty() {
+        warnings.emit(
+            WarningCode::VintfUnchecked,
+            Severity::Medium,
+            format!(
+                "Cannot check VINTF compatibility of replaced partitions: {}",
+                joined(&replaced),
+            ),
+        );
 
-    let signing_writer = zip_writer
-        .finish()
-        .context("Failed to finalize output zip")?;
-    let buffered_writer = signing_writer
-        .finish(&key_ota, &cert_ota)
-        .context("Failed to sign output zip")?;
-    let hole_punching_writer = buffered_writer
-        .into_inner()
-        .context("Failed to flush output zip")?;
-    let mut temp_writer = hole_punching_writer.into_inner();
-    temp_writer.flush().context("Failed to flush output zip")?;
+        for info in &infos {
+            status!("- {info}");
+        }
+    }
 
-    // We do a lot of low-level hackery. Reopen and verify offsets.
-    status!("Verifying metadata offsets");
-    temp_writer.rewind()?;
-    ota::verify_metadata(
-        BufReader::new(&mut temp_writer),
-        &metadata,
-        payload_metadata_size,
-    )
-    .context("Failed to verify OTA metadata offsets")?;
+    Ok(())
+}
 
-    status!("Completed after {:.1}s", start.elapsed().as_secs_f64());
+/// Check that the KMI version of the GKI kernel in `boot` matches the kernel
+/// modules in `vendor_boot`. This is only done if either image is replaced or
+/// if a prepatched boot image is used because the stock images are assumed to
+/// be consistent. A prepatched image is used in place of `boot` if it contains
+/// a GKI kernel. The modules in `vendor_dlkm` cannot be checked because avbroot
+/// has no filesystem readers.
+fn check_kmi_compatibility(
+    input: &Path,
+    external_images: &HashMap<String, PathBuf>,
+    prepatched: Option<&Path>,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let replaced = ["boot", "vendor_boot"]
+        .iter()
+        .any(|n| external_images.contains_key(*n));
+    if !replaced && prepatched.is_none() {
+        return Ok(());
+    }
 
-    // NamedTempFile forces 600 permissions on temp files because it's the safe
-    // option for a shared /tmp. Since we're writing to the output file's
-    // directory, just mimic umask.
-    #[cfg(unix)]
-    {
-        use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+    let (raw_reader, payload_offset, payload_size, header) = open_ota_payload(input)?;
+    let open_payload = || -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(SectionReader::new(
+            BufReader::new(raw_reader.clone()),
+            payload_offset,
+            payload_size,
+        )?))
+    };
 
-        use rustix::{fs::Mode, process::umask};
+    let has_partition = |name: &str| {
+        header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| p.partition_name == name)
+    };
+    if !has_partition("boot") || !has_partition("vendor_boot") {
+        status!("Skipping KMI check: OTA does not contain boot and vendor_boot");
+        return Ok(());
+    }
 
-        let mask = umask(Mode::empty());
-        umask(mask);
+    let load_image = |name: &str| -> Result<BootImage> {
+        if let Some(path) = external_images.get(name) {
+            let reader = compression::open_standalone(path)
+                .with_context(|| format!("Failed to open external image: {path:?}"))?;
+            BootImage::from_reader(BufReader::new(reader))
+                .with_context(|| format!("Failed to read boot image: {path:?}"))
+        } else {
+            let stream =
+                payload::extract_image_to_memory(&open_payload, &header, name, cancel_signal)
+                    .with_context(|| format!("Failed to extract from original payload: {name}"))?;
+            BootImage::from_reader(stream)
+                .with_context(|| format!("Failed to read boot image: {name}"))
+        }
+    };
 
-        // Mac uses a 16-bit value.
-        #[allow(clippy::useless_conversion)]
-        let mode = u32::from(0o666 & !mask.bits());
+    status!("Checking GKI KMI compatibility: boot, vendor_boot");
 
-        temp_writer
-            .as_file()
-            .set_permissions(Permissions::from_mode(mode))
-            .with_context(|| format!("Failed to set permissions to {mode:o}: {temp_path:?}"))?;
+    let vendor_boot_image = load_image("vendor_boot")?;
+    let mut check = None;
+
+    if let Some(path) = prepatched {
+        let reader = compression::open_standalone(path)
+            .with_context(|| format!("Failed to open prepatched image: {path:?}"))?;
+        let image = BootImage::from_reader(BufReader::new(reader))
+            .with_context(|| format!("Failed to read boot image: {path:?}"))?;
+
+        check = boot::check_kmi(&image, &vendor_boot_image)?;
     }
 
-    temp_writer.persist(output.as_ref()).with_context(|| {
-        format!("Failed to move temporary file to output path: {temp_path:?} -> {output:?}")
-    })?;
+    if check.is_none() {
+        check = boot::check_kmi(&load_image("boot")?, &vendor_boot_image)?;
+    }
 
-    Ok(())
-}
+    let Some(check) = check else {
+        status!("Skipping KMI check: boot image does not contain a GKI kernel");
+        return Ok(());
+    };
 
-pub fn extract_subcommand(cli: &ExtractCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut zip = ZipArchive::new(BufReader::new(raw_reader.clone()))
-        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
-    let payload_entry = zip
-        .by_name(ota::PATH_PAYLOAD)
-        .with_context(|| format!("Failed to open zip entry: {:?}", ota::PATH_PAYLOAD))?;
-    let payload_offset = payload_entry.data_start();
-    let payload_size = payload_entry.size();
+    if !check.mismatches.is_empty() {
+        bail!(
+            "vendor_boot kernel modules do not match KMI {}: {}. Use --skip-kmi-check to ignore",
+            check.kernel_kmi,
+            joined(&check.mismatches),
+        );
+    }
 
-    // Open the payload data directly.
-    let mut payload_reader = SectionReader::new(
-        BufReader::new(raw_reader.clone()),
-        payload_offset,
-        payload_size,
-    )?;
+    status!(
+        "Checked {} kernel modules against KMI {}",
+        check.modules_checked,
+        check.kernel_kmi,
+    );
 
-    let header = PayloadHeader::from_reader(&mut payload_reader)
-        .context("Failed to load OTA payload header")?;
+    Ok(())
+}
+
+/// Partition that is always included in the DSU image set.
+const DSU_SYSTEM_PARTITION: &str = "system";
+
+/// DSU images are padded to a multiple of this size. This is also the block
+/// size that avbtool uses for appended vbmeta images.
+const DSU_BLOCK_SIZE: u64 = 4096;
+
+/// Userdata size used in the printed DSU installation command. This is the same
+/// as the example in the AOSP documentation.
+const DSU_USERDATA_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Extract the images for installing an OTA with Dynamic System Updates (DSU)
+/// and print the commands for starting the installation.
+///
+/// DSU verifies each image with the vbmeta header in its own footer instead of
+/// going through `vbmeta_system`, so the headers are signed with `key_avb`. A
+/// `vbmeta.img` containing the hash tree descriptors of all images and the AVB
+/// public key are written too.
+fn export_dsu(
+    ota_path: &Path,
+    directory: &Path,
+    extra_partitions: &[String],
+    key_avb: &RsaPrivateKey,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let (raw_reader, payload_offset, payload_size, header) = open_ota_payload(ota_path)?;
     if !header.is_full_ota() {
         bail!("Payload is a delta OTA, not a full OTA");
     }
 
-    let mut unique_images = BTreeSet::new();
+    let mut images = BTreeSet::from([DSU_SYSTEM_PARTITION.to_owned()]);
+    images.extend(extra_partitions.iter().cloned());
 
-    if cli.all {
-        unique_images.extend(
-            header
-                .manifest
-                .partitions
+    extract_ota_zip(
+        &raw_reader,
+        directory,
+        payload_offset,
+        payload_size,
+        &header,
+        &images,
+        &ExtractOptions::default(),
+        cancel_signal,
+    )?;
+
+    let algorithm_type = crypto::validate_avb_key(key_avb)?;
+    let mut root_header = None;
+    let mut descriptors = vec![];
+    let mut system_size = 0;
+
+    for name in &images {
+        let path = directory.join(format!("{name}.img"));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+
+        let (mut avb_header, footer, image_size) = avb::load_image(BufReader::new(&mut file))
+            .with_context(|| format!("Failed to load vbmeta structures: {path:?}"))?;
+        let Some(footer) = footer else {
+            bail!("{name} has no vbmeta footer");
+        };
+
+        avb_header.algorithm_type = algorithm_type;
+        avb_header
+            .sign(key_avb)
+            .with_context(|| format!("Failed to sign vbmeta header: {name}"))?;
+
+        let new_size = avb::replace_appended_header(
+            &mut file,
+            &avb_header,
+            &footer,
+            image_size,
+            DSU_BLOCK_SIZE,
+        )
+        .with_context(|| format!("Failed to write vbmeta structures: {path:?}"))?;
+
+        descriptors.extend(
+            avb_header
+                .descriptors
                 .iter()
-                .map(|p| &p.partition_name)
+                .filter(|d| matches!(d, Descriptor::Hashtree(_)))
                 .cloned(),
         );
-    } else {
-        let images = get_required_images(&header.manifest, &cli.boot_partition, true)?;
 
-        if cli.boot_only {
-            unique_images.insert(images["@rootpatch"].clone());
-        } else {
-            unique_images.extend(images.into_values());
+        if name == DSU_SYSTEM_PARTITION {
+            system_size = new_size;
+            root_header = Some(avb_header);
         }
     }
 
+    // Keep the version fields and release string from the system image.
+    let mut root_header = root_header.unwrap();
+    root_header.descriptors = descriptors;
+    root_header.flags = 0;
+    root_header.rollback_index_location = 0;
+    root_header
+        .sign(key_avb)
+        .context("Failed to sign DSU vbmeta header")?;
+
+    let vbmeta_path = directory.join("vbmeta.img");
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &root_header, DSU_BLOCK_SIZE)?;
+    fs::write(&vbmeta_path, writer.into_inner())
+        .with_context(|| format!("Failed to write file: {vbmeta_path:?}"))?;
+
+    let public_key_path = directory.join("dsu.avbpubkey");
+    let public_key = avb::encode_public_key(&key_avb.to_public_key())?;
+    fs::write(&public_key_path, public_key)
+        .with_context(|| format!("Failed to write file: {public_key_path:?}"))?;
+
+    // Multiple images can only be installed together from a zip.
+    let file_name = if images.len() == 1 {
+        format!("{DSU_SYSTEM_PARTITION}.img")
+    } else {
+        "dsu.zip".to_owned()
+    };
+    let mut commands = vec![];
+
+    if images.len() > 1 {
+        let names = images
+            .iter()
+            .map(|n| format!("{n}.img"))
+            .collect::<Vec<_>>();
+        commands.push(format!(
+            "(cd {directory:?} && zip -0 {file_name} {})",
+            names.join(" "),
+        ));
+    }
+
+    commands.push(
+        "adb shell setprop persist.sys.fflag.override.settings_dynamic_system true".to_owned(),
+    );
+    commands.push(format!(
+        "adb push {:?} /sdcard/Download/{file_name}",
+        directory.join(&file_name),
+    ));
+
+    let mut install = vec![
+        "adb shell am start-activity".to_owned(),
+        "-n com.android.dynsystem/com.android.dynsystem.VerificationActivity".to_owned(),
+        "-a android.os.image.action.START_INSTALL".to_owned(),
+        format!("-d file:///storage/emulated/0/Download/{file_name}"),
+    ];
+    if images.len() == 1 {
+        install.push(format!("--el KEY_SYSTEM_SIZE {system_size}"));
+    }
+    install.push(format!("--el KEY_USERDATA_SIZE {DSU_USERDATA_SIZE}"));
+    commands.push(install.join(" \\\n    "));
+
+    status!("Wrote DSU images to {directory:?}. To install them, run:");
+    println!();
+
+    for command in commands {
+        println!("{command}");
+    }
+
+    println!();
+
+    Ok(())
+}
+
+/// Slot suffixes that images are written for by [`export_ab_images()`].
+const AB_SLOT_SUFFIXES: [&str; 2] = ["_a", "_b"];
+
+/// Extract every partition from a patched OTA and write the images for both
+/// A/B slots (see [`cli::avb::write_slot_images()`]). Each partition is only
+/// extracted once since the data is identical for both slots. Afterwards, the
+/// images for each slot are verified against `key_avb`.
+fn export_ab_images(
+    ota_path: &Path,
+    directory: &Path,
+    key_avb: &RsaPrivateKey,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let (raw_reader, payload_offset, payload_size, header) = open_ota_payload(ota_path)?;
+    if !header.is_full_ota() {
+        bail!("Payload is a delta OTA, not a full OTA");
+    }
+
+    let images = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.clone())
+        .collect::<BTreeSet<_>>();
+
     extract_ota_zip(
         &raw_reader,
-        &cli.directory,
+        directory,
         payload_offset,
         payload_size,
         &header,
-        &unique_images,
+        &images,
+        &ExtractOptions::default(),
         cancel_signal,
     )?;
 
+    let written = cli::avb::write_slot_images(directory, &images, "vbmeta", &AB_SLOT_SUFFIXES)?;
+    let public_key = key_avb.to_public_key();
+
+    for suffix in AB_SLOT_SUFFIXES {
+        status!("Verifying AVB signatures for slot {suffix}");
+
+        let mut seen = HashSet::<String>::new();
+        let mut descriptors = HashMap::<String, Descriptor>::new();
+
+        cli::avb::verify_headers(
+            directory,
+            &format!("vbmeta{suffix}"),
+            Some(suffix),
+            Some(&public_key),
+            header.is_partial_update(),
+            &mut seen,
+            &mut descriptors,
+            warnings,
+        )?;
+        cli::avb::verify_descriptors(directory, &descriptors, warnings, cancel_signal)?;
+    }
+
+    status!(
+        "Wrote A/B slot images to {directory:?}: {}",
+        joined(written)
+    );
+
     Ok(())
 }
 
-pub fn verify_subcommand(cli: &VerifyCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut reader = BufReader::new(raw_reader);
+/// NamedTempFile forces 600 permissions on temp files because it's the safe
+/// option for a shared /tmp. Since we're writing to the output file's
+/// directory, just mimic umask.
+#[allow(unused_variables)]
+fn set_umask_permissions(file: &File, path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
 
-    status!("Verifying whole-file signature");
+        use rustix::{fs::Mode, process::umask};
 
-    let embedded_cert = ota::verify_ota(&mut reader, cancel_signal)?;
+        let mask = umask(Mode::empty());
+        umask(mask);
 
-    let (metadata, ota_cert, header, properties) = ota::parse_zip_ota_info(&mut reader)?;
-    if embedded_cert != ota_cert {
+        // Mac uses a 16-bit value.
+        #[allow(clippy::useless_conversion)]
+        let mode = u32::from(0o666 & !mask.bits());
+
+        file.set_permissions(Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions to {mode:o}: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// The JSON report written by `ota patch --report` and `ota verify --report`.
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checks: Option<&'a [VerifyCheck]>,
+    warnings: &'a [Warning],
+}
+
+impl<'a> JsonReport<'a> {
+    fn new(error: Option<&anyhow::Error>, warnings: &'a [Warning]) -> Self {
+        Self {
+            status: if error.is_some() { "failed" } else { "passed" },
+            error: error.map(|e| format!("{e:#}")),
+            checks: None,
+            warnings,
+        }
+    }
+
+    fn to_json(&self) -> Result<String> {
+        let mut result =
+            serde_json::to_string_pretty(self).context("Failed to serialize report")?;
+        result.push('\n');
+
+        Ok(result)
+    }
+}
+
+/// Serialize the result of `ota patch` to JSON. `error` is the error that
+/// patching stopped with, if any, and `warnings` are the warnings emitted
+/// during patching.
+pub fn patch_report_json(error: Option<&anyhow::Error>, warnings: &[Warning]) -> Result<String> {
+    JsonReport::new(error, warnings).to_json()
+}
+
+fn write_patch_report(
+    cli: &PatchCli,
+    result: &Result<()>,
+    warnings: &WarningCollector,
+) -> Result<()> {
+    if let Some(path) = &cli.report {
+        let json = patch_report_json(result.as_ref().err(), &warnings.warnings())?;
+        fs::write(path, json).with_context(|| format!("Failed to write report: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+pub fn patch_subcommand(cli: &PatchCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let metrics = PatchMetrics::default();
+    let warnings = WarningCollector::new(|w| warning!("{w}"));
+    let result = patch_ota(cli, &metrics, &warnings, cancel_signal);
+
+    if let Some(path) = &cli.metrics_file {
+        metrics
+            .write_textfile(path, result.is_ok())
+            .with_context(|| format!("Failed to write metrics: {path:?}"))?;
+    }
+    write_patch_report(cli, &result, &warnings)?;
+
+    result
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn patch_subcommand(cli: &PatchCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let warnings = WarningCollector::new(|w| warning!("{w}"));
+    let result = patch_ota(cli, &warnings, cancel_signal);
+
+    write_patch_report(cli, &result, &warnings)?;
+
+    result
+}
+
+fn patch_ota(
+    cli: &PatchCli,
+    #[cfg(feature = "metrics")] metrics: &PatchMetrics,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    #[cfg(feature = "metrics")]
+    metrics.start_stage("setup");
+
+    let output = cli.output.as_ref().map_or_else(
+        || {
+            let mut s = cli.input.clone().into_os_string();
+            s.push(".patched");
+            Cow::Owned(PathBuf::from(s))
+        },
+        Cow::Borrowed,
+    );
+
+    let passphrase_avb = if let Some(v) = &cli.pass_avb_env_var {
+        PassphraseSource::EnvVar(v.clone())
+    } else if let Some(p) = &cli.pass_avb_file {
+        PassphraseSource::File(p.clone())
+    } else {
+        PassphraseSource::Prompt(format!("Enter passphrase for {:?}: ", cli.key_avb))
+    };
+    let passphrase_ota = if let Some(v) = &cli.pass_ota_env_var {
+        PassphraseSource::EnvVar(v.clone())
+    } else if let Some(p) = &cli.pass_ota_file {
+        PassphraseSource::File(p.clone())
+    } else {
+        PassphraseSource::Prompt(format!("Enter passphrase for {:?}: ", cli.key_ota))
+    };
+
+    let p12_alias = cli.p12_alias.as_deref();
+
+    let key_avb = crypto::read_key_file(&cli.key_avb, &passphrase_avb, p12_alias)
+        .with_context(|| format!("Failed to load key: {:?}", cli.key_avb))?;
+    crypto::validate_avb_key(&key_avb)
+        .with_context(|| format!("Key cannot be used for AVB: {:?}", cli.key_avb))?;
+    let (key_ota, cert_ota) =
+        load_key_and_cert(&cli.key_ota, &cli.cert_ota, &passphrase_ota, p12_alias)?;
+
+    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
         bail!(
-            "CMS embedded certificate does not match {}",
-            ota::PATH_OTACERT,
+            "Private key {:?} does not match certificate {:?}",
+            cli.key_ota,
+            cli.cert_ota,
         );
-    } else if let Some(p) = &cli.cert_ota {
-        let verify_cert = crypto::read_pem_cert_file(p)
-            .with_context(|| format!("Failed to load certificate: {:?}", p))?;
+    }
+
+    // clap guarantees that the payload key and certificate are either both
+    // specified or both omitted.
+    let payload_signing = match (&cli.key_payload, &cli.cert_payload) {
+        (Some(key_path), Some(cert_path)) => {
+            let passphrase_payload = if let Some(v) = &cli.pass_payload_env_var {
+                PassphraseSource::EnvVar(v.clone())
+            } else if let Some(p) = &cli.pass_payload_file {
+                PassphraseSource::File(p.clone())
+            } else {
+                PassphraseSource::Prompt(format!("Enter passphrase for {key_path:?}: "))
+            };
 
-        if embedded_cert != verify_cert {
-            bail!("OTA has a valid signature, but was not signed with: {p:?}");
+            let (key, cert) =
+                load_key_and_cert(key_path, cert_path, &passphrase_payload, p12_alias)?;
+
+            if !crypto::cert_matches_key(&cert, &key)? {
+                bail!("Private key {key_path:?} does not match certificate {cert_path:?}");
+            }
+
+            Some((key, cert))
         }
-    } else {
-        warning!("Whole-file signature is valid, but its trust is unknown");
+        _ => None,
+    };
+
+    check_signing_cert(Some(cli.cert_ota.as_path()), &cert_ota, warnings);
+
+    let skip_avb = cli.skip_avb.iter().cloned().collect::<HashSet<_>>();
+    if !skip_avb.is_empty() {
+        warnings.emit(
+            WarningCode::AvbResigningSkipped,
+            Severity::Medium,
+            format!(
+                "AVB re-signing is skipped for: {}",
+                joined(sorted(skip_avb.iter())),
+            ),
+        );
+    }
+    if let (Some(path), Some((_, cert))) = (&cli.cert_payload, &payload_signing) {
+        check_signing_cert(Some(path.as_path()), cert, warnings);
     }
 
-    ota::verify_metadata(&mut reader, &metadata, header.blob_offset)
-        .context("Failed to verify OTA metadata offsets")?;
+    let mut external_images = HashMap::new();
 
-    status!("Verifying payload");
+    for item in cli.replace.chunks_exact(2) {
+        let name = item[0]
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid partition name: {:?}", item[0]))?;
+        let path = Path::new(&item[1]);
 
-    let pfs_raw = metadata
-        .property_files
-        .get(ota::PF_NAME)
-        .ok_or_else(|| anyhow!("Missing property files: {}", ota::PF_NAME))?;
-    let pfs = ota::parse_property_files(pfs_raw)
-        .with_context(|| format!("Failed to parse property files: {}", ota::PF_NAME))?;
-    let pf_payload = pfs
+        external_images.insert(name.to_owned(), path.to_owned());
+    }
+
+    let mut dtbo_entries = BTreeMap::new();
+
+    for (index, path) in &cli.replace_dtbo_entry {
+        let data = fs::read(path).with_context(|| format!("Failed to read: {path:?}"))?;
+
+        if dtbo_entries.insert(*index, data).is_some() {
+            bail!("dtbo entry {index} is replaced more than once");
+        }
+    }
+
+    let exclude = cli.exclude.iter().cloned().collect::<BTreeSet<_>>();
+    if !exclude.is_empty() {
+        warnings.emit(
+            WarningCode::PartitionsExcluded,
+            Severity::Medium,
+            format!("Output is a partial update without: {}", joined(&exclude)),
+        );
+    }
+
+    let vbmeta_options = VbmetaOptions {
+        clear_flags: cli.clear_vbmeta_flags,
+        keep_flags: cli.keep_vbmeta_flags,
+        skip_avb: skip_avb.clone(),
+        edit_cmdline: !cli.avb_cmdline_remove.is_empty() || !cli.avb_cmdline_add.is_empty(),
+        edit_props: !cli.avb_delete_prop.is_empty() || !cli.avb_prop.is_empty(),
+        keep_stub: cli.keep_vbmeta_stub,
+    };
+    let root_edits = RootVbmetaEdits {
+        cmdline_remove: cli.avb_cmdline_remove.clone(),
+        cmdline_add: cli.avb_cmdline_add.clone(),
+        prop_delete: cli.avb_delete_prop.clone(),
+        prop_set: cli.avb_prop.clone(),
+    };
+
+    if cli.dry_run {
+        return print_vbmeta_plan(
+            &cli.input,
+            &external_images,
+            !dtbo_entries.is_empty(),
+            &cli.boot_partition,
+            !cli.root.rootless,
+            &vbmeta_options,
+            &exclude,
+            warnings,
+            cancel_signal,
+        );
+    }
+
+    // Only the zip signature can use a non-default padding scheme. The others
+    // were already validated when parsing the arguments.
+    let zip_padding = cli
+        .signature_padding
         .iter()
-        .find(|pf| pf.name == ota::PATH_PAYLOAD)
-        .ok_or_else(|| anyhow!("Missing property files entry: {}", ota::PATH_PAYLOAD))?;
+        .rev()
+        .find(|(f, _)| *f == SignatureFormat::OtaZip)
+        .map_or(RsaPadding::default(), |(_, p)| *p);
+    if zip_padding == RsaPadding::Pss {
+        warnings.emit(
+            WarningCode::PssZipSignature,
+            Severity::High,
+            "AOSP recovery only verifies PKCS#1 v1.5 zip signatures",
+        );
+    }
+
+    let root_patcher: Option<Box<dyn BootImagePatcher + Send>> = if cli.root.rootless {
+        None
+    } else if let Some(magisk) = &cli.root.magisk {
+        let options = MagiskOptions {
+            keep_verity: cli.magisk_keep_verity,
+            keep_force_encrypt: cli.magisk_keep_force_encrypt,
+            recovery_mode: cli.magisk_recovery_mode,
+            legacy_sar: cli.magisk_legacy_sar,
+        };
+        status!("Magisk options: {options}");
+
+        let patcher = MagiskRootPatcher::new(
+            magisk,
+            cli.magisk_preinit_device.as_deref(),
+            cli.magisk_random_seed,
+            options,
+            cli.ignore_magisk_warnings,
+            cli.refuse_repatch,
+            warnings,
+        )
+        .context("Failed to create Magisk boot image patcher")?;
+
+        Some(Box::new(patcher))
+    } else if let Some(prepatched) = &cli.root.prepatched {
+        let patcher = PrepatchedImagePatcher::new(
+            prepatched,
+            cli.ignore_prepatched_compat + 1,
+            warnings.clone(),
+        );
+
+        Some(Box::new(patcher))
+    } else {
+        unreachable!()
+    };
 
-    let section_reader = SectionReader::new(&mut reader, pf_payload.offset, pf_payload.size)?;
+    // A reference OTA is purely an optimization, so any problems with it just
+    // fall back to compressing everything.
+    let reference = match &cli.ref_output {
+        Some(p) => match ReferencePayload::open(p) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                status!("Not reusing data from reference OTA {p:?}: {e:#}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    let start = Instant::now();
 
-    payload::verify_payload(section_reader, &ota_cert, &properties, cancel_signal)?;
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.clone()))
+        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+
+    let markers = detect_prior_patch(&mut zip_reader, &cert_ota)?;
+    if !markers.is_empty() {
+        if cli.refuse_repatch {
+            bail!(
+                "Input OTA was already patched by avbroot ({})",
+                markers.join(", "),
+            );
+        }
+
+        // The previous otacerts are replaced and the previous Magisk patch is
+        // removed from the boot image, so re-patching gives the same result as
+        // patching the stock OTA.
+        status!(
+            "Input OTA was already patched by avbroot ({}); re-patching",
+            markers.join(", "),
+        );
+    }
+
+    // Recovery cannot install the OTA any earlier than its build timestamp, so
+    // check this before spending any time on patching.
+    let input_metadata = ota::read_zip_metadata(&mut zip_reader)
+        .with_context(|| format!("Failed to read OTA metadata: {:?}", cli.input))?;
+    if let Some(p) = &input_metadata.postcondition {
+        check_signing_cert_for_build(
+            Some(cli.cert_ota.as_path()),
+            &cert_ota,
+            p.timestamp,
+            warnings,
+        );
+    }
+
+    if zip_reader
+        .file_names()
+        .any(|n| n == ota::PATH_COMPATIBILITY)
+    {
+        check_vintf_compatibility(&mut zip_reader, &external_images, warnings)?;
+    }
+
+    if !cli.skip_kmi_check {
+        check_kmi_compatibility(
+            &cli.input,
+            &external_images,
+            cli.root.prepatched.as_deref(),
+            cancel_signal,
+        )?;
+    }
+
+    // The patched OTA is about the same size as the original. If the temporary
+    // directory is on a different filesystem, the output is copied there at the
+    // end, so both locations need the space.
+    let output_dir = match output.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    let temp_policy = TempPolicy::new(cli.temp_dir.as_deref().unwrap_or(output_dir));
+    let projected_size = fs::metadata(&cli.input)
+        .with_context(|| format!("Failed to stat: {:?}", cli.input))?
+        .len();
+    #[cfg(feature = "metrics")]
+    metrics.set_input_bytes(projected_size);
+
+    // The compressed replacement images are also kept in the temporary directory
+    // until they are written to the new payload.
+    let replaced_size = external_images
+        .values()
+        .map(|p| fs::metadata(p).with_context(|| format!("Failed to stat: {p:?}")))
+        .map(|r| r.map(|m| m.len()))
+        .sum::<Result<u64>>()?;
+
+    temp_policy.check_space(projected_size + replaced_size, warnings);
+    if cli.temp_dir.is_some() {
+        temp::check_available_space(output_dir, projected_size, warnings);
+    }
+
+    // Open the output file for reading too, so we can verify offsets later.
+    let mut zip_stage = temp_policy.stage("zip write")?;
+    let temp_writer = zip_stage
+        .named_file(
+            output
+                .file_name()
+                .unwrap_or_else(|| OsStr::new("avbroot.tmp")),
+        )
+        .context("Failed to open temporary output file")?;
+    let temp_path = temp_writer.path().to_owned();
+    let hole_punching_writer = HolePunchingWriter::new(temp_writer);
+    let buffered_writer =
+        BufWriter::with_capacity(cli.output_buffer_size as usize * 1024, hole_punching_writer);
+    let mut signing_writer = SigningWriter::new(buffered_writer);
+    signing_writer
+        .set_padding(zip_padding)
+        .context("Failed to set output zip signature padding")?;
+    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    let mut compress_stage = temp_policy.stage("compression")?;
+
+    #[cfg(feature = "metrics")]
+    metrics.start_stage("patch");
+
+    let (metadata, payload_metadata_size) = patch_ota_zip(
+        &raw_reader,
+        &mut zip_reader,
+        &mut zip_writer,
+        &external_images,
+        &dtbo_entries,
+        &cli.boot_partition,
+        root_patcher,
+        cli.ramdisk_compression.target(cli.ramdisk_min_savings),
+        &vbmeta_options,
+        &exclude,
+        !cli.hash_full_size,
+        &root_edits,
+        &key_avb,
+        payload_signing.as_ref().map_or(&key_ota, |(k, _)| k),
+        &cert_ota,
+        payload_signing.as_ref().map(|(_, c)| c),
+        reference.as_ref(),
+        cli.jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .unwrap_or(NonZeroUsize::new(1).unwrap()),
+        cli.save_stock_images.as_deref(),
+        &mut compress_stage,
+        warnings,
+        cancel_signal,
+    )
+    .context("Failed to patch OTA zip")?;
+
+    drop(compress_stage);
+
+    #[cfg(feature = "metrics")]
+    {
+        metrics.set_build(&metadata);
+        if let Some(r) = &reference {
+            let (hits, misses) = *r.hits.lock().unwrap();
+            metrics.set_reference_images(hits, misses);
+        }
+        metrics.start_stage("sign");
+    }
+
+    let signing_writer = zip_writer
+        .finish()
+        .context("Failed to finalize output zip")?;
+    let buffered_writer = sign_output_zip(
+        signing_writer,
+        cli.output_comment.as_ref(),
+        &metadata,
+        &key_ota,
+        &cert_ota,
+    )?;
+    let hole_punching_writer = buffered_writer
+        .into_inner()
+        .context("Failed to flush output zip")?;
+    let mut temp_writer = hole_punching_writer.into_inner();
+    temp_writer.flush().context("Failed to flush output zip")?;
+
+    #[cfg(feature = "metrics")]
+    metrics.start_stage("verify");
+
+    // We do a lot of low-level hackery. Reopen and verify offsets.
+    status!("Verifying metadata offsets");
+    temp_writer.rewind()?;
+    ota::verify_metadata(
+        BufReader::new(&mut temp_writer),
+        &metadata,
+        payload_metadata_size,
+    )
+    .context("Failed to verify OTA metadata offsets")?;
+
+    if let Some(expected) = &cli.expect_vbmeta_digest {
+        status!("Verifying vbmeta digest");
+        temp_writer.rewind()?;
+        attestation::verify_vbmeta_digest(
+            BufReader::new(&mut temp_writer),
+            expected,
+            cancel_signal,
+        )
+        .context("Output does not have the expected vbmeta digest")?;
+    }
+
+    status!("Completed after {:.1}s", start.elapsed().as_secs_f64());
+    status!(
+        "OTA certificate expires: {}",
+        cert_ota.tbs_certificate.validity.not_after,
+    );
+    if let Some((_, cert)) = &payload_signing {
+        status!(
+            "Payload certificate expires: {}",
+            cert.tbs_certificate.validity.not_after,
+        );
+    }
+    if !skip_avb.is_empty() {
+        status!(
+            "Partitions left with their original AVB signatures (--skip-avb): {}",
+            joined(sorted(skip_avb.iter())),
+        );
+    }
+    if !exclude.is_empty() {
+        let output_size = temp_writer
+            .as_file()
+            .metadata()
+            .with_context(|| format!("Failed to stat: {temp_path:?}"))?
+            .len();
+
+        status!(
+            "Partitions excluded from the output (--exclude): {}",
+            joined(&exclude),
+        );
+        status!(
+            "Output is {} ({} smaller than the input)",
+            temp::format_size(output_size),
+            temp::format_size(projected_size.saturating_sub(output_size)),
+        );
+    }
+
+    let all_warnings = warnings.warnings();
+    if !all_warnings.is_empty() {
+        status!("Warnings emitted during patching:");
+
+        for w in &all_warnings {
+            warning!("{w}");
+        }
+
+        if cli.deny_warnings {
+            bail!(
+                "{} warning(s) were emitted and --deny-warnings is set",
+                all_warnings.len(),
+            );
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        let size = temp_writer
+            .as_file()
+            .metadata()
+            .with_context(|| format!("Failed to stat: {temp_path:?}"))?
+            .len();
+        metrics.set_output_bytes(size);
+        metrics.start_stage("persist");
+    }
+
+    set_umask_permissions(temp_writer.as_file(), &temp_path)?;
+
+    zip_stage.update_peak_size()?;
+
+    temp::persist(temp_writer, &output).with_context(|| {
+        format!("Failed to move temporary file to output path: {temp_path:?} -> {output:?}")
+    })?;
+
+    drop(zip_stage);
+    temp_policy.report();
+
+    if let Some(directory) = &cli.dsu {
+        #[cfg(feature = "metrics")]
+        metrics.start_stage("dsu");
+
+        export_dsu(
+            &output,
+            directory,
+            &cli.dsu_partition,
+            &key_avb,
+            cancel_signal,
+        )
+        .context("Failed to export DSU images")?;
+    }
+
+    if let Some(directory) = &cli.ab_images {
+        #[cfg(feature = "metrics")]
+        metrics.start_stage("ab_images");
+
+        export_ab_images(&output, directory, &key_avb, warnings, cancel_signal)
+            .context("Failed to export A/B slot images")?;
+    }
+
+    Ok(())
+}
+
+/// Open an OTA zip and load the payload header. Returns the file, the offset
+/// and size of the payload within the zip, and the payload header.
+pub fn open_ota_payload(path: &Path) -> Result<(PSeekFile, u64, u64, PayloadHeader)> {
+    let raw_reader = File::open(path)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let mut zip = ZipArchive::new(BufReader::new(raw_reader.clone()))
+        .with_context(|| format!("Failed to read zip: {path:?}"))?;
+    let payload_entry = zip
+        .by_name(ota::PATH_PAYLOAD)
+        .with_context(|| format!("Failed to open zip entry: {:?}", ota::PATH_PAYLOAD))?;
+    let payload_offset = payload_entry.data_start();
+    let payload_size = payload_entry.size();
+
+    // Open the payload data directly.
+    let mut payload_reader = SectionReader::new(
+        BufReader::new(raw_reader.clone()),
+        payload_offset,
+        payload_size,
+    )?;
+
+    let header = PayloadHeader::from_reader(&mut payload_reader)
+        .context("Failed to load OTA payload header")?;
+
+    Ok((raw_reader, payload_offset, payload_size, header))
+}
+
+/// Print the vbmeta plan for patching the OTA at `input` without patching
+/// anything. Only the vbmeta images are extracted.
+#[allow(clippy::too_many_arguments)]
+fn print_vbmeta_plan(
+    input: &Path,
+    external_images: &HashMap<String, PathBuf>,
+    replace_dtbo: bool,
+    boot_partition: &str,
+    with_root: bool,
+    vbmeta_options: &VbmetaOptions,
+    exclude: &BTreeSet<String>,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let (raw_reader, payload_offset, payload_size, header) = open_ota_payload(input)?;
+    if !header.is_full_ota() {
+        bail!("Payload is a delta OTA, not a full OTA");
+    }
+
+    let all_partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect::<HashSet<_>>();
+
+    for (name, path) in external_images {
+        if !all_partitions.contains(name.as_str()) {
+            bail!("Cannot replace non-existent {name} partition with {path:?}");
+        }
+    }
+
+    let mut required_images = get_required_images(&header.manifest, boot_partition, with_root)?;
+
+    if replace_dtbo {
+        if !all_partitions.contains("dtbo") {
+            bail!("Cannot replace dtbo entries because the OTA has no dtbo partition");
+        }
+
+        required_images.insert("@dtbo".to_owned(), "dtbo".to_owned());
+    }
+
+    check_skip_avb(&vbmeta_options.skip_avb, &all_partitions, &required_images)?;
+    check_exclude(exclude, &all_partitions, &required_images, external_images)?;
+    required_images.retain(|_, p| !exclude.contains(p));
+
+    let vbmeta_images = get_vbmeta_images(&required_images);
+    let modified = required_images
+        .values()
+        .chain(external_images.keys())
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    let open_payload = || -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(SectionReader::new(
+            BufReader::new(raw_reader.clone()),
+            payload_offset,
+            payload_size,
+        )?))
+    };
+    let vbmeta_required = required_images
+        .into_iter()
+        .filter(|(k, _)| k.starts_with("@vbmeta:"))
+        .collect();
+    let vbmeta_external = external_images
+        .iter()
+        .filter(|(n, _)| vbmeta_images.contains(*n))
+        .map(|(n, p)| (n.clone(), p.clone()))
+        .collect();
+    let mut input_streams = open_input_streams(
+        &open_payload,
+        &vbmeta_required,
+        &vbmeta_external,
+        &header,
+        cancel_signal,
+    )?;
+
+    let mut headers = load_vbmeta_headers(&mut input_streams, &vbmeta_images, warnings)?;
+    let rebuilt = rebuild_stub_root_vbmeta(
+        &open_payload,
+        &header,
+        &mut input_streams,
+        external_images,
+        &mut headers,
+        &modified,
+        exclude,
+        vbmeta_options,
+        warnings,
+        cancel_signal,
+    )?;
+    check_excluded_descriptors(&headers, exclude, &vbmeta_options.skip_avb)?;
+    let plan = plan_vbmeta(&headers, &modified, vbmeta_options, warnings)?;
+    if rebuilt {
+        check_rebuilt_signed(&plan)?;
+    }
+
+    status!("vbmeta plan:");
+    for entry in &plan {
+        status!("- {entry}");
+    }
+
+    status!("Dry run; no output was written");
+
+    Ok(())
+}
+
+pub fn extract_subcommand(cli: &ExtractCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let (raw_reader, payload_offset, payload_size, header) = open_ota_payload(&cli.input)?;
+    if !header.is_full_ota() {
+        bail!("Payload is a delta OTA, not a full OTA");
+    }
+
+    let mut unique_images = BTreeSet::new();
+
+    if cli.all {
+        unique_images.extend(
+            header
+                .manifest
+                .partitions
+                .iter()
+                .map(|p| &p.partition_name)
+                .cloned(),
+        );
+    } else {
+        let images = get_required_images(&header.manifest, &cli.boot_partition, true)?;
+
+        if cli.boot_only {
+            unique_images.insert(images["@rootpatch"].clone());
+        } else {
+            unique_images.extend(images.into_values());
+        }
+    }
+
+    let options = ExtractOptions {
+        verify_digests: !cli.skip_operation_digests,
+        max_readers: cli.max_readers,
+    };
+    // Zero means rayon's default, which is the number of CPUs.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.map_or(0, |j| j.get()))
+        .build()
+        .context("Failed to create thread pool")?;
+
+    pool.install(|| {
+        extract_ota_zip(
+            &raw_reader,
+            &cli.directory,
+            payload_offset,
+            payload_size,
+            &header,
+            &unique_images,
+            &options,
+            cancel_signal,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Load a private key and its certificate. Either can be a PKCS#12 bundle. If
+/// both are the same bundle, it is only decrypted once so that the user isn't
+/// prompted for the passphrase twice.
+fn load_key_and_cert(
+    key_path: &Path,
+    cert_path: &Path,
+    source: &PassphraseSource,
+    p12_alias: Option<&str>,
+) -> Result<(RsaPrivateKey, Certificate)> {
+    if key_path == cert_path && crypto::is_pkcs12_path(key_path) {
+        let bundle = crypto::read_pkcs12_file(key_path, source)
+            .with_context(|| format!("Failed to load PKCS#12 bundle: {key_path:?}"))?;
+        let key = bundle
+            .key(p12_alias)
+            .with_context(|| format!("Failed to load key: {key_path:?}"))?;
+        let cert = bundle
+            .cert(p12_alias)
+            .with_context(|| format!("Failed to load certificate: {cert_path:?}"))?;
+
+        return Ok((key.clone(), cert.clone()));
+    }
+
+    let key = crypto::read_key_file(key_path, source, p12_alias)
+        .with_context(|| format!("Failed to load key: {key_path:?}"))?;
+    let cert = crypto::read_cert_file(cert_path, source, p12_alias)
+        .with_context(|| format!("Failed to load certificate: {cert_path:?}"))?;
+
+    Ok((key, cert))
+}
+
+/// Report issues with an OTA certificate as warnings. Issues that would
+/// prevent recovery from accepting the OTA are reported with high severity.
+/// The certificate is identified by `path`, if it was loaded from a file, and
+/// its subject.
+fn report_cert_issues(
+    path: Option<&Path>,
+    cert: &Certificate,
+    issues: Vec<OtaCertIssue>,
+    warnings: &WarningCollector,
+) {
+    let tbs = &cert.tbs_certificate;
+    let expires = &tbs.validity.not_after;
+    let source = match path {
+        Some(p) => format!("{p:?} ({})", tbs.subject),
+        None => format!("OTA certificate ({})", tbs.subject),
+    };
+
+    for issue in issues {
+        let severity = if issue.prevents_install() {
+            Severity::High
+        } else {
+            Severity::Medium
+        };
+
+        warnings.emit(
+            WarningCode::OtaCertIssue,
+            severity,
+            format!("{source}: {issue} (valid until {expires})"),
+        );
+    }
+}
+
+/// Check that the OTA certificate is suitable for signing right now.
+fn check_signing_cert(path: Option<&Path>, cert: &Certificate, warnings: &WarningCollector) {
+    let issues = crypto::check_ota_cert(cert, SystemTime::now());
+
+    report_cert_issues(path, cert, issues, warnings);
+}
+
+/// Check that the OTA certificate is still valid at the OTA's build timestamp,
+/// which is the earliest time that the OTA can be installed. Unknown (zero)
+/// timestamps are ignored.
+fn check_signing_cert_for_build(
+    path: Option<&Path>,
+    cert: &Certificate,
+    post_timestamp: i64,
+    warnings: &WarningCollector,
+) {
+    if post_timestamp <= 0 {
+        return;
+    }
+
+    let build_time = UNIX_EPOCH + Duration::from_secs(post_timestamp as u64);
+    let issues = crypto::check_ota_cert_for_build(cert, build_time);
+
+    report_cert_issues(path, cert, issues, warnings);
+}
+
+/// Outcome of a single check performed by [`verify_subcommand()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check failed, but the failure was downgraded to a warning with one
+    /// of the `--ignore-*` options.
+    FailedButIgnored,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VerifyCheck {
+    pub name: &'static str,
+    /// Partition that the check applies to, for per-partition checks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// A failed check, along with the expected and actual values if the failure
+/// was caused by a mismatch.
+#[derive(Debug)]
+pub struct CheckFailure {
+    pub error: anyhow::Error,
+    pub mismatch: Option<(String, String)>,
+}
+
+impl CheckFailure {
+    pub fn new(error: anyhow::Error, expected: String, actual: String) -> Self {
+        Self {
+            error,
+            mismatch: Some((expected, actual)),
+        }
+    }
+}
+
+impl From<anyhow::Error> for CheckFailure {
+    /// Digest and property files mismatches are found anywhere in the error's
+    /// chain of causes.
+    fn from(error: anyhow::Error) -> Self {
+        let mismatch = error.chain().find_map(|e| {
+            if let Some(
+                avb::Error::InvalidRootDigest(expected, actual)
+                | avb::Error::InvalidHashtree(expected, actual),
+            ) = e.downcast_ref::<avb::Error>()
+            {
+                Some((expected.clone(), actual.clone()))
+            } else if let Some(ota::Error::MismatchedPropertyFiles(expected, actual)) =
+                e.downcast_ref::<ota::Error>()
+            {
+                Some((expected.clone(), actual.clone()))
+            } else {
+                None
+            }
+        });
+
+        Self { error, mismatch }
+    }
+}
+
+/// Structured record of every check performed by [`verify_subcommand()`].
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub checks: Vec<VerifyCheck>,
+}
+
+impl VerifyReport {
+    /// Record the result of a check. If the check failed and `ignore` is true,
+    /// a warning is emitted and [`Ok`] is returned. Only mismatches can be
+    /// ignored. Other failures, like I/O errors, are always returned.
+    pub fn record(
+        &mut self,
+        name: &'static str,
+        partition: Option<&str>,
+        result: Result<(), CheckFailure>,
+        ignore: bool,
+        warnings: &WarningCollector,
+    ) -> Result<()> {
+        let failure = match result {
+            Ok(()) => {
+                self.checks.push(VerifyCheck {
+                    name,
+                    partition: partition.map(|p| p.to_owned()),
+                    status: CheckStatus::Passed,
+                    expected: None,
+                    actual: None,
+                    message: None,
+                });
+                return Ok(());
+            }
+            Err(f) => f,
+        };
+
+        let ignore = ignore && failure.mismatch.is_some();
+        let message = format!("{:#}", failure.error);
+        let (expected, actual) = failure.mismatch.unzip();
+
+        self.checks.push(VerifyCheck {
+            name,
+            partition: partition.map(|p| p.to_owned()),
+            status: if ignore {
+                CheckStatus::FailedButIgnored
+            } else {
+                CheckStatus::Failed
+            },
+            expected,
+            actual,
+            message: Some(message.clone()),
+        });
+
+        if ignore {
+            warnings.emit(
+                WarningCode::VerifyCheckIgnored,
+                Severity::High,
+                format!("Ignoring failed check: {name}: {message}"),
+            );
+            Ok(())
+        } else {
+            Err(failure.error)
+        }
+    }
+
+    /// Number of failed checks that were ignored.
+    pub fn ignored(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|c| c.status == CheckStatus::FailedButIgnored)
+            .count()
+    }
+
+    /// Serialize the report to JSON. `error` is the error that verification
+    /// stopped with, if any, and `warnings` are the warnings emitted during
+    /// verification.
+    pub fn to_json(&self, error: Option<&anyhow::Error>, warnings: &[Warning]) -> Result<String> {
+        JsonReport {
+            checks: Some(&self.checks),
+            ..JsonReport::new(error, warnings)
+        }
+        .to_json()
+    }
+}
+
+fn verify_ota(
+    cli: &VerifyCli,
+    report: &mut VerifyReport,
+    warnings: &WarningCollector,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let mut reader = BufReader::new(raw_reader);
+
+    let signature =
+        ota::OtaSignature::from_zip(&mut reader).context("Failed to parse whole-file signature")?;
+
+    let (metadata, ota_cert, header, properties) = ota::parse_zip_ota_info(&mut reader)?;
+
+    let payload_cert = match &cli.cert_payload {
+        Some(p) => crypto::read_pem_cert_file(p)
+            .with_context(|| format!("Failed to load certificate: {p:?}"))?,
+        None => ota_cert.clone(),
+    };
+
+    let result = ota::verify_metadata(&mut reader, &metadata, header.blob_offset)
+        .context("Failed to verify OTA metadata offsets");
+    report.record(
+        "property_files",
+        None,
+        result.map_err(CheckFailure::from),
+        cli.ignore_property_files,
+        warnings,
+    )?;
+
+    let pfs_raw = metadata
+        .property_files
+        .get(ota::PF_NAME)
+        .ok_or_else(|| anyhow!("Missing property files: {}", ota::PF_NAME))?;
+    let pfs = ota::parse_property_files(pfs_raw)
+        .with_context(|| format!("Failed to parse property files: {}", ota::PF_NAME))?;
+    let pf_payload = pfs
+        .iter()
+        .find(|pf| pf.name == ota::PATH_PAYLOAD)
+        .ok_or_else(|| anyhow!("Missing property files entry: {}", ota::PATH_PAYLOAD))?;
+
+    if pf_payload
+        .offset
+        .checked_add(pf_payload.size)
+        .map_or(true, |end| end > signature.hashed_size())
+    {
+        bail!("Payload is not covered by the whole-file signature");
+    }
+
+    status!("Verifying whole-file signature and payload");
+
+    // Both the whole-file signature and the payload are verified in a single
+    // sequential pass over the file. No temporary files are needed.
+    {
+        reader.rewind()?;
+
+        let hashing_reader = HashingReader::new(&mut reader, signature.new_context());
+        let mut counting_reader = CountingReader::new(hashing_reader);
+
+        stream::copy_n(
+            &mut counting_reader,
+            io::sink(),
+            pf_payload.offset,
+            cancel_signal,
+        )?;
+
+        let result = payload::verify_payload(
+            (&mut counting_reader).take(pf_payload.size),
+            &payload_cert,
+            &properties,
+            cancel_signal,
+        )
+        .context("Failed to verify payload");
+        report.record(
+            "payload",
+            None,
+            result.map_err(CheckFailure::from),
+            false,
+            warnings,
+        )?;
+
+        let position = counting_reader.stream_position()?;
+        stream::copy_n(
+            &mut counting_reader,
+            io::sink(),
+            signature.hashed_size() - position,
+            cancel_signal,
+        )?;
+
+        let (hashing_reader, _) = counting_reader.finish();
+        let (_, context) = hashing_reader.finish();
+
+        let result = signature
+            .verify(&context.finish())
+            .context("Failed to verify whole-file signature");
+        report.record(
+            "whole_file_signature",
+            None,
+            result.map_err(CheckFailure::from),
+            false,
+            warnings,
+        )?;
+    }
+
+    let embedded_cert = signature.cert();
+    let embedded_fingerprint = crypto::cert_fingerprint(embedded_cert)?;
+
+    let result = if *embedded_cert != ota_cert {
+        Err(CheckFailure::new(
+            anyhow!(
+                "CMS embedded certificate does not match {}",
+                ota::PATH_OTACERT,
+            ),
+            crypto::cert_fingerprint(&ota_cert)?,
+            embedded_fingerprint.clone(),
+        ))
+    } else {
+        Ok(())
+    };
+    report.record(
+        "ota_cert_embedded",
+        None,
+        result,
+        cli.ignore_cert_mismatch,
+        warnings,
+    )?;
+
+    if let Some(p) = &cli.cert_ota {
+        let verify_cert = crypto::read_pem_cert_file(p)
+            .with_context(|| format!("Failed to load certificate: {:?}", p))?;
+
+        let result = if *embedded_cert != verify_cert {
+            Err(CheckFailure::new(
+                anyhow!("OTA has a valid signature, but was not signed with: {p:?}"),
+                crypto::cert_fingerprint(&verify_cert)?,
+                embedded_fingerprint,
+            ))
+        } else {
+            Ok(())
+        };
+        report.record(
+            "ota_cert_trusted",
+            None,
+            result,
+            cli.ignore_cert_mismatch,
+            warnings,
+        )?;
+    } else {
+        warnings.emit(
+            WarningCode::SignatureTrustUnknown,
+            Severity::Medium,
+            "Whole-file signature is valid, but its trust is unknown",
+        );
+    }
+
+    status!(
+        "OTA certificate expires: {}",
+        ota_cert.tbs_certificate.validity.not_after,
+    );
+
+    check_signing_cert(None, &ota_cert, warnings);
+    if let Some(p) = &metadata.postcondition {
+        check_signing_cert_for_build(None, &ota_cert, p.timestamp, warnings);
+    }
+
+    status!("Checking ramdisk's otacerts.zip");
+
+    let raw_reader = reader.into_inner();
+    let open_payload = || -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(SectionReader::new(
+            BufReader::new(raw_reader.clone()),
+            pf_payload.offset,
+            pf_payload.size,
+        )?))
+    };
+
+    let partitions_by_type = get_partitions_by_type(&header.manifest)?;
+
+    // Partial updates might not contain any of the possible partitions.
+    if let Some(name) = partitions_by_type.get("@otacerts") {
+        let stream = payload::extract_image_to_memory(open_payload, &header, name, cancel_signal)
+            .with_context(|| format!("Failed to extract from payload: {name}"))?;
+        let boot_image = BootImage::from_reader(stream.clone_rewind())
+            .with_context(|| format!("Failed to read boot image: {name}"))?;
+
+        let ramdisk_certs = OtaCertPatcher::get_certificates(&boot_image)
+            .context("Failed to read ramdisk's otacerts.zip")?;
+        let now = SystemTime::now();
+        let mut ramdisk_fingerprints = vec![];
+
+        for cert in &ramdisk_certs {
+            let tbs = &cert.tbs_certificate;
+            let fingerprint = crypto::cert_fingerprint(cert)?;
+
+            status!("Found otacerts.zip certificate:");
+            status!("- Subject: {}", tbs.subject);
+            status!("- SHA-256 fingerprint: {fingerprint}");
+            status!("- Expires: {}", tbs.validity.not_after);
+
+            report_cert_issues(None, cert, crypto::check_ota_cert(cert, now), warnings);
+
+            ramdisk_fingerprints.push(fingerprint);
+        }
+
+        for (check, cert, description) in [
+            ("otacerts_ota_cert", &ota_cert, "OTA"),
+            ("otacerts_payload_cert", &payload_cert, "payload"),
+        ] {
+            let result = if !ramdisk_certs.contains(cert) {
+                Err(CheckFailure::new(
+                    anyhow!("Ramdisk's otacerts.zip does not contain {description} certificate"),
+                    crypto::cert_fingerprint(cert)?,
+                    joined(&ramdisk_fingerprints),
+                ))
+            } else {
+                Ok(())
+            };
+            report.record(check, None, result, cli.ignore_cert_mismatch, warnings)?;
+        }
+    } else {
+        status!("Skipping otacerts.zip check: no boot image in partial OTA");
+    }
+
+    let has_partition = |name: &str| {
+        header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| p.partition_name == name)
+    };
+
+    if has_partition("boot") && has_partition("vendor_boot") {
+        status!("Checking GKI KMI compatibility: boot, vendor_boot");
+
+        let mut images = vec![];
+
+        for name in ["boot", "vendor_boot"] {
+            let stream =
+                payload::extract_image_to_memory(&open_payload, &header, name, cancel_signal)
+                    .with_context(|| format!("Failed to extract from payload: {name}"))?;
+            let image = BootImage::from_reader(stream)
+                .with_context(|| format!("Failed to read boot image: {name}"))?;
+            images.push(image);
+        }
+
+        if let Some(check) = boot::check_kmi(&images[0], &images[1])? {
+            let result = if !check.mismatches.is_empty() {
+                Err(CheckFailure::new(
+                    anyhow!("vendor_boot kernel modules do not match the kernel's KMI"),
+                    check.kernel_kmi,
+                    joined(&check.mismatches),
+                ))
+            } else {
+                Ok(())
+            };
+            report.record(
+                "kmi",
+                Some("vendor_boot"),
+                result,
+                cli.ignore_kmi_mismatch,
+                warnings,
+            )?;
+        }
+    }
+
+    if !cli.verify_avb && cli.public_key_avb.is_none() {
+        status!("Skipping AVB signatures. Use --verify-avb to check them");
+        return Ok(());
+    }
+
+    status!("Extracting partition images to temporary directory");
+
+    let temp_policy = TempPolicy::new(cli.temp_dir.clone().unwrap_or_else(env::temp_dir));
+    let mut projected_size = 0u64;
+
+    for p in &header.manifest.partitions {
+        let size = payload::partition_size(p, header.manifest.block_size)
+            .with_context(|| format!("Failed to get partition size: {}", p.partition_name))?;
+        projected_size = projected_size.saturating_add(size);
+    }
+
+    temp_policy.check_space(projected_size, warnings);
+
+    let mut extract_stage = temp_policy.stage("extraction")?;
+    let unique_images = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| &p.partition_name)
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    extract_ota_zip(
+        &raw_reader,
+        extract_stage.path(),
+        pf_payload.offset,
+        pf_payload.size,
+        &header,
+        &unique_images,
+        &ExtractOptions::default(),
+        cancel_signal,
+    )?;
+
+    extract_stage.update_peak_size()?;
+
+    status!("Verifying AVB signatures");
+
+    let public_key = if let Some(p) = &cli.public_key_avb {
+        let data = fs::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
+        let key = avb::decode_public_key(&data)
+            .with_context(|| format!("Failed to decode public key: {p:?}"))?;
+
+        Some(key)
+    } else {
+        None
+    };
+
+    let mut seen = HashSet::<String>::new();
+    let mut descriptors = HashMap::<String, Descriptor>::new();
+
+    let result = cli::avb::verify_headers(
+        extract_stage.path(),
+        "vbmeta",
+        None,
+        public_key.as_ref(),
+        header.is_partial_update(),
+        &mut seen,
+        &mut descriptors,
+        warnings,
+    );
+    report.record(
+        "avb_headers",
+        None,
+        result.map_err(CheckFailure::from),
+        false,
+        warnings,
+    )?;
+
+    for name in &cli.ignore_avb_digest {
+        if !descriptors.contains_key(name) {
+            warnings.emit(
+                WarningCode::UnusedIgnoreOption,
+                Severity::Low,
+                format!("Partition passed to --ignore-avb-digest has no descriptor: {name}"),
+            );
+        }
+    }
+
+    // Every descriptor is verified so that the report lists all mismatches,
+    // not just the first one.
+    let mut first_error = None;
+
+    for (name, result) in cli::avb::verify_descriptors_each(
+        extract_stage.path(),
+        &descriptors,
+        warnings,
+        cancel_signal,
+    ) {
+        let ignore = cli.ignore_avb_digest.contains(&name);
+
+        if let Err(e) = report.record(
+            "avb_digest",
+            Some(&name),
+            result.map_err(CheckFailure::from),
+            ignore,
+            warnings,
+        ) {
+            first_error.get_or_insert(e);
+        }
+    }
+
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+
+    drop(extract_stage);
+    temp_policy.report();
+
+    Ok(())
+}
+
+pub fn verify_subcommand(cli: &VerifyCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let warnings = WarningCollector::new(|w| warning!("{w}"));
+    let mut report = VerifyReport::default();
+    let result = verify_ota(cli, &mut report, &warnings, cancel_signal);
+
+    if let Some(path) = &cli.report {
+        let json = report.to_json(result.as_ref().err(), &warnings.warnings())?;
+        fs::write(path, json).with_context(|| format!("Failed to write report: {path:?}"))?;
+    }
+
+    result?;
+
+    match report.ignored() {
+        0 => status!("Signatures are all valid!"),
+        n => status!("Signatures are valid, except for {n} ignored failed checks"),
+    }
+
+    Ok(())
+}
+
+/// Boot-related partitions that are inspected for boot image headers.
+const INSPECT_BOOT_PARTITIONS: &[&str] = &[
+    "boot",
+    "init_boot",
+    "recovery",
+    "vendor_boot",
+    "vendor_kernel_boot",
+];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum UpdateType {
+    Full,
+    Partial,
+    Incremental,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectPartition {
+    name: String,
+    size: u64,
+    operation_types: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectBootImage {
+    partition: String,
+    header_version: u32,
+    ramdisks: usize,
+    has_otacerts: bool,
+    has_recovery_ramdisk: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectDescriptor {
+    partition: String,
+    /// `chained` for chain partition descriptors. Otherwise, the partition is
+    /// rooted in this vbmeta image and this is `hash` or `hashtree`.
+    kind: &'static str,
+    /// Whether the A/B slot suffix is appended to the partition name.
+    uses_ab_suffix: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectVbmeta {
+    partition: String,
+    algorithm: String,
+    flags: u32,
+    descriptors: Vec<InspectDescriptor>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Patchable,
+    PatchableWithFlags,
+    Unsupported,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectReport {
+    update_type: UpdateType,
+    payload_version: u64,
+    payload_minor_version: u32,
+    security_patch_level: Option<String>,
+    signature_algorithm: String,
+    otacerts_partition: Option<String>,
+    /// Partition whose ramdisk is used when booting into recovery.
+    recovery_ramdisk_partition: Option<String>,
+    /// Partition that avbroot selects for each image type, like `@gki_ramdisk`,
+    /// when patching.
+    profile: BTreeMap<String, String>,
+    verdict: Verdict,
+    /// Options that `ota patch` needs in addition to the usual ones.
+    required_flags: Vec<String>,
+    /// Reasons why the OTA cannot be patched.
+    reasons: Vec<String>,
+    partitions: Vec<InspectPartition>,
+    boot_images: Vec<InspectBootImage>,
+    vbmeta: Vec<InspectVbmeta>,
+}
+
+/// Whether a boot image contains the ramdisk that is used when booting into
+/// recovery. This is the recovery partition on devices that have one. On other
+/// devices, it is the vendor boot image's recovery fragment, its only ramdisk
+/// for v3, or the boot image's ramdisk for v0 through v2.
+pub fn has_recovery_ramdisk(partition: &str, boot_image: &BootImage) -> bool {
+    match boot_image {
+        BootImage::V0Through2(b) => !b.ramdisk.is_empty(),
+        BootImage::V3Through4(b) => partition == "recovery" && !b.ramdisk.is_empty(),
+        BootImage::VendorV3Through4(b) => match &b.v4_extra {
+            Some(v4) => v4.ramdisk_metas.iter().any(|m| {
+                m.ramdisk_type == bootimage::VENDOR_RAMDISK_TYPE_RECOVERY
+                    || m.ramdisk_name == "recovery"
+            }),
+            None => !b.ramdisks.is_empty(),
+        },
+    }
+}
+
+/// Read the fixed-size fields of the payload header from an OTA zip. Unlike
+/// [`ota::parse_zip_ota_info()`], this works for unsupported payload versions.
+fn read_raw_payload_header(reader: impl Read + Seek) -> Result<RawPayloadHeader> {
+    let mut zip = ZipArchive::new(reader).context("Failed to read OTA zip")?;
+    let entry = zip
+        .by_name(ota::PATH_PAYLOAD)
+        .with_context(|| format!("Failed to open zip entry: {}", ota::PATH_PAYLOAD))?;
+
+    RawPayloadHeader::from_reader(entry).context("Failed to read payload header")
+}
+
+fn inspect_ota(cli: &InspectCli, cancel_signal: &Arc<AtomicBool>) -> Result<InspectReport> {
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let mut reader = BufReader::new(raw_reader);
+
+    // The OTA is re-signed when patching, so this is informational only.
+    let signature_algorithm = match ota::OtaSignature::from_zip(&mut reader) {
+        Ok(s) => format!("{:?}", s.signature_algorithm()),
+        Err(e) => format!("unsupported: {e}"),
+    };
+
+    let (metadata, _, header, _) = match ota::parse_zip_ota_info(&mut reader) {
+        Ok(info) => info,
+        Err(e) => {
+            // Show whatever is still readable. This is the command that users
+            // run to report unsupported OTAs, so a bare error is not useful.
+            warning!("Signature algorithm: {signature_algorithm}");
+            if let Ok(raw_header) = read_raw_payload_header(&mut reader) {
+                warning!(
+                    "Payload version: {} (manifest: {} bytes, metadata signature: {} bytes)",
+                    raw_header.version,
+                    raw_header.manifest_size,
+                    raw_header.metadata_signature_size,
+                );
+            }
+
+            return Err(e).context("Failed to parse OTA metadata");
+        }
+    };
+
+    let pfs_raw = metadata
+        .property_files
+        .get(ota::PF_NAME)
+        .ok_or_else(|| anyhow!("Missing property files: {}", ota::PF_NAME))?;
+    let pfs = ota::parse_property_files(pfs_raw)
+        .with_context(|| format!("Failed to parse property files: {}", ota::PF_NAME))?;
+    let pf_payload = pfs
+        .iter()
+        .find(|pf| pf.name == ota::PATH_PAYLOAD)
+        .ok_or_else(|| anyhow!("Missing property files entry: {}", ota::PATH_PAYLOAD))?;
+
+    let raw_reader = reader.into_inner();
+    let open_payload = || -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(SectionReader::new(
+            BufReader::new(raw_reader.clone()),
+            pf_payload.offset,
+            pf_payload.size,
+        )?))
+    };
+
+    let update_type = if !header.is_full_ota() {
+        UpdateType::Incremental
+    } else if header.is_partial_update() {
+        UpdateType::Partial
+    } else {
+        UpdateType::Full
+    };
+
+    let mut partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| -> Result<InspectPartition> {
+            let operation_types = p
+                .operations
+                .iter()
+                .map(|op| format!("{:?}", op.type_pb))
+                .collect::<BTreeSet<_>>();
+
+            Ok(InspectPartition {
+                name: p.partition_name.clone(),
+                size: payload::partition_size(p, header.manifest.block_size)?,
+                operation_types: operation_types.into_iter().collect(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut required_flags = vec![];
+    let mut reasons = vec![];
+    let mut boot_images = vec![];
+    let mut vbmeta = vec![];
+
+    let partitions_by_type = get_partitions_by_type(&header.manifest).ok();
+    let otacerts_partition = partitions_by_type
+        .as_ref()
+        .and_then(|p| p.get("@otacerts"))
+        .cloned();
+    let profile = partitions_by_type
+        .into_iter()
+        .flatten()
+        .filter(|(t, _)| !t.starts_with("@vbmeta:"))
+        .collect::<BTreeMap<_, _>>();
+
+    if let Err(e) = get_required_images(&header.manifest, &cli.boot_partition, true) {
+        if get_required_images(&header.manifest, &cli.boot_partition, false).is_ok() {
+            required_flags.push("--rootless".to_owned());
+        } else {
+            reasons.push(e.to_string());
+        }
+    }
+
+    // Images can only be extracted if they don't depend on the existing data
+    // on the device.
+    if matches!(update_type, UpdateType::Incremental) {
+        reasons.push("Payload is a delta OTA, not a full OTA".to_owned());
+    } else {
+        for p in &partitions {
+            let name = &p.name;
+            let is_boot = INSPECT_BOOT_PARTITIONS.contains(&name.as_str());
+            let is_vbmeta = name.contains("vbmeta");
+
+            if !is_boot && !is_vbmeta {
+                continue;
+            }
+
+            let stream =
+                payload::extract_image_to_memory(open_payload, &header, name, cancel_signal)
+                    .with_context(|| format!("Failed to extract from payload: {name}"))?;
+
+            if is_boot {
+                let boot_image = match BootImage::from_reader(stream.clone_rewind()) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        reasons.push(format!("Failed to read boot image: {name}: {e}"));
+                        continue;
+                    }
+                };
+                let ramdisks = match &boot_image {
+                    BootImage::V0Through2(b) => usize::from(!b.ramdisk.is_empty()),
+                    BootImage::V3Through4(b) => usize::from(!b.ramdisk.is_empty()),
+                    BootImage::VendorV3Through4(b) => b.ramdisks.len(),
+                };
+                let has_otacerts =
+                    OtaCertPatcher::get_certificates(&boot_image).map_or(false, |c| !c.is_empty());
+
+                if otacerts_partition.as_ref() == Some(name) && !has_otacerts {
+                    reasons.push(format!("No otacerts.zip found in ramdisk: {name}"));
+                }
+
+                boot_images.push(InspectBootImage {
+                    partition: name.clone(),
+                    header_version: boot_image.header_version(),
+                    ramdisks,
+                    has_otacerts,
+                    has_recovery_ramdisk: has_recovery_ramdisk(name, &boot_image),
+                });
+            } else {
+                let (avb_header, _, _) = match avb::load_image(stream.clone_rewind()) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        reasons.push(format!("Failed to read vbmeta image: {name}: {e}"));
+                        continue;
+                    }
+                };
+
+                if avb_header.flags != 0 {
+                    let flag = "--clear-vbmeta-flags".to_owned();
+                    if !required_flags.contains(&flag) {
+                        required_flags.push(flag);
+                    }
+                }
+
+                let descriptors = avb_header
+                    .descriptors
+                    .iter()
+                    .filter_map(|d| {
+                        let kind = match d {
+                            Descriptor::ChainPartition(_) => "chained",
+                            Descriptor::Hash(_) => "hash",
+                            Descriptor::Hashtree(_) => "hashtree",
+                            _ => return None,
+                        };
+
+                        Some(InspectDescriptor {
+                            partition: d.partition_name()?.to_owned(),
+                            kind,
+                            uses_ab_suffix: d.uses_ab_suffix(),
+                        })
+                    })
+                    .collect();
+
+                vbmeta.push(InspectVbmeta {
+                    partition: name.clone(),
+                    algorithm: format!("{:?}", avb_header.algorithm_type),
+                    flags: avb_header.flags,
+                    descriptors,
+                });
+            }
+        }
+    }
+
+    // A dedicated recovery partition takes precedence over the recovery
+    // resources in the other boot images.
+    let recovery_ramdisk_partition = ["recovery", "vendor_boot", "boot"]
+        .into_iter()
+        .find(|p| {
+            boot_images
+                .iter()
+                .any(|b| b.partition == *p && b.has_recovery_ramdisk)
+        })
+        .map(|p| p.to_owned());
+
+    let verdict = if !reasons.is_empty() {
+        Verdict::Unsupported
+    } else if !required_flags.is_empty() {
+        Verdict::PatchableWithFlags
+    } else {
+        Verdict::Patchable
+    };
+
+    Ok(InspectReport {
+        update_type,
+        payload_version: header.version,
+        payload_minor_version: header.manifest.minor_version,
+        security_patch_level: header.manifest.security_patch_level.clone(),
+        signature_algorithm,
+        otacerts_partition,
+        recovery_ramdisk_partition,
+        profile,
+        verdict,
+        required_flags,
+        reasons,
+        partitions,
+        boot_images,
+        vbmeta,
+    })
+}
+
+fn display_inspect_report(report: &InspectReport) {
+    println!("Update type: {:?}", report.update_type);
+    println!(
+        "Payload version: {} (minor version {})",
+        report.payload_version, report.payload_minor_version,
+    );
+    if let Some(spl) = &report.security_patch_level {
+        println!("Security patch level: {spl}");
+    }
+    println!("Signature algorithm: {}", report.signature_algorithm);
+    println!(
+        "otacerts.zip partition: {}",
+        report.otacerts_partition.as_deref().unwrap_or("<none>"),
+    );
+    println!(
+        "Recovery ramdisk partition: {}",
+        report
+            .recovery_ramdisk_partition
+            .as_deref()
+            .unwrap_or("<none>"),
+    );
+
+    if !report.profile.is_empty() {
+        println!();
+        println!("Device profile:");
+        for (image_type, partition) in &report.profile {
+            println!("- {image_type}: {partition}");
+        }
+    }
+
+    println!();
+    println!("Partitions:");
+    for p in &report.partitions {
+        println!(
+            "- {}: {} bytes, {}",
+            p.name,
+            p.size,
+            joined(&p.operation_types)
+        );
+    }
+
+    if !report.boot_images.is_empty() {
+        println!();
+        println!("Boot images:");
+        for b in &report.boot_images {
+            let mut contents = String::new();
+            if b.has_otacerts {
+                contents.push_str(", has otacerts.zip");
+            }
+            if b.has_recovery_ramdisk {
+                contents.push_str(", has recovery ramdisk");
+            }
+
+            println!(
+                "- {}: header v{}, {} ramdisk(s){contents}",
+                b.partition, b.header_version, b.ramdisks,
+            );
+        }
+    }
+
+    for v in &report.vbmeta {
+        println!();
+        println!("{} ({}, flags {:#x}):", v.partition, v.algorithm, v.flags);
+        for d in &v.descriptors {
+            let slotted = if d.uses_ab_suffix { ", slotted" } else { "" };
+            println!("- {}: {}{slotted}", d.partition, d.kind);
+        }
+    }
+
+    println!();
+    match report.verdict {
+        Verdict::Patchable => println!("Verdict: patchable"),
+        Verdict::PatchableWithFlags => println!(
+            "Verdict: patchable with flags: {}",
+            report.required_flags.join(" "),
+        ),
+        Verdict::Unsupported => {
+            println!("Verdict: unsupported because:");
+            for reason in &report.reasons {
+                println!("- {reason}");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct InspectOperations {
+    partition: String,
+    operations: Vec<OperationDesc>,
+}
+
+fn display_extents(extents: &[ExtentDesc]) -> String {
+    if extents.is_empty() {
+        return "<none>".to_owned();
+    }
+
+    joined(
+        extents
+            .iter()
+            .map(|e| format!("{}+{}", e.start_block, e.num_blocks)),
+    )
+}
+
+fn inspect_operations(input: &Path, partition: &str, json: bool) -> Result<()> {
+    let raw_reader = File::open(input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {input:?}"))?;
+    let mut zip = ZipArchive::new(BufReader::new(raw_reader))
+        .with_context(|| format!("Failed to read zip: {input:?}"))?;
+    let entry = zip
+        .by_name(ota::PATH_PAYLOAD)
+        .with_context(|| format!("Failed to open zip entry: {}", ota::PATH_PAYLOAD))?;
+
+    let operations = payload::describe_operations(entry, partition)
+        .with_context(|| format!("Failed to describe operations: {partition}"))?;
+
+    if json {
+        let report = InspectOperations {
+            partition: partition.to_owned(),
+            operations,
+        };
+        let data =
+            serde_json::to_string_pretty(&report).context("Failed to serialize operations")?;
+        println!("{data}");
+    } else {
+        for op in &operations {
+            let data = match (op.data_offset, op.data_length) {
+                (Some(offset), Some(length)) => format!("{length} bytes at {offset}"),
+                _ => "<none>".to_owned(),
+            };
+
+            println!(
+                "{}: {}, data: {data}, src: {}, dst: {}",
+                op.index,
+                op.op_type,
+                display_extents(&op.src_extents),
+                display_extents(&op.dst_extents),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn inspect_subcommand(cli: &InspectCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    if let Some(partition) = &cli.operations {
+        return inspect_operations(&cli.input, partition, cli.json);
+    }
+
+    let report = inspect_ota(cli, cancel_signal)?;
+
+    if cli.json {
+        let data = serde_json::to_string_pretty(&report)
+            .context("Failed to serialize inspection report")?;
+        println!("{data}");
+    } else {
+        display_inspect_report(&report);
+    }
+
+    Ok(())
+}
+
+/// Split a `<host>[:<port>]` device address. IPv6 addresses with a port must be
+/// enclosed in brackets.
+pub fn parse_device_address(address: &str, default_port: u16) -> (&str, u16) {
+    if let Some((host, port)) = address.rsplit_once(':') {
+        if let Ok(port) = port.parse() {
+            if !host.contains(':') || (host.starts_with('[') && host.ends_with(']')) {
+                return (host.trim_start_matches('[').trim_end_matches(']'), port);
+            }
+        }
+    }
+
+    (address, default_port)
+}
+
+/// Connect to adbd over TCP. If `adb_key` is not specified, the key from
+/// `~/.android/adbkey` is used if it exists.
+pub fn connect_adb(device: &str, adb_key: Option<&Path>) -> Result<AdbConnection<TcpStream>> {
+    let key_path = adb_key.map(|p| p.to_owned()).or_else(|| {
+        env::var_os("HOME")
+            .map(|h| Path::new(&h).join(".android").join("adbkey"))
+            .filter(|p| p.exists())
+    });
+    let key = key_path
+        .map(|p| {
+            let source = PassphraseSource::Prompt(format!("Enter passphrase for {p:?}: "));
+            crypto::read_pem_key_file(&p, &source)
+                .with_context(|| format!("Failed to load key: {p:?}"))
+        })
+        .transpose()?;
+
+    let (host, port) = parse_device_address(device, ADB_DEFAULT_PORT);
+    status!("Connecting to {host} port {port}");
+
+    let stream = TcpStream::connect((host, port))
+        .with_context(|| format!("Failed to connect to device: {device}"))?;
+    stream
+        .set_nodelay(true)
+        .context("Failed to disable Nagle's algorithm")?;
+
+    AdbConnection::connect(stream, key.as_ref())
+        .with_context(|| format!("Failed to connect to adbd: {device}"))
+}
+
+pub fn sideload_subcommand(cli: &SideloadCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let file = File::open(&cli.input)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let size = file
+        .metadata()
+        .with_context(|| format!("Failed to stat: {:?}", cli.input))?
+        .len();
+
+    let mut conn = connect_adb(&cli.device, cli.adb_key.as_deref())?;
+    if !conn.banner().starts_with("sideload:") {
+        bail!("Device is not in sideload mode: {:?}", conn.banner());
+    }
+
+    status!("Sideloading {:?}", cli.input);
+
+    let mut last_percent = 0;
+
+    adb::sideload(
+        &mut conn,
+        file,
+        size,
+        |sent, total| {
+            let percent = sent * 100 / total;
+            if percent / 10 > last_percent / 10 {
+                status!("Sent {percent}% of the package");
+            }
+            last_percent = percent;
+        },
+        cancel_signal,
+    )
+    .context("Failed to sideload package")?;
+
+    status!("Device accepted the package");
+
+    Ok(())
+}
+
+/// Percent-encode everything in a URL path segment aside from the unreserved
+/// characters from RFC 3986.
+fn encode_url_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                char::from(b).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+pub fn export_update_descriptor_subcommand(
+    cli: &ExportUpdateDescriptorCli,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let file_name = cli
+        .input
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Input file name is not valid UTF-8: {:?}", cli.input))?;
+    let url = format!(
+        "{}/{}",
+        cli.url_base.trim_end_matches('/'),
+        encode_url_segment(file_name),
+    );
+
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let reader = BufReader::new(raw_reader);
+
+    let descriptor =
+        ota::UpdateDescriptor::from_zip(reader, &url, cli.channel.as_deref(), cancel_signal)
+            .with_context(|| format!("Failed to build update descriptor: {:?}", cli.input))?;
+    let data = descriptor
+        .to_json()
+        .context("Failed to serialize update descriptor")?;
+
+    if let Some(path) = &cli.output {
+        fs::write(path, data).with_context(|| format!("Failed to write file: {path:?}"))?;
+    } else {
+        print!("{data}");
+    }
+
+    Ok(())
+}
+
+pub fn sign_request_subcommand(
+    cli: &SignRequestCli,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let reader = BufReader::new(raw_reader);
 
-    status!("Extracting partition images to temporary directory");
+    let request = ota::prepare_signing(reader, cancel_signal)
+        .with_context(|| format!("Failed to compute digest to sign: {:?}", cli.input))?;
 
-    let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
-    let raw_reader = reader.into_inner();
-    let unique_images = header
-        .manifest
-        .partitions
-        .iter()
-        .map(|p| &p.partition_name)
-        .cloned()
-        .collect::<BTreeSet<_>>();
+    fs::write(&cli.output, &request.digest)
+        .with_context(|| format!("Failed to write digest: {:?}", cli.output))?;
 
-    extract_ota_zip(
-        &raw_reader,
-        temp_dir.path(),
-        pf_payload.offset,
-        pf_payload.size,
-        &header,
-        &unique_images,
-        cancel_signal,
-    )?;
+    status!(
+        "SHA-256 digest of the first {} bytes: {}",
+        request.hashed_size,
+        hex::encode(&request.digest),
+    );
 
-    status!("Checking ramdisk's otacerts.zip");
+    Ok(())
+}
 
-    let boot_image = {
-        let partitions_by_type = get_partitions_by_type(&header.manifest)?;
-        let path = temp_dir
-            .path()
-            .join(format!("{}.img", partitions_by_type["@otacerts"]));
-        let file =
-            File::open(&path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
-        BootImage::from_reader(BufReader::new(file))
-            .with_context(|| format!("Failed to read boot image: {path:?}"))?
+pub fn sign_finalize_subcommand(
+    cli: &SignFinalizeCli,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let cert = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+    let signature = ota::ExternalSignature {
+        signature: fs::read(&cli.signature)
+            .with_context(|| format!("Failed to read signature: {:?}", cli.signature))?,
+        padding: if cli.pss {
+            RsaPadding::Pss
+        } else {
+            RsaPadding::Pkcs1v15
+        },
+        cert,
     };
 
-    let ramdisk_certs = OtaCertPatcher::get_certificates(&boot_image)
-        .context("Failed to read ramdisk's otacerts.zip")?;
-    if !ramdisk_certs.contains(&ota_cert) {
-        bail!("Ramdisk's otacerts.zip does not contain OTA certificate");
-    }
-
-    status!("Verifying AVB signatures");
-
-    let public_key = if let Some(p) = &cli.public_key_avb {
-        let data = fs::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
-        let key = avb::decode_public_key(&data)
-            .with_context(|| format!("Failed to decode public key: {p:?}"))?;
+    let raw_reader = File::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let reader = BufReader::new(raw_reader);
 
-        Some(key)
-    } else {
-        None
+    let output_dir = match cli.output.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
     };
+    let temp_writer = tempfile::NamedTempFile::new_in(output_dir)
+        .with_context(|| format!("Failed to create temporary file in: {output_dir:?}"))?;
+    set_umask_permissions(temp_writer.as_file(), temp_writer.path())?;
+    let mut writer = BufWriter::new(temp_writer);
 
-    let mut seen = HashSet::<String>::new();
-    let mut descriptors = HashMap::<String, Descriptor>::new();
+    ota::finalize_signing(reader, &mut writer, &signature, cancel_signal)
+        .with_context(|| format!("Failed to insert signature: {:?}", cli.signature))?;
 
-    cli::avb::verify_headers(
-        temp_dir.path(),
-        "vbmeta",
-        public_key.as_ref(),
-        &mut seen,
-        &mut descriptors,
-    )?;
-    cli::avb::verify_descriptors(temp_dir.path(), &descriptors, cancel_signal)?;
+    let temp_writer = writer.into_inner().context("Failed to flush output")?;
 
-    status!("Signatures are all valid!");
+    temp::persist(temp_writer, &cli.output)
+        .with_context(|| format!("Failed to write output: {:?}", cli.output))?;
+
+    status!("Signed OTA written to: {:?}", cli.output);
 
     Ok(())
 }
@@ -1189,6 +4889,13 @@ pub fn ota_main(cli: &OtaCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
         OtaCommand::Patch(c) => patch_subcommand(c, cancel_signal),
         OtaCommand::Extract(c) => extract_subcommand(c, cancel_signal),
         OtaCommand::Verify(c) => verify_subcommand(c, cancel_signal),
+        OtaCommand::Inspect(c) => inspect_subcommand(c, cancel_signal),
+        OtaCommand::Sideload(c) => sideload_subcommand(c, cancel_signal),
+        OtaCommand::ExportUpdateDescriptor(c) => {
+            export_update_descriptor_subcommand(c, cancel_signal)
+        }
+        OtaCommand::SignRequest(c) => sign_request_subcommand(c, cancel_signal),
+        OtaCommand::SignFinalize(c) => sign_finalize_subcommand(c, cancel_signal),
     }
 }
 
@@ -1224,6 +4931,44 @@ pub struct PatchCli {
     #[arg(short, long, value_name = "FILE", value_parser)]
     pub output: Option<PathBuf>,
 
+    /// Directory for temporary files.
+    ///
+    /// The default is the output file's directory. If this is on a different
+    /// filesystem, the finished OTA is copied to the output path, so both
+    /// locations need enough space for the OTA.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Directory for saving unmodified copies of the images that are patched.
+    ///
+    /// The original boot and vbmeta images from the OTA are written to this
+    /// directory, along with a manifest containing their digests. They can be
+    /// flashed back with `avbroot device restore` to temporarily unroot.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub save_stock_images: Option<PathBuf>,
+
+    /// Size of the write buffer for the output OTA in KiB.
+    ///
+    /// The zip and payload writers issue many small writes. A larger buffer
+    /// reduces the number of syscalls, which helps on slow storage, like SD
+    /// cards and network mounts.
+    #[arg(
+        long,
+        value_name = "KiB",
+        default_value_t = 1024,
+        value_parser = value_parser!(u32).range(4..=1024 * 1024)
+    )]
+    pub output_buffer_size: u32,
+
+    /// Number of partition images to compress in parallel.
+    ///
+    /// Each image being compressed needs a fixed amount of memory, regardless
+    /// of its size. The compressed images are stored in the temporary
+    /// directory until they are written to the payload. The default is the
+    /// number of CPUs.
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+
     /// Private key for signing vbmeta images.
     #[arg(long, alias = "privkey-avb", value_name = "FILE", value_parser)]
     pub key_avb: PathBuf,
@@ -1233,9 +4978,21 @@ pub struct PatchCli {
     pub key_ota: PathBuf,
 
     /// Certificate for OTA signing key.
+    ///
+    /// This can be the same PKCS#12 bundle as --key-ota.
     #[arg(long, value_name = "FILE", value_parser)]
     pub cert_ota: PathBuf,
 
+    /// Alias of the entry to use in PKCS#12 bundles.
+    ///
+    /// Keys and certificates with a .p12 or .pfx extension are loaded from
+    /// PKCS#12 bundles, which are decrypted with the corresponding passphrase
+    /// option. This is only needed if a bundle has more than one private key
+    /// (or certificate, if it has no private key). The alias is the bundle
+    /// entry's friendly name.
+    #[arg(long, value_name = "ALIAS")]
+    pub p12_alias: Option<String>,
+
     /// Environment variable containing AVB private key passphrase.
     #[arg(
         long,
@@ -1276,10 +5033,51 @@ pub struct PatchCli {
     )]
     pub pass_ota_file: Option<PathBuf>,
 
+    /// Private key for signing the payload.
+    ///
+    /// If this is omitted, the payload is signed with --key-ota. The zip is
+    /// always signed with --key-ota. Both certificates are added to
+    /// otacerts.zip.
+    #[arg(long, value_name = "FILE", value_parser, requires = "cert_payload")]
+    pub key_payload: Option<PathBuf>,
+
+    /// Certificate for payload signing key.
+    #[arg(long, value_name = "FILE", value_parser, requires = "key_payload")]
+    pub cert_payload: Option<PathBuf>,
+
+    /// Environment variable containing payload private key passphrase.
+    #[arg(
+        long,
+        value_name = "ENV_VAR",
+        value_parser,
+        group = "pass_payload",
+        requires = "key_payload"
+    )]
+    pub pass_payload_env_var: Option<OsString>,
+
+    /// File containing payload private key passphrase.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        group = "pass_payload",
+        requires = "key_payload"
+    )]
+    pub pass_payload_file: Option<PathBuf>,
+
     /// Use partition image from a file instead of the original payload.
     #[arg(long, value_names = ["PARTITION", "FILE"], value_parser = value_parser!(OsString), num_args = 2)]
     pub replace: Vec<OsString>,
 
+    /// Replace a device tree entry in the dtbo image.
+    ///
+    /// The index refers to the entry's position in the dtbo image's device
+    /// tree table. The dtbo image is taken from --replace dtbo if specified and
+    /// from the original payload otherwise. All other entries are kept as is
+    /// and the image is re-signed. This can be specified multiple times.
+    #[arg(long, value_name = "INDEX=FILE", value_parser = parse_dtbo_entry)]
+    pub replace_dtbo_entry: Vec<(usize, PathBuf)>,
+
     #[command(flatten)]
     pub root: RootGroup,
 
@@ -1291,6 +5089,47 @@ pub struct PatchCli {
     #[arg(long, value_name = "NUMBER", conflicts_with_all = ["prepatched", "rootless"])]
     pub magisk_random_seed: Option<u64>,
 
+    /// Keep dm-verity enabled (Magisk's KEEPVERITY).
+    ///
+    /// If false, the verity and AVB flags are removed from the fstab files in
+    /// the boot image's ramdisk.
+    #[arg(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        action = ArgAction::Set,
+        conflicts_with_all = ["prepatched", "rootless"],
+    )]
+    pub magisk_keep_verity: bool,
+
+    /// Keep forced encryption enabled (Magisk's KEEPFORCEENCRYPT).
+    ///
+    /// If false, the forced encryption flags in the fstab files in the boot
+    /// image's ramdisk are replaced with `encryptable`. This is only useful on
+    /// old devices that don't use file-based encryption.
+    #[arg(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        action = ArgAction::Set,
+        conflicts_with_all = ["prepatched", "rootless"],
+    )]
+    pub magisk_keep_force_encrypt: bool,
+
+    /// Only start Magisk when booting into recovery (Magisk's RECOVERYMODE).
+    ///
+    /// This is for devices that boot recovery from the boot image, where
+    /// Magisk is started by holding the recovery key combo.
+    #[arg(long, conflicts_with_all = ["prepatched", "rootless"])]
+    pub magisk_recovery_mode: bool,
+
+    /// Patch the kernel for legacy system-as-root (Magisk's LEGACYSAR).
+    ///
+    /// This replaces `skip_initramfs` with `want_initramfs` in the kernel so
+    /// that the device boots from the ramdisk.
+    #[arg(long, conflicts_with_all = ["prepatched", "rootless"])]
+    pub magisk_legacy_sar: bool,
+
     /// Ignore Magisk compatibility/version warnings.
     #[arg(long, conflicts_with_all = ["prepatched", "rootless"])]
     pub ignore_magisk_warnings: bool,
@@ -1299,13 +5138,244 @@ pub struct PatchCli {
     #[arg(long, action = ArgAction::Count, conflicts_with_all = ["magisk", "rootless"])]
     pub ignore_prepatched_compat: u8,
 
+    /// Fail if the input OTA was already patched by avbroot.
+    ///
+    /// By default, an OTA that was previously patched by avbroot is detected
+    /// and re-patched. The previous Magisk patch is removed from the boot image
+    /// and the previous OTA certificate is replaced.
+    #[arg(long)]
+    pub refuse_repatch: bool,
+
+    /// Skip checking the GKI kernel's KMI against vendor_boot's modules.
+    ///
+    /// If boot or vendor_boot is replaced or a prepatched boot image is used,
+    /// the KMI version of the kernel must match the vermagic of every kernel
+    /// module in vendor_boot's ramdisks. The modules in vendor_dlkm are not
+    /// checked.
+    #[arg(long)]
+    pub skip_kmi_check: bool,
+
+    /// Leave a partition's AVB signature untouched.
+    ///
+    /// The image is not re-signed and the chain descriptor in its parent keeps
+    /// the original public key. This is for chained vbmeta images whose key is
+    /// pinned by the bootloader. Partitions that avbroot patches cannot be
+    /// skipped. This can be specified multiple times.
+    #[arg(long, value_name = "PARTITION")]
+    pub skip_avb: Vec<String>,
+
     /// Forcibly clear vbmeta flags if they disable AVB.
     #[arg(long)]
     pub clear_vbmeta_flags: bool,
 
+    /// Keep vbmeta flags even if they disable AVB.
+    ///
+    /// The flags are preserved exactly when the vbmeta images are re-signed.
+    /// This is only useful for configurations that intentionally disable
+    /// hashtree verification while keeping the descriptors.
+    #[arg(long, conflicts_with = "clear_vbmeta_flags")]
+    pub keep_vbmeta_flags: bool,
+
+    /// Keep a root vbmeta image that has no descriptors.
+    ///
+    /// By default, a root vbmeta image with no descriptors, like the stubs that
+    /// are flashed to disable AVB, is rebuilt to cover every partition with
+    /// AVB metadata and verification is re-enabled. This requires extracting
+    /// the partitions to read their AVB metadata, which can be slow.
+    #[arg(long)]
+    pub keep_vbmeta_stub: bool,
+
+    /// Remove a partition from the output OTA entirely.
+    ///
+    /// The output becomes a partial update, which leaves the partition on the
+    /// device untouched. This is useful for building a smaller OTA that only
+    /// updates a few partitions, like the boot image. Partitions that avbroot
+    /// patches cannot be excluded. If a vbmeta image in the output has a
+    /// descriptor for the partition, the partition (or that vbmeta image) must
+    /// also be passed to --skip-avb. This can be specified multiple times.
+    #[arg(long, value_name = "PARTITION")]
+    pub exclude: Vec<String>,
+
+    /// Print the vbmeta plan and exit without writing the output.
+    ///
+    /// The plan lists which vbmeta images are re-signed, which are passed
+    /// through unmodified, and how their header flags change. Only the vbmeta
+    /// images are extracted from the input OTA.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Hash the full size of images without AVB metadata.
+    ///
+    /// By default, when an image passed to --replace has no vbmeta footer,
+    /// trailing zero padding is excluded from the hash descriptor's image
+    /// size, like avbtool does when given the unpadded image. Filesystems and
+    /// boot images are never cut shorter than the size in their headers.
+    #[arg(long)]
+    pub hash_full_size: bool,
+
+    /// Message to store in the output zip's archive comment.
+    ///
+    /// The message can be auto, none, or a custom string. auto describes the
+    /// avbroot version and the OTA's build fingerprint and build date. none
+    /// keeps the default "signed by avbroot" message. The message is placed
+    /// before the signature, like signapk does, so it is ignored when recovery
+    /// verifies the OTA.
+    #[arg(long, value_name = "COMMENT", value_parser = parse_output_comment)]
+    pub output_comment: Option<OutputComment>,
+
+    /// Export images for Dynamic System Updates (DSU) to a directory.
+    ///
+    /// After the patched OTA is written, system.img is extracted from it and
+    /// the vbmeta header in its footer is signed with the AVB key, since DSU
+    /// verifies each image on its own. The image is padded to a multiple of
+    /// 4096 bytes. A vbmeta.img covering the DSU images, the AVB public key
+    /// (dsu.avbpubkey), and the adb commands for starting the installation are
+    /// also produced. Unless the device is unlocked, DSU only accepts images
+    /// signed by a key in the /avb directory of the first stage ramdisk.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub dsu: Option<PathBuf>,
+
+    /// Additional partition to include in the DSU images.
+    ///
+    /// This can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "PARTITION",
+        value_parser = ["product", "system_ext"],
+        requires = "dsu"
+    )]
+    pub dsu_partition: Vec<String>,
+
+    /// Export the patched images for both A/B slots to a directory.
+    ///
+    /// After the patched OTA is written, every partition is extracted from it
+    /// once and then written as <partition>_a.img and <partition>_b.img for
+    /// flashing each slot with fastboot. Partitions that vbmeta refers to
+    /// without the A/B suffix are written once as <partition>.img. The images
+    /// for both slots are verified against the AVB key.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub ab_images: Option<PathBuf>,
+
+    /// RSA padding scheme for signatures in a specific format.
+    ///
+    /// The format can be avb, payload, or zip and the padding can be pkcs1v15
+    /// or pss. All formats default to pkcs1v15. Only zip signatures can use
+    /// pss, but AOSP recovery will reject them, so this is only useful for
+    /// custom recoveries. This can be specified multiple times.
+    #[arg(long, value_name = "FORMAT=PADDING", value_parser = parse_signature_padding)]
+    pub signature_padding: Vec<(SignatureFormat, RsaPadding)>,
+
+    /// Recompress the ramdisks of patched boot images with a different format.
+    ///
+    /// The format can be auto, smallest, none, gzip, lz4_legacy, or xz. auto
+    /// keeps the original format. smallest picks the format with the smallest
+    /// output, but keeps the original ramdisk if the savings are below
+    /// --ramdisk-min-savings. If the kernel has an embedded config, it is
+    /// checked for the corresponding decompressors.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "auto",
+        value_parser = parse_ramdisk_compression
+    )]
+    pub ramdisk_compression: RamdiskCompression,
+
+    /// Minimum savings needed for --ramdisk-compression smallest.
+    ///
+    /// This is a ratio of the original ramdisk size. For example, 0.05 means
+    /// that a ramdisk is only recompressed if the result is at least 5%
+    /// smaller.
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value = "0.05",
+        value_parser = parse_min_savings_ratio
+    )]
+    pub ramdisk_min_savings: f64,
+
+    /// Remove kernel cmdline descriptors matching a regex from the root vbmeta.
+    ///
+    /// This can be specified multiple times and fails if a regex matches
+    /// nothing. Descriptors are removed before any are added with
+    /// --avb-cmdline-add.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub avb_cmdline_remove: Vec<Regex>,
+
+    /// Add a kernel cmdline descriptor to the root vbmeta.
+    ///
+    /// The value is in the form <flags>:<cmdline>. The flags are 0 to always
+    /// apply the cmdline, 1 to only apply it if hashtree verification is not
+    /// disabled, or 2 to only apply it if hashtree verification is disabled.
+    /// This can be specified multiple times.
+    #[arg(long, value_name = "FLAGS:CMDLINE", value_parser = parse_kernel_cmdline)]
+    pub avb_cmdline_add: Vec<KernelCmdlineDescriptor>,
+
+    /// Remove property descriptors with a key from the root vbmeta.
+    ///
+    /// This can be specified multiple times and fails if no descriptor has the
+    /// key. Descriptors are removed before any are set with --avb-prop. All
+    /// other properties and the release string are kept as-is.
+    #[arg(long, value_name = "KEY")]
+    pub avb_delete_prop: Vec<String>,
+
+    /// Set a property descriptor in the root vbmeta.
+    ///
+    /// The value is in the form <key>=<value>. An existing descriptor with the
+    /// same key is replaced in place. Otherwise, a new descriptor is added.
+    /// This can be specified multiple times.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_property)]
+    pub avb_prop: Vec<PropertyDescriptor>,
+
     /// Boot partition name.
+    ///
+    /// An A/B slot suffix, like `init_boot_a`, is accepted since the partitions
+    /// in the payload are not slotted.
     #[arg(long, value_name = "PARTITION", default_value = "@gki_ramdisk")]
     pub boot_partition: String,
+
+    /// Previously patched OTA zip to reuse compressed images from.
+    ///
+    /// Replacement images that are identical to the ones in this OTA are copied
+    /// as-is instead of being compressed again. The copied data is verified to
+    /// decompress to the new image, so a reference OTA that doesn't match only
+    /// makes patching slower.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub ref_output: Option<PathBuf>,
+
+    /// Fail if any warnings are emitted.
+    ///
+    /// All warnings are repeated in the final summary regardless of this
+    /// option. When this is set, the output file is not written if there are
+    /// any warnings.
+    #[arg(long)]
+    pub deny_warnings: bool,
+
+    /// Write a JSON report of the warnings to this file.
+    ///
+    /// The report is written even if patching fails. It has a status of
+    /// "passed" or "failed" and lists every warning with its code, severity,
+    /// and message.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub report: Option<PathBuf>,
+
+    /// Fail if the output's vbmeta digest does not match.
+    ///
+    /// This is the SHA-256 digest that the device reports in the
+    /// `ro.boot.vbmeta.digest` property and in key attestation records after
+    /// installing the OTA. It is computed from the output before the output
+    /// file is written, so an OTA whose digest differs from an allowlisted
+    /// value is never produced.
+    #[arg(long, value_name = "HEX", value_parser = parse_vbmeta_digest)]
+    pub expect_vbmeta_digest: Option<[u8; 32]>,
+
+    /// Write Prometheus metrics to a file after patching.
+    ///
+    /// The file is written in the text exposition format for node_exporter's
+    /// textfile collector, even if patching fails. The patch counters are
+    /// carried over from the existing file.
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub metrics_file: Option<PathBuf>,
 }
 
 /// Extract partition images from an OTA zip's payload.
@@ -1330,6 +5400,28 @@ pub struct ExtractCli {
     /// Boot partition name.
     #[arg(long, value_name = "PARTITION", default_value = "@gki_ramdisk")]
     pub boot_partition: String,
+
+    /// Skip verifying the digest of each payload operation's data.
+    ///
+    /// This makes extraction slightly faster, but corrupted data will not be
+    /// detected unless the OTA is verified separately.
+    #[arg(long)]
+    pub skip_operation_digests: bool,
+
+    /// Number of threads to use for extracting images.
+    ///
+    /// The default is the number of CPUs.
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Maximum number of threads that read from the OTA at the same time.
+    ///
+    /// The images are extracted in parallel, which results in lots of seeking
+    /// between different parts of the file. On hard drives, setting this to a
+    /// small value may speed up extraction. Decompression is not limited by
+    /// this option. By default, reads are only limited by --jobs.
+    #[arg(long, value_name = "N")]
+    pub max_readers: Option<NonZeroUsize>,
 }
 
 /// Verify signatures of an OTA.
@@ -1348,12 +5440,210 @@ pub struct VerifyCli {
     #[arg(long, value_name = "FILE", value_parser)]
     pub cert_ota: Option<PathBuf>,
 
+    /// Certificate for verifying the payload signatures.
+    ///
+    /// This is only needed if the payload was signed with a different key than
+    /// the zip. If this is omitted, the payload is verified with the OTA
+    /// certificate embedded in the zip.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub cert_payload: Option<PathBuf>,
+
     /// Public key for verifying the vbmeta signatures.
     ///
     /// If this is omitted, the check only verifies that the signatures are
     /// valid, not that they are trusted.
     #[arg(long, value_name = "FILE", value_parser)]
     pub public_key_avb: Option<PathBuf>,
+
+    /// Also verify the AVB signatures and hashes of all partitions.
+    ///
+    /// This requires extracting every partition image to a temporary
+    /// directory, which needs as much free space as the unpacked OTA. This is
+    /// implied by --public-key-avb.
+    #[arg(long)]
+    pub verify_avb: bool,
+
+    /// Don't fail if a partition's AVB digest does not match its descriptor.
+    ///
+    /// This is useful when a partition is intentionally different from what
+    /// the vbmeta image expects, like a custom kernel in a prepatched boot
+    /// image. The mismatch is still shown as a warning and recorded in the
+    /// report. This can be specified multiple times.
+    #[arg(long, value_name = "PARTITION")]
+    pub ignore_avb_digest: Vec<String>,
+
+    /// Don't fail if the OTA certificates do not match.
+    ///
+    /// This covers the CMS embedded certificate, the certificate passed to
+    /// --cert-ota, and the certificates in the ramdisk's otacerts.zip. The
+    /// signatures themselves must still be valid.
+    #[arg(long)]
+    pub ignore_cert_mismatch: bool,
+
+    /// Don't fail if the property files in the OTA metadata are incorrect.
+    #[arg(long)]
+    pub ignore_property_files: bool,
+
+    /// Don't fail if vendor_boot's kernel modules do not match the GKI KMI.
+    #[arg(long)]
+    pub ignore_kmi_mismatch: bool,
+
+    /// Write a JSON report of every check to this file.
+    ///
+    /// The report is written even if verification fails. Each check has a
+    /// status of "passed", "failed", or "failed-but-ignored". Failed checks
+    /// include the expected and actual values if the failure was a mismatch.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub report: Option<PathBuf>,
+
+    /// Directory for temporary files.
+    ///
+    /// The default is the system temporary directory.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// Sideload an OTA to a device in recovery's sideload mode.
+///
+/// This speaks the ADB protocol directly, so the platform tools are not
+/// needed. Only TCP connections to adbd are supported. The command only
+/// succeeds if the device reports that it installed the package.
+#[derive(Debug, Parser)]
+pub struct SideloadCli {
+    /// Path to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Address of the device in the form <host>[:<port>].
+    ///
+    /// The default port is 5555.
+    #[arg(short, long, value_name = "ADDRESS")]
+    pub device: String,
+
+    /// ADB private key for authenticating with the device.
+    ///
+    /// The default is ~/.android/adbkey if it exists. Sideload mode normally
+    /// does not require authentication.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub adb_key: Option<PathBuf>,
+}
+
+/// Summarize whether an OTA can be patched.
+///
+/// This reports the OTA type, partitions, boot image header versions, the
+/// partitions that contain otacerts.zip and the recovery ramdisk, the partition
+/// used for each image type, AVB layout, and signature algorithm, followed by a
+/// verdict. If the OTA can only be patched with additional options, those
+/// options are listed.
+#[derive(Debug, Parser)]
+pub struct InspectCli {
+    /// Path to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Boot partition name.
+    #[arg(long, value_name = "PARTITION", default_value = "@gki_ramdisk")]
+    pub boot_partition: String,
+
+    /// Print the report as JSON.
+    #[arg(long)]
+    pub json: bool,
+
+    /// List the install operations of a partition instead of the report.
+    ///
+    /// Each operation is shown with its type, the location of its data in the
+    /// payload, and its source and destination extents. This is useful for
+    /// understanding what an incremental OTA does to a partition.
+    #[arg(long, value_name = "PARTITION")]
+    pub operations: Option<String>,
+}
+
+/// Export a JSON descriptor for hosting an OTA on a static file server.
+///
+/// The descriptor contains what updater apps need to check for and stream the
+/// update: the build fingerprint and timestamp, the size and SHA-256 digest of
+/// the zip, the download URL, and the streaming property files. It is derived
+/// entirely from the OTA zip, which is checked to have consistent property
+/// files first.
+#[derive(Debug, Parser)]
+pub struct ExportUpdateDescriptorCli {
+    /// Path to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Base URL of the directory where the OTA is hosted.
+    ///
+    /// The download URL is this followed by the file name of the OTA zip.
+    #[arg(long, value_name = "URL")]
+    pub url_base: String,
+
+    /// Release channel to include in the descriptor.
+    #[arg(long, value_name = "NAME")]
+    pub channel: Option<String>,
+
+    /// Path to output JSON file.
+    ///
+    /// The default is to print the descriptor to stdout.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: Option<PathBuf>,
+}
+
+/// Export the digest for signing an OTA zip on another machine.
+///
+/// Only the whole-file signature of the zip is handled. The payload and vbmeta
+/// signatures and the ramdisk's otacerts.zip are all produced while patching,
+/// so `ota patch` still needs every key, including --key-ota. Recovery only
+/// accepts the externally signed OTA if the certificate is in otacerts.zip.
+///
+/// The raw SHA-256 digest is written to the output file. For PKCS#1 v1.5
+/// padding, it can be signed with:
+///
+/// openssl pkeyutl -sign -inkey <key> -pkeyopt digest:sha256 -in <digest>
+/// -out <signature>
+#[derive(Debug, Parser)]
+pub struct SignRequestCli {
+    /// Path to OTA zip.
+    ///
+    /// The zip can be unsigned, with an empty archive comment, or already
+    /// signed, in which case the existing signature is replaced.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Path to output digest file.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
+}
+
+/// Insert an externally produced whole-file signature into an OTA zip.
+///
+/// The signature is a raw RSA signature of the digest from `ota sign-request`.
+/// It is verified against the certificate before the output is written. Like
+/// `ota sign-request`, this does not touch the payload or vbmeta signatures.
+#[derive(Debug, Parser)]
+pub struct SignFinalizeCli {
+    /// Path to OTA zip.
+    ///
+    /// This must be the same file that was passed to `ota sign-request`.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Path to raw RSA signature of the digest.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub signature: PathBuf,
+
+    /// Certificate for the key that produced the signature.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub cert_ota: PathBuf,
+
+    /// The signature uses PSS padding instead of PKCS#1 v1.5.
+    ///
+    /// AOSP recovery rejects PSS signatures.
+    #[arg(long)]
+    pub pss: bool,
+
+    /// Path to output OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -1362,9 +5652,15 @@ enum OtaCommand {
     Patch(PatchCli),
     Extract(ExtractCli),
     Verify(VerifyCli),
+    Inspect(InspectCli),
+    Sideload(SideloadCli),
+    ExportUpdateDescriptor(ExportUpdateDescriptorCli),
+    SignRequest(SignRequestCli),
+    SignFinalize(SignFinalizeCli),
 }
 
-/// Patch or extract OTA images.
+/// Patch, extract, verify, inspect, or sideload OTA images, export update
+/// descriptors for them, or sign them on another machine.
 #[derive(Debug, Parser)]
 pub struct OtaCli {
     #[command(subcommand)]
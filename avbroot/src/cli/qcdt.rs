@@ -0,0 +1,254 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    format::qcdt::QcdtImage,
+    stream::{FromReader, ToWriter},
+};
+
+fn read_image(path: &Path) -> Result<QcdtImage> {
+    let file = File::open(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let image = QcdtImage::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to read QCDT image: {path:?}"))?;
+
+    Ok(image)
+}
+
+fn write_image(path: &Path, image: &QcdtImage) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
+    let mut writer = BufWriter::new(file);
+    image
+        .to_writer(&mut writer)
+        .with_context(|| format!("Failed to write QCDT image: {path:?}"))?;
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush: {path:?}"))?;
+
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<QcdtImage> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest TOML: {path:?}"))?;
+    let image = toml_edit::de::from_str(&data)
+        .with_context(|| format!("Failed to parse manifest TOML: {path:?}"))?;
+
+    Ok(image)
+}
+
+fn write_manifest(path: &Path, image: &QcdtImage) -> Result<()> {
+    let data = toml_edit::ser::to_string_pretty(image)
+        .with_context(|| format!("Failed to serialize manifest TOML: {path:?}"))?;
+    fs::write(path, data).with_context(|| format!("Failed to write manifest TOML: {path:?}"))?;
+
+    Ok(())
+}
+
+fn entry_path(prefix: &Path, index: usize) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(format!("{index}.dtb"));
+    path.into()
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal ID.
+fn parse_id(s: &str) -> Result<u32> {
+    let id = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    id.with_context(|| format!("Invalid ID: {s:?}"))
+}
+
+/// Parse an entry replacement in the form `<platform_id>:<variant_id>=<file>`.
+fn parse_entry(s: &str) -> Result<(u32, u32, PathBuf)> {
+    let (ids, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <platform_id>:<variant_id>=<file>"))?;
+    let (platform_id, variant_id) = ids
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected <platform_id>:<variant_id>"))?;
+
+    Ok((
+        parse_id(platform_id)?,
+        parse_id(variant_id)?,
+        PathBuf::from(path),
+    ))
+}
+
+fn display_info(cli: &QcdtCli, image: &QcdtImage) {
+    if !cli.quiet {
+        if cli.debug {
+            println!("{image:#?}");
+        } else {
+            print!("{image}");
+        }
+    }
+}
+
+fn unpack_subcommand(qcdt_cli: &QcdtCli, cli: &UnpackCli) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    display_info(qcdt_cli, &image);
+
+    write_manifest(&cli.output_manifest, &image)?;
+
+    for (i, entry) in image.entries.iter().enumerate() {
+        let path = entry_path(&cli.output_entry_prefix, i);
+        fs::write(&path, &entry.data)
+            .with_context(|| format!("Failed to write device tree: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn pack_subcommand(qcdt_cli: &QcdtCli, cli: &PackCli) -> Result<()> {
+    let mut image = read_manifest(&cli.input_manifest)?;
+
+    for (i, entry) in image.entries.iter_mut().enumerate() {
+        let path = entry_path(&cli.input_entry_prefix, i);
+        entry.data =
+            fs::read(&path).with_context(|| format!("Failed to read device tree: {path:?}"))?;
+    }
+
+    display_info(qcdt_cli, &image);
+    write_image(&cli.output, &image)?;
+
+    Ok(())
+}
+
+fn replace_subcommand(qcdt_cli: &QcdtCli, cli: &ReplaceCli) -> Result<()> {
+    let mut image = read_image(&cli.input)?;
+
+    for (platform_id, variant_id, path) in &cli.entry {
+        let data =
+            fs::read(path).with_context(|| format!("Failed to read device tree: {path:?}"))?;
+        image
+            .replace_entries(*platform_id, *variant_id, &data)
+            .with_context(|| format!("Failed to replace entry with {path:?}"))?;
+    }
+
+    display_info(qcdt_cli, &image);
+    write_image(&cli.output, &image)?;
+
+    Ok(())
+}
+
+fn info_subcommand(qcdt_cli: &QcdtCli, cli: &InfoCli) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    display_info(qcdt_cli, &image);
+
+    Ok(())
+}
+
+pub fn qcdt_main(cli: &QcdtCli) -> Result<()> {
+    match &cli.command {
+        QcdtCommand::Unpack(c) => unpack_subcommand(cli, c),
+        QcdtCommand::Pack(c) => pack_subcommand(cli, c),
+        QcdtCommand::Replace(c) => replace_subcommand(cli, c),
+        QcdtCommand::Info(c) => info_subcommand(cli, c),
+    }
+}
+
+/// Unpack a QCDT image.
+///
+/// Each device tree is written to <prefix><index>.dtb. The remaining fields
+/// are written to the manifest TOML. Entries that share a device tree in the
+/// image are written to separate files.
+#[derive(Debug, Parser)]
+struct UnpackCli {
+    /// Path to input QCDT image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output manifest TOML.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "qcdt.toml")]
+    output_manifest: PathBuf,
+
+    /// Path prefix for output device tree blobs.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "entry.")]
+    output_entry_prefix: PathBuf,
+}
+
+/// Pack a QCDT image.
+///
+/// The entries listed in the manifest TOML are packed in order. Identical
+/// device trees are only stored once and the offsets are recomputed.
+#[derive(Debug, Parser)]
+struct PackCli {
+    /// Path to output QCDT image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Path to input manifest TOML.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "qcdt.toml")]
+    input_manifest: PathBuf,
+
+    /// Path prefix for input device tree blobs.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "entry.")]
+    input_entry_prefix: PathBuf,
+}
+
+/// Replace device trees in a QCDT image.
+///
+/// Every entry with the specified platform and variant IDs is updated. All
+/// other entries and fields are preserved, but the offsets are recomputed.
+#[derive(Debug, Parser)]
+struct ReplaceCli {
+    /// Path to input QCDT image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output QCDT image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Device tree to replace, as <platform_id>:<variant_id>=<file>.
+    ///
+    /// The IDs can be decimal or 0x-prefixed hexadecimal. This can be specified
+    /// multiple times.
+    #[arg(long, value_name = "ENTRY", value_parser = parse_entry, required = true)]
+    entry: Vec<(u32, u32, PathBuf)>,
+}
+
+/// Display QCDT image information.
+#[derive(Debug, Parser)]
+struct InfoCli {
+    /// Path to input QCDT image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+enum QcdtCommand {
+    Unpack(UnpackCli),
+    Pack(PackCli),
+    Replace(ReplaceCli),
+    Info(InfoCli),
+}
+
+/// Pack, unpack, or edit legacy Qualcomm device tree table (QCDT) images.
+#[derive(Debug, Parser)]
+pub struct QcdtCli {
+    #[command(subcommand)]
+    command: QcdtCommand,
+
+    /// Don't print QCDT image information.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Print QCDT image information in debug format.
+    #[arg(short, long, global = true)]
+    debug: bool,
+}
@@ -5,6 +5,7 @@
 
 use std::{
     fs::File,
+    io::{BufReader, Write},
     path::{Path, PathBuf},
     str,
 };
@@ -14,7 +15,7 @@ use clap::{Parser, Subcommand};
 
 use crate::{
     format::{
-        compression::{CompressedFormat, CompressedReader, CompressedWriter},
+        compression::{self, CompressedFormat, CompressedReader, FILE_BUFFER_SIZE},
         cpio::{self, CpioEntryNew},
     },
     util::EscapedString,
@@ -72,7 +73,7 @@ fn load_archive(
     include_trailer: bool,
 ) -> Result<(Vec<CpioEntryNew>, CompressedFormat)> {
     let file = File::open(path)?;
-    let reader = CompressedReader::new(file, true)?;
+    let reader = CompressedReader::new(BufReader::with_capacity(FILE_BUFFER_SIZE, file), true)?;
     let format = reader.format();
     let entries = cpio::load(reader, include_trailer)?;
 
@@ -80,10 +81,9 @@ fn load_archive(
 }
 
 fn save_archive(path: &Path, entries: &[CpioEntryNew], format: CompressedFormat) -> Result<()> {
-    let file = File::create(path)?;
-    let mut writer = CompressedWriter::new(file, format)?;
+    let mut writer = compression::create_standalone(path, format)?;
     cpio::save(&mut writer, entries, false)?;
-    writer.finish()?;
+    writer.finish()?.flush()?;
 
     Ok(())
 }
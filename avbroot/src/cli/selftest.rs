@@ -0,0 +1,300 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Read, Write},
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use rsa::RsaPrivateKey;
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    cli::{status, warning},
+    crypto::{self, PassphraseSource},
+    format::{
+        avb::AlgorithmType,
+        compression::{CompressedFormat, CompressedReader, CompressedWriter},
+        cpio::{self, CpioEntryNew},
+        ota::{self, SigningWriter},
+    },
+    protobuf::chromeos_update_engine::{
+        mod_InstallOperation::Type, DeltaArchiveManifest, Extent, InstallOperation, PartitionInfo,
+        PartitionUpdate,
+    },
+    util,
+};
+
+// These keys are only used for the known-answer tests and are intentionally
+// public. They must never be used for signing anything real.
+const KEY_2048: &str = include_str!("selftest/key_2048.pem");
+pub(crate) const KEY_4096: &str = include_str!("selftest/key_4096.pem");
+const CERT_2048: &str = include_str!("selftest/cert_2048.crt");
+
+const MESSAGE: &[u8] = b"avbroot self-test";
+
+type TestFn = fn(&Arc<AtomicBool>) -> Result<()>;
+
+/// The subsystems covered by the self-test, in the order they are run.
+pub const SUBSYSTEMS: &[(&str, TestFn)] = &[
+    ("AVB signatures", test_avb_signatures),
+    ("cpio", test_cpio),
+    ("payload manifest", test_payload_manifest),
+    ("compression", test_compression),
+    ("OTA signing", test_ota_signing),
+];
+
+fn load_key(data: &str) -> Result<RsaPrivateKey> {
+    // The bundled keys are unencrypted, so the passphrase is never prompted.
+    crypto::read_pem_key(data.as_bytes(), &PassphraseSource::Prompt(String::new()))
+        .context("Failed to load bundled key")
+}
+
+fn load_cert() -> Result<Certificate> {
+    crypto::read_pem_cert(CERT_2048.as_bytes()).context("Failed to load bundled certificate")
+}
+
+fn check_sha256(name: &str, data: &[u8], expected: &str) -> Result<()> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    let actual = hex::encode(digest);
+
+    if actual != expected {
+        bail!("{name}: expected SHA-256 {expected}, but got {actual}");
+    }
+
+    Ok(())
+}
+
+fn test_avb_signatures(_cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let key_2048 = load_key(KEY_2048)?;
+    let key_4096 = load_key(KEY_4096)?;
+
+    // Digests of the expected signatures. PKCS#1 v1.5 signatures are
+    // deterministic, so these must match exactly.
+    let vectors = [
+        (
+            AlgorithmType::Sha256Rsa2048,
+            &key_2048,
+            "8c0f5b71b4ccdb7f946fe9750ab230fdd28ada48e0ed4b7777304996273e9174",
+        ),
+        (
+            AlgorithmType::Sha512Rsa2048,
+            &key_2048,
+            "623757d4ae88df8c688e8f677bcccd64ef75e3ff7897b86055c10bcb31bbb531",
+        ),
+        (
+            AlgorithmType::Sha256Rsa4096,
+            &key_4096,
+            "9fbf695d318f144b3d9414f2692af8a7b06625b8450d8f54ac8591ff16a1c22d",
+        ),
+        (
+            AlgorithmType::Sha512Rsa4096,
+            &key_4096,
+            "c25298e5cf4818319f4310014048979ac65fa0b9fc63645681c2b3dae2ec8a49",
+        ),
+    ];
+
+    for (algorithm, key, expected) in vectors {
+        let public_key = key.to_public_key();
+        let digest = algorithm.hash(MESSAGE);
+
+        let signature = algorithm
+            .sign(key, &digest)
+            .with_context(|| format!("Failed to sign with {algorithm:?}"))?;
+        check_sha256(&format!("{algorithm:?} signature"), &signature, expected)?;
+
+        algorithm
+            .verify(&public_key, &digest, &signature)
+            .with_context(|| format!("Failed to verify {algorithm:?} signature"))?;
+
+        let mut bad_digest = digest.clone();
+        bad_digest[0] ^= 0xff;
+
+        if algorithm
+            .verify(&public_key, &bad_digest, &signature)
+            .is_ok()
+        {
+            bail!("{algorithm:?} signature verified against the wrong digest");
+        }
+    }
+
+    Ok(())
+}
+
+fn test_cpio(_cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let mut init = CpioEntryNew::new_file(b"init");
+    init.mode |= 0o750;
+    init.content = b"#!/system/bin/sh\n".to_vec();
+
+    let mut system = CpioEntryNew::new_directory(b"system");
+    system.mode |= 0o755;
+
+    let bin = CpioEntryNew::new_symlink(b"/system/bin", b"system/bin");
+
+    let mut file = CpioEntryNew::new_file(b"system/file");
+    file.mode |= 0o644;
+    file.content = b"avbroot\n".to_vec();
+
+    // Intentionally out of order to exercise sorting.
+    let mut entries = vec![file, bin, system, init];
+    cpio::sort(&mut entries);
+    cpio::reassign_inodes(&mut entries);
+
+    let mut data = vec![];
+    cpio::save(&mut data, &entries, false).context("Failed to write cpio archive")?;
+    check_sha256(
+        "cpio archive",
+        &data,
+        "3af488a6e9ab4950b020dbbebb7d4b72439db92e9daf645f2e37366b29995413",
+    )?;
+
+    let loaded = cpio::load(Cursor::new(&data), false).context("Failed to read cpio archive")?;
+    ensure!(loaded == entries, "cpio archive did not round trip");
+
+    Ok(())
+}
+
+fn test_payload_manifest(_cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let manifest = DeltaArchiveManifest {
+        block_size: 512,
+        minor_version: 8,
+        partitions: vec![PartitionUpdate {
+            partition_name: "system".to_owned(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(8192),
+                hash: Some((0..32).collect()),
+            }),
+            operations: vec![InstallOperation {
+                type_pb: Type::ZERO,
+                dst_extents: vec![Extent {
+                    start_block: Some(0),
+                    num_blocks: Some(2),
+                }],
+                ..Default::default()
+            }],
+            // The proto default. This is never serialized.
+            fec_roots: 2,
+            ..Default::default()
+        }],
+        max_timestamp: Some(1234),
+        ..Default::default()
+    };
+
+    let data = util::write_protobuf(&manifest).context("Failed to write payload manifest")?;
+    check_sha256(
+        "payload manifest",
+        &data,
+        "d23be64f8929a153c537ecf9a461e63a86ea9c5a43a1d253b013edde98a6c7b2",
+    )?;
+
+    let loaded = util::read_protobuf::<DeltaArchiveManifest>(&data)
+        .context("Failed to read payload manifest")?;
+    ensure!(loaded == manifest, "Payload manifest did not round trip");
+
+    Ok(())
+}
+
+fn test_compression(_cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let data = MESSAGE.repeat(4096);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format)
+            .with_context(|| format!("Failed to create {format:?} compressor"))?;
+        writer
+            .write_all(&data)
+            .with_context(|| format!("Failed to compress {format:?} data"))?;
+        let compressed = writer
+            .finish()
+            .with_context(|| format!("Failed to finalize {format:?} data"))?
+            .into_inner();
+
+        let mut reader = CompressedReader::new(Cursor::new(&compressed), true)
+            .with_context(|| format!("Failed to detect {format:?} data"))?;
+        ensure!(
+            reader.format() == format,
+            "{format:?} data was detected as {:?}",
+            reader.format(),
+        );
+
+        let mut decompressed = vec![];
+        reader
+            .read_to_end(&mut decompressed)
+            .with_context(|| format!("Failed to decompress {format:?} data"))?;
+        ensure!(decompressed == data, "{format:?} data did not round trip");
+    }
+
+    Ok(())
+}
+
+fn test_ota_signing(cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let key = load_key(KEY_2048)?;
+    let cert = load_cert()?;
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(Cursor::new(Vec::new())));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip_writer.start_file("self-test.txt", options)?;
+    zip_writer.write_all(MESSAGE)?;
+
+    let signing_writer = zip_writer.finish().context("Failed to finalize zip")?;
+    let data = signing_writer
+        .finish(&key, &cert)
+        .context("Failed to sign zip")?
+        .into_inner();
+
+    let embedded_cert =
+        ota::verify_ota(Cursor::new(&data), cancel_signal).context("Failed to verify zip")?;
+    ensure!(embedded_cert == cert, "Embedded certificate does not match");
+
+    let offset = memchr::memmem::find(&data, MESSAGE).context("Zip entry data not found")?;
+    let mut bad_data = data.clone();
+    bad_data[offset] ^= 0xff;
+
+    if ota::verify_ota(Cursor::new(&bad_data), cancel_signal).is_ok() {
+        bail!("Modified zip passed signature verification");
+    }
+
+    Ok(())
+}
+
+pub fn selftest_main(_cli: &SelfTestCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let mut failed = vec![];
+
+    for (name, test) in SUBSYSTEMS {
+        let start = Instant::now();
+
+        match test(cancel_signal) {
+            Ok(()) => status!("{name}: passed in {:?}", start.elapsed()),
+            Err(e) => {
+                warning!("{name}: failed: {e:?}");
+                failed.push(*name);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Self-test failed for: {}", failed.join(", "));
+    }
+
+    status!("All self-tests passed");
+
+    Ok(())
+}
+
+/// Run built-in known-answer tests.
+///
+/// This checks that the cryptographic, archive, and serialization code
+/// produces the expected output on this platform. Each subsystem that fails is
+/// reported by name.
+#[derive(Debug, Parser)]
+pub struct SelfTestCli {}
@@ -0,0 +1,228 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Placement and space accounting for temporary files.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, Seek},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::{
+    cli::status,
+    warning::{Severity, WarningCode, WarningCollector},
+};
+
+pub(crate) fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    let stat = rustix::fs::statvfs(path)?;
+
+    Ok(Some(stat.f_bavail.saturating_mul(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Warn if the filesystem containing `dir` has less than `required` bytes
+/// available. This is not an error because the free space reported by some
+/// filesystems, like tmpfs or overlayfs in containers, is not reliable. This
+/// does nothing on platforms where the free space cannot be queried.
+pub fn check_available_space(dir: &Path, required: u64, warnings: &WarningCollector) {
+    let message = match available_space(dir) {
+        Ok(Some(available)) if available < required => format!(
+            "{dir:?} may not have enough space: {} needed, but only {} available",
+            format_size(required),
+            format_size(available),
+        ),
+        Ok(_) => return,
+        Err(e) => format!("Failed to query available space: {dir:?}: {e}"),
+    };
+
+    warnings.emit(WarningCode::LowFreeSpace, Severity::Medium, message);
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Move a finished temporary file to `path`. If the file cannot be renamed,
+/// eg. because the temporary directory is on a different filesystem, then it is
+/// copied to a new temporary file next to `path`, which is then renamed. On
+/// Linux, the copy uses `copy_file_range()`, which makes reflink copies on
+/// filesystems that support them, like btrfs.
+pub fn persist(file: NamedTempFile, path: &Path) -> Result<()> {
+    let mut file = match file.persist(path) {
+        Ok(_) => return Ok(()),
+        Err(e) => e.file,
+    };
+
+    let parent = match path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    let prefix = path
+        .file_name()
+        .unwrap_or_else(|| OsStr::new("avbroot.tmp"));
+    let mut copy = NamedTempFile::with_prefix_in(prefix, parent)
+        .with_context(|| format!("Failed to create temporary file in {parent:?}"))?;
+
+    let permissions = file.as_file().metadata()?.permissions();
+    copy.as_file()
+        .set_permissions(permissions)
+        .with_context(|| format!("Failed to set permissions: {:?}", copy.path()))?;
+
+    file.rewind()?;
+    io::copy(file.as_file_mut(), copy.as_file_mut())
+        .with_context(|| format!("Failed to copy {:?} to {:?}", file.path(), copy.path()))?;
+
+    let copy_path = copy.path().to_owned();
+    copy.persist(path)
+        .with_context(|| format!("Failed to move {copy_path:?} to {path:?}"))?;
+
+    Ok(())
+}
+
+/// Where temporary files are stored and how much space they used. Each stage
+/// of an operation that needs temporary files gets its own subdirectory of the
+/// base directory via [`Self::stage()`].
+pub struct TempPolicy {
+    base_dir: PathBuf,
+    peak_sizes: Mutex<Vec<(&'static str, u64)>>,
+}
+
+impl TempPolicy {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+            peak_sizes: Mutex::default(),
+        }
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Warn if the base directory does not have `required` bytes available.
+    pub fn check_space(&self, required: u64, warnings: &WarningCollector) {
+        check_available_space(&self.base_dir, required, warnings);
+    }
+
+    /// Create the directory for a stage's temporary files. The directory and
+    /// its contents are deleted when the [`TempStage`] is dropped.
+    pub fn stage(&self, name: &'static str) -> Result<TempStage<'_>> {
+        let dir = tempfile::Builder::new()
+            .prefix(&format!(".avbroot-{}-", name.replace(' ', "_")))
+            .tempdir_in(&self.base_dir)
+            .with_context(|| {
+                format!(
+                    "Failed to create temporary directory in {:?}",
+                    self.base_dir
+                )
+            })?;
+
+        Ok(TempStage {
+            policy: self,
+            name,
+            dir,
+            peak_size: 0,
+        })
+    }
+
+    /// Get the peak size of each stage that has completed, in the order that
+    /// they completed.
+    pub fn peak_sizes(&self) -> Vec<(&'static str, u64)> {
+        self.peak_sizes.lock().unwrap().clone()
+    }
+
+    /// Print the peak size of each completed stage.
+    pub fn report(&self) {
+        for (name, size) in self.peak_sizes() {
+            status!("Temporary space used by {name}: {}", format_size(size));
+        }
+    }
+}
+
+/// A temporary directory for a single stage. The peak size is recorded in the
+/// parent [`TempPolicy`] when this is dropped.
+pub struct TempStage<'a> {
+    policy: &'a TempPolicy,
+    name: &'static str,
+    dir: TempDir,
+    peak_size: u64,
+}
+
+impl TempStage<'_> {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Create a named temporary file in the stage's directory.
+    pub fn named_file(&self, prefix: &OsStr) -> Result<NamedTempFile> {
+        NamedTempFile::with_prefix_in(prefix, self.dir.path())
+            .with_context(|| format!("Failed to create temporary file in {:?}", self.dir.path()))
+    }
+
+    /// Measure the current size of the stage's files and update the peak size.
+    /// This must be called before files are moved out of the directory.
+    pub fn update_peak_size(&mut self) -> Result<u64> {
+        let size = dir_size(self.dir.path())
+            .with_context(|| format!("Failed to compute size of {:?}", self.dir.path()))?;
+        self.peak_size = self.peak_size.max(size);
+
+        Ok(size)
+    }
+}
+
+impl Drop for TempStage<'_> {
+    fn drop(&mut self) {
+        // The stage may have been interrupted, so the size is best effort.
+        let _ = self.update_peak_size();
+
+        self.policy
+            .peak_sizes
+            .lock()
+            .unwrap()
+            .push((self.name, self.peak_size));
+    }
+}
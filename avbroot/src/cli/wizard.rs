@@ -0,0 +1,693 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use rsa::RsaPrivateKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adb,
+    boot::{MagiskOptions, MagiskRootPatcher},
+    cli::{key, ota, status, warning},
+    crypto::{self, PassphraseSource},
+    format::{
+        bootimage::BootImage,
+        ota::{self as ota_format, CompatibilityResult},
+    },
+    protobuf::build::tools::releasetools::OtaMetadata,
+    stream::FromReader,
+    warning::WarningCollector,
+};
+
+/// Environment variables used to pass passphrases that were entered in the
+/// wizard to the commands that it runs.
+const ENV_PASS_AVB: &str = "AVBROOT_PASS_AVB";
+const ENV_PASS_OTA: &str = "AVBROOT_PASS_OTA";
+
+const DEFAULT_CONFIG: &str = "avbroot-wizard.toml";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootMode {
+    #[default]
+    Magisk,
+    Prepatched,
+    Rootless,
+}
+
+/// Answers from a previous run of the wizard. These are used as the defaults
+/// for the prompts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WizardConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_avb: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_ota: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert_ota: Option<PathBuf>,
+    #[serde(default)]
+    pub root: RootMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub magisk: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub magisk_preinit_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prepatched: Option<PathBuf>,
+}
+
+pub fn read_config(path: &Path) -> Result<WizardConfig> {
+    let data =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config: {path:?}"))?;
+    let config = toml_edit::de::from_str(&data)
+        .with_context(|| format!("Failed to parse config: {path:?}"))?;
+
+    Ok(config)
+}
+
+pub fn write_config(path: &Path, config: &WizardConfig) -> Result<()> {
+    let data = toml_edit::ser::to_string_pretty(config)
+        .with_context(|| format!("Failed to serialize config: {path:?}"))?;
+    fs::write(path, data).with_context(|| format!("Failed to write config: {path:?}"))?;
+
+    Ok(())
+}
+
+/// Print a prompt and read one line from stdin. An empty answer selects the
+/// default, if there is one.
+fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    {
+        let mut stdout = io::stdout().lock();
+        match default {
+            Some(d) if !d.is_empty() => write!(stdout, "{question} [{d}]: ")?,
+            _ => write!(stdout, "{question}: ")?,
+        }
+        stdout.flush()?;
+    }
+
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        bail!("Unexpected end of input");
+    }
+
+    let answer = line.trim();
+
+    match default {
+        Some(d) if answer.is_empty() => Ok(d.to_owned()),
+        _ => Ok(answer.to_owned()),
+    }
+}
+
+/// Keep prompting until `validate` accepts the answer. Validation errors are
+/// shown inline instead of aborting the wizard.
+fn prompt_until<T>(
+    question: &str,
+    default: Option<&str>,
+    mut validate: impl FnMut(&str) -> Result<T>,
+) -> Result<T> {
+    loop {
+        let answer = prompt(question, default)?;
+
+        match validate(&answer) {
+            Ok(v) => return Ok(v),
+            Err(e) => warning!("{e:#}"),
+        }
+    }
+}
+
+fn prompt_choice(question: &str, choices: &[&str], default: usize) -> Result<usize> {
+    println!("{question}");
+    for (i, choice) in choices.iter().enumerate() {
+        println!("  {}) {choice}", i + 1);
+    }
+
+    prompt_until("Choice", Some((default + 1).to_string().as_str()), |a| {
+        a.parse::<usize>()
+            .ok()
+            .filter(|n| (1..=choices.len()).contains(n))
+            .map(|n| n - 1)
+            .ok_or_else(|| anyhow!("Enter a number between 1 and {}", choices.len()))
+    })
+}
+
+pub fn prompt_yes_no(question: &str, default: bool) -> Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+
+    prompt_until(&format!("{question} [{choices}]"), None, |a| {
+        match a.to_ascii_lowercase().as_str() {
+            "" => Ok(default),
+            "y" | "yes" => Ok(true),
+            "n" | "no" => Ok(false),
+            _ => Err(anyhow!("Enter yes or no")),
+        }
+    })
+}
+
+fn path_default(path: Option<&Path>) -> Option<String> {
+    path.map(|p| p.to_string_lossy().into_owned())
+}
+
+fn existing_file(answer: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(answer);
+    if answer.is_empty() || !path.is_file() {
+        bail!("File does not exist: {path:?}");
+    }
+
+    Ok(path)
+}
+
+fn new_file(answer: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(answer);
+    if answer.is_empty() {
+        bail!("A path is required");
+    } else if path.exists() {
+        bail!("File already exists: {path:?}");
+    }
+
+    Ok(path)
+}
+
+/// Open the OTA zip and show which device it is for. This fails if the file is
+/// not a valid full OTA.
+pub fn validate_ota(path: &Path) -> Result<OtaMetadata> {
+    let reader = File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let (metadata, _, header, _) = ota_format::parse_zip_ota_info(reader)
+        .with_context(|| format!("Not a valid OTA zip: {path:?}"))?;
+
+    if !header.is_full_ota() {
+        bail!("Only full OTAs are supported: {path:?}");
+    }
+
+    let devices = metadata
+        .precondition
+        .as_ref()
+        .map(|p| p.device.join(", "))
+        .unwrap_or_default();
+    let build = metadata
+        .postcondition
+        .as_ref()
+        .and_then(|p| p.build.first().cloned())
+        .unwrap_or_default();
+
+    status!("Found OTA for device: {devices} ({build})");
+
+    Ok(metadata)
+}
+
+/// Check that the OTA can be installed on `device` (`ro.product.device`)
+/// running the build `fingerprint` (`ro.build.fingerprint`), like recovery
+/// does before installing it.
+pub fn check_device(metadata: &OtaMetadata, device: &str, fingerprint: Option<&str>) -> Result<()> {
+    match ota_format::check_metadata_compatibility(metadata, device, fingerprint) {
+        CompatibilityResult::Compatible => Ok(()),
+        r => bail!("{r}"),
+    }
+}
+
+/// Query the device's codename and build fingerprint over ADB.
+fn detect_device(address: &str) -> Result<(String, String)> {
+    let mut conn = ota::connect_adb(address, None)?;
+    let mut getprop = |name: &str| -> Result<String> {
+        let output = adb::exec(&mut conn, &format!("getprop {name}"), &[])
+            .with_context(|| format!("Failed to query property on device: {name}"))?;
+
+        Ok(String::from_utf8_lossy(&output).trim().to_owned())
+    };
+
+    let device = getprop("ro.product.device")?;
+    let fingerprint = getprop("ro.build.fingerprint")?;
+
+    Ok((device, fingerprint))
+}
+
+/// Optionally check the OTA against a device connected over ADB. Connection
+/// failures are re-prompted. An error is only returned if the device was
+/// detected, but the OTA is not for that device.
+fn select_device(metadata: &OtaMetadata) -> Result<()> {
+    println!("To check that the OTA is for the right device, enter the address of a device");
+    println!("with ADB over the network enabled. Leave this empty to skip the check.");
+
+    loop {
+        let address = prompt("Device address", None)?;
+        if address.is_empty() {
+            return Ok(());
+        }
+
+        match detect_device(&address) {
+            Ok((device, fingerprint)) => {
+                check_device(metadata, &device, Some(&fingerprint))?;
+                status!("OTA is compatible with the device: {device}");
+                return Ok(());
+            }
+            Err(e) => warning!("{e:#}"),
+        }
+    }
+}
+
+/// Run a `key` subcommand exactly as if it was invoked from the command line.
+fn run_key_command(args: &[&OsStr]) -> Result<()> {
+    let args = [OsStr::new("key")].into_iter().chain(args.iter().copied());
+    let cli = key::KeyCli::try_parse_from(args)?;
+
+    key::key_main(&cli)
+}
+
+/// Load a private key, prompting for the passphrase until it is correct.
+/// Returns the key and whether it is encrypted. If so, the passphrase is stored
+/// in the `env_var` environment variable.
+fn load_key(path: &Path, env_var: &str) -> Result<(RsaPrivateKey, bool)> {
+    let source = PassphraseSource::EnvVar(env_var.into());
+
+    // Try without a passphrase first so that unencrypted keys never prompt.
+    env::remove_var(env_var);
+
+    match crypto::read_pem_key_file(path, &source) {
+        Ok(key) => return Ok((key, false)),
+        Err(crypto::Error::InvalidEnvVar(_, _)) => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to load key: {path:?}")),
+    }
+
+    loop {
+        let passphrase = rpassword::prompt_password(format!("Enter passphrase for {path:?}: "))?;
+        env::set_var(env_var, passphrase);
+
+        match crypto::read_pem_key_file(path, &source) {
+            Ok(key) => return Ok((key, true)),
+            Err(e) => warning!("Failed to decrypt key: {e}"),
+        }
+    }
+}
+
+/// Select an existing key or generate a new one with `avbroot key generate-key`.
+fn select_key(
+    description: &str,
+    default: Option<&Path>,
+    env_var: &str,
+) -> Result<(PathBuf, RsaPrivateKey, bool)> {
+    loop {
+        let choice = prompt_choice(
+            &format!("Private key for {description}:"),
+            &["Use an existing key", "Generate a new key"],
+            0,
+        )?;
+
+        let path = if choice == 0 {
+            prompt_until(
+                "Path to private key",
+                path_default(default).as_deref(),
+                existing_file,
+            )?
+        } else {
+            let path = prompt_until("Path to new private key", None, new_file)?;
+
+            println!("Leave the passphrase empty to store the key unencrypted.");
+            if let Err(e) = run_key_command(&[
+                OsStr::new("generate-key"),
+                OsStr::new("-o"),
+                path.as_os_str(),
+            ]) {
+                warning!("{e:#}");
+                continue;
+            }
+
+            path
+        };
+
+        match load_key(&path, env_var) {
+            Ok((key, encrypted)) => return Ok((path, key, encrypted)),
+            Err(e) => warning!("{e:#}"),
+        }
+    }
+}
+
+/// Select an existing certificate or generate a new one from the OTA key with
+/// `avbroot key generate-cert`. The certificate must match the key.
+fn select_cert(
+    default: Option<&Path>,
+    key_path: &Path,
+    key: &RsaPrivateKey,
+    encrypted: bool,
+) -> Result<PathBuf> {
+    loop {
+        let choice = prompt_choice(
+            "Certificate for OTA signing key:",
+            &["Use an existing certificate", "Generate a new certificate"],
+            0,
+        )?;
+
+        let path = if choice == 0 {
+            prompt_until(
+                "Path to certificate",
+                path_default(default).as_deref(),
+                existing_file,
+            )?
+        } else {
+            let path = prompt_until("Path to new certificate", None, new_file)?;
+
+            let mut args = vec![
+                OsStr::new("generate-cert"),
+                OsStr::new("-k"),
+                key_path.as_os_str(),
+                OsStr::new("-o"),
+                path.as_os_str(),
+            ];
+            if encrypted {
+                args.push(OsStr::new("--pass-env-var"));
+                args.push(OsStr::new(ENV_PASS_OTA));
+            }
+
+            if let Err(e) = run_key_command(&args) {
+                warning!("{e:#}");
+                continue;
+            }
+
+            path
+        };
+
+        let result = crypto::read_pem_cert_file(&path)
+            .with_context(|| format!("Failed to load certificate: {path:?}"))
+            .and_then(|cert| Ok(crypto::cert_matches_key(&cert, key)?));
+
+        match result {
+            Ok(true) => return Ok(path),
+            Ok(false) => {
+                warning!("Private key {key_path:?} does not match certificate {path:?}")
+            }
+            Err(e) => warning!("{e:#}"),
+        }
+    }
+}
+
+/// Prompt for the Magisk APK and preinit device. This performs the same
+/// validation as `avbroot ota patch`.
+fn select_magisk(
+    default_apk: Option<&Path>,
+    default_device: Option<&str>,
+) -> Result<(PathBuf, Option<String>)> {
+    loop {
+        let apk = prompt_until(
+            "Path to Magisk APK",
+            path_default(default_apk).as_deref(),
+            existing_file,
+        )?;
+
+        println!("See the \"Magisk preinit device\" section of the documentation for how to");
+        println!("find the preinit device. Leave this empty if the Magisk version does not");
+        println!("need one.");
+        let device = prompt("Magisk preinit device", default_device)?;
+        let device = (!device.is_empty()).then_some(device);
+
+        let warnings = WarningCollector::default();
+
+        match MagiskRootPatcher::new(
+            &apk,
+            device.as_deref(),
+            None,
+            MagiskOptions::default(),
+            false,
+            false,
+            &warnings,
+        ) {
+            Ok(_) => return Ok((apk, device)),
+            Err(e) => warning!(
+                "{:#}",
+                anyhow::Error::from(e).context("Magisk APK cannot be used"),
+            ),
+        }
+    }
+}
+
+fn select_prepatched(default: Option<&Path>) -> Result<PathBuf> {
+    prompt_until(
+        "Path to prepatched boot image",
+        path_default(default).as_deref(),
+        |a| {
+            let path = existing_file(a)?;
+            let reader = File::open(&path)
+                .map(BufReader::new)
+                .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+            BootImage::from_reader(reader)
+                .with_context(|| format!("Not a valid boot image: {path:?}"))?;
+
+            Ok(path)
+        },
+    )
+}
+
+/// Quote an argument so that it can be pasted into a POSIX shell.
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Format the `avbroot ota patch` command with `args` so that it can be pasted
+/// into a POSIX shell.
+pub fn format_command(args: &[OsString]) -> String {
+    ["avbroot", "ota", "patch"]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(args.iter().map(|a| shell_quote(a)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Get the default output path for `input`. A previous output path is only a
+/// sensible default for the same input.
+pub fn default_output(config: &WizardConfig, input: &Path) -> PathBuf {
+    config
+        .output
+        .clone()
+        .filter(|_| config.input.as_deref() == Some(input))
+        .unwrap_or_else(|| {
+            let mut s = input.as_os_str().to_owned();
+            s.push(".patched");
+            PathBuf::from(s)
+        })
+}
+
+/// Build the `avbroot ota patch` arguments for the answers in `config`. If a
+/// key is encrypted, its passphrase is read from [`ENV_PASS_AVB`] or
+/// [`ENV_PASS_OTA`].
+pub fn patch_args(
+    config: &WizardConfig,
+    key_avb_encrypted: bool,
+    key_ota_encrypted: bool,
+) -> Result<Vec<OsString>> {
+    let required = |value: &Option<PathBuf>, name: &str| {
+        value
+            .clone()
+            .map(OsString::from)
+            .ok_or_else(|| anyhow!("No answer for: {name}"))
+    };
+
+    let mut args = vec![
+        OsString::from("--input"),
+        required(&config.input, "input")?,
+        OsString::from("--output"),
+        required(&config.output, "output")?,
+        OsString::from("--key-avb"),
+        required(&config.key_avb, "key-avb")?,
+        OsString::from("--key-ota"),
+        required(&config.key_ota, "key-ota")?,
+        OsString::from("--cert-ota"),
+        required(&config.cert_ota, "cert-ota")?,
+    ];
+
+    match config.root {
+        RootMode::Magisk => {
+            args.push("--magisk".into());
+            args.push(required(&config.magisk, "magisk")?);
+            if let Some(d) = &config.magisk_preinit_device {
+                args.push("--magisk-preinit-device".into());
+                args.push(d.into());
+            }
+        }
+        RootMode::Prepatched => {
+            args.push("--prepatched".into());
+            args.push(required(&config.prepatched, "prepatched")?);
+        }
+        RootMode::Rootless => args.push("--rootless".into()),
+    }
+
+    if key_avb_encrypted {
+        args.push("--pass-avb-env-var".into());
+        args.push(ENV_PASS_AVB.into());
+    }
+    if key_ota_encrypted {
+        args.push("--pass-ota-env-var".into());
+        args.push(ENV_PASS_OTA.into());
+    }
+
+    Ok(args)
+}
+
+pub fn wizard_main(cli: &WizardCli, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+    let mut config = match &cli.config {
+        Some(p) if p.exists() => read_config(p)?,
+        _ => WizardConfig::default(),
+    };
+
+    status!("This wizard will patch an OTA. Press enter to accept the default in [].");
+
+    let input = loop {
+        let (input, metadata) = prompt_until(
+            "Path to original OTA zip",
+            path_default(config.input.as_deref()).as_deref(),
+            |a| {
+                let path = existing_file(a)?;
+                let metadata = validate_ota(&path)?;
+                Ok((path, metadata))
+            },
+        )?;
+
+        match select_device(&metadata) {
+            Ok(()) => break input,
+            Err(e) => warning!("{e:#}"),
+        }
+    };
+
+    let output = prompt_until(
+        "Path to new OTA zip",
+        path_default(Some(&default_output(&config, &input))).as_deref(),
+        |a| {
+            if Path::new(a) == input {
+                bail!("Output cannot be the same as the input");
+            }
+            Ok(PathBuf::from(a))
+        },
+    )?;
+
+    let (key_avb, key_avb_encrypted) = loop {
+        let (path, key, encrypted) = select_key(
+            "signing vbmeta images",
+            config.key_avb.as_deref(),
+            ENV_PASS_AVB,
+        )?;
+
+        match crypto::validate_avb_key(&key) {
+            Ok(_) => break (path, encrypted),
+            Err(e) => warning!("Key cannot be used for AVB: {path:?}: {e}"),
+        }
+    };
+
+    let (key_ota, key_ota_data, key_ota_encrypted) =
+        select_key("signing the OTA", config.key_ota.as_deref(), ENV_PASS_OTA)?;
+    let cert_ota = select_cert(
+        config.cert_ota.as_deref(),
+        &key_ota,
+        &key_ota_data,
+        key_ota_encrypted,
+    )?;
+
+    let root_modes = [RootMode::Magisk, RootMode::Prepatched, RootMode::Rootless];
+    let root_index = prompt_choice(
+        "Root method:",
+        &[
+            "Patch with Magisk",
+            "Use a prepatched boot image",
+            "Rootless (no root)",
+        ],
+        root_modes.iter().position(|m| *m == config.root).unwrap(),
+    )?;
+    let root = root_modes[root_index];
+
+    match root {
+        RootMode::Magisk => {
+            let (apk, device) = select_magisk(
+                config.magisk.as_deref(),
+                config.magisk_preinit_device.as_deref(),
+            )?;
+
+            config.magisk = Some(apk);
+            config.magisk_preinit_device = device;
+        }
+        RootMode::Prepatched => {
+            config.prepatched = Some(select_prepatched(config.prepatched.as_deref())?);
+        }
+        RootMode::Rootless => {}
+    }
+
+    config.input = Some(input);
+    config.output = Some(output);
+    config.key_avb = Some(key_avb);
+    config.key_ota = Some(key_ota);
+    config.cert_ota = Some(cert_ota);
+    config.root = root;
+
+    let args = patch_args(&config, key_avb_encrypted, key_ota_encrypted)?;
+
+    status!("Equivalent command:");
+    println!("{}", format_command(&args));
+    for (encrypted, var) in [
+        (key_avb_encrypted, ENV_PASS_AVB),
+        (key_ota_encrypted, ENV_PASS_OTA),
+    ] {
+        if encrypted {
+            println!("(The {var} environment variable must contain the key's passphrase.)");
+        }
+    }
+
+    let default_config = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+    if prompt_yes_no("Save answers for next time?", true)? {
+        let path = prompt(
+            "Path to config file",
+            path_default(Some(&default_config)).as_deref(),
+        )?;
+        write_config(Path::new(&path), &config)?;
+        status!(
+            "Run `avbroot wizard --config {}` to reuse these answers",
+            shell_quote(OsStr::new(&path)),
+        );
+    }
+
+    if !prompt_yes_no("Patch the OTA now?", true)? {
+        return Ok(());
+    }
+
+    let patch_cli =
+        ota::PatchCli::try_parse_from([OsString::from("patch")].into_iter().chain(args))?;
+
+    ota::patch_subcommand(&patch_cli, cancel_signal)
+}
+
+/// Interactively patch an OTA.
+///
+/// This walks through selecting the input OTA, optionally checking it against
+/// a device connected over ADB, the signing keys, and the root method, showing
+/// the equivalent `avbroot ota patch` command before running it. Invalid
+/// answers are re-prompted instead of aborting.
+#[derive(Debug, Parser)]
+pub struct WizardCli {
+    /// Config file with answers from a previous run.
+    ///
+    /// The answers are used as the defaults for each prompt.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub config: Option<PathBuf>,
+}
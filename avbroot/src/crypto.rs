@@ -6,12 +6,22 @@
 use std::{
     env::{self, VarError},
     ffi::OsString,
+    fmt,
+    io::{self, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "native")]
+use std::{
     fs::{self, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Write},
-    path::{Path, PathBuf},
-    time::Duration,
+    io::{BufReader, BufWriter},
+    path::PathBuf,
 };
 
+use aes::{Aes128, Aes192, Aes256};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use cms::{
     cert::{CertificateChoices, IssuerAndSerialNumber},
     content_info::{CmsVersion, ContentInfo},
@@ -20,24 +30,40 @@ use cms::{
         SignedData, SignerIdentifier, SignerInfo, SignerInfos,
     },
 };
+use const_oid::ObjectIdentifier;
+use md5::{Digest, Md5};
 use pkcs8::{
     pkcs5::{pbes2, scrypt},
     DecodePrivateKey, EncodePrivateKey, EncodePublicKey, EncryptedPrivateKeyInfo, LineEnding,
     PrivateKeyInfo,
 };
 use rand::RngCore;
-use rsa::{pkcs1v15::SigningKey, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, RsaPssParams},
+    pkcs1v15::SigningKey,
+    traits::{PublicKeyParts, SignatureScheme},
+    Pkcs1v15Sign, Pss, RsaPrivateKey, RsaPublicKey,
+};
 use sha2::Sha256;
 use thiserror::Error;
 use x509_cert::{
     builder::{Builder, CertificateBuilder, Profile},
-    der::{pem::PemLabel, referenced::OwnedToRef, Any, Decode, DecodePem, EncodePem},
+    der::{pem::PemLabel, referenced::OwnedToRef, Any, Decode, DecodePem, Encode, EncodePem},
+    ext::pkix::{KeyUsage, KeyUsages},
     serial_number::SerialNumber,
     spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
-    time::Validity,
+    time::{Time, Validity},
     Certificate,
 };
 
+use crate::{
+    format::{
+        avb::AlgorithmType,
+        pkcs12::{self, Pkcs12},
+    },
+    util,
+};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Passphrases do not match")]
@@ -50,10 +76,30 @@ pub enum Error {
     LoadKeyEncrypted(#[source] pkcs8::Error),
     #[error("Failed to load unencrypted private key")]
     LoadKeyUnencrypted(#[source] pkcs8::Error),
+    #[error("Failed to load legacy OpenSSL private key")]
+    LoadKeyLegacy(#[source] rsa::pkcs1::Error),
+    #[error("Failed to decrypt legacy OpenSSL private key (incorrect passphrase?)")]
+    DecryptKeyLegacy,
+    #[error("Invalid legacy OpenSSL PEM: {0}")]
+    InvalidLegacyPem(&'static str),
+    #[error("Unsupported legacy OpenSSL PEM cipher: {0:?}")]
+    UnsupportedLegacyCipher(String),
+    #[error("Unsupported private key format: {0:?}")]
+    UnsupportedKeyFormat(String),
+    #[error("Unsupported key size for AVB: {0} bits (must be 2048 or 4096 bits)")]
+    UnsupportedAvbKeySize(usize),
+    #[error("{0} signatures do not support {1} padding")]
+    UnsupportedPadding(SignatureFormat, RsaPadding),
+    #[error("Certificate validity period ends before it starts")]
+    InvalidValidity,
+    #[error("Unsigned mode requires AVBROOT_UNSAFE_NO_SIGN=1 to be set")]
+    UnsafeNoSignNotAllowed,
     #[error("Failed to save encrypted private key")]
     SaveKeyEncrypted(#[source] pkcs8::Error),
     #[error("Failed to save unencrypted private key")]
     SaveKeyUnencrypted(#[source] pkcs8::Error),
+    #[error("PKCS#12 error")]
+    Pkcs12(#[from] pkcs12::Error),
     #[error("X509 error")]
     X509(#[from] x509_cert::builder::Error),
     #[error("SPKI error")]
@@ -68,15 +114,63 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Environment variable that must be set to `1` for [`enable_unsafe_no_sign()`]
+/// to succeed. This makes it impossible to turn on unsigned mode with just a
+/// stray command-line flag.
+pub const UNSAFE_NO_SIGN_ENV: &str = "AVBROOT_UNSAFE_NO_SIGN";
+
+static UNSAFE_NO_SIGN: AtomicBool = AtomicBool::new(false);
+
+/// Enable unsigned mode for the rest of the process. All RSA signatures that
+/// avbroot produces for AVB, payloads, and OTA zips are replaced by zero-filled
+/// blobs of the same length. Every other byte of the output is the same as when
+/// signing normally, which is useful for comparing output against other tools
+/// and for fuzzing the writers without the cost of RSA. The output is invalid
+/// and will fail verification.
+pub fn enable_unsafe_no_sign() -> Result<()> {
+    if env::var_os(UNSAFE_NO_SIGN_ENV).as_deref() != Some("1".as_ref()) {
+        return Err(Error::UnsafeNoSignNotAllowed);
+    }
+
+    UNSAFE_NO_SIGN.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Check if unsigned mode was enabled with [`enable_unsafe_no_sign()`].
+pub fn is_unsafe_no_sign() -> bool {
+    UNSAFE_NO_SIGN.load(Ordering::SeqCst)
+}
+
+/// Sign `digest` with `key`. In unsigned mode, a zero-filled signature of the
+/// same length is returned instead.
+pub fn rsa_sign(
+    key: &RsaPrivateKey,
+    scheme: impl SignatureScheme,
+    digest: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    if is_unsafe_no_sign() {
+        return Ok(vec![0u8; key.size()]);
+    }
+
+    key.sign_with_rng(&mut rand::thread_rng(), scheme, digest)
+}
+
+/// Where to get a passphrase from. Prompting and reading from a file are only
+/// available with the `native` feature.
 pub enum PassphraseSource {
+    #[cfg(feature = "native")]
     Prompt(String),
     EnvVar(OsString),
+    #[cfg(feature = "native")]
     File(PathBuf),
 }
 
 impl PassphraseSource {
+    #[cfg_attr(not(feature = "native"), allow(unused_variables))]
     pub fn acquire(&self, confirm: bool) -> Result<String> {
         let passphrase = match self {
+            #[cfg(feature = "native")]
             Self::Prompt(p) => {
                 let first = rpassword::prompt_password(p)?;
 
@@ -91,6 +185,7 @@ impl PassphraseSource {
                 first
             }
             Self::EnvVar(v) => env::var(v).map_err(|e| Error::InvalidEnvVar(v.clone(), e))?,
+            #[cfg(feature = "native")]
             Self::File(p) => fs::read_to_string(p)?
                 .trim_end_matches(&['\r', '\n'])
                 .to_owned(),
@@ -110,20 +205,55 @@ pub fn generate_rsa_key_pair() -> Result<RsaPrivateKey> {
     Ok(key)
 }
 
-/// Generate a self-signed certificate.
+/// Check that an RSA key can be used for signing AVB structures and return the
+/// matching signature algorithm. AVB also allows 8192-bit keys, but RustCrypto
+/// cannot sign with them.
+pub fn validate_avb_key(key: &RsaPrivateKey) -> Result<AlgorithmType> {
+    match key.size() * 8 {
+        2048 => Ok(AlgorithmType::Sha256Rsa2048),
+        4096 => Ok(AlgorithmType::Sha256Rsa4096),
+        bits => Err(Error::UnsupportedAvbKeySize(bits)),
+    }
+}
+
+/// Generate a self-signed certificate that is valid for `validity`, starting
+/// from [`util::now()`].
 pub fn generate_cert(
     key: &RsaPrivateKey,
     serial: u64,
     validity: Duration,
     subject: &str,
 ) -> Result<Certificate> {
+    let not_before = util::now()?;
+
+    generate_cert_with_validity(key, serial, not_before, not_before + validity, subject)
+}
+
+/// Generate a self-signed certificate that is valid from `not_before` until
+/// `not_after`. The output only depends on the inputs, so generating a
+/// certificate twice with the same parameters produces identical results.
+pub fn generate_cert_with_validity(
+    key: &RsaPrivateKey,
+    serial: u64,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    subject: &str,
+) -> Result<Certificate> {
+    if not_after <= not_before {
+        return Err(Error::InvalidValidity);
+    }
+
+    let validity = Validity {
+        not_before: Time::try_from(not_before)?,
+        not_after: Time::try_from(not_after)?,
+    };
     let public_key_der = key.to_public_key().to_public_key_der()?;
     let signing_key = SigningKey::<Sha256>::new(key.clone());
 
     let builder = CertificateBuilder::new(
         Profile::Root,
         SerialNumber::from(serial),
-        Validity::from_now(validity)?,
+        validity,
         subject.parse()?,
         SubjectPublicKeyInfoOwned::from_der(public_key_der.as_bytes())?,
         &signing_key,
@@ -135,6 +265,16 @@ pub fn generate_cert(
     Ok(cert)
 }
 
+/// Derive a certificate serial number from a public key. This is the first 8
+/// bytes of the SHA-256 digest of the DER-encoded public key, which allows
+/// certificates to be regenerated without a random serial number.
+pub fn serial_from_public_key(key: &RsaPublicKey) -> Result<u64> {
+    let der = key.to_public_key_der()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, der.as_bytes());
+
+    Ok(u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap()))
+}
+
 /// x509_cert/pem follow rfc7468 strictly instead of implementing a lenient
 /// parser. The PEM decoder rejects lines in the base64 section that are longer
 /// than 64 characters, excluding whitespace. We'll reformat the data to deal
@@ -200,6 +340,7 @@ pub fn write_pem_cert(mut writer: impl Write, cert: &Certificate) -> Result<()>
 }
 
 /// Read PEM-encoded certificate from a file.
+#[cfg(feature = "native")]
 pub fn read_pem_cert_file(path: &Path) -> Result<Certificate> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
@@ -208,6 +349,7 @@ pub fn read_pem_cert_file(path: &Path) -> Result<Certificate> {
 }
 
 /// Write PEM-encoded certificate to a file.
+#[cfg(feature = "native")]
 pub fn write_pem_cert_file(path: &Path, cert: &Certificate) -> Result<()> {
     let file = File::create(path)?;
     let writer = BufWriter::new(file);
@@ -215,17 +357,130 @@ pub fn write_pem_cert_file(path: &Path, cert: &Certificate) -> Result<()> {
     write_pem_cert(writer, cert)
 }
 
-/// Read PEM-encoded PKCS8 private key from a reader.
+/// Derive a key from a passphrase using OpenSSL's `EVP_BytesToKey()` with MD5
+/// and an iteration count of 1. This is what the legacy PEM encryption uses.
+fn evp_bytes_to_key(passphrase: &[u8], salt: &[u8], key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len);
+    let mut prev_block = vec![];
+
+    while key.len() < key_len {
+        let mut hasher = Md5::new();
+        hasher.update(&prev_block);
+        hasher.update(passphrase);
+        hasher.update(salt);
+
+        prev_block = hasher.finalize().to_vec();
+        key.extend_from_slice(&prev_block);
+    }
+
+    key.truncate(key_len);
+    key
+}
+
+/// Read a legacy OpenSSL PEM-encoded PKCS1 private key. These are produced by
+/// eg. `openssl genrsa -aes256` and can optionally be encrypted, which is
+/// indicated by the `Proc-Type` and `DEK-Info` headers.
+fn read_legacy_pem_key(data: &str, source: &PassphraseSource) -> Result<RsaPrivateKey> {
+    let mut headers = vec![];
+    let mut base64 = String::new();
+    let mut in_body = false;
+
+    for line in data.lines().map(|l| l.trim()) {
+        if line.starts_with("-----BEGIN ") {
+            in_body = true;
+        } else if line.starts_with("-----END ") {
+            break;
+        } else if !in_body || line.is_empty() {
+            continue;
+        } else if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim(), value.trim()));
+        } else {
+            base64.push_str(line);
+        }
+    }
+
+    let mut der = STANDARD
+        .decode(&base64)
+        .map_err(|_| Error::InvalidLegacyPem("Invalid base64 data"))?;
+
+    let encrypted = headers
+        .iter()
+        .any(|(k, v)| *k == "Proc-Type" && *v == "4,ENCRYPTED");
+
+    if encrypted {
+        let dek_info = headers
+            .iter()
+            .find(|(k, _)| *k == "DEK-Info")
+            .map(|(_, v)| *v)
+            .ok_or(Error::InvalidLegacyPem("Missing DEK-Info header"))?;
+        let (cipher, iv_hex) = dek_info
+            .split_once(',')
+            .ok_or(Error::InvalidLegacyPem("Missing IV in DEK-Info header"))?;
+        let iv = hex::decode(iv_hex)
+            .ok()
+            .filter(|iv| iv.len() == 16)
+            .ok_or(Error::InvalidLegacyPem("Invalid IV in DEK-Info header"))?;
+
+        let key_len = match cipher {
+            "AES-128-CBC" => 16,
+            "AES-192-CBC" => 24,
+            "AES-256-CBC" => 32,
+            c => return Err(Error::UnsupportedLegacyCipher(c.to_owned())),
+        };
+
+        let passphrase = source.acquire(false)?;
+        // The first 8 bytes of the IV are used as the salt.
+        let key = evp_bytes_to_key(passphrase.as_bytes(), &iv[..8], key_len);
+
+        let plaintext = match key_len {
+            16 => cbc::Decryptor::<Aes128>::new_from_slices(&key, &iv)
+                .unwrap()
+                .decrypt_padded_mut::<Pkcs7>(&mut der),
+            24 => cbc::Decryptor::<Aes192>::new_from_slices(&key, &iv)
+                .unwrap()
+                .decrypt_padded_mut::<Pkcs7>(&mut der),
+            _ => cbc::Decryptor::<Aes256>::new_from_slices(&key, &iv)
+                .unwrap()
+                .decrypt_padded_mut::<Pkcs7>(&mut der),
+        }
+        .map_err(|_| Error::DecryptKeyLegacy)?;
+
+        let plaintext_len = plaintext.len();
+        der.truncate(plaintext_len);
+
+        // A wrong passphrase can still produce valid padding by chance.
+        RsaPrivateKey::from_pkcs1_der(&der).map_err(|_| Error::DecryptKeyLegacy)
+    } else {
+        RsaPrivateKey::from_pkcs1_der(&der).map_err(Error::LoadKeyLegacy)
+    }
+}
+
+/// Read PEM-encoded private key from a reader. PKCS8 keys, both encrypted and
+/// unencrypted, are supported. Legacy OpenSSL PKCS1 keys (`BEGIN RSA PRIVATE
+/// KEY`) are supported too, including ones encrypted with AES-CBC.
 pub fn read_pem_key(mut reader: impl Read, source: &PassphraseSource) -> Result<RsaPrivateKey> {
     let mut data = String::new();
     reader.read_to_string(&mut data)?;
 
-    if data.contains("ENCRYPTED") {
-        let passphrase = source.acquire(false)?;
+    let label = data
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("-----BEGIN "))
+        .and_then(|l| l.strip_suffix("-----"))
+        .unwrap_or_default();
 
-        RsaPrivateKey::from_pkcs8_encrypted_pem(&data, passphrase).map_err(Error::LoadKeyEncrypted)
-    } else {
-        RsaPrivateKey::from_pkcs8_pem(&data).map_err(Error::LoadKeyUnencrypted)
+    match label {
+        "ENCRYPTED PRIVATE KEY" => {
+            let passphrase = source.acquire(false)?;
+
+            RsaPrivateKey::from_pkcs8_encrypted_pem(&data, passphrase)
+                .map_err(Error::LoadKeyEncrypted)
+        }
+        "PRIVATE KEY" => RsaPrivateKey::from_pkcs8_pem(&data).map_err(Error::LoadKeyUnencrypted),
+        "RSA PRIVATE KEY" => read_legacy_pem_key(&data, source),
+        "" => Err(Error::UnsupportedKeyFormat(
+            "No PEM header found".to_owned(),
+        )),
+        l => Err(Error::UnsupportedKeyFormat(l.to_owned())),
     }
 }
 
@@ -282,6 +537,7 @@ pub fn write_pem_key(
 }
 
 /// Read PEM-encoded PKCS8 private key from a file.
+#[cfg(feature = "native")]
 pub fn read_pem_key_file(path: &Path, source: &PassphraseSource) -> Result<RsaPrivateKey> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
@@ -289,7 +545,64 @@ pub fn read_pem_key_file(path: &Path, source: &PassphraseSource) -> Result<RsaPr
     read_pem_key(reader, source)
 }
 
+/// Check if a path refers to a PKCS#12 bundle. This is only based on the
+/// `.p12` or `.pfx` file extension.
+pub fn is_pkcs12_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| {
+            e.eq_ignore_ascii_case("p12") || e.eq_ignore_ascii_case("pfx")
+        })
+}
+
+/// Load a DER-encoded PKCS#12 bundle from a file. The passphrase is always
+/// acquired because it is needed to verify the MAC and decrypt the contents.
+#[cfg(feature = "native")]
+pub fn read_pkcs12_file(path: &Path, source: &PassphraseSource) -> Result<Pkcs12> {
+    let data = fs::read(path)?;
+    let passphrase = source.acquire(false)?;
+
+    Ok(Pkcs12::parse(&data, &passphrase)?)
+}
+
+/// Load a private key from either a PEM file or, if [`is_pkcs12_path()`] is
+/// true, a PKCS#12 bundle. `p12_alias` selects the key in bundles with more
+/// than one. See [`Pkcs12::key()`].
+#[cfg(feature = "native")]
+pub fn read_key_file(
+    path: &Path,
+    source: &PassphraseSource,
+    p12_alias: Option<&str>,
+) -> Result<RsaPrivateKey> {
+    if is_pkcs12_path(path) {
+        let bundle = read_pkcs12_file(path, source)?;
+
+        Ok(bundle.key(p12_alias)?.clone())
+    } else {
+        read_pem_key_file(path, source)
+    }
+}
+
+/// Load a certificate from either a PEM file or, if [`is_pkcs12_path()`] is
+/// true, a PKCS#12 bundle. `source` is only used for PKCS#12 bundles. See
+/// [`Pkcs12::cert()`] for how the certificate is selected.
+#[cfg(feature = "native")]
+pub fn read_cert_file(
+    path: &Path,
+    source: &PassphraseSource,
+    p12_alias: Option<&str>,
+) -> Result<Certificate> {
+    if is_pkcs12_path(path) {
+        let bundle = read_pkcs12_file(path, source)?;
+
+        Ok(bundle.cert(p12_alias)?.clone())
+    } else {
+        read_pem_cert_file(path)
+    }
+}
+
 /// Save PEM-encoded PKCS8 private key to a file.
+#[cfg(feature = "native")]
 pub fn write_pem_key_file(
     path: &Path,
     key: &RsaPrivateKey,
@@ -327,6 +640,194 @@ pub fn cert_matches_key(cert: &Certificate, key: &RsaPrivateKey) -> Result<bool>
     Ok(key.to_public_key() == public_key)
 }
 
+/// Minimum remaining validity of an OTA certificate before it is reported as
+/// [`OtaCertIssue::ExpiresSoon`].
+pub const OTA_CERT_MIN_VALIDITY: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Padding scheme for RSA signatures.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RsaPadding {
+    #[default]
+    Pkcs1v15,
+    /// PSS using MGF1 with the same digest as the message and a salt that is
+    /// as long as the digest.
+    Pss,
+}
+
+impl fmt::Display for RsaPadding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pkcs1v15 => f.write_str("PKCS#1 v1.5"),
+            Self::Pss => f.write_str("PSS"),
+        }
+    }
+}
+
+/// Formats that avbroot creates RSA signatures for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignatureFormat {
+    /// vbmeta header signatures. The AVB algorithm types only cover PKCS#1
+    /// v1.5.
+    Avb,
+    /// payload.bin metadata and payload signatures. update_engine only verifies
+    /// raw PKCS#1 v1.5 signatures.
+    Payload,
+    /// The CMS whole-file signature in the OTA zip's archive comment. CMS can
+    /// describe PSS signatures, but AOSP recovery only verifies PKCS#1 v1.5.
+    OtaZip,
+}
+
+impl SignatureFormat {
+    /// Check that signatures in this format can use the padding scheme.
+    pub fn check_padding(self, padding: RsaPadding) -> Result<()> {
+        match (self, padding) {
+            (_, RsaPadding::Pkcs1v15) | (Self::OtaZip, RsaPadding::Pss) => Ok(()),
+            _ => Err(Error::UnsupportedPadding(self, padding)),
+        }
+    }
+}
+
+impl fmt::Display for SignatureFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Avb => f.write_str("AVB"),
+            Self::Payload => f.write_str("Payload"),
+            Self::OtaZip => f.write_str("OTA zip"),
+        }
+    }
+}
+
+/// Problems with a certificate that is used for signing OTAs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OtaCertIssue {
+    NotYetValid,
+    Expired,
+    /// The certificate expired before the OTA's build timestamp
+    /// (`post-timestamp`), so it cannot be valid when the OTA is installed.
+    ExpiredBeforeBuild,
+    ExpiresSoon,
+    /// Recovery only supports certificates signed with SHA256withRSA.
+    UnsupportedSignatureAlgorithm(ObjectIdentifier),
+    /// The key usage extension allows neither digital signatures nor
+    /// certificate signing.
+    InvalidKeyUsage,
+}
+
+impl OtaCertIssue {
+    /// Whether recovery would refuse to install an OTA signed with the
+    /// certificate.
+    pub fn prevents_install(&self) -> bool {
+        !matches!(self, Self::ExpiresSoon | Self::InvalidKeyUsage)
+    }
+
+    /// Whether the issue is caused by the certificate's expiry date.
+    pub fn is_expiry(&self) -> bool {
+        matches!(self, Self::Expired | Self::ExpiredBeforeBuild)
+    }
+}
+
+impl fmt::Display for OtaCertIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotYetValid => f.write_str("Certificate is not yet valid"),
+            Self::Expired => f.write_str("Certificate has expired"),
+            Self::ExpiredBeforeBuild => {
+                f.write_str("Certificate expired before the OTA's build timestamp")
+            }
+            Self::ExpiresSoon => f.write_str("Certificate expires in less than 90 days"),
+            Self::UnsupportedSignatureAlgorithm(oid) => {
+                write!(f, "Certificate has unsupported signature algorithm: {oid}")
+            }
+            Self::InvalidKeyUsage => {
+                f.write_str("Certificate key usage does not allow digital signatures")
+            }
+        }
+    }
+}
+
+/// Check if a certificate is suitable for signing OTAs, as of `now`. This does
+/// not check if the certificate matches the private key. Use
+/// [`cert_matches_key`] for that.
+///
+/// Self-signed certificates that only allow certificate signing in their key
+/// usage extension are accepted because that is what [`generate_cert`]
+/// produces. A key usage extension that cannot be parsed is reported as
+/// [`OtaCertIssue::InvalidKeyUsage`].
+pub fn check_ota_cert(cert: &Certificate, now: SystemTime) -> Vec<OtaCertIssue> {
+    let mut issues = vec![];
+    let validity = &cert.tbs_certificate.validity;
+
+    if now < validity.not_before.to_system_time() {
+        issues.push(OtaCertIssue::NotYetValid);
+    }
+
+    let not_after = validity.not_after.to_system_time();
+    if now >= not_after {
+        issues.push(OtaCertIssue::Expired);
+    } else if not_after
+        .duration_since(now)
+        .map_or(true, |d| d < OTA_CERT_MIN_VALIDITY)
+    {
+        issues.push(OtaCertIssue::ExpiresSoon);
+    }
+
+    let algorithm = cert.signature_algorithm.oid;
+    if algorithm != const_oid::db::rfc5912::SHA_256_WITH_RSA_ENCRYPTION {
+        issues.push(OtaCertIssue::UnsupportedSignatureAlgorithm(algorithm));
+    }
+
+    let extensions = cert
+        .tbs_certificate
+        .extensions
+        .as_deref()
+        .unwrap_or_default();
+
+    for extension in extensions {
+        if extension.extn_id != const_oid::db::rfc5280::ID_CE_KEY_USAGE {
+            continue;
+        }
+
+        let valid = KeyUsage::from_der(extension.extn_value.as_bytes()).map_or(false, |k| {
+            k.0.contains(KeyUsages::DigitalSignature) || k.0.contains(KeyUsages::KeyCertSign)
+        });
+        if !valid {
+            issues.push(OtaCertIssue::InvalidKeyUsage);
+        }
+    }
+
+    issues
+}
+
+/// Check if a certificate is still valid at the OTA's build timestamp
+/// (`post-timestamp`), which is the earliest time that the OTA can be
+/// installed.
+pub fn check_ota_cert_for_build(cert: &Certificate, build_time: SystemTime) -> Vec<OtaCertIssue> {
+    let not_after = cert.tbs_certificate.validity.not_after.to_system_time();
+
+    if build_time >= not_after {
+        vec![OtaCertIssue::ExpiredBeforeBuild]
+    } else {
+        vec![]
+    }
+}
+
+/// Get the expiry time of a certificate as a Unix timestamp.
+pub fn cert_not_after(cert: &Certificate) -> u64 {
+    cert.tbs_certificate
+        .validity
+        .not_after
+        .to_unix_duration()
+        .as_secs()
+}
+
+/// Get the hex-encoded SHA-256 digest of a certificate's DER encoding.
+pub fn cert_fingerprint(cert: &Certificate) -> Result<String> {
+    let der = cert.to_der()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &der);
+
+    Ok(hex::encode(digest))
+}
+
 /// Parse a CMS [`SignedData`] structure from raw DER-encoded data.
 pub fn parse_cms(data: &[u8]) -> Result<SignedData> {
     let ci = ContentInfo::from_der(data)?;
@@ -362,9 +863,48 @@ pub fn cms_sign_external(
     key: &RsaPrivateKey,
     cert: &Certificate,
     digest: &[u8],
+    padding: RsaPadding,
 ) -> Result<ContentInfo> {
-    let scheme = Pkcs1v15Sign::new::<Sha256>();
-    let signature = key.sign(scheme, digest)?;
+    SignatureFormat::OtaZip.check_padding(padding)?;
+
+    let signature = match padding {
+        RsaPadding::Pkcs1v15 => rsa_sign(key, Pkcs1v15Sign::new::<Sha256>(), digest)?,
+        RsaPadding::Pss => {
+            let salt_len = <Sha256 as Digest>::output_size();
+            rsa_sign(key, Pss::new_with_salt::<Sha256>(salt_len), digest)?
+        }
+    };
+
+    cms_from_signature(cert, signature, padding)
+}
+
+/// Wrap a raw RSA signature of a SHA-256 digest in the same CMS structure as
+/// [`cms_sign_external()`]. This is for signatures that were produced
+/// elsewhere, like on a machine that holds the private key. The signature is
+/// not checked.
+pub fn cms_from_signature(
+    cert: &Certificate,
+    signature: Vec<u8>,
+    padding: RsaPadding,
+) -> Result<ContentInfo> {
+    SignatureFormat::OtaZip.check_padding(padding)?;
+
+    let signature_algorithm = match padding {
+        RsaPadding::Pkcs1v15 => AlgorithmIdentifierOwned {
+            oid: const_oid::db::rfc5912::SHA_256_WITH_RSA_ENCRYPTION,
+            parameters: None,
+        },
+        RsaPadding::Pss => {
+            let salt_len = <Sha256 as Digest>::output_size();
+
+            AlgorithmIdentifierOwned {
+                oid: const_oid::db::rfc5912::ID_RSASSA_PSS,
+                parameters: Some(Any::encode_from(&RsaPssParams::new::<Sha256>(
+                    salt_len as u8,
+                ))?),
+            }
+        }
+    };
 
     let digest_algorithm = AlgorithmIdentifierOwned {
         oid: const_oid::db::rfc5912::ID_SHA_256,
@@ -390,10 +930,7 @@ pub fn cms_sign_external(
             }),
             digest_alg: digest_algorithm,
             signed_attrs: None,
-            signature_algorithm: AlgorithmIdentifierOwned {
-                oid: const_oid::db::rfc5912::SHA_256_WITH_RSA_ENCRYPTION,
-                parameters: None,
-            },
+            signature_algorithm,
             signature: SignatureValue::new(signature)?,
             unsigned_attrs: None,
         }])?,
@@ -0,0 +1,583 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal fastboot client for flashing images. The TCP transport works
+//! everywhere, while the USB transport uses usbfs and is only available on
+//! Linux. The operations are defined by the [`Fastboot`] trait so that callers
+//! can be tested against a mock device.
+
+#[cfg(target_os = "linux")]
+use std::{
+    fs::{self, File, OpenOptions},
+    os::fd::AsRawFd,
+    path::Path,
+};
+use std::{
+    io::{self, Read, Write},
+    str,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use thiserror::Error;
+
+/// Default port of fastbootd and bootloaders that support fastboot over TCP.
+pub const FASTBOOT_DEFAULT_PORT: u16 = 5554;
+
+/// Handshake for version 1 of the TCP protocol.
+const TCP_HANDSHAKE: &[u8; 4] = b"FB01";
+
+/// Maximum size of a command sent to the device.
+const MAX_COMMAND_SIZE: usize = 4096;
+
+/// Maximum size of a response from the device. The protocol limits responses
+/// to 256 bytes, but some bootloaders send longer `INFO` messages.
+const MAX_RESPONSE_SIZE: u64 = 4096;
+
+/// Size of each packet when sending download data.
+const DOWNLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Class, subclass, and protocol of the fastboot USB interface.
+#[cfg(target_os = "linux")]
+const USB_FASTBOOT_INTERFACE: [u8; 3] = [0xff, 0x42, 0x03];
+
+/// Maximum size of a single bulk transfer. Older kernels reject larger usbfs
+/// transfers.
+#[cfg(target_os = "linux")]
+const USB_MAX_TRANSFER_SIZE: usize = 16 * 1024;
+
+/// usbfs ioctls from `<linux/usbdevice_fs.h>`.
+#[cfg(target_os = "linux")]
+mod usbfs {
+    use std::ffi::{c_uint, c_void};
+
+    #[cfg(not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64",
+    )))]
+    const IOC: (u32, u32, u32) = (1, 2, 30);
+    #[cfg(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc64",
+    ))]
+    const IOC: (u32, u32, u32) = (4, 2, 29);
+
+    /// Equivalent of the `_IOC()` macro for ioctls of type `U`. The result is
+    /// cast to whatever type the libc's `ioctl()` expects.
+    const fn ioc(write: bool, read: bool, nr: u32, size: usize) -> u32 {
+        let dir = if write { IOC.0 } else { 0 } | if read { IOC.1 } else { 0 };
+        (dir << IOC.2) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr
+    }
+
+    #[repr(C)]
+    pub struct BulkTransfer {
+        pub ep: c_uint,
+        pub len: c_uint,
+        /// Timeout in milliseconds. 0 waits forever.
+        pub timeout: c_uint,
+        pub data: *mut c_void,
+    }
+
+    pub const BULK: u32 = ioc(true, true, 2, std::mem::size_of::<BulkTransfer>());
+    pub const CLAIM_INTERFACE: u32 = ioc(false, true, 15, std::mem::size_of::<c_uint>());
+    pub const RELEASE_INTERFACE: u32 = ioc(false, true, 16, std::mem::size_of::<c_uint>());
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid fastboot handshake: {0:?}")]
+    InvalidHandshake([u8; 4]),
+    #[error("Command is too long: {0} bytes")]
+    CommandTooLong(usize),
+    #[error("Response packet is too large: {0} bytes")]
+    ResponseTooLarge(u64),
+    #[error("Invalid response: {0:?}")]
+    InvalidResponse(String),
+    #[error("Device rejected command {0:?}: {1}")]
+    CommandFailed(String, String),
+    #[error("Expected device to request {0} bytes, but it requested {1} bytes")]
+    DataSizeMismatch(u64, u64),
+    #[error("Expected data phase for command: {0:?}")]
+    MissingDataPhase(String),
+    #[error("Unexpected data phase for command: {0:?}")]
+    UnexpectedDataPhase(String),
+    #[error("Download is too large: {0} bytes")]
+    DownloadTooLarge(u64),
+    #[error("No fastboot USB device found")]
+    UsbDeviceNotFound,
+    #[error("Multiple fastboot USB devices found: {0:?}")]
+    UsbDeviceAmbiguous(Vec<String>),
+    #[error("Fastboot USB interface has no bulk endpoints: {0}")]
+    UsbEndpointsNotFound(String),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Operations supported by a device in fastboot mode.
+pub trait Fastboot {
+    /// Get the value of a variable, like `current-slot`.
+    fn getvar(&mut self, name: &str) -> Result<String>;
+
+    /// Send `size` bytes from `reader` to the device for a subsequent
+    /// [`Self::flash()`]. The data is streamed, so it does not need to fit in
+    /// memory.
+    fn download(&mut self, reader: &mut dyn Read, size: u64) -> Result<()>;
+
+    /// Write the last downloaded data to a partition.
+    fn flash(&mut self, partition: &str) -> Result<()>;
+
+    fn erase(&mut self, partition: &str) -> Result<()>;
+
+    /// Mark a slot as active. `slot` has no leading underscore.
+    fn set_active(&mut self, slot: &str) -> Result<()>;
+
+    fn reboot(&mut self) -> Result<()>;
+
+    /// Read `size` bytes of a partition starting at `offset`. Returns [`None`]
+    /// if the device does not support reading partitions.
+    fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Option<Vec<u8>>>;
+}
+
+/// Final response to a command.
+enum Response {
+    Okay(String),
+    /// Size of the data phase that follows.
+    Data(u64),
+}
+
+/// Parse an integer variable, which may be decimal or `0x`-prefixed
+/// hexadecimal.
+pub fn parse_int(value: &str) -> Option<u64> {
+    let value = value.trim();
+
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Framing of fastboot messages on top of the underlying connection.
+pub trait Transport {
+    /// Send a command or a chunk of download data.
+    fn send(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Receive a response or a chunk of fetched data. Returns an error if the
+    /// device sends more than `max_size` bytes.
+    fn recv(&mut self, max_size: u64) -> Result<Vec<u8>>;
+}
+
+/// Fastboot TCP protocol. Every message is sent as a packet prefixed with its
+/// size as a big-endian u64.
+pub struct TcpTransport<S: Read + Write> {
+    inner: S,
+}
+
+impl<S: Read + Write> TcpTransport<S> {
+    /// Perform the protocol version handshake.
+    pub fn connect(mut inner: S) -> Result<Self> {
+        inner.write_all(TCP_HANDSHAKE)?;
+        inner.flush()?;
+
+        let mut handshake = [0u8; 4];
+        inner.read_exact(&mut handshake)?;
+
+        // The device replies with the highest version it supports.
+        let version = str::from_utf8(&handshake[2..])
+            .ok()
+            .and_then(|v| v.parse::<u8>().ok());
+        if &handshake[..2] != b"FB" || version.map_or(true, |v| v < 1) {
+            return Err(Error::InvalidHandshake(handshake));
+        }
+
+        Ok(Self { inner })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read + Write> Transport for TcpTransport<S> {
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.write_u64::<BigEndian>(data.len() as u64)?;
+        self.inner.write_all(data)?;
+        self.inner.flush()?;
+
+        Ok(())
+    }
+
+    fn recv(&mut self, max_size: u64) -> Result<Vec<u8>> {
+        let size = self.inner.read_u64::<BigEndian>()?;
+        if size > max_size {
+            return Err(Error::ResponseTooLarge(size));
+        }
+
+        let mut data = vec![0u8; size as usize];
+        self.inner.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Fastboot USB protocol over Linux's usbfs. Messages are sent as-is with bulk
+/// transfers. The user needs write access to the device node in
+/// `/dev/bus/usb`, which usually requires a udev rule.
+#[cfg(target_os = "linux")]
+pub struct UsbTransport {
+    file: File,
+    interface: u32,
+    ep_in: u8,
+    ep_out: u8,
+}
+
+#[cfg(target_os = "linux")]
+impl UsbTransport {
+    /// Open the fastboot USB device with the specified serial number. If
+    /// `serial` is [`None`], there must be exactly one fastboot device.
+    pub fn open(serial: Option<&str>) -> Result<Self> {
+        let mut found = vec![];
+
+        for entry in fs::read_dir("/sys/bus/usb/devices")? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // Interfaces are named <device>:<config>.<interface>.
+            let Some((device, _)) = name.split_once(':') else {
+                continue;
+            };
+
+            let path = entry.path();
+            let id = [
+                "bInterfaceClass",
+                "bInterfaceSubClass",
+                "bInterfaceProtocol",
+            ]
+            .map(|n| read_sysfs_hex(&path.join(n)).ok());
+            if id != USB_FASTBOOT_INTERFACE.map(|v| Some(u32::from(v))) {
+                continue;
+            }
+
+            let device_path = path.with_file_name(device);
+            let device_serial = fs::read_to_string(device_path.join("serial"))
+                .map(|s| s.trim().to_owned())
+                .unwrap_or_default();
+
+            if serial.map_or(true, |s| s == device_serial) {
+                found.push((path, device_path, device_serial));
+            }
+        }
+
+        let (path, device_path, _) = match found.len() {
+            0 => return Err(Error::UsbDeviceNotFound),
+            1 => found.pop().unwrap(),
+            _ => {
+                let serials = found.into_iter().map(|(_, _, s)| s).collect();
+                return Err(Error::UsbDeviceAmbiguous(serials));
+            }
+        };
+
+        let interface = read_sysfs_hex(&path.join("bInterfaceNumber"))?;
+        let mut ep_in = None;
+        let mut ep_out = None;
+
+        for entry in fs::read_dir(&path)? {
+            let ep_path = entry?.path();
+            if !ep_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.starts_with("ep_"))
+            {
+                continue;
+            }
+
+            if fs::read_to_string(ep_path.join("type"))?.trim() != "Bulk" {
+                continue;
+            }
+
+            let address = read_sysfs_hex(&ep_path.join("bEndpointAddress"))? as u8;
+            if address & 0x80 != 0 {
+                ep_in = Some(address);
+            } else {
+                ep_out = Some(address);
+            }
+        }
+
+        let (Some(ep_in), Some(ep_out)) = (ep_in, ep_out) else {
+            return Err(Error::UsbEndpointsNotFound(path.display().to_string()));
+        };
+
+        let busnum = read_sysfs_int(&device_path.join("busnum"))?;
+        let devnum = read_sysfs_int(&device_path.join("devnum"))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/bus/usb/{busnum:03}/{devnum:03}"))?;
+
+        let transport = Self {
+            file,
+            interface,
+            ep_in,
+            ep_out,
+        };
+        transport.ioctl(usbfs::CLAIM_INTERFACE, &mut { interface })?;
+
+        Ok(transport)
+    }
+
+    fn ioctl<T>(&self, request: u32, arg: &mut T) -> io::Result<i32> {
+        // SAFETY: The request numbers and argument types match the usbfs ABI
+        // and the file descriptor stays open for the duration of the call.
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                request as _,
+                arg as *mut T as *mut libc::c_void,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret)
+    }
+
+    fn bulk(&self, ep: u8, data: &mut [u8]) -> io::Result<usize> {
+        let mut transfer = usbfs::BulkTransfer {
+            ep: ep.into(),
+            len: data.len() as _,
+            timeout: 0,
+            data: data.as_mut_ptr().cast(),
+        };
+
+        loop {
+            match self.ioctl(usbfs::BULK, &mut transfer) {
+                Ok(n) => return Ok(n as usize),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for UsbTransport {
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        // The ioctl takes a mutable pointer even for OUT transfers.
+        let mut buf = vec![0u8; data.len().min(USB_MAX_TRANSFER_SIZE)];
+
+        for chunk in data.chunks(USB_MAX_TRANSFER_SIZE) {
+            let buf = &mut buf[..chunk.len()];
+            buf.copy_from_slice(chunk);
+
+            let n = self.bulk(self.ep_out, buf)?;
+            if n != chunk.len() {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recv(&mut self, max_size: u64) -> Result<Vec<u8>> {
+        let size = max_size.min(USB_MAX_TRANSFER_SIZE as u64) as usize;
+        let mut data = vec![0u8; size];
+        let n = self.bulk(self.ep_in, &mut data)?;
+        data.truncate(n);
+
+        Ok(data)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for UsbTransport {
+    fn drop(&mut self) {
+        let _ = self.ioctl(usbfs::RELEASE_INTERFACE, &mut { self.interface });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_sysfs_hex(path: &Path) -> io::Result<u32> {
+    let value = fs::read_to_string(path)?;
+
+    u32::from_str_radix(value.trim(), 16).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(target_os = "linux")]
+fn read_sysfs_int(path: &Path) -> io::Result<u32> {
+    let value = fs::read_to_string(path)?;
+
+    value
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Connection to a device in fastboot mode over a [`Transport`].
+pub struct FastbootConnection<T: Transport> {
+    transport: T,
+    messages: Vec<String>,
+}
+
+impl<S: Read + Write> FastbootConnection<TcpTransport<S>> {
+    /// Connect over the TCP protocol. This performs the version handshake.
+    pub fn connect(inner: S) -> Result<Self> {
+        TcpTransport::connect(inner).map(Self::new)
+    }
+}
+
+impl<T: Transport> FastbootConnection<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            messages: vec![],
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Take the `INFO` and `TEXT` messages received since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Wait for the final response to `command`.
+    fn recv_response(&mut self, command: &str) -> Result<Response> {
+        loop {
+            let packet = self.transport.recv(MAX_RESPONSE_SIZE)?;
+            if packet.len() < 4 {
+                return Err(Error::InvalidResponse(
+                    String::from_utf8_lossy(&packet).into_owned(),
+                ));
+            }
+
+            let (kind, payload) = packet.split_at(4);
+            let payload = String::from_utf8_lossy(payload).into_owned();
+
+            match kind {
+                b"OKAY" => return Ok(Response::Okay(payload)),
+                b"FAIL" => return Err(Error::CommandFailed(command.to_owned(), payload)),
+                b"INFO" | b"TEXT" => self.messages.push(payload),
+                b"DATA" => {
+                    let size = u64::from_str_radix(&payload, 16)
+                        .map_err(|_| Error::InvalidResponse(format!("DATA{payload}")))?;
+                    return Ok(Response::Data(size));
+                }
+                _ => {
+                    return Err(Error::InvalidResponse(
+                        String::from_utf8_lossy(&packet).into_owned(),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn send_command(&mut self, command: &str) -> Result<Response> {
+        if command.len() > MAX_COMMAND_SIZE {
+            return Err(Error::CommandTooLong(command.len()));
+        }
+
+        self.transport.send(command.as_bytes())?;
+        self.recv_response(command)
+    }
+
+    /// Send a command that has no data phase.
+    fn simple_command(&mut self, command: &str) -> Result<String> {
+        match self.send_command(command)? {
+            Response::Okay(payload) => Ok(payload),
+            Response::Data(_) => Err(Error::UnexpectedDataPhase(command.to_owned())),
+        }
+    }
+}
+
+impl<T: Transport> Fastboot for FastbootConnection<T> {
+    fn getvar(&mut self, name: &str) -> Result<String> {
+        self.simple_command(&format!("getvar:{name}"))
+    }
+
+    fn download(&mut self, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let size = u32::try_from(size).map_err(|_| Error::DownloadTooLarge(size))?;
+        let command = format!("download:{size:08x}");
+
+        match self.send_command(&command)? {
+            Response::Data(n) if n == u64::from(size) => {}
+            Response::Data(n) => return Err(Error::DataSizeMismatch(size.into(), n)),
+            Response::Okay(_) => return Err(Error::MissingDataPhase(command)),
+        }
+
+        let mut buf = vec![0u8; (size as usize).min(DOWNLOAD_CHUNK_SIZE)];
+        let mut remaining = size as usize;
+
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(DOWNLOAD_CHUNK_SIZE)];
+            reader.read_exact(chunk)?;
+            self.transport.send(chunk)?;
+            remaining -= chunk.len();
+        }
+
+        match self.recv_response(&command)? {
+            Response::Okay(_) => Ok(()),
+            Response::Data(_) => Err(Error::UnexpectedDataPhase(command)),
+        }
+    }
+
+    fn flash(&mut self, partition: &str) -> Result<()> {
+        self.simple_command(&format!("flash:{partition}"))?;
+        Ok(())
+    }
+
+    fn erase(&mut self, partition: &str) -> Result<()> {
+        self.simple_command(&format!("erase:{partition}"))?;
+        Ok(())
+    }
+
+    fn set_active(&mut self, slot: &str) -> Result<()> {
+        self.simple_command(&format!("set_active:{slot}"))?;
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<()> {
+        self.simple_command("reboot")?;
+        Ok(())
+    }
+
+    fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Option<Vec<u8>>> {
+        let command = format!("fetch:{partition}:{offset:#010x}:{size:#010x}");
+
+        let data_size = match self.send_command(&command) {
+            Ok(Response::Data(n)) if n == size => n,
+            Ok(Response::Data(n)) => return Err(Error::DataSizeMismatch(size, n)),
+            Ok(Response::Okay(_)) => return Err(Error::MissingDataPhase(command)),
+            // Most bootloaders only implement fetch in fastbootd, if at all.
+            Err(Error::CommandFailed(_, _)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut data = Vec::with_capacity(data_size as usize);
+
+        while (data.len() as u64) < data_size {
+            let remaining = data_size - data.len() as u64;
+            data.extend(self.transport.recv(remaining)?);
+        }
+
+        match self.recv_response(&command)? {
+            Response::Okay(_) => Ok(Some(data)),
+            Response::Data(_) => Err(Error::UnexpectedDataPhase(command)),
+        }
+    }
+}
@@ -6,29 +6,34 @@
 use std::{
     cmp, fmt,
     io::{self, Cursor, Read, Seek, SeekFrom, Write},
-    str,
+    mem, str,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use num_bigint_dig::{ModInverse, ToBigInt};
 use num_traits::{Pow, ToPrimitive};
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use ring::digest::{Algorithm, Context};
 use rsa::{traits::PublicKeyParts, BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
 use sha2::{Digest, Sha256, Sha512};
 use thiserror::Error;
 
 use crate::{
-    format::padding,
+    crypto,
+    format::{fec, padding},
     stream::{
-        self, CountingReader, FromReader, ReadDiscardExt, ReadSeek, ReadStringExt, ToWriter,
-        WriteStringExt, WriteZerosExt,
+        self, CountingReader, FromReader, ReadDiscardExt, ReadSeek, ReadStringExt, SectionReader,
+        ToWriter, WriteStringExt, WriteZerosExt,
+    },
+    util::{
+        self,
+        par::{IntoParallelIterator, ParallelIterator},
+        EscapedString,
     },
-    util::{self, EscapedString},
 };
 
 pub const VERSION_MAJOR: u32 = 1;
@@ -41,6 +46,33 @@ pub const FOOTER_VERSION_MINOR: u32 = 0;
 pub const HEADER_MAGIC: [u8; 4] = *b"AVB0";
 pub const FOOTER_MAGIC: [u8; 4] = *b"AVBf";
 
+/// Hashtree verification is disabled for the partitions covered by the vbmeta
+/// image.
+pub const HEADER_FLAG_HASHTREE_DISABLED: u32 = 1 << 0;
+/// Verification of the descriptors in the vbmeta image is disabled.
+pub const HEADER_FLAG_VERIFICATION_DISABLED: u32 = 1 << 1;
+
+/// Do not append the current slot's A/B suffix to the hashtree descriptor's
+/// partition name.
+pub const HASHTREE_FLAG_DO_NOT_USE_AB: u32 = 1 << 0;
+/// Only verify each hashtree block once with dm-verity.
+pub const HASHTREE_FLAG_CHECK_AT_MOST_ONCE: u32 = 1 << 1;
+
+/// Do not append the current slot's A/B suffix to the hash descriptor's
+/// partition name.
+pub const HASH_FLAG_DO_NOT_USE_AB: u32 = 1 << 0;
+
+/// Do not append the current slot's A/B suffix to the chain partition
+/// descriptor's partition name.
+pub const CHAIN_PARTITION_FLAG_DO_NOT_USE_AB: u32 = 1 << 0;
+
+/// Only apply the kernel cmdline descriptor if hashtree verification is not
+/// disabled.
+pub const KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED: u32 = 1 << 0;
+/// Only apply the kernel cmdline descriptor if hashtree verification is
+/// disabled.
+pub const KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_DISABLED: u32 = 1 << 1;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Failed to read {0:?} field: {1}")]
@@ -73,6 +105,10 @@ pub enum Error {
     UnsupportedHashAlgorithm(String),
     #[error("Incorrect key size ({0} bytes) for algorithm {1:?} ({2} bytes)")]
     IncorrectKeySize(usize, AlgorithmType, usize),
+    #[error("Incorrect digest size ({0} bytes) for algorithm {1:?} ({2} bytes)")]
+    IncorrectDigestSize(u32, String, usize),
+    #[error("{0:?} field ({1} bytes) exceeds the remaining descriptor data")]
+    FieldTooLong(&'static str, u32),
     #[error("Expected root digest {0}, but have {1}")]
     InvalidRootDigest(String, String),
     #[error("Expected hash tree {0}, but have {1}")]
@@ -83,6 +119,32 @@ pub enum Error {
     RsaVerifyError(rsa::Error),
     #[error("{0} byte image size is too small to fit header or footer")]
     ImageSizeTooSmall(u64),
+    #[error("Image does not have a vbmeta footer")]
+    MissingFooter,
+    #[error("Image does not have a hashtree descriptor")]
+    MissingHashtreeDescriptor,
+    #[error("Image needs {0} bytes for hash tree, FEC data, and vbmeta, but is {1} bytes")]
+    InsufficientSpace(u64, u64),
+    #[error("Expected hash tree size {0}, but have {1}")]
+    IncorrectTreeSize(u64, usize),
+    #[error("Expected FEC data size {0}, but have {1}")]
+    IncorrectFecSize(u64, u64),
+    #[error("{0:?} field must not be zero")]
+    ZeroBlockSize(&'static str),
+    #[error("Block size {0} is not a power of two that fits at least one {1} byte node")]
+    InvalidBlockSize(u32, usize),
+    #[error("Block is {0} bytes, but the block size is only {1} bytes")]
+    BlockTooLarge(usize, u32),
+    #[error("Block {0} is out of bounds")]
+    BlockOutOfBounds(u64),
+    #[error("Block {0} should be {1} bytes, but is {2} bytes")]
+    IncorrectBlockSize(u64, u64, usize),
+    #[error("Block {0} does not match hash tree at level {1}")]
+    InvalidBlockDigest(u64, usize),
+    #[error("Invalid chained partition {0:?}: {1}")]
+    InvalidChainedPartition(String, &'static str),
+    #[error("FEC error")]
+    FecError(#[from] fec::Error),
     #[error("I/O error")]
     IoError(#[from] io::Error),
 }
@@ -175,11 +237,11 @@ impl AlgorithmType {
             Self::None | Self::Unknown(_) => vec![],
             Self::Sha256Rsa2048 | Self::Sha256Rsa4096 | Self::Sha256Rsa8192 => {
                 let scheme = Pkcs1v15Sign::new::<Sha256>();
-                key.sign(scheme, digest).map_err(Error::RsaSignError)?
+                crypto::rsa_sign(key, scheme, digest).map_err(Error::RsaSignError)?
             }
             Self::Sha512Rsa2048 | Self::Sha512Rsa4096 | Self::Sha512Rsa8192 => {
                 let scheme = Pkcs1v15Sign::new::<Sha512>();
-                key.sign(scheme, digest).map_err(Error::RsaSignError)?
+                crypto::rsa_sign(key, scheme, digest).map_err(Error::RsaSignError)?
             }
         };
 
@@ -325,6 +387,64 @@ impl fmt::Debug for HashtreeDescriptor {
 }
 
 impl HashtreeDescriptor {
+    /// Whether the [`HASHTREE_FLAG_DO_NOT_USE_AB`] flag is set.
+    pub fn do_not_use_ab(&self) -> bool {
+        self.flags & HASHTREE_FLAG_DO_NOT_USE_AB != 0
+    }
+
+    /// Whether the [`HASHTREE_FLAG_CHECK_AT_MOST_ONCE`] flag is set.
+    pub fn check_at_most_once(&self) -> bool {
+        self.flags & HASHTREE_FLAG_CHECK_AT_MOST_ONCE != 0
+    }
+
+    /// Opt in to FEC data with `roots` parity bytes per codeword. This is
+    /// needed for images that were created without FEC data since
+    /// [`Self::update()`] only generates it if [`Self::fec_num_roots`] is
+    /// non-zero. The offset and size are filled in by the next update.
+    pub fn enable_fec(&mut self, roots: u32) -> Result<()> {
+        if !(fec::MIN_ROOTS..=fec::MAX_ROOTS).contains(&roots) {
+            return Err(fec::Error::InvalidRoots(roots).into());
+        }
+
+        self.fec_num_roots = roots;
+
+        Ok(())
+    }
+
+    /// Compute the size of the hash tree from [`Self::image_size`], the block
+    /// sizes, and the digest size of [`Self::hash_algorithm`]. This is the
+    /// value that [`Self::tree_size`] must have.
+    pub fn expected_tree_size(&self) -> Result<u64> {
+        let algorithm = hash_algorithm(&self.hash_algorithm)?;
+        if self.data_block_size == 0 {
+            return Err(Error::ZeroBlockSize("data_block_size"));
+        } else if self.hash_block_size == 0 {
+            return Err(Error::ZeroBlockSize("hash_block_size"));
+        }
+
+        let node_size = HashTree::node_size_for(algorithm) as u64;
+        let mut block_size = u64::from(self.data_block_size);
+        let mut level_size = self.image_size;
+        let mut tree_size = 0u64;
+
+        // Images no larger than one block have no tree.
+        while level_size > block_size {
+            let num_nodes = level_size / block_size + u64::from(level_size % block_size != 0);
+            level_size = num_nodes
+                .checked_mul(node_size)
+                .and_then(|s| padding::round(s, u64::from(self.hash_block_size)))
+                .ok_or_else(|| Error::IntegerTooLarge("tree_size"))?;
+            tree_size = tree_size
+                .checked_add(level_size)
+                .ok_or_else(|| Error::IntegerTooLarge("tree_size"))?;
+
+            // Every level above the leaves hashes the blocks of the level below.
+            block_size = u64::from(self.hash_block_size);
+        }
+
+        Ok(tree_size)
+    }
+
     /// Calculate the hash tree digests for a single level of the tree. If the
     /// reader's position is block-aligned and `image_size` is a multiple of the
     /// block size, then this function can also be used to calculate the digests
@@ -411,15 +531,19 @@ impl HashtreeDescriptor {
         Ok(pieces.into_iter().flatten().collect())
     }
 
-    /// Calculate the hash tree for the given input in parallel.
+    /// Calculate the hash tree for the given input in parallel. Timings are
+    /// only measured if `metrics` is set.
     fn calculate_hash_tree(
         open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
         image_size: u64,
         block_size: u32,
         algorithm: &'static Algorithm,
         salt: &[u8],
+        mut metrics: Option<&mut HashTreeMetrics>,
         cancel_signal: &Arc<AtomicBool>,
     ) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let total_start = metrics.is_some().then(Instant::now);
+
         // Small files are hashed directly, exactly like a hash descriptor.
         if image_size <= u64::from(block_size) {
             let mut reader = open_input()?;
@@ -431,6 +555,11 @@ impl HashtreeDescriptor {
             context.update(&buf);
             let digest = context.finish();
 
+            if let (Some(m), Some(start)) = (metrics, total_start) {
+                m.leaf_hashing = start.elapsed();
+                m.total = m.leaf_hashing;
+            }
+
             return Ok((digest.as_ref().to_vec(), vec![]));
         }
 
@@ -439,6 +568,8 @@ impl HashtreeDescriptor {
         let mut level_size = image_size;
 
         while level_size > u64::from(block_size) {
+            let level_start = metrics.is_some().then(Instant::now);
+
             let mut level = if let Some(prev_level) = levels.last() {
                 // Hash the previous level.
                 Self::hash_one_level(
@@ -464,16 +595,31 @@ impl HashtreeDescriptor {
             // Pad to the block size.
             level.resize(padding::round(level.len(), block_size as usize).unwrap(), 0);
 
+            if let (Some(m), Some(start)) = (metrics.as_deref_mut(), level_start) {
+                if levels.is_empty() {
+                    m.leaf_hashing = start.elapsed();
+                } else {
+                    m.levels.push(start.elapsed());
+                }
+            }
+
             level_size = level.len() as u64;
             levels.push(level);
         }
 
+        let root_start = metrics.is_some().then(Instant::now);
+
         // Calculate the root hash.
         let mut context = Context::new(algorithm);
         context.update(salt);
         context.update(levels.last().unwrap());
         let root_hash = context.finish().as_ref().to_vec();
 
+        if let (Some(m), Some(root_start), Some(total_start)) = (metrics, root_start, total_start) {
+            m.root = root_start.elapsed();
+            m.total = total_start.elapsed();
+        }
+
         // The tree is oriented such that the leaves are at the end.
         let hash_tree = levels.into_iter().rev().flatten().collect();
 
@@ -488,22 +634,25 @@ impl HashtreeDescriptor {
         open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
         cancel_signal: &Arc<AtomicBool>,
     ) -> Result<()> {
-        let algorithm = match self.hash_algorithm.as_str() {
-            "sha256" => &ring::digest::SHA256,
-            "sha512" => &ring::digest::SHA512,
-            a => return Err(Error::UnsupportedHashAlgorithm(a.to_owned())),
-        };
+        let algorithm = hash_algorithm(&self.hash_algorithm)?;
         let tree_size = self
             .tree_size
             .to_usize()
             .ok_or_else(|| Error::IntegerTooLarge("tree_size"))?;
 
+        // Catch corrupt or tampered descriptors before reading the image.
+        let expected_tree_size = self.expected_tree_size()?;
+        if self.tree_size != expected_tree_size {
+            return Err(Error::IncorrectTreeSize(expected_tree_size, tree_size));
+        }
+
         let (actual_root_digest, actual_hash_tree) = Self::calculate_hash_tree(
             &open_input,
             self.image_size,
             self.data_block_size,
             algorithm,
             &self.salt,
+            None,
             cancel_signal,
         )?;
 
@@ -531,8 +680,633 @@ impl HashtreeDescriptor {
             ));
         }
 
+        if self.fec_num_roots != 0 {
+            let fec_input_size = self
+                .tree_offset
+                .checked_add(self.tree_size)
+                .ok_or_else(|| Error::IntegerTooLarge("tree_offset"))?;
+
+            // Like the tree size, don't trust the descriptor's FEC size before
+            // allocating a buffer for it.
+            let expected_fec_size = fec::fec_size(fec_input_size, self.fec_num_roots)?;
+            if self.fec_size != expected_fec_size {
+                return Err(Error::IncorrectFecSize(expected_fec_size, self.fec_size));
+            }
+
+            let fec_size = self
+                .fec_size
+                .to_usize()
+                .ok_or_else(|| Error::IntegerTooLarge("fec_size"))?;
+
+            reader.seek(SeekFrom::Start(self.fec_offset))?;
+
+            let mut fec_data = vec![0u8; fec_size];
+            reader.read_exact(&mut fec_data)?;
+
+            fec::verify(
+                &open_input,
+                fec_input_size,
+                self.fec_num_roots,
+                &fec_data,
+                cancel_signal,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Recompute the hash tree and root digest from the first `image_size`
+    /// bytes of the input in parallel. The existing salt, hash algorithm, and
+    /// block size are kept. If [`Self::fec_num_roots`] is non-zero, the FEC
+    /// data, which covers both the image and the hash tree, is recomputed too.
+    ///
+    /// Like avbtool, the tree is placed at the first block boundary after the
+    /// image and the FEC data immediately follows the tree. Returns the tree
+    /// and the FEC data, which the caller must write at [`Self::tree_offset`]
+    /// and [`Self::fec_offset`]. See [`Self::verify()`] for the requirements
+    /// for `open_input`.
+    pub fn update(
+        &mut self,
+        open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+        image_size: u64,
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let algorithm = hash_algorithm(&self.hash_algorithm)?;
+
+        let (root_digest, hash_tree) = Self::calculate_hash_tree(
+            &open_input,
+            image_size,
+            self.data_block_size,
+            algorithm,
+            &self.salt,
+            None,
+            cancel_signal,
+        )?;
+
+        let tree_offset = padding::round(image_size, u64::from(self.data_block_size))
+            .ok_or_else(|| Error::IntegerTooLarge("tree_offset"))?;
+        let tree_size = hash_tree.len() as u64;
+        let fec_offset = tree_offset
+            .checked_add(tree_size)
+            .ok_or_else(|| Error::IntegerTooLarge("fec_offset"))?;
+
+        let (fec_data, fec_offset, fec_size) = if self.fec_num_roots != 0 {
+            fec::add_fec(
+                || {
+                    Ok(TreeAppendedReader {
+                        inner: open_input()?,
+                        image_size,
+                        tree_offset,
+                        tree: &hash_tree,
+                        pos: 0,
+                    })
+                },
+                fec_offset,
+                self.fec_num_roots,
+                cancel_signal,
+            )?
+        } else {
+            (vec![], 0, 0)
+        };
+
+        self.image_size = image_size;
+        self.tree_offset = tree_offset;
+        self.tree_size = tree_size;
+        self.root_digest = root_digest;
+        self.fec_offset = fec_offset;
+        self.fec_size = fec_size;
+
+        Ok((hash_tree, fec_data))
+    }
+}
+
+/// A reader that presents the image, the zero padding up to the next block
+/// boundary, and a hash tree as a single stream. This is used to compute the
+/// FEC data before the tree is written to the image.
+struct TreeAppendedReader<'a, R> {
+    inner: R,
+    image_size: u64,
+    tree_offset: u64,
+    tree: &'a [u8],
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for TreeAppendedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let total_size = self.tree_offset + self.tree.len() as u64;
+        if self.pos >= total_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = if self.pos < self.image_size {
+            let to_read = (self.image_size - self.pos).min(buf.len() as u64) as usize;
+
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = self.inner.read(&mut buf[..to_read])?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            n
+        } else if self.pos < self.tree_offset {
+            let n = (self.tree_offset - self.pos).min(buf.len() as u64) as usize;
+            buf[..n].fill(0);
+
+            n
+        } else {
+            let start = (self.pos - self.tree_offset) as usize;
+            let n = (self.tree.len() - start).min(buf.len());
+            buf[..n].copy_from_slice(&self.tree[start..start + n]);
+
+            n
+        };
+
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for TreeAppendedReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let total_size = self.tree_offset + self.tree.len() as u64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => total_size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek to negative offset")
+        })?;
+
+        Ok(self.pos)
+    }
+}
+
+/// Get the digest algorithm for a hash or hashtree descriptor's
+/// `hash_algorithm` field.
+fn hash_algorithm(name: &str) -> Result<&'static Algorithm> {
+    match name {
+        "sha256" => Ok(&ring::digest::SHA256),
+        "sha512" => Ok(&ring::digest::SHA512),
+        a => Err(Error::UnsupportedHashAlgorithm(a.to_owned())),
+    }
+}
+
+/// Time spent in each phase of [`HashTree::calculate_with_metrics()`]. This is
+/// useful for deciding whether hashing a partition in parallel is worthwhile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HashTreeMetrics {
+    /// Time spent hashing the data blocks in parallel. For images no larger
+    /// than one block, this is the time spent hashing the only block.
+    pub leaf_hashing: Duration,
+    /// Time spent building each level above the leaves, from the bottom up.
+    pub levels: Vec<Duration>,
+    /// Time spent computing the root digest from the top level.
+    pub root: Duration,
+    pub total: Duration,
+}
+
+/// Block-level access to a dm-verity hash tree, for callers that want to hash
+/// or verify individual blocks instead of the whole image.
+///
+/// The tree layout matches what [`HashtreeDescriptor`] produces and what
+/// dm-verity expects:
+///
+/// * The data block size and hash block size are the same.
+/// * Each node is `hash(salt || block)`. An undersized last block is padded
+///   with zeros before hashing.
+/// * Each node is padded with zeros to the next power of two.
+/// * Level 0 contains the nodes for the data blocks. Level `n + 1` contains the
+///   nodes for the blocks of level `n`. Each level is padded with zeros to a
+///   multiple of the block size. The topmost level fits in a single block and
+///   the root digest is the hash of that block.
+/// * On disk, the levels are stored from the top down, so the leaves are at
+///   the end.
+/// * Images no larger than one block have no tree. The root digest is the hash
+///   of the only block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashTree {
+    algorithm: &'static Algorithm,
+    block_size: u32,
+    image_size: u64,
+    salt: Vec<u8>,
+    root_digest: Vec<u8>,
+    /// Levels from the bottom up.
+    levels: Vec<Vec<u8>>,
+}
+
+impl HashTree {
+    /// Size of each node, including padding.
+    fn node_size_for(algorithm: &'static Algorithm) -> usize {
+        algorithm.output_len.next_power_of_two()
+    }
+
+    /// Ensure that the block size is a non-zero power of two and can fit at
+    /// least one node. dm-verity has the same requirements.
+    fn validate_block_size(block_size: u32, algorithm: &'static Algorithm) -> Result<()> {
+        let node_size = Self::node_size_for(algorithm);
+
+        if block_size == 0 {
+            return Err(Error::ZeroBlockSize("block_size"));
+        } else if !block_size.is_power_of_two() || (block_size as usize) < node_size {
+            return Err(Error::InvalidBlockSize(block_size, node_size));
+        }
+
+        Ok(())
+    }
+
+    /// Compute the size in bytes of each level, from the bottom up.
+    fn level_sizes(
+        image_size: u64,
+        block_size: u32,
+        algorithm: &'static Algorithm,
+    ) -> Result<Vec<u64>> {
+        Self::validate_block_size(block_size, algorithm)?;
+
+        let block_size = u64::from(block_size);
+        let node_size = Self::node_size_for(algorithm) as u64;
+        let mut sizes = vec![];
+        let mut level_size = image_size;
+
+        while level_size > block_size {
+            let num_nodes = level_size / block_size + u64::from(level_size % block_size != 0);
+            level_size = num_nodes
+                .checked_mul(node_size)
+                .and_then(|s| padding::round(s, block_size))
+                .ok_or_else(|| Error::IntegerTooLarge("tree_size"))?;
+            sizes.push(level_size);
+        }
+
+        Ok(sizes)
+    }
+
+    /// Number of nodes that fit in a single block of an upper level.
+    fn nodes_per_block(&self) -> Result<u64> {
+        Self::validate_block_size(self.block_size, self.algorithm)?;
+
+        Ok(u64::from(self.block_size) / Self::node_size_for(self.algorithm) as u64)
+    }
+
+    /// Load an existing on-disk hash tree.
+    pub fn new(
+        hash_algorithm: &str,
+        block_size: u32,
+        image_size: u64,
+        salt: &[u8],
+        root_digest: &[u8],
+        tree: &[u8],
+    ) -> Result<Self> {
+        let algorithm = self::hash_algorithm(hash_algorithm)?;
+        let sizes = Self::level_sizes(image_size, block_size, algorithm)?;
+
+        let expected_size = sizes
+            .iter()
+            .try_fold(0u64, |total, s| total.checked_add(*s))
+            .ok_or_else(|| Error::IntegerTooLarge("tree_size"))?;
+        if tree.len() as u64 != expected_size {
+            return Err(Error::IncorrectTreeSize(expected_size, tree.len()));
+        }
+
+        let mut levels = vec![];
+        let mut offset = tree.len();
+
+        for size in sizes {
+            let start = offset - size as usize;
+            levels.push(tree[start..offset].to_vec());
+            offset = start;
+        }
+
+        Ok(Self {
+            algorithm,
+            block_size,
+            image_size,
+            salt: salt.to_vec(),
+            root_digest: root_digest.to_vec(),
+            levels,
+        })
+    }
+
+    /// Load the hash tree for a hashtree descriptor. `tree` is the data at
+    /// [`HashtreeDescriptor::tree_offset`].
+    pub fn from_descriptor(descriptor: &HashtreeDescriptor, tree: &[u8]) -> Result<Self> {
+        Self::new(
+            &descriptor.hash_algorithm,
+            descriptor.data_block_size,
+            descriptor.image_size,
+            &descriptor.salt,
+            &descriptor.root_digest,
+            tree,
+        )
+    }
+
+    /// Calculate a new hash tree for the input in parallel.
+    pub fn calculate(
+        open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+        hash_algorithm: &str,
+        block_size: u32,
+        image_size: u64,
+        salt: &[u8],
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let algorithm = self::hash_algorithm(hash_algorithm)?;
+        Self::validate_block_size(block_size, algorithm)?;
+
+        let (root_digest, tree) = HashtreeDescriptor::calculate_hash_tree(
+            open_input,
+            image_size,
+            block_size,
+            algorithm,
+            salt,
+            None,
+            cancel_signal,
+        )?;
+
+        Self::new(
+            hash_algorithm,
+            block_size,
+            image_size,
+            salt,
+            &root_digest,
+            &tree,
+        )
+    }
+
+    /// Like [`Self::calculate()`], but also measure how long each phase takes.
+    pub fn calculate_with_metrics(
+        open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+        hash_algorithm: &str,
+        block_size: u32,
+        image_size: u64,
+        salt: &[u8],
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<(Self, HashTreeMetrics)> {
+        let algorithm = self::hash_algorithm(hash_algorithm)?;
+        Self::validate_block_size(block_size, algorithm)?;
+
+        let mut metrics = HashTreeMetrics::default();
+        let (root_digest, tree) = HashtreeDescriptor::calculate_hash_tree(
+            open_input,
+            image_size,
+            block_size,
+            algorithm,
+            salt,
+            Some(&mut metrics),
+            cancel_signal,
+        )?;
+
+        let hash_tree = Self::new(
+            hash_algorithm,
+            block_size,
+            image_size,
+            salt,
+            &root_digest,
+            &tree,
+        )?;
+
+        Ok((hash_tree, metrics))
+    }
+
+    pub fn root_digest(&self) -> &[u8] {
+        &self.root_digest
+    }
+
+    /// Number of levels in the tree, excluding the root digest.
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Number of data blocks covered by the tree.
+    pub fn num_blocks(&self) -> u64 {
+        let block_size = u64::from(self.block_size);
+        self.image_size / block_size + u64::from(self.image_size % block_size != 0)
+    }
+
+    /// Serialize the tree in the on-disk layout.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.levels.iter().rev().flatten().copied().collect()
+    }
+
+    /// Hash a single block. Undersized blocks are padded with zeros. The
+    /// block must not be larger than the block size.
+    pub fn hash_block(&self, block_data: &[u8]) -> Result<ring::digest::Digest> {
+        if block_data.len() > self.block_size as usize {
+            return Err(Error::BlockTooLarge(block_data.len(), self.block_size));
+        }
+
+        let mut context = Context::new(self.algorithm);
+        context.update(&self.salt);
+        context.update(block_data);
+        context.update(&vec![0u8; self.block_size as usize - block_data.len()]);
+
+        Ok(context.finish())
+    }
+
+    /// Get the node at `index` within `level`, without padding. Level 0
+    /// contains the nodes for the data blocks. Returns [`None`] if the node
+    /// does not exist.
+    pub fn node_at(&self, level: usize, index: u64) -> Option<&[u8]> {
+        let num_nodes = if level == 0 {
+            self.num_blocks()
+        } else {
+            let prev_size = self.levels.get(level - 1)?.len() as u64;
+            prev_size / u64::from(self.block_size)
+        };
+        if index >= num_nodes {
+            return None;
+        }
+
+        let node_size = Self::node_size_for(self.algorithm);
+        let start = usize::try_from(index).ok()? * node_size;
+
+        self.levels
+            .get(level)?
+            .get(start..start + self.algorithm.output_len)
+    }
+
+    /// Verify a single data block against the tree, all the way up to the
+    /// root digest. The tree itself is not trusted, so every node along the
+    /// path is checked.
+    pub fn verify_block(&self, block_index: u64, data: &[u8]) -> Result<()> {
+        let block_size = u64::from(self.block_size);
+        if block_index >= self.num_blocks() {
+            return Err(Error::BlockOutOfBounds(block_index));
+        }
+
+        let expected_size = block_size.min(self.image_size - block_index * block_size);
+        if data.len() as u64 != expected_size {
+            return Err(Error::IncorrectBlockSize(
+                block_index,
+                expected_size,
+                data.len(),
+            ));
+        }
+
+        let nodes_per_block = self.nodes_per_block()?;
+        let mut digest = self.hash_block(data)?;
+        let mut index = block_index;
+
+        for (level_index, level) in self.levels.iter().enumerate() {
+            if self.node_at(level_index, index) != Some(digest.as_ref()) {
+                return Err(Error::InvalidBlockDigest(block_index, level_index));
+            }
+
+            // Hash the block of this level that contains the node.
+            index /= nodes_per_block;
+            let start = (index * block_size) as usize;
+            digest = self.hash_block(&level[start..start + block_size as usize])?;
+        }
+
+        if digest.as_ref() != self.root_digest {
+            return Err(Error::InvalidRootDigest(
+                hex::encode(&self.root_digest),
+                hex::encode(digest),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a [`HashTree`] from data that is supplied one block at a time, like
+/// while a partition image is being written. Only the leaves are kept until
+/// [`Self::finalize()`] hashes the upper levels, so the data is never read a
+/// second time. The result is identical to [`HashTree::calculate()`].
+#[derive(Clone, Debug)]
+pub struct IncrementalHashTree {
+    hash_algorithm: String,
+    algorithm: &'static Algorithm,
+    block_size: u32,
+    salt: Vec<u8>,
+    image_size: u64,
+    /// Level 0 of the tree, without the padding at the end.
+    leaves: Vec<u8>,
+    /// The only block if the image is no larger than one block, since the root
+    /// digest is then the hash of the block itself.
+    first_block: Vec<u8>,
+}
+
+impl IncrementalHashTree {
+    pub fn new(hash_algorithm: &str, block_size: u32, salt: &[u8]) -> Result<Self> {
+        let algorithm = self::hash_algorithm(hash_algorithm)?;
+        HashTree::validate_block_size(block_size, algorithm)?;
+
+        Ok(Self {
+            hash_algorithm: hash_algorithm.to_owned(),
+            algorithm,
+            block_size,
+            salt: salt.to_vec(),
+            image_size: 0,
+            leaves: vec![],
+            first_block: vec![],
+        })
+    }
+
+    /// Number of bytes of data supplied so far.
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        let block_size = u64::from(self.block_size);
+        self.image_size / block_size + u64::from(self.image_size % block_size != 0)
+    }
+
+    /// Hash a block, padded with zeros, and append the node to `level`.
+    fn push_node(&self, level: &mut Vec<u8>, block: &[u8]) {
+        let mut context = Context::new(self.algorithm);
+        context.update(&self.salt);
+        context.update(block);
+        context.update(&vec![0u8; self.block_size as usize - block.len()]);
+
+        level.extend(context.finish().as_ref());
+        level.resize(
+            level.len() + HashTree::node_size_for(self.algorithm) - self.algorithm.output_len,
+            0,
+        );
+    }
+
+    /// Add the next data block. Every block must be exactly the block size,
+    /// except for the last one, which may be shorter.
+    pub fn update(&mut self, block: &[u8]) -> Result<()> {
+        let block_size = self.block_size as usize;
+        let index = self.num_blocks();
+
+        if block.is_empty()
+            || block.len() > block_size
+            || self.image_size % u64::from(self.block_size) != 0
+        {
+            return Err(Error::IncorrectBlockSize(
+                index,
+                block_size as u64,
+                block.len(),
+            ));
+        }
+
+        if index == 0 {
+            self.first_block = block.to_vec();
+        }
+
+        let mut leaves = mem::take(&mut self.leaves);
+        self.push_node(&mut leaves, block);
+        self.leaves = leaves;
+
+        self.image_size += block.len() as u64;
+
         Ok(())
     }
+
+    /// Hash the upper levels of the tree and compute the root digest.
+    pub fn finalize(mut self) -> Result<HashTree> {
+        let block_size = self.block_size as usize;
+
+        // Small images are hashed directly, exactly like a hash descriptor.
+        if self.image_size <= u64::from(self.block_size) {
+            let mut digest = vec![];
+            self.push_node(&mut digest, &self.first_block);
+            digest.truncate(self.algorithm.output_len);
+
+            return HashTree::new(
+                &self.hash_algorithm,
+                self.block_size,
+                self.image_size,
+                &self.salt,
+                &digest,
+                &[],
+            );
+        }
+
+        let mut level = mem::take(&mut self.leaves);
+        level.resize(padding::round(level.len(), block_size).unwrap(), 0);
+        let mut levels = vec![level];
+
+        while levels.last().unwrap().len() > block_size {
+            let mut level = vec![];
+            for block in levels.last().unwrap().chunks(block_size) {
+                self.push_node(&mut level, block);
+            }
+            level.resize(padding::round(level.len(), block_size).unwrap(), 0);
+
+            levels.push(level);
+        }
+
+        let mut root_digest = vec![];
+        self.push_node(&mut root_digest, levels.last().unwrap());
+        root_digest.truncate(self.algorithm.output_len);
+
+        // The tree is oriented such that the leaves are at the end.
+        let tree = levels.into_iter().rev().flatten().collect::<Vec<_>>();
+
+        HashTree::new(
+            &self.hash_algorithm,
+            self.block_size,
+            self.image_size,
+            &self.salt,
+            &root_digest,
+            &tree,
+        )
+    }
 }
 
 impl DescriptorTag for HashtreeDescriptor {
@@ -682,26 +1456,50 @@ impl fmt::Debug for HashDescriptor {
 }
 
 impl HashDescriptor {
-    /// Verify the root hash against the input reader.
-    pub fn verify(&self, reader: impl Read, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
-        let algorithm = match self.hash_algorithm.as_str() {
-            "sha256" => &ring::digest::SHA256,
-            "sha512" => &ring::digest::SHA512,
-            a => return Err(Error::UnsupportedHashAlgorithm(a.to_owned())),
-        };
+    /// Whether the [`HASH_FLAG_DO_NOT_USE_AB`] flag is set.
+    pub fn do_not_use_ab(&self) -> bool {
+        self.flags & HASH_FLAG_DO_NOT_USE_AB != 0
+    }
 
-        let mut context = Context::new(algorithm);
+    fn calculate(
+        &self,
+        reader: impl Read,
+        image_size: u64,
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<ring::digest::Digest> {
+        let mut context = Context::new(hash_algorithm(&self.hash_algorithm)?);
         context.update(&self.salt);
 
         stream::copy_n_inspect(
             reader,
             io::sink(),
-            self.image_size,
+            image_size,
             |data| context.update(data),
             cancel_signal,
         )?;
 
-        let digest = context.finish();
+        Ok(context.finish())
+    }
+
+    /// Recompute the root hash from the first `image_size` bytes of the input
+    /// reader. The existing salt and hash algorithm are kept.
+    pub fn update(
+        &mut self,
+        reader: impl Read,
+        image_size: u64,
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let digest = self.calculate(reader, image_size, cancel_signal)?;
+
+        self.image_size = image_size;
+        self.root_digest = digest.as_ref().to_vec();
+
+        Ok(())
+    }
+
+    /// Verify the root hash against the input reader.
+    pub fn verify(&self, reader: impl Read, cancel_signal: &Arc<AtomicBool>) -> Result<()> {
+        let digest = self.calculate(reader, self.image_size, cancel_signal)?;
 
         if self.root_digest != digest.as_ref() {
             return Err(Error::InvalidRootDigest(
@@ -718,6 +1516,20 @@ impl DescriptorTag for HashDescriptor {
     const TAG: u64 = 2;
 }
 
+/// Read a variable-length field, failing if the reader has fewer than `len`
+/// bytes left. Unlike [`Read::read_exact()`], a corrupt length does not cause a
+/// huge allocation.
+fn read_field(reader: impl Read, field: &'static str, len: u32) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(len.into()).read_to_end(&mut data)?;
+
+    if data.len() != len.to_usize().unwrap() {
+        return Err(Error::FieldTooLong(field, len));
+    }
+
+    Ok(data)
+}
+
 impl<R: Read> FromReader<R> for HashDescriptor {
     type Error = Error;
 
@@ -736,6 +1548,20 @@ impl<R: Read> FromReader<R> for HashDescriptor {
         let root_digest_len = reader.read_u32::<BigEndian>()?;
         let flags = reader.read_u32::<BigEndian>()?;
 
+        // An empty digest is used for partitions with persistent digests.
+        // Unknown algorithms are not rejected until the digest is verified.
+        if root_digest_len != 0 {
+            if let Ok(algorithm) = self::hash_algorithm(&hash_algorithm) {
+                if root_digest_len.to_usize() != Some(algorithm.output_len) {
+                    return Err(Error::IncorrectDigestSize(
+                        root_digest_len,
+                        hash_algorithm,
+                        algorithm.output_len,
+                    ));
+                }
+            }
+        }
+
         let mut reserved = [0u8; 60];
         reader.read_exact(&mut reserved)?;
 
@@ -744,11 +1570,8 @@ impl<R: Read> FromReader<R> for HashDescriptor {
             .read_string_exact(partition_name_len.to_usize().unwrap())
             .map_err(|e| Error::ReadFieldError("partition_name", e))?;
 
-        let mut salt = vec![0u8; salt_len.to_usize().unwrap()];
-        reader.read_exact(&mut salt)?;
-
-        let mut root_digest = vec![0u8; root_digest_len.to_usize().unwrap()];
-        reader.read_exact(&mut root_digest)?;
+        let salt = read_field(&mut reader, "salt_len", salt_len)?;
+        let root_digest = read_field(&mut reader, "root_digest_len", root_digest_len)?;
 
         let descriptor = Self {
             image_size,
@@ -817,6 +1640,20 @@ pub struct KernelCmdlineDescriptor {
     pub cmdline: String,
 }
 
+impl KernelCmdlineDescriptor {
+    /// Whether the [`KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED`]
+    /// flag is set.
+    pub fn use_only_if_hashtree_not_disabled(&self) -> bool {
+        self.flags & KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED != 0
+    }
+
+    /// Whether the [`KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_DISABLED`] flag
+    /// is set.
+    pub fn use_only_if_hashtree_disabled(&self) -> bool {
+        self.flags & KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_DISABLED != 0
+    }
+}
+
 impl DescriptorTag for KernelCmdlineDescriptor {
     const TAG: u64 = 3;
 }
@@ -863,7 +1700,16 @@ pub struct ChainPartitionDescriptor {
     pub rollback_index_location: u32,
     pub partition_name: String,
     pub public_key: Vec<u8>,
-    pub reserved: [u8; 64],
+    /// This was part of the reserved field prior to libavb 1.3.
+    pub flags: u32,
+    pub reserved: [u8; 60],
+}
+
+impl ChainPartitionDescriptor {
+    /// Whether the [`CHAIN_PARTITION_FLAG_DO_NOT_USE_AB`] flag is set.
+    pub fn do_not_use_ab(&self) -> bool {
+        self.flags & CHAIN_PARTITION_FLAG_DO_NOT_USE_AB != 0
+    }
 }
 
 impl fmt::Debug for ChainPartitionDescriptor {
@@ -872,6 +1718,7 @@ impl fmt::Debug for ChainPartitionDescriptor {
             .field("rollback_index_location", &self.rollback_index_location)
             .field("partition_name", &self.partition_name)
             .field("public_key", &hex::encode(&self.public_key))
+            .field("flags", &self.flags)
             .field("reserved", &hex::encode(self.reserved))
             .finish()
     }
@@ -888,8 +1735,9 @@ impl<R: Read> FromReader<R> for ChainPartitionDescriptor {
         let rollback_index_location = reader.read_u32::<BigEndian>()?;
         let partition_name_len = reader.read_u32::<BigEndian>()?;
         let public_key_len = reader.read_u32::<BigEndian>()?;
+        let flags = reader.read_u32::<BigEndian>()?;
 
-        let mut reserved = [0u8; 64];
+        let mut reserved = [0u8; 60];
         reader.read_exact(&mut reserved)?;
 
         // Not NULL-terminated.
@@ -904,6 +1752,7 @@ impl<R: Read> FromReader<R> for ChainPartitionDescriptor {
             rollback_index_location,
             partition_name,
             public_key,
+            flags,
             reserved,
         };
 
@@ -930,6 +1779,7 @@ impl<W: Write> ToWriter<W> for ChainPartitionDescriptor {
             .to_u32()
             .ok_or_else(|| Error::IntegerTooLarge("public_key_len"))?;
         writer.write_u32::<BigEndian>(public_key_len)?;
+        writer.write_u32::<BigEndian>(self.flags)?;
 
         writer.write_all(&self.reserved)?;
         writer.write_all(self.partition_name.as_bytes())?;
@@ -946,6 +1796,9 @@ pub enum Descriptor {
     Hash(HashDescriptor),
     KernelCmdline(KernelCmdlineDescriptor),
     ChainPartition(ChainPartitionDescriptor),
+    /// Descriptor with a tag that avbroot does not know how to parse, like a
+    /// vendor-specific one. The tag and data (including any padding) are kept
+    /// as is so that the descriptor is written back unchanged.
     Unknown(u64, Vec<u8>),
 }
 
@@ -958,6 +1811,42 @@ impl Descriptor {
             _ => None,
         }
     }
+
+    /// Whether libavb appends the current slot's A/B suffix to the partition
+    /// name when loading the partition. This is always false for descriptors
+    /// that do not refer to a partition.
+    pub fn uses_ab_suffix(&self) -> bool {
+        match self {
+            Self::Hashtree(d) => !d.do_not_use_ab(),
+            Self::Hash(d) => !d.do_not_use_ab(),
+            Self::ChainPartition(d) => !d.do_not_use_ab(),
+            _ => false,
+        }
+    }
+
+    /// Get the name of the partition that libavb loads when booting the slot
+    /// with the specified suffix (eg. `_a`).
+    pub fn partition_name_for_slot(&self, slot_suffix: &str) -> Option<String> {
+        let name = self.partition_name()?;
+
+        if self.uses_ab_suffix() {
+            Some(format!("{name}{slot_suffix}"))
+        } else {
+            Some(name.to_owned())
+        }
+    }
+}
+
+/// Split the A/B slot suffix (eg. `_a`) from a partition name. The suffix is an
+/// underscore followed by a single lowercase letter.
+pub fn split_slot_suffix(name: &str) -> (&str, Option<&str>) {
+    match name.as_bytes() {
+        [_, .., b'_', c] if c.is_ascii_lowercase() => {
+            let (base, suffix) = name.split_at(name.len() - 2);
+            (base, Some(suffix))
+        }
+        _ => (name, None),
+    }
 }
 
 impl<R: Read> FromReader<R> for Descriptor {
@@ -1060,6 +1949,16 @@ impl<W: Write> ToWriter<W> for Descriptor {
     }
 }
 
+/// A partition for [`Header::new_chained()`] to chain to.
+#[derive(Clone, Debug)]
+pub struct ChainedPartition {
+    /// Partition name without the slot suffix.
+    pub partition_name: String,
+    pub rollback_index_location: u32,
+    /// Key that the partition's own vbmeta header is signed with.
+    pub public_key: RsaPublicKey,
+}
+
 #[derive(Clone, Eq, PartialEq)]
 pub struct Header {
     pub required_libavb_version_major: u32,
@@ -1109,6 +2008,152 @@ impl fmt::Debug for Header {
 impl Header {
     pub const SIZE: usize = 256;
 
+    /// Create a top-level vbmeta header that contains nothing but a chain
+    /// partition descriptor for each of `partitions`, in order. This is what
+    /// avbtool produces with only `--chain_partition` options. The header is
+    /// unsigned and must be signed with a key matching `algorithm_type`.
+    ///
+    /// Rollback index location 0 is used by the top-level vbmeta image itself,
+    /// so each partition must use a different, non-zero location.
+    pub fn new_chained(
+        algorithm_type: AlgorithmType,
+        partitions: &[ChainedPartition],
+    ) -> Result<Self> {
+        let mut descriptors = vec![];
+
+        for (i, p) in partitions.iter().enumerate() {
+            let invalid = |reason| Error::InvalidChainedPartition(p.partition_name.clone(), reason);
+            let earlier = &partitions[..i];
+
+            if p.partition_name.is_empty() {
+                return Err(invalid("Partition name is empty"));
+            } else if p.rollback_index_location == 0 {
+                return Err(invalid("Rollback index location 0 is reserved"));
+            } else if earlier.iter().any(|e| e.partition_name == p.partition_name) {
+                return Err(invalid("Partition is listed more than once"));
+            } else if earlier
+                .iter()
+                .any(|e| e.rollback_index_location == p.rollback_index_location)
+            {
+                return Err(invalid("Rollback index location is already used"));
+            }
+
+            descriptors.push(Descriptor::ChainPartition(ChainPartitionDescriptor {
+                rollback_index_location: p.rollback_index_location,
+                partition_name: p.partition_name.clone(),
+                public_key: encode_public_key(&p.public_key)?,
+                flags: 0,
+                reserved: [0u8; 60],
+            }));
+        }
+
+        Ok(Self {
+            required_libavb_version_major: VERSION_MAJOR,
+            required_libavb_version_minor: 0,
+            algorithm_type,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors,
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: String::new(),
+            reserved: [0u8; 80],
+        })
+    }
+
+    /// Whether the header flags disable hashtree verification.
+    pub fn is_hashtree_disabled(&self) -> bool {
+        self.flags & HEADER_FLAG_HASHTREE_DISABLED != 0
+    }
+
+    /// Whether the header flags disable verification entirely.
+    pub fn is_verification_disabled(&self) -> bool {
+        self.flags & HEADER_FLAG_VERIFICATION_DISABLED != 0
+    }
+
+    /// Set or clear the [`HEADER_FLAG_HASHTREE_DISABLED`] flag. The other flag
+    /// bits, including unknown ones, are left untouched. The header must be
+    /// re-signed afterwards.
+    pub fn set_hashtree_disabled(&mut self, disabled: bool) {
+        if disabled {
+            self.flags |= HEADER_FLAG_HASHTREE_DISABLED;
+        } else {
+            self.flags &= !HEADER_FLAG_HASHTREE_DISABLED;
+        }
+    }
+
+    /// Set or clear the [`HEADER_FLAG_VERIFICATION_DISABLED`] flag. The other
+    /// flag bits, including unknown ones, are left untouched. The header must
+    /// be re-signed afterwards.
+    pub fn set_verification_disabled(&mut self, disabled: bool) {
+        if disabled {
+            self.flags |= HEADER_FLAG_VERIFICATION_DISABLED;
+        } else {
+            self.flags &= !HEADER_FLAG_VERIFICATION_DISABLED;
+        }
+    }
+
+    /// Remove all kernel cmdline descriptors for which `predicate` returns
+    /// true. The order of the remaining descriptors is preserved. The sizes of
+    /// the descriptors and the auxiliary block are recomputed when the header
+    /// is written, but the header must be re-signed.
+    pub fn remove_kernel_cmdlines(
+        &mut self,
+        mut predicate: impl FnMut(&KernelCmdlineDescriptor) -> bool,
+    ) -> Vec<KernelCmdlineDescriptor> {
+        let mut removed = vec![];
+
+        self.descriptors.retain(|d| match d {
+            Descriptor::KernelCmdline(c) if predicate(c) => {
+                removed.push(c.clone());
+                false
+            }
+            _ => true,
+        });
+
+        removed
+    }
+
+    /// Remove all property descriptors for which `predicate` returns true. Like
+    /// [`Self::remove_kernel_cmdlines()`], the header must be re-signed.
+    pub fn remove_properties(
+        &mut self,
+        mut predicate: impl FnMut(&PropertyDescriptor) -> bool,
+    ) -> Vec<PropertyDescriptor> {
+        let mut removed = vec![];
+
+        self.descriptors.retain(|d| match d {
+            Descriptor::Property(p) if predicate(p) => {
+                removed.push(p.clone());
+                false
+            }
+            _ => true,
+        });
+
+        removed
+    }
+
+    /// Replace the value of the first property descriptor with the same key,
+    /// keeping its position, or append the descriptor if there is none.
+    /// Returns the old value. The header must be re-signed.
+    pub fn set_property(&mut self, descriptor: PropertyDescriptor) -> Option<Vec<u8>> {
+        let existing = self.descriptors.iter_mut().find_map(|d| match d {
+            Descriptor::Property(p) if p.key == descriptor.key => Some(p),
+            _ => None,
+        });
+
+        match existing {
+            Some(p) => Some(mem::replace(&mut p.value, descriptor.value)),
+            None => {
+                self.descriptors.push(Descriptor::Property(descriptor));
+                None
+            }
+        }
+    }
+
     fn to_writer_internal(&self, mut writer: impl Write, skip_auth_block: bool) -> Result<()> {
         let mut descriptors_writer = Cursor::new(Vec::new());
         for d in &self.descriptors {
@@ -1382,7 +2427,7 @@ impl<R: Read> FromReader<R> for Header {
 
         if hash_offset
             .checked_add(hash_size)
-            .map_or(false, |s| s > auth_block.len())
+            .map_or(true, |s| s > auth_block.len())
         {
             return Err(Error::OutOfBounds("hash_offset", "hash_size"));
         }
@@ -1390,7 +2435,7 @@ impl<R: Read> FromReader<R> for Header {
 
         if signature_offset
             .checked_add(signature_size)
-            .map_or(false, |s| s > auth_block.len())
+            .map_or(true, |s| s > auth_block.len())
         {
             return Err(Error::OutOfBounds("signature_offset", "signature_size"));
         }
@@ -1400,7 +2445,7 @@ impl<R: Read> FromReader<R> for Header {
 
         if public_key_offset
             .checked_add(public_key_size)
-            .map_or(false, |s| s > aux_block.len())
+            .map_or(true, |s| s > aux_block.len())
         {
             return Err(Error::OutOfBounds("public_key_offset", "public_key_size"));
         }
@@ -1408,7 +2453,7 @@ impl<R: Read> FromReader<R> for Header {
 
         if public_key_metadata_offset
             .checked_add(public_key_metadata_size)
-            .map_or(false, |s| s > aux_block.len())
+            .map_or(true, |s| s > aux_block.len())
         {
             return Err(Error::OutOfBounds(
                 "public_key_metadata_offset",
@@ -1418,14 +2463,23 @@ impl<R: Read> FromReader<R> for Header {
         let public_key_metadata = &aux_block
             [public_key_metadata_offset..public_key_metadata_offset + public_key_metadata_size];
 
+        if descriptors_offset
+            .checked_add(descriptors_size)
+            .map_or(true, |s| s > aux_block.len())
+        {
+            return Err(Error::OutOfBounds("descriptors_offset", "descriptors_size"));
+        }
+        let descriptors_raw = &aux_block[descriptors_offset..descriptors_offset + descriptors_size];
+
+        // The spec doesn't require any particular order, so each descriptor is
+        // read independently by its tag until the descriptors are consumed.
+        // They are kept in the original order, which is used when writing.
         let mut descriptors: Vec<Descriptor> = vec![];
-        let mut descriptor_reader = Cursor::new(&aux_block);
-        let mut pos = descriptor_reader.seek(SeekFrom::Start(descriptors_offset as u64))?;
+        let mut descriptor_reader = Cursor::new(descriptors_raw);
 
-        while pos < (descriptors_offset + descriptors_size) as u64 {
+        while descriptor_reader.position() < descriptors_raw.len() as u64 {
             let descriptor = Descriptor::from_reader(&mut descriptor_reader)?;
             descriptors.push(descriptor);
-            pos = descriptor_reader.stream_position()?;
         }
 
         let header = Self {
@@ -1463,6 +2517,8 @@ pub struct Footer {
     pub original_image_size: u64,
     pub vbmeta_offset: u64,
     pub vbmeta_size: u64,
+    /// Always zero in practice, but kept as is so that existing footers are
+    /// written back byte for byte.
     pub reserved: [u8; 28],
 }
 
@@ -1590,24 +2646,88 @@ pub fn decode_public_key(data: &[u8]) -> Result<RsaPublicKey> {
     Ok(public_key)
 }
 
-/// Load the vbmeta header and footer from the specified reader. A footer is
-/// present only if the file is not a vbmeta partition image (ie. the header
-/// follows actual data).
-pub fn load_image(mut reader: impl Read + Seek) -> Result<(Header, Option<Footer>, u64)> {
+/// Size of the region at the end of an image that is searched for a footer if
+/// it is not in the last [`Footer::SIZE`] bytes.
+pub const FOOTER_SEARCH_WINDOW: u64 = 1024 * 1024;
+
+/// Maximum size of a vbmeta header, matching avbtool's `MAX_VBMETA_SIZE`.
+pub const VBMETA_MAX_SIZE: u64 = 64 * 1024;
+
+/// Find the vbmeta footer in the specified reader and return it along with its
+/// offset. The footer is normally in the last [`Footer::SIZE`] bytes, but if
+/// the image was padded to a larger block size afterwards, it is followed by
+/// zeros. In that case, the last [`FOOTER_SEARCH_WINDOW`] bytes are searched
+/// for the last footer magic that is only followed by zeros. If the footer
+/// found by the search is not consistent with its offset, [`None`] is returned
+/// because the magic is likely part of the image data.
+pub fn find_footer(mut reader: impl Read + Seek) -> Result<Option<(Footer, u64)>> {
     let image_size = reader.seek(SeekFrom::End(0))?;
+    if image_size < Footer::SIZE as u64 {
+        return Ok(None);
+    }
 
-    reader.seek(SeekFrom::End(-(Footer::SIZE as i64)))?;
+    let footer_offset = image_size - Footer::SIZE as u64;
+    reader.seek(SeekFrom::Start(footer_offset))?;
 
-    let footer = match Footer::from_reader(&mut reader) {
-        Ok(f) => Some(f),
+    match Footer::from_reader(&mut reader) {
+        Ok(f) => return Ok(Some((f, footer_offset))),
         Err(e @ Error::IoError(_)) => return Err(e),
-        Err(_) => None,
+        Err(_) => {}
+    }
+
+    let window_offset = image_size - image_size.min(FOOTER_SEARCH_WINDOW);
+    reader.seek(SeekFrom::Start(window_offset))?;
+
+    let mut window = vec![0u8; (image_size - window_offset) as usize];
+    reader.read_exact(&mut window)?;
+
+    let Some(end) = window.iter().rposition(|b| *b != 0).map(|i| i + 1) else {
+        return Ok(None);
     };
 
-    let vbmeta_offset = footer.as_ref().map_or(0, |f| f.vbmeta_offset);
+    let Some(index) = window[..end]
+        .windows(FOOTER_MAGIC.len())
+        .rposition(|w| w == FOOTER_MAGIC)
+    else {
+        return Ok(None);
+    };
+
+    // Everything after the footer must be padding.
+    let footer_end = index + Footer::SIZE;
+    if footer_end > window.len() || window[footer_end..].iter().any(|b| *b != 0) {
+        return Ok(None);
+    }
+
+    // The magic may just be part of the data. It is only a footer if the
+    // original image and the vbmeta header both end before it.
+    let offset = window_offset + index as u64;
+    let Ok(footer) = Footer::from_reader(&window[index..footer_end]) else {
+        return Ok(None);
+    };
+    let vbmeta_end = footer.vbmeta_offset.checked_add(footer.vbmeta_size);
+
+    if footer.original_image_size > offset || vbmeta_end.map_or(true, |end| end > offset) {
+        return Ok(None);
+    }
+
+    Ok(Some((footer, offset)))
+}
+
+/// Load the vbmeta header and footer from the specified reader. A footer is
+/// present only if the file is not a vbmeta partition image (ie. the header
+/// follows actual data). See [`find_footer()`] for how the footer is located.
+pub fn load_image(mut reader: impl Read + Seek) -> Result<(Header, Option<Footer>, u64)> {
+    let image_size = reader.seek(SeekFrom::End(0))?;
+
+    let footer = find_footer(&mut reader)?.map(|(f, _)| f);
+
+    // Don't let a corrupted header read past the vbmeta region.
+    let (vbmeta_offset, vbmeta_size) = footer
+        .as_ref()
+        .map_or((0, image_size), |f| (f.vbmeta_offset, f.vbmeta_size));
 
-    reader.seek(SeekFrom::Start(vbmeta_offset))?;
-    let header = Header::from_reader(&mut reader)?;
+    let section_reader = SectionReader::new(&mut reader, vbmeta_offset, vbmeta_size)?;
+    let header = Header::from_reader(section_reader)?;
 
     Ok((header, footer, image_size))
 }
@@ -1689,3 +2809,199 @@ pub fn write_appended_image(
     // avbtool hardcodes a 4096 block size for appended non-sparse images.
     write_image_internal(writer, header, Some(footer), Some(image_size), 4096)
 }
+
+/// Replace the vbmeta header of an image that already has a vbmeta footer. The
+/// new header is written at the existing vbmeta offset, so all data before it,
+/// including the hash tree and FEC data, is kept as is. The writer must refer
+/// to the whole image, which is `image_size` bytes.
+///
+/// The image is extended, if needed, so that the new header fits with a
+/// separate block for the footer, and so that the size is a multiple of
+/// `block_size`. Returns the new image size.
+pub fn replace_appended_header(
+    mut writer: impl Write + Seek,
+    header: &Header,
+    footer: &Footer,
+    image_size: u64,
+    block_size: u64,
+) -> Result<u64> {
+    writer.seek(SeekFrom::Start(footer.vbmeta_offset))?;
+    header.to_writer(&mut writer)?;
+    let vbmeta_end = writer.stream_position()?;
+
+    let round =
+        |n: u64| padding::round(n, block_size).ok_or_else(|| Error::IntegerTooLarge("image_size"));
+    let footer_space = cmp::max(block_size, Footer::SIZE as u64);
+    let new_image_size = cmp::max(round(image_size)?, round(vbmeta_end)? + footer_space);
+
+    // Clear out the remains of the old header and footer.
+    let footer_offset = new_image_size - Footer::SIZE as u64;
+    writer.write_zeros_exact(footer_offset - vbmeta_end)?;
+
+    let mut new_footer = footer.clone();
+    new_footer.vbmeta_size = vbmeta_end - footer.vbmeta_offset;
+    new_footer.to_writer(&mut writer)?;
+
+    Ok(new_image_size)
+}
+
+/// Copy an image with a vbmeta footer from `input` to `output`, re-signing the
+/// vbmeta header with `key`. Everything before the vbmeta header, including
+/// the hash tree and FEC data, is streamed as is. The hash and hashtree
+/// descriptors only cover that data, so they are kept without being recomputed.
+/// This is much faster than [`write_appended_image()`] for large partitions
+/// when only the signing key changes.
+///
+/// The algorithm type of the header is not changed, so `key` must be the same
+/// size as the original key, if any. The footer is kept at the same offset and
+/// the output is the same size as the input. Returns the newly signed header.
+pub fn resign_footer_in_place<R: Read + Seek, W: Write>(
+    mut input: R,
+    key: &RsaPrivateKey,
+    mut output: W,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Header> {
+    let image_size = input.seek(SeekFrom::End(0))?;
+    let Some((footer, footer_offset)) = find_footer(&mut input)? else {
+        return Err(Error::MissingFooter);
+    };
+
+    let mut header = {
+        let reader = SectionReader::new(&mut input, footer.vbmeta_offset, footer.vbmeta_size)?;
+        Header::from_reader(reader)?
+    };
+    header.sign(key)?;
+
+    let mut header_raw = Cursor::new(Vec::new());
+    header.to_writer(&mut header_raw)?;
+    let header_raw = header_raw.into_inner();
+
+    let vbmeta_end = footer
+        .vbmeta_offset
+        .checked_add(header_raw.len() as u64)
+        .ok_or_else(|| Error::IntegerTooLarge("vbmeta_size"))?;
+    if vbmeta_end > footer_offset {
+        return Err(Error::ImageSizeTooSmall(image_size));
+    }
+
+    input.rewind()?;
+    stream::copy_n(&mut input, &mut output, footer.vbmeta_offset, cancel_signal)?;
+
+    output.write_all(&header_raw)?;
+    output.write_zeros_exact(footer_offset - vbmeta_end)?;
+
+    let mut new_footer = footer;
+    new_footer.vbmeta_size = header_raw.len() as u64;
+    new_footer.to_writer(&mut output)?;
+
+    // Keep any padding after the footer.
+    let footer_end = footer_offset + Footer::SIZE as u64;
+    input.seek(SeekFrom::Start(footer_end))?;
+    stream::copy_n(
+        &mut input,
+        &mut output,
+        image_size - footer_end,
+        cancel_signal,
+    )?;
+
+    Ok(header)
+}
+
+/// Copy an image with a vbmeta footer and a hashtree descriptor to `output`,
+/// recomputing the hash tree and FEC data and re-signing the vbmeta header with
+/// `key`. If `fec_roots` is set and the image has no FEC data yet, FEC data with
+/// that many parity bytes per codeword is generated and placed after the hash
+/// tree. Existing FEC data keeps its number of roots.
+///
+/// The footer is kept at the same offset and the output is the same size as the
+/// input, which is normally the partition size. If the data, hash tree, FEC
+/// data, and vbmeta header no longer fit before the footer,
+/// [`Error::InsufficientSpace`] is returned before anything is written. See
+/// [`HashtreeDescriptor::verify()`] for the requirements for `open_input`.
+/// Returns the newly signed header.
+pub fn update_hashtree_image(
+    open_input: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+    mut output: impl Write,
+    key: &RsaPrivateKey,
+    fec_roots: Option<u32>,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Header> {
+    let mut input = open_input()?;
+    let image_size = input.seek(SeekFrom::End(0))?;
+    let Some((footer, footer_offset)) = find_footer(&mut input)? else {
+        return Err(Error::MissingFooter);
+    };
+
+    let mut header = {
+        let reader = SectionReader::new(&mut input, footer.vbmeta_offset, footer.vbmeta_size)?;
+        Header::from_reader(reader)?
+    };
+
+    let descriptor = header
+        .descriptors
+        .iter_mut()
+        .find_map(|d| match d {
+            Descriptor::Hashtree(d) => Some(d),
+            _ => None,
+        })
+        .ok_or(Error::MissingHashtreeDescriptor)?;
+
+    if let Some(roots) = fec_roots {
+        if descriptor.fec_num_roots == 0 {
+            descriptor.enable_fec(roots)?;
+        }
+    }
+
+    let data_size = footer.original_image_size;
+    let (hash_tree, fec_data) = descriptor.update(&open_input, data_size, cancel_signal)?;
+    let tree_offset = descriptor.tree_offset;
+    let data_end = if fec_data.is_empty() {
+        tree_offset + descriptor.tree_size
+    } else {
+        descriptor.fec_offset + descriptor.fec_size
+    };
+
+    header.sign(key)?;
+
+    let mut header_raw = Cursor::new(Vec::new());
+    header.to_writer(&mut header_raw)?;
+    let header_raw = header_raw.into_inner();
+
+    // avbtool hardcodes a 4096 block size for appended non-sparse images.
+    let vbmeta_offset =
+        padding::round(data_end, 4096).ok_or_else(|| Error::IntegerTooLarge("vbmeta_offset"))?;
+    let vbmeta_end = vbmeta_offset
+        .checked_add(header_raw.len() as u64)
+        .ok_or_else(|| Error::IntegerTooLarge("vbmeta_size"))?;
+    if vbmeta_end > footer_offset {
+        let needed = vbmeta_end.saturating_add(image_size - footer_offset);
+        return Err(Error::InsufficientSpace(needed, image_size));
+    }
+
+    input.rewind()?;
+    stream::copy_n(&mut input, &mut output, data_size, cancel_signal)?;
+
+    output.write_zeros_exact(tree_offset - data_size)?;
+    output.write_all(&hash_tree)?;
+    output.write_all(&fec_data)?;
+    output.write_zeros_exact(vbmeta_offset - data_end)?;
+    output.write_all(&header_raw)?;
+    output.write_zeros_exact(footer_offset - vbmeta_end)?;
+
+    let mut new_footer = footer;
+    new_footer.vbmeta_offset = vbmeta_offset;
+    new_footer.vbmeta_size = header_raw.len() as u64;
+    new_footer.to_writer(&mut output)?;
+
+    // Keep any padding after the footer.
+    let footer_end = footer_offset + Footer::SIZE as u64;
+    input.seek(SeekFrom::Start(footer_end))?;
+    stream::copy_n(
+        &mut input,
+        &mut output,
+        image_size - footer_end,
+        cancel_signal,
+    )?;
+
+    Ok(header)
+}
@@ -0,0 +1,214 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Support for the bootloader control block (BCB), the `bootloader_message`
+//! struct at the beginning of the misc partition. The bootloader and recovery
+//! use it to pass commands to each other, like booting into recovery with a
+//! set of arguments.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use thiserror::Error;
+
+use crate::stream::WriteZerosExt;
+
+const COMMAND_SIZE: usize = 32;
+const STATUS_SIZE: usize = 32;
+const RECOVERY_SIZE: usize = 768;
+const STAGE_SIZE: usize = 32;
+const SLOT_SUFFIX_SIZE: usize = 32;
+
+const LEGACY_SIZE: usize = 1088;
+const CURRENT_SIZE: usize = 2048;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to read {0:?} field: {1}")]
+    ReadFieldError(&'static str, io::Error),
+    #[error("{0:?} field exceeds maximum length of {1} bytes")]
+    FieldTooLong(&'static str, usize),
+    #[error("{0:?} field contains a NUL byte")]
+    FieldHasNul(&'static str),
+    #[error("{0:?} field is not part of the {1} layout")]
+    FieldNotInLayout(&'static str, Layout),
+    #[error("I/O error")]
+    IoError(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Layout of the `bootloader_message` struct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Android 7 and older. The struct is 1088 bytes and includes the
+    /// `slot_suffix` field. This is also compatible with the original layout
+    /// where `recovery` was 1024 bytes, as long as the recovery arguments fit
+    /// in 768 bytes.
+    Legacy,
+    /// Android 8 and newer. The struct is 2048 bytes. The A/B fields that
+    /// follow it in `bootloader_message_ab` belong to the boot control HAL and
+    /// are not touched.
+    Current,
+}
+
+impl Layout {
+    /// Size of the struct in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            Self::Legacy => LEGACY_SIZE,
+            Self::Current => CURRENT_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Legacy => f.write_str("legacy"),
+            Self::Current => f.write_str("current"),
+        }
+    }
+}
+
+/// Fields of the `bootloader_message` struct. Each field is a NUL-terminated
+/// string in a fixed size buffer, so the maximum length is one less than the
+/// buffer size. Reserved space is always written as zeros.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootloaderMessage {
+    /// Command for the bootloader, eg. `boot-recovery`.
+    pub command: String,
+    /// Status written by the bootloader after running the command.
+    pub status: String,
+    /// Command line for recovery. Each argument is terminated by a newline and
+    /// the first argument must be `recovery`.
+    pub recovery: String,
+    /// Stage of a multi-stage package install, eg. `2/3`.
+    pub stage: String,
+    /// Slot suffix for A/B devices. This only exists in [`Layout::Legacy`].
+    pub slot_suffix: String,
+}
+
+fn read_field(reader: &mut impl Read, field: &'static str, size: usize) -> Result<String> {
+    let mut buf = vec![0u8; size];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|e| Error::ReadFieldError(field, e))?;
+
+    // Anything after the NUL terminator is ignored, like strlcpy() does.
+    if let Some(n) = buf.iter().position(|&b| b == 0) {
+        buf.truncate(n);
+    }
+
+    String::from_utf8(buf)
+        .map_err(|e| Error::ReadFieldError(field, io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+fn write_field(
+    writer: &mut impl Write,
+    field: &'static str,
+    value: &str,
+    size: usize,
+) -> Result<()> {
+    if value.len() >= size {
+        return Err(Error::FieldTooLong(field, size - 1));
+    } else if value.contains('\0') {
+        return Err(Error::FieldHasNul(field));
+    }
+
+    writer.write_all(value.as_bytes())?;
+    writer.write_zeros_exact((size - value.len()) as u64)?;
+
+    Ok(())
+}
+
+impl BootloaderMessage {
+    /// Create a message that makes the bootloader boot into recovery with the
+    /// specified arguments, like `--update_package=<path>`.
+    pub fn boot_recovery<'a>(args: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut recovery = String::from("recovery\n");
+        for arg in args {
+            recovery.push_str(arg);
+            recovery.push('\n');
+        }
+
+        Self {
+            command: "boot-recovery".to_owned(),
+            recovery,
+            ..Default::default()
+        }
+    }
+
+    /// Get the recovery arguments, excluding the initial `recovery`.
+    pub fn recovery_args(&self) -> Vec<&str> {
+        let mut lines = self.recovery.split('\n').filter(|l| !l.is_empty());
+        if self.recovery.starts_with("recovery\n") {
+            lines.next();
+        }
+
+        lines.collect()
+    }
+
+    /// Read a message with the specified layout. Exactly [`Layout::size()`]
+    /// bytes are read.
+    pub fn from_reader_layout(mut reader: impl Read, layout: Layout) -> Result<Self> {
+        let command = read_field(&mut reader, "command", COMMAND_SIZE)?;
+        let status = read_field(&mut reader, "status", STATUS_SIZE)?;
+        let recovery = read_field(&mut reader, "recovery", RECOVERY_SIZE)?;
+        let stage = read_field(&mut reader, "stage", STAGE_SIZE)?;
+        let mut used = COMMAND_SIZE + STATUS_SIZE + RECOVERY_SIZE + STAGE_SIZE;
+
+        let slot_suffix = if layout == Layout::Legacy {
+            used += SLOT_SUFFIX_SIZE;
+            read_field(&mut reader, "slot_suffix", SLOT_SUFFIX_SIZE)?
+        } else {
+            String::new()
+        };
+
+        // The reserved space is ignored, even if it isn't zeroed.
+        let mut reserved = vec![0u8; layout.size() - used];
+        reader
+            .read_exact(&mut reserved)
+            .map_err(|e| Error::ReadFieldError("reserved", e))?;
+
+        Ok(Self {
+            command,
+            status,
+            recovery,
+            stage,
+            slot_suffix,
+        })
+    }
+
+    /// Write the message with the specified layout. Exactly [`Layout::size()`]
+    /// bytes are written.
+    pub fn to_writer_layout(&self, mut writer: impl Write, layout: Layout) -> Result<()> {
+        if layout != Layout::Legacy && !self.slot_suffix.is_empty() {
+            return Err(Error::FieldNotInLayout("slot_suffix", layout));
+        }
+
+        write_field(&mut writer, "command", &self.command, COMMAND_SIZE)?;
+        write_field(&mut writer, "status", &self.status, STATUS_SIZE)?;
+        write_field(&mut writer, "recovery", &self.recovery, RECOVERY_SIZE)?;
+        write_field(&mut writer, "stage", &self.stage, STAGE_SIZE)?;
+        let mut used = COMMAND_SIZE + STATUS_SIZE + RECOVERY_SIZE + STAGE_SIZE;
+
+        if layout == Layout::Legacy {
+            write_field(
+                &mut writer,
+                "slot_suffix",
+                &self.slot_suffix,
+                SLOT_SUFFIX_SIZE,
+            )?;
+            used += SLOT_SUFFIX_SIZE;
+        }
+
+        writer.write_zeros_exact((layout.size() - used) as u64)?;
+
+        Ok(())
+    }
+}
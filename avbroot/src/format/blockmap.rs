@@ -0,0 +1,138 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Block maps describe which blocks of a partition image contain data. They
+//! are written in the text format of AOSP's `.map` files, where each line is a
+//! name followed by a space-separated list of inclusive block ranges. Only the
+//! special `__ZERO` and `__NONZERO-0` names are used because the files within
+//! the filesystem are not known.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{format::ota::BlockRange, util};
+
+/// Find every `block_size` block of a partition image that contains non-zero
+/// data. Adjacent blocks are merged into a single range. A partial block at
+/// the end of the image counts as a full block. Sparse images should be read
+/// with [`super::sparse::SparseReader`], which returns zeros for don't-care
+/// chunks.
+pub fn generate(
+    mut reader: impl Read + Seek,
+    block_size: u32,
+    cancel_signal: &Arc<AtomicBool>,
+) -> io::Result<Vec<BlockRange>> {
+    if block_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Block size must not be zero",
+        ));
+    }
+
+    let block_size = u64::from(block_size);
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+
+    let mut buf = vec![0u8; block_size as usize];
+    let mut ranges = Vec::<BlockRange>::new();
+    let mut offset = 0;
+
+    while offset < size {
+        if cancel_signal.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Received cancel signal",
+            ));
+        }
+
+        let n = (size - offset).min(block_size) as usize;
+        reader.read_exact(&mut buf[..n])?;
+
+        if !util::is_zero(&buf[..n]) {
+            let block = offset / block_size;
+
+            match ranges.last_mut() {
+                Some(r) if r.end == block => r.end += 1,
+                _ => ranges.push(BlockRange {
+                    start: block,
+                    end: block + 1,
+                }),
+            }
+        }
+
+        offset += n as u64;
+    }
+
+    Ok(ranges)
+}
+
+/// Get the ranges within the first `num_blocks` blocks that are not covered
+/// by `ranges`, which must be sorted and non-overlapping.
+pub fn invert(ranges: &[BlockRange], num_blocks: u64) -> Vec<BlockRange> {
+    let mut result = vec![];
+    let mut start = 0;
+
+    for range in ranges {
+        if range.start > start {
+            result.push(BlockRange {
+                start,
+                end: range.start.min(num_blocks),
+            });
+        }
+
+        start = range.end;
+        if start >= num_blocks {
+            break;
+        }
+    }
+
+    if start < num_blocks {
+        result.push(BlockRange {
+            start,
+            end: num_blocks,
+        });
+    }
+
+    result.retain(|r| r.start < r.end);
+    result
+}
+
+/// Format block ranges as a space-separated list of inclusive ranges. Ranges
+/// of a single block are written as just the block number.
+pub fn format_ranges(ranges: &[BlockRange]) -> String {
+    let tokens = ranges
+        .iter()
+        .map(|r| {
+            if r.end - r.start == 1 {
+                r.start.to_string()
+            } else {
+                format!("{}-{}", r.start, r.end - 1)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    tokens.join(" ")
+}
+
+/// Write a block map for an image with `num_blocks` blocks, where `ranges`
+/// are the blocks that contain data, as returned by [`generate()`]. Names with
+/// no blocks are omitted. To write a compressed map, pass in a
+/// [`super::compression::CompressedWriter`].
+pub fn write_map(mut writer: impl Write, ranges: &[BlockRange], num_blocks: u64) -> io::Result<()> {
+    let zero = invert(ranges, num_blocks);
+
+    for (name, ranges) in [("__ZERO", zero.as_slice()), ("__NONZERO-0", ranges)] {
+        if !ranges.is_empty() {
+            writeln!(writer, "{name} {}", format_ranges(ranges))?;
+        }
+    }
+
+    writer.flush()
+}
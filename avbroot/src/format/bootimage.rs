@@ -4,12 +4,15 @@
  */
 
 use std::{
-    fmt,
+    fmt, fs,
     io::{self, Cursor, Read, Seek, Write},
+    iter,
+    path::Path,
     str::{self},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 use num_traits::ToPrimitive;
 use ring::digest::Context;
 use rsa::RsaPrivateKey;
@@ -22,8 +25,8 @@ use crate::{
         padding,
     },
     stream::{
-        CountingReader, CountingWriter, FromReader, HashingWriter, ReadStringExt, ToWriter,
-        WriteStringExt,
+        CountingReader, CountingWriter, FromReader, HashingWriter, ReadStringExt, SectionReader,
+        ToWriter, WriteStringExt, WriteZerosExt,
     },
     util::{self, NumBytes},
 };
@@ -45,6 +48,9 @@ pub const VENDOR_RAMDISK_NAME_SIZE: usize = 32;
 pub const VENDOR_RAMDISK_TABLE_ENTRY_BOARD_ID_SIZE: usize = 16;
 
 pub const PAGE_SIZE: u32 = 4096;
+/// Section alignment of v3 and v4 boot images built for kernels with 16 KiB
+/// pages.
+pub const LARGE_PAGE_SIZE: u32 = 16384;
 
 const HDR_V0_SIZE: u32 = 1632;
 const HDR_V1_EXTRA_SIZE: u32 = 16;
@@ -52,11 +58,23 @@ const HDR_V2_EXTRA_SIZE: u32 = 12;
 const HDR_V3_SIZE: u32 = 1580;
 const HDR_V4_EXTRA_SIZE: u32 = 4;
 const HDR_V4_SIGNATURE_SIZE: u64 = 4096;
+const HDR_V4_SIGNATURE_MAX_SIZE: u64 = 16384;
+
+/// Partition name in the hash descriptor of the GKI 2.0 boot signature that
+/// only covers the kernel image.
+pub const GKI_KERNEL_PARTITION_NAME: &str = "generic_kernel";
 
 const VENDOR_HDR_V3_SIZE: u32 = 2112;
 const VENDOR_HDR_V4_EXTRA_SIZE: u32 = 16;
 const VENDOR_RAMDISK_TABLE_ENTRY_V4_SIZE: u32 = 108;
 
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+const MTK_MAGIC: [u8; 4] = [0x88, 0x16, 0x88, 0x58];
+const MTK_HEADER_SIZE: usize = 512;
+const MTK_NAME_SIZE: usize = 32;
+const MTK_EXTRA_SIZE: usize = MTK_HEADER_SIZE - 8 - MTK_NAME_SIZE;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unknown boot image format")]
@@ -75,10 +93,14 @@ pub enum Error {
     IntegerTooLarge(&'static str),
     #[error("Invalid data: {0}")]
     InvalidData(&'static str),
+    #[error("Kernel cmdline is {0} bytes, but the maximum is {1} bytes")]
+    CmdlineTooLong(usize, usize),
     #[error("VTS signature is missing hash descriptor")]
     MissingHashDescriptor,
     #[error("AVB error")]
     Avb(#[from] avb::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
     #[error("I/O error")]
     Io(#[from] io::Error),
 }
@@ -91,6 +113,124 @@ pub trait BootImageExt {
     fn header_size(&self) -> u32;
 }
 
+/// OS version from the upper 21 bits of a boot image header's `os_version`
+/// field. Each component is 7 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OsVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Security patch level from the lower 11 bits of a boot image header's
+/// `os_version` field. The year is stored as a 7-bit offset from 2000 and the
+/// month as 4 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OsPatchLevel {
+    pub year: u16,
+    pub month: u8,
+}
+
+impl fmt::Display for OsPatchLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Get the OS version from the packed `os_version` header field. Returns
+/// [`None`] if the version is unset.
+pub fn os_version(field: u32) -> Option<OsVersion> {
+    let version = field >> 11;
+    if version == 0 {
+        return None;
+    }
+
+    Some(OsVersion {
+        major: (version >> 14) as u8,
+        minor: ((version >> 7) & 0x7f) as u8,
+        patch: (version & 0x7f) as u8,
+    })
+}
+
+/// Set the OS version in the packed `os_version` header field, keeping the
+/// patch level. Each component must be less than 128.
+pub fn set_os_version(field: &mut u32, version: OsVersion) -> Result<()> {
+    for component in [version.major, version.minor, version.patch] {
+        if component >= 128 {
+            return Err(Error::InvalidFieldValue("os_version", component.into()));
+        }
+    }
+
+    let version = (u32::from(version.major) << 14)
+        | (u32::from(version.minor) << 7)
+        | u32::from(version.patch);
+    *field = (version << 11) | (*field & 0x7ff);
+
+    Ok(())
+}
+
+/// Get the security patch level from the packed `os_version` header field.
+/// Returns [`None`] if the patch level is unset.
+pub fn os_patch_level(field: u32) -> Option<OsPatchLevel> {
+    let level = field & 0x7ff;
+    if level == 0 {
+        return None;
+    }
+
+    Some(OsPatchLevel {
+        year: 2000 + (level >> 4) as u16,
+        month: (level & 0xf) as u8,
+    })
+}
+
+/// Set the security patch level in the packed `os_version` header field,
+/// keeping the OS version. The year must be from 2000 to 2127 and the month
+/// from 1 to 12.
+pub fn set_os_patch_level(field: &mut u32, level: OsPatchLevel) -> Result<()> {
+    if !(2000..2128).contains(&level.year) {
+        return Err(Error::InvalidFieldValue(
+            "os_patch_level",
+            level.year.into(),
+        ));
+    } else if !(1..=12).contains(&level.month) {
+        return Err(Error::InvalidFieldValue(
+            "os_patch_level",
+            level.month.into(),
+        ));
+    }
+
+    let level = (u32::from(level.year - 2000) << 4) | u32::from(level.month);
+    *field = (*field & !0x7ff) | level;
+
+    Ok(())
+}
+
+/// Format the packed `os_version` header field, along with the decoded values
+/// if they are set.
+fn format_os_version(field: u32) -> String {
+    let mut result = format!("{field:#x}");
+
+    let decoded = [
+        os_version(field).map(|v| v.to_string()),
+        os_patch_level(field).map(|l| l.to_string()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    if !decoded.is_empty() {
+        result.push_str(&format!(" ({})", decoded.join(", ")));
+    }
+
+    result
+}
+
 #[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct V1Extra {
     pub recovery_dtbo_offset: u64,
@@ -123,6 +263,13 @@ impl fmt::Debug for V2Extra {
     }
 }
 
+/// Hash algorithm used for the `id` field of v0 through v2 boot images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdAlgorithm {
+    Sha1,
+    Sha256,
+}
+
 #[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct BootImageV0Through2 {
     // v0+ fields.
@@ -182,7 +329,11 @@ impl fmt::Display for BootImageV0Through2 {
         writeln!(f, "- Second stage address: {:#x}", self.second_addr)?;
         writeln!(f, "- Kernel tags address:  {:#x}", self.tags_addr)?;
         writeln!(f, "- Page size:            {}", self.page_size)?;
-        writeln!(f, "- OS version:           {:#x}", self.os_version)?;
+        writeln!(
+            f,
+            "- OS version:           {}",
+            format_os_version(self.os_version),
+        )?;
         writeln!(f, "- Name:                 {:?}", self.name)?;
         writeln!(f, "- Kernel cmdline:       {:?}", self.cmdline)?;
         writeln!(f, "- ID:                   {:?}", self.id)?;
@@ -204,6 +355,71 @@ impl fmt::Display for BootImageV0Through2 {
     }
 }
 
+impl BootImageV0Through2 {
+    /// Guess the algorithm that was used to compute [`Self::id`]. mkbootimg
+    /// uses SHA-1, which only fills the first 20 bytes, but some vendor tools
+    /// use SHA-256 instead.
+    pub fn id_algorithm(&self) -> IdAlgorithm {
+        if self.id[5..].iter().any(|w| *w != 0) {
+            IdAlgorithm::Sha256
+        } else {
+            IdAlgorithm::Sha1
+        }
+    }
+
+    /// Compute the `id` field the same way as mkbootimg. Each section is hashed
+    /// in order, followed by its size as a little-endian u32: kernel, ramdisk,
+    /// second stage, recovery dtbo (v1+), and device tree (v2). Empty sections
+    /// only contribute their size.
+    pub fn compute_id(&self, algorithm: IdAlgorithm) -> Result<[u32; 8]> {
+        let mut sections = vec![
+            ("kernel_size", &self.kernel),
+            ("ramdisk_size", &self.ramdisk),
+            ("second_size", &self.second),
+        ];
+
+        if let Some(v1) = &self.v1_extra {
+            sections.push(("recovery_dtbo_size", &v1.recovery_dtbo));
+        }
+        if let Some(v2) = &self.v2_extra {
+            sections.push(("dtb_size", &v2.dtb));
+        }
+
+        let mut context = Context::new(match algorithm {
+            IdAlgorithm::Sha1 => &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+            IdAlgorithm::Sha256 => &ring::digest::SHA256,
+        });
+
+        for (name, data) in sections {
+            let size = data
+                .len()
+                .to_u32()
+                .ok_or_else(|| Error::IntegerTooLarge(name))?;
+
+            context.update(data);
+            context.update(&size.to_le_bytes());
+        }
+
+        let digest = context.finish();
+        let mut id = [0u32; 8];
+
+        for (item, chunk) in id.iter_mut().zip(digest.as_ref().chunks_exact(4)) {
+            *item = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+
+        Ok(id)
+    }
+
+    /// Recompute [`Self::id`] with the algorithm it was originally computed
+    /// with. This must be done after modifying any of the sections because some
+    /// bootloaders reject images with a mismatched ID.
+    pub fn update_id(&mut self) -> Result<()> {
+        self.id = self.compute_id(self.id_algorithm())?;
+
+        Ok(())
+    }
+}
+
 impl BootImageExt for BootImageV0Through2 {
     fn header_version(&self) -> u32 {
         if self.v2_extra.is_some() {
@@ -476,8 +692,32 @@ impl<W: Write> ToWriter<W> for BootImageV0Through2 {
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct V4Extra {
+    /// The boot signature. This is a standalone vbmeta structure covering the
+    /// boot image, excluding the signature itself. It is separate from the AVB
+    /// footer of the boot partition.
     #[serde(skip)]
     pub signature: Option<Header>,
+    /// Additional vbmeta structures following [`Self::signature`]. GKI 2.0
+    /// boot signatures include one that only covers the kernel (see
+    /// [`GKI_KERNEL_PARTITION_NAME`]).
+    #[serde(skip)]
+    pub extra_signatures: Vec<Header>,
+    /// Size of the boot signature region. When writing a boot signature, it is
+    /// padded to at least this size. This preserves the 16 KiB region used by
+    /// GKI 2.0 images, even when the signature itself is smaller.
+    #[serde(default)]
+    pub signature_size: u32,
+}
+
+impl V4Extra {
+    /// Iterate through all vbmeta structures in the boot signature.
+    pub fn signatures(&self) -> impl Iterator<Item = &Header> {
+        self.signature.iter().chain(&self.extra_signatures)
+    }
+}
+
+fn default_page_size() -> u32 {
+    PAGE_SIZE
 }
 
 #[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
@@ -487,6 +727,11 @@ pub struct BootImageV3Through4 {
     pub reserved: [u32; 4],
     pub cmdline: String,
     pub v4_extra: Option<V4Extra>,
+    /// Alignment of the sections. This is not stored in the header. The format
+    /// specifies [`PAGE_SIZE`], but images for 16 KiB page size kernels are
+    /// aligned to [`LARGE_PAGE_SIZE`]. This is detected when parsing.
+    #[serde(default = "default_page_size")]
+    pub page_size: u32,
     // Images.
     #[serde(skip)]
     pub kernel: Vec<u8>,
@@ -501,6 +746,7 @@ impl fmt::Debug for BootImageV3Through4 {
             .field("reserved", &self.reserved)
             .field("cmdline", &self.cmdline)
             .field("v4_extra", &self.v4_extra)
+            .field("page_size", &self.page_size)
             .field("kernel", &NumBytes(self.kernel.len()))
             .field("ramdisk", &NumBytes(self.ramdisk.len()))
             .finish()
@@ -512,13 +758,29 @@ impl fmt::Display for BootImageV3Through4 {
         writeln!(f, "Boot image v{} header:", self.header_version())?;
         writeln!(f, "- Kernel size:       {}", self.kernel.len())?;
         writeln!(f, "- Ramdisk size:      {}", self.ramdisk.len())?;
-        writeln!(f, "- OS version:        {:#x}", self.os_version)?;
+        writeln!(f, "- Page size:         {}", self.page_size)?;
+        writeln!(
+            f,
+            "- OS version:        {}",
+            format_os_version(self.os_version),
+        )?;
         writeln!(f, "- Reserved:          {:?}", self.reserved)?;
         write!(f, "- Kernel cmdline:    {:?}", self.cmdline)?;
 
         if let Some(v4) = &self.v4_extra {
             writeln!(f)?;
             write!(f, "- Has VTS signature: {:?}", v4.signature.is_some())?;
+
+            if !v4.extra_signatures.is_empty() {
+                let names = v4
+                    .extra_signatures
+                    .iter()
+                    .flat_map(|h| h.descriptors.iter().filter_map(|d| d.partition_name()))
+                    .collect::<Vec<_>>();
+
+                writeln!(f)?;
+                write!(f, "- Extra signatures:  {names:?}")?;
+            }
         }
 
         Ok(())
@@ -593,11 +855,27 @@ impl<R: Read> FromReader<R> for BootImageV3Through4 {
 
         kernel.resize(kernel_size.to_usize().unwrap(), 0);
         reader.read_exact(&mut kernel)?;
-        padding::read_discard(&mut reader, PAGE_SIZE.into())?;
+
+        // The header doesn't record the alignment. A kernel never starts with
+        // zeros, so if the data following the first page is all zeros up to the
+        // next 16 KiB boundary, then the image uses 16 KiB alignment.
+        let gap = (LARGE_PAGE_SIZE - PAGE_SIZE) as usize;
+        let page_size = if kernel.len() > gap && util::is_zero(&kernel[..gap]) {
+            kernel.drain(..gap);
+            kernel.resize(kernel_size.to_usize().unwrap(), 0);
+            let offset = kernel.len() - gap;
+            reader.read_exact(&mut kernel[offset..])?;
+
+            LARGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+
+        padding::read_discard(&mut reader, page_size.into())?;
 
         ramdisk.resize(ramdisk_size.to_usize().unwrap(), 0);
         reader.read_exact(&mut ramdisk)?;
-        padding::read_discard(&mut reader, PAGE_SIZE.into())?;
+        padding::read_discard(&mut reader, page_size.into())?;
 
         // Don't preserve the signature. It is only used for VTS tests and is
         // not relevant for booting.
@@ -606,15 +884,32 @@ impl<R: Read> FromReader<R> for BootImageV3Through4 {
             let mut data = vec![0u8; s.to_usize().unwrap()];
             reader.read_exact(&mut data)?;
 
-            let signature = if s > 0 && !util::is_zero(&data) {
-                Some(Header::from_reader(Cursor::new(data))?)
-            } else {
-                None
-            };
+            let mut signatures = vec![];
+
+            if s > 0 && !util::is_zero(&data) {
+                // GKI 2.0 boot signatures contain multiple vbmeta structures
+                // back to back, followed by zero padding.
+                let mut sig_reader = Cursor::new(&data);
+
+                loop {
+                    signatures.push(Header::from_reader(&mut sig_reader)?);
 
-            padding::read_discard(&mut reader, PAGE_SIZE.into())?;
+                    let offset = sig_reader.position() as usize;
+                    if util::is_zero(&data[offset..]) {
+                        break;
+                    }
+                }
+            }
 
-            Some(V4Extra { signature })
+            padding::read_discard(&mut reader, page_size.into())?;
+
+            let mut iter = signatures.into_iter();
+
+            Some(V4Extra {
+                signature: iter.next(),
+                extra_signatures: iter.collect(),
+                signature_size: s,
+            })
         } else {
             None
         };
@@ -624,6 +919,7 @@ impl<R: Read> FromReader<R> for BootImageV3Through4 {
             reserved,
             cmdline,
             v4_extra,
+            page_size,
             kernel,
             ramdisk,
         };
@@ -636,6 +932,10 @@ impl BootImageV3Through4 {
     fn to_writer_internal(&self, writer: impl Write, skip_v4_sig: bool) -> Result<()> {
         let mut writer = CountingWriter::new(writer);
 
+        if self.page_size != PAGE_SIZE && self.page_size != LARGE_PAGE_SIZE {
+            return Err(Error::InvalidFieldValue("page_size", self.page_size));
+        }
+
         let kernel_size = self
             .kernel
             .len()
@@ -666,17 +966,32 @@ impl BootImageV3Through4 {
         let v4_signature = if let Some(v4) = &self.v4_extra {
             let mut sig_writer = Cursor::new(Vec::new());
 
-            if let Some(s) = &v4.signature {
+            if v4.signature.is_none() && !v4.extra_signatures.is_empty() {
+                return Err(Error::InvalidData(
+                    "Extra signatures require a boot signature",
+                ));
+            }
+
+            for s in v4.signatures() {
                 s.to_writer(&mut sig_writer)?;
+            }
 
+            if v4.signature.is_some() {
                 let size = sig_writer.stream_position()?;
 
-                // The VTS signature is always a fixed size.
-                if size > HDR_V4_SIGNATURE_SIZE {
+                // The signature is padded to a multiple of the VTS signature
+                // size, which is 4 KiB. GKI 2.0 signatures can be up to 16 KiB.
+                if size > HDR_V4_SIGNATURE_MAX_SIZE {
                     return Err(Error::IntegerTooLarge("signature_size"));
                 }
 
                 padding::write_zeros(&mut sig_writer, HDR_V4_SIGNATURE_SIZE)?;
+
+                let size = sig_writer.stream_position()?;
+                let min_size = u64::from(v4.signature_size);
+                if size < min_size {
+                    sig_writer.write_zeros_exact(min_size - size)?;
+                }
             }
 
             let sig = sig_writer.into_inner();
@@ -686,85 +1001,98 @@ impl BootImageV3Through4 {
             None
         };
 
-        padding::write_zeros(&mut writer, PAGE_SIZE.into())?;
+        padding::write_zeros(&mut writer, self.page_size.into())?;
 
         writer.write_all(&self.kernel)?;
-        padding::write_zeros(&mut writer, PAGE_SIZE.into())?;
+        padding::write_zeros(&mut writer, self.page_size.into())?;
 
         writer.write_all(&self.ramdisk)?;
-        padding::write_zeros(&mut writer, PAGE_SIZE.into())?;
+        padding::write_zeros(&mut writer, self.page_size.into())?;
 
         if !skip_v4_sig {
             if let Some(sig) = v4_signature {
                 writer.write_all(&sig)?;
-                padding::write_zeros(&mut writer, PAGE_SIZE.into())?;
+                padding::write_zeros(&mut writer, self.page_size.into())?;
             }
         }
 
         Ok(())
     }
 
-    /// Sign the boot image with a legacy VTS signature. Returns true if the
-    /// image was successfully signed. Returns false if there's no vbmeta
+    /// Sign the boot image with a legacy VTS signature or a GKI 2.0 boot
+    /// signature. Each vbmeta structure in the signature is regenerated. The
+    /// one for [`GKI_KERNEL_PARTITION_NAME`] covers only the kernel, while the
+    /// others cover everything but the signature at the end. Returns true if
+    /// the image was successfully signed. Returns false if there's no vbmeta
     /// structure to sign in [`V4Extra::signature`].
     pub fn sign(&mut self, key: &RsaPrivateKey) -> Result<bool> {
-        let mut context = Context::new(&ring::digest::SHA256);
-        let image_size;
+        let Some(v4) = &self.v4_extra else {
+            // V3.
+            return Ok(false);
+        };
+        if v4.signature.is_none() {
+            // V4 with no signature.
+            return Ok(false);
+        }
 
-        if let Some(v4) = &self.v4_extra {
-            if let Some(signature) = &v4.signature {
-                // The hash includes everything but the signature at the end.
-                let descriptor = signature
-                    .descriptors
-                    .iter()
-                    .find_map(|d| match d {
-                        Descriptor::Hash(h) => Some(h),
-                        _ => None,
-                    })
-                    .ok_or(Error::MissingHashDescriptor)?;
-
-                if descriptor.hash_algorithm != "sha256" {
-                    return Err(avb::Error::UnsupportedHashAlgorithm(
-                        descriptor.hash_algorithm.clone(),
-                    )
-                    .into());
-                }
+        let mut digests = vec![];
+
+        for signature in v4.signatures() {
+            let descriptor = signature
+                .descriptors
+                .iter()
+                .find_map(|d| match d {
+                    Descriptor::Hash(h) => Some(h),
+                    _ => None,
+                })
+                .ok_or(Error::MissingHashDescriptor)?;
+
+            if descriptor.hash_algorithm != "sha256" {
+                return Err(avb::Error::UnsupportedHashAlgorithm(
+                    descriptor.hash_algorithm.clone(),
+                )
+                .into());
+            }
+
+            let mut context = Context::new(&ring::digest::SHA256);
+            context.update(&descriptor.salt);
 
-                context.update(&descriptor.salt);
+            if descriptor.partition_name == GKI_KERNEL_PARTITION_NAME {
+                context.update(&self.kernel);
 
+                digests.push((self.kernel.len() as u64, context));
+            } else {
+                // The hash includes everything but the signature at the end.
                 let hashing_writer = HashingWriter::new(io::sink(), context);
                 let mut counting_writer = CountingWriter::new(hashing_writer);
                 self.to_writer_internal(&mut counting_writer, true)?;
 
-                let (hashing_writer, s) = counting_writer.finish();
-                let (_, c) = hashing_writer.finish();
+                let (hashing_writer, image_size) = counting_writer.finish();
+                let (_, context) = hashing_writer.finish();
 
-                context = c;
-                image_size = s;
-            } else {
-                // V4 with no signature.
-                return Ok(false);
+                digests.push((image_size, context));
             }
-        } else {
-            // V3.
-            return Ok(false);
         }
 
         // Reborrow mutably.
         let v4 = self.v4_extra.as_mut().unwrap();
-        let signature = v4.signature.as_mut().unwrap();
-        let descriptor = signature
-            .descriptors
-            .iter_mut()
-            .find_map(|d| match d {
-                Descriptor::Hash(h) => Some(h),
-                _ => None,
-            })
-            .unwrap();
-
-        descriptor.image_size = image_size;
-        descriptor.root_digest = context.finish().as_ref().to_vec();
-        signature.sign(key)?;
+        let signatures =
+            iter::once(v4.signature.as_mut().unwrap()).chain(v4.extra_signatures.iter_mut());
+
+        for (signature, (image_size, context)) in signatures.zip(digests) {
+            let descriptor = signature
+                .descriptors
+                .iter_mut()
+                .find_map(|d| match d {
+                    Descriptor::Hash(h) => Some(h),
+                    _ => None,
+                })
+                .unwrap();
+
+            descriptor.image_size = image_size;
+            descriptor.root_digest = context.finish().as_ref().to_vec();
+            signature.sign(key)?;
+        }
 
         Ok(true)
     }
@@ -864,12 +1192,51 @@ impl fmt::Display for VendorBootImageV3Through4 {
             }
 
             write!(f, "- Bootconfig size:     {}", v4.bootconfig.len())?;
+
+            if let Some(page_size) = self.bootconfig_page_size() {
+                writeln!(f)?;
+                write!(f, "- Declared page size:  {page_size}")?;
+            }
         }
 
         Ok(())
     }
 }
 
+impl VendorBootImageV3Through4 {
+    /// Get the kernel page size declared by `androidboot.page_size` in the
+    /// bootconfig. Devices with 16 KiB page size kernels may set this even if
+    /// the header's page size is 4096.
+    pub fn bootconfig_page_size(&self) -> Option<u32> {
+        let v4 = self.v4_extra.as_ref()?;
+
+        v4.bootconfig.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            if key.trim() != "androidboot.page_size" {
+                return None;
+            }
+
+            value.trim().trim_matches('"').parse().ok()
+        })
+    }
+
+    /// Get the ramdisk fragments of the specified type (eg.
+    /// [`VENDOR_RAMDISK_TYPE_DLKM`]) along with their metadata. v3 images have
+    /// no ramdisk table, so nothing is returned for them.
+    pub fn ramdisks_by_type(
+        &self,
+        ramdisk_type: u32,
+    ) -> impl Iterator<Item = (&RamdiskMeta, &[u8])> {
+        self.v4_extra.iter().flat_map(move |v4| {
+            v4.ramdisk_metas
+                .iter()
+                .zip(&self.ramdisks)
+                .filter(move |(m, _)| m.ramdisk_type == ramdisk_type)
+                .map(|(m, r)| (m, r.as_slice()))
+        })
+    }
+}
+
 impl BootImageExt for VendorBootImageV3Through4 {
     fn header_version(&self) -> u32 {
         if self.v4_extra.is_some() {
@@ -1246,3 +1613,421 @@ impl<W: Write> ToWriter<W> for BootImage {
         }
     }
 }
+
+/// Optional sections that can be removed with [`BootImage::strip()`]. Sections
+/// that bootloaders require, like the kernel, the ramdisks, and the DTB, can
+/// never be removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OptionalSection {
+    /// Second stage bootloader in v0 through v2 boot images.
+    Second,
+    /// Recovery DTBO in v1 and v2 boot images. This is only used when booting
+    /// recovery on non-A/B devices.
+    RecoveryDtbo,
+    /// Boot signature in v4 boot images. This is only used for VTS and GKI
+    /// certification and is not checked by bootloaders.
+    BootSignature,
+}
+
+impl BootImage {
+    fn serialized_size(&self) -> Result<u64> {
+        let mut writer = CountingWriter::new(io::sink());
+        self.to_writer(&mut writer)?;
+
+        Ok(writer.finish().1)
+    }
+
+    /// Remove the specified optional sections. Sections that the header version
+    /// doesn't have or that are already empty are ignored. If the v0 through v2
+    /// header ID was valid before, it is recomputed so that it stays valid.
+    /// Returns the number of bytes saved when the image is written.
+    pub fn strip(&mut self, sections: &[OptionalSection]) -> Result<u64> {
+        let old_size = self.serialized_size()?;
+
+        match self {
+            Self::V0Through2(b) => {
+                let id_valid = b.compute_id(b.id_algorithm())? == b.id;
+
+                if sections.contains(&OptionalSection::Second) {
+                    b.second.clear();
+                }
+                if let Some(v1) = &mut b.v1_extra {
+                    if sections.contains(&OptionalSection::RecoveryDtbo) {
+                        v1.recovery_dtbo.clear();
+                        // mkbootimg leaves the offset as 0 without a DTBO.
+                        v1.recovery_dtbo_offset = 0;
+                    }
+                }
+
+                if id_valid {
+                    b.update_id()?;
+                }
+            }
+            Self::V3Through4(b) => {
+                if let Some(v4) = &mut b.v4_extra {
+                    if sections.contains(&OptionalSection::BootSignature) {
+                        v4.signature = None;
+                        v4.extra_signatures.clear();
+                        v4.signature_size = 0;
+                    }
+                }
+            }
+            Self::VendorV3Through4(_) => {}
+        }
+
+        let new_size = self.serialized_size()?;
+
+        Ok(old_size.saturating_sub(new_size))
+    }
+
+    /// Alignment of the sections in the image.
+    fn section_alignment(&self) -> u64 {
+        match self {
+            Self::V0Through2(b) => b.page_size.into(),
+            Self::V3Through4(b) => b.page_size.into(),
+            Self::VendorV3Through4(b) => b.page_size.into(),
+        }
+    }
+
+    /// Get the full kernel command line. For v0 through v2 images, this is the
+    /// `cmdline` field followed by the `extra_cmdline` field, which is how
+    /// bootloaders combine them.
+    pub fn cmdline(&self) -> String {
+        match self {
+            Self::V0Through2(b) => format!("{}{}", b.cmdline, b.extra_cmdline),
+            Self::V3Through4(b) => b.cmdline.clone(),
+            Self::VendorV3Through4(b) => b.cmdline.clone(),
+        }
+    }
+
+    /// Set the full kernel command line. For v0 through v2 images, it is split
+    /// across the `cmdline` and `extra_cmdline` fields the same way as
+    /// mkbootimg, except that the split never happens in the middle of a UTF-8
+    /// character. Each field keeps one byte for the NUL terminator. Unlike
+    /// mkbootimg, which silently truncates, an error is returned if the command
+    /// line doesn't fit.
+    pub fn set_cmdline(&mut self, cmdline: &str) -> Result<()> {
+        let max_size = match self {
+            Self::V0Through2(_) => BOOT_ARGS_SIZE - 1 + BOOT_EXTRA_ARGS_SIZE - 1,
+            Self::V3Through4(_) => BOOT_ARGS_SIZE + BOOT_EXTRA_ARGS_SIZE - 1,
+            Self::VendorV3Through4(_) => VENDOR_BOOT_ARGS_SIZE - 1,
+        };
+
+        if cmdline.len() > max_size {
+            return Err(Error::CmdlineTooLong(cmdline.len(), max_size));
+        }
+
+        match self {
+            Self::V0Through2(b) => {
+                let split = (0..=cmdline.len().min(BOOT_ARGS_SIZE - 1))
+                    .rev()
+                    .find(|i| cmdline.is_char_boundary(*i))
+                    .unwrap();
+                let (first, extra) = cmdline.split_at(split);
+
+                // Moving the split point back can push the rest over the limit.
+                if extra.len() > BOOT_EXTRA_ARGS_SIZE - 1 {
+                    return Err(Error::CmdlineTooLong(cmdline.len(), max_size));
+                }
+
+                b.cmdline = first.to_owned();
+                b.extra_cmdline = extra.to_owned();
+            }
+            Self::V3Through4(b) => b.cmdline = cmdline.to_owned(),
+            Self::VendorV3Through4(b) => b.cmdline = cmdline.to_owned(),
+        }
+
+        Ok(())
+    }
+
+    /// Size of the ramdisk section, excluding padding. For vendor boot images,
+    /// this is the combined size of all ramdisks.
+    pub fn ramdisk_size(&self) -> u64 {
+        match self {
+            Self::V0Through2(b) => b.ramdisk.len() as u64,
+            Self::V3Through4(b) => b.ramdisk.len() as u64,
+            Self::VendorV3Through4(b) => b.ramdisks.iter().map(|r| r.len() as u64).sum(),
+        }
+    }
+}
+
+/// Compute the maximum ramdisk size that fits in `partition_size` bytes,
+/// given the size of the other sections in `image`. The ramdisk section is
+/// padded to the page size, so the result is always a multiple of it. For
+/// vendor boot images, this is the limit for the combined size of all
+/// ramdisks. Returns 0 if the other sections are already too large.
+pub fn ramdisk_budget(image: &BootImage, partition_size: u64) -> Result<u64> {
+    let alignment = image.section_alignment();
+    let padded_ramdisk_size = padding::round(image.ramdisk_size(), alignment)
+        .ok_or_else(|| Error::IntegerTooLarge("ramdisk_size"))?;
+    let other_size = image.serialized_size()? - padded_ramdisk_size;
+    let available = partition_size.saturating_sub(other_size);
+
+    Ok(available / alignment * alignment)
+}
+
+/// Name of the manifest written by [`extract_all()`].
+pub const EXTRACT_MANIFEST_NAME: &str = "manifest.json";
+
+/// Header fields of each boot image type, serialized without the enum tag so
+/// that they end up at the top level of the manifest.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ExtractHeader<'a> {
+    V0Through2(&'a BootImageV0Through2),
+    V3Through4(&'a BootImageV3Through4),
+    VendorV3Through4(&'a VendorBootImageV3Through4),
+}
+
+#[derive(Serialize)]
+struct ExtractSection {
+    name: String,
+    file: String,
+    size: usize,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ExtractManifest<'a> {
+    #[serde(rename = "type")]
+    image_type: &'static str,
+    header_version: u32,
+    #[serde(flatten)]
+    header: ExtractHeader<'a>,
+    sections: Vec<ExtractSection>,
+}
+
+impl BootImage {
+    /// Raw sections as `(name, file name, data)` tuples. Which sections are
+    /// included only depends on the image type and header version, not on
+    /// whether they are empty.
+    fn raw_sections(&self) -> Result<Vec<(String, String, Vec<u8>)>> {
+        let mut sections = vec![];
+        let mut push = |name: &str, file: &str, data: &[u8]| {
+            sections.push((name.to_owned(), file.to_owned(), data.to_vec()));
+        };
+
+        match self {
+            Self::V0Through2(b) => {
+                push("kernel", "kernel.img", &b.kernel);
+                push("ramdisk.0", "ramdisk.img.0", &b.ramdisk);
+                push("second", "second.img", &b.second);
+                if let Some(v1) = &b.v1_extra {
+                    push("recovery_dtbo", "recovery_dtbo.img", &v1.recovery_dtbo);
+                }
+                if let Some(v2) = &b.v2_extra {
+                    push("dtb", "dtb.img", &v2.dtb);
+                }
+            }
+            Self::V3Through4(b) => {
+                push("kernel", "kernel.img", &b.kernel);
+                push("ramdisk.0", "ramdisk.img.0", &b.ramdisk);
+                if let Some(v4) = &b.v4_extra {
+                    let mut data = vec![];
+                    for header in v4.signatures() {
+                        header.to_writer(&mut data)?;
+                    }
+                    push("vts_signature", "vts_signature.img", &data);
+                }
+            }
+            Self::VendorV3Through4(b) => {
+                push("dtb", "dtb.img", &b.dtb);
+                for (i, ramdisk) in b.ramdisks.iter().enumerate() {
+                    push(
+                        &format!("ramdisk.{i}"),
+                        &format!("ramdisk.img.{i}"),
+                        ramdisk,
+                    );
+                }
+                if let Some(v4) = &b.v4_extra {
+                    push("bootconfig", "bootconfig.txt", v4.bootconfig.as_bytes());
+                }
+            }
+        }
+
+        Ok(sections)
+    }
+}
+
+/// Write each raw section of `image` to a separate file in `out_dir`, along
+/// with a JSON manifest ([`EXTRACT_MANIFEST_NAME`]) describing the header
+/// fields and the size and SHA-256 digest of each section. This is meant for
+/// troubleshooting. The file names match the defaults of `avbroot boot
+/// unpack`, but the manifest is informational only and cannot be packed.
+pub fn extract_all(image: &BootImage, out_dir: &Path) -> Result<()> {
+    fs::create_dir_all(out_dir)?;
+
+    let (image_type, header) = match image {
+        BootImage::V0Through2(b) => ("boot", ExtractHeader::V0Through2(b)),
+        BootImage::V3Through4(b) => ("boot", ExtractHeader::V3Through4(b)),
+        BootImage::VendorV3Through4(b) => ("vendor_boot", ExtractHeader::VendorV3Through4(b)),
+    };
+
+    let mut sections = vec![];
+
+    for (name, file, data) in image.raw_sections()? {
+        fs::write(out_dir.join(&file), &data)?;
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+        sections.push(ExtractSection {
+            name,
+            file,
+            size: data.len(),
+            sha256: hex::encode(digest),
+        });
+    }
+
+    let mut manifest = serde_json::to_string_pretty(&ExtractManifest {
+        image_type,
+        header_version: image.header_version(),
+        header,
+        sections,
+    })?;
+    manifest.push('\n');
+
+    fs::write(out_dir.join(EXTRACT_MANIFEST_NAME), manifest)?;
+
+    Ok(())
+}
+
+/// A MediaTek header that precedes the actual boot image on some devices.
+#[derive(Clone, Eq, PartialEq)]
+pub struct MtkHeader {
+    /// Size of the wrapped data. This is recomputed when writing a new image.
+    pub size: u32,
+    pub name: String,
+    /// Remainder of the header after the name field. This is preserved as-is.
+    pub extra: Vec<u8>,
+}
+
+impl fmt::Debug for MtkHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MtkHeader")
+            .field("size", &self.size)
+            .field("name", &self.name)
+            .field("extra", &hex::encode(&self.extra))
+            .finish()
+    }
+}
+
+impl<R: Read> FromReader<R> for MtkHeader {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; MTK_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+
+        if magic != MTK_MAGIC {
+            return Err(Error::InvalidData("Invalid MTK header magic"));
+        }
+
+        let size = reader.read_u32::<LittleEndian>()?;
+        let name = reader
+            .read_string_padded(MTK_NAME_SIZE)
+            .map_err(|e| Error::ReadFieldError("mtk_name", e))?;
+
+        let mut extra = vec![0u8; MTK_EXTRA_SIZE];
+        reader.read_exact(&mut extra)?;
+
+        Ok(Self { size, name, extra })
+    }
+}
+
+impl<W: Write> ToWriter<W> for MtkHeader {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        if self.extra.len() != MTK_EXTRA_SIZE {
+            return Err(Error::InvalidData("MTK header has incorrect size"));
+        }
+
+        writer.write_all(&MTK_MAGIC)?;
+        writer.write_u32::<LittleEndian>(self.size)?;
+        writer
+            .write_string_padded(&self.name, MTK_NAME_SIZE)
+            .map_err(|e| Error::WriteFieldError("mtk_name", e))?;
+        writer.write_all(&self.extra)?;
+
+        Ok(())
+    }
+}
+
+/// A vendor-specific container that a boot image may be wrapped in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BootContainer {
+    /// The data is a plain boot image.
+    None,
+    /// The entire boot image is compressed with the LZ4 frame format. This is
+    /// used by the `boot.img.lz4` files in Samsung firmware packages.
+    Lz4Frame,
+    /// The boot image is preceded by a 512-byte MediaTek header.
+    Mtk(MtkHeader),
+}
+
+impl BootContainer {
+    /// Load a boot image, transparently unwrapping any container that it may
+    /// be wrapped in. Containers are only detected by their magic, which never
+    /// overlaps with the boot image magic values, so plain images are always
+    /// parsed as-is.
+    pub fn load(mut reader: impl Read + Seek) -> Result<(BootImage, Self)> {
+        reader.rewind()?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        reader.rewind()?;
+
+        if magic == LZ4_FRAME_MAGIC {
+            let mut data = vec![];
+            FrameDecoder::new(reader).read_to_end(&mut data)?;
+
+            let image = BootImage::from_reader(Cursor::new(data))?;
+
+            Ok((image, Self::Lz4Frame))
+        } else if magic == MTK_MAGIC {
+            let header = MtkHeader::from_reader(&mut reader)?;
+            let section_reader =
+                SectionReader::new(reader, MTK_HEADER_SIZE as u64, header.size.into())?;
+
+            let image = BootImage::from_reader(section_reader)?;
+
+            Ok((image, Self::Mtk(header)))
+        } else {
+            let image = BootImage::from_reader(reader)?;
+
+            Ok((image, Self::None))
+        }
+    }
+
+    /// Write a boot image, wrapped in this container. For MTK headers, the size
+    /// field is updated to match the new boot image.
+    pub fn save(&self, mut writer: impl Write, image: &BootImage) -> Result<()> {
+        match self {
+            Self::None => image.to_writer(writer),
+            Self::Lz4Frame => {
+                let mut encoder = FrameEncoder::new(writer);
+                image.to_writer(&mut encoder)?;
+                encoder.finish().map_err(io::Error::from)?;
+
+                Ok(())
+            }
+            Self::Mtk(h) => {
+                let mut data = vec![];
+                image.to_writer(&mut data)?;
+
+                let header = MtkHeader {
+                    size: data
+                        .len()
+                        .to_u32()
+                        .ok_or_else(|| Error::IntegerTooLarge("mtk_size"))?,
+                    ..h.clone()
+                };
+
+                header.to_writer(&mut writer)?;
+                writer.write_all(&data)?;
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -3,123 +3,1007 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::{self, Read, Seek, Write};
+#[cfg(feature = "native")]
+use std::{fs::File, io::BufWriter};
+use std::{
+    io::{self, BufRead, BufReader, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write},
+    iter, mem,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
 
 use byteorder::{LittleEndian, WriteBytesExt};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use flate2::{bufread::DeflateDecoder, write::GzEncoder, Compression, GzBuilder};
 use lz4_flex::frame::FrameDecoder;
+use serde::Serialize;
 use thiserror::Error;
+#[cfg(feature = "native")]
+use xz2::{
+    bufread::XzDecoder,
+    stream::{Check, Stream},
+    write::XzEncoder,
+};
+
+#[cfg(feature = "native")]
+use crate::stream::PSeekFile;
+use crate::stream::{CountingWriter, SectionReader};
 
 static GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
 static LZ4_LEGACY_MAGIC: &[u8; 4] = b"\x02\x21\x4c\x18";
+static LZ4_FRAME_MAGIC: &[u8; 4] = b"\x04\x22\x4d\x18";
+/// Only the first 4 bytes of the 6-byte magic.
+static XZ_MAGIC: &[u8; 4] = b"\xfd7zX";
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unknown compression format")]
     UnknownFormat,
+    #[error("Reading {0:?} data is not supported")]
+    UnsupportedReadFormat(CompressedFormat),
+    #[error("Writing {0:?} data is not supported")]
+    UnsupportedWriteFormat(CompressedFormat),
+    #[error("Cannot determine compression format from extension: {0:?} (supported: {1})")]
+    UnknownExtension(PathBuf, String),
+    #[error("Uncompressed input exceeds {0} bytes")]
+    InputTooLarge(u64),
+    #[error("Compression did not finish before the deadline")]
+    DeadlineExceeded,
+    #[error("Invalid {0:?} compression level: {1}")]
+    InvalidLevel(CompressedFormat, u32),
+    #[error("Invalid LZ4 legacy block size: {0}")]
+    InvalidBlockSize(usize),
+    #[error("Offset {0} is beyond the end of the input ({1} bytes)")]
+    OffsetOutOfBounds(u64, u64),
+    #[error("Expected {0:?} data, but found {1:?} data")]
+    FormatMismatch(CompressedFormat, CompressedFormat),
+    #[error("Segment offset {0} is not after the previous segment's offset")]
+    UnorderedSegment(u64),
+    #[error("Segment at offset {0} is empty")]
+    EmptySegment(u64),
+    #[error("Compressed stream is truncated")]
+    Truncated,
+    #[error("Checksum mismatch after decompressing {0} bytes")]
+    ChecksumMismatch(u64),
+    #[error("Gzip header checksum mismatch")]
+    HeaderChecksumMismatch,
+    #[error("Compressed data is corrupt")]
+    Corrupt(#[source] io::Error),
     #[error("I/O error")]
     IoError(#[from] io::Error),
 }
 
+impl Error {
+    /// Whether the decompressed data is still usable despite the error. This is
+    /// only the case for checksum mismatches that are detected after the end of
+    /// the compressed stream, where all of the data has already been produced.
+    /// Some tools, like older versions of mkbootimg, write incorrect trailing
+    /// checksums. A mismatched gzip header CRC that is rejected before
+    /// decompressing ([`Self::HeaderChecksumMismatch`]) is not recoverable.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Self::ChecksumMismatch(_))
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
+/// Buffer size for files opened by [`open_standalone()`] and
+/// [`create_standalone()`]. Most writers in this module issue many small
+/// writes, so this keeps the number of syscalls down.
+pub const FILE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Size of each sample compressed by [`estimate_size()`].
+const ESTIMATE_SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Number of evenly spaced samples compressed by [`estimate_size()`].
+const ESTIMATE_SAMPLE_COUNT: usize = 8;
+
+/// Buffer size for the raw input of the gzip and xz decoders. This is the same
+/// as what the non-buffered decoders from flate2 and xz2 use internally.
+const DECODER_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Maximum number of bytes preallocated by [`CompressedReader::decompress_all()`]
+/// based on the size stored in the header. The size is not trustworthy until
+/// the stream has been fully decoded.
+const PREALLOCATE_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Maximum number of bytes copied together for a vectored write to an encoder
+/// that does not natively support vectored I/O.
+const COALESCE_MAX_SIZE: usize = 64 * 1024;
+
+/// Number of uncompressed bytes written between checks of the deadline. This
+/// keeps the cost of reading the clock negligible for small writes.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024 * 1024;
+
+/// Write the leading buffers in `bufs` with a single call to `writer`. The
+/// encoders from flate2 and xz2 fall back to writing only the first buffer,
+/// which results in a separate round of compression for each buffer.
+fn write_coalesced(writer: &mut impl Write, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+    let mut bufs = bufs.iter().filter(|b| !b.is_empty());
+    let Some(first) = bufs.next() else {
+        return Ok(0);
+    };
+
+    if first.len() >= COALESCE_MAX_SIZE {
+        return writer.write(first);
+    }
+
+    let mut data = Vec::with_capacity(COALESCE_MAX_SIZE);
+    data.extend_from_slice(first);
+
+    for buf in bufs {
+        if data.len() + buf.len() > COALESCE_MAX_SIZE {
+            break;
+        }
+
+        data.extend_from_slice(buf);
+    }
+
+    writer.write(&data)
+}
+
+/// Default and maximum block size. Smaller blocks can be configured with
+/// [`Lz4LegacyEncoder::set_block_size()`].
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Highest compression level for gzip and xz.
+const MAX_LEVEL: u32 = 9;
+
+/// Same as the xz CLI's default.
+#[cfg(feature = "native")]
+const XZ_DEFAULT_LEVEL: u32 = 6;
+
+/// Gzip and xz level used by [`normalize()`].
+const NORMALIZE_LEVEL: u32 = 9;
+
+/// Maximum number of full-size block buffers kept around for reuse.
+const LZ4_LEGACY_POOL_SIZE: usize = 4;
+
+/// Full-size block buffers from finished encoders. Patching a boot image with
+/// many ramdisks creates many encoders and this avoids repeatedly allocating
+/// and freeing 8 MiB buffers.
+static LZ4_LEGACY_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Position of a block boundary in an LZ4 legacy stream. Every block is
+/// compressed independently, so decoding can begin at any boundary.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlockIndexEntry {
+    /// Offset of the block's length prefix in the compressed stream.
+    pub compressed_offset: u64,
+    /// Offset of the block's first byte in the uncompressed data.
+    pub uncompressed_offset: u64,
+}
+
+/// Encoder for the LZ4 legacy format.
+///
+/// This supports non-blocking writers. If the underlying writer returns
+/// [`io::ErrorKind::WouldBlock`], the compressed data that could not be written
+/// is kept and written out on the next call to [`Write::write()`],
+/// [`Write::flush()`], or [`Self::try_finish()`].
+///
+/// The block buffer is not allocated until the first non-empty write. The
+/// block size is always 8 MiB regardless of how much is allocated up front, so
+/// the output does not depend on the size hint.
+///
+/// The format has no end-of-stream marker, but some of Android's userspace
+/// tools write a zero-length block after the final block. To produce
+/// identical output, enable this with [`Self::set_end_marker()`].
 pub struct Lz4LegacyEncoder<W: Write> {
     writer: Option<W>,
+    /// Uncompressed data for the current block. The capacity is only reserved
+    /// on the first write.
     buf: Vec<u8>,
-    n_filled: usize,
+    size_hint: Option<usize>,
+    block_size: usize,
+    /// Amount of buffered data at which a block is written during a normal
+    /// write, even if it is not full yet.
+    min_block_fill: Option<usize>,
+    /// Compressed data that has not been written to the writer yet.
+    pending: Vec<u8>,
+    n_pending_written: usize,
+    /// Size of all compressed data produced so far, including pending data.
+    compressed_offset: u64,
+    /// Size of all uncompressed data that has been compressed into blocks.
+    uncompressed_offset: u64,
+    end_marker: bool,
+    verify_blocks: bool,
+    finished: bool,
 }
 
 impl<W: Write> Lz4LegacyEncoder<W> {
-    pub fn new(mut writer: W) -> io::Result<Self> {
-        writer.write_all(LZ4_LEGACY_MAGIC)?;
-
+    pub fn new(writer: W) -> io::Result<Self> {
         Ok(Self {
             writer: Some(writer),
-            // We always use the max block size.
-            buf: vec![0u8; 8 * 1024 * 1024],
-            n_filled: 0,
+            buf: Vec::new(),
+            size_hint: None,
+            block_size: LZ4_LEGACY_BLOCK_SIZE,
+            min_block_fill: None,
+            pending: LZ4_LEGACY_MAGIC.to_vec(),
+            n_pending_written: 0,
+            compressed_offset: LZ4_LEGACY_MAGIC.len() as u64,
+            uncompressed_offset: 0,
+            end_marker: false,
+            verify_blocks: false,
+            finished: false,
         })
     }
 
-    pub fn write_block(&mut self, force: bool) -> io::Result<()> {
-        if !force && self.n_filled < self.buf.len() {
-            // Block not fully filled yet.
-            return Ok(());
+    /// Create an encoder that expects roughly `size_hint` bytes of input. The
+    /// block buffer will initially be sized to fit the hint instead of the
+    /// full block size. It will still grow if more data is written.
+    pub fn with_size_hint(writer: W, size_hint: usize) -> io::Result<Self> {
+        let mut result = Self::new(writer)?;
+        result.size_hint = Some(size_hint);
+        Ok(result)
+    }
+
+    /// Set the maximum amount of uncompressed data per block. This is clamped
+    /// to 8 MiB, which is the default and the largest size that decoders
+    /// accept. It must be set before any data is written.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size.clamp(1, LZ4_LEGACY_BLOCK_SIZE);
+    }
+
+    /// Set the amount of buffered data at which [`Write::write()`] writes a
+    /// block, even if it is not full yet. This reduces latency when streaming
+    /// at the cost of compression ratio. Blocks may still be larger than this
+    /// if a single write provides more data. The default of [`None`] only
+    /// writes full blocks. This does not affect [`Self::flush_block_boundary()`]
+    /// and [`Self::finish()`], which always write the buffered data.
+    pub fn set_min_block_fill(&mut self, min_block_fill: Option<usize>) {
+        self.min_block_fill = min_block_fill.map(|n| n.max(1));
+    }
+
+    /// Amount of buffered data at which a block is written by a normal write.
+    fn block_fill_threshold(&self) -> usize {
+        self.min_block_fill
+            .map_or(self.block_size, |n| n.min(self.block_size))
+    }
+
+    /// Set whether a zero-length block is written after the final block. This
+    /// is disabled by default and must be set before the encoder is finished.
+    pub fn set_end_marker(&mut self, enabled: bool) {
+        self.end_marker = enabled;
+    }
+
+    /// Set whether each block is decompressed again after it is compressed and
+    /// compared with the input. A mismatch is reported as an
+    /// [`io::ErrorKind::InvalidData`] error. This roughly doubles the CPU time
+    /// and is disabled by default.
+    pub fn set_verify_blocks(&mut self, enabled: bool) {
+        self.verify_blocks = enabled;
+    }
+
+    /// Number of bytes currently allocated for the uncompressed block buffer.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Make sure the block buffer can fit `additional` more bytes. The buffer
+    /// is allocated on first use and never grows beyond the block size.
+    fn reserve_buf(&mut self, additional: usize) {
+        if self.buf.capacity() == 0 {
+            let size = self
+                .size_hint
+                .map_or(self.block_size, |h| h.min(self.block_size));
+
+            if size == LZ4_LEGACY_BLOCK_SIZE {
+                if let Some(buf) = LZ4_LEGACY_POOL.lock().unwrap().pop() {
+                    self.buf = buf;
+                    return;
+                }
+            }
+
+            self.buf = Vec::with_capacity(size);
+        }
+
+        let needed = self.buf.len() + additional;
+        if needed > self.buf.capacity() {
+            let new_capacity = needed.max(self.buf.capacity() * 2).min(self.block_size);
+            self.buf.reserve_exact(new_capacity - self.buf.len());
+        }
+    }
+
+    /// Return the block buffer to the pool if it is full-size.
+    fn release_buf(&mut self) {
+        let mut buf = mem::take(&mut self.buf);
+        if buf.capacity() < LZ4_LEGACY_BLOCK_SIZE {
+            return;
+        }
+
+        buf.clear();
+
+        let mut pool = LZ4_LEGACY_POOL.lock().unwrap();
+        if pool.len() < LZ4_LEGACY_POOL_SIZE {
+            pool.push(buf);
+        }
+    }
+
+    /// Write out compressed data left over from previous calls.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+
+        while self.n_pending_written < self.pending.len() {
+            let n = writer.write(&self.pending[self.n_pending_written..])?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "Failed to write compressed block",
+                ));
+            }
+
+            self.n_pending_written += n;
         }
 
+        self.pending.clear();
+        self.n_pending_written = 0;
+
+        Ok(())
+    }
+
+    /// Compress the buffered data into a new block. This must only be called
+    /// when there is no pending data.
+    fn compress_block(&mut self) -> io::Result<()> {
+        debug_assert!(self.pending.is_empty());
+
         // HC is currently not supported:
         // https://github.com/PSeitz/lz4_flex/issues/21
-        let compressed = lz4_flex::block::compress(&self.buf[..self.n_filled]);
+        let compressed = lz4_flex::block::compress(&self.buf);
 
-        let writer = self.writer.as_mut().unwrap();
-        writer.write_u32::<LittleEndian>(compressed.len() as u32)?;
-        writer.write_all(&compressed)?;
+        if self.verify_blocks {
+            let decompressed = lz4_flex::block::decompress(&compressed, self.buf.len())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            if decompressed != self.buf {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Compressed block at offset {} does not match input",
+                        self.compressed_offset,
+                    ),
+                ));
+            }
+        }
+
+        self.pending
+            .write_u32::<LittleEndian>(compressed.len() as u32)
+            .unwrap();
+        self.pending.extend_from_slice(&compressed);
+
+        self.compressed_offset += 4 + compressed.len() as u64;
+        self.uncompressed_offset += self.buf.len() as u64;
+
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    pub fn write_block(&mut self, force: bool) -> io::Result<()> {
+        self.write_pending()?;
+
+        if !force && self.buf.len() < self.block_fill_threshold() {
+            // Block not sufficiently filled yet.
+            return Ok(());
+        }
+
+        self.compress_block()?;
+        self.write_pending()
+    }
+
+    /// End the current block, even if it is not full, and return the position
+    /// of the boundary after it. Collecting these as data is written produces
+    /// an index for seeking in the compressed stream. If the current block is
+    /// empty, nothing is written because a zero-length block would mark the
+    /// end of the stream. If this returns [`io::ErrorKind::WouldBlock`], it
+    /// can be called again to resume writing.
+    pub fn flush_block_boundary(&mut self) -> io::Result<BlockIndexEntry> {
+        self.write_pending()?;
+
+        if !self.buf.is_empty() {
+            self.compress_block()?;
+            self.write_pending()?;
+        }
+
+        Ok(BlockIndexEntry {
+            compressed_offset: self.compressed_offset,
+            uncompressed_offset: self.uncompressed_offset,
+        })
+    }
 
-        self.n_filled = 0;
+    /// Write the final block. If this returns [`io::ErrorKind::WouldBlock`],
+    /// it can be called again to resume writing.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+
+        if !self.finished {
+            self.compress_block()?;
+            if self.end_marker {
+                self.pending.write_u32::<LittleEndian>(0).unwrap();
+            }
+            self.finished = true;
+            self.release_buf();
+            self.write_pending()?;
+        }
 
         Ok(())
     }
 
     pub fn finish(mut self) -> io::Result<W> {
-        self.write_block(true)?;
+        self.try_finish()?;
         Ok(self.writer.take().unwrap())
     }
+
+    /// Predict the framing of the output if a new encoder with the current
+    /// block size, minimum block fill, and end marker settings is given
+    /// `input_len` bytes with [`Write::write_all()`] calls of `write_size`
+    /// bytes each. This returns the number of blocks and the number of bytes of
+    /// overhead, which is the magic, the 4-byte length prefix for each block,
+    /// and the end marker, if enabled. The end marker is not counted as a
+    /// block. The size of the compressed data itself is not included.
+    ///
+    /// The final block is always written, even if it is empty. This assumes
+    /// that [`Self::write_block()`] is never called with `force` set and that
+    /// [`Self::flush_block_boundary()`] is never called, both of which would
+    /// end a block early.
+    pub fn predict_output(&self, input_len: u64, write_size: u64) -> (u64, u64) {
+        let block_size = self.block_size as u64;
+        let threshold = self.block_fill_threshold() as u64;
+        let write_size = write_size.max(1);
+
+        // Same logic as write(), which takes at most the rest of the block
+        // from each call and ends the block once the threshold is reached.
+        let mut num_blocks = 1;
+        let mut buffered = 0;
+        let mut remaining = input_len;
+
+        while remaining > 0 {
+            let mut chunk = remaining.min(write_size);
+            remaining -= chunk;
+
+            while chunk > 0 {
+                let n = chunk.min(block_size - buffered);
+                buffered += n;
+                chunk -= n;
+
+                if buffered >= threshold {
+                    num_blocks += 1;
+                    buffered = 0;
+                }
+            }
+        }
+
+        let end_marker_size = if self.end_marker { 4 } else { 0 };
+        let header_overhead = LZ4_LEGACY_MAGIC.len() as u64 + num_blocks * 4 + end_marker_size;
+
+        (num_blocks, header_overhead)
+    }
 }
 
 impl<W: Write> Drop for Lz4LegacyEncoder<W> {
     fn drop(&mut self) {
         if self.writer.is_some() {
-            let _ = self.write_block(true);
+            let _ = self.try_finish();
         }
     }
 }
 
 impl<W: Write> Write for Lz4LegacyEncoder<W> {
-    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
-        let total = buf.len();
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // If the previous block still can't be written, nothing is consumed.
+        self.write_pending()?;
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
 
-        while !buf.is_empty() {
-            let to_write = buf.len().min(self.buf.len() - self.n_filled);
-            self.buf[self.n_filled..self.n_filled + to_write].copy_from_slice(&buf[..to_write]);
+        let to_write = buf.len().min(self.block_size - self.buf.len());
+        self.reserve_buf(to_write);
+        self.buf.extend_from_slice(&buf[..to_write]);
 
-            self.n_filled += to_write;
-            self.write_block(false)?;
+        if self.buf.len() >= self.block_fill_threshold() {
+            // Verification errors are not retryable, so the input is not
+            // consumed.
+            if let Err(e) = self.compress_block() {
+                self.buf.truncate(self.buf.len() - to_write);
+                return Err(e);
+            }
 
-            buf = &buf[to_write..];
+            // The input has already been consumed, so any errors are reported
+            // by the next call instead, which retries writing the block.
+            let _ = self.write_pending();
         }
 
-        Ok(total)
+        Ok(to_write)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.write_pending()?;
+
+        let total = bufs.iter().map(|b| b.len()).sum::<usize>();
+        let to_write = total.min(self.block_size - self.buf.len());
+        if to_write == 0 {
+            return Ok(0);
+        }
+
+        self.reserve_buf(to_write);
+
+        let mut remain = to_write;
+        for buf in bufs {
+            let n = buf.len().min(remain);
+            self.buf.extend_from_slice(&buf[..n]);
+            remain -= n;
+
+            if remain == 0 {
+                break;
+            }
+        }
+
+        if self.buf.len() >= self.block_fill_threshold() {
+            // Same as write().
+            if let Err(e) = self.compress_block() {
+                self.buf.truncate(self.buf.len() - to_write);
+                return Err(e);
+            }
+
+            let _ = self.write_pending();
+        }
+
+        Ok(to_write)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.write_block(false)
+        self.write_pending()?;
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+/// Offset of the XFL (extra flags) byte in the gzip header.
+const GZIP_XFL_OFFSET: u64 = 8;
+/// The only compression method defined for gzip.
+const GZIP_CM_DEFLATE: u8 = 8;
+
+// Gzip header flags that affect the header layout.
+const GZIP_FLAG_FHCRC: u8 = 1 << 1;
+const GZIP_FLAG_FEXTRA: u8 = 1 << 2;
+const GZIP_FLAG_FNAME: u8 = 1 << 3;
+const GZIP_FLAG_FCOMMENT: u8 = 1 << 4;
+
+/// Modern LZ4 frame descriptor flag indicating that the content size is present.
+const LZ4_FRAME_FLAG_CONTENT_SIZE: u8 = 1 << 3;
+
+/// Parse the content size from the descriptor of the modern LZ4 frame at the
+/// start of `reader`. Returns [`None`] if the content size flag is not set.
+fn lz4_frame_content_size(mut reader: impl Read) -> io::Result<Option<u64>> {
+    let mut header = [0u8; 6];
+    reader.read_exact(&mut header)?;
+
+    // The FLG byte follows the magic.
+    if header[4] & LZ4_FRAME_FLAG_CONTENT_SIZE == 0 {
+        return Ok(None);
+    }
+
+    let mut size = [0u8; 8];
+    reader.read_exact(&mut size)?;
+
+    Ok(Some(u64::from_le_bytes(size)))
+}
+
+/// Parse the gzip header at the start of `reader`. Returns the header bytes
+/// before the FHCRC field and the stored header CRC if the flag is set.
+fn read_gzip_header(mut reader: impl BufRead) -> io::Result<(Vec<u8>, Option<u16>)> {
+    let mut header = vec![0u8; 10];
+    reader.read_exact(&mut header)?;
+
+    if &header[0..2] != GZIP_MAGIC || header[2] != GZIP_CM_DEFLATE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid gzip header",
+        ));
+    }
+
+    let flags = header[3];
+
+    if flags & GZIP_FLAG_FEXTRA != 0 {
+        let mut xlen = [0u8; 2];
+        reader.read_exact(&mut xlen)?;
+        header.extend_from_slice(&xlen);
+
+        let start = header.len();
+        header.resize(start + usize::from(u16::from_le_bytes(xlen)), 0);
+        reader.read_exact(&mut header[start..])?;
+    }
+
+    for flag in [GZIP_FLAG_FNAME, GZIP_FLAG_FCOMMENT] {
+        if flags & flag != 0 {
+            reader.read_until(0, &mut header)?;
+            if header.last() != Some(&0) {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    let stored = if flags & GZIP_FLAG_FHCRC != 0 {
+        let mut stored = [0u8; 2];
+        reader.read_exact(&mut stored)?;
+        Some(u16::from_le_bytes(stored))
+    } else {
+        None
+    };
+
+    Ok((header, stored))
+}
+
+/// Whether the stored header CRC, if any, does not match the lower 16 bits of
+/// the CRC32 of the header bytes.
+fn is_gzip_header_crc_mismatch(header: &[u8], stored: Option<u16>) -> bool {
+    stored.map_or(false, |s| s != crc32fast::hash(header) as u16)
+}
+
+/// The CRC32 or size in the gzip trailer does not match the decompressed
+/// data. This is the inner error of the [`io::Error`] returned by
+/// [`GzipDecoder`].
+#[derive(Debug, Error)]
+#[error("Gzip trailer does not match the decompressed data")]
+pub struct GzipTrailerMismatch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GzipState {
+    Header,
+    Data,
+    Done,
+}
+
+/// Decoder for a single gzip member. Only the deflate stream is decoded by
+/// flate2. The header and trailer are handled here because flate2 always
+/// rejects a mismatched header CRC (see [`CompressedReader::new_lenient()`])
+/// and reports a mismatched trailer the same way as any other corruption.
+pub struct GzipDecoder<R: BufRead> {
+    inner: DeflateDecoder<R>,
+    state: GzipState,
+    /// Whether a mismatched header CRC is accepted instead of being an error.
+    lenient: bool,
+    header_crc_mismatch: bool,
+    crc: crc32fast::Hasher,
+    size: u32,
+}
+
+impl<R: BufRead> GzipDecoder<R> {
+    fn new(reader: R, lenient: bool) -> Self {
+        Self {
+            inner: DeflateDecoder::new(reader),
+            state: GzipState::Header,
+            lenient,
+            header_crc_mismatch: false,
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        }
+    }
+
+    /// Whether the header CRC did not match and was ignored.
+    pub fn header_crc_mismatch(&self) -> bool {
+        self.header_crc_mismatch
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl<R: BufRead> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state == GzipState::Header {
+            let (header, stored) = read_gzip_header(self.inner.get_mut())?;
+
+            if is_gzip_header_crc_mismatch(&header, stored) {
+                if !self.lenient {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Gzip header CRC does not match",
+                    ));
+                }
+
+                self.header_crc_mismatch = true;
+            }
+
+            self.state = GzipState::Data;
+        }
+
+        if self.state == GzipState::Done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = self.inner.read(buf)?;
+        if n != 0 {
+            self.crc.update(&buf[..n]);
+            // ISIZE is the size modulo 2^32.
+            self.size = self.size.wrapping_add(n as u32);
+            return Ok(n);
+        }
+
+        let mut trailer = [0u8; 8];
+        self.inner.get_mut().read_exact(&mut trailer)?;
+        self.state = GzipState::Done;
+
+        let crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+
+        if crc != self.crc.clone().finalize() || size != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                GzipTrailerMismatch,
+            ));
+        }
+
+        Ok(0)
+    }
+}
+
+/// Options for the fixed fields of the gzip header. These have no effect on
+/// decompression and only exist to allow reproducing existing gzip files byte
+/// for byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GzipOptions {
+    /// Modification time.
+    pub mtime: u32,
+    /// Operating system. 255 means unknown.
+    pub os: u8,
+    /// Extra flags. If unset, this is derived from the compression level,
+    /// which results in 0 for the default level.
+    pub xfl: Option<u8>,
+}
+
+impl Default for GzipOptions {
+    /// Same as flate2's defaults.
+    fn default() -> Self {
+        Self {
+            mtime: 0,
+            os: 255,
+            xfl: None,
+        }
+    }
+}
+
+impl GzipOptions {
+    /// Get the options from the header of existing gzip data. Returns [`None`]
+    /// if the data does not start with a gzip header.
+    pub fn from_header(data: &[u8]) -> Option<Self> {
+        if data.len() < 10 || &data[0..2] != GZIP_MAGIC || data[2] != 8 {
+            return None;
+        }
+
+        Some(Self {
+            mtime: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            os: data[9],
+            xfl: Some(data[8]),
+        })
+    }
+}
+
+/// Writer that overrides the XFL byte of the gzip header as it passes through.
+/// flate2 does not allow setting it directly.
+struct GzipHeaderWriter<W: Write> {
+    inner: W,
+    pos: u64,
+    xfl: Option<u8>,
+}
+
+impl<W: Write> Write for GzipHeaderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match (self.xfl, GZIP_XFL_OFFSET.checked_sub(self.pos)) {
+            (Some(xfl), Some(offset)) if offset < buf.len() as u64 => {
+                // Write up to and including the XFL byte so that partial
+                // writes are handled the same as for the rest of the data.
+                let offset = offset as usize;
+                let mut chunk = buf[..=offset].to_vec();
+                chunk[offset] = xfl;
+
+                self.inner.write(&chunk)?
+            }
+            _ => self.inner.write(buf)?,
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Gzip encoder with control over the header fields. See [`GzipOptions`].
+pub struct GzipEncoder<W: Write>(GzEncoder<GzipHeaderWriter<W>>);
+
+impl<W: Write> GzipEncoder<W> {
+    pub fn new(writer: W, options: &GzipOptions) -> Self {
+        Self::with_level(writer, options, Compression::default().level())
+    }
+
+    /// Create an encoder with a compression level from 0 to 9.
+    pub fn with_level(writer: W, options: &GzipOptions, level: u32) -> Self {
+        let header_writer = GzipHeaderWriter {
+            inner: writer,
+            pos: 0,
+            xfl: options.xfl,
+        };
+
+        Self(
+            GzBuilder::new()
+                .mtime(options.mtime)
+                .operating_system(options.os)
+                .write(header_writer, Compression::new(level)),
+        )
+    }
+
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.0.try_finish()
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.0.finish().map(|w| w.inner)
+    }
+}
+
+impl<W: Write> Write for GzipEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        write_coalesced(&mut self.0, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CompressedFormat {
     None,
     Gzip,
     Lz4Legacy,
+    /// Modern LZ4 frame format. This can only be decompressed.
+    Lz4Frame,
+    Xz,
+}
+
+impl CompressedFormat {
+    /// Guess the format of a standalone file from its `.gz` or `.xz`
+    /// extension. Any other extension is treated as uncompressed.
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("xz") => Self::Xz,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Stand-in for liblzma's decoder when the `native` feature is disabled. Every
+/// read fails, so [`CompressedReader::reader_for()`] can stay infallible.
+#[cfg(not(feature = "native"))]
+pub struct XzDecoder<R>(R);
+
+#[cfg(not(feature = "native"))]
+impl<R> XzDecoder<R> {
+    fn new_multi_decoder(inner: R) -> Self {
+        Self(inner)
+    }
+
+    fn into_inner(self) -> R {
+        self.0
+    }
+}
+
+#[cfg(not(feature = "native"))]
+impl<R> Read for XzDecoder<R> {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            Error::UnsupportedReadFormat(CompressedFormat::Xz),
+        ))
+    }
 }
 
+/// The gzip and xz decoders read from a [`BufReader`] that we own so that the
+/// input that was read ahead, but not consumed, can be accounted for in
+/// [`CompressedReader::into_parts()`].
 pub enum CompressedReader<R: Read> {
     None(R),
-    Gzip(GzDecoder<R>),
+    Gzip(GzipDecoder<BufReader<R>>),
     Lz4(FrameDecoder<R>),
+    /// The decoder and the content size from the frame descriptor, if present.
+    Lz4Frame(FrameDecoder<R>, Option<u64>),
+    Xz(XzDecoder<BufReader<R>>),
 }
 
 impl<R: Read + Seek> CompressedReader<R> {
-    pub fn new(mut reader: R, raw_if_unknown: bool) -> Result<Self> {
+    /// Detect the compression format and create a decoder. If the data is
+    /// gzip-compressed and the header has a CRC (FHCRC flag), the CRC must
+    /// be correct or else [`Error::HeaderChecksumMismatch`] is returned.
+    pub fn new(reader: R, raw_if_unknown: bool) -> Result<Self> {
+        Self::new_internal(reader, 0, raw_if_unknown, true)
+    }
+
+    /// Like [`Self::new()`], but the compressed stream starts at `offset`
+    /// within `reader` instead of at the beginning. This is useful for streams
+    /// embedded in a larger file. The consumed byte count returned by
+    /// [`Self::into_parts()`] is relative to the start of `reader`, not
+    /// `offset`.
+    pub fn new_at(mut reader: R, offset: u64, raw_if_unknown: bool) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if offset > len {
+            return Err(Error::OffsetOutOfBounds(offset, len));
+        }
+
+        Self::new_internal(reader, offset, raw_if_unknown, true)
+    }
+
+    /// Like [`Self::new()`], but a mismatched gzip header CRC is ignored.
+    /// [`Self::decompress_all()`] still reports it as a recoverable error.
+    pub fn new_lenient(reader: R, raw_if_unknown: bool) -> Result<Self> {
+        Self::new_internal(reader, 0, raw_if_unknown, false)
+    }
+
+    /// Like [`Self::new()`], but the data must be in the specified format or
+    /// else [`Error::FormatMismatch`] is returned. With
+    /// [`CompressedFormat::None`], the data is always read as is, even if it
+    /// looks compressed.
+    pub fn with_format(mut reader: R, format: CompressedFormat) -> Result<Self> {
+        if format == CompressedFormat::None {
+            reader.rewind()?;
+            return Ok(Self::None(reader));
+        }
+
+        let result = Self::new(reader, true)?;
+        if result.format() != format {
+            return Err(Error::FormatMismatch(format, result.format()));
+        }
+
+        Ok(result)
+    }
+
+    fn new_internal(mut reader: R, start: u64, raw_if_unknown: bool, strict: bool) -> Result<Self> {
+        reader.seek(SeekFrom::Start(start))?;
+
         let mut magic = [0u8; 4];
         reader.read_exact(&mut magic)?;
 
-        reader.rewind()?;
+        reader.seek(SeekFrom::Start(start))?;
 
         if &magic[0..2] == GZIP_MAGIC {
-            Ok(Self::Gzip(GzDecoder::new(reader)))
+            if strict {
+                // A truncated header is reported by the decoder instead.
+                let mismatch = read_gzip_header(BufReader::new(&mut reader))
+                    .map_or(false, |(header, stored)| {
+                        is_gzip_header_crc_mismatch(&header, stored)
+                    });
+                reader.seek(SeekFrom::Start(start))?;
+
+                if mismatch {
+                    return Err(Error::HeaderChecksumMismatch);
+                }
+            }
+
+            let reader = BufReader::with_capacity(DECODER_BUFFER_SIZE, reader);
+            Ok(Self::Gzip(GzipDecoder::new(reader, true)))
         } else if &magic == LZ4_LEGACY_MAGIC {
             Ok(Self::Lz4(FrameDecoder::new(reader)))
+        } else if &magic == LZ4_FRAME_MAGIC {
+            // A truncated descriptor is reported by the decoder instead.
+            let size = lz4_frame_content_size(&mut reader).ok().flatten();
+            reader.seek(SeekFrom::Start(start))?;
+
+            Ok(Self::Lz4Frame(FrameDecoder::new(reader), size))
+        } else if &magic == XZ_MAGIC {
+            if cfg!(not(feature = "native")) {
+                return Err(Error::UnsupportedReadFormat(CompressedFormat::Xz));
+            }
+
+            let reader = BufReader::with_capacity(DECODER_BUFFER_SIZE, reader);
+            Ok(Self::Xz(XzDecoder::new_multi_decoder(reader)))
         } else if raw_if_unknown {
             Ok(Self::None(reader))
         } else {
@@ -127,21 +1011,365 @@ impl<R: Read + Seek> CompressedReader<R> {
         }
     }
 
-    pub fn format(&self) -> CompressedFormat {
+    /// Get the uncompressed size if it is stored before the compressed data.
+    /// This is only the case for modern LZ4 frames with the content size field
+    /// set. The value is not validated until the whole stream is decoded, so it
+    /// should only be used for preallocation.
+    pub fn uncompressed_size_hint(&self) -> Option<u64> {
         match self {
-            Self::None(_) => CompressedFormat::None,
-            Self::Gzip(_) => CompressedFormat::Gzip,
-            Self::Lz4(_) => CompressedFormat::Lz4Legacy,
+            Self::Lz4Frame(_, size) => *size,
+            _ => None,
         }
     }
 
+    /// Get the underlying reader. The position of the reader is unspecified
+    /// because the decoder may have read ahead. Use [`Self::into_parts()`] if
+    /// the data after the compressed stream is needed.
     pub fn into_inner(self) -> R {
         match self {
             Self::None(r) => r,
-            Self::Gzip(r) => r.into_inner(),
-            Self::Lz4(r) => r.into_inner(),
+            Self::Gzip(r) => r.into_inner().into_inner(),
+            Self::Lz4(r) | Self::Lz4Frame(r, _) => r.into_inner(),
+            Self::Xz(r) => r.into_inner().into_inner(),
+        }
+    }
+
+    /// Get the underlying reader, positioned immediately after the last raw
+    /// byte that the decoder consumed, along with the number of raw bytes
+    /// consumed since the start of the stream. Input that was read ahead, but
+    /// not consumed, is given back by seeking. This allows reading data that
+    /// follows the compressed stream, like another concatenated archive.
+    ///
+    /// To be positioned after the end of the compressed stream, including any
+    /// trailer, read until EOF first (eg. with [`Self::drain()`]). Otherwise,
+    /// the position may be in the middle of the stream. For LZ4, it is always
+    /// at a block boundary because whole blocks are decoded at a time.
+    pub fn into_parts(self) -> io::Result<(R, u64)> {
+        match self {
+            Self::None(mut r) => {
+                let consumed = r.stream_position()?;
+                Ok((r, consumed))
+            }
+            // lz4_flex reads the exact size of each header and block from the
+            // reader and never reads ahead.
+            Self::Lz4(r) | Self::Lz4Frame(r, _) => {
+                let mut r = r.into_inner();
+                let consumed = r.stream_position()?;
+                Ok((r, consumed))
+            }
+            Self::Gzip(r) => Self::unbuffer(r.into_inner()),
+            Self::Xz(r) => Self::unbuffer(r.into_inner()),
+        }
+    }
+
+    /// Seek the inner reader back to the first byte that was not consumed
+    /// from the buffer.
+    fn unbuffer<T: Read + Seek>(mut reader: BufReader<T>) -> io::Result<(T, u64)> {
+        // This accounts for the data remaining in the buffer.
+        let consumed = reader.stream_position()?;
+
+        let mut inner = reader.into_inner();
+        inner.seek(SeekFrom::Start(consumed))?;
+
+        Ok((inner, consumed))
+    }
+
+    /// Decompress all of the remaining data. Errors from the decoder are
+    /// categorized into the [`Error`] variants. If the error is recoverable
+    /// (see [`Error::is_recoverable()`]), then the data is returned along with
+    /// the error and the caller can decide whether to accept it.
+    pub fn decompress_all(&mut self) -> Result<(Vec<u8>, Option<Error>)> {
+        let capacity = self
+            .uncompressed_size_hint()
+            .map_or(0, |s| s.min(PREALLOCATE_MAX_SIZE));
+        let mut data = Vec::with_capacity(capacity as usize);
+
+        match self.read_to_end(&mut data) {
+            Ok(_) => {
+                let header_crc_mismatch = match self {
+                    Self::Gzip(r) => r.header_crc_mismatch(),
+                    _ => false,
+                };
+
+                let error =
+                    header_crc_mismatch.then_some(Error::ChecksumMismatch(data.len() as u64));
+
+                Ok((data, error))
+            }
+            Err(e) => {
+                // read_to_end() keeps all data that was read before the error.
+                let error = categorize_error(self.format(), e, data.len() as u64);
+
+                if error.is_recoverable() {
+                    Ok((data, Some(error)))
+                } else {
+                    Err(error)
+                }
+            }
+        }
+    }
+
+    /// Read and discard all of the remaining data without buffering it. This
+    /// returns the number of decompressed bytes that were skipped. Since the
+    /// whole stream is consumed, checksums at the end of the stream, if any,
+    /// are verified.
+    pub fn drain(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 16384];
+        let mut skipped = 0;
+
+        loop {
+            match self.read(&mut buf) {
+                Ok(0) => return Ok(skipped),
+                Ok(n) => skipped += n as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
     }
+
+    /// Decompress the remaining data into owned buffers of `chunk_size` bytes,
+    /// which can be handed off to other threads. Only the last chunk may be
+    /// shorter. If an error occurs, the partially filled chunk is yielded first
+    /// and the error is yielded as the final item.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunks(mut self, chunk_size: usize) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+        assert!(chunk_size > 0, "Chunk size must be non-zero");
+
+        let mut error = None;
+        let mut done = false;
+
+        iter::from_fn(move || {
+            if let Some(e) = error.take() {
+                return Some(Err(e));
+            } else if done {
+                return None;
+            }
+
+            let mut chunk = vec![0u8; chunk_size];
+            let mut n = 0;
+
+            while n < chunk_size {
+                match self.read(&mut chunk[n..]) {
+                    Ok(0) => break,
+                    Ok(r) => n += r,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            if n < chunk_size {
+                done = true;
+            }
+
+            if n == 0 {
+                return error.take().map(Err);
+            }
+
+            chunk.truncate(n);
+            Some(Ok(chunk))
+        })
+    }
+}
+
+/// Presents several independently compressed segments of a file as one
+/// continuous stream of decompressed data. This is for containers that store
+/// eg. a gzip-compressed section followed by an LZ4-compressed section. Each
+/// segment extends until the next segment's offset or the end of the file.
+pub struct SegmentedCompressedReader<R: Read + Seek> {
+    /// Offset, size, and format of each segment.
+    segments: Vec<(u64, u64, Option<CompressedFormat>)>,
+    /// Index of the next segment to open.
+    next: usize,
+    current: Option<CompressedReader<SectionReader<R>>>,
+    /// The source reader while no segment is open. This is [`None`] if a
+    /// segment failed to open.
+    inner: Option<R>,
+}
+
+impl<R: Read + Seek> SegmentedCompressedReader<R> {
+    /// Create a reader for `segments`, which consists of the offset of each
+    /// segment in increasing order and its format. If the format is [`None`],
+    /// it is detected like with [`CompressedReader::new()`] and unknown
+    /// formats are read as is. Otherwise, the segment must be in the specified
+    /// format (see [`CompressedReader::with_format()`]). Every segment must
+    /// contain at least one byte.
+    pub fn new(mut reader: R, segments: &[(u64, Option<CompressedFormat>)]) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut result = vec![];
+
+        for (i, (offset, format)) in segments.iter().enumerate() {
+            if *offset > len {
+                return Err(Error::OffsetOutOfBounds(*offset, len));
+            } else if i > 0 && *offset <= segments[i - 1].0 {
+                return Err(Error::UnorderedSegment(*offset));
+            }
+
+            // Only the last segment can be empty since the offsets are
+            // increasing and within bounds.
+            let end = segments.get(i + 1).map_or(len, |(o, _)| (*o).min(len));
+            if end <= *offset {
+                return Err(Error::EmptySegment(*offset));
+            }
+
+            result.push((*offset, end - *offset, *format));
+        }
+
+        Ok(Self {
+            segments: result,
+            next: 0,
+            current: None,
+            inner: Some(reader),
+        })
+    }
+
+    /// Format of the segment currently being read, or [`None`] if no segment
+    /// has been opened yet or all segments have been read.
+    pub fn format(&self) -> Option<CompressedFormat> {
+        self.current.as_ref().map(|r| r.format())
+    }
+
+    fn open_next(&mut self) -> io::Result<bool> {
+        let Some(&(offset, size, format)) = self.segments.get(self.next) else {
+            return Ok(false);
+        };
+
+        let Some(inner) = self.inner.take() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Previous segment failed to open",
+            ));
+        };
+        let section = SectionReader::new(inner, offset, size)?;
+        let reader = match format {
+            Some(f) => CompressedReader::with_format(section, f),
+            None => CompressedReader::new(section, true),
+        };
+
+        self.current = Some(reader.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to open segment at offset {offset}: {e}"),
+            )
+        })?);
+        self.next += 1;
+
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for SegmentedCompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(reader) = &mut self.current {
+                let n = reader.read(buf)?;
+                if n > 0 {
+                    return Ok(n);
+                }
+
+                // Give the source back so that the next segment can use it.
+                let reader = self.current.take().unwrap();
+                self.inner = Some(reader.into_inner().into_inner());
+            }
+
+            if !self.open_next()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// Convert an I/O error from a decoder to a more specific [`Error`]. `size` is
+/// the number of bytes that were decompressed before the error.
+fn categorize_error(format: CompressedFormat, e: io::Error, size: u64) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return Error::Truncated;
+    }
+
+    let lz4_error = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<lz4_flex::frame::Error>());
+
+    let is_checksum_error = match format {
+        CompressedFormat::Gzip => e
+            .get_ref()
+            .map_or(false, |inner| inner.is::<GzipTrailerMismatch>()),
+        CompressedFormat::Lz4Frame => matches!(
+            lz4_error,
+            Some(lz4_flex::frame::Error::ContentChecksumError),
+        ),
+        // Legacy LZ4 frames have no checksums and liblzma does not distinguish
+        // between integrity check failures and other corruption.
+        CompressedFormat::None | CompressedFormat::Lz4Legacy | CompressedFormat::Xz => false,
+    };
+
+    if is_checksum_error {
+        Error::ChecksumMismatch(size)
+    } else if lz4_error.is_some()
+        || matches!(
+            e.kind(),
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+        )
+    {
+        Error::Corrupt(e)
+    } else {
+        Error::IoError(e)
+    }
+}
+
+impl<R: Read> CompressedReader<R> {
+    /// Create a decoder for `format` without detecting the format from the
+    /// data. This is for decoding many streams that are known to use the same
+    /// format as one that was detected with [`Self::new()`]. Unlike the other
+    /// constructors, the reader does not need to be seekable. Gzip header CRCs
+    /// are always checked and invalid data is only reported when it is read.
+    pub fn reader_for(format: CompressedFormat, reader: R) -> Self {
+        match format {
+            CompressedFormat::None => Self::None(reader),
+            CompressedFormat::Gzip => {
+                let reader = BufReader::with_capacity(DECODER_BUFFER_SIZE, reader);
+                Self::Gzip(GzipDecoder::new(reader, false))
+            }
+            CompressedFormat::Lz4Legacy => Self::Lz4(FrameDecoder::new(reader)),
+            CompressedFormat::Lz4Frame => Self::Lz4Frame(FrameDecoder::new(reader), None),
+            CompressedFormat::Xz => {
+                let reader = BufReader::with_capacity(DECODER_BUFFER_SIZE, reader);
+                Self::Xz(XzDecoder::new_multi_decoder(reader))
+            }
+        }
+    }
+
+    pub fn format(&self) -> CompressedFormat {
+        match self {
+            Self::None(_) => CompressedFormat::None,
+            Self::Gzip(_) => CompressedFormat::Gzip,
+            Self::Lz4(_) => CompressedFormat::Lz4Legacy,
+            Self::Lz4Frame(_, _) => CompressedFormat::Lz4Frame,
+            Self::Xz(_) => CompressedFormat::Xz,
+        }
+    }
+
+    /// Read at most `max` decompressed bytes into `buf`, even if `buf` is
+    /// larger. This only bounds the amount of data returned per call, not the
+    /// amount of work. Decoders for formats with large blocks, like the 8 MiB
+    /// blocks of LZ4 legacy, still decompress a whole block when their
+    /// internal buffer is empty. Any decompressed data that doesn't fit is kept
+    /// by the decoder and returned by the next call. As with [`Read::read()`],
+    /// 0 is returned at the end of the stream and also if `max` or `buf` is
+    /// empty.
+    pub fn read_up_to(&mut self, max: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let len = max.min(buf.len());
+        self.read(&mut buf[..len])
+    }
 }
 
 impl<R: Read> Read for CompressedReader<R> {
@@ -149,59 +1377,514 @@ impl<R: Read> Read for CompressedReader<R> {
         match self {
             Self::None(r) => r.read(buf),
             Self::Gzip(r) => r.read(buf),
-            Self::Lz4(r) => r.read(buf),
+            Self::Lz4(r) | Self::Lz4Frame(r, _) => r.read(buf),
+            Self::Xz(r) => r.read(buf),
+        }
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            Self::None(r) => r.read_vectored(bufs),
+            Self::Gzip(r) => r.read_vectored(bufs),
+            Self::Lz4(r) | Self::Lz4Frame(r, _) => r.read_vectored(bufs),
+            Self::Xz(r) => r.read_vectored(bufs),
+        }
+    }
+}
+
+/// Configuration for [`CompressedWriter::with_options()`]. The same options
+/// can be reused for any number of writers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressionOptions {
+    pub format: CompressedFormat,
+    /// Compression level from 0 to 9 for gzip and xz. If unset, the default of
+    /// each format is used. This is ignored for LZ4 legacy, which only has a
+    /// single level.
+    pub level: Option<u32>,
+    /// Maximum amount of uncompressed data per LZ4 legacy block. If unset, the
+    /// maximum of 8 MiB is used. This is ignored for other formats.
+    pub block_size: Option<usize>,
+    /// See [`Lz4LegacyEncoder::set_min_block_fill()`]. This is ignored for
+    /// other formats.
+    pub min_block_fill: Option<usize>,
+    /// Whether to store an integrity check in xz streams. This is always a
+    /// CRC32 because the kernel's xz decompressor does not support CRC64. Gzip
+    /// always stores a CRC32 and LZ4 legacy has no checksum, so this only
+    /// affects xz.
+    pub checksum: bool,
+    /// Gzip header fields.
+    pub gzip: GzipOptions,
+    /// See [`CompressedWriter::with_size_hint()`].
+    pub size_hint: Option<usize>,
+    /// Maximum number of uncompressed bytes that can be written. Writes past
+    /// the limit fail with [`Error::InputTooLarge`].
+    pub size_limit: Option<u64>,
+    /// Time after which writes fail with [`Error::DeadlineExceeded`] and
+    /// [`io::ErrorKind::TimedOut`]. This is only checked about once per MiB of
+    /// input, so it may be exceeded slightly. Finishing the stream fails too if
+    /// the deadline has passed by the time it is done.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            format: CompressedFormat::None,
+            level: None,
+            block_size: None,
+            min_block_fill: None,
+            checksum: true,
+            gzip: GzipOptions::default(),
+            size_hint: None,
+            size_limit: None,
+            deadline: None,
+        }
+    }
+}
+
+impl CompressionOptions {
+    /// Default options for `format`.
+    pub fn new(format: CompressedFormat) -> Self {
+        Self {
+            format,
+            ..Default::default()
         }
     }
 }
 
-pub enum CompressedWriter<W: Write> {
+enum Encoder<W: Write> {
     None(W),
-    Gzip(GzEncoder<W>),
+    Gzip(GzipEncoder<W>),
     Lz4Legacy(Lz4LegacyEncoder<W>),
+    #[cfg(feature = "native")]
+    Xz(XzEncoder<W>),
+}
+
+pub struct CompressedWriter<W: Write> {
+    encoder: Encoder<W>,
+    size_limit: Option<u64>,
+    deadline: Option<Instant>,
+    /// Number of uncompressed bytes written so far.
+    written: u64,
+    /// Value of [`Self::written`] at which the deadline is checked next.
+    next_deadline_check: u64,
 }
 
 impl<W: Write> CompressedWriter<W> {
     pub fn new(writer: W, format: CompressedFormat) -> Result<Self> {
-        match format {
-            CompressedFormat::None => Ok(Self::None(writer)),
+        Self::with_options(writer, &CompressionOptions::new(format))
+    }
+
+    /// Like [`Self::new()`], but with a hint for the expected amount of
+    /// uncompressed data. This only affects how much memory is allocated up
+    /// front and never changes the output.
+    pub fn with_size_hint(writer: W, format: CompressedFormat, size_hint: usize) -> Result<Self> {
+        let options = CompressionOptions {
+            size_hint: Some(size_hint),
+            ..CompressionOptions::new(format)
+        };
+
+        Self::with_options(writer, &options)
+    }
+
+    /// Create a gzip writer with the specified header fields.
+    pub fn with_gzip_options(writer: W, options: &GzipOptions) -> Self {
+        Self::from_encoder(Encoder::Gzip(GzipEncoder::new(writer, options)), None, None)
+    }
+
+    /// Create a writer with all settings taken from `options`. Invalid levels
+    /// and block sizes are rejected instead of being clamped.
+    pub fn with_options(writer: W, options: &CompressionOptions) -> Result<Self> {
+        let format = options.format;
+
+        if let Some(level) = options.level {
+            if matches!(format, CompressedFormat::Gzip | CompressedFormat::Xz) && level > MAX_LEVEL
+            {
+                return Err(Error::InvalidLevel(format, level));
+            }
+        }
+
+        if let Some(block_size) = options.block_size {
+            if block_size == 0 || block_size > LZ4_LEGACY_BLOCK_SIZE {
+                return Err(Error::InvalidBlockSize(block_size));
+            }
+        }
+
+        let encoder = match format {
+            CompressedFormat::None => Encoder::None(writer),
             CompressedFormat::Gzip => {
-                Ok(Self::Gzip(GzEncoder::new(writer, Compression::default())))
+                let level = options
+                    .level
+                    .unwrap_or_else(|| Compression::default().level());
+                Encoder::Gzip(GzipEncoder::with_level(writer, &options.gzip, level))
+            }
+            CompressedFormat::Lz4Legacy => {
+                let mut encoder = match options.size_hint {
+                    Some(size_hint) => Lz4LegacyEncoder::with_size_hint(writer, size_hint)?,
+                    None => Lz4LegacyEncoder::new(writer)?,
+                };
+                if let Some(block_size) = options.block_size {
+                    encoder.set_block_size(block_size);
+                }
+                encoder.set_min_block_fill(options.min_block_fill);
+
+                Encoder::Lz4Legacy(encoder)
             }
-            CompressedFormat::Lz4Legacy => Ok(Self::Lz4Legacy(Lz4LegacyEncoder::new(writer)?)),
+            CompressedFormat::Lz4Frame => return Err(Error::UnsupportedWriteFormat(format)),
+            #[cfg(not(feature = "native"))]
+            CompressedFormat::Xz => return Err(Error::UnsupportedWriteFormat(format)),
+            #[cfg(feature = "native")]
+            CompressedFormat::Xz => {
+                let level = options.level.unwrap_or(XZ_DEFAULT_LEVEL);
+                let check = if options.checksum {
+                    Check::Crc32
+                } else {
+                    Check::None
+                };
+                let stream = Stream::new_easy_encoder(level, check).map_err(io::Error::from)?;
+
+                Encoder::Xz(XzEncoder::new_stream(writer, stream))
+            }
+        };
+
+        Ok(Self::from_encoder(
+            encoder,
+            options.size_limit,
+            options.deadline,
+        ))
+    }
+
+    fn from_encoder(
+        encoder: Encoder<W>,
+        size_limit: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> Self {
+        Self {
+            encoder,
+            size_limit,
+            deadline,
+            written: 0,
+            next_deadline_check: 0,
         }
     }
 
     pub fn format(&self) -> CompressedFormat {
-        match self {
-            Self::None(_) => CompressedFormat::None,
-            Self::Gzip(_) => CompressedFormat::Gzip,
-            Self::Lz4Legacy(_) => CompressedFormat::Lz4Legacy,
+        match &self.encoder {
+            Encoder::None(_) => CompressedFormat::None,
+            Encoder::Gzip(_) => CompressedFormat::Gzip,
+            Encoder::Lz4Legacy(_) => CompressedFormat::Lz4Legacy,
+            #[cfg(feature = "native")]
+            Encoder::Xz(_) => CompressedFormat::Xz,
+        }
+    }
+
+    /// End the current block for formats where each block can be decoded
+    /// independently. This returns [`None`] for other formats. See
+    /// [`Lz4LegacyEncoder::flush_block_boundary()`].
+    pub fn flush_block_boundary(&mut self) -> io::Result<Option<BlockIndexEntry>> {
+        match &mut self.encoder {
+            Encoder::Lz4Legacy(w) => w.flush_block_boundary().map(Some),
+            _ => Ok(None),
         }
     }
 
+    /// Write out all remaining compressed data without consuming the writer.
+    /// This is meant for non-blocking writers. If this returns
+    /// [`io::ErrorKind::WouldBlock`], it can be called again to resume.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(w) => w.try_finish(),
+            Encoder::Lz4Legacy(w) => w.try_finish(),
+            #[cfg(feature = "native")]
+            Encoder::Xz(w) => w.try_finish(),
+        }?;
+
+        check_deadline_now(self.deadline)
+    }
+
+    /// Write out all remaining compressed data and flush the inner writer. If
+    /// the inner writer is a [`BufWriter`], nothing is left in its buffer.
     pub fn finish(self) -> io::Result<W> {
-        match self {
-            Self::None(w) => Ok(w),
-            Self::Gzip(w) => w.finish(),
-            Self::Lz4Legacy(w) => w.finish(),
+        let mut writer = match self.encoder {
+            Encoder::None(w) => w,
+            Encoder::Gzip(w) => w.finish()?,
+            Encoder::Lz4Legacy(w) => w.finish()?,
+            #[cfg(feature = "native")]
+            Encoder::Xz(w) => w.finish()?,
+        };
+
+        writer.flush()?;
+        check_deadline_now(self.deadline)?;
+
+        Ok(writer)
+    }
+
+    /// Number of bytes out of `len` that can be written without exceeding the
+    /// size limit. Fails if the limit has already been reached.
+    fn allowed_len(&self, len: usize) -> io::Result<usize> {
+        let Some(limit) = self.size_limit else {
+            return Ok(len);
+        };
+
+        let remaining = limit - self.written;
+        if remaining == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                Error::InputTooLarge(limit),
+            ));
         }
+
+        Ok(remaining.min(len as u64) as usize)
     }
+
+    /// Fail if the deadline has passed. The clock is only read after every
+    /// [`DEADLINE_CHECK_INTERVAL`] bytes of input.
+    fn check_deadline(&mut self) -> io::Result<()> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+
+        if self.written >= self.next_deadline_check {
+            check_deadline_now(Some(deadline))?;
+
+            self.next_deadline_check = self.written + DEADLINE_CHECK_INTERVAL;
+        }
+
+        Ok(())
+    }
+}
+
+/// Fail if `deadline` has passed. Finishing a stream can take as long as
+/// writing a large chunk of input, so [`CompressedWriter`] always reads the
+/// clock afterwards.
+fn check_deadline_now(deadline: Option<Instant>) -> io::Result<()> {
+    if deadline.map_or(false, |d| Instant::now() >= d) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            Error::DeadlineExceeded,
+        ));
+    }
+
+    Ok(())
 }
 
 impl<W: Write> Write for CompressedWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self {
-            Self::None(w) => w.write(buf),
-            Self::Gzip(w) => w.write(buf),
-            Self::Lz4Legacy(w) => w.write(buf),
+        let buf = &buf[..self.allowed_len(buf.len())?];
+        self.check_deadline()?;
+
+        let n = match &mut self.encoder {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::Lz4Legacy(w) => w.write(buf),
+            #[cfg(feature = "native")]
+            Encoder::Xz(w) => w.write(buf),
+        }?;
+
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let total = bufs.iter().map(|b| b.len()).sum::<usize>();
+        if self.allowed_len(total)? < total {
+            // Close to the limit, so just write what's allowed of the first
+            // non-empty buffer.
+            let buf = bufs.iter().find(|b| !b.is_empty()).unwrap();
+            return self.write(buf);
         }
+
+        self.check_deadline()?;
+
+        let n = match &mut self.encoder {
+            Encoder::None(w) => w.write_vectored(bufs),
+            Encoder::Gzip(w) => w.write_vectored(bufs),
+            Encoder::Lz4Legacy(w) => w.write_vectored(bufs),
+            #[cfg(feature = "native")]
+            Encoder::Xz(w) => write_coalesced(w, bufs),
+        }?;
+
+        self.written += n as u64;
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self {
-            Self::None(w) => w.flush(),
-            Self::Gzip(w) => w.flush(),
-            Self::Lz4Legacy(w) => w.flush(),
+        match &mut self.encoder {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::Lz4Legacy(w) => w.flush(),
+            #[cfg(feature = "native")]
+            Encoder::Xz(w) => w.flush(),
+        }
+    }
+}
+
+/// Open a standalone file for reading. If the file is gzip or xz compressed,
+/// it is decompressed to an anonymous temporary file first so that the result
+/// is always seekable. Other files, including LZ4-compressed files, are opened
+/// as-is because the boot image parser handles those itself.
+#[cfg(feature = "native")]
+pub fn open_standalone(path: &Path) -> Result<PSeekFile> {
+    let file = File::open(path)?;
+    let mut reader = CompressedReader::new(BufReader::with_capacity(FILE_BUFFER_SIZE, file), true)?;
+
+    match reader.format() {
+        CompressedFormat::Gzip | CompressedFormat::Xz => {}
+        _ => {
+            let file = reader.into_inner().into_inner();
+            return Ok(PSeekFile::new(file));
         }
     }
+
+    let mut temp = tempfile::tempfile()?;
+    io::copy(&mut reader, &mut temp)?;
+    temp.rewind()?;
+
+    Ok(PSeekFile::new(temp))
+}
+
+/// Create a standalone file for writing with the specified compression format.
+/// The file is buffered with [`FILE_BUFFER_SIZE`] bytes and is fully written
+/// once [`CompressedWriter::finish()`] returns.
+#[cfg(feature = "native")]
+pub fn create_standalone(
+    path: &Path,
+    format: CompressedFormat,
+) -> Result<CompressedWriter<BufWriter<File>>> {
+    let file = File::create(path)?;
+
+    CompressedWriter::new(BufWriter::with_capacity(FILE_BUFFER_SIZE, file), format)
+}
+
+/// Extensions recognized by [`compress_file()`]. This is intentionally separate
+/// from [`CompressedFormat::from_extension()`] because LZ4 files are normally
+/// left compressed for the boot image parser to handle.
+#[cfg(feature = "native")]
+const COMPRESSED_EXTENSIONS: &[(&str, CompressedFormat)] = &[
+    ("gz", CompressedFormat::Gzip),
+    ("lz4", CompressedFormat::Lz4Legacy),
+    ("xz", CompressedFormat::Xz),
+];
+
+/// Compress `input` to `output`, with the format picked from the extension of
+/// `output`. Returns the number of uncompressed bytes.
+#[cfg(feature = "native")]
+pub fn compress_file(input: &Path, output: &Path) -> Result<u64> {
+    let extension = output.extension().and_then(|e| e.to_str());
+    let Some(format) = COMPRESSED_EXTENSIONS
+        .iter()
+        .find(|(e, _)| Some(*e) == extension)
+        .map(|(_, f)| *f)
+    else {
+        let supported = COMPRESSED_EXTENSIONS
+            .iter()
+            .map(|(e, _)| format!(".{e}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Err(Error::UnknownExtension(output.to_owned(), supported));
+    };
+
+    let mut reader = BufReader::with_capacity(FILE_BUFFER_SIZE, File::open(input)?);
+    let mut writer = create_standalone(output, format)?;
+    let n = io::copy(&mut reader, &mut writer)?;
+    writer.finish()?;
+
+    Ok(n)
+}
+
+/// Decompress `input` to `output`. The input format is detected automatically
+/// and must be compressed. Returns the number of decompressed bytes.
+#[cfg(feature = "native")]
+pub fn decompress_file(input: &Path, output: &Path) -> Result<u64> {
+    let file = File::open(input)?;
+    let mut reader =
+        CompressedReader::new(BufReader::with_capacity(FILE_BUFFER_SIZE, file), false)?;
+    let format = reader.format();
+
+    let file = File::create(output)?;
+    let mut writer = BufWriter::with_capacity(FILE_BUFFER_SIZE, file);
+    let mut buf = [0u8; 16384];
+    let mut written = 0;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(categorize_error(format, e, written)),
+        };
+
+        writer.write_all(&buf[..n])?;
+        written += n as u64;
+    }
+
+    writer.flush()?;
+
+    Ok(written)
+}
+
+fn compressed_size(data: &[u8], format: CompressedFormat) -> Result<u64> {
+    let writer = CountingWriter::new(io::sink());
+    let mut writer = CompressedWriter::with_size_hint(writer, format, data.len())?;
+    writer.write_all(data)?;
+
+    let (_, size) = writer.finish()?.finish();
+
+    Ok(size)
+}
+
+/// Estimate the size of `data` after compressing it with `format`. Small inputs
+/// are compressed in full, which gives the exact size. Otherwise, only evenly
+/// spaced samples are compressed and the result is extrapolated.
+pub fn estimate_size(data: &[u8], format: CompressedFormat) -> Result<u64> {
+    let sampled_size = ESTIMATE_SAMPLE_SIZE * ESTIMATE_SAMPLE_COUNT;
+    if data.len() <= sampled_size {
+        return compressed_size(data, format);
+    }
+
+    let step = data.len() / ESTIMATE_SAMPLE_COUNT;
+    let mut total = 0;
+
+    for i in 0..ESTIMATE_SAMPLE_COUNT {
+        let offset = i * step;
+        total += compressed_size(&data[offset..offset + ESTIMATE_SAMPLE_SIZE], format)?;
+    }
+
+    let estimate = u128::from(total) * data.len() as u128 / sampled_size as u128;
+
+    Ok(estimate as u64)
+}
+
+/// Decompress `data` and compress it again in the same format with fixed
+/// parameters so that the output does not depend on how the data was
+/// originally compressed. Gzip and xz use level 9, gzip headers have an mtime
+/// of 0 and an unknown OS (255), xz streams have a CRC32 check, and LZ4 legacy
+/// uses the default 8 MiB block size. Uncompressed data is returned as is.
+///
+/// Normalizing already normalized data produces the same bytes. LZ4 frame data
+/// fails with [`Error::UnsupportedWriteFormat`] and recoverable decompression
+/// errors (see [`Error::is_recoverable()`]) are returned as errors.
+pub fn normalize(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = CompressedReader::new(io::Cursor::new(data), true)?;
+    let format = reader.format();
+    if format == CompressedFormat::None {
+        return Ok(data.to_vec());
+    }
+
+    let (raw, error) = reader.decompress_all()?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let options = CompressionOptions {
+        level: Some(NORMALIZE_LEVEL),
+        size_hint: Some(raw.len()),
+        ..CompressionOptions::new(format)
+    };
+    let mut writer = CompressedWriter::with_options(Vec::new(), &options)?;
+    writer.write_all(&raw)?;
+
+    Ok(writer.finish()?)
 }
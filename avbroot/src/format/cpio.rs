@@ -6,7 +6,7 @@
 use std::{
     borrow::Cow,
     fmt,
-    io::{self, Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
 };
 
 use num_traits::ToPrimitive;
@@ -14,12 +14,24 @@ use thiserror::Error;
 
 use crate::{
     format::padding,
-    stream::{CountingReader, CountingWriter, FromReader, ToWriter, WriteZerosExt},
+    stream::{CountingReader, CountingWriter, FromReader, SectionReader, ToWriter, WriteZerosExt},
     util::{EscapedString, NumBytes},
 };
 
 const MAGIC_NEW: &[u8; 6] = b"070701";
 const MAGIC_NEW_CRC: &[u8; 6] = b"070702";
+/// The "newcx" format from the proposed Linux initramfs xattr support. The
+/// header has an additional `xattrsize` field after `chksum` and the xattrs are
+/// stored between the padded filename and the content.
+///
+/// This format was never merged. Mainline kernels, and therefore stock Android
+/// kernels, fail to unpack an initramfs containing these entries, so it only
+/// works on kernels that carry the out-of-tree patches. Neither AOSP's mkbootfs
+/// nor any other released tool writes it. Stock ramdisks use plain newc and get
+/// their SELinux labels from `file_contexts` at boot instead. Archives in this
+/// format are read and written back as-is, but avbroot never converts an
+/// archive to it unless [`CpioEntryNew::enable_xattrs()`] is called.
+const MAGIC_NEW_XATTR: &[u8; 6] = b"070703";
 
 const CPIO_TRAILER: &[u8; 10] = b"TRAILER!!!";
 
@@ -34,6 +46,12 @@ const C_ISCTG: u32 = 0o110000;
 
 const IO_BLOCK_SIZE: u64 = 512;
 
+pub const XATTR_SELINUX: &[u8] = b"security.selinux";
+pub const XATTR_CAPABILITY: &[u8] = b"security.capability";
+
+/// SELinux label for files in the root of the ramdisk.
+pub const ROOTFS_LABEL: &[u8] = b"u:object_r:rootfs:s0";
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unknown magic: {0:?}")]
@@ -42,6 +60,10 @@ pub enum Error {
     HardLinksNotSupported(EscapedString<Vec<u8>>),
     #[error("{0:?} field exceeds integer bounds")]
     IntegerTooLarge(&'static str),
+    #[error("Invalid extended attributes: {0}")]
+    InvalidXattrs(EscapedString<Vec<u8>>),
+    #[error("Extended attributes are not enabled for entry: {0}")]
+    XattrsNotEnabled(EscapedString<Vec<u8>>),
     #[error("I/O error")]
     IoError(#[from] io::Error),
 }
@@ -89,6 +111,41 @@ fn file_type(mode: u32) -> u32 {
     mode & 0o170000
 }
 
+/// Parse a list of xattr records. Each record consists of its total size
+/// (including the size field) as an ASCII 8-char wide hex string, the
+/// NULL-terminated name, and the value.
+fn parse_xattrs(mut data: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut xattrs = vec![];
+
+    while !data.is_empty() {
+        let size = read_int(&mut data).ok()?.to_usize()?;
+        let record = data.get(..size.checked_sub(8)?)?;
+        let name_len = record.iter().position(|b| *b == b'\0')?;
+
+        xattrs.push((record[..name_len].to_vec(), record[name_len + 1..].to_vec()));
+        data = &data[record.len()..];
+    }
+
+    Some(xattrs)
+}
+
+fn serialize_xattrs(xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut data = vec![];
+
+    for (name, value) in xattrs {
+        let size = (8 + name.len() + 1 + value.len())
+            .to_u32()
+            .ok_or_else(|| Error::IntegerTooLarge("xattrsize"))?;
+
+        write_int(&mut data, size)?;
+        data.extend_from_slice(name);
+        data.push(b'\0');
+        data.extend_from_slice(value);
+    }
+
+    Ok(data)
+}
+
 #[derive(Clone, Default, PartialEq, Eq)]
 pub struct CpioEntryNew {
     pub ino: u32,
@@ -104,6 +161,17 @@ pub struct CpioEntryNew {
     pub chksum: u32,
     pub name: Vec<u8>,
     pub content: Vec<u8>,
+    /// Extended attributes as (name, value) pairs in archive order. This is
+    /// [`None`] for entries in the newc and CRC formats, which cannot store
+    /// xattrs, and [`Some`] for entries in the newcx format, even if the entry
+    /// has no xattrs. This way, entries are written back in the same format
+    /// that they were read in. Mainline kernels cannot unpack the newcx format.
+    /// See [`Self::enable_xattrs()`].
+    pub xattrs: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    /// Whether the entry is in the CRC format. Entries with a nonzero
+    /// [`Self::chksum`] are always written in the CRC format, but this keeps
+    /// entries like empty files, whose checksum is 0, in the same format too.
+    pub crc: bool,
 }
 
 impl fmt::Debug for CpioEntryNew {
@@ -120,8 +188,17 @@ impl fmt::Debug for CpioEntryNew {
             .field("rdev_maj", &self.rdev_maj)
             .field("rdev_min", &self.rdev_min)
             .field("chksum", &self.chksum)
+            .field("crc", &self.crc)
             .field("name", &EscapedString::new(&self.name))
             .field("content", &NumBytes(self.content.len()))
+            .field(
+                "xattrs",
+                &self.xattrs.as_ref().map(|x| {
+                    x.iter()
+                        .map(|(n, v)| (EscapedString::new(n), EscapedString::new(v)))
+                        .collect::<Vec<_>>()
+                }),
+            )
             .finish()
     }
 }
@@ -153,6 +230,21 @@ impl fmt::Display for CpioEntryNew {
         writeln!(f, "Checksum:  {:x}", self.chksum)?;
         writeln!(f, "Content:   {:?}", NumBytes(self.content.len()))?;
 
+        if let Some(label) = self.selinux_label() {
+            writeln!(f, "Label:     {}", EscapedString::new_unquoted(label))?;
+        }
+
+        for (name, value) in self.xattrs.iter().flatten() {
+            if name != XATTR_SELINUX {
+                writeln!(
+                    f,
+                    "Xattr:     {}={}",
+                    EscapedString::new_unquoted(name),
+                    EscapedString::new(value),
+                )?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -194,66 +286,155 @@ impl CpioEntryNew {
             ..Default::default()
         }
     }
-}
 
-impl<R: Read> FromReader<R> for CpioEntryNew {
-    type Error = Error;
+    pub fn is_file(&self) -> bool {
+        file_type(self.mode) == S_IFREG
+    }
 
-    fn from_reader(reader: R) -> Result<Self> {
-        let mut reader = CountingReader::new(reader);
+    pub fn xattr(&self, name: &[u8]) -> Option<&[u8]> {
+        self.xattrs
+            .iter()
+            .flatten()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
 
-        let mut magic = [0u8; 6];
-        reader.read_exact(&mut magic)?;
+    /// Switch the entry to the newcx format so that it can store xattrs. This
+    /// is a no-op if the entry is already in that format. Only do this for
+    /// archives that are known to be unpacked by a kernel with the out-of-tree
+    /// xattr patches. Mainline kernels refuse to unpack the result.
+    pub fn enable_xattrs(&mut self) {
+        self.xattrs.get_or_insert_with(Vec::new);
+    }
 
-        if magic != *MAGIC_NEW && magic != *MAGIC_NEW_CRC {
-            return Err(Error::UnknownMagic(magic));
-        }
+    /// Set an xattr, replacing the existing value if there is one. Fails with
+    /// [`Error::XattrsNotEnabled`] if the entry is not in the newcx format. See
+    /// [`Self::enable_xattrs()`].
+    pub fn set_xattr(&mut self, name: &[u8], value: &[u8]) -> Result<()> {
+        let Some(xattrs) = &mut self.xattrs else {
+            return Err(Error::XattrsNotEnabled(EscapedString::new(
+                self.name.clone(),
+            )));
+        };
 
-        let ino = read_int(&mut reader)?;
-        let mode = read_int(&mut reader)?;
-        let uid = read_int(&mut reader)?;
-        let gid = read_int(&mut reader)?;
-        let nlink = read_int(&mut reader)?;
-        let mtime = read_int(&mut reader)?;
-        let filesize = read_int(&mut reader)?;
-        let dev_maj = read_int(&mut reader)?;
-        let dev_min = read_int(&mut reader)?;
-        let rdev_maj = read_int(&mut reader)?;
-        let rdev_min = read_int(&mut reader)?;
-        let namesize = read_int(&mut reader)?;
-        let chksum = read_int(&mut reader)?;
-
-        let mut name = vec![0u8; namesize.to_usize().unwrap()];
-        reader.read_exact(&mut name)?;
-        if name.last() != Some(&b'\0') {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Filename is not NULL-terminated",
-            )
-            .into());
+        if let Some((_, v)) = xattrs.iter_mut().find(|(n, _)| n == name) {
+            *v = value.to_vec();
+        } else {
+            xattrs.push((name.to_vec(), value.to_vec()));
         }
-        name.pop();
+
+        Ok(())
+    }
+
+    /// Get the SELinux label without the trailing NULL terminator.
+    pub fn selinux_label(&self) -> Option<&[u8]> {
+        self.xattr(XATTR_SELINUX)
+            .map(|v| v.strip_suffix(b"\0").unwrap_or(v))
+    }
+
+    /// Set the SELinux label. The value is stored NULL-terminated, like the
+    /// kernel does. This has the same requirements as [`Self::set_xattr()`].
+    pub fn set_selinux_label(&mut self, label: &[u8]) -> Result<()> {
+        let mut value = label.to_vec();
+        value.push(b'\0');
+
+        self.set_xattr(XATTR_SELINUX, &value)
+    }
+}
+
+/// Read everything in an entry up to the content, which is left empty. The
+/// content size is returned alongside the entry. The reader is left aligned to
+/// the start of the content.
+fn read_header<R: Read>(mut reader: &mut CountingReader<R>) -> Result<(CpioEntryNew, u32)> {
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+
+    let crc = magic == *MAGIC_NEW_CRC;
+    let has_xattrs = if magic == *MAGIC_NEW_XATTR {
+        true
+    } else if magic == *MAGIC_NEW || magic == *MAGIC_NEW_CRC {
+        false
+    } else {
+        return Err(Error::UnknownMagic(magic));
+    };
+
+    let ino = read_int(&mut reader)?;
+    let mode = read_int(&mut reader)?;
+    let uid = read_int(&mut reader)?;
+    let gid = read_int(&mut reader)?;
+    let nlink = read_int(&mut reader)?;
+    let mtime = read_int(&mut reader)?;
+    let filesize = read_int(&mut reader)?;
+    let dev_maj = read_int(&mut reader)?;
+    let dev_min = read_int(&mut reader)?;
+    let rdev_maj = read_int(&mut reader)?;
+    let rdev_min = read_int(&mut reader)?;
+    let namesize = read_int(&mut reader)?;
+    let chksum = read_int(&mut reader)?;
+    let xattrsize = if has_xattrs {
+        read_int(&mut reader)?
+    } else {
+        0
+    };
+
+    let mut name = vec![0u8; namesize.to_usize().unwrap()];
+    reader.read_exact(&mut name)?;
+    if name.last() != Some(&b'\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Filename is not NULL-terminated",
+        )
+        .into());
+    }
+    name.pop();
+    padding::read_discard(&mut reader, 4)?;
+
+    let xattrs = if has_xattrs {
+        let mut data = vec![0u8; xattrsize.to_usize().unwrap()];
+        reader.read_exact(&mut data)?;
         padding::read_discard(&mut reader, 4)?;
 
-        let mut content = vec![0u8; filesize.to_usize().unwrap()];
-        reader.read_exact(&mut content)?;
+        let xattrs = parse_xattrs(&data)
+            .ok_or_else(|| Error::InvalidXattrs(EscapedString::new(name.clone())))?;
+
+        Some(xattrs)
+    } else {
+        None
+    };
+
+    let entry = CpioEntryNew {
+        ino,
+        mode,
+        uid,
+        gid,
+        nlink,
+        mtime,
+        dev_maj,
+        dev_min,
+        rdev_maj,
+        rdev_min,
+        chksum,
+        name,
+        content: vec![],
+        xattrs,
+        crc,
+    };
+
+    Ok((entry, filesize))
+}
+
+impl<R: Read> FromReader<R> for CpioEntryNew {
+    type Error = Error;
+
+    fn from_reader(reader: R) -> Result<Self> {
+        let mut reader = CountingReader::new(reader);
+        let (mut entry, filesize) = read_header(&mut reader)?;
+
+        entry.content = vec![0u8; filesize.to_usize().unwrap()];
+        reader.read_exact(&mut entry.content)?;
         padding::read_discard(&mut reader, 4)?;
 
-        Ok(Self {
-            ino,
-            mode,
-            uid,
-            gid,
-            nlink,
-            mtime,
-            dev_maj,
-            dev_min,
-            rdev_maj,
-            rdev_min,
-            chksum,
-            name,
-            content,
-        })
+        Ok(entry)
     }
 }
 
@@ -275,10 +456,22 @@ impl<W: Write> ToWriter<W> for CpioEntryNew {
             .and_then(|s| s.to_u32())
             .ok_or_else(|| Error::IntegerTooLarge("filesize"))?;
 
-        if self.chksum == 0 {
-            writer.write_all(MAGIC_NEW)?;
-        } else {
+        let xattrs = self.xattrs.as_deref().map(serialize_xattrs).transpose()?;
+        let xattrsize = match &xattrs {
+            Some(x) => Some(
+                x.len()
+                    .to_u32()
+                    .ok_or_else(|| Error::IntegerTooLarge("xattrsize"))?,
+            ),
+            None => None,
+        };
+
+        if xattrs.is_some() {
+            writer.write_all(MAGIC_NEW_XATTR)?;
+        } else if self.crc || self.chksum != 0 {
             writer.write_all(MAGIC_NEW_CRC)?;
+        } else {
+            writer.write_all(MAGIC_NEW)?;
         }
 
         write_int(&mut writer, self.ino)?;
@@ -294,11 +487,19 @@ impl<W: Write> ToWriter<W> for CpioEntryNew {
         write_int(&mut writer, self.rdev_min)?;
         write_int(&mut writer, namesize)?;
         write_int(&mut writer, self.chksum)?;
+        if let Some(size) = xattrsize {
+            write_int(&mut writer, size)?;
+        }
 
         writer.write_all(&self.name)?;
         writer.write_zeros_exact(1)?;
         padding::write_zeros(&mut writer, 4)?;
 
+        if let Some(data) = &xattrs {
+            writer.write_all(data)?;
+            padding::write_zeros(&mut writer, 4)?;
+        }
+
         writer.write_all(&self.content)?;
         padding::write_zeros(&mut writer, 4)?;
 
@@ -306,6 +507,13 @@ impl<W: Write> ToWriter<W> for CpioEntryNew {
     }
 }
 
+/// Check if `data` starts with the magic of a supported cpio header.
+pub fn has_magic(data: &[u8]) -> bool {
+    [MAGIC_NEW, MAGIC_NEW_CRC, MAGIC_NEW_XATTR]
+        .iter()
+        .any(|m| data.starts_with(*m))
+}
+
 pub fn load(mut reader: impl Read, include_trailer: bool) -> Result<Vec<CpioEntryNew>> {
     let mut entries = vec![];
 
@@ -328,6 +536,61 @@ pub fn load(mut reader: impl Read, include_trailer: bool) -> Result<Vec<CpioEntr
     Ok(entries)
 }
 
+/// Find the entry named `name` and return a reader for just its content,
+/// without reading the content of any entry. The returned entry has empty
+/// content. Returns [`None`] if the trailer is reached first.
+///
+/// The archive must start at the current position of `reader` and `reader` must
+/// be able to seek, so this only works for uncompressed ramdisks.
+/// [`super::compression::CompressedReader`] cannot seek, so compressed ramdisks
+/// must be decompressed first, either into memory or into a temporary file.
+pub fn open_entry<R: Read + Seek>(
+    mut reader: R,
+    name: &[u8],
+) -> Result<Option<(CpioEntryNew, SectionReader<R>)>> {
+    loop {
+        let entry_offset = reader.stream_position()?;
+        let mut counting_reader = CountingReader::new(&mut reader);
+        let (entry, filesize) = read_header(&mut counting_reader)?;
+        let data_offset = entry_offset + counting_reader.stream_position()?;
+
+        if entry.name == CPIO_TRAILER {
+            return Ok(None);
+        } else if entry.name == name {
+            let section = SectionReader::new(reader, data_offset, filesize.into())?;
+            return Ok(Some((entry, section)));
+        }
+
+        let next_offset = data_offset + padding::round(u64::from(filesize), 4).unwrap();
+        reader.seek(SeekFrom::Start(next_offset))?;
+    }
+}
+
+/// Whether any entry is in the newcx format, which stores xattrs.
+pub fn has_xattrs(entries: &[CpioEntryNew]) -> bool {
+    entries.iter().any(|e| e.xattrs.is_some())
+}
+
+/// If the archive already stores xattrs, switch every entry that is not in the
+/// newcx format yet, like newly added entries, to that format and set its
+/// SELinux label to `label`. Archives without xattrs are left untouched because
+/// mainline kernels do not support the newcx format. An archive that uses it
+/// can only have come from a kernel build that does.
+pub fn label_new_entries(entries: &mut [CpioEntryNew], label: &[u8]) -> Result<()> {
+    if !has_xattrs(entries) {
+        return Ok(());
+    }
+
+    for entry in entries {
+        if entry.xattrs.is_none() {
+            entry.enable_xattrs();
+            entry.set_selinux_label(label)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn sort(entries: &mut [CpioEntryNew]) {
     entries.sort_by(|a, b| a.name.cmp(&b.name));
 }
@@ -341,6 +604,10 @@ pub fn reassign_inodes(entries: &mut [CpioEntryNew]) {
     }
 }
 
+/// Write a cpio archive. A trailer is added after the entries unless the last
+/// entry already is one, like when the archive was loaded with
+/// `include_trailer` set. In that case, the trailer is written as-is so that
+/// the archive is reproduced exactly.
 pub fn save(writer: impl Write, entries: &[CpioEntryNew], pad_to_block_size: bool) -> Result<()> {
     let mut writer = CountingWriter::new(writer);
 
@@ -348,10 +615,18 @@ pub fn save(writer: impl Write, entries: &[CpioEntryNew], pad_to_block_size: boo
         entry.to_writer(&mut writer)?;
     }
 
-    let mut trailer = CpioEntryNew::new_trailer();
-    // 1 higher than the highest inode if possible.
-    trailer.ino = entries.iter().map(|e| e.ino).max().map_or(0, |i| i + 1);
-    trailer.to_writer(&mut writer)?;
+    if !entries.last().map_or(false, |e| e.name == CPIO_TRAILER) {
+        let mut trailer = CpioEntryNew::new_trailer();
+        // 1 higher than the highest inode if possible.
+        trailer.ino = entries.iter().map(|e| e.ino).max().map_or(0, |i| i + 1);
+        // Use the same format as the rest of the archive.
+        if has_xattrs(entries) {
+            trailer.xattrs = Some(vec![]);
+        } else if entries.iter().any(|e| e.crc) {
+            trailer.crc = true;
+        }
+        trailer.to_writer(&mut writer)?;
+    }
 
     // Pad until the end of the block.
     if pad_to_block_size {
@@ -0,0 +1,382 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Support for the device tree table format used by `dtbo.img` and, rarely,
+//! `dtb.img`. The image contains a header, a table of entry descriptors, and
+//! the device tree blobs that the descriptors point to. All integers are big
+//! endian.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::padding,
+    stream::{FromReader, ToWriter, WriteZerosExt},
+    util::NumBytes,
+};
+
+pub const DTBO_MAGIC: u32 = 0xd7b7ab1e;
+
+pub const VERSION_MAX: u32 = 1;
+
+const HEADER_SIZE: u32 = 32;
+const ENTRY_SIZE: u32 = 32;
+
+/// Number of `u32` fields after `rev` in an entry. In version 1, the first one
+/// is the `flags` field.
+const ENTRY_EXTRA_FIELDS: usize = 4;
+
+/// Arbitrary limit to avoid allocating excessive amounts of memory when reading
+/// invalid data.
+const MAX_ENTRIES: u32 = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid DTBO magic: {0:#010x}")]
+    InvalidMagic(u32),
+    #[error("Unsupported DTBO version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Failed to read {0:?} field")]
+    ReadFieldError(&'static str, #[source] io::Error),
+    #[error("{0:?} field: invalid value: {1}")]
+    InvalidFieldValue(&'static str, u32),
+    #[error("{0:?} field exceeds integer bounds")]
+    IntegerTooLarge(&'static str),
+    #[error("Entry table is out of bounds")]
+    TableOutOfBounds,
+    #[error("Entry #{0} data is out of bounds")]
+    EntryOutOfBounds(usize),
+    #[error("Entry #{0} is not valid for version {1}: {2}")]
+    InvalidEntry(usize, u32, &'static str),
+    #[error("Entry index {0} is out of bounds for {1} entries")]
+    EntryIndexOutOfBounds(usize, usize),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A single device tree entry. The device tree blob is stored as is, so if the
+/// version 1 `flags` field specifies a compression format, `data` is the
+/// compressed blob.
+#[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct DtboEntry {
+    pub id: u32,
+    pub rev: u32,
+    /// Only present in version 1. The lower 4 bits specify the compression
+    /// format of the device tree blob.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<u32>,
+    /// Vendor-specific fields. There are 4 in version 0 and 3 in version 1.
+    pub custom: Vec<u32>,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for DtboEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DtboEntry")
+            .field("id", &self.id)
+            .field("rev", &self.rev)
+            .field("flags", &self.flags)
+            .field("custom", &self.custom)
+            .field("data", &NumBytes(self.data.len()))
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct DtboImage {
+    pub version: u32,
+    pub page_size: u32,
+    /// Whether each device tree blob and the end of the image are aligned to
+    /// `page_size`. Otherwise, the blobs are packed immediately after the
+    /// entry table. When reading, this is set if the original image was laid
+    /// out this way.
+    #[serde(default)]
+    pub align_entries: bool,
+    /// Whether entries with identical device tree blobs get their own copy of
+    /// the data. Otherwise, like mkdtboimg, identical blobs are only stored
+    /// once and the entries point to the same offset. When reading, this is
+    /// set if the original image had separate copies of an identical blob.
+    #[serde(default)]
+    pub duplicate_entries: bool,
+    pub entries: Vec<DtboEntry>,
+}
+
+impl DtboImage {
+    /// Replace the device tree blob of an entry. The other fields are kept.
+    pub fn replace_entry(&mut self, index: usize, data: Vec<u8>) -> Result<()> {
+        let num_entries = self.entries.len();
+        let entry = self
+            .entries
+            .get_mut(index)
+            .ok_or(Error::EntryIndexOutOfBounds(index, num_entries))?;
+
+        entry.data = data;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.version > VERSION_MAX {
+            return Err(Error::UnsupportedVersion(self.version));
+        } else if self.align_entries && self.page_size == 0 {
+            return Err(Error::InvalidFieldValue("page_size", self.page_size));
+        }
+
+        let num_custom = if self.version == 0 {
+            ENTRY_EXTRA_FIELDS
+        } else {
+            ENTRY_EXTRA_FIELDS - 1
+        };
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.flags.is_some() != (self.version > 0) {
+                return Err(Error::InvalidEntry(
+                    i,
+                    self.version,
+                    "flags must be set in version 1 and unset in version 0",
+                ));
+            } else if entry.custom.len() != num_custom {
+                return Err(Error::InvalidEntry(
+                    i,
+                    self.version,
+                    "wrong number of custom fields",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for DtboImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "DTBO image v{}", self.version)?;
+        writeln!(f, "- Page size: {}", self.page_size)?;
+        writeln!(f, "- Aligned entries: {}", self.align_entries)?;
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            writeln!(f, "- Entry #{i}:")?;
+            writeln!(f, "  - ID:      {:#010x}", entry.id)?;
+            writeln!(f, "  - Rev:     {:#010x}", entry.rev)?;
+            if let Some(flags) = entry.flags {
+                writeln!(f, "  - Flags:   {flags:#010x}")?;
+            }
+            writeln!(f, "  - Custom:  {:x?}", entry.custom)?;
+            writeln!(f, "  - Size:    {}", entry.data.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> FromReader<R> for DtboImage {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let read_u32 = |reader: &mut R, field| {
+            reader
+                .read_u32::<BigEndian>()
+                .map_err(|e| Error::ReadFieldError(field, e))
+        };
+
+        let magic = read_u32(&mut reader, "magic")?;
+        if magic != DTBO_MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+
+        let total_size = read_u32(&mut reader, "total_size")?;
+        let header_size = read_u32(&mut reader, "header_size")?;
+        let entry_size = read_u32(&mut reader, "dt_entry_size")?;
+        let entry_count = read_u32(&mut reader, "dt_entry_count")?;
+        let entries_offset = read_u32(&mut reader, "dt_entries_offset")?;
+        let page_size = read_u32(&mut reader, "page_size")?;
+        let version = read_u32(&mut reader, "version")?;
+
+        if version > VERSION_MAX {
+            return Err(Error::UnsupportedVersion(version));
+        } else if header_size < HEADER_SIZE {
+            return Err(Error::InvalidFieldValue("header_size", header_size));
+        } else if entry_size < ENTRY_SIZE {
+            return Err(Error::InvalidFieldValue("dt_entry_size", entry_size));
+        } else if entry_count > MAX_ENTRIES {
+            return Err(Error::InvalidFieldValue("dt_entry_count", entry_count));
+        }
+
+        let table_end = u64::from(entries_offset) + u64::from(entry_count) * u64::from(entry_size);
+        if u64::from(entries_offset) < u64::from(header_size) || table_end > u64::from(total_size) {
+            return Err(Error::TableOutOfBounds);
+        }
+
+        let mut locations = vec![];
+        let mut entries = vec![];
+
+        for i in 0..entry_count {
+            let offset = u64::from(entries_offset) + u64::from(i) * u64::from(entry_size);
+            reader.seek(SeekFrom::Start(offset))?;
+
+            let dt_size = read_u32(&mut reader, "dt_size")?;
+            let dt_offset = read_u32(&mut reader, "dt_offset")?;
+            let id = read_u32(&mut reader, "id")?;
+            let rev = read_u32(&mut reader, "rev")?;
+
+            let mut extra = [0u32; ENTRY_EXTRA_FIELDS];
+            for value in &mut extra {
+                *value = read_u32(&mut reader, "custom")?;
+            }
+
+            let (flags, custom) = if version == 0 {
+                (None, extra.to_vec())
+            } else {
+                (Some(extra[0]), extra[1..].to_vec())
+            };
+
+            if u64::from(dt_offset) + u64::from(dt_size) > u64::from(total_size) {
+                return Err(Error::EntryOutOfBounds(i as usize));
+            }
+
+            locations.push((dt_offset, dt_size));
+            entries.push(DtboEntry {
+                id,
+                rev,
+                flags,
+                custom,
+                data: vec![],
+            });
+        }
+
+        for (entry, (dt_offset, dt_size)) in entries.iter_mut().zip(&locations) {
+            reader.seek(SeekFrom::Start(u64::from(*dt_offset)))?;
+
+            entry.data.resize(*dt_size as usize, 0);
+            reader
+                .read_exact(&mut entry.data)
+                .map_err(|e| Error::ReadFieldError("dt", e))?;
+        }
+
+        let align_entries = page_size != 0
+            && !locations.is_empty()
+            && total_size % page_size == 0
+            && locations.iter().all(|(o, _)| o % page_size == 0);
+
+        let duplicate_entries = entries.iter().enumerate().any(|(i, a)| {
+            entries[i + 1..]
+                .iter()
+                .zip(&locations[i + 1..])
+                .any(|(b, (o, _))| a.data == b.data && *o != locations[i].0)
+        });
+
+        Ok(Self {
+            version,
+            page_size,
+            align_entries,
+            duplicate_entries,
+            entries,
+        })
+    }
+}
+
+impl<W: Write> ToWriter<W> for DtboImage {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        self.validate()?;
+
+        let entry_count = self
+            .entries
+            .len()
+            .to_u32()
+            .filter(|n| *n <= MAX_ENTRIES)
+            .ok_or(Error::IntegerTooLarge("dt_entry_count"))?;
+        let align = |offset: u64| {
+            if self.align_entries {
+                padding::round(offset, self.page_size.into())
+                    .ok_or(Error::IntegerTooLarge("dt_offset"))
+            } else {
+                Ok(offset)
+            }
+        };
+
+        // Blobs are written in entry order. Unless duplicate_entries is set,
+        // an entry whose blob is identical to an earlier one shares its
+        // location instead of getting a new copy.
+        let mut offset = u64::from(HEADER_SIZE) + u64::from(entry_count) * u64::from(ENTRY_SIZE);
+        let mut locations = vec![];
+        let mut seen = HashMap::<&[u8], (u32, u32)>::new();
+
+        for entry in &self.entries {
+            if !self.duplicate_entries {
+                if let Some(location) = seen.get(entry.data.as_slice()) {
+                    locations.push(*location);
+                    continue;
+                }
+            }
+
+            offset = align(offset)?;
+
+            let dt_offset = offset.to_u32().ok_or(Error::IntegerTooLarge("dt_offset"))?;
+            let dt_size = entry
+                .data
+                .len()
+                .to_u32()
+                .ok_or(Error::IntegerTooLarge("dt_size"))?;
+
+            locations.push((dt_offset, dt_size));
+            seen.insert(&entry.data, (dt_offset, dt_size));
+            offset += u64::from(dt_size);
+        }
+
+        let total_size = align(offset)?
+            .to_u32()
+            .ok_or(Error::IntegerTooLarge("total_size"))?;
+
+        writer.write_u32::<BigEndian>(DTBO_MAGIC)?;
+        writer.write_u32::<BigEndian>(total_size)?;
+        writer.write_u32::<BigEndian>(HEADER_SIZE)?;
+        writer.write_u32::<BigEndian>(ENTRY_SIZE)?;
+        writer.write_u32::<BigEndian>(entry_count)?;
+        writer.write_u32::<BigEndian>(HEADER_SIZE)?;
+        writer.write_u32::<BigEndian>(self.page_size)?;
+        writer.write_u32::<BigEndian>(self.version)?;
+
+        for (entry, (dt_offset, dt_size)) in self.entries.iter().zip(&locations) {
+            writer.write_u32::<BigEndian>(*dt_size)?;
+            writer.write_u32::<BigEndian>(*dt_offset)?;
+            writer.write_u32::<BigEndian>(entry.id)?;
+            writer.write_u32::<BigEndian>(entry.rev)?;
+
+            for value in entry.flags.iter().chain(&entry.custom) {
+                writer.write_u32::<BigEndian>(*value)?;
+            }
+        }
+
+        let mut pos = u64::from(HEADER_SIZE) + u64::from(entry_count) * u64::from(ENTRY_SIZE);
+
+        for (entry, (dt_offset, _)) in self.entries.iter().zip(&locations) {
+            // Already written for an earlier entry.
+            if u64::from(*dt_offset) < pos {
+                continue;
+            }
+
+            writer.write_zeros_exact(u64::from(*dt_offset) - pos)?;
+            writer.write_all(&entry.data)?;
+            pos = u64::from(*dt_offset) + entry.data.len() as u64;
+        }
+
+        writer.write_zeros_exact(u64::from(total_size) - pos)?;
+
+        Ok(())
+    }
+}
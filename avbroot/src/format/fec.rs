@@ -0,0 +1,701 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Forward error correction data for dm-verity images, compatible with AOSP's
+//! `fec` tool and libfec. The data is a Reed-Solomon code over GF(2^8) with
+//! 255-byte codewords. Each codeword takes one byte from each of `255 - roots`
+//! equally sized stripes of the input, so a run of corrupted blocks is spread
+//! across many codewords. The parity bytes are followed by a block containing
+//! the [`Header`].
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use num_traits::ToPrimitive;
+use thiserror::Error;
+
+use crate::{
+    stream::{FromReader, ToWriter},
+    util::par::{IntoParallelIterator, ParallelIterator},
+};
+
+pub const FEC_MAGIC: u32 = 0xfecfecfe;
+pub const FEC_VERSION: u32 = 0;
+pub const FEC_BLOCK_SIZE: u64 = 4096;
+
+pub const MIN_ROOTS: u32 = 2;
+pub const MAX_ROOTS: u32 = 24;
+
+/// Length of a Reed-Solomon codeword.
+const RS_N: usize = 255;
+/// Primitive polynomial used to generate GF(2^8).
+const GF_POLY: u16 = 0x11d;
+/// Logarithm of zero in [`ReedSolomon::index_of`].
+const A0: u8 = 255;
+
+const HEADER_SIZE: u32 = 60;
+
+/// Number of codewords computed at a time by each thread. Each thread reads
+/// this many bytes from every stripe.
+const CHUNK_CODEWORDS: u64 = 4 * FEC_BLOCK_SIZE;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Number of roots must be between {MIN_ROOTS} and {MAX_ROOTS}: {0}")]
+    InvalidRoots(u32),
+    #[error("Input size is not a multiple of {FEC_BLOCK_SIZE}: {0}")]
+    InputNotAligned(u64),
+    #[error("{0:?} field exceeds integer bounds")]
+    IntegerTooLarge(&'static str),
+    #[error("Unknown magic: {0:#010x}")]
+    UnknownMagic(u32),
+    #[error("Unsupported FEC version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("{0:?} field: expected {1}, but have {2}")]
+    FieldMismatch(&'static str, u64, u64),
+    #[error("Expected {0} bytes of FEC data, but have {1}")]
+    IncorrectSize(u64, usize),
+    #[error("Expected FEC data digest {0}, but have {1}")]
+    InvalidDigest(String, String),
+    #[error("FEC data does not match the input")]
+    InvalidParity,
+    #[error("Too many errors to correct in codeword {0}")]
+    Uncorrectable(u64),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The header stored in the last block of the FEC data. The block contains a
+/// copy of the header at the beginning and another at the end.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub roots: u32,
+    /// Size of the parity bytes, excluding the header block.
+    pub fec_size: u32,
+    /// Size of the data protected by the FEC data.
+    pub input_size: u64,
+    /// SHA-256 digest of the parity bytes.
+    pub digest: [u8; 32],
+}
+
+impl<R: Read> FromReader<R> for Header {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != FEC_MAGIC {
+            return Err(Error::UnknownMagic(magic));
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != FEC_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let size = reader.read_u32::<LittleEndian>()?;
+        if size != HEADER_SIZE {
+            return Err(Error::FieldMismatch(
+                "size",
+                HEADER_SIZE.into(),
+                size.into(),
+            ));
+        }
+
+        let roots = reader.read_u32::<LittleEndian>()?;
+        let fec_size = reader.read_u32::<LittleEndian>()?;
+        let input_size = reader.read_u64::<LittleEndian>()?;
+
+        let mut digest = [0u8; 32];
+        reader.read_exact(&mut digest)?;
+
+        Ok(Self {
+            version,
+            roots,
+            fec_size,
+            input_size,
+            digest,
+        })
+    }
+}
+
+impl<W: Write> ToWriter<W> for Header {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        writer.write_u32::<LittleEndian>(FEC_MAGIC)?;
+        writer.write_u32::<LittleEndian>(self.version)?;
+        writer.write_u32::<LittleEndian>(HEADER_SIZE)?;
+        writer.write_u32::<LittleEndian>(self.roots)?;
+        writer.write_u32::<LittleEndian>(self.fec_size)?;
+        writer.write_u64::<LittleEndian>(self.input_size)?;
+        writer.write_all(&self.digest)?;
+
+        Ok(())
+    }
+}
+
+/// Systematic Reed-Solomon codec, equivalent to libfec's `encode_rs_char()` and
+/// `decode_rs_char()` with the parameters that the `fec` tool uses: 8-bit
+/// symbols, the primitive polynomial 0x11d, first consecutive root 0, and
+/// primitive element 1.
+struct ReedSolomon {
+    alpha_to: [u8; 256],
+    index_of: [u8; 256],
+    /// Generator polynomial coefficients in logarithm form.
+    genpoly: Vec<u8>,
+}
+
+impl ReedSolomon {
+    fn new(roots: usize) -> Self {
+        let modnn = |x: usize| x % RS_N;
+
+        let mut alpha_to = [0u8; 256];
+        let mut index_of = [0u8; 256];
+        index_of[0] = A0;
+        alpha_to[usize::from(A0)] = 0;
+
+        let mut sr = 1u16;
+        for (i, alpha) in alpha_to.iter_mut().take(RS_N).enumerate() {
+            index_of[usize::from(sr)] = i as u8;
+            *alpha = sr as u8;
+
+            sr <<= 1;
+            if sr & 0x100 != 0 {
+                sr ^= GF_POLY;
+            }
+        }
+
+        let mut genpoly = vec![0u8; roots + 1];
+        genpoly[0] = 1;
+
+        for root in 0..roots {
+            genpoly[root + 1] = 1;
+
+            // Multiply by (x + alpha^root).
+            for j in (1..=root).rev() {
+                genpoly[j] = if genpoly[j] != 0 {
+                    let log = usize::from(index_of[usize::from(genpoly[j])]);
+                    genpoly[j - 1] ^ alpha_to[modnn(log + root)]
+                } else {
+                    genpoly[j - 1]
+                };
+            }
+
+            let log = usize::from(index_of[usize::from(genpoly[0])]);
+            genpoly[0] = alpha_to[modnn(log + root)];
+        }
+
+        for coefficient in &mut genpoly {
+            *coefficient = index_of[usize::from(*coefficient)];
+        }
+
+        Self {
+            alpha_to,
+            index_of,
+            genpoly,
+        }
+    }
+
+    /// Compute the parity bytes for `data`. The length of `parity` must be the
+    /// number of roots.
+    fn encode(&self, data: &[u8], parity: &mut [u8]) {
+        let roots = parity.len();
+        parity.fill(0);
+
+        for &byte in data {
+            let feedback = self.index_of[usize::from(byte ^ parity[0])];
+
+            if feedback != A0 {
+                let coefficients = self.genpoly[1..roots].iter().rev();
+
+                for (p, &coefficient) in parity[1..].iter_mut().zip(coefficients) {
+                    let log = usize::from(feedback) + usize::from(coefficient);
+                    *p ^= self.alpha_to[log % RS_N];
+                }
+            }
+
+            parity.copy_within(1.., 0);
+
+            parity[roots - 1] = if feedback != A0 {
+                let log = usize::from(feedback) + usize::from(self.genpoly[0]);
+                self.alpha_to[log % RS_N]
+            } else {
+                0
+            };
+        }
+    }
+
+    /// Correct errors in a full 255-byte codeword, consisting of the data
+    /// followed by the parity bytes, in place. Up to `roots / 2` errors can be
+    /// corrected. Returns the positions of the corrected bytes or [`None`] if
+    /// the codeword has too many errors.
+    fn decode(&self, codeword: &mut [u8; RS_N]) -> Option<Vec<usize>> {
+        let modnn = |x: usize| x % RS_N;
+        let log = |x: u8| usize::from(self.index_of[usize::from(x)]);
+        let exp = |x: usize| self.alpha_to[modnn(x)];
+
+        let roots = self.genpoly.len() - 1;
+
+        // Evaluate the codeword at each root of the generator polynomial.
+        let mut syndromes = vec![0u8; roots];
+        for (i, syndrome) in syndromes.iter_mut().enumerate() {
+            let mut value = codeword[0];
+            for &byte in &codeword[1..] {
+                value = if value == 0 {
+                    byte
+                } else {
+                    byte ^ exp(log(value) + i)
+                };
+            }
+            *syndrome = self.index_of[usize::from(value)];
+        }
+
+        if syndromes.iter().all(|s| *s == A0) {
+            return Some(vec![]);
+        }
+
+        // Find the error locator polynomial with Berlekamp-Massey. lambda is
+        // in polynomial form and b is in logarithm form.
+        let mut lambda = vec![0u8; roots + 1];
+        lambda[0] = 1;
+        let mut b = lambda
+            .iter()
+            .map(|c| self.index_of[usize::from(*c)])
+            .collect::<Vec<_>>();
+        let mut el = 0;
+
+        for r in 1..=roots {
+            let mut discr = 0u8;
+            for i in 0..r {
+                let s = syndromes[r - i - 1];
+                if lambda[i] != 0 && s != A0 {
+                    discr ^= exp(log(lambda[i]) + usize::from(s));
+                }
+            }
+            let discr = self.index_of[usize::from(discr)];
+
+            if discr == A0 {
+                b.copy_within(..roots, 1);
+                b[0] = A0;
+                continue;
+            }
+
+            let mut t = vec![0u8; roots + 1];
+            t[0] = lambda[0];
+            for i in 0..roots {
+                t[i + 1] = if b[i] != A0 {
+                    lambda[i + 1] ^ exp(usize::from(discr) + usize::from(b[i]))
+                } else {
+                    lambda[i + 1]
+                };
+            }
+
+            if 2 * el < r {
+                el = r - el;
+                for (bi, &l) in b.iter_mut().zip(&lambda) {
+                    *bi = if l == 0 {
+                        A0
+                    } else {
+                        modnn(log(l) + RS_N - usize::from(discr)) as u8
+                    };
+                }
+            } else {
+                b.copy_within(..roots, 1);
+                b[0] = A0;
+            }
+
+            lambda = t;
+        }
+
+        let lambda = lambda
+            .iter()
+            .map(|c| self.index_of[usize::from(*c)])
+            .collect::<Vec<_>>();
+        let deg_lambda = lambda.iter().rposition(|c| *c != A0).unwrap_or(0);
+        if deg_lambda == 0 {
+            return None;
+        }
+
+        // Chien search for the roots of the error locator polynomial.
+        let mut reg = lambda.clone();
+        let mut found = vec![];
+
+        for i in 1..=RS_N {
+            let mut q = 1u8;
+            for j in (1..=deg_lambda).rev() {
+                if reg[j] != A0 {
+                    reg[j] = modnn(usize::from(reg[j]) + j) as u8;
+                    q ^= self.alpha_to[usize::from(reg[j])];
+                }
+            }
+
+            if q == 0 {
+                // The root alpha^i locates an error at position i - 1.
+                found.push((i, i - 1));
+                if found.len() == deg_lambda {
+                    break;
+                }
+            }
+        }
+
+        if found.len() != deg_lambda {
+            return None;
+        }
+
+        // Error evaluator polynomial in logarithm form.
+        let omega = (0..deg_lambda)
+            .map(|i| {
+                let mut value = 0u8;
+                for j in 0..=i {
+                    let (s, l) = (syndromes[i - j], lambda[j]);
+                    if s != A0 && l != A0 {
+                        value ^= exp(usize::from(s) + usize::from(l));
+                    }
+                }
+                self.index_of[usize::from(value)]
+            })
+            .collect::<Vec<_>>();
+
+        // Compute the error values with the Forney algorithm. Nothing is
+        // corrected until all of them are known, so that an uncorrectable
+        // codeword is left untouched.
+        let mut corrections = Vec::with_capacity(found.len());
+
+        for &(root, pos) in &found {
+            let mut num1 = 0u8;
+            for (i, &o) in omega.iter().enumerate() {
+                if o != A0 {
+                    num1 ^= exp(usize::from(o) + i * root);
+                }
+            }
+
+            // The first consecutive root is 0, so this is alpha^-root.
+            let num2 = exp(RS_N - root);
+
+            let mut den = 0u8;
+            for i in (0..deg_lambda.min(roots - 1) + 1).step_by(2) {
+                if lambda[i + 1] != A0 {
+                    den ^= exp(usize::from(lambda[i + 1]) + i * root);
+                }
+            }
+
+            // Like libfec, treat this as uncorrectable instead of dividing by
+            // zero.
+            if den == 0 {
+                return None;
+            }
+
+            if num1 != 0 {
+                corrections.push((pos, exp(log(num1) + log(num2) + RS_N - log(den))));
+            }
+        }
+
+        for (pos, value) in corrections {
+            codeword[pos] ^= value;
+        }
+
+        Some(found.into_iter().map(|(_, pos)| pos).collect())
+    }
+}
+
+/// Number of codewords per byte position within a block. This is also the size
+/// of each stripe in blocks.
+fn num_rounds(input_size: u64, roots: u32) -> Result<u64> {
+    if !(MIN_ROOTS..=MAX_ROOTS).contains(&roots) {
+        return Err(Error::InvalidRoots(roots));
+    } else if input_size % FEC_BLOCK_SIZE != 0 {
+        return Err(Error::InputNotAligned(input_size));
+    }
+
+    let data_per_codeword = RS_N as u64 - u64::from(roots);
+
+    Ok((input_size / FEC_BLOCK_SIZE).div_ceil(data_per_codeword))
+}
+
+/// Compute the size of the FEC data, including the header block, for an input
+/// of `input_size` bytes.
+pub fn fec_size(input_size: u64, roots: u32) -> Result<u64> {
+    let rounds = num_rounds(input_size, roots)?;
+
+    rounds
+        .checked_mul(u64::from(roots) * FEC_BLOCK_SIZE)
+        .and_then(|s| s.checked_add(FEC_BLOCK_SIZE))
+        .ok_or(Error::IntegerTooLarge("fec_size"))
+}
+
+/// Read `count` bytes starting at `start` from each of the `data_per_codeword`
+/// stripes of the input. Byte i of codeword n comes from offset
+/// (n + i * stripe_size). Anything past the end of the input is treated as
+/// zeros.
+fn read_stripes(
+    mut reader: impl Read + Seek,
+    start: u64,
+    count: usize,
+    stripe_size: u64,
+    input_size: u64,
+    data_per_codeword: usize,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<u8>> {
+    let mut stripes = vec![0u8; data_per_codeword * count];
+
+    for (i, stripe) in stripes.chunks_exact_mut(count).enumerate() {
+        if cancel_signal.load(Ordering::SeqCst) {
+            return Err(
+                io::Error::new(io::ErrorKind::Interrupted, "Received cancel signal").into(),
+            );
+        }
+
+        let offset = start + i as u64 * stripe_size;
+        if offset >= input_size {
+            break;
+        }
+
+        let n = (input_size - offset).min(count as u64) as usize;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut stripe[..n])?;
+    }
+
+    Ok(stripes)
+}
+
+/// Generate the FEC data, including the header block, for the first
+/// `input_size` bytes of the input in parallel. `open_input` will be called
+/// from multiple threads and must return independently seekable handles to the
+/// same data.
+pub fn generate<R: Read + Seek>(
+    open_input: impl Fn() -> io::Result<R> + Sync,
+    input_size: u64,
+    roots: u32,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<u8>> {
+    let total_size = fec_size(input_size, roots)?;
+
+    let rs = ReedSolomon::new(roots as usize);
+    let num_roots = roots as usize;
+    let data_per_codeword = RS_N - num_roots;
+    let stripe_size = num_rounds(input_size, roots)? * FEC_BLOCK_SIZE;
+    let chunk_count = stripe_size.div_ceil(CHUNK_CODEWORDS);
+
+    let pieces = (0..chunk_count)
+        .into_par_iter()
+        .map(|c| -> Result<Vec<u8>> {
+            let start = c * CHUNK_CODEWORDS;
+            let count = CHUNK_CODEWORDS.min(stripe_size - start) as usize;
+
+            let mut reader = open_input()?;
+            let stripes = read_stripes(
+                &mut reader,
+                start,
+                count,
+                stripe_size,
+                input_size,
+                data_per_codeword,
+                cancel_signal,
+            )?;
+
+            let mut parity = vec![0u8; count * num_roots];
+            let mut codeword = vec![0u8; data_per_codeword];
+
+            for (n, codeword_parity) in parity.chunks_exact_mut(num_roots).enumerate() {
+                for (i, byte) in codeword.iter_mut().enumerate() {
+                    *byte = stripes[i * count + n];
+                }
+
+                rs.encode(&codeword, codeword_parity);
+            }
+
+            Ok(parity)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut data = pieces.into_iter().flatten().collect::<Vec<_>>();
+
+    let mut header = Header {
+        version: FEC_VERSION,
+        roots,
+        fec_size: data
+            .len()
+            .to_u32()
+            .ok_or(Error::IntegerTooLarge("fec_size"))?,
+        input_size,
+        digest: [0u8; 32],
+    };
+    header
+        .digest
+        .copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &data).as_ref());
+
+    let block_size = FEC_BLOCK_SIZE as usize;
+    let header_size = HEADER_SIZE as usize;
+    let mut block = vec![0u8; block_size];
+    header.to_writer(&mut block[..header_size])?;
+    block.copy_within(..header_size, block_size - header_size);
+
+    data.extend_from_slice(&block);
+    assert_eq!(data.len() as u64, total_size);
+
+    Ok(data)
+}
+
+/// Generate FEC data from scratch for an image that doesn't have any. The
+/// data covers the first `input_size` bytes of the input and is meant to be
+/// appended right after them. Returns the FEC data, including the header
+/// block, along with its offset and size. `roots` must be between
+/// [`MIN_ROOTS`] and [`MAX_ROOTS`]. See [`generate()`] for the requirements
+/// for `open_input`.
+pub fn add_fec<R: Read + Seek>(
+    open_input: impl Fn() -> io::Result<R> + Sync,
+    input_size: u64,
+    roots: u32,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<(Vec<u8>, u64, u64)> {
+    if !(MIN_ROOTS..=MAX_ROOTS).contains(&roots) {
+        return Err(Error::InvalidRoots(roots));
+    }
+
+    let data = generate(open_input, input_size, roots, cancel_signal)?;
+    let size = data.len() as u64;
+
+    Ok((data, input_size, size))
+}
+
+/// Check that the header block matches the input and the parity bytes. Returns
+/// the parity bytes.
+fn parity_bytes(input_size: u64, roots: u32, fec: &[u8]) -> Result<&[u8]> {
+    let expected_size = fec_size(input_size, roots)?;
+    if fec.len() as u64 != expected_size {
+        return Err(Error::IncorrectSize(expected_size, fec.len()));
+    }
+
+    let (parity, header_block) = fec.split_at(fec.len() - FEC_BLOCK_SIZE as usize);
+    let header = Header::from_reader(header_block)?;
+
+    if header.roots != roots {
+        return Err(Error::FieldMismatch(
+            "roots",
+            roots.into(),
+            header.roots.into(),
+        ));
+    } else if header.input_size != input_size {
+        return Err(Error::FieldMismatch(
+            "inp_size",
+            input_size,
+            header.input_size,
+        ));
+    } else if u64::from(header.fec_size) != parity.len() as u64 {
+        return Err(Error::FieldMismatch(
+            "fec_size",
+            parity.len() as u64,
+            header.fec_size.into(),
+        ));
+    }
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, parity);
+    if digest.as_ref() != header.digest {
+        return Err(Error::InvalidDigest(
+            hex::encode(header.digest),
+            hex::encode(digest),
+        ));
+    }
+
+    Ok(parity)
+}
+
+/// Verify the FEC data, including the header block, against the first
+/// `input_size` bytes of the input in parallel. See [`generate()`] for the
+/// requirements for `open_input`.
+pub fn verify<R: Read + Seek>(
+    open_input: impl Fn() -> io::Result<R> + Sync,
+    input_size: u64,
+    roots: u32,
+    fec: &[u8],
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let parity = parity_bytes(input_size, roots, fec)?;
+
+    let expected = generate(open_input, input_size, roots, cancel_signal)?;
+    if expected[..parity.len()] != *parity {
+        return Err(Error::InvalidParity);
+    }
+
+    Ok(())
+}
+
+/// Use the FEC data, including the header block, to correct errors in the first
+/// `input_size` bytes of the input in place. Each codeword can have up to
+/// `roots / 2` corrupted bytes. Since consecutive bytes belong to different
+/// codewords, this is enough to repair many corrupted blocks. Returns the
+/// number of bytes that were corrected.
+pub fn correct(
+    mut input: impl Read + Write + Seek,
+    input_size: u64,
+    roots: u32,
+    fec: &[u8],
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<u64> {
+    let parity = parity_bytes(input_size, roots, fec)?;
+
+    let rs = ReedSolomon::new(roots as usize);
+    let num_roots = roots as usize;
+    let data_per_codeword = RS_N - num_roots;
+    let stripe_size = num_rounds(input_size, roots)? * FEC_BLOCK_SIZE;
+    let mut corrected = 0;
+
+    for start in (0..stripe_size).step_by(CHUNK_CODEWORDS as usize) {
+        let count = CHUNK_CODEWORDS.min(stripe_size - start) as usize;
+
+        let mut stripes = read_stripes(
+            &mut input,
+            start,
+            count,
+            stripe_size,
+            input_size,
+            data_per_codeword,
+            cancel_signal,
+        )?;
+        let mut modified = vec![false; data_per_codeword];
+        let mut codeword = [0u8; RS_N];
+
+        for n in 0..count {
+            for (i, byte) in codeword[..data_per_codeword].iter_mut().enumerate() {
+                *byte = stripes[i * count + n];
+            }
+            let parity_offset = (start as usize + n) * num_roots;
+            codeword[data_per_codeword..].copy_from_slice(&parity[parity_offset..][..num_roots]);
+
+            let positions = rs
+                .decode(&mut codeword)
+                .ok_or(Error::Uncorrectable(start + n as u64))?;
+
+            // Errors in the parity bytes don't need to be written back.
+            for i in positions.into_iter().filter(|i| *i < data_per_codeword) {
+                stripes[i * count + n] = codeword[i];
+                modified[i] = true;
+                corrected += 1;
+            }
+        }
+
+        for (i, stripe) in stripes.chunks_exact(count).enumerate() {
+            let offset = start + i as u64 * stripe_size;
+            if !modified[i] || offset >= input_size {
+                continue;
+            }
+
+            let n = (input_size - offset).min(count as u64) as usize;
+            input.seek(SeekFrom::Start(offset))?;
+            input.write_all(&stripe[..n])?;
+        }
+    }
+
+    Ok(corrected)
+}
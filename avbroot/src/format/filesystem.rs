@@ -0,0 +1,89 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal superblock parsing for determining the size of a filesystem image.
+//! Nothing else about the filesystems is parsed.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+/// Both ext4 and erofs store their superblock at this offset.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+
+const EXT4_MAGIC: u16 = 0xef53;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+
+const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+
+/// Maximum block size shift that is considered valid. This is much larger than
+/// what either filesystem supports in practice.
+const MAX_BLOCK_SIZE_BITS: u32 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsType {
+    Ext4,
+    Erofs,
+}
+
+/// Detect an ext4 or erofs filesystem and get the size declared by its
+/// superblock. Returns [`None`], instead of an error, if the data does not
+/// contain either filesystem or the superblock is too short or invalid.
+pub fn declared_size(mut reader: impl Read + Seek) -> io::Result<Option<(FsType, u64)>> {
+    let mut sb = [0u8; 1024];
+
+    reader.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+    match reader.read_exact(&mut sb) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    Ok(ext4_size(&sb)
+        .map(|s| (FsType::Ext4, s))
+        .or_else(|| erofs_size(&sb).map(|s| (FsType::Erofs, s))))
+}
+
+fn ext4_size(sb: &[u8]) -> Option<u64> {
+    let field_u32 = |offset: usize| (&sb[offset..]).read_u32::<LittleEndian>().ok();
+
+    let magic = (&sb[56..]).read_u16::<LittleEndian>().ok()?;
+    if magic != EXT4_MAGIC {
+        return None;
+    }
+
+    let blocks_lo = field_u32(4)?;
+    let log_block_size = field_u32(24)?;
+    let feature_incompat = field_u32(96)?;
+    let blocks_hi = if feature_incompat & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+        field_u32(336)?
+    } else {
+        0
+    };
+
+    // The block size is 1024 << s_log_block_size.
+    let block_size_bits = log_block_size.checked_add(10)?;
+    if block_size_bits > MAX_BLOCK_SIZE_BITS {
+        return None;
+    }
+
+    let blocks = (u64::from(blocks_hi) << 32) | u64::from(blocks_lo);
+    blocks.checked_mul(1 << block_size_bits)
+}
+
+fn erofs_size(sb: &[u8]) -> Option<u64> {
+    let magic = (&sb[0..]).read_u32::<LittleEndian>().ok()?;
+    if magic != EROFS_MAGIC {
+        return None;
+    }
+
+    let block_size_bits = u32::from(sb[12]);
+    if block_size_bits > MAX_BLOCK_SIZE_BITS {
+        return None;
+    }
+
+    let blocks = (&sb[36..]).read_u32::<LittleEndian>().ok()?;
+    u64::from(blocks).checked_mul(1 << block_size_bits)
+}
@@ -0,0 +1,422 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Support for GUID partition tables (GPT), as found on full disk images.
+//! Disks with 512-byte and 4096-byte sectors are supported. If the primary GPT
+//! is corrupt, the backup GPT at the end of the disk is used. The partition
+//! table itself can only be read, but the contents of partitions can be
+//! replaced in place.
+
+use std::{
+    fmt,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use thiserror::Error;
+
+use crate::stream::{self, FromReader, SectionReader, WriteZerosExt};
+
+pub const HEADER_SIGNATURE: &[u8; 8] = b"EFI PART";
+pub const HEADER_REVISION: u32 = 0x00010000;
+
+/// Logical sector sizes to try, in order.
+pub const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+const HEADER_MIN_SIZE: u32 = 92;
+const ENTRY_MIN_SIZE: u32 = 128;
+const NAME_SIZE: usize = 72;
+
+/// Partition entry arrays larger than this are rejected. The UEFI spec's
+/// minimum is 16 KiB and real disks rarely use more.
+const ENTRIES_MAX_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid GPT header signature: {0:?}")]
+    InvalidSignature([u8; 8]),
+    #[error("Unsupported GPT revision: {0:#010x}")]
+    UnsupportedRevision(u32),
+    #[error("Invalid {0:?} size: {1}")]
+    InvalidSize(&'static str, u32),
+    #[error("Expected {0} CRC32 {1:#010x}, but have {2:#010x}")]
+    InvalidChecksum(&'static str, u32, u32),
+    #[error("Invalid {0:?} LBA: {1}")]
+    InvalidLba(&'static str, u64),
+    #[error("Partition entry array is out of bounds")]
+    TableOutOfBounds,
+    #[error("Partition {0:?} is out of bounds")]
+    PartitionOutOfBounds(String),
+    #[error("GPT partition not found: {0:?}")]
+    PartitionNotFound(String),
+    #[error("New data ({1} bytes) does not fit in partition {0:?} ({2} bytes)")]
+    PartitionTooSmall(String, u64, u64),
+    #[error("Invalid GUID: {0:?}")]
+    InvalidGuid(String),
+    #[error("I/O error")]
+    IoError(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A GUID in its on-disk form, where the first three fields are little endian.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0u8; 16]
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guid({self})")
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{}-{}",
+            u32::from_le_bytes(b[0..4].try_into().unwrap()),
+            u16::from_le_bytes(b[4..6].try_into().unwrap()),
+            u16::from_le_bytes(b[6..8].try_into().unwrap()),
+            hex::encode_upper(&b[8..10]),
+            hex::encode_upper(&b[10..16]),
+        )
+    }
+}
+
+impl FromStr for Guid {
+    type Err = Error;
+
+    /// Parse a GUID in the usual `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidGuid(s.to_owned());
+
+        let fields = s.split('-').collect::<Vec<_>>();
+        if fields.iter().map(|f| f.len()).ne([8, 4, 4, 4, 12]) {
+            return Err(invalid());
+        }
+
+        let mut raw = [0u8; 16];
+        hex::decode_to_slice(fields.concat(), &mut raw).map_err(|_| invalid())?;
+        raw[0..4].reverse();
+        raw[4..6].reverse();
+        raw[6..8].reverse();
+
+        Ok(Self(raw))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Last sector of the partition (inclusive).
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl<R: Read> FromReader<R> for Partition {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let mut type_guid = Guid::default();
+        reader.read_exact(&mut type_guid.0)?;
+
+        let mut unique_guid = Guid::default();
+        reader.read_exact(&mut unique_guid.0)?;
+
+        let first_lba = reader.read_u64::<LittleEndian>()?;
+        let last_lba = reader.read_u64::<LittleEndian>()?;
+        let attributes = reader.read_u64::<LittleEndian>()?;
+
+        let mut name_raw = [0u8; NAME_SIZE];
+        reader.read_exact(&mut name_raw)?;
+        let name_utf16 = name_raw
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            type_guid,
+            unique_guid,
+            first_lba,
+            last_lba,
+            attributes,
+            name: String::from_utf16_lossy(&name_utf16),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gpt {
+    pub sector_size: u64,
+    pub disk_guid: Guid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// Whether the primary GPT was corrupt and the backup GPT was used.
+    pub from_backup: bool,
+    /// Used partition entries. Entries with a zero type GUID are skipped.
+    pub partitions: Vec<Partition>,
+}
+
+impl Gpt {
+    /// Read and validate the header at `lba` and its partition entries.
+    fn read_at(
+        mut reader: impl Read + Seek,
+        sector_size: u64,
+        lba: u64,
+        disk_sectors: u64,
+    ) -> Result<Self> {
+        reader.seek(SeekFrom::Start(lba * sector_size))?;
+
+        let mut sector = vec![0u8; sector_size as usize];
+        reader.read_exact(&mut sector)?;
+
+        let mut cursor = Cursor::new(&sector);
+
+        let mut signature = [0u8; 8];
+        cursor.read_exact(&mut signature)?;
+        if &signature != HEADER_SIGNATURE {
+            return Err(Error::InvalidSignature(signature));
+        }
+
+        let revision = cursor.read_u32::<LittleEndian>()?;
+        if revision != HEADER_REVISION {
+            return Err(Error::UnsupportedRevision(revision));
+        }
+
+        let header_size = cursor.read_u32::<LittleEndian>()?;
+        if header_size < HEADER_MIN_SIZE || u64::from(header_size) > sector_size {
+            return Err(Error::InvalidSize("header_size", header_size));
+        }
+
+        let header_crc32 = cursor.read_u32::<LittleEndian>()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&sector[..16]);
+        hasher.update(&[0u8; 4]);
+        hasher.update(&sector[20..header_size as usize]);
+        let actual_crc32 = hasher.finalize();
+        if actual_crc32 != header_crc32 {
+            return Err(Error::InvalidChecksum("header", header_crc32, actual_crc32));
+        }
+
+        let _reserved = cursor.read_u32::<LittleEndian>()?;
+
+        let my_lba = cursor.read_u64::<LittleEndian>()?;
+        if my_lba != lba {
+            return Err(Error::InvalidLba("my_lba", my_lba));
+        }
+
+        let alternate_lba = cursor.read_u64::<LittleEndian>()?;
+
+        let first_usable_lba = cursor.read_u64::<LittleEndian>()?;
+        let last_usable_lba = cursor.read_u64::<LittleEndian>()?;
+        if first_usable_lba > last_usable_lba || last_usable_lba >= disk_sectors {
+            return Err(Error::InvalidLba("last_usable_lba", last_usable_lba));
+        }
+
+        // Partition data must never overlap the GPT structures so that writing
+        // to a partition can't corrupt them.
+        let usable = first_usable_lba..=last_usable_lba;
+        if usable.contains(&lba) {
+            return Err(Error::InvalidLba("my_lba", my_lba));
+        } else if usable.contains(&alternate_lba) {
+            return Err(Error::InvalidLba("alternate_lba", alternate_lba));
+        }
+
+        let mut disk_guid = Guid::default();
+        cursor.read_exact(&mut disk_guid.0)?;
+
+        let entries_lba = cursor.read_u64::<LittleEndian>()?;
+        let num_entries = cursor.read_u32::<LittleEndian>()?;
+        let entry_size = cursor.read_u32::<LittleEndian>()?;
+        if entry_size < ENTRY_MIN_SIZE || entry_size % ENTRY_MIN_SIZE != 0 {
+            return Err(Error::InvalidSize("entry_size", entry_size));
+        }
+        let entries_crc32 = cursor.read_u32::<LittleEndian>()?;
+
+        let entries_size = u64::from(num_entries) * u64::from(entry_size);
+        if entries_size > ENTRIES_MAX_SIZE
+            || entries_lba
+                .checked_mul(sector_size)
+                .and_then(|o| o.checked_add(entries_size))
+                .map_or(true, |end| {
+                    end > disk_sectors * sector_size
+                        || (entries_lba <= last_usable_lba && end > first_usable_lba * sector_size)
+                })
+        {
+            return Err(Error::TableOutOfBounds);
+        }
+
+        reader.seek(SeekFrom::Start(entries_lba * sector_size))?;
+        let mut entries = vec![0u8; entries_size as usize];
+        reader.read_exact(&mut entries)?;
+
+        let actual_crc32 = crc32fast::hash(&entries);
+        if actual_crc32 != entries_crc32 {
+            return Err(Error::InvalidChecksum(
+                "entries",
+                entries_crc32,
+                actual_crc32,
+            ));
+        }
+
+        let mut partitions = vec![];
+
+        for entry in entries.chunks_exact(entry_size as usize) {
+            let partition = Partition::from_reader(entry)?;
+            if partition.type_guid.is_zero() {
+                continue;
+            }
+
+            if partition.first_lba < first_usable_lba
+                || partition.last_lba > last_usable_lba
+                || partition.first_lba > partition.last_lba
+            {
+                return Err(Error::PartitionOutOfBounds(partition.name));
+            }
+
+            partitions.push(partition);
+        }
+
+        Ok(Self {
+            sector_size,
+            disk_guid,
+            first_usable_lba,
+            last_usable_lba,
+            from_backup: false,
+            partitions,
+        })
+    }
+
+    /// Find a partition by its GPT name.
+    pub fn partition(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Find the first partition with the specified type GUID.
+    pub fn partition_by_type(&self, type_guid: &Guid) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.type_guid == *type_guid)
+    }
+
+    /// Get the byte offset and size of a partition within the disk image.
+    pub fn partition_range(&self, partition: &Partition) -> (u64, u64) {
+        let offset = partition.first_lba * self.sector_size;
+        let size = (partition.last_lba - partition.first_lba + 1) * self.sector_size;
+
+        (offset, size)
+    }
+
+    /// Get a reader for the contents of partition `name`.
+    pub fn open_partition<R: Read + Seek>(&self, inner: R, name: &str) -> Result<SectionReader<R>> {
+        let partition = self
+            .partition(name)
+            .ok_or_else(|| Error::PartitionNotFound(name.to_owned()))?;
+        let (offset, size) = self.partition_range(partition);
+
+        Ok(SectionReader::new(inner, offset, size)?)
+    }
+}
+
+impl<R: Read + Seek> FromReader<R> for Gpt {
+    type Error = Error;
+
+    /// Read the primary GPT, or the backup GPT if the primary copy is corrupt.
+    /// The sector size is detected from where a valid header is found. If
+    /// neither copy is valid, the error for the primary GPT is returned.
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let disk_size = reader.seek(SeekFrom::End(0))?;
+        let mut errors = vec![];
+
+        for sector_size in SECTOR_SIZES {
+            let disk_sectors = disk_size / sector_size;
+            if disk_sectors < 2 {
+                continue;
+            }
+
+            match Self::read_at(&mut reader, sector_size, 1, disk_sectors) {
+                Ok(gpt) => return Ok(gpt),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for sector_size in SECTOR_SIZES {
+            let disk_sectors = disk_size / sector_size;
+            if disk_sectors < 3 {
+                continue;
+            }
+
+            if let Ok(mut gpt) =
+                Self::read_at(&mut reader, sector_size, disk_sectors - 1, disk_sectors)
+            {
+                gpt.from_backup = true;
+                return Ok(gpt);
+            }
+        }
+
+        if errors.is_empty() {
+            return Err(Error::IoError(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Disk image is too small for a GPT",
+            )));
+        }
+
+        // A wrong signature most likely means that the sector size was wrong,
+        // so the other errors are more useful.
+        let index = errors
+            .iter()
+            .position(|e| !matches!(e, Error::InvalidSignature(_)))
+            .unwrap_or(0);
+
+        Err(errors.swap_remove(index))
+    }
+}
+
+/// Replace the contents of partition `name` within a disk image in place.
+/// `size` bytes are read from `reader`. If the new data is smaller than the
+/// partition, the remainder is filled with zeros. The GPT is never modified,
+/// so the partition's size does not change and both copies of the GPT remain
+/// valid. If an error occurs while writing, the partition may be left
+/// partially written.
+pub fn replace_partition(
+    mut image: impl Read + Write + Seek,
+    name: &str,
+    reader: impl Read,
+    size: u64,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let gpt = Gpt::from_reader(&mut image)?;
+    let partition = gpt
+        .partition(name)
+        .ok_or_else(|| Error::PartitionNotFound(name.to_owned()))?;
+    let (offset, partition_size) = gpt.partition_range(partition);
+
+    if size > partition_size {
+        return Err(Error::PartitionTooSmall(
+            name.to_owned(),
+            size,
+            partition_size,
+        ));
+    }
+
+    image.seek(SeekFrom::Start(offset))?;
+    stream::copy_n(reader, &mut image, size, cancel_signal)?;
+    image.write_zeros_exact(partition_size - size)?;
+    image.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,758 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Read-only support for logical partition (LP) metadata, as used by
+//! `super.img` on devices with dynamic partitions. Only raw images are
+//! supported. Sparse images must be unsparsed first.
+
+use std::{
+    fmt,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use num_traits::ToPrimitive;
+use ring::digest;
+use thiserror::Error;
+
+use crate::{
+    stream::{self, FromReader, ReadStringExt, WriteZerosExt},
+    util,
+};
+
+pub const GEOMETRY_MAGIC: u32 = 0x616c4467;
+pub const HEADER_MAGIC: u32 = 0x414c5030;
+
+pub const MAJOR_VERSION: u16 = 10;
+pub const MINOR_VERSION_MAX: u16 = 2;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Space reserved at the beginning of the image before the geometry.
+pub const PARTITION_RESERVED_BYTES: u64 = 4096;
+/// Space reserved for each copy of the geometry.
+pub const GEOMETRY_SIZE: u64 = 4096;
+
+pub const TARGET_TYPE_LINEAR: u32 = 0;
+pub const TARGET_TYPE_ZERO: u32 = 1;
+
+const GEOMETRY_STRUCT_SIZE: usize = 52;
+const HEADER_V1_0_SIZE: usize = 128;
+const PARTITION_ENTRY_SIZE: usize = 52;
+const EXTENT_ENTRY_SIZE: usize = 24;
+const GROUP_ENTRY_SIZE: usize = 48;
+const BLOCK_DEVICE_ENTRY_SIZE: usize = 64;
+const NAME_SIZE: usize = 36;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to read {0:?} field: {1}")]
+    ReadFieldError(&'static str, io::Error),
+    #[error("Invalid geometry magic: {0:#010x}")]
+    InvalidGeometryMagic(u32),
+    #[error("Invalid metadata header magic: {0:#010x}")]
+    InvalidHeaderMagic(u32),
+    #[error("Unsupported metadata version: {0}.{1}")]
+    UnsupportedVersion(u16, u16),
+    #[error("Invalid {0:?} size: {1}")]
+    InvalidSize(&'static str, u32),
+    #[error("Expected {0} checksum {1}, but have {2}")]
+    InvalidChecksum(&'static str, String, String),
+    #[error("{0:?} table is out of bounds")]
+    TableOutOfBounds(&'static str),
+    #[error("Partition {0:?} has out of bounds extents")]
+    ExtentsOutOfBounds(String),
+    #[error("Partition {0:?} has extent with unsupported target type: {1}")]
+    UnsupportedTargetType(String, u32),
+    #[error("Partition {0:?} has extent on other block device: {1}")]
+    UnsupportedBlockDevice(String, u32),
+    #[error("Logical partition not found: {0:?}")]
+    PartitionNotFound(String),
+    #[error("Metadata slot out of bounds: {0}")]
+    SlotOutOfBounds(u32),
+    #[error("Partition {0:?} has different extents in metadata slot {1}")]
+    InconsistentSlots(String, u32),
+    #[error("New data ({1} bytes) does not fit in partition {0:?} ({2} bytes)")]
+    PartitionTooSmall(String, u64, u64),
+    #[error("Partition {0:?} has non-zero data for zero extent at offset {1}")]
+    DataInZeroExtent(String, u64),
+    #[error("I/O error")]
+    IoError(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn read_name<R: Read>(reader: &mut R, field: &'static str) -> Result<String> {
+    reader
+        .read_string_padded(NAME_SIZE)
+        .map_err(|e| Error::ReadFieldError(field, e))
+}
+
+/// Verify the SHA256 checksum of `data`. The checksum field at `offset` is
+/// treated as zeros.
+fn verify_checksum(
+    name: &'static str,
+    data: &[u8],
+    offset: Option<usize>,
+    expected: &[u8],
+) -> Result<()> {
+    let mut context = digest::Context::new(&digest::SHA256);
+
+    match offset {
+        Some(o) => {
+            context.update(&data[..o]);
+            context.update(&[0u8; 32]);
+            context.update(&data[o + 32..]);
+        }
+        None => context.update(data),
+    }
+
+    let digest = context.finish();
+    if digest.as_ref() != expected {
+        return Err(Error::InvalidChecksum(
+            name,
+            hex::encode(expected),
+            hex::encode(digest),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub metadata_max_size: u32,
+    pub metadata_slot_count: u32,
+    pub logical_block_size: u32,
+}
+
+impl<R: Read> FromReader<R> for Geometry {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let mut buf = [0u8; GEOMETRY_STRUCT_SIZE];
+        reader.read_exact(&mut buf)?;
+
+        let mut cursor = Cursor::new(&buf);
+
+        let magic = cursor.read_u32::<LittleEndian>()?;
+        if magic != GEOMETRY_MAGIC {
+            return Err(Error::InvalidGeometryMagic(magic));
+        }
+
+        let struct_size = cursor.read_u32::<LittleEndian>()?;
+        if struct_size as usize != GEOMETRY_STRUCT_SIZE {
+            return Err(Error::InvalidSize("geometry", struct_size));
+        }
+
+        let mut checksum = [0u8; 32];
+        cursor.read_exact(&mut checksum)?;
+        verify_checksum("geometry", &buf, Some(8), &checksum)?;
+
+        let metadata_max_size = cursor.read_u32::<LittleEndian>()?;
+        let metadata_slot_count = cursor.read_u32::<LittleEndian>()?;
+        let logical_block_size = cursor.read_u32::<LittleEndian>()?;
+
+        if metadata_max_size == 0 || metadata_max_size % SECTOR_SIZE as u32 != 0 {
+            return Err(Error::InvalidSize("metadata_max_size", metadata_max_size));
+        }
+        if metadata_slot_count == 0 {
+            return Err(Error::InvalidSize(
+                "metadata_slot_count",
+                metadata_slot_count,
+            ));
+        }
+        if logical_block_size == 0 || logical_block_size % SECTOR_SIZE as u32 != 0 {
+            return Err(Error::InvalidSize("logical_block_size", logical_block_size));
+        }
+
+        Ok(Self {
+            metadata_max_size,
+            metadata_slot_count,
+            logical_block_size,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub attributes: u32,
+    pub first_extent_index: u32,
+    pub num_extents: u32,
+    pub group_index: u32,
+}
+
+impl<R: Read> FromReader<R> for Partition {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        Ok(Self {
+            name: read_name(&mut reader, "name")?,
+            attributes: reader.read_u32::<LittleEndian>()?,
+            first_extent_index: reader.read_u32::<LittleEndian>()?,
+            num_extents: reader.read_u32::<LittleEndian>()?,
+            group_index: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub num_sectors: u64,
+    pub target_type: u32,
+    /// Physical sector for [`TARGET_TYPE_LINEAR`]. Unused for
+    /// [`TARGET_TYPE_ZERO`].
+    pub target_data: u64,
+    /// Index of the block device.
+    pub target_source: u32,
+}
+
+impl<R: Read> FromReader<R> for Extent {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        Ok(Self {
+            num_sectors: reader.read_u64::<LittleEndian>()?,
+            target_type: reader.read_u32::<LittleEndian>()?,
+            target_data: reader.read_u64::<LittleEndian>()?,
+            target_source: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionGroup {
+    pub name: String,
+    pub flags: u32,
+    pub maximum_size: u64,
+}
+
+impl<R: Read> FromReader<R> for PartitionGroup {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        Ok(Self {
+            name: read_name(&mut reader, "name")?,
+            flags: reader.read_u32::<LittleEndian>()?,
+            maximum_size: reader.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockDevice {
+    pub first_logical_sector: u64,
+    pub alignment: u32,
+    pub alignment_offset: u32,
+    pub size: u64,
+    pub partition_name: String,
+    pub flags: u32,
+}
+
+impl<R: Read> FromReader<R> for BlockDevice {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        Ok(Self {
+            first_logical_sector: reader.read_u64::<LittleEndian>()?,
+            alignment: reader.read_u32::<LittleEndian>()?,
+            alignment_offset: reader.read_u32::<LittleEndian>()?,
+            size: reader.read_u64::<LittleEndian>()?,
+            partition_name: read_name(&mut reader, "partition_name")?,
+            flags: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+}
+
+/// Parse a table of `T` from the tables region.
+fn read_table<T: for<'a> FromReader<&'a [u8], Error = Error>>(
+    name: &'static str,
+    tables: &[u8],
+    descriptor: (u32, u32, u32),
+    min_entry_size: usize,
+) -> Result<Vec<T>> {
+    let (offset, num_entries, entry_size) = descriptor;
+    let offset = offset as usize;
+    let entry_size = entry_size as usize;
+
+    if entry_size < min_entry_size {
+        return Err(Error::InvalidSize(name, entry_size as u32));
+    }
+
+    let end = (num_entries as usize)
+        .checked_mul(entry_size)
+        .and_then(|s| s.checked_add(offset))
+        .filter(|&e| e <= tables.len())
+        .ok_or(Error::TableOutOfBounds(name))?;
+
+    tables[offset..end]
+        .chunks_exact(entry_size)
+        .map(T::from_reader)
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub geometry: Geometry,
+    pub major_version: u16,
+    pub minor_version: u16,
+    /// Header flags. Only present in version 10.2 and newer.
+    pub flags: u32,
+    pub partitions: Vec<Partition>,
+    pub extents: Vec<Extent>,
+    pub groups: Vec<PartitionGroup>,
+    pub block_devices: Vec<BlockDevice>,
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "LP metadata v{}.{}",
+            self.major_version, self.minor_version
+        )?;
+
+        for partition in &self.partitions {
+            let group = self
+                .groups
+                .get(partition.group_index as usize)
+                .map_or("<invalid>", |g| g.name.as_str());
+
+            let size = match self.partition_size(partition) {
+                Ok(s) => format!("{s} bytes"),
+                Err(_) => "<invalid> size".to_owned(),
+            };
+
+            writeln!(
+                f,
+                "- {}: {size}, group {group}, {} extent(s)",
+                partition.name, partition.num_extents,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Metadata {
+    /// Read the geometry, preferring the primary copy and falling back to the
+    /// backup copy.
+    fn read_geometry(mut reader: impl Read + Seek) -> Result<Geometry> {
+        reader.seek(SeekFrom::Start(PARTITION_RESERVED_BYTES))?;
+
+        match Geometry::from_reader(&mut reader) {
+            Ok(g) => Ok(g),
+            Err(e) => {
+                reader.seek(SeekFrom::Start(PARTITION_RESERVED_BYTES + GEOMETRY_SIZE))?;
+                Geometry::from_reader(&mut reader).map_err(|_| e)
+            }
+        }
+    }
+
+    /// Parse a metadata header and its tables from the current position.
+    fn read_metadata(mut reader: impl Read, geometry: Geometry) -> Result<Self> {
+        let max_size = geometry.metadata_max_size as usize;
+
+        let mut header = vec![0u8; HEADER_V1_0_SIZE];
+        reader.read_exact(&mut header)?;
+
+        let mut cursor = Cursor::new(&header);
+
+        let magic = cursor.read_u32::<LittleEndian>()?;
+        if magic != HEADER_MAGIC {
+            return Err(Error::InvalidHeaderMagic(magic));
+        }
+
+        let major_version = cursor.read_u16::<LittleEndian>()?;
+        let minor_version = cursor.read_u16::<LittleEndian>()?;
+        if major_version != MAJOR_VERSION || minor_version > MINOR_VERSION_MAX {
+            return Err(Error::UnsupportedVersion(major_version, minor_version));
+        }
+
+        let header_size = cursor.read_u32::<LittleEndian>()?;
+        if (header_size as usize) < HEADER_V1_0_SIZE || header_size as usize > max_size {
+            return Err(Error::InvalidSize("header", header_size));
+        }
+
+        // Newer headers have extra fields after the v1.0 fields.
+        let mut rest = vec![0u8; header_size as usize - HEADER_V1_0_SIZE];
+        reader.read_exact(&mut rest)?;
+        header.extend_from_slice(&rest);
+
+        let mut cursor = Cursor::new(&header);
+        cursor.set_position(12);
+
+        let mut header_checksum = [0u8; 32];
+        cursor.read_exact(&mut header_checksum)?;
+        verify_checksum("header", &header, Some(12), &header_checksum)?;
+
+        let tables_size = cursor.read_u32::<LittleEndian>()?;
+        if tables_size as usize > max_size - header_size as usize {
+            return Err(Error::InvalidSize("tables", tables_size));
+        }
+
+        let mut tables_checksum = [0u8; 32];
+        cursor.read_exact(&mut tables_checksum)?;
+
+        let mut descriptors = [(0u32, 0u32, 0u32); 4];
+        for descriptor in &mut descriptors {
+            descriptor.0 = cursor.read_u32::<LittleEndian>()?;
+            descriptor.1 = cursor.read_u32::<LittleEndian>()?;
+            descriptor.2 = cursor.read_u32::<LittleEndian>()?;
+        }
+
+        let flags = if minor_version >= 2 && header.len() >= HEADER_V1_0_SIZE + 4 {
+            cursor.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+
+        let mut tables = vec![0u8; tables_size as usize];
+        reader.read_exact(&mut tables)?;
+        verify_checksum("tables", &tables, None, &tables_checksum)?;
+
+        let metadata = Self {
+            geometry,
+            major_version,
+            minor_version,
+            flags,
+            partitions: read_table("partitions", &tables, descriptors[0], PARTITION_ENTRY_SIZE)?,
+            extents: read_table("extents", &tables, descriptors[1], EXTENT_ENTRY_SIZE)?,
+            groups: read_table("groups", &tables, descriptors[2], GROUP_ENTRY_SIZE)?,
+            block_devices: read_table(
+                "block_devices",
+                &tables,
+                descriptors[3],
+                BLOCK_DEVICE_ENTRY_SIZE,
+            )?,
+        };
+
+        for partition in &metadata.partitions {
+            metadata.partition_extents(partition)?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Read the metadata for the specified slot. The primary copy is preferred
+    /// and the backup copy is used if the primary copy is corrupt.
+    pub fn from_reader_slot(mut reader: impl Read + Seek, slot: u32) -> Result<Self> {
+        let geometry = Self::read_geometry(&mut reader)?;
+        if slot >= geometry.metadata_slot_count {
+            return Err(Error::SlotOutOfBounds(slot));
+        }
+
+        let max_size = u64::from(geometry.metadata_max_size);
+        let primary_offset =
+            PARTITION_RESERVED_BYTES + 2 * GEOMETRY_SIZE + u64::from(slot) * max_size;
+        let backup_offset = primary_offset + u64::from(geometry.metadata_slot_count) * max_size;
+
+        reader.seek(SeekFrom::Start(primary_offset))?;
+
+        match Self::read_metadata(&mut reader, geometry.clone()) {
+            Ok(m) => Ok(m),
+            Err(e) => {
+                reader.seek(SeekFrom::Start(backup_offset))?;
+                Self::read_metadata(&mut reader, geometry).map_err(|_| e)
+            }
+        }
+    }
+
+    pub fn partition(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Get the extents that belong to a partition.
+    pub fn partition_extents(&self, partition: &Partition) -> Result<&[Extent]> {
+        let start = partition.first_extent_index as usize;
+
+        start
+            .checked_add(partition.num_extents as usize)
+            .and_then(|end| self.extents.get(start..end))
+            .ok_or_else(|| Error::ExtentsOutOfBounds(partition.name.clone()))
+    }
+
+    /// Get the size of a partition in bytes.
+    pub fn partition_size(&self, partition: &Partition) -> Result<u64> {
+        self.partition_extents(partition)?
+            .iter()
+            .try_fold(0u64, |size, e| {
+                e.num_sectors
+                    .checked_mul(SECTOR_SIZE)
+                    .and_then(|s| size.checked_add(s))
+            })
+            .ok_or_else(|| Error::ExtentsOutOfBounds(partition.name.clone()))
+    }
+}
+
+impl<R: Read + Seek> FromReader<R> for Metadata {
+    type Error = Error;
+
+    /// Read the metadata for the first slot.
+    fn from_reader(reader: R) -> Result<Self> {
+        Self::from_reader_slot(reader, 0)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MappedExtent {
+    /// Offset within the logical partition.
+    start: u64,
+    size: u64,
+    /// Offset within the super image or [`None`] for zero extents.
+    physical_offset: Option<u64>,
+}
+
+/// Compute where each of a partition's extents lives within the logical
+/// partition and within the super image. Returns the extents and the total
+/// size of the partition.
+fn map_extents(metadata: &Metadata, name: &str) -> Result<(Vec<MappedExtent>, u64)> {
+    let partition = metadata
+        .partition(name)
+        .ok_or_else(|| Error::PartitionNotFound(name.to_owned()))?;
+    let mut extents = vec![];
+    let mut size = 0u64;
+
+    for extent in metadata.partition_extents(partition)? {
+        let extent_size = extent
+            .num_sectors
+            .checked_mul(SECTOR_SIZE)
+            .ok_or_else(|| Error::ExtentsOutOfBounds(name.to_owned()))?;
+
+        let physical_offset = match extent.target_type {
+            TARGET_TYPE_LINEAR => {
+                if extent.target_source != 0 {
+                    return Err(Error::UnsupportedBlockDevice(
+                        name.to_owned(),
+                        extent.target_source,
+                    ));
+                }
+
+                Some(
+                    extent
+                        .target_data
+                        .checked_mul(SECTOR_SIZE)
+                        .filter(|o| o.checked_add(extent_size).is_some())
+                        .ok_or_else(|| Error::ExtentsOutOfBounds(name.to_owned()))?,
+                )
+            }
+            TARGET_TYPE_ZERO => None,
+            t => return Err(Error::UnsupportedTargetType(name.to_owned(), t)),
+        };
+
+        extents.push(MappedExtent {
+            start: size,
+            size: extent_size,
+            physical_offset,
+        });
+
+        size = size
+            .checked_add(extent_size)
+            .ok_or_else(|| Error::ExtentsOutOfBounds(name.to_owned()))?;
+    }
+
+    Ok((extents, size))
+}
+
+/// Ensure that every linear extent lies within the first block device and does
+/// not overlap the reserved area, the geometry, or the metadata slots.
+fn check_writable_extents(metadata: &Metadata, name: &str, extents: &[MappedExtent]) -> Result<()> {
+    let out_of_bounds = || Error::ExtentsOutOfBounds(name.to_owned());
+    let block_device = metadata.block_devices.first().ok_or_else(out_of_bounds)?;
+    let geometry = &metadata.geometry;
+
+    // Primary and backup copies of every slot.
+    let metadata_end = u64::from(geometry.metadata_max_size)
+        .checked_mul(u64::from(geometry.metadata_slot_count))
+        .and_then(|s| s.checked_mul(2))
+        .and_then(|s| s.checked_add(PARTITION_RESERVED_BYTES + 2 * GEOMETRY_SIZE))
+        .ok_or_else(out_of_bounds)?;
+    let data_start = block_device
+        .first_logical_sector
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(out_of_bounds)?
+        .max(metadata_end);
+
+    for extent in extents {
+        let Some(offset) = extent.physical_offset else {
+            continue;
+        };
+
+        // map_extents() already checked that this does not overflow.
+        if offset < data_start || offset + extent.size > block_device.size {
+            return Err(out_of_bounds());
+        }
+    }
+
+    Ok(())
+}
+
+/// A reader for the contents of a logical partition within a super image.
+pub struct PartitionReader<R: Read + Seek> {
+    inner: R,
+    extents: Vec<MappedExtent>,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> PartitionReader<R> {
+    pub fn new(inner: R, metadata: &Metadata, name: &str) -> Result<Self> {
+        let (extents, size) = map_extents(metadata, name)?;
+
+        Ok(Self {
+            inner,
+            extents,
+            size,
+            pos: 0,
+        })
+    }
+
+    /// Size of the logical partition in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for PartitionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+
+        let index = self
+            .extents
+            .partition_point(|e| e.start + e.size <= self.pos);
+        let extent = &self.extents[index];
+        let offset = self.pos - extent.start;
+        let to_read = (extent.size - offset).min(buf.len() as u64) as usize;
+
+        let n = match extent.physical_offset {
+            Some(physical_offset) => {
+                self.inner.seek(SeekFrom::Start(physical_offset + offset))?;
+                self.inner.read(&mut buf[..to_read])?
+            }
+            None => {
+                buf[..to_read].fill(0);
+                to_read
+            }
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for PartitionReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(o) => {
+                self.pos = o;
+                return Ok(o);
+            }
+            SeekFrom::End(o) => (self.size, o),
+            SeekFrom::Current(o) => (self.pos, o),
+        };
+
+        self.pos = base
+            .to_i64()
+            .and_then(|b| b.checked_add(offset))
+            .and_then(|p| p.to_u64())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Offset would be before the start of the file",
+                )
+            })?;
+
+        Ok(self.pos)
+    }
+}
+
+/// Replace the contents of a logical partition within a super image in place.
+/// `size` bytes are read from `reader`. If the new data is smaller than the
+/// space allocated to the partition, the remainder is filled with zeros. The
+/// LP metadata is never modified, so the partition's size does not change.
+///
+/// The partition's extents must be inside the first block device and after the
+/// metadata region.
+///
+/// Every metadata slot that contains the partition must map it to the same
+/// extents. Otherwise, writing the new data could corrupt another slot's view
+/// of the super image. If an error occurs while writing, the partition may be
+/// left partially written.
+pub fn replace_partition(
+    mut image: impl Read + Write + Seek,
+    name: &str,
+    mut reader: impl Read,
+    size: u64,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let metadata = Metadata::from_reader_slot(&mut image, 0)?;
+    let partition = metadata
+        .partition(name)
+        .ok_or_else(|| Error::PartitionNotFound(name.to_owned()))?;
+    let partition_extents = metadata.partition_extents(partition)?;
+
+    for slot in 1..metadata.geometry.metadata_slot_count {
+        let other = Metadata::from_reader_slot(&mut image, slot)?;
+
+        if let Some(p) = other.partition(name) {
+            if other.partition_extents(p)? != partition_extents {
+                return Err(Error::InconsistentSlots(name.to_owned(), slot));
+            }
+        }
+    }
+
+    let (extents, partition_size) = map_extents(&metadata, name)?;
+    check_writable_extents(&metadata, name, &extents)?;
+
+    if size > partition_size {
+        return Err(Error::PartitionTooSmall(
+            name.to_owned(),
+            size,
+            partition_size,
+        ));
+    }
+
+    let mut remaining = size;
+
+    for extent in extents {
+        let to_copy = remaining.min(extent.size);
+
+        match extent.physical_offset {
+            Some(offset) => {
+                image.seek(SeekFrom::Start(offset))?;
+                stream::copy_n(&mut reader, &mut image, to_copy, cancel_signal)?;
+                image.write_zeros_exact(extent.size - to_copy)?;
+            }
+            None => {
+                let mut non_zero = false;
+                stream::copy_n_inspect(
+                    &mut reader,
+                    io::sink(),
+                    to_copy,
+                    |data| non_zero |= !util::is_zero(data),
+                    cancel_signal,
+                )?;
+
+                if non_zero {
+                    return Err(Error::DataInZeroExtent(name.to_owned(), extent.start));
+                }
+            }
+        }
+
+        remaining -= to_copy;
+    }
+
+    image.flush()?;
+
+    Ok(())
+}
@@ -4,9 +4,21 @@
  */
 
 pub mod avb;
+pub mod bcb;
+pub mod blockmap;
 pub mod bootimage;
 pub mod compression;
 pub mod cpio;
+pub mod dtbo;
+pub mod fec;
+pub mod filesystem;
+pub mod gpt;
+pub mod lp;
 pub mod ota;
 pub mod padding;
 pub mod payload;
+pub mod pkcs12;
+pub mod protowire;
+pub mod qcdt;
+pub mod sparse;
+pub mod vintf;
@@ -4,31 +4,48 @@
  */
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
     io::{self, Cursor, Read, Seek, SeekFrom, Write},
-    iter,
+    iter, mem,
     sync::{atomic::AtomicBool, Arc},
 };
 
-use cms::signed_data::SignedData;
+use cms::{content_info::ContentInfo, signed_data::SignedData};
 use const_oid::{db::rfc5912, ObjectIdentifier};
 use memchr::memmem;
-use ring::digest::Context;
-use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use ring::digest::{Algorithm, Context, Digest};
+use rsa::{pkcs1::RsaPssParams, Pkcs1v15Sign, Pss, RsaPrivateKey, RsaPublicKey};
+use serde::{Serialize, Serializer};
 use sha1::Sha1;
 use sha2::Sha256;
 use thiserror::Error;
-use x509_cert::{der::Encode, Certificate};
+use x509_cert::{der::Encode, spki::AlgorithmIdentifierOwned, Certificate};
 use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::{
-    crypto,
-    format::payload::{self, PayloadHeader},
-    protobuf::build::tools::releasetools::{mod_OtaMetadata::OtaType, OtaMetadata},
-    stream::{self, FromReader, HashingReader, HashingWriter},
+    crypto::{self, RsaPadding, SignatureFormat},
+    format::{
+        avb::{self, Descriptor, Footer},
+        blockmap,
+        compression::{self, CompressedFormat},
+        payload::{self, PayloadHeader},
+        protowire::{self, Schema, UnknownFields},
+    },
+    protobuf::{
+        android::care_map::CareMap,
+        build::tools::releasetools::{mod_OtaMetadata::OtaType, OtaMetadata},
+        chromeos_update_engine::{
+            mod_InstallOperation::Type, Extent, InstallOperation, PartitionInfo,
+        },
+    },
+    stream::{self, CountingWriter, FromReader, HashingReader, HashingWriter, SectionReader},
     util,
 };
 
+pub const PATH_CARE_MAP_PB: &str = "care_map.pb";
+pub const PATH_CARE_MAP_TXT: &str = "care_map.txt";
+pub const PATH_COMPATIBILITY: &str = "compatibility.zip";
 pub const PATH_METADATA: &str = "META-INF/com/android/metadata";
 pub const PATH_METADATA_PB: &str = "META-INF/com/android/metadata.pb";
 pub const PATH_OTACERT: &str = "META-INF/com/android/otacert";
@@ -37,12 +54,89 @@ pub const PATH_PROPERTIES: &str = "payload_properties.txt";
 
 const NAME_PAYLOAD_METADATA: &str = "payload_metadata.bin";
 
+/// Optional entries whose offsets and sizes are listed in the property files.
+/// Like all entries in the property files, they must be stored uncompressed.
+const PROPERTY_FILE_OPTIONAL_PATHS: [&str; 4] = [
+    "apex_info.pb",
+    PATH_CARE_MAP_PB,
+    PATH_CARE_MAP_TXT,
+    PATH_COMPATIBILITY,
+];
+
 pub const PF_NAME: &str = "ota-property-files";
 pub const PF_STREAMING_NAME: &str = "ota-streaming-property-files";
 
 const ZIP_EOCD_MAGIC: &[u8; 4] = b"PK\x05\x06";
 
+/// Fixed size of a zip local file header, excluding the name.
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+/// Fixed size of a zip central directory header, excluding the name.
+const ZIP_CENTRAL_HEADER_SIZE: u64 = 46;
+/// Size of a data descriptor, which [`ZipWriter::new_streaming()`] writes
+/// after every entry. The zip64 variant has 8-byte size fields.
+const ZIP_DATA_DESCRIPTOR_SIZE: u64 = 16;
+const ZIP64_DATA_DESCRIPTOR_SIZE: u64 = 24;
+/// Size of the zip64 extra fields in the local and central directory headers.
+const ZIP64_LOCAL_EXTRA_SIZE: u64 = 20;
+const ZIP64_CENTRAL_EXTRA_SIZE: u64 = 28;
+/// Size of the EOCD record, excluding the archive comment.
+const ZIP_EOCD_SIZE: u64 = 22;
+/// Size of the zip64 EOCD record and its locator.
+const ZIP64_EOCD_SIZE: u64 = 56 + 20;
+
 const COMMENT_MESSAGE: &[u8] = b"signed by avbroot\0";
+const PROVENANCE_PREFIX: &str = "patched by avbroot v";
+/// Message used in place of the normal message when the signature was replaced
+/// with zeros by [`crypto::enable_unsafe_no_sign()`].
+const UNSIGNED_COMMENT_MESSAGE: &[u8] = b"UNSIGNED by avbroot (unsafe no-sign mode)\0";
+/// Maximum size of a custom archive comment message. The rest of the 64 KiB
+/// archive comment must be able to hold the signature.
+pub const COMMENT_MESSAGE_MAX_SIZE: usize = 1024;
+
+/// Block size used for the ranges in care maps.
+pub const CARE_MAP_BLOCK_SIZE: u64 = 4096;
+
+// Fields of the sidecar messages that are known to the generated protobuf code.
+// Unknown fields are preserved when the messages are rewritten. These must be
+// kept in sync with `care_map.proto` and `ota_metadata.proto`.
+static CARE_MAP_PARTITION_INFO_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None), (3, None), (4, None)],
+};
+static CARE_MAP_SCHEMA: Schema = Schema {
+    fields: &[(
+        CARE_MAP_FIELD_PARTITIONS,
+        Some(&CARE_MAP_PARTITION_INFO_SCHEMA),
+    )],
+};
+const CARE_MAP_FIELD_PARTITIONS: u32 = 1;
+static PARTITION_STATE_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None), (3, None), (4, None)],
+};
+static DEVICE_STATE_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, None),
+        (2, None),
+        (3, None),
+        (4, None),
+        (5, None),
+        (6, None),
+        (7, Some(&PARTITION_STATE_SCHEMA)),
+    ],
+};
+static OTA_METADATA_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, None),
+        (2, None),
+        (3, None),
+        // The property files are always regenerated.
+        (4, None),
+        (5, Some(&DEVICE_STATE_SCHEMA)),
+        (6, Some(&DEVICE_STATE_SCHEMA)),
+        (7, None),
+        (8, None),
+        (9, None),
+    ],
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -72,12 +166,38 @@ pub enum Error {
     InvalidPropertyFileEntry(String),
     #[error("Missing entry in OTA zip: {0}")]
     MissingZipEntry(&'static str),
+    #[error("Missing field in OTA metadata: {0}")]
+    MissingMetadataField(&'static str),
+    #[error("Unsafe zip entry name: {0:?}")]
+    UnsafeEntryName(String),
+    #[error("Multiple zip entries have the same sanitized name: {0:?}")]
+    DuplicateEntryName(String),
+    #[error("Care map has no ranges for partition: {0:?}")]
+    CareMapMissingRanges(String),
+    #[error("Archive comment message contains a NUL byte")]
+    CommentMessageHasNul,
+    #[error("Archive comment message contains EOCD magic")]
+    CommentMessageHasEocdMagic,
+    #[error("Archive comment message exceeds {COMMENT_MESSAGE_MAX_SIZE} bytes: {0}")]
+    CommentMessageTooLong(usize),
+    #[error("Archive comment exceeds {} bytes: {0}", u16::MAX)]
+    ArchiveCommentTooLong(usize),
+    #[error("Partition not found in payload: {0:?}")]
+    PartitionNotFound(String),
+    #[error("Partition {0:?} vbmeta size exceeds {max} bytes: {1}", max = avb::VBMETA_MAX_SIZE)]
+    VbmetaTooLarge(String, u64),
     #[error("CMS signing error")]
     CmsSign(#[from] crypto::Error),
+    #[error("AVB error")]
+    Avb(#[from] avb::Error),
+    #[error("Compression error")]
+    Compression(#[from] compression::Error),
     #[error("Payload error")]
     Payload(#[from] payload::Error),
     #[error("Protobuf error")]
     Protobuf(#[from] quick_protobuf::Error),
+    #[error("Protobuf wire format error")]
+    ProtoWire(#[from] protowire::Error),
     #[error("SPKI error")]
     Spki(#[from] pkcs8::spki::Error),
     #[error("x509 DER error")]
@@ -86,6 +206,8 @@ pub enum Error {
     Rsa(#[from] rsa::Error),
     #[error("Zip error")]
     Zip(#[from] ZipError),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
     #[error("I/O error")]
     Io(#[from] io::Error),
 }
@@ -93,8 +215,12 @@ pub enum Error {
 type Result<T> = std::result::Result<T, Error>;
 
 /// Generate the legacy plain-text and modern protobuf serializations of the
-/// given metadata instance.
-fn serialize_metadata(metadata: &OtaMetadata) -> Result<(String, Vec<u8>)> {
+/// given metadata instance. `unknown_fields` are merged into the protobuf
+/// serialization.
+fn serialize_metadata(
+    metadata: &OtaMetadata,
+    unknown_fields: &UnknownFields,
+) -> Result<(String, Vec<u8>)> {
     const SEP: &str = "|";
 
     let mut pairs = BTreeMap::<String, String>::new();
@@ -161,7 +287,8 @@ fn serialize_metadata(metadata: &OtaMetadata) -> Result<(String, Vec<u8>)> {
         .into_iter()
         .map(|(k, v)| format!("{k}={v}\n"))
         .collect::<String>();
-    let modern_metadata = util::write_protobuf(metadata)?;
+    let modern_metadata =
+        unknown_fields.merge(&util::write_protobuf(metadata)?, &OTA_METADATA_SCHEMA)?;
 
     Ok((legacy_metadata, modern_metadata))
 }
@@ -173,6 +300,155 @@ pub struct ZipEntry {
     pub size: u64,
 }
 
+/// Sanitize the name of a zip entry from an input OTA. Like libziparchive,
+/// names containing NUL bytes are rejected. Additionally, backslashes are
+/// normalized to forward slashes and leading slashes are removed. Names that
+/// could still escape the directory they are extracted to, like those with `..`
+/// components or a drive letter, are rejected.
+pub fn sanitize_entry_name(name: &str) -> Result<String> {
+    let unsafe_name = || Error::UnsafeEntryName(name.to_owned());
+
+    if name.contains('\0') {
+        return Err(unsafe_name());
+    }
+
+    let normalized = name.replace('\\', "/");
+    let normalized = normalized.trim_start_matches('/');
+
+    if normalized.is_empty()
+        || normalized.split('/').any(|c| c == "..")
+        || normalized
+            .split('/')
+            .next()
+            .map_or(false, |c| c.contains(':'))
+    {
+        return Err(unsafe_name());
+    }
+
+    Ok(normalized.to_owned())
+}
+
+/// Get the sanitized names of all entries in a zip, mapped to their original
+/// names. See [`sanitize_entry_name()`].
+pub fn sanitized_entry_names(
+    zip: &ZipArchive<impl Read + Seek>,
+) -> Result<BTreeMap<String, String>> {
+    let mut names = BTreeMap::new();
+
+    for name in zip.file_names() {
+        let sanitized = sanitize_entry_name(name)?;
+
+        if names.insert(sanitized.clone(), name.to_owned()).is_some() {
+            return Err(Error::DuplicateEntryName(sanitized));
+        }
+    }
+
+    Ok(names)
+}
+
+/// Half-open range of blocks in a care map or block map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Generate a care map for a partition image. Every [`CARE_MAP_BLOCK_SIZE`]
+/// block that contains non-zero data is included and adjacent blocks are
+/// merged into a single range. A partial block at the end of the image counts
+/// as a full block. Sparse images should be read with
+/// [`super::sparse::SparseReader`], which returns zeros for don't-care chunks.
+pub fn generate_care_map(
+    reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<BlockRange>> {
+    let ranges = blockmap::generate(reader, CARE_MAP_BLOCK_SIZE as u32, cancel_signal)?;
+
+    Ok(ranges)
+}
+
+/// Format block ranges in the `RangeSet` syntax used by care maps. This is the
+/// number of integers followed by the start and end of each range, all
+/// separated by commas.
+pub fn format_range_set(ranges: &[BlockRange]) -> String {
+    let mut tokens = vec![(ranges.len() * 2).to_string()];
+
+    for range in ranges {
+        tokens.push(range.start.to_string());
+        tokens.push(range.end.to_string());
+    }
+
+    tokens.join(",")
+}
+
+/// Update the ranges in a `care_map.pb` file. `ranges_for` is called with the
+/// name of each partition listed in the care map and should return the new
+/// ranges or [`None`] to keep the existing ranges. Partitions in `removed` are
+/// dropped from the care map without calling `ranges_for`. Partitions are never
+/// added. Fields that are unknown to avbroot are preserved.
+pub fn update_care_map_pb(
+    data: &[u8],
+    removed: &BTreeSet<String>,
+    mut ranges_for: impl FnMut(&str) -> Result<Option<Vec<BlockRange>>>,
+) -> Result<Vec<u8>> {
+    let mut care_map: CareMap = util::read_protobuf(data)?;
+    let mut unknown_fields = UnknownFields::extract(data, &CARE_MAP_SCHEMA)?;
+
+    // The unknown fields of each partition info are matched up by index, so
+    // they need to move along with the partitions that are kept.
+    for (index, mut info) in mem::take(&mut care_map.partitions).into_iter().enumerate() {
+        let unknown = unknown_fields.take_nested(CARE_MAP_FIELD_PARTITIONS, index);
+        if removed.contains(&info.name) {
+            continue;
+        }
+
+        if let Some(ranges) = ranges_for(&info.name)? {
+            info.ranges = format_range_set(&ranges);
+        }
+        if let Some(unknown) = unknown {
+            let new_index = care_map.partitions.len();
+            unknown_fields.insert_nested(CARE_MAP_FIELD_PARTITIONS, new_index, unknown);
+        }
+
+        care_map.partitions.push(info);
+    }
+
+    Ok(unknown_fields.merge(&util::write_protobuf(&care_map)?, &CARE_MAP_SCHEMA)?)
+}
+
+/// Update the ranges in a legacy `care_map.txt` file, which consists of
+/// alternating lines of partition names and ranges. `removed` and `ranges_for`
+/// behave the same as in [`update_care_map_pb()`].
+pub fn update_care_map_txt(
+    data: &str,
+    removed: &BTreeSet<String>,
+    mut ranges_for: impl FnMut(&str) -> Result<Option<Vec<BlockRange>>>,
+) -> Result<String> {
+    let mut lines = data.lines();
+    let mut result = String::new();
+
+    while let Some(name) = lines.next() {
+        let ranges = lines
+            .next()
+            .ok_or_else(|| Error::CareMapMissingRanges(name.to_owned()))?;
+
+        if removed.contains(name) {
+            continue;
+        }
+
+        result.push_str(name);
+        result.push('\n');
+
+        match ranges_for(name)? {
+            Some(r) => result.push_str(&format_range_set(&r)),
+            None => result.push_str(ranges),
+        }
+        result.push('\n');
+    }
+
+    Ok(result)
+}
+
 /// Parse OTA property files string.
 pub fn parse_property_files(data: &str) -> Result<Vec<ZipEntry>> {
     let mut result = vec![];
@@ -230,12 +506,7 @@ fn compute_property_files(
         tokens.push(compute(path)?);
     }
 
-    for path in [
-        "apex_info.pb",
-        "care_map.pb",
-        "care_map.txt",
-        "compatibility.zip",
-    ] {
+    for path in PROPERTY_FILE_OPTIONAL_PATHS {
         if let Ok(token) = compute(path) {
             tokens.push(token);
         }
@@ -300,6 +571,7 @@ pub fn add_metadata(
     payload_metadata_size: u64,
 ) -> Result<OtaMetadata> {
     let mut metadata: OtaMetadata = util::read_protobuf(metadata_pb_raw)?;
+    let unknown_fields = UnknownFields::extract(metadata_pb_raw, &OTA_METADATA_SCHEMA)?;
     let options = FileOptions::default().compression_method(CompressionMethod::Stored);
 
     let mut zip_entries = zip_entries.to_owned();
@@ -317,7 +589,7 @@ pub fn add_metadata(
 
     // Add the placeholders to a temporary zip to compute final property files.
     let (temp_legacy_offset, temp_modern_offset) = {
-        let (legacy_raw, modern_raw) = serialize_metadata(&metadata)?;
+        let (legacy_raw, modern_raw) = serialize_metadata(&metadata, &unknown_fields)?;
         let mut writer = ZipWriter::new_streaming(Cursor::new(Vec::new()));
 
         writer.start_file_with_extra_data(PATH_METADATA, options)?;
@@ -349,7 +621,7 @@ pub fn add_metadata(
 
     // Add the final metadata files to the real zip.
     {
-        let (legacy_raw, modern_raw) = serialize_metadata(&metadata)?;
+        let (legacy_raw, modern_raw) = serialize_metadata(&metadata, &unknown_fields)?;
 
         zip_writer.start_file_with_extra_data(PATH_METADATA, options)?;
         let legacy_offset = zip_writer.end_extra_data()?;
@@ -396,6 +668,238 @@ pub fn verify_metadata(
     Ok(())
 }
 
+/// Check if the entries of an OTA zip are laid out for update_engine HTTP
+/// streaming. All entries must be stored uncompressed, except for the ones that
+/// [`can_copy_raw()`] allows, `payload_properties.txt` must exist, and
+/// `payload.bin` must come after every other entry aside from the OTA metadata.
+pub fn is_streaming_layout(zip: &mut ZipArchive<impl Read + Seek>) -> Result<bool> {
+    let mut entries = vec![];
+
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        let compression = entry.compression();
+        if compression != CompressionMethod::Stored && !can_copy_raw(entry.name(), compression) {
+            return Ok(false);
+        }
+
+        entries.push((entry.data_start(), entry.name().to_owned()));
+    }
+
+    entries.sort();
+
+    let has_properties = entries.iter().any(|(_, n)| n == PATH_PROPERTIES);
+    let last_data_entry = entries
+        .iter()
+        .map(|(_, n)| n.as_str())
+        .filter(|n| *n != PATH_METADATA && *n != PATH_METADATA_PB)
+        .last();
+
+    Ok(has_properties && last_data_entry == Some(PATH_PAYLOAD))
+}
+
+/// Whether an entry that is passed through unmodified can be copied in its
+/// original compressed form with [`copy_entry_raw()`]. Entries that are stored
+/// uncompressed, that avbroot regenerates, or that are listed in the property
+/// files are always written uncompressed instead.
+pub fn can_copy_raw(path: &str, compression: CompressionMethod) -> bool {
+    compression != CompressionMethod::Stored
+        && ![
+            PATH_METADATA,
+            PATH_METADATA_PB,
+            PATH_OTACERT,
+            PATH_PAYLOAD,
+            PATH_PROPERTIES,
+        ]
+        .contains(&path)
+        && !PROPERTY_FILE_OPTIONAL_PATHS.contains(&path)
+}
+
+/// Map each entry name in `zip` to its index. [`ZipArchive`] has no raw lookup
+/// by name, so this is needed for [`copy_entry_raw()`].
+pub fn entry_indices(zip: &mut ZipArchive<impl Read + Seek>) -> Result<HashMap<String, usize>> {
+    let mut indices = HashMap::new();
+
+    for i in 0..zip.len() {
+        indices.insert(zip.by_index_raw(i)?.name().to_owned(), i);
+    }
+
+    Ok(indices)
+}
+
+/// Copy the compressed data of the entry at `index` to the output as `name`
+/// without recompressing it. The CRC32 and sizes are kept from the original
+/// entry, so the data is byte-for-byte identical. The entry is decompressed
+/// once beforehand to verify its CRC32 so that a corrupted entry is never
+/// passed through silently. Use [`entry_indices()`] to find the index of an
+/// entry by name.
+pub fn copy_entry_raw(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    zip_writer: &mut ZipWriter<impl Write>,
+    index: usize,
+    name: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    // The reader returns an error at EOF if the CRC32 does not match.
+    let entry = zip_reader.by_index(index)?;
+    stream::copy(entry, io::sink(), cancel_signal)?;
+
+    let entry = zip_reader.by_index_raw(index)?;
+
+    zip_writer.raw_copy_file_rename(entry, name)?;
+
+    Ok(())
+}
+
+/// Convert a sideloadable OTA zip to a layout suitable for update_engine HTTP
+/// streaming (see [`is_streaming_layout()`]). `payload_properties.txt` is
+/// generated from the payload if it does not exist. The payload itself is
+/// copied without modification, so its signatures remain valid.
+///
+/// Entry names are sanitized with [`sanitize_entry_name()`]. If the input
+/// already has the streaming layout and no entry names were changed by
+/// sanitization, it is copied as-is and `Ok(false)` is returned. Otherwise, the
+/// OTA metadata property files are regenerated and, since the entries have
+/// moved, the new zip is signed with `key` and `cert`.
+pub fn to_streaming(
+    mut reader: impl Read + Seek,
+    writer: impl Write,
+    key: &RsaPrivateKey,
+    cert: &Certificate,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<bool> {
+    let mut zip_reader = ZipArchive::new(&mut reader)?;
+
+    // Entries are looked up by their sanitized names, but read using their
+    // original names.
+    let names = sanitized_entry_names(&zip_reader)?;
+    let names_unchanged = names.iter().all(|(k, v)| k == v);
+
+    if names_unchanged && is_streaming_layout(&mut zip_reader)? {
+        drop(zip_reader);
+        reader.rewind()?;
+        stream::copy(&mut reader, writer, cancel_signal)?;
+
+        return Ok(false);
+    }
+
+    for path in [PATH_METADATA_PB, PATH_PAYLOAD] {
+        if !names.contains_key(path) {
+            return Err(Error::MissingZipEntry(path));
+        }
+    }
+
+    let metadata_pb_raw = {
+        let mut entry = zip_reader.by_name(&names[PATH_METADATA_PB])?;
+        let mut buf = vec![];
+        entry.read_to_end(&mut buf)?;
+        buf
+    };
+    let payload_metadata_size = {
+        let entry = zip_reader.by_name(&names[PATH_PAYLOAD])?;
+        PayloadHeader::from_reader(entry)?.blob_offset
+    };
+    let properties = if let Some(name) = names.get(PATH_PROPERTIES) {
+        let mut entry = zip_reader.by_name(name)?;
+        let mut buf = String::new();
+        entry.read_to_string(&mut buf)?;
+        buf
+    } else {
+        let entry = zip_reader.by_name(&names[PATH_PAYLOAD])?;
+        payload::compute_properties(entry, cancel_signal)?
+    };
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(writer));
+
+    // Compressed entries are copied as-is first. Their offsets are not needed
+    // for the property files and this keeps a stored entry last, which the
+    // next offset calculation below relies on.
+    let indices = entry_indices(&mut zip_reader)?;
+    let mut raw_paths = vec![];
+    for (path, original_name) in &names {
+        if can_copy_raw(path, zip_reader.by_name(original_name)?.compression()) {
+            raw_paths.push(path.clone());
+        }
+    }
+    for path in &raw_paths {
+        copy_entry_raw(
+            &mut zip_reader,
+            &mut zip_writer,
+            indices[&names[path]],
+            path,
+            cancel_signal,
+        )?;
+    }
+
+    // Keep the remaining entries in sorted order for reproducibility.
+    let mut paths = names
+        .keys()
+        .filter(|n| {
+            ![
+                PATH_METADATA,
+                PATH_METADATA_PB,
+                PATH_PAYLOAD,
+                PATH_PROPERTIES,
+            ]
+            .contains(&n.as_str())
+                && !raw_paths.contains(n)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    paths.push(PATH_PROPERTIES.to_owned());
+    paths.push(PATH_PAYLOAD.to_owned());
+
+    let mut entries = vec![];
+    let mut last_entry_used_zip64 = false;
+
+    for path in &paths {
+        let (mut reader, size): (Box<dyn Read + '_>, u64) = if path == PATH_PROPERTIES {
+            (Box::new(properties.as_bytes()), properties.len() as u64)
+        } else {
+            let entry = zip_reader.by_name(&names[path])?;
+            let size = entry.size();
+            (Box::new(entry), size)
+        };
+
+        // Android's libarchive parser only reads 64-bit data descriptor size
+        // fields if the central directory says the file size is >= 2^32 - 1.
+        let use_zip64 = size >= 0xffffffff;
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(use_zip64);
+
+        zip_writer.start_file_with_extra_data(path, options)?;
+        let offset = zip_writer.end_extra_data()?;
+        let mut writer = CountingWriter::new(&mut zip_writer);
+
+        stream::copy(&mut reader, &mut writer, cancel_signal)?;
+
+        let (_, size) = writer.finish();
+
+        entries.push(ZipEntry {
+            name: path.clone(),
+            offset,
+            size,
+        });
+
+        last_entry_used_zip64 = use_zip64;
+    }
+
+    let data_descriptor_size = if last_entry_used_zip64 { 24 } else { 16 };
+    add_metadata(
+        &entries,
+        &mut zip_writer,
+        // Offset where next entry would begin.
+        entries.last().map(|e| e.offset + e.size).unwrap() + data_descriptor_size,
+        &metadata_pb_raw,
+        payload_metadata_size,
+    )?;
+
+    let signing_writer = zip_writer.finish()?;
+    signing_writer.finish(key, cert)?;
+
+    Ok(true)
+}
+
 /// Parse the CMS signature from the OTA zip comment. Returns the decoded CMS
 /// [`SignedData`] structure and the length of the file (from the beginning)
 /// that's covered by the signature. This does not perform any parsing of zip
@@ -445,71 +949,216 @@ fn parse_ota_sig(mut reader: impl Read + Seek) -> Result<(SignedData, u64)> {
     Ok((sd, hashed_size))
 }
 
-/// Verify an OTA zip against its embedded certificates. This function makes no
-/// assertion about whether the certificate is actually trusted. Returns the
-/// embedded certificate.
-///
-/// CMS signed attributes are intentionally not supported because AOSP recovery
-/// does not support them either. It expects the CMS [`SignedData`] structure to
-/// be used for nothing more than a raw signature transport mechanism.
-pub fn verify_ota(
-    mut reader: impl Read + Seek,
-    cancel_signal: &Arc<AtomicBool>,
-) -> Result<Certificate> {
-    let (sd, hashed_size) = parse_ota_sig(&mut reader)?;
+fn digest_algorithm_for_oid(oid: ObjectIdentifier) -> Option<&'static Algorithm> {
+    if oid == rfc5912::ID_SHA_256 {
+        Some(&ring::digest::SHA256)
+    } else if oid == rfc5912::ID_SHA_1 {
+        Some(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY)
+    } else {
+        None
+    }
+}
 
-    // Make sure the certificate in the CMS structure matches the otacert zip
-    // entry.
-    let certs = crypto::get_cms_certs(&sd);
-    if certs.len() != 1 {
-        return Err(Error::NotOneCmsCertificate(certs.len()));
+/// Signature algorithms supported for verifying OTA whole-file signatures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// RSA with PKCS#1 v1.5 padding. This is what AOSP's signapk produces.
+    RsaPkcs1v15,
+    /// RSA with PSS padding, using MGF1 with the same digest as the message.
+    RsaPss { salt_len: usize },
+}
+
+impl SignatureAlgorithm {
+    /// Determine the signature algorithm from a CMS `SignerInfo`'s signature
+    /// algorithm identifier. The signature must use the same digest algorithm
+    /// as the one used to hash the file.
+    fn from_cms(
+        algorithm: &AlgorithmIdentifierOwned,
+        digest_oid: ObjectIdentifier,
+    ) -> Result<Self> {
+        let oid = algorithm.oid;
+
+        if oid == rfc5912::RSA_ENCRYPTION
+            || oid == rfc5912::SHA_256_WITH_RSA_ENCRYPTION
+            || oid == rfc5912::SHA_1_WITH_RSA_ENCRYPTION
+        {
+            Ok(Self::RsaPkcs1v15)
+        } else if oid == rfc5912::ID_RSASSA_PSS {
+            // Absent parameters mean SHA1, MGF1 with SHA1, and a 20 byte salt.
+            let params = match &algorithm.parameters {
+                Some(p) => p.decode_as::<RsaPssParams>()?,
+                None => RsaPssParams::default(),
+            };
+            let mgf_digest_oid = params.mask_gen.parameters.map(|p| p.oid);
+
+            if params.hash.oid != digest_oid
+                || params.mask_gen.oid != rfc5912::ID_MGF_1
+                || mgf_digest_oid != Some(digest_oid)
+            {
+                return Err(Error::UnsupportedSignatureAlgorithm(oid));
+            }
+
+            Ok(Self::RsaPss {
+                salt_len: params.salt_len.into(),
+            })
+        } else {
+            Err(Error::UnsupportedSignatureAlgorithm(oid))
+        }
     }
+}
 
-    let cert = &certs[0];
-    let public_key = crypto::get_public_key(cert)?;
+/// The parsed whole-file signature of an OTA zip. This allows the caller to
+/// hash the signed region of the file themselves, for example to verify other
+/// parts of the zip in the same pass.
+pub struct OtaSignature {
+    cert: Certificate,
+    public_key: RsaPublicKey,
+    digest_algorithm: &'static Algorithm,
+    signature_algorithm: SignatureAlgorithm,
+    signature: Vec<u8>,
+    hashed_size: u64,
+}
 
-    // Make sure this is a signature scheme we can handle. There's currently no
-    // Rust library to verify arbitrary CMS signatures for large files without
-    // fully reading them into memory.
-    if sd.signer_infos.0.len() != 1 {
-        return Err(Error::NotOneCmsSignerInfo(sd.signer_infos.0.len()));
+impl OtaSignature {
+    /// Parse the whole-file signature from the archive comment of an OTA zip.
+    ///
+    /// CMS signed attributes are intentionally not supported because AOSP
+    /// recovery does not support them either. It expects the CMS
+    /// [`SignedData`] structure to be used for nothing more than a raw
+    /// signature transport mechanism.
+    pub fn from_zip(reader: impl Read + Seek) -> Result<Self> {
+        let (sd, hashed_size) = parse_ota_sig(reader)?;
+
+        // Make sure the certificate in the CMS structure matches the otacert
+        // zip entry.
+        let certs = crypto::get_cms_certs(&sd);
+        if certs.len() != 1 {
+            return Err(Error::NotOneCmsCertificate(certs.len()));
+        }
+
+        let cert = certs[0].clone();
+
+        // Make sure this is a signature scheme we can handle. There's currently
+        // no Rust library to verify arbitrary CMS signatures for large files
+        // without fully reading them into memory.
+        if sd.signer_infos.0.len() != 1 {
+            return Err(Error::NotOneCmsSignerInfo(sd.signer_infos.0.len()));
+        }
+
+        let signer = sd.signer_infos.0.get(0).unwrap();
+
+        // We support SHA1 for verification only.
+        let digest_algorithm = digest_algorithm_for_oid(signer.digest_alg.oid)
+            .ok_or(Error::UnsupportedDigestAlgorithm(signer.digest_alg.oid))?;
+        let signature_algorithm =
+            SignatureAlgorithm::from_cms(&signer.signature_algorithm, signer.digest_alg.oid)?;
+
+        // This is only done after checking the algorithm so that certificates
+        // with non-RSA keys are reported as an unsupported algorithm.
+        let public_key = crypto::get_public_key(&cert)?;
+
+        Ok(Self {
+            cert,
+            public_key,
+            digest_algorithm,
+            signature_algorithm,
+            signature: signer.signature.as_bytes().to_vec(),
+            hashed_size,
+        })
     }
 
-    let signer = sd.signer_infos.0.get(0).unwrap();
-    if signer.digest_alg.oid != rfc5912::ID_SHA_256 && signer.digest_alg.oid != rfc5912::ID_SHA_1 {
-        return Err(Error::UnsupportedDigestAlgorithm(signer.digest_alg.oid));
-    } else if signer.signature_algorithm.oid != rfc5912::RSA_ENCRYPTION
-        && signer.signature_algorithm.oid != rfc5912::SHA_256_WITH_RSA_ENCRYPTION
-    {
-        return Err(Error::UnsupportedSignatureAlgorithm(
-            signer.signature_algorithm.oid,
-        ));
+    /// The embedded certificate. This makes no assertion about whether the
+    /// certificate is actually trusted.
+    pub fn cert(&self) -> &Certificate {
+        &self.cert
+    }
+
+    /// The signature algorithm declared in the CMS structure.
+    pub fn signature_algorithm(&self) -> SignatureAlgorithm {
+        self.signature_algorithm
+    }
+
+    /// Number of bytes, starting from the beginning of the file, that are
+    /// covered by the signature.
+    pub fn hashed_size(&self) -> u64 {
+        self.hashed_size
+    }
+
+    /// Create a new hash context using the signature's digest algorithm.
+    pub fn new_context(&self) -> Context {
+        Context::new(self.digest_algorithm)
+    }
+
+    /// Verify the signature against the digest of the first
+    /// [`Self::hashed_size()`] bytes of the file.
+    pub fn verify(&self, digest: &Digest) -> Result<()> {
+        let is_sha256 = self.digest_algorithm == &ring::digest::SHA256;
+
+        match self.signature_algorithm {
+            SignatureAlgorithm::RsaPkcs1v15 => {
+                let scheme = if is_sha256 {
+                    Pkcs1v15Sign::new::<Sha256>()
+                } else {
+                    Pkcs1v15Sign::new::<Sha1>()
+                };
+
+                self.public_key
+                    .verify(scheme, digest.as_ref(), &self.signature)?;
+            }
+            SignatureAlgorithm::RsaPss { salt_len } => {
+                let scheme = if is_sha256 {
+                    Pss::new_with_salt::<Sha256>(salt_len)
+                } else {
+                    Pss::new_with_salt::<Sha1>(salt_len)
+                };
+
+                self.public_key
+                    .verify(scheme, digest.as_ref(), &self.signature)?;
+            }
+        }
+
+        Ok(())
     }
+}
+
+/// Verify an OTA zip against its embedded certificates. This function makes no
+/// assertion about whether the certificate is actually trusted. Returns the
+/// embedded certificate. See [`OtaSignature`] for verifying the signature at
+/// the same time as reading other parts of the file.
+pub fn verify_ota(
+    mut reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Certificate> {
+    let signature = OtaSignature::from_zip(&mut reader)?;
 
     // Manually hash the parts of the file covered by the signature.
     reader.seek(SeekFrom::Start(0))?;
 
-    // We support SHA1 for verification only.
-    let (algorithm, scheme) = if signer.digest_alg.oid == rfc5912::ID_SHA_256 {
-        (&ring::digest::SHA256, Pkcs1v15Sign::new::<Sha256>())
-    } else {
-        (
-            &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
-            Pkcs1v15Sign::new::<Sha1>(),
-        )
-    };
+    let mut hashing_reader = HashingReader::new(reader, signature.new_context());
 
-    let mut hashing_reader = HashingReader::new(reader, Context::new(algorithm));
-
-    stream::copy_n(&mut hashing_reader, io::sink(), hashed_size, cancel_signal)?;
+    stream::copy_n(
+        &mut hashing_reader,
+        io::sink(),
+        signature.hashed_size(),
+        cancel_signal,
+    )?;
 
     let (_, context) = hashing_reader.finish();
     let digest = context.finish();
 
     // Verify the signature against the public key.
-    public_key.verify(scheme, digest.as_ref(), signer.signature.as_bytes())?;
+    signature.verify(&digest)?;
+
+    Ok(signature.cert)
+}
+
+/// Read the protobuf-encoded OTA metadata from an OTA zip.
+pub fn read_zip_metadata(zip: &mut ZipArchive<impl Read + Seek>) -> Result<OtaMetadata> {
+    let mut entry = zip.by_name(PATH_METADATA_PB)?;
+    let mut buf = vec![0u8; entry.size() as usize];
+    entry.read_exact(&mut buf)?;
 
-    Ok(cert.clone())
+    Ok(util::read_protobuf::<OtaMetadata>(&buf)?)
 }
 
 /// Get and parse the protobuf-encoded OTA metadata, the PEM-encoded otacert,
@@ -519,12 +1168,7 @@ pub fn parse_zip_ota_info(
 ) -> Result<(OtaMetadata, Certificate, PayloadHeader, String)> {
     let mut zip = ZipArchive::new(reader)?;
 
-    let metadata = {
-        let mut entry = zip.by_name(PATH_METADATA_PB)?;
-        let mut buf = vec![0u8; entry.size() as usize];
-        entry.read_exact(&mut buf)?;
-        util::read_protobuf::<OtaMetadata>(&buf)?
-    };
+    let metadata = read_zip_metadata(&mut zip)?;
 
     let certificate = {
         let entry = zip.by_name(PATH_OTACERT)?;
@@ -546,14 +1190,454 @@ pub fn parse_zip_ota_info(
     Ok((metadata, certificate, header, properties))
 }
 
+/// Find the AVB descriptor that refers to partition `name` in an OTA zip. If
+/// the partition has a vbmeta footer, the descriptor is taken from the footer's
+/// vbmeta structure. Otherwise, the vbmeta partitions are searched, starting
+/// with the root `vbmeta` partition. This finds the descriptors of partitions
+/// that are verified by a vbmeta partition directly, like those covered by
+/// `vbmeta_system`, and the chain partition descriptors that point to other
+/// partitions. Returns [`None`] if no descriptor refers to the partition.
+///
+/// Only the parts of the images that are needed are extracted, but this does
+/// not work for delta OTAs.
+pub fn descriptor_for_partition(
+    mut reader: impl Read + Seek,
+    name: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Option<Descriptor>> {
+    let (payload_offset, payload_size) = {
+        let mut zip = ZipArchive::new(&mut reader)?;
+        let entry = zip.by_name(PATH_PAYLOAD)?;
+        (entry.data_start(), entry.size())
+    };
+
+    let mut payload_reader = SectionReader::new(reader, payload_offset, payload_size)?;
+    let header = PayloadHeader::from_reader(&mut payload_reader)?;
+    let block_size = header.manifest.block_size;
+
+    let find_descriptor = |vbmeta: &[u8]| -> Result<Option<Descriptor>> {
+        let vbmeta_header = match avb::Header::from_reader(vbmeta) {
+            Ok(h) => h,
+            // Not every partition named vbmeta* is a vbmeta image.
+            Err(avb::Error::InvalidHeaderMagic(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let descriptor = vbmeta_header
+            .descriptors
+            .into_iter()
+            .find(|d| d.partition_name() == Some(name));
+
+        Ok(descriptor)
+    };
+
+    if let Some(partition) = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == name)
+    {
+        let image_size = payload::partition_size(partition, block_size)?;
+
+        if let Some(footer_offset) = image_size.checked_sub(Footer::SIZE as u64) {
+            let data = payload::extract_range(
+                &mut payload_reader,
+                &header,
+                name,
+                footer_offset,
+                Footer::SIZE as u64,
+                cancel_signal,
+            )?;
+
+            // Images without a footer fall back to searching vbmeta.
+            if let Ok(footer) = Footer::from_reader(data.as_slice()) {
+                if footer.vbmeta_size > avb::VBMETA_MAX_SIZE {
+                    return Err(Error::VbmetaTooLarge(name.to_owned(), footer.vbmeta_size));
+                }
+
+                let vbmeta = payload::extract_range(
+                    &mut payload_reader,
+                    &header,
+                    name,
+                    footer.vbmeta_offset,
+                    footer.vbmeta_size,
+                    cancel_signal,
+                )?;
+
+                if let Some(descriptor) = find_descriptor(&vbmeta)? {
+                    return Ok(Some(descriptor));
+                }
+            }
+        }
+    }
+
+    let mut vbmeta_partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .filter(|p| p.partition_name.starts_with("vbmeta"))
+        .collect::<Vec<_>>();
+    vbmeta_partitions.sort_by_key(|p| (p.partition_name != "vbmeta", &p.partition_name));
+
+    for partition in vbmeta_partitions {
+        // The header is at the start, so there's no need to read the padding.
+        let image_size = payload::partition_size(partition, block_size)?;
+        let vbmeta = payload::extract_range(
+            &mut payload_reader,
+            &header,
+            &partition.partition_name,
+            0,
+            image_size.min(avb::VBMETA_MAX_SIZE),
+            cancel_signal,
+        )?;
+
+        if let Some(descriptor) = find_descriptor(&vbmeta)? {
+            return Ok(Some(descriptor));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether an OTA can be installed on a device. See [`check_compatibility()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompatibilityResult {
+    Compatible,
+    /// The device is not in the OTA's list of supported devices.
+    UnsupportedDevice {
+        device: String,
+        supported: Vec<String>,
+    },
+    /// The OTA is an incremental OTA and the device is not running one of the
+    /// source builds. `current` is [`None`] if the device's build fingerprint
+    /// was not specified.
+    WrongSourceBuild {
+        current: Option<String>,
+        required: Vec<String>,
+    },
+}
+
+impl CompatibilityResult {
+    pub fn is_compatible(&self) -> bool {
+        *self == Self::Compatible
+    }
+}
+
+impl fmt::Display for CompatibilityResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compatible => write!(f, "OTA is compatible with the device"),
+            Self::UnsupportedDevice { device, supported } => {
+                if supported.is_empty() {
+                    write!(f, "OTA does not list any supported devices")
+                } else {
+                    write!(
+                        f,
+                        "OTA is for {}, but the device is {device:?}",
+                        supported.join(", "),
+                    )
+                }
+            }
+            Self::WrongSourceBuild { current, required } => {
+                write!(
+                    f,
+                    "Incremental OTA requires source build {}",
+                    required.join(" or "),
+                )?;
+
+                match current {
+                    Some(c) => write!(f, ", but the device is running {c}"),
+                    None => write!(f, ", but the device's build fingerprint is unknown"),
+                }
+            }
+        }
+    }
+}
+
+/// Check if the OTA metadata allows installing on `device` (`ro.product.device`)
+/// with the build fingerprint `current_fingerprint` (`ro.build.fingerprint`).
+/// This performs the same precondition checks as recovery. The fingerprint is
+/// only needed for incremental OTAs. See [`check_compatibility()`].
+pub fn check_metadata_compatibility(
+    metadata: &OtaMetadata,
+    device: &str,
+    current_fingerprint: Option<&str>,
+) -> CompatibilityResult {
+    let Some(precondition) = &metadata.precondition else {
+        return CompatibilityResult::UnsupportedDevice {
+            device: device.to_owned(),
+            supported: vec![],
+        };
+    };
+
+    if !precondition.device.iter().any(|d| d == device) {
+        return CompatibilityResult::UnsupportedDevice {
+            device: device.to_owned(),
+            supported: precondition.device.clone(),
+        };
+    }
+
+    // Full OTAs have no source build requirement.
+    if !precondition.build.is_empty()
+        && !current_fingerprint.map_or(false, |f| precondition.build.iter().any(|b| b == f))
+    {
+        return CompatibilityResult::WrongSourceBuild {
+            current: current_fingerprint.map(|f| f.to_owned()),
+            required: precondition.build.clone(),
+        };
+    }
+
+    CompatibilityResult::Compatible
+}
+
+/// Check if an OTA zip can be installed on a device based on the supported
+/// devices and source builds in its metadata. Flashing an OTA for a different
+/// device or applying an incremental OTA to the wrong build will fail at best
+/// and brick the device at worst. See [`check_metadata_compatibility()`].
+pub fn check_compatibility(
+    reader: impl Read + Seek,
+    device: &str,
+    current_fingerprint: Option<&str>,
+) -> Result<CompatibilityResult> {
+    let mut zip = ZipArchive::new(reader)?;
+    let metadata = read_zip_metadata(&mut zip)?;
+
+    Ok(check_metadata_compatibility(
+        &metadata,
+        device,
+        current_fingerprint,
+    ))
+}
+
+/// Version of the JSON schema produced by [`UpdateDescriptor::to_json()`]. This
+/// is incremented whenever a field is removed or changes meaning. Adding new
+/// fields does not change the version.
+pub const UPDATE_DESCRIPTOR_VERSION: u32 = 1;
+
+/// Information that an updater app needs to check for and stream an OTA hosted
+/// on a static file server. Everything is derived from the OTA zip itself, so
+/// the descriptor cannot drift from the file it describes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UpdateDescriptor {
+    /// Optional release channel, like `stable` or `beta`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Devices from the OTA's postcondition.
+    pub devices: Vec<String>,
+    /// Build fingerprint after installing the OTA.
+    pub fingerprint: String,
+    /// Build timestamp (`post-timestamp`) after installing the OTA.
+    pub post_timestamp: i64,
+    /// Expiry time of the OTA signing certificate as a Unix timestamp.
+    /// Recovery rejects the OTA after this time.
+    pub cert_not_after: u64,
+    /// Size of the OTA zip.
+    pub size: u64,
+    /// SHA-256 digest of the OTA zip.
+    #[serde(serialize_with = "serialize_hex")]
+    pub sha256: [u8; 32],
+    /// Download URL of the OTA zip.
+    pub url: String,
+    /// The `ota-streaming-property-files` value from the OTA metadata, without
+    /// the trailing padding. This lists the offsets and sizes of the entries
+    /// needed for update_engine to stream the OTA.
+    pub property_files: String,
+}
+
+impl UpdateDescriptor {
+    /// Build the descriptor for the OTA zip in `reader`, which can be
+    /// downloaded from `url`. The offsets in the property files are checked
+    /// against the actual zip entries, like [`verify_metadata()`] does, so that
+    /// a descriptor is never produced for an OTA that cannot be streamed.
+    pub fn from_zip(
+        mut reader: impl Read + Seek,
+        url: &str,
+        channel: Option<&str>,
+        cancel_signal: &Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let (metadata, cert, payload_metadata_size) = {
+            let mut zip = ZipArchive::new(&mut reader)?;
+            let metadata = read_zip_metadata(&mut zip)?;
+            let cert = crypto::read_pem_cert(zip.by_name(PATH_OTACERT)?)?;
+            let entry = zip.by_name(PATH_PAYLOAD)?;
+            (
+                metadata,
+                cert,
+                PayloadHeader::from_reader(entry)?.blob_offset,
+            )
+        };
+
+        verify_metadata(&mut reader, &metadata, payload_metadata_size)?;
+
+        let postcondition = metadata
+            .postcondition
+            .as_ref()
+            .ok_or_else(|| Error::MissingMetadataField("postcondition"))?;
+        let fingerprint = postcondition
+            .build
+            .first()
+            .ok_or_else(|| Error::MissingMetadataField("postcondition.build"))?;
+        let property_files = metadata
+            .property_files
+            .get(PF_STREAMING_NAME)
+            .ok_or_else(|| Error::MissingMetadataField(PF_STREAMING_NAME))?;
+
+        reader.rewind()?;
+        let context = Context::new(&ring::digest::SHA256);
+        let mut hashing_reader = HashingReader::new(reader, context);
+        let size = stream::copy(&mut hashing_reader, io::sink(), cancel_signal)?;
+        let (_, context) = hashing_reader.finish();
+
+        Ok(Self {
+            channel: channel.map(|c| c.to_owned()),
+            devices: postcondition.device.clone(),
+            fingerprint: fingerprint.clone(),
+            post_timestamp: postcondition.timestamp,
+            cert_not_after: crypto::cert_not_after(&cert),
+            size,
+            sha256: context.finish().as_ref().try_into().unwrap(),
+            url: url.to_owned(),
+            property_files: property_files.trim_end().to_owned(),
+        })
+    }
+
+    /// Serialize the descriptor as a JSON object, along with the
+    /// [`UPDATE_DESCRIPTOR_VERSION`].
+    pub fn to_json(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Versioned<'a> {
+            schema_version: u32,
+            #[serde(flatten)]
+            descriptor: &'a UpdateDescriptor,
+        }
+
+        let mut result = serde_json::to_string_pretty(&Versioned {
+            schema_version: UPDATE_DESCRIPTOR_VERSION,
+            descriptor: self,
+        })?;
+        result.push('\n');
+
+        Ok(result)
+    }
+}
+
+fn serialize_hex<S: Serializer>(
+    data: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(data))
+}
+
+/// Check that `payload_properties.txt` in an OTA zip matches `payload.bin`. This
+/// is what update_engine clients use to validate a streaming OTA before and
+/// while downloading the payload, so it must stay consistent after the payload
+/// is replaced. See [`payload::verify_properties()`].
+pub fn verify_zip_properties(
+    reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let mut zip = ZipArchive::new(reader)?;
+
+    let properties = match zip.by_name(PATH_PROPERTIES) {
+        Ok(mut entry) => {
+            let mut buf = String::new();
+            entry.read_to_string(&mut buf)?;
+            buf
+        }
+        Err(ZipError::FileNotFound) => return Err(Error::MissingZipEntry(PATH_PROPERTIES)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let entry = match zip.by_name(PATH_PAYLOAD) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Err(Error::MissingZipEntry(PATH_PAYLOAD)),
+        Err(e) => return Err(e.into()),
+    };
+
+    payload::verify_properties(entry, &properties, cancel_signal)?;
+
+    Ok(())
+}
+
+/// Check that a message can be stored in the archive comment of a signed zip.
+/// The message must not contain NUL bytes because the NUL terminator is what
+/// separates it from the signature. It also must not contain the EOCD magic
+/// because recovery rejects zips where the magic appears in the comment.
+pub fn validate_comment_message(message: &str) -> Result<()> {
+    if message.len() > COMMENT_MESSAGE_MAX_SIZE {
+        return Err(Error::CommentMessageTooLong(message.len()));
+    } else if message.contains('\0') {
+        return Err(Error::CommentMessageHasNul);
+    } else if memmem::find(message.as_bytes(), ZIP_EOCD_MAGIC).is_some() {
+        return Err(Error::CommentMessageHasEocdMagic);
+    }
+
+    Ok(())
+}
+
+/// Format a Unix timestamp as a `YYYY-MM-DD` date in UTC.
+fn format_date(timestamp: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = timestamp.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Build a short provenance message for the archive comment from the OTA's
+/// postcondition build fingerprint and timestamp. The build date is used
+/// instead of the current time so that the output remains reproducible.
+pub fn provenance_comment(metadata: &OtaMetadata) -> String {
+    let mut message = format!("{PROVENANCE_PREFIX}{}", env!("CARGO_PKG_VERSION"));
+
+    if let Some(p) = &metadata.postcondition {
+        if let Some(fingerprint) = p.build.first() {
+            message.push_str(" from build ");
+            message.push_str(fingerprint);
+        }
+        if p.timestamp > 0 {
+            message.push_str(" on ");
+            message.push_str(&format_date(p.timestamp));
+        }
+    }
+
+    message
+}
+
+/// Check if a zip archive comment was written by avbroot, either with the
+/// default message or with a provenance message from [`provenance_comment()`].
+/// Custom messages cannot be detected.
+pub fn is_avbroot_comment(comment: &[u8]) -> bool {
+    comment.starts_with(COMMENT_MESSAGE) || comment.starts_with(PROVENANCE_PREFIX.as_bytes())
+}
+
 /// A writer that produces a signapk-style signed zip file with a whole-file
 /// signature stored in the zip archive comment. The data will be left in an
 /// unusable state if [`Self::finish()`] is not called.
+///
+/// The digest is computed as the data passes through, with only the last 22
+/// bytes (the EOCD) held back, so the output is never seeked or read back.
+/// Combined with [`ZipWriter::new_streaming()`], which writes the central
+/// directory once all entry sizes are known, the whole zip is signed in a
+/// single pass.
+///
+/// The archive comment consists of an optional NUL-terminated message, the CMS
+/// signature, and a 6-byte footer pointing to the signature. This is the same
+/// layout as signapk's "signed by SignApk" comment. Only the footer is used to
+/// locate the signature, so the message is ignored during verification.
 pub struct SigningWriter<W: Write> {
     inner: HashingWriter<W>,
     // Android only supports non-zip64 EOCD.
     queue: [u8; 22],
     used: usize,
+    message: Vec<u8>,
+    padding: RsaPadding,
 }
 
 impl<W: Write> SigningWriter<W> {
@@ -562,9 +1646,35 @@ impl<W: Write> SigningWriter<W> {
             inner: HashingWriter::new(inner, Context::new(&ring::digest::SHA256)),
             queue: Default::default(),
             used: 0,
+            message: COMMENT_MESSAGE.to_vec(),
+            padding: RsaPadding::Pkcs1v15,
         }
     }
 
+    /// Set the RSA padding scheme for the whole-file signature. The default is
+    /// PKCS#1 v1.5, which is the only scheme that AOSP recovery supports.
+    pub fn set_padding(&mut self, padding: RsaPadding) -> Result<()> {
+        SignatureFormat::OtaZip.check_padding(padding)?;
+        self.padding = padding;
+
+        Ok(())
+    }
+
+    /// Replace the default "signed by avbroot" message at the beginning of the
+    /// archive comment. If the message is empty, the archive comment will only
+    /// contain the signature and footer.
+    pub fn set_comment_message(&mut self, message: &str) -> Result<()> {
+        validate_comment_message(message)?;
+
+        self.message.clear();
+        if !message.is_empty() {
+            self.message.extend(message.as_bytes());
+            self.message.push(b'\0');
+        }
+
+        Ok(())
+    }
+
     pub fn finish(mut self, key: &RsaPrivateKey, cert: &Certificate) -> Result<W> {
         if self.used < self.queue.len() {
             return Err(
@@ -586,40 +1696,65 @@ impl<W: Write> SigningWriter<W> {
         let (mut raw_writer, context) = self.inner.finish();
         let digest = context.finish();
 
-        let cms_signature = crypto::cms_sign_external(key, cert, digest.as_ref())?;
-        let cms_signature_der = cms_signature.to_der()?;
+        let cms_signature = crypto::cms_sign_external(key, cert, digest.as_ref(), self.padding)?;
 
-        let mut comment = COMMENT_MESSAGE.to_vec();
-        comment.extend(&cms_signature_der);
+        // Make it obvious that the signature is not real. The message is not
+        // covered by the signature, so this does not change anything else.
+        let message = if crypto::is_unsafe_no_sign() {
+            UNSIGNED_COMMENT_MESSAGE.to_vec()
+        } else {
+            self.message
+        };
 
-        let comment_size = comment.len() + 6;
+        write_signature_comment(&mut raw_writer, message, &cms_signature)?;
+
+        Ok(raw_writer)
+    }
+}
 
-        // Absolute value of the offset of the signature from the end of the
-        // archive comment.
-        comment.extend((cms_signature_der.len() as u16 + 6).to_le_bytes());
+/// Write the archive comment size field, which must immediately follow the
+/// rest of the EOCD, and the archive comment containing `message` and the
+/// signature.
+fn write_signature_comment(
+    mut writer: impl Write,
+    message: Vec<u8>,
+    cms_signature: &ContentInfo,
+) -> Result<()> {
+    let cms_signature_der = cms_signature.to_der()?;
 
-        // Magic value.
-        comment.extend(b"\xff\xff");
+    let mut comment = message;
+    comment.extend(&cms_signature_der);
 
-        // EOCD archive comment size.
-        comment.extend(((comment_size) as u16).to_le_bytes());
+    let comment_size = comment.len() + 6;
+    if comment_size > usize::from(u16::MAX) {
+        return Err(Error::ArchiveCommentTooLong(comment_size));
+    }
 
-        if let Some(o) = memmem::find(&comment, ZIP_EOCD_MAGIC) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Archive comment contains EOCD magic at offset {o}"),
-            )
-            .into());
-        }
+    // Absolute value of the offset of the signature from the end of the
+    // archive comment.
+    comment.extend((cms_signature_der.len() as u16 + 6).to_le_bytes());
 
-        // Write EOCD comment size field, which was removed before.
-        raw_writer.write_all(&((comment_size) as u16).to_le_bytes())?;
+    // Magic value.
+    comment.extend(b"\xff\xff");
 
-        // Finally, write the comment.
-        raw_writer.write_all(&comment)?;
+    // EOCD archive comment size.
+    comment.extend(((comment_size) as u16).to_le_bytes());
 
-        Ok(raw_writer)
+    if let Some(o) = memmem::find(&comment, ZIP_EOCD_MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Archive comment contains EOCD magic at offset {o}"),
+        )
+        .into());
     }
+
+    // Write the EOCD comment size field, which the caller left out.
+    writer.write_all(&((comment_size) as u16).to_le_bytes())?;
+
+    // Finally, write the comment.
+    writer.write_all(&comment)?;
+
+    Ok(())
 }
 
 impl<W: Write> Write for SigningWriter<W> {
@@ -654,3 +1789,245 @@ impl<W: Write> Write for SigningWriter<W> {
         self.inner.flush()
     }
 }
+
+/// Find the part of an OTA zip that is covered by the whole-file signature and
+/// hash it with SHA-256. If the zip is already signed, the existing signature
+/// is ignored. Otherwise, the archive comment must be empty.
+fn hash_signed_region(
+    mut reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<(Digest, u64)> {
+    let hashed_size = match parse_ota_sig(&mut reader) {
+        Ok((_, hashed_size)) => hashed_size,
+        Err(Error::OtaMagicNotFound) => {
+            let file_size = reader.seek(SeekFrom::End(0))?;
+            if file_size < ZIP_EOCD_SIZE {
+                return Err(Error::ZipTooSmall);
+            }
+
+            let mut eocd = [0u8; ZIP_EOCD_SIZE as usize];
+            reader.seek(SeekFrom::Start(file_size - ZIP_EOCD_SIZE))?;
+            reader.read_exact(&mut eocd)?;
+
+            if &eocd[..4] != ZIP_EOCD_MAGIC || eocd[20..] != [0, 0] {
+                return Err(Error::EocdMagicNotFound);
+            }
+
+            // Everything except the archive comment size field.
+            file_size - 2
+        }
+        Err(e) => return Err(e),
+    };
+
+    reader.rewind()?;
+
+    let mut hashing_reader = HashingReader::new(reader, Context::new(&ring::digest::SHA256));
+    stream::copy_n(&mut hashing_reader, io::sink(), hashed_size, cancel_signal)?;
+
+    let (_, context) = hashing_reader.finish();
+
+    Ok((context.finish(), hashed_size))
+}
+
+/// The digest that must be signed to produce the whole-file signature of an
+/// OTA zip. See [`prepare_signing()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigningRequest {
+    /// SHA-256 digest of the signed part of the file.
+    pub digest: Vec<u8>,
+    /// Number of bytes, starting from the beginning of the file, that are
+    /// covered by the signature.
+    pub hashed_size: u64,
+}
+
+/// A whole-file signature that was produced outside of avbroot for a
+/// [`SigningRequest`].
+#[derive(Clone, Debug)]
+pub struct ExternalSignature {
+    /// Raw RSA signature of [`SigningRequest::digest`].
+    pub signature: Vec<u8>,
+    /// Padding scheme that was used for the signature.
+    pub padding: RsaPadding,
+    /// Certificate for the signing key, which is embedded in the signature.
+    pub cert: Certificate,
+}
+
+/// Compute the digest for signing an OTA zip on a different machine, like one
+/// that holds the private key, without needing access to the key here. The
+/// zip can be unsigned, with an empty archive comment, or already signed, in
+/// which case it is re-signed. The signature is inserted with
+/// [`finalize_signing()`].
+///
+/// Only the whole-file signature is handled. The signatures inside the payload
+/// are covered by the whole-file signature, so they must already be final.
+pub fn prepare_signing(
+    reader: impl Read + Seek,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<SigningRequest> {
+    let (digest, hashed_size) = hash_signed_region(reader, cancel_signal)?;
+
+    Ok(SigningRequest {
+        digest: digest.as_ref().to_vec(),
+        hashed_size,
+    })
+}
+
+/// Write a copy of the OTA zip from `reader` to `writer` with `signature` as
+/// the whole-file signature. The zip must be unchanged since
+/// [`prepare_signing()`] was called. The signature is verified against the
+/// digest, which is computed again, before anything is written. The archive
+/// comment uses the default "signed by avbroot" message.
+pub fn finalize_signing(
+    mut reader: impl Read + Seek,
+    mut writer: impl Write,
+    signature: &ExternalSignature,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let (digest, hashed_size) = hash_signed_region(&mut reader, cancel_signal)?;
+
+    let ota_signature = OtaSignature {
+        cert: signature.cert.clone(),
+        public_key: crypto::get_public_key(&signature.cert)?,
+        digest_algorithm: &ring::digest::SHA256,
+        signature_algorithm: match signature.padding {
+            RsaPadding::Pkcs1v15 => SignatureAlgorithm::RsaPkcs1v15,
+            RsaPadding::Pss => SignatureAlgorithm::RsaPss {
+                salt_len: digest.as_ref().len(),
+            },
+        },
+        signature: signature.signature.clone(),
+        hashed_size,
+    };
+    ota_signature.verify(&digest)?;
+
+    let cms_signature = crypto::cms_from_signature(
+        &signature.cert,
+        signature.signature.clone(),
+        signature.padding,
+    )?;
+
+    reader.rewind()?;
+    stream::copy_n(&mut reader, &mut writer, hashed_size, cancel_signal)?;
+
+    write_signature_comment(&mut writer, COMMENT_MESSAGE.to_vec(), &cms_signature)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Inputs for [`estimate_output_size()`].
+#[derive(Clone, Copy)]
+pub struct OutputSizeInputs<'a> {
+    /// Header of the original payload. Partitions without a replacement image
+    /// keep their original install operations.
+    pub header: &'a PayloadHeader,
+    /// New images for the partitions that are replaced, by partition name.
+    pub images: &'a [(&'a str, &'a [u8])],
+    /// Names and sizes of all other entries in the output zip. The OTA
+    /// metadata files are regenerated when patching, but the sizes from the
+    /// original OTA are close enough.
+    pub entries: &'a [(&'a str, u64)],
+}
+
+/// Signing options for [`estimate_output_size()`].
+#[derive(Clone, Copy)]
+pub struct OutputSizeOptions<'a> {
+    /// Key for signing the payload.
+    pub key_payload: &'a RsaPrivateKey,
+    /// Key for the whole-file signature.
+    pub key_ota: &'a RsaPrivateKey,
+    /// Certificate embedded in the whole-file signature.
+    pub cert_ota: &'a Certificate,
+    /// Padding scheme for the whole-file signature.
+    pub padding: RsaPadding,
+    /// Archive comment message, as passed to
+    /// [`SigningWriter::set_comment_message()`]. If this is [`None`], the
+    /// default message is used.
+    pub comment_message: Option<&'a str>,
+}
+
+/// Estimate the size of a patched OTA zip without writing it. This is meant for
+/// capacity checks before starting a long patching operation.
+///
+/// The result is only an estimate. The payload metadata and the payload and
+/// whole-file signature sizes are exact, but the compressed size of each
+/// replacement image is extrapolated from samples with
+/// [`compression::estimate_size()`]. The zip overhead assumes that the output
+/// is written with [`ZipWriter::new_streaming()`] and that every entry is
+/// stored uncompressed. Entries copied with [`copy_entry_raw()`] should be
+/// listed with their compressed size.
+pub fn estimate_output_size(inputs: &OutputSizeInputs, options: &OutputSizeOptions) -> Result<u64> {
+    let mut header = inputs.header.clone();
+    let block_size = u64::from(header.manifest.block_size);
+
+    for (name, data) in inputs.images {
+        let partition = header
+            .manifest
+            .partitions
+            .iter_mut()
+            .find(|p| p.partition_name == *name)
+            .ok_or_else(|| Error::PartitionNotFound((*name).to_owned()))?;
+        let compressed_size = compression::estimate_size(data, CompressedFormat::Xz)?;
+
+        // Same layout as what payload::CompressedPartitionWriter produces. The
+        // digests only need to have the right size.
+        partition.old_partition_info = None;
+        partition.new_partition_info = Some(PartitionInfo {
+            size: Some(data.len() as u64),
+            hash: Some(vec![0; 32]),
+        });
+        partition.operations = vec![InstallOperation {
+            type_pb: Type::REPLACE_XZ,
+            data_length: Some(compressed_size),
+            dst_extents: vec![Extent {
+                start_block: Some(0),
+                num_blocks: Some(data.len() as u64 / block_size),
+            }],
+            data_sha256_hash: Some(vec![0; 32]),
+            ..Default::default()
+        }];
+    }
+
+    let payload_size = payload::payload_size(&header, options.key_payload)?;
+
+    let mut size = 0;
+
+    for (name, entry_size) in inputs
+        .entries
+        .iter()
+        .copied()
+        .chain(iter::once((PATH_PAYLOAD, payload_size)))
+    {
+        let name_size = name.len() as u64;
+
+        size += ZIP_LOCAL_HEADER_SIZE + ZIP_CENTRAL_HEADER_SIZE + 2 * name_size + entry_size;
+
+        if entry_size >= 0xffffffff {
+            size += ZIP64_LOCAL_EXTRA_SIZE + ZIP64_CENTRAL_EXTRA_SIZE + ZIP64_DATA_DESCRIPTOR_SIZE;
+        } else {
+            size += ZIP_DATA_DESCRIPTOR_SIZE;
+        }
+    }
+
+    if size >= 0xffffffff {
+        size += ZIP64_EOCD_SIZE;
+    }
+
+    // The whole-file signature has the same size for every digest.
+    let digest = ring::digest::digest(&ring::digest::SHA256, b"");
+    let cms_signature = crypto::cms_sign_external(
+        options.key_ota,
+        options.cert_ota,
+        digest.as_ref(),
+        options.padding,
+    )?;
+    let message_size = match options.comment_message {
+        Some("") => 0,
+        Some(m) => m.len() + 1,
+        None => COMMENT_MESSAGE.len(),
+    };
+
+    size += ZIP_EOCD_SIZE + message_size as u64 + cms_signature.to_der()?.len() as u64 + 6;
+
+    Ok(size)
+}
@@ -3,7 +3,13 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::{self, Read, Seek, Write};
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use num_traits::PrimInt;
 
@@ -38,7 +44,9 @@ pub fn read_discard(mut reader: impl Read + Seek, page_size: u64) -> io::Result<
 }
 
 /// Write zeros until the next multiple of the page size. [`Seek`] is only used
-/// for querying the file position.
+/// for querying the file position. Nothing is written if the position is
+/// already aligned, so an empty section that follows a padded section does not
+/// take up any space.
 pub fn write_zeros(mut writer: impl Write + Seek, page_size: u64) -> io::Result<u64> {
     let pos = writer.stream_position()?;
     let padding = calc(pos, page_size);
@@ -47,3 +55,33 @@ pub fn write_zeros(mut writer: impl Write + Seek, page_size: u64) -> io::Result<
 
     Ok(padding)
 }
+
+/// Find the end of the data in `reader` when ignoring trailing zeros. This
+/// reads backwards from the end, so it is fast for images that are mostly data,
+/// but needs to read all of the padding for images that are mostly zeros. The
+/// reader's position is not restored.
+pub fn data_end(mut reader: impl Read + Seek, cancel_signal: &Arc<AtomicBool>) -> io::Result<u64> {
+    let mut buf = [0u8; 16384];
+    let mut end = reader.seek(SeekFrom::End(0))?;
+
+    while end > 0 {
+        if cancel_signal.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Received cancel signal",
+            ));
+        }
+
+        let n = end.min(buf.len() as u64) as usize;
+        reader.seek(SeekFrom::Start(end - n as u64))?;
+        reader.read_exact(&mut buf[..n])?;
+
+        if let Some(i) = buf[..n].iter().rposition(|&b| b != 0) {
+            return Ok(end - n as u64 + i as u64 + 1);
+        }
+
+        end -= n as u64;
+    }
+
+    Ok(0)
+}
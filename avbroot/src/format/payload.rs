@@ -4,23 +4,29 @@
  */
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     io::{self, Cursor, Read, Seek, SeekFrom, Write},
-    sync::{atomic::AtomicBool, Arc},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
 };
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use byteorder::{BigEndian, ReadBytesExt};
+#[cfg(feature = "native")]
 use bzip2::write::BzDecoder;
-use num_traits::ToPrimitive;
 use quick_protobuf::MessageWrite;
-use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use ring::digest::{Context, Digest};
+use ring::digest::{Algorithm, Context, Digest};
 use rsa::{traits::PublicKeyParts, Pkcs1v15Sign, RsaPrivateKey};
+use serde::Serialize;
 use sha2::Sha256;
 use thiserror::Error;
 use x509_cert::Certificate;
+#[cfg(feature = "native")]
 use xz2::{
     stream::{Check, Stream},
     write::XzDecoder,
@@ -29,32 +35,137 @@ use xz2::{
 
 use crate::{
     crypto,
+    format::protowire::{self, Schema, UnknownFields},
     protobuf::chromeos_update_engine::{
         mod_InstallOperation, mod_Signatures::Signature, DeltaArchiveManifest, Extent,
         InstallOperation, PartitionInfo, PartitionUpdate, Signatures,
     },
     stream::{
-        self, CountingReader, CountingWriter, FromReader, HashingWriter, ReadDiscardExt, ReadSeek,
-        SharedCursor, WriteSeek,
+        self, CountingReader, FromReader, HashingReader, ReadDiscardExt, ReadSeek, SharedCursor,
+        WriteSeek,
+    },
+    util::{
+        self,
+        par::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
     },
-    util,
 };
 
+#[cfg(feature = "native")]
+use crate::stream::{CountingWriter, HashingWriter};
+
 const OTA_MAGIC: &[u8; 4] = b"CrAU";
 const OTA_HEADER_SIZE: usize = OTA_MAGIC.len() + 8 + 8 + 4;
 
+/// Payload file format versions that can be parsed.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u64> = 2..=2;
+
+/// Field number of [`DeltaArchiveManifest::partitions`].
+const FIELD_PARTITIONS: u32 = 13;
+
+// Fields of the manifest and its nested messages that are known to the
+// generated protobuf code. Deprecated fields are not generated. These must be
+// kept in sync with `update_metadata.proto`.
+static EXTENT_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None)],
+};
+static SIGNATURE_SCHEMA: Schema = Schema {
+    fields: &[(2, None), (3, None)],
+};
+static PARTITION_INFO_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None)],
+};
+static INSTALL_OPERATION_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, None),
+        (2, None),
+        (3, None),
+        (4, Some(&EXTENT_SCHEMA)),
+        (5, None),
+        (6, Some(&EXTENT_SCHEMA)),
+        (7, None),
+        (8, None),
+        (9, None),
+    ],
+};
+static COW_MERGE_OPERATION_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, None),
+        (2, Some(&EXTENT_SCHEMA)),
+        (3, Some(&EXTENT_SCHEMA)),
+        (4, None),
+    ],
+};
+static PARTITION_UPDATE_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, None),
+        (2, None),
+        (3, None),
+        (4, None),
+        (5, Some(&SIGNATURE_SCHEMA)),
+        (6, Some(&PARTITION_INFO_SCHEMA)),
+        (7, Some(&PARTITION_INFO_SCHEMA)),
+        (8, Some(&INSTALL_OPERATION_SCHEMA)),
+        (9, None),
+        (10, Some(&EXTENT_SCHEMA)),
+        (11, Some(&EXTENT_SCHEMA)),
+        (12, None),
+        (13, None),
+        (14, Some(&EXTENT_SCHEMA)),
+        (15, Some(&EXTENT_SCHEMA)),
+        (16, None),
+        (17, None),
+        (18, Some(&COW_MERGE_OPERATION_SCHEMA)),
+        (19, None),
+    ],
+};
+static DYNAMIC_PARTITION_GROUP_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None), (3, None)],
+};
+static VABC_FEATURE_SET_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None)],
+};
+static DYNAMIC_PARTITION_METADATA_SCHEMA: Schema = Schema {
+    fields: &[
+        (1, Some(&DYNAMIC_PARTITION_GROUP_SCHEMA)),
+        (2, None),
+        (3, None),
+        (4, None),
+        (5, None),
+        (6, Some(&VABC_FEATURE_SET_SCHEMA)),
+    ],
+};
+static APEX_INFO_SCHEMA: Schema = Schema {
+    fields: &[(1, None), (2, None), (3, None), (4, None)],
+};
+static MANIFEST_SCHEMA: Schema = Schema {
+    fields: &[
+        (3, None),
+        (4, None),
+        (5, None),
+        (12, None),
+        (FIELD_PARTITIONS, Some(&PARTITION_UPDATE_SCHEMA)),
+        (14, None),
+        (15, Some(&DYNAMIC_PARTITION_METADATA_SCHEMA)),
+        (16, None),
+        (17, Some(&APEX_INFO_SCHEMA)),
+        (18, None),
+    ],
+};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unknown magic: {0:?}")]
     UnknownMagic([u8; 4]),
-    #[error("Unsupported payload version: {0}")]
-    UnsupportedVersion(u64),
+    #[error("Unsupported payload version: {version} (supported: {min} to {max})")]
+    UnsupportedVersion { version: u64, min: u64, max: u64 },
     #[error("Payload contains no signatures")]
     NoSignatures,
     #[error("Blob offset should be {0}, but is {1}")]
     InvalidBlobOffset(u64, u64),
     #[error("Payload signatures offset should be {0}, but is {1}")]
     InvalidPayloadSignaturesOffset(u64, u64),
+    #[error("Operation data at blob offset {0} overlaps previous operation")]
+    OverlappingOperationData(u64),
     #[error("Invalid payload properties line: {0:?}")]
     InvalidPropertiesLine(String),
     #[error("Duplicate payload property: {0:?}")]
@@ -65,20 +176,42 @@ pub enum Error {
     UnsupportedOperation(mod_InstallOperation::Type),
     #[error("Expected sha256 {0:?}, but have {1:?}")]
     MismatchedDigest(Option<String>, String),
+    #[error("{partition} operation #{op_index}: Expected sha256 {expected:?}, but have {actual}")]
+    OperationHashMismatch {
+        partition: String,
+        op_index: usize,
+        expected: Option<String>,
+        actual: String,
+    },
     #[error("Size of {0} ({1}) is not aligned to the block size ({2})")]
     InvalidPartitionSize(String, u64, u32),
     #[error("Partition not found in payload: {0}")]
     MissingPartition(String),
+    #[error("Range {1}..{2} is out of bounds for partition {0:?}")]
+    RangeOutOfBounds(String, u64, u64),
+    #[error("Expected {0} image to be {1} bytes, but have {2} bytes")]
+    ImageSizeMismatch(String, u64, u64),
+    #[error("{partition} operation #{op_index}: Block {block} does not match the image")]
+    ImageMismatch {
+        partition: String,
+        op_index: usize,
+        block: u64,
+    },
     #[error("Partitions not found in payload: {0:?}")]
     MissingPartitions(HashSet<String>),
     #[error("{0:?} field is missing")]
     MissingField(&'static str),
+    #[error("Partition {0:?} has unsupported field: {1:?}")]
+    UnsupportedPartitionField(String, &'static str),
     #[error("{0:?} field exceeds integer bounds")]
     IntegerTooLarge(&'static str),
     #[error("Crypto error")]
     Crypto(#[from] crypto::Error),
     #[error("Protobuf error")]
     Protobuf(#[from] quick_protobuf::Error),
+    #[error("Protobuf wire format error")]
+    ProtoWire(#[from] protowire::Error),
+    #[cfg(feature = "native")]
     #[error("XZ stream error")]
     XzStream(#[from] xz2::stream::Error),
     #[error("RSA error")]
@@ -89,21 +222,326 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// The fixed-size fields at the beginning of a payload file. Unlike
+/// [`PayloadHeader`], these can be read regardless of the payload version,
+/// which is useful for reporting what an unsupported payload contains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawPayloadHeader {
+    pub version: u64,
+    pub manifest_size: u64,
+    pub metadata_signature_size: u32,
+}
+
+impl RawPayloadHeader {
+    /// Return an error if [`Self::version`] is not in [`SUPPORTED_VERSIONS`].
+    pub fn check_version(&self) -> Result<()> {
+        if !SUPPORTED_VERSIONS.contains(&self.version) {
+            return Err(Error::UnsupportedVersion {
+                version: self.version,
+                min: *SUPPORTED_VERSIONS.start(),
+                max: *SUPPORTED_VERSIONS.end(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Size of the header and manifest, excluding the metadata signature.
+    pub fn metadata_size(&self) -> Result<u64> {
+        self.manifest_size
+            .checked_add(OTA_HEADER_SIZE as u64)
+            .ok_or_else(|| Error::IntegerTooLarge("manifest_size"))
+    }
+}
+
+impl<R: Read> FromReader<R> for RawPayloadHeader {
+    type Error = Error;
+
+    /// Read the magic and the fixed-size fields. The version is not checked.
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != *OTA_MAGIC {
+            return Err(Error::UnknownMagic(magic));
+        }
+
+        let version = reader.read_u64::<BigEndian>()?;
+        let manifest_size = reader.read_u64::<BigEndian>()?;
+        let metadata_signature_size = reader.read_u32::<BigEndian>()?;
+
+        Ok(Self {
+            version,
+            manifest_size,
+            metadata_signature_size,
+        })
+    }
+}
+
+/// Read exactly `size` bytes. The buffer grows as the data is read, so a
+/// corrupted size field results in an EOF error instead of a huge allocation.
+fn read_vec_exact(reader: impl Read, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(size).read_to_end(&mut data)?;
+
+    if (data.len() as u64) != size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    Ok(data)
+}
+
+/// Postinstall settings of a partition. These mirror the fields of
+/// [`PartitionUpdate`] so that unset fields stay unset when the manifest is
+/// written again. update_engine only uses `postinstall_path` and
+/// `filesystem_type` if `run_postinstall` is true.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PostinstallConfig {
+    pub run_postinstall: Option<bool>,
+    /// Path of the program relative to the root of the partition's filesystem.
+    /// update_engine defaults to `postinst`.
+    pub postinstall_path: Option<String>,
+    /// Filesystem type for mount(2). update_engine tries a fixed list of types
+    /// if this is unset.
+    pub filesystem_type: Option<String>,
+    /// Whether a failure of the postinstall program is ignored.
+    pub postinstall_optional: Option<bool>,
+}
+
+impl PostinstallConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.run_postinstall == Some(true)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PayloadHeader {
     pub version: u64,
     pub manifest: DeltaArchiveManifest,
     pub metadata_signature_size: u32,
     pub blob_offset: u64,
+    /// Manifest fields that are unknown to avbroot. These are preserved when
+    /// the manifest is written again. The unknown fields of each partition are
+    /// in [`Self::partition_unknown_fields`] instead.
+    pub unknown_fields: UnknownFields,
+    /// Unknown fields of each partition in [`DeltaArchiveManifest::partitions`],
+    /// keyed by the partition name. They stay with the right partition even if
+    /// partitions are removed or reordered.
+    pub partition_unknown_fields: BTreeMap<String, UnknownFields>,
 }
 
 impl PayloadHeader {
+    /// Whether every partition can be installed without reading the existing
+    /// data on the device. Some OEMs' partial OTAs list the old partition info,
+    /// but only use operations that write full data.
     pub fn is_full_ota(&self) -> bool {
         self.manifest
             .partitions
             .iter()
-            .all(|p| p.old_partition_info.is_none())
+            .all(|p| p.old_partition_info.is_none() || p.operations.iter().all(is_full_operation))
     }
+
+    /// Whether the payload only updates a subset of the device's partitions.
+    pub fn is_partial_update(&self) -> bool {
+        self.manifest.partial_update == Some(true)
+    }
+
+    /// Discard the unknown fields that describe the data of partition `name`.
+    /// This must be called after the partition's data is replaced because they
+    /// would no longer match the new install operations. Unknown fields of the
+    /// partition itself are kept.
+    pub fn clear_unknown_data_fields(&mut self, name: &str) {
+        if let Some(unknown) = self.partition_unknown_fields.get_mut(name) {
+            for number in [6, 7, 8, 18] {
+                unknown.remove_nested(number);
+            }
+        }
+    }
+
+    /// Combine [`Self::unknown_fields`] and [`Self::partition_unknown_fields`]
+    /// according to the current order of the partitions.
+    fn all_unknown_fields(&self) -> UnknownFields {
+        let mut unknown_fields = self.unknown_fields.clone();
+
+        for (index, partition) in self.manifest.partitions.iter().enumerate() {
+            if let Some(unknown) = self.partition_unknown_fields.get(&partition.partition_name) {
+                unknown_fields.insert_nested(FIELD_PARTITIONS, index, unknown.clone());
+            }
+        }
+
+        unknown_fields
+    }
+
+    /// Get the path of each unknown manifest field. See
+    /// [`UnknownFields::paths()`].
+    pub fn unknown_field_paths(&self) -> Vec<String> {
+        self.all_unknown_fields().paths()
+    }
+
+    /// Remove partition `name` from the manifest and turn the payload into a
+    /// partial update, which leaves the partition on the device untouched. The
+    /// partition's unknown fields and its entry in the dynamic partition
+    /// groups are removed too. Partial updates rely on the per-partition
+    /// version for downgrade detection, so the remaining partitions without a
+    /// version get the payload's `max_timestamp`, if any.
+    ///
+    /// The install operations are not modified. Their data offsets are
+    /// reassigned by [`PayloadWriter`].
+    pub fn remove_partition(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .manifest
+            .partitions
+            .iter()
+            .position(|p| p.partition_name == name)
+            .ok_or_else(|| Error::MissingPartition(name.to_owned()))?;
+
+        self.manifest.partitions.remove(index);
+        self.partition_unknown_fields.remove(name);
+
+        if let Some(dpm) = &mut self.manifest.dynamic_partition_metadata {
+            for group in &mut dpm.groups {
+                group.partition_names.retain(|n| n != name);
+            }
+        }
+
+        self.manifest.partial_update = Some(true);
+
+        if let Some(timestamp) = self.manifest.max_timestamp {
+            for partition in &mut self.manifest.partitions {
+                if partition.version.is_none() {
+                    partition.version = Some(timestamp.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the postinstall settings of partition `name`.
+    pub fn postinstall(&self, name: &str) -> Result<PostinstallConfig> {
+        let partition = self
+            .manifest
+            .partitions
+            .iter()
+            .find(|p| p.partition_name == name)
+            .ok_or_else(|| Error::MissingPartition(name.to_owned()))?;
+
+        Ok(PostinstallConfig {
+            run_postinstall: partition.run_postinstall,
+            postinstall_path: partition.postinstall_path.clone(),
+            filesystem_type: partition.filesystem_type.clone(),
+            postinstall_optional: partition.postinstall_optional,
+        })
+    }
+
+    /// Replace the postinstall settings of partition `name`. The new manifest
+    /// size and signature are computed by [`PayloadWriter`].
+    pub fn set_postinstall(&mut self, name: &str, config: PostinstallConfig) -> Result<()> {
+        let partition = self
+            .manifest
+            .partitions
+            .iter_mut()
+            .find(|p| p.partition_name == name)
+            .ok_or_else(|| Error::MissingPartition(name.to_owned()))?;
+
+        partition.run_postinstall = config.run_postinstall;
+        partition.postinstall_path = config.postinstall_path;
+        partition.filesystem_type = config.filesystem_type;
+        partition.postinstall_optional = config.postinstall_optional;
+
+        Ok(())
+    }
+
+    /// Serialize [`Self::manifest`], including the unknown fields that were
+    /// read along with it. For an unmodified header, this produces the same
+    /// bytes as the manifest in the original payload.
+    pub fn manifest_raw(&self) -> Result<Vec<u8>> {
+        let manifest_raw = self
+            .all_unknown_fields()
+            .merge(&util::write_protobuf(&self.manifest)?, &MANIFEST_SCHEMA)?;
+
+        Ok(manifest_raw)
+    }
+}
+
+/// Whether a payload's operations need the existing partition data on the
+/// device. See [`payload_kind()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadKind {
+    /// Every operation writes full data.
+    Full,
+    /// Every partition has at least one operation that reads from the source
+    /// partition.
+    Incremental,
+    /// Some partitions are written in full and others are updated incrementally.
+    Mixed,
+}
+
+/// Determine the [`PayloadKind`] from the operation types in the payload's
+/// manifest. This only reads the payload header and does not apply or verify
+/// anything. Partitions with no operations, like unchanged partitions in some
+/// incremental OTAs, are ignored.
+pub fn payload_kind(reader: impl Read) -> Result<PayloadKind> {
+    let header = PayloadHeader::from_reader(reader)?;
+    let mut full = 0;
+    let mut incremental = 0;
+
+    for partition in &header.manifest.partitions {
+        if partition.operations.is_empty() {
+            continue;
+        } else if partition.operations.iter().all(is_full_operation) {
+            full += 1;
+        } else {
+            incremental += 1;
+        }
+    }
+
+    let kind = if incremental == 0 {
+        PayloadKind::Full
+    } else if full == 0 {
+        PayloadKind::Incremental
+    } else {
+        PayloadKind::Mixed
+    };
+
+    Ok(kind)
+}
+
+/// Prepare the partition for having its data replaced. update_engine would
+/// otherwise compute the dm-verity hash tree and FEC data from stale metadata,
+/// corrupting the new image, so those fields are cleared. The new image is
+/// expected to contain its own hash tree and FEC data, if any. Partitions with
+/// merge operations are rejected because the operations cannot be recomputed.
+pub fn prepare_replacement(partition: &mut PartitionUpdate) -> Result<()> {
+    if !partition.merge_operations.is_empty() {
+        return Err(Error::UnsupportedPartitionField(
+            partition.partition_name.clone(),
+            "merge_operations",
+        ));
+    }
+
+    partition.hash_tree_data_extent = None;
+    partition.hash_tree_extent = None;
+    partition.hash_tree_algorithm = None;
+    partition.hash_tree_salt = None;
+    partition.fec_data_extent = None;
+    partition.fec_extent = None;
+    // The proto default.
+    partition.fec_roots = 2;
+
+    Ok(())
+}
+
+/// Whether the operation writes its data without reading from the source
+/// partition.
+fn is_full_operation(operation: &InstallOperation) -> bool {
+    matches!(
+        operation.type_pb,
+        mod_InstallOperation::Type::REPLACE
+            | mod_InstallOperation::Type::REPLACE_BZ
+            | mod_InstallOperation::Type::REPLACE_XZ
+            | mod_InstallOperation::Type::ZERO
+            | mod_InstallOperation::Type::DISCARD,
+    )
 }
 
 impl<R: Read> FromReader<R> for PayloadHeader {
@@ -114,35 +552,30 @@ impl<R: Read> FromReader<R> for PayloadHeader {
     fn from_reader(reader: R) -> Result<Self> {
         let mut reader = CountingReader::new(reader);
 
-        let mut magic = [0u8; 4];
-        reader.read_exact(&mut magic)?;
-        if magic != *OTA_MAGIC {
-            return Err(Error::UnknownMagic(magic));
-        }
+        let raw_header = RawPayloadHeader::from_reader(&mut reader)?;
+        raw_header.check_version()?;
 
-        let version = reader.read_u64::<BigEndian>()?;
-        if version != 2 {
-            return Err(Error::UnsupportedVersion(version));
-        }
-
-        let manifest_size = reader
-            .read_u64::<BigEndian>()?
-            .to_usize()
-            .ok_or_else(|| Error::IntegerTooLarge("manifest_size"))?;
-        let metadata_signature_size = reader.read_u32::<BigEndian>()?;
-
-        let mut manifest_raw = vec![0u8; manifest_size];
-        reader.read_exact(&mut manifest_raw)?;
+        let manifest_raw = read_vec_exact(&mut reader, raw_header.manifest_size)?;
         let manifest: DeltaArchiveManifest = util::read_protobuf(&manifest_raw)?;
+        let mut unknown_fields = UnknownFields::extract(&manifest_raw, &MANIFEST_SCHEMA)?;
+        let mut partition_unknown_fields = BTreeMap::new();
+
+        for (index, partition) in manifest.partitions.iter().enumerate() {
+            if let Some(unknown) = unknown_fields.take_nested(FIELD_PARTITIONS, index) {
+                partition_unknown_fields.insert(partition.partition_name.clone(), unknown);
+            }
+        }
 
         // Skip manifest signatures.
-        reader.read_discard_exact(metadata_signature_size.into())?;
+        reader.read_discard_exact(raw_header.metadata_signature_size.into())?;
 
         Ok(Self {
-            version,
+            version: raw_header.version,
             manifest,
-            metadata_signature_size,
+            metadata_signature_size: raw_header.metadata_signature_size,
             blob_offset: reader.stream_position()?,
+            unknown_fields,
+            partition_unknown_fields,
         })
     }
 }
@@ -151,7 +584,7 @@ impl<R: Read> FromReader<R> for PayloadHeader {
 /// the signature padded to the maximum size.
 fn sign_digest(digest: &[u8], key: &RsaPrivateKey) -> Result<Signatures> {
     let scheme = Pkcs1v15Sign::new::<Sha256>();
-    let mut digest_signed = key.sign(scheme, digest)?;
+    let mut digest_signed = crypto::rsa_sign(key, scheme, digest)?;
     assert!(
         digest_signed.len() <= key.size(),
         "Signature exceeds maximum size",
@@ -246,6 +679,58 @@ fn generate_properties(
     properties
 }
 
+/// Recompute the data offsets and the signature fields in `header` for a
+/// payload signed with `key`. Returns the serialized manifest and the size of
+/// each signature struct.
+fn finalize_header(header: &mut PayloadHeader, key: &RsaPrivateKey) -> Result<(Vec<u8>, usize)> {
+    let mut blob_size = 0;
+
+    // The blob must contain all data in sequential order with no gaps.
+    for p in &mut header.manifest.partitions {
+        for op in &mut p.operations {
+            if let Some(length) = op.data_length {
+                // The field must be left unset when the blob contains no data
+                // for the operation.
+                op.data_offset = Some(blob_size);
+                blob_size += length;
+            }
+        }
+    }
+
+    // Get the length of an dummy signature struct since the length fields are
+    // part of the data to be signed.
+    let dummy_sig = sign_digest(
+        ring::digest::digest(&ring::digest::SHA256, b"").as_ref(),
+        key,
+    )?;
+    let dummy_sig_size = dummy_sig.get_size();
+
+    // Fill out the new payload signature information.
+    header.manifest.signatures_offset = Some(blob_size);
+    header.manifest.signatures_size = Some(dummy_sig_size as u64);
+
+    // Build new manifest.
+    let manifest_raw = header.manifest_raw()?;
+
+    Ok((manifest_raw, dummy_sig_size))
+}
+
+/// Compute the size of the payload that [`PayloadWriter`] would write for
+/// `header` when signing with `key`. This is exact and includes both the
+/// metadata signature and the payload signature. Only the
+/// [`InstallOperation::data_length`] fields are needed to compute the size of
+/// the blob section.
+pub fn payload_size(header: &PayloadHeader, key: &RsaPrivateKey) -> Result<u64> {
+    let mut header = header.clone();
+    let (manifest_raw, sig_size) = finalize_header(&mut header, key)?;
+
+    Ok(OTA_HEADER_SIZE as u64
+        + manifest_raw.len() as u64
+        + sig_size as u64
+        + header.manifest.signatures_offset.unwrap()
+        + sig_size as u64)
+}
+
 /// A writer for producing signed `payload.bin` files.
 pub struct PayloadWriter<W: Write> {
     inner: W,
@@ -289,34 +774,7 @@ impl<W: Write> PayloadWriter<W> {
     /// no gaps. All partitions' install operation data is written to the blob
     /// section in order.
     pub fn new(mut inner: W, mut header: PayloadHeader, key: RsaPrivateKey) -> Result<Self> {
-        let mut blob_size = 0;
-
-        // The blob must contain all data in sequential order with no gaps.
-        for p in &mut header.manifest.partitions {
-            for op in &mut p.operations {
-                if let Some(length) = op.data_length {
-                    // The field must be left unset when the blob contains no
-                    // data for the operation.
-                    op.data_offset = Some(blob_size);
-                    blob_size += length;
-                }
-            }
-        }
-
-        // Get the length of an dummy signature struct since the length fields
-        // are part of the data to be signed.
-        let dummy_sig = sign_digest(
-            ring::digest::digest(&ring::digest::SHA256, b"").as_ref(),
-            &key,
-        )?;
-        let dummy_sig_size = dummy_sig.get_size();
-
-        // Fill out the new payload signature information.
-        header.manifest.signatures_offset = Some(blob_size);
-        header.manifest.signatures_size = Some(dummy_sig_size as u64);
-
-        // Build new manifest.
-        let manifest_raw_new = util::write_protobuf(&header.manifest)?;
+        let (manifest_raw_new, dummy_sig_size) = finalize_header(&mut header, &key)?;
 
         // Excludes signatures (hashes are for signing).
         let mut h_partial = Context::new(&ring::digest::SHA256);
@@ -498,8 +956,13 @@ impl<W: Write> Write for PayloadWriter<W> {
         let partition = &self.header.manifest.partitions[pi];
         let operation = &partition.operations[oi];
 
-        let to_write =
-            (operation.data_length.unwrap() - self.written).min(buf.len() as u64) as usize;
+        let data_length = operation.data_length.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Operation does not reference any data in the blob",
+            )
+        })?;
+        let to_write = (data_length - self.written).min(buf.len() as u64) as usize;
         let n = self.inner.write(&buf[..to_write])?;
 
         self.h_full.update(&buf[..n]);
@@ -521,6 +984,7 @@ impl<W: Write> Write for PayloadWriter<W> {
 /// [`PartitionUpdate`] instance is updated with the final size and hash. The
 /// entire data will be represented as a single [`InstallOperation`] and
 /// [`InstallOperation::data_offset`] will be set to `None`.
+#[cfg(feature = "native")]
 pub struct CompressedPartitionWriter<W: Write> {
     inner: XzEncoder<HashingWriter<CountingWriter<W>>>,
     block_size: u32,
@@ -528,6 +992,7 @@ pub struct CompressedPartitionWriter<W: Write> {
     written: u64,
 }
 
+#[cfg(feature = "native")]
 impl<W: Write> CompressedPartitionWriter<W> {
     pub fn new(writer: W, block_size: u32) -> Result<Self> {
         let counting_writer = CountingWriter::new(writer);
@@ -552,6 +1017,8 @@ impl<W: Write> CompressedPartitionWriter<W> {
     /// Finish writing and update the [`PartitionUpdate`] instance with the new
     /// size, hash, and install operation metadata.
     pub fn finish(mut self, partition: &mut PartitionUpdate) -> Result<W> {
+        prepare_replacement(partition)?;
+
         if self.written % u64::from(self.block_size) != 0 {
             return Err(Error::InvalidPartitionSize(
                 partition.partition_name.clone(),
@@ -570,6 +1037,8 @@ impl<W: Write> CompressedPartitionWriter<W> {
         // XzEncoder::finish() writes data.
         let (writer, size_compressed) = counting_writer.finish();
 
+        // The new operation never reads from the source partition.
+        partition.old_partition_info = None;
         partition.new_partition_info = Some(PartitionInfo {
             size: Some(self.written),
             hash: Some(digest_uncompressed.as_ref().to_vec()),
@@ -600,6 +1069,7 @@ impl<W: Write> CompressedPartitionWriter<W> {
     }
 }
 
+#[cfg(feature = "native")]
 impl<W: Write> Write for CompressedPartitionWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.inner.write(buf)?;
@@ -614,15 +1084,35 @@ impl<W: Write> Write for CompressedPartitionWriter<W> {
 }
 
 /// Verify the payload signatures using the specified certificate and check that
-/// the digests in `payload_properties.txt` are correct.
+/// the digests in `payload_properties.txt` are correct. The digests of the
+/// install operations' data in the blob section are also checked. The payload
+/// is read in a single sequential pass, so `reader` does not need to be
+/// seekable.
 pub fn verify_payload(
-    mut reader: impl Read + Seek,
+    reader: impl Read,
     cert: &Certificate,
     properties_raw: &str,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
-    let header = PayloadHeader::from_reader(&mut reader)?;
-    reader.rewind()?;
+    let mut reader = CountingReader::new(reader);
+
+    // Read the metadata (header + manifest) and the metadata signature, which
+    // are small enough to keep in memory.
+    let mut metadata_raw = vec![0u8; OTA_HEADER_SIZE];
+    reader.read_exact(&mut metadata_raw)?;
+
+    let raw_header = RawPayloadHeader::from_reader(Cursor::new(&metadata_raw))?;
+    raw_header.check_version()?;
+
+    let metadata_size = raw_header.metadata_size()?;
+    let remaining_size = raw_header
+        .manifest_size
+        .checked_add(raw_header.metadata_signature_size.into())
+        .ok_or_else(|| Error::IntegerTooLarge("metadata_signature_size"))?;
+
+    metadata_raw.extend(read_vec_exact(&mut reader, remaining_size)?);
+
+    let header = PayloadHeader::from_reader(Cursor::new(&metadata_raw))?;
 
     let payload_signatures_offset = header
         .manifest
@@ -638,37 +1128,15 @@ pub fn verify_payload(
     // Includes signatures (hashes are for properties file).
     let mut h_full = Context::new(&ring::digest::SHA256);
 
-    // Read from the beginning to the metadata signature.
-    let metadata_size = header.blob_offset - u64::from(header.metadata_signature_size);
-    stream::copy_n_inspect(
-        &mut reader,
-        io::sink(),
-        metadata_size,
-        |data| {
-            h_partial.update(data);
-            h_full.update(data);
-        },
-        cancel_signal,
-    )?;
-    let metadata_hash = h_partial.clone().finish();
-
-    // Read the metadata signatures.
-    let metadata_sigs = {
-        let mut writer = Cursor::new(Vec::new());
-
-        stream::copy_n_inspect(
-            &mut reader,
-            &mut writer,
-            header.metadata_signature_size.into(),
-            |data| h_full.update(data),
-            cancel_signal,
-        )?;
+    let (metadata, metadata_sig_raw) = metadata_raw.split_at(metadata_size as usize);
+    h_partial.update(metadata);
+    h_full.update(metadata);
+    h_full.update(metadata_sig_raw);
 
-        let buf = writer.into_inner();
-        util::read_protobuf::<Signatures>(&buf)?
-    };
+    let metadata_hash = h_partial.clone().finish();
 
     // Check the metadata signatures.
+    let metadata_sigs = util::read_protobuf::<Signatures>(metadata_sig_raw)?;
     verify_digest(metadata_hash.as_ref(), &metadata_sigs, cert)?;
 
     // Check the blob offset.
@@ -679,11 +1147,76 @@ pub fn verify_payload(
         }
     }
 
-    // Read (and discard) all the payload blobs.
+    // Read (and discard) all the payload blobs, checking each operation's data
+    // along the way.
+    let mut operations = header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+        .filter_map(|op| Some((op.data_offset?, op.data_length?, op)))
+        .collect::<Vec<_>>();
+    operations.sort_by_key(|(offset, _, _)| *offset);
+
+    let mut blob_position = 0;
+
+    for (data_offset, data_length, op) in operations {
+        if data_offset < blob_position {
+            return Err(Error::OverlappingOperationData(data_offset));
+        }
+
+        stream::copy_n_inspect(
+            &mut reader,
+            io::sink(),
+            data_offset - blob_position,
+            |data| {
+                h_partial.update(data);
+                h_full.update(data);
+            },
+            cancel_signal,
+        )?;
+
+        let mut h_op = Context::new(&ring::digest::SHA256);
+
+        stream::copy_n_inspect(
+            &mut reader,
+            io::sink(),
+            data_length,
+            |data| {
+                h_partial.update(data);
+                h_full.update(data);
+                h_op.update(data);
+            },
+            cancel_signal,
+        )?;
+
+        if let Some(expected_digest) = &op.data_sha256_hash {
+            let digest = h_op.finish();
+
+            if expected_digest != digest.as_ref() {
+                return Err(Error::MismatchedDigest(
+                    Some(hex::encode(expected_digest)),
+                    hex::encode(digest.as_ref()),
+                ));
+            }
+        }
+
+        blob_position = data_offset
+            .checked_add(data_length)
+            .ok_or_else(|| Error::IntegerTooLarge("data_length"))?;
+    }
+
+    if payload_signatures_offset < blob_position {
+        return Err(Error::InvalidPayloadSignaturesOffset(
+            header.blob_offset + blob_position,
+            header.blob_offset + payload_signatures_offset,
+        ));
+    }
+
     stream::copy_n_inspect(
         &mut reader,
         io::sink(),
-        payload_signatures_offset,
+        payload_signatures_offset - blob_position,
         |data| {
             h_partial.update(data);
             h_full.update(data);
@@ -728,8 +1261,14 @@ pub fn verify_payload(
         metadata_size,
     );
 
+    check_properties(properties_raw, &expected_properties_raw)
+}
+
+/// Check that every property in `actual_raw` has the same value in
+/// `properties_raw`.
+fn check_properties(properties_raw: &str, actual_raw: &str) -> Result<()> {
     let expected_properties = parse_properties(properties_raw)?;
-    let actual_properties = parse_properties(&expected_properties_raw)?;
+    let actual_properties = parse_properties(actual_raw)?;
 
     for (key, actual_value) in actual_properties {
         let expected_value = expected_properties.get(&key);
@@ -746,115 +1285,394 @@ pub fn verify_payload(
     Ok(())
 }
 
-/// Apply a partition operation from `reader` to `writer`.
+/// Hash the payload metadata (header + manifest) and return the digest and the
+/// metadata size. The metadata signature is not included.
+fn hash_metadata(reader: impl Read, algorithm: &'static Algorithm) -> Result<(Digest, u64)> {
+    let hashing_reader = HashingReader::new(reader, Context::new(algorithm));
+    let mut reader = CountingReader::new(hashing_reader);
+
+    // The metadata signature size is part of the signed metadata, even though
+    // the signature itself isn't.
+    let raw_header = RawPayloadHeader::from_reader(&mut reader)?;
+    raw_header.check_version()?;
+
+    reader.read_discard_exact(raw_header.manifest_size)?;
+
+    let (hashing_reader, size) = reader.finish();
+    let (_, context) = hashing_reader.finish();
+
+    Ok((context.finish(), size))
+}
+
+/// Compute the hash of the payload metadata (header + manifest), excluding the
+/// metadata signature. This is the digest that update_engine signs and, when
+/// using SHA-256, what is stored as `METADATA_HASH` in `payload_properties.txt`.
+/// Only the metadata at the beginning of the payload is read, so this is
+/// suitable for checking a payload without downloading all of it.
+pub fn metadata_hash(reader: impl Read, algorithm: &'static Algorithm) -> Result<Digest> {
+    hash_metadata(reader, algorithm).map(|(digest, _)| digest)
+}
+
+/// Compute the `payload_properties.txt` contents for an existing payload. The
+/// payload is read in a single sequential pass.
+pub fn compute_properties(reader: impl Read, cancel_signal: &Arc<AtomicBool>) -> Result<String> {
+    let hashing_reader = HashingReader::new(reader, Context::new(&ring::digest::SHA256));
+    let mut reader = CountingReader::new(hashing_reader);
+
+    let (metadata_hash, metadata_size) = hash_metadata(&mut reader, &ring::digest::SHA256)?;
+    stream::copy(&mut reader, io::sink(), cancel_signal)?;
+
+    let (hashing_reader, file_size) = reader.finish();
+    let (_, context) = hashing_reader.finish();
+
+    Ok(generate_properties(
+        context.finish().as_ref(),
+        file_size,
+        metadata_hash.as_ref(),
+        metadata_size,
+    ))
+}
+
+/// Check that the file and metadata hashes and sizes in
+/// `payload_properties.txt` match the payload. Unlike [`verify_payload()`], the
+/// signatures are not checked, so this only ensures that the properties are
+/// consistent with the payload. The payload is read in a single sequential
+/// pass.
+pub fn verify_properties(
+    reader: impl Read,
+    properties_raw: &str,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let actual_properties_raw = compute_properties(reader, cancel_signal)?;
+
+    check_properties(properties_raw, &actual_properties_raw)
+}
+
+/// Check that the payload metadata matches the `METADATA_HASH` and
+/// `METADATA_SIZE` entries in `payload_properties.txt`. Like
+/// [`metadata_hash()`], only the metadata at the beginning of the payload is
+/// read.
+pub fn verify_metadata_hash(reader: impl Read, properties_raw: &str) -> Result<()> {
+    let (digest, size) = hash_metadata(reader, &ring::digest::SHA256)?;
+    let properties = parse_properties(properties_raw)?;
+
+    for (key, actual_value) in [
+        ("METADATA_HASH", STANDARD.encode(digest)),
+        ("METADATA_SIZE", size.to_string()),
+    ] {
+        let expected_value = properties.get(key);
+
+        if expected_value != Some(&actual_value) {
+            return Err(Error::InvalidProperty(
+                key.to_owned(),
+                actual_value,
+                expected_value.cloned(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// A writer that maps a contiguous stream of data onto a list of extents in the
+/// underlying writer. The extents do not need to be sorted or adjacent. Writes
+/// past the end of the last extent report EOF.
+struct ExtentsWriter<'a, W: Write + Seek> {
+    inner: W,
+    /// List of (offset, size) pairs in bytes.
+    extents: &'a [(u64, u64)],
+    index: usize,
+    extent_written: u64,
+}
+
+impl<'a, W: Write + Seek> ExtentsWriter<'a, W> {
+    fn new(inner: W, extents: &'a [(u64, u64)]) -> Self {
+        Self {
+            inner,
+            extents,
+            index: 0,
+            extent_written: 0,
+        }
+    }
+}
+
+impl<W: Write + Seek> Write for ExtentsWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let Some(&(offset, size)) = self.extents.get(self.index) else {
+                return Ok(0);
+            };
+
+            if self.extent_written == size {
+                self.index += 1;
+                self.extent_written = 0;
+                continue;
+            }
+
+            if self.extent_written == 0 {
+                self.inner.seek(SeekFrom::Start(offset))?;
+            }
+
+            let to_write = (size - self.extent_written).min(buf.len() as u64) as usize;
+            let n = self.inner.write(&buf[..to_write])?;
+            self.extent_written += n as u64;
+
+            return Ok(n);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Get the byte offset and size of each of the extents.
+fn extents_to_bytes(extents: &[Extent], block_size: u32) -> Result<Vec<(u64, u64)>> {
+    extents
+        .iter()
+        .map(|e| {
+            let start_block = e
+                .start_block
+                .ok_or_else(|| Error::MissingField("start_block"))?;
+            let num_blocks = e
+                .num_blocks
+                .ok_or_else(|| Error::MissingField("num_blocks"))?;
+
+            let offset = start_block
+                .checked_mul(block_size.into())
+                .ok_or_else(|| Error::IntegerTooLarge("out_offset"))?;
+            let size = num_blocks
+                .checked_mul(block_size.into())
+                .ok_or_else(|| Error::IntegerTooLarge("out_data_length"))?;
+
+            Ok((offset, size))
+        })
+        .collect()
+}
+
+/// Get the size of the new partition image. If the manifest does not specify
+/// the size, the end of the furthest destination extent is used instead.
+pub fn partition_size(partition: &PartitionUpdate, block_size: u32) -> Result<u64> {
+    if let Some(size) = partition.new_partition_info.as_ref().and_then(|i| i.size) {
+        return Ok(size);
+    }
+
+    let mut size = 0;
+
+    for op in &partition.operations {
+        for (offset, length) in extents_to_bytes(&op.dst_extents, block_size)? {
+            let end = offset
+                .checked_add(length)
+                .ok_or_else(|| Error::IntegerTooLarge("partition_size"))?;
+            size = size.max(end);
+        }
+    }
+
+    Ok(size)
+}
+
+/// A contiguous range of blocks in a partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ExtentDesc {
+    pub start_block: u64,
+    pub num_blocks: u64,
+}
+
+/// Human-readable description of an [`InstallOperation`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct OperationDesc {
+    /// Index of the operation within the partition.
+    pub index: usize,
+    /// Operation type, eg. `SOURCE_COPY`.
+    pub op_type: String,
+    /// Offset of the operation's data relative to the start of the blob. This
+    /// is absent for operations that have no data, like `ZERO`.
+    pub data_offset: Option<u64>,
+    pub data_length: Option<u64>,
+    /// Extents that are read from the old partition. These are only used by
+    /// delta operations.
+    pub src_extents: Vec<ExtentDesc>,
+    /// Extents that are written in the new partition.
+    pub dst_extents: Vec<ExtentDesc>,
+}
+
+fn describe_extents(extents: &[Extent]) -> Result<Vec<ExtentDesc>> {
+    extents
+        .iter()
+        .map(|e| {
+            Ok(ExtentDesc {
+                start_block: e
+                    .start_block
+                    .ok_or_else(|| Error::MissingField("start_block"))?,
+                num_blocks: e
+                    .num_blocks
+                    .ok_or_else(|| Error::MissingField("num_blocks"))?,
+            })
+        })
+        .collect()
+}
+
+/// Describe the install operations of partition `partition` in the payload
+/// from `reader`. Only the payload header is read and nothing is applied, so
+/// this works for delta payloads too.
+pub fn describe_operations(reader: impl Read, partition: &str) -> Result<Vec<OperationDesc>> {
+    let header = PayloadHeader::from_reader(reader)?;
+    let partition = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == partition)
+        .ok_or_else(|| Error::MissingPartition(partition.to_owned()))?;
+
+    partition
+        .operations
+        .iter()
+        .enumerate()
+        .map(|(index, op)| {
+            Ok(OperationDesc {
+                index,
+                op_type: format!("{:?}", op.type_pb),
+                data_offset: op.data_offset,
+                data_length: op.data_length,
+                src_extents: describe_extents(&op.src_extents)?,
+                dst_extents: describe_extents(&op.dst_extents)?,
+            })
+        })
+        .collect()
+}
+
+/// Apply a partition operation from `reader` to `writer`. The output data is
+/// written to the operation's destination extents in the order they are
+/// listed, regardless of their offsets. For ZERO and DISCARD operations, zeros
+/// are written to the destination extents.
+///
+/// If `verify_digest` is true, the operation's data is checked against its
+/// `data_sha256_hash`. The check can only happen once all of the data has been
+/// read, so the output is already written when [`Error::MismatchedDigest`] is
+/// returned.
 pub fn apply_operation(
     mut reader: impl Read + Seek,
     mut writer: impl Write + Seek,
     block_size: u32,
     blob_offset: u64,
     op: &InstallOperation,
+    verify_digest: bool,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
-    for extent in &op.dst_extents {
-        let start_block = extent
-            .start_block
-            .ok_or_else(|| Error::MissingField("start_block"))?;
-        let num_blocks = extent
-            .num_blocks
-            .ok_or_else(|| Error::MissingField("num_blocks"))?;
-
-        let out_offset = start_block
-            .checked_mul(block_size.into())
-            .ok_or_else(|| Error::IntegerTooLarge("out_offset"))?;
-        let out_data_length = num_blocks
-            .checked_mul(block_size.into())
-            .ok_or_else(|| Error::IntegerTooLarge("out_data_length"))?;
-
-        writer.seek(SeekFrom::Start(out_offset))?;
-
-        let mut hasher = Context::new(&ring::digest::SHA256);
-
-        match op.type_pb {
-            // Handle ZERO/DISCARD specially since they don't require access to
-            // the payload blob.
-            mod_InstallOperation::Type::ZERO | mod_InstallOperation::Type::DISCARD => {
-                stream::copy_n_inspect(
-                    io::repeat(0),
-                    &mut writer,
-                    out_data_length,
-                    |data| hasher.update(data),
-                    cancel_signal,
-                )?;
-            }
-            other => {
-                let data_offset = op
-                    .data_offset
-                    .ok_or_else(|| Error::MissingField("data_offset"))?;
-                let data_length = op
-                    .data_length
-                    .ok_or_else(|| Error::MissingField("data_length"))?;
-                let in_offset = blob_offset
-                    .checked_add(data_offset)
-                    .ok_or_else(|| Error::IntegerTooLarge("in_offset"))?;
-
-                reader.seek(SeekFrom::Start(in_offset))?;
-
-                match other {
-                    mod_InstallOperation::Type::REPLACE => {
-                        stream::copy_n_inspect(
-                            &mut reader,
-                            &mut writer,
-                            data_length,
-                            |data| hasher.update(data),
-                            cancel_signal,
-                        )?;
-                    }
-                    mod_InstallOperation::Type::REPLACE_BZ => {
-                        let mut decoder = BzDecoder::new(&mut writer);
-                        stream::copy_n_inspect(
-                            &mut reader,
-                            &mut decoder,
-                            data_length,
-                            |data| hasher.update(data),
-                            cancel_signal,
-                        )?;
-                        decoder.finish()?;
-                    }
-                    mod_InstallOperation::Type::REPLACE_XZ => {
-                        let mut decoder = XzDecoder::new(&mut writer);
-                        stream::copy_n_inspect(
-                            &mut reader,
-                            &mut decoder,
-                            data_length,
-                            |data| hasher.update(data),
-                            cancel_signal,
-                        )?;
-                        decoder.finish()?;
-                    }
-                    _ => return Err(Error::UnsupportedOperation(op.type_pb)),
+    let extents = extents_to_bytes(&op.dst_extents, block_size)?;
+    let out_data_length = extents
+        .iter()
+        .try_fold(0u64, |total, (_, size)| total.checked_add(*size))
+        .ok_or_else(|| Error::IntegerTooLarge("out_data_length"))?;
+    let mut extents_writer = ExtentsWriter::new(&mut writer, &extents);
+
+    match op.type_pb {
+        // Handle ZERO/DISCARD specially since they don't require access to the
+        // payload blob and have no digest to verify.
+        mod_InstallOperation::Type::ZERO | mod_InstallOperation::Type::DISCARD => {
+            stream::copy_n(
+                io::repeat(0),
+                &mut extents_writer,
+                out_data_length,
+                cancel_signal,
+            )?;
+        }
+        other => {
+            let data_offset = op
+                .data_offset
+                .ok_or_else(|| Error::MissingField("data_offset"))?;
+            let data_length = op
+                .data_length
+                .ok_or_else(|| Error::MissingField("data_length"))?;
+            let in_offset = blob_offset
+                .checked_add(data_offset)
+                .ok_or_else(|| Error::IntegerTooLarge("in_offset"))?;
+
+            reader.seek(SeekFrom::Start(in_offset))?;
+
+            let mut hasher = verify_digest.then(|| Context::new(&ring::digest::SHA256));
+            let mut update = |data: &[u8]| {
+                if let Some(h) = &mut hasher {
+                    h.update(data);
+                }
+            };
+
+            match other {
+                mod_InstallOperation::Type::REPLACE => {
+                    stream::copy_n_inspect(
+                        &mut reader,
+                        &mut extents_writer,
+                        data_length,
+                        &mut update,
+                        cancel_signal,
+                    )?;
+                }
+                // Without the native feature, these fall through to
+                // UnsupportedOperation.
+                #[cfg(feature = "native")]
+                mod_InstallOperation::Type::REPLACE_BZ => {
+                    let mut decoder = BzDecoder::new(&mut extents_writer);
+                    stream::copy_n_inspect(
+                        &mut reader,
+                        &mut decoder,
+                        data_length,
+                        &mut update,
+                        cancel_signal,
+                    )?;
+                    decoder.finish()?;
+                }
+                #[cfg(feature = "native")]
+                mod_InstallOperation::Type::REPLACE_XZ => {
+                    let mut decoder = XzDecoder::new(&mut extents_writer);
+                    stream::copy_n_inspect(
+                        &mut reader,
+                        &mut decoder,
+                        data_length,
+                        &mut update,
+                        cancel_signal,
+                    )?;
+                    decoder.finish()?;
                 }
+                _ => return Err(Error::UnsupportedOperation(op.type_pb)),
             }
-        }
 
-        let expected_digest = op.data_sha256_hash.as_deref();
-        let digest = hasher.finish();
+            if let Some(hasher) = hasher {
+                let expected_digest = op.data_sha256_hash.as_deref();
+                let digest = hasher.finish();
 
-        if expected_digest != Some(digest.as_ref())
-            && op.type_pb != mod_InstallOperation::Type::ZERO
-        {
-            return Err(Error::MismatchedDigest(
-                expected_digest.map(hex::encode),
-                hex::encode(digest.as_ref()),
-            ));
+                if expected_digest != Some(digest.as_ref()) {
+                    return Err(Error::MismatchedDigest(
+                        expected_digest.map(hex::encode),
+                        hex::encode(digest.as_ref()),
+                    ));
+                }
+            }
         }
     }
 
+    extents_writer.flush()?;
+
     Ok(())
 }
 
+/// Add the partition name and operation index to a digest mismatch error from
+/// [`apply_operation`].
+fn with_operation_index(result: Result<()>, partition: &str, op_index: usize) -> Result<()> {
+    result.map_err(|e| match e {
+        Error::MismatchedDigest(expected, actual) => Error::OperationHashMismatch {
+            partition: partition.to_owned(),
+            op_index,
+            expected,
+            actual,
+        },
+        e => e,
+    })
+}
+
 /// Extract the specified image from the payload into memory. This is done
 /// multithreaded and uses rayon's global thread pool. `open_payload` will be
-/// called from multiple threads.
+/// called from multiple threads. The digest of every operation's data is
+/// verified.
 pub fn extract_image_to_memory(
     open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
     header: &PayloadHeader,
@@ -868,50 +1686,406 @@ pub fn extract_image_to_memory(
         .find(|p| p.partition_name == partition_name)
         .ok_or_else(|| Error::MissingPartition(partition_name.to_owned()))?;
     let stream = SharedCursor::default();
+    stream.set_len(partition_size(partition, header.manifest.block_size)?)?;
 
     partition
         .operations
         .par_iter()
-        .map(|op| -> Result<()> {
+        .enumerate()
+        .map(|(i, op)| -> Result<()> {
             let reader = open_payload()?;
             let writer = stream.clone();
 
-            apply_operation(
+            let result = apply_operation(
                 reader,
                 writer,
                 header.manifest.block_size,
                 header.blob_offset,
                 op,
+                true,
                 cancel_signal,
-            )?;
+            );
 
-            Ok(())
+            with_operation_index(result, partition_name, i)
         })
         .collect::<Result<_>>()?;
 
     Ok(stream)
 }
 
+/// A writer that only keeps the data that falls within a window of the output
+/// and discards the rest.
+struct WindowWriter<'a> {
+    window_offset: u64,
+    window: &'a mut [u8],
+    pos: u64,
+}
+
+impl Write for WindowWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let window_end = self.window_offset + self.window.len() as u64;
+        let start = self.pos.clamp(self.window_offset, window_end);
+        let end = (self.pos + buf.len() as u64).clamp(self.window_offset, window_end);
+
+        if start < end {
+            let len = (end - start) as usize;
+            let buf_start = (start - self.pos) as usize;
+            let window_start = (start - self.window_offset) as usize;
+
+            self.window[window_start..window_start + len]
+                .copy_from_slice(&buf[buf_start..buf_start + len]);
+        }
+
+        self.pos += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for WindowWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => self
+                .pos
+                .checked_add_signed(offset)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+
+        Ok(self.pos)
+    }
+}
+
+/// Extract `size` bytes at `offset` from the specified image in the payload.
+/// Only the operations that write to that range are applied, so this is much
+/// cheaper than extracting the whole image when only a small part, like the
+/// AVB footer, is needed. The digest of every applied operation's data is
+/// verified. Delta operations are not supported.
+pub fn extract_range(
+    mut reader: impl Read + Seek,
+    header: &PayloadHeader,
+    partition_name: &str,
+    offset: u64,
+    size: u64,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<Vec<u8>> {
+    let partition = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == partition_name)
+        .ok_or_else(|| Error::MissingPartition(partition_name.to_owned()))?;
+    let block_size = header.manifest.block_size;
+    let image_size = partition_size(partition, block_size)?;
+
+    let end = offset
+        .checked_add(size)
+        .filter(|e| *e <= image_size)
+        .ok_or_else(|| {
+            Error::RangeOutOfBounds(
+                partition_name.to_owned(),
+                offset,
+                offset.saturating_add(size),
+            )
+        })?;
+    let size = usize::try_from(size).map_err(|_| Error::IntegerTooLarge("size"))?;
+    let mut data = vec![0u8; size];
+
+    for (i, op) in partition.operations.iter().enumerate() {
+        let overlaps = extents_to_bytes(&op.dst_extents, block_size)?
+            .into_iter()
+            .any(|(o, s)| o < end && offset < o.saturating_add(s));
+        if !overlaps {
+            continue;
+        }
+
+        let writer = WindowWriter {
+            window_offset: offset,
+            window: &mut data,
+            pos: 0,
+        };
+
+        let result = apply_operation(
+            &mut reader,
+            writer,
+            block_size,
+            header.blob_offset,
+            op,
+            true,
+            cancel_signal,
+        );
+
+        with_operation_index(result, partition_name, i)?;
+    }
+
+    Ok(data)
+}
+
+/// A writer that compares the data written to it with `expected` instead of
+/// storing it. When the data differs, the offset of the first difference is
+/// recorded and the write fails so that nothing more is written.
+struct CompareWriter<R: Read + Seek> {
+    expected: R,
+    pos: u64,
+    buf: Vec<u8>,
+    mismatch: Option<u64>,
+}
+
+impl<R: Read + Seek> Write for CompareWriter<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.resize(buf.len(), 0);
+        self.expected.seek(SeekFrom::Start(self.pos))?;
+        self.expected.read_exact(&mut self.buf)?;
+
+        if let Some(i) = self.buf.iter().zip(buf).position(|(a, b)| a != b) {
+            self.mismatch = Some(self.pos + i as u64);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Data does not match expected image",
+            ));
+        }
+
+        self.pos += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Seek for CompareWriter<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => self
+                .pos
+                .checked_add_signed(offset)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+
+        Ok(self.pos)
+    }
+}
+
+/// Verify that applying the payload from `reader` would produce the existing
+/// partition images in `partitions`. Each partition's operations are applied
+/// in order and the output is compared with the image instead of being
+/// written. The first block that differs is reported as
+/// [`Error::ImageMismatch`]. The digest of every operation's data is verified
+/// too. Partitions in the payload that are not listed are not checked, but
+/// every listed partition must exist in the payload.
+pub fn verify_against<R: Read + Seek>(
+    mut reader: impl Read + Seek,
+    partitions: &mut [(&str, R)],
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let header = PayloadHeader::from_reader(&mut reader)?;
+    let block_size = header.manifest.block_size;
+
+    for (name, image) in partitions {
+        let name = *name;
+        let partition = header
+            .manifest
+            .partitions
+            .iter()
+            .find(|p| p.partition_name == name)
+            .ok_or_else(|| Error::MissingPartition(name.to_owned()))?;
+
+        let expected_size = partition_size(partition, block_size)?;
+        let actual_size = image.seek(SeekFrom::End(0))?;
+        if actual_size != expected_size {
+            return Err(Error::ImageSizeMismatch(
+                name.to_owned(),
+                expected_size,
+                actual_size,
+            ));
+        }
+
+        for (i, op) in partition.operations.iter().enumerate() {
+            let mut writer = CompareWriter {
+                expected: &mut *image,
+                pos: 0,
+                buf: vec![],
+                mismatch: None,
+            };
+
+            let result = apply_operation(
+                &mut reader,
+                &mut writer,
+                block_size,
+                header.blob_offset,
+                op,
+                true,
+                cancel_signal,
+            );
+
+            if let Some(offset) = writer.mismatch {
+                return Err(Error::ImageMismatch {
+                    partition: name.to_owned(),
+                    op_index: i,
+                    block: offset / u64::from(block_size),
+                });
+            }
+
+            with_operation_index(result, name, i)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Options for [`extract_images_with_options()`].
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractOptions {
+    /// Whether to verify the digest of every operation's data.
+    pub verify_digests: bool,
+    /// Maximum number of operations that read from the payload at the same
+    /// time. If set, each operation's data is read into memory before it is
+    /// decompressed so that other operations can read while this one is
+    /// decompressing. This avoids excessive seeking on hard drives. If unset,
+    /// the number of concurrent reads is only limited by the thread pool.
+    pub max_readers: Option<NonZeroUsize>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            verify_digests: true,
+            max_readers: None,
+        }
+    }
+}
+
+/// Counting semaphore for limiting the number of concurrent payload reads.
+struct ReadLimiter {
+    available: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl ReadLimiter {
+    fn new(permits: NonZeroUsize) -> Self {
+        Self {
+            available: Mutex::new(permits.get()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Run `f` once a permit is available.
+    fn with_permit<T>(&self, f: impl FnOnce() -> T) -> T {
+        {
+            let mut available = self.available.lock().unwrap();
+            while *available == 0 {
+                available = self.cond.wait(available).unwrap();
+            }
+            *available -= 1;
+        }
+
+        let result = f();
+
+        *self.available.lock().unwrap() += 1;
+        self.cond.notify_one();
+
+        result
+    }
+}
+
+/// Read the data for an operation into memory and return it along with a copy
+/// of the operation that refers to the data at offset 0.
+fn read_operation_data(
+    mut reader: impl Read + Seek,
+    blob_offset: u64,
+    op: &InstallOperation,
+    data_offset: u64,
+    data_length: u64,
+) -> Result<(Vec<u8>, InstallOperation)> {
+    let in_offset = blob_offset
+        .checked_add(data_offset)
+        .ok_or_else(|| Error::IntegerTooLarge("in_offset"))?;
+
+    reader.seek(SeekFrom::Start(in_offset))?;
+    let data = read_vec_exact(reader, data_length)?;
+
+    let op = InstallOperation {
+        data_offset: Some(0),
+        ..op.clone()
+    };
+
+    Ok((data, op))
+}
+
 /// Extract the specified partition images from the payload into writers. This
 /// is done multithreaded and uses rayon's global thread pool. `open_payload`
-/// and `open_output` will be called from multiple threads.
+/// and `open_output` will be called from multiple threads. If `verify_digests`
+/// is true, the digest of every operation's data is verified.
 pub fn extract_images<'a>(
     open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
     open_output: impl Fn(&str) -> io::Result<Box<dyn WriteSeek>> + Sync,
     header: &PayloadHeader,
     partition_names: impl IntoIterator<Item = &'a str>,
+    verify_digests: bool,
+    cancel_signal: &Arc<AtomicBool>,
+) -> Result<()> {
+    let options = ExtractOptions {
+        verify_digests,
+        ..Default::default()
+    };
+
+    extract_images_with_options(
+        open_payload,
+        open_output,
+        header,
+        partition_names,
+        &options,
+        |_| {},
+        cancel_signal,
+    )
+}
+
+/// Like [`extract_images()`], but with more control over how the payload is
+/// read. This runs in the current rayon thread pool, so the number of threads
+/// can be limited with [`rayon::ThreadPool::install()`]. `partition_done` is
+/// called with the partition name once all of its operations have been
+/// applied. It may be called from multiple threads.
+///
+/// The operations are applied in parallel regardless of which partition they
+/// belong to, so the partitions are not written in order. The output is
+/// identical to extracting serially as long as the operations' destination
+/// extents do not overlap, which is guaranteed for valid full payloads. Enable
+/// [`ExtractOptions::verify_digests`] to detect corrupted data.
+pub fn extract_images_with_options<'a>(
+    open_payload: impl Fn() -> io::Result<Box<dyn ReadSeek>> + Sync,
+    open_output: impl Fn(&str) -> io::Result<Box<dyn WriteSeek>> + Sync,
+    header: &PayloadHeader,
+    partition_names: impl IntoIterator<Item = &'a str>,
+    options: &ExtractOptions,
+    partition_done: impl Fn(&str) + Sync,
     cancel_signal: &Arc<AtomicBool>,
 ) -> Result<()> {
     let mut remaining = partition_names.into_iter().collect::<HashSet<_>>();
     // We parallelize at the operation level or else one thread might get stuck
     // processing a giant image.
     let mut operations = vec![];
+    // Number of operations left for each partition.
+    let mut ops_left = HashMap::new();
 
     for p in &header.manifest.partitions {
         if remaining.remove(p.partition_name.as_str()) {
-            for op in &p.operations {
-                operations.push((p.partition_name.as_str(), op));
+            for (i, op) in p.operations.iter().enumerate() {
+                operations.push((p.partition_name.as_str(), i, op));
             }
+
+            ops_left.insert(
+                p.partition_name.as_str(),
+                AtomicUsize::new(p.operations.len()),
+            );
         }
     }
 
@@ -920,20 +2094,52 @@ pub fn extract_images<'a>(
         return Err(Error::MissingPartitions(remaining));
     }
 
+    for (name, count) in &ops_left {
+        if count.load(Ordering::Relaxed) == 0 {
+            partition_done(name);
+        }
+    }
+
+    let limiter = options.max_readers.map(ReadLimiter::new);
+
     operations
         .into_par_iter()
-        .map(|(name, op)| -> Result<()> {
+        .map(|(name, i, op)| -> Result<()> {
             let reader = open_payload()?;
             let writer = open_output(name)?;
 
-            apply_operation(
-                reader,
-                writer,
-                header.manifest.block_size,
-                header.blob_offset,
-                op,
-                cancel_signal,
-            )?;
+            let result = match (&limiter, op.data_offset, op.data_length) {
+                (Some(l), Some(offset), Some(length)) => l
+                    .with_permit(|| {
+                        read_operation_data(reader, header.blob_offset, op, offset, length)
+                    })
+                    .and_then(|(data, op)| {
+                        apply_operation(
+                            Cursor::new(data),
+                            writer,
+                            header.manifest.block_size,
+                            0,
+                            &op,
+                            options.verify_digests,
+                            cancel_signal,
+                        )
+                    }),
+                _ => apply_operation(
+                    reader,
+                    writer,
+                    header.manifest.block_size,
+                    header.blob_offset,
+                    op,
+                    options.verify_digests,
+                    cancel_signal,
+                ),
+            };
+
+            with_operation_index(result, name, i)?;
+
+            if ops_left[name].fetch_sub(1, Ordering::AcqRel) == 1 {
+                partition_done(name);
+            }
 
             Ok(())
         })
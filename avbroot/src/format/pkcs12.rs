@@ -0,0 +1,595 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal PKCS#12 (RFC 7292) parser for loading RSA private keys and
+//! certificates. Only password integrity and privacy modes are supported. This
+//! covers the defaults of both OpenSSL 1.1 (RC2 and 3DES with the PKCS#12 KDF)
+//! and OpenSSL 3.x (PBES2 with AES).
+
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, InnerIvInit, KeyIvInit};
+use cms::{content_info::ContentInfo, encrypted_data::EncryptedData};
+use const_oid::ObjectIdentifier;
+use des::{TdesEde2, TdesEde3};
+use pkcs8::{pkcs5::EncryptionScheme, DecodePrivateKey};
+use rc2::Rc2;
+use ring::{digest, hmac};
+use rsa::RsaPrivateKey;
+use thiserror::Error;
+use x509_cert::{
+    der::{asn1::OctetString, Any, Decode, Encode, Reader, SliceReader, Tag, TagNumber},
+    spki::AlgorithmIdentifierOwned,
+    Certificate,
+};
+
+const PKCS12_VERSION: u8 = 3;
+
+const OID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const OID_ENCRYPTED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.6");
+
+const OID_KEY_BAG: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.1");
+const OID_SHROUDED_KEY_BAG: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.2");
+const OID_CERT_BAG: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.3");
+const OID_X509_CERTIFICATE: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.22.1");
+
+const OID_FRIENDLY_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.20");
+const OID_LOCAL_KEY_ID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.21");
+
+const OID_PBE_SHA1_3DES: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.1.3");
+const OID_PBE_SHA1_2DES: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.1.4");
+const OID_PBE_SHA1_RC2_128: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.1.5");
+const OID_PBE_SHA1_RC2_40: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.1.6");
+const OID_PBES2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.13");
+const OID_PBKDF2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.12");
+const OID_SCRYPT: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.11591.4.11");
+const OID_AES_128_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.2");
+const OID_AES_192_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.22");
+const OID_AES_256_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.42");
+
+const OID_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+const OID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const OID_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.2");
+const OID_SHA512: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.3");
+
+/// Diversifier IDs for the PKCS#12 KDF (RFC 7292, appendix B.3).
+const KDF_ID_KEY: u8 = 1;
+const KDF_ID_IV: u8 = 2;
+const KDF_ID_MAC: u8 = 3;
+
+/// Upper bound for the KDF iteration counts, which come from the file. This is
+/// far above what any tool writes by default (OpenSSL uses 2048), but prevents
+/// a crafted file from making key loading take practically forever.
+pub const MAX_ITERATIONS: u32 = 10_000_000;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unsupported PKCS#12 version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid PKCS#12 structure: {0}")]
+    InvalidStructure(&'static str),
+    #[error("Unsupported PKCS#12 content type: {0}")]
+    UnsupportedContentType(ObjectIdentifier),
+    #[error("Unsupported PKCS#12 encryption algorithm: {0}")]
+    UnsupportedEncryption(ObjectIdentifier),
+    #[error("Unsupported PKCS#12 MAC digest algorithm: {0}")]
+    UnsupportedMac(ObjectIdentifier),
+    #[error("PKCS#12 {0} iteration count exceeds {MAX_ITERATIONS}: {1}")]
+    TooManyIterations(&'static str, u32),
+    #[error("PKCS#12 MAC verification failed (incorrect passphrase?)")]
+    MacMismatch,
+    #[error("Failed to decrypt PKCS#12 {0} (incorrect passphrase?)")]
+    Decrypt(&'static str),
+    #[error("Failed to load private key from PKCS#12 bundle")]
+    LoadKey(#[source] pkcs8::Error),
+    #[error("PKCS#12 bundle contains no {0}")]
+    MissingEntry(&'static str),
+    #[error("PKCS#12 bundle contains multiple {0}, but no alias was specified: {1:?}")]
+    AmbiguousEntry(&'static str, Vec<String>),
+    #[error("PKCS#12 bundle contains no {0} with alias: {1:?}")]
+    AliasNotFound(&'static str, String),
+    #[error("DER error")]
+    Der(#[from] x509_cert::der::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A key or certificate from a PKCS#12 bundle, along with the attributes that
+/// are used to match them up.
+#[derive(Clone, Debug)]
+pub struct Pkcs12Bag<T> {
+    /// The `friendlyName` attribute, which is what OpenSSL calls the alias.
+    pub friendly_name: Option<String>,
+    /// The `localKeyId` attribute, which is the same for a key and its
+    /// certificate.
+    pub local_key_id: Option<Vec<u8>>,
+    pub value: T,
+}
+
+/// The RSA private keys and X509 certificates in a PKCS#12 bundle. Other bag
+/// types, like CRLs and secrets, are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct Pkcs12 {
+    pub keys: Vec<Pkcs12Bag<RsaPrivateKey>>,
+    pub certs: Vec<Pkcs12Bag<Certificate>>,
+}
+
+/// Get the children of a DER SEQUENCE or SET.
+fn children(any: &Any, tag: Tag) -> Result<Vec<Any>> {
+    if any.tag() != tag {
+        return Err(Error::InvalidStructure("Unexpected tag"));
+    }
+
+    let mut reader = SliceReader::new(any.value())?;
+    let mut result = vec![];
+
+    while !reader.is_finished() {
+        result.push(reader.decode()?);
+    }
+
+    Ok(result)
+}
+
+/// Get the value of a `[0] EXPLICIT` field.
+fn explicit_0(any: &Any) -> Result<Any> {
+    let tag = Tag::ContextSpecific {
+        constructed: true,
+        number: TagNumber::N0,
+    };
+    if any.tag() != tag {
+        return Err(Error::InvalidStructure("Expected [0] EXPLICIT field"));
+    }
+
+    Ok(Any::from_der(any.value())?)
+}
+
+/// Encode a passphrase as a NUL-terminated big endian UTF-16 string, which is
+/// what the PKCS#12 KDF and MAC use.
+fn bmp_passphrase(passphrase: &str) -> Vec<u8> {
+    passphrase
+        .encode_utf16()
+        .chain([0])
+        .flat_map(|c| c.to_be_bytes())
+        .collect()
+}
+
+fn check_iterations(iterations: u32, what: &'static str) -> Result<u32> {
+    if iterations > MAX_ITERATIONS {
+        return Err(Error::TooManyIterations(what, iterations));
+    }
+
+    Ok(iterations)
+}
+
+/// Derive `size` bytes of key material with the PKCS#12 KDF (RFC 7292,
+/// appendix B.2).
+fn pkcs12_kdf(
+    algorithm: &'static digest::Algorithm,
+    passphrase: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    id: u8,
+    size: usize,
+) -> Vec<u8> {
+    let block_size = algorithm.block_len;
+
+    let repeat = |data: &[u8]| -> Vec<u8> {
+        let len = data.len().div_ceil(block_size) * block_size;
+        data.iter().copied().cycle().take(len).collect()
+    };
+
+    let mut input = repeat(salt);
+    input.extend(repeat(passphrase));
+
+    let mut result = Vec::with_capacity(size);
+
+    while result.len() < size {
+        let mut context = digest::Context::new(algorithm);
+        context.update(&vec![id; block_size]);
+        context.update(&input);
+        let mut hash = context.finish();
+
+        for _ in 1..iterations {
+            hash = digest::digest(algorithm, hash.as_ref());
+        }
+
+        result.extend_from_slice(hash.as_ref());
+
+        // Each block of the input is incremented by the hash (repeated to fill
+        // the block) plus 1, as big endian integers.
+        let b = hash
+            .as_ref()
+            .iter()
+            .copied()
+            .cycle()
+            .take(block_size)
+            .collect::<Vec<_>>();
+
+        for chunk in input.chunks_exact_mut(block_size) {
+            let mut carry = 1u16;
+
+            for (x, y) in chunk.iter_mut().zip(&b).rev() {
+                let sum = u16::from(*x) + u16::from(*y) + carry;
+                *x = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+    }
+
+    result.truncate(size);
+    result
+}
+
+/// Decrypt data with one of the PKCS#12 password-based encryption schemes
+/// (RFC 7292, appendix C). These use SHA-1 with the PKCS#12 KDF.
+fn decrypt_pkcs12_pbe(
+    oid: ObjectIdentifier,
+    params: &Any,
+    passphrase: &str,
+    ciphertext: &[u8],
+    what: &'static str,
+) -> Result<Vec<u8>> {
+    let fields = children(params, Tag::Sequence)?;
+    let [salt, iterations] = fields.as_slice() else {
+        return Err(Error::InvalidStructure("Invalid PBE parameters"));
+    };
+    let salt = salt.decode_as::<OctetString>()?;
+    let iterations = check_iterations(iterations.decode_as::<u32>()?, what)?;
+
+    let (key_size, iv_size) = match oid {
+        OID_PBE_SHA1_3DES => (24, 8),
+        OID_PBE_SHA1_2DES => (16, 8),
+        OID_PBE_SHA1_RC2_128 => (16, 8),
+        OID_PBE_SHA1_RC2_40 => (5, 8),
+        o => return Err(Error::UnsupportedEncryption(o)),
+    };
+
+    let passphrase = bmp_passphrase(passphrase);
+    let kdf = |id, size| {
+        pkcs12_kdf(
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            &passphrase,
+            salt.as_bytes(),
+            iterations,
+            id,
+            size,
+        )
+    };
+    let key = kdf(KDF_ID_KEY, key_size);
+    let iv = kdf(KDF_ID_IV, iv_size);
+
+    let mut buf = ciphertext.to_vec();
+
+    let plaintext = match oid {
+        OID_PBE_SHA1_3DES => cbc::Decryptor::<TdesEde3>::new_from_slices(&key, &iv)
+            .unwrap()
+            .decrypt_padded_mut::<Pkcs7>(&mut buf),
+        OID_PBE_SHA1_2DES => cbc::Decryptor::<TdesEde2>::new_from_slices(&key, &iv)
+            .unwrap()
+            .decrypt_padded_mut::<Pkcs7>(&mut buf),
+        _ => {
+            let cipher = Rc2::new_with_eff_key_len(&key, key_size * 8);
+
+            cbc::Decryptor::<Rc2>::inner_iv_slice_init(cipher, &iv)
+                .unwrap()
+                .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        }
+    }
+    .map_err(|_| Error::Decrypt(what))?;
+
+    let plaintext_len = plaintext.len();
+    buf.truncate(plaintext_len);
+
+    Ok(buf)
+}
+
+/// Decrypt data with PBES2 (RFC 8018). The passphrase is used as-is, without
+/// converting it to UTF-16 like the PKCS#12 KDF does.
+fn decrypt_pbes2(
+    algorithm: &AlgorithmIdentifierOwned,
+    passphrase: &str,
+    ciphertext: &[u8],
+    what: &'static str,
+) -> Result<Vec<u8>> {
+    let params = algorithm
+        .parameters
+        .as_ref()
+        .ok_or(Error::InvalidStructure("Missing PBES2 parameters"))?;
+
+    // Check the algorithms first so that unsupported ones are reported clearly
+    // instead of as a generic parse error.
+    for field in children(params, Tag::Sequence)? {
+        let inner = field.decode_as::<AlgorithmIdentifierOwned>()?;
+
+        match inner.oid {
+            OID_PBKDF2 | OID_SCRYPT | OID_AES_128_CBC | OID_AES_192_CBC | OID_AES_256_CBC => {}
+            o => return Err(Error::UnsupportedEncryption(o)),
+        }
+    }
+
+    let der = algorithm.to_der()?;
+    let scheme = EncryptionScheme::from_der(&der)?;
+
+    if let Some(params) = scheme.pbes2().and_then(|p| p.kdf.pbkdf2()) {
+        check_iterations(params.iteration_count, what)?;
+    }
+
+    scheme
+        .decrypt(passphrase, ciphertext)
+        .map_err(|_| Error::Decrypt(what))
+}
+
+fn decrypt(
+    algorithm: &AlgorithmIdentifierOwned,
+    passphrase: &str,
+    ciphertext: &[u8],
+    what: &'static str,
+) -> Result<Vec<u8>> {
+    if algorithm.oid == OID_PBES2 {
+        decrypt_pbes2(algorithm, passphrase, ciphertext, what)
+    } else if let Some(params) = &algorithm.parameters {
+        decrypt_pkcs12_pbe(algorithm.oid, params, passphrase, ciphertext, what)
+    } else {
+        Err(Error::UnsupportedEncryption(algorithm.oid))
+    }
+}
+
+/// Verify the password integrity MAC over the authenticated safe.
+fn verify_mac(mac_data: &Any, passphrase: &str, data: &[u8]) -> Result<()> {
+    let fields = children(mac_data, Tag::Sequence)?;
+    let (digest_info, salt, iterations) = match fields.as_slice() {
+        [d, s] => (d, s, 1),
+        [d, s, i] => (d, s, i.decode_as::<u32>()?),
+        _ => return Err(Error::InvalidStructure("Invalid MAC data")),
+    };
+    let iterations = check_iterations(iterations, "MAC")?;
+    let salt = salt.decode_as::<OctetString>()?;
+
+    let digest_fields = children(digest_info, Tag::Sequence)?;
+    let [algorithm, expected] = digest_fields.as_slice() else {
+        return Err(Error::InvalidStructure("Invalid MAC digest info"));
+    };
+    let algorithm = algorithm.decode_as::<AlgorithmIdentifierOwned>()?;
+    let expected = expected.decode_as::<OctetString>()?;
+
+    let (digest_algorithm, hmac_algorithm) = match algorithm.oid {
+        OID_SHA1 => (
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        ),
+        OID_SHA256 => (&digest::SHA256, hmac::HMAC_SHA256),
+        OID_SHA384 => (&digest::SHA384, hmac::HMAC_SHA384),
+        OID_SHA512 => (&digest::SHA512, hmac::HMAC_SHA512),
+        o => return Err(Error::UnsupportedMac(o)),
+    };
+
+    let key = pkcs12_kdf(
+        digest_algorithm,
+        &bmp_passphrase(passphrase),
+        salt.as_bytes(),
+        iterations,
+        KDF_ID_MAC,
+        digest_algorithm.output_len,
+    );
+    let key = hmac::Key::new(hmac_algorithm, &key);
+
+    hmac::verify(&key, data, expected.as_bytes()).map_err(|_| Error::MacMismatch)
+}
+
+/// Parse the `friendlyName` and `localKeyId` bag attributes.
+fn parse_attributes(attributes: &Any) -> Result<(Option<String>, Option<Vec<u8>>)> {
+    let mut friendly_name = None;
+    let mut local_key_id = None;
+
+    for attribute in children(attributes, Tag::Set)? {
+        let fields = children(&attribute, Tag::Sequence)?;
+        let [oid, values] = fields.as_slice() else {
+            return Err(Error::InvalidStructure("Invalid bag attribute"));
+        };
+        let oid = oid.decode_as::<ObjectIdentifier>()?;
+        let Some(value) = children(values, Tag::Set)?.into_iter().next() else {
+            continue;
+        };
+
+        match oid {
+            OID_FRIENDLY_NAME => {
+                if value.tag() != Tag::BmpString || value.value().len() % 2 != 0 {
+                    return Err(Error::InvalidStructure("Invalid friendly name"));
+                }
+
+                let units = value
+                    .value()
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect::<Vec<_>>();
+                let name = String::from_utf16(&units)
+                    .map_err(|_| Error::InvalidStructure("Invalid friendly name"))?;
+
+                friendly_name = Some(name);
+            }
+            OID_LOCAL_KEY_ID => {
+                local_key_id = Some(value.decode_as::<OctetString>()?.into_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    Ok((friendly_name, local_key_id))
+}
+
+fn load_key(der: &[u8]) -> Result<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs8_der(der).map_err(Error::LoadKey)
+}
+
+impl Pkcs12 {
+    /// Parse a DER-encoded PKCS#12 bundle. If the bundle has a MAC, it is
+    /// verified before anything is decrypted.
+    pub fn parse(data: &[u8], passphrase: &str) -> Result<Self> {
+        let pfx = children(&Any::from_der(data)?, Tag::Sequence)?;
+        let (version, auth_safe, mac_data) = match pfx.as_slice() {
+            [v, a] => (v, a, None),
+            [v, a, m] => (v, a, Some(m)),
+            _ => return Err(Error::InvalidStructure("Invalid PFX")),
+        };
+
+        let version = version.decode_as::<u8>()?;
+        if version != PKCS12_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let auth_safe = auth_safe.decode_as::<ContentInfo>()?;
+        if auth_safe.content_type != OID_DATA {
+            return Err(Error::UnsupportedContentType(auth_safe.content_type));
+        }
+        let auth_safe_data = auth_safe.content.decode_as::<OctetString>()?;
+
+        if let Some(m) = mac_data {
+            verify_mac(m, passphrase, auth_safe_data.as_bytes())?;
+        }
+
+        let mut result = Self::default();
+
+        for content_info in Vec::<ContentInfo>::from_der(auth_safe_data.as_bytes())? {
+            let safe_contents = match content_info.content_type {
+                OID_DATA => content_info
+                    .content
+                    .decode_as::<OctetString>()?
+                    .into_bytes(),
+                OID_ENCRYPTED_DATA => {
+                    let encrypted = content_info.content.decode_as::<EncryptedData>()?;
+                    let info = encrypted.enc_content_info;
+                    let ciphertext = info
+                        .encrypted_content
+                        .ok_or(Error::InvalidStructure("Missing encrypted content"))?;
+
+                    decrypt(
+                        &info.content_enc_alg,
+                        passphrase,
+                        ciphertext.as_bytes(),
+                        "encrypted data",
+                    )?
+                }
+                t => return Err(Error::UnsupportedContentType(t)),
+            };
+
+            result.parse_safe_contents(&safe_contents, passphrase)?;
+        }
+
+        Ok(result)
+    }
+
+    fn parse_safe_contents(&mut self, data: &[u8], passphrase: &str) -> Result<()> {
+        for bag in children(&Any::from_der(data)?, Tag::Sequence)? {
+            let fields = children(&bag, Tag::Sequence)?;
+            let (bag_id, bag_value, attributes) = match fields.as_slice() {
+                [i, v] => (i, v, None),
+                [i, v, a] => (i, v, Some(a)),
+                _ => return Err(Error::InvalidStructure("Invalid safe bag")),
+            };
+            let bag_id = bag_id.decode_as::<ObjectIdentifier>()?;
+            let bag_value = explicit_0(bag_value)?;
+            let (friendly_name, local_key_id) = match attributes {
+                Some(a) => parse_attributes(a)?,
+                None => (None, None),
+            };
+
+            match bag_id {
+                OID_KEY_BAG => {
+                    self.keys.push(Pkcs12Bag {
+                        friendly_name,
+                        local_key_id,
+                        value: load_key(&bag_value.to_der()?)?,
+                    });
+                }
+                OID_SHROUDED_KEY_BAG => {
+                    let key_fields = children(&bag_value, Tag::Sequence)?;
+                    let [algorithm, ciphertext] = key_fields.as_slice() else {
+                        return Err(Error::InvalidStructure("Invalid shrouded key bag"));
+                    };
+                    let algorithm = algorithm.decode_as::<AlgorithmIdentifierOwned>()?;
+                    let ciphertext = ciphertext.decode_as::<OctetString>()?;
+                    let der =
+                        decrypt(&algorithm, passphrase, ciphertext.as_bytes(), "private key")?;
+
+                    self.keys.push(Pkcs12Bag {
+                        friendly_name,
+                        local_key_id,
+                        // A wrong passphrase can still produce valid padding
+                        // by chance when there is no MAC.
+                        value: load_key(&der).map_err(|_| Error::Decrypt("private key"))?,
+                    });
+                }
+                OID_CERT_BAG => {
+                    let cert_fields = children(&bag_value, Tag::Sequence)?;
+                    let [cert_id, cert_value] = cert_fields.as_slice() else {
+                        return Err(Error::InvalidStructure("Invalid certificate bag"));
+                    };
+
+                    // SDSI certificates are not supported.
+                    if cert_id.decode_as::<ObjectIdentifier>()? != OID_X509_CERTIFICATE {
+                        continue;
+                    }
+
+                    let der = explicit_0(cert_value)?.decode_as::<OctetString>()?;
+
+                    self.certs.push(Pkcs12Bag {
+                        friendly_name,
+                        local_key_id,
+                        value: Certificate::from_der(der.as_bytes())?,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the private key to use. If there is more than one key, `alias` must
+    /// match the friendly name of one of them. Otherwise, `alias` is ignored.
+    pub fn key(&self, alias: Option<&str>) -> Result<&RsaPrivateKey> {
+        select(&self.keys, alias, "private keys").map(|b| &b.value)
+    }
+
+    /// Get the certificate to use. If the bundle has exactly one private key,
+    /// this is the certificate for that key. Otherwise, this works like
+    /// [`Self::key()`].
+    pub fn cert(&self, alias: Option<&str>) -> Result<&Certificate> {
+        if let [key] = self.keys.as_slice() {
+            if let Some(bag) = self
+                .certs
+                .iter()
+                .find(|c| c.local_key_id.is_some() && c.local_key_id == key.local_key_id)
+            {
+                return Ok(&bag.value);
+            }
+        }
+
+        select(&self.certs, alias, "certificates").map(|b| &b.value)
+    }
+}
+
+fn select<'a, T>(
+    bags: &'a [Pkcs12Bag<T>],
+    alias: Option<&str>,
+    what: &'static str,
+) -> Result<&'a Pkcs12Bag<T>> {
+    match (bags, alias) {
+        ([], _) => Err(Error::MissingEntry(what)),
+        ([bag], _) => Ok(bag),
+        (_, Some(a)) => bags
+            .iter()
+            .find(|b| b.friendly_name.as_deref() == Some(a))
+            .ok_or_else(|| Error::AliasNotFound(what, a.to_owned())),
+        (_, None) => Err(Error::AmbiguousEntry(
+            what,
+            bags.iter()
+                .filter_map(|b| b.friendly_name.clone())
+                .collect(),
+        )),
+    }
+}
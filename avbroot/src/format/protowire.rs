@@ -0,0 +1,270 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal protobuf wire format parser for preserving fields that are not in
+//! avbroot's copies of the `.proto` files. quick-protobuf silently discards
+//! unknown fields while parsing, so a message that is parsed and serialized
+//! again would otherwise lose them.
+
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_I64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+const WIRE_I32: u8 = 5;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Truncated protobuf field at offset {0}")]
+    Truncated(usize),
+    #[error("Invalid protobuf varint at offset {0}")]
+    InvalidVarint(usize),
+    #[error("Unsupported protobuf wire type {0} at offset {1}")]
+    UnsupportedWireType(u8, usize),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// The fields of a message that are known to the generated protobuf code.
+pub struct Schema {
+    /// Known field numbers and, for fields that are messages, their schema.
+    pub fields: &'static [(u32, Option<&'static Schema>)],
+}
+
+impl Schema {
+    fn lookup(&self, number: u32) -> Option<Option<&'static Schema>> {
+        self.fields
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, s)| *s)
+    }
+}
+
+struct Field<'a> {
+    number: u32,
+    wire_type: u8,
+    /// The entire field, including the tag.
+    raw: &'a [u8],
+    /// The value, excluding the length prefix for length-delimited fields.
+    value: &'a [u8],
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let start = *pos;
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or(Error::Truncated(start))?;
+        *pos += 1;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::InvalidVarint(start))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, size: u64, start: usize) -> Result<&'a [u8]> {
+    let end = usize::try_from(size)
+        .ok()
+        .and_then(|s| pos.checked_add(s))
+        .filter(|e| *e <= data.len())
+        .ok_or(Error::Truncated(start))?;
+    let value = &data[*pos..end];
+    *pos = end;
+
+    Ok(value)
+}
+
+/// Split a serialized message into its fields without interpreting them.
+fn parse_fields(data: &[u8]) -> Result<Vec<Field<'_>>> {
+    let mut fields = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let start = pos;
+        let tag = read_varint(data, &mut pos)?;
+        let number = u32::try_from(tag >> 3).map_err(|_| Error::InvalidVarint(start))?;
+        let wire_type = (tag & 7) as u8;
+
+        let value = match wire_type {
+            WIRE_VARINT => {
+                let value_start = pos;
+                read_varint(data, &mut pos)?;
+                &data[value_start..pos]
+            }
+            WIRE_I64 => read_bytes(data, &mut pos, 8, start)?,
+            WIRE_LEN => {
+                let size = read_varint(data, &mut pos)?;
+                read_bytes(data, &mut pos, size, start)?
+            }
+            WIRE_I32 => read_bytes(data, &mut pos, 4, start)?,
+            // Groups have been deprecated since proto2.
+            t => return Err(Error::UnsupportedWireType(t, start)),
+        };
+
+        fields.push(Field {
+            number,
+            wire_type,
+            raw: &data[start..pos],
+            value,
+        });
+    }
+
+    Ok(fields)
+}
+
+/// Get the index of each field among the previous fields with the same number.
+fn occurrences<'a>(fields: &'a [Field<'a>]) -> impl Iterator<Item = (usize, &'a Field<'a>)> {
+    let mut counts = BTreeMap::<u32, usize>::new();
+
+    fields.iter().map(move |f| {
+        let count = counts.entry(f.number).or_default();
+        let index = *count;
+        *count += 1;
+
+        (index, f)
+    })
+}
+
+/// The fields of a message, including nested messages, that are not known to
+/// the generated protobuf code. These are extracted from the raw message
+/// separately and merged back in when the message is serialized again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnknownFields {
+    /// Raw unknown fields of this message, including their tags.
+    raw: Vec<u8>,
+    /// Unknown fields of nested messages, keyed by the field number and the
+    /// index of the occurrence for repeated fields.
+    nested: BTreeMap<(u32, usize), UnknownFields>,
+}
+
+impl UnknownFields {
+    /// Extract all fields from the serialized message `data` that are not
+    /// listed in `schema`.
+    pub fn extract(data: &[u8], schema: &Schema) -> Result<Self> {
+        let mut result = Self::default();
+        let fields = parse_fields(data)?;
+
+        for (index, field) in occurrences(&fields) {
+            match schema.lookup(field.number) {
+                None => result.raw.extend_from_slice(field.raw),
+                Some(Some(nested)) if field.wire_type == WIRE_LEN => {
+                    let unknown = Self::extract(field.value, nested)?;
+                    if !unknown.is_empty() {
+                        result.nested.insert((field.number, index), unknown);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Merge the unknown fields into the serialized message `data`. Repeated
+    /// message fields are matched up by their index, so they must be in the
+    /// same order as when the unknown fields were extracted. Like with the
+    /// official protobuf library, unknown fields are written after the known
+    /// fields of each message.
+    pub fn merge(&self, data: &[u8], schema: &Schema) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            return Ok(data.to_vec());
+        }
+
+        let mut result = Vec::with_capacity(data.len() + self.raw.len());
+        let fields = parse_fields(data)?;
+
+        for (index, field) in occurrences(&fields) {
+            match (
+                self.nested.get(&(field.number, index)),
+                schema.lookup(field.number),
+            ) {
+                (Some(unknown), Some(Some(nested))) if field.wire_type == WIRE_LEN => {
+                    let value = unknown.merge(field.value, nested)?;
+
+                    write_varint(
+                        &mut result,
+                        (u64::from(field.number) << 3) | u64::from(WIRE_LEN),
+                    );
+                    write_varint(&mut result, value.len() as u64);
+                    result.extend_from_slice(&value);
+                }
+                _ => result.extend_from_slice(field.raw),
+            }
+        }
+
+        result.extend_from_slice(&self.raw);
+
+        Ok(result)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty() && self.nested.is_empty()
+    }
+
+    /// Get the unknown fields of the `index`th occurrence of the nested message
+    /// field `number`.
+    pub fn nested_mut(&mut self, number: u32, index: usize) -> Option<&mut Self> {
+        self.nested.get_mut(&(number, index))
+    }
+
+    /// Remove and return the unknown fields of the `index`th occurrence of the
+    /// nested message field `number`.
+    pub fn take_nested(&mut self, number: u32, index: usize) -> Option<Self> {
+        self.nested.remove(&(number, index))
+    }
+
+    /// Set the unknown fields of the `index`th occurrence of the nested message
+    /// field `number`, replacing any existing unknown fields.
+    pub fn insert_nested(&mut self, number: u32, index: usize, fields: Self) {
+        if fields.is_empty() {
+            self.nested.remove(&(number, index));
+        } else {
+            self.nested.insert((number, index), fields);
+        }
+    }
+
+    /// Discard the unknown fields of every occurrence of the nested message
+    /// field `number`.
+    pub fn remove_nested(&mut self, number: u32) {
+        self.nested.retain(|(n, _), _| *n != number);
+    }
+
+    /// Get the path of each unknown field, like `13[2].20` for field 20 of the
+    /// third occurrence of the nested message field 13.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = vec![];
+        self.collect_paths("", &mut paths);
+        paths
+    }
+
+    fn collect_paths(&self, prefix: &str, paths: &mut Vec<String>) {
+        // The raw data was already validated during extraction.
+        for field in parse_fields(&self.raw).unwrap_or_default() {
+            let path = format!("{prefix}{}", field.number);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+
+        for ((number, index), unknown) in &self.nested {
+            unknown.collect_paths(&format!("{prefix}{number}[{index}]."), paths);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Support for the legacy Qualcomm device tree table (`QCDT`) format, as
+//! created by `dtbTool`. Older Qualcomm devices use this instead of the
+//! standard dtbo format to ship multiple device tree blobs in `dt.img`, which
+//! the bootloader selects from based on the platform and variant IDs. The image
+//! contains a header, a table of entries terminated by a zero word, and the
+//! page-aligned device tree blobs. All integers are little endian.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::padding,
+    stream::{FromReader, ToWriter, WriteZerosExt},
+    util::NumBytes,
+};
+
+pub const QCDT_MAGIC: [u8; 4] = *b"QCDT";
+
+pub const VERSION_MIN: u32 = 1;
+pub const VERSION_MAX: u32 = 3;
+
+/// Page size used when the image has no entries to detect it from. This is
+/// `dtbTool`'s default.
+pub const DEFAULT_PAGE_SIZE: u32 = 2048;
+
+const HEADER_SIZE: u32 = 12;
+
+/// Number of PMIC revision fields in a version 3 entry.
+const PMIC_REV_FIELDS: usize = 4;
+
+/// Arbitrary limit to avoid allocating excessive amounts of memory when reading
+/// invalid data.
+const MAX_ENTRIES: u32 = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid QCDT magic: {0:?}")]
+    InvalidMagic([u8; 4]),
+    #[error("Unsupported QCDT version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Failed to read {0:?} field")]
+    ReadFieldError(&'static str, #[source] io::Error),
+    #[error("{0:?} field: invalid value: {1}")]
+    InvalidFieldValue(&'static str, u32),
+    #[error("{0:?} field exceeds integer bounds")]
+    IntegerTooLarge(&'static str),
+    #[error("Entry table is not terminated")]
+    MissingTerminator,
+    #[error("Entry #{0} data is out of bounds")]
+    EntryOutOfBounds(usize),
+    #[error("Entry #{0} is not valid for version {1}: {2}")]
+    InvalidEntry(usize, u32, &'static str),
+    #[error("No entry for platform ID {0:#x} and variant ID {1:#x}")]
+    EntryNotFound(u32, u32),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Size of an entry in the table for the given version.
+fn entry_size(version: u32) -> u32 {
+    match version {
+        1 => 20,
+        2 => 24,
+        _ => 40,
+    }
+}
+
+/// A single device tree entry. Multiple entries may refer to the same device
+/// tree blob in the raw image. They are read into separate copies of the data
+/// and identical blobs are deduplicated again when writing.
+#[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct QcdtEntry {
+    pub platform_id: u32,
+    pub variant_id: u32,
+    /// Only present in version 2 and newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtype_id: Option<u32>,
+    pub soc_rev: u32,
+    /// Only present in version 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pmic_rev: Option<[u32; PMIC_REV_FIELDS]>,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for QcdtEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QcdtEntry")
+            .field("platform_id", &self.platform_id)
+            .field("variant_id", &self.variant_id)
+            .field("subtype_id", &self.subtype_id)
+            .field("soc_rev", &self.soc_rev)
+            .field("pmic_rev", &self.pmic_rev)
+            .field("data", &NumBytes(self.data.len()))
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct QcdtImage {
+    pub version: u32,
+    /// Alignment of the entry table and each device tree blob. This is not
+    /// stored in the image, so when reading, it is set to the largest power
+    /// of two that all blob offsets and the padded end of the file are aligned
+    /// to. That always produces the same layout as the page size that the image
+    /// was created with.
+    pub page_size: u32,
+    pub entries: Vec<QcdtEntry>,
+}
+
+impl QcdtImage {
+    /// Replace the device tree blob of every entry with the specified platform
+    /// and variant IDs. The other fields are kept. Returns the number of
+    /// entries that were replaced, which may be more than one if the device
+    /// tree is used for multiple SoC revisions.
+    pub fn replace_entries(
+        &mut self,
+        platform_id: u32,
+        variant_id: u32,
+        data: &[u8],
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        for entry in &mut self.entries {
+            if entry.platform_id == platform_id && entry.variant_id == variant_id {
+                entry.data = data.to_vec();
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return Err(Error::EntryNotFound(platform_id, variant_id));
+        }
+
+        Ok(count)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(VERSION_MIN..=VERSION_MAX).contains(&self.version) {
+            return Err(Error::UnsupportedVersion(self.version));
+        } else if self.page_size == 0 {
+            return Err(Error::InvalidFieldValue("page_size", self.page_size));
+        }
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.subtype_id.is_some() != (self.version >= 2) {
+                return Err(Error::InvalidEntry(
+                    i,
+                    self.version,
+                    "subtype_id must be set in version 2 and newer",
+                ));
+            } else if entry.pmic_rev.is_some() != (self.version >= 3) {
+                return Err(Error::InvalidEntry(
+                    i,
+                    self.version,
+                    "pmic_rev must be set in version 3 and unset otherwise",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for QcdtImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "QCDT image v{}", self.version)?;
+        writeln!(f, "- Page size: {}", self.page_size)?;
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            writeln!(f, "- Entry #{i}:")?;
+            writeln!(f, "  - Platform ID: {:#010x}", entry.platform_id)?;
+            writeln!(f, "  - Variant ID:  {:#010x}", entry.variant_id)?;
+            if let Some(subtype_id) = entry.subtype_id {
+                writeln!(f, "  - Subtype ID:  {subtype_id:#010x}")?;
+            }
+            writeln!(f, "  - SoC rev:     {:#010x}", entry.soc_rev)?;
+            if let Some(pmic_rev) = &entry.pmic_rev {
+                writeln!(f, "  - PMIC rev:    {pmic_rev:x?}")?;
+            }
+            writeln!(f, "  - Size:        {}", entry.data.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether writing the blobs at `locations` with the specified alignment would
+/// place them at the same offsets and produce a file of the same size.
+fn layout_matches(locations: &[(u32, u32)], table_end: u32, file_size: u64, align: u32) -> bool {
+    let mut blobs = locations.to_vec();
+    blobs.sort_unstable();
+    blobs.dedup_by_key(|(o, _)| *o);
+
+    let mut pos = u64::from(table_end);
+
+    for (offset, size) in blobs {
+        if padding::round(pos, align.into()) != Some(offset.into()) {
+            return false;
+        }
+
+        pos = u64::from(offset) + u64::from(size);
+    }
+
+    padding::round(pos, align.into()) == Some(file_size)
+}
+
+impl<R: Read + Seek> FromReader<R> for QcdtImage {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let read_u32 = |reader: &mut R, field| {
+            reader
+                .read_u32::<LittleEndian>()
+                .map_err(|e| Error::ReadFieldError(field, e))
+        };
+
+        let mut magic = [0u8; 4];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|e| Error::ReadFieldError("magic", e))?;
+        if magic != QCDT_MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+
+        let version = read_u32(&mut reader, "version")?;
+        let entry_count = read_u32(&mut reader, "num_entries")?;
+
+        if !(VERSION_MIN..=VERSION_MAX).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        } else if entry_count > MAX_ENTRIES {
+            return Err(Error::InvalidFieldValue("num_entries", entry_count));
+        }
+
+        let mut locations = vec![];
+        let mut entries = vec![];
+
+        for _ in 0..entry_count {
+            let platform_id = read_u32(&mut reader, "platform_id")?;
+            let variant_id = read_u32(&mut reader, "variant_id")?;
+            let subtype_id = if version >= 2 {
+                Some(read_u32(&mut reader, "subtype_id")?)
+            } else {
+                None
+            };
+            let soc_rev = read_u32(&mut reader, "soc_rev")?;
+            let pmic_rev = if version >= 3 {
+                let mut pmic_rev = [0u32; PMIC_REV_FIELDS];
+                for value in &mut pmic_rev {
+                    *value = read_u32(&mut reader, "pmic_rev")?;
+                }
+                Some(pmic_rev)
+            } else {
+                None
+            };
+            let offset = read_u32(&mut reader, "offset")?;
+            let size = read_u32(&mut reader, "size")?;
+
+            locations.push((offset, size));
+            entries.push(QcdtEntry {
+                platform_id,
+                variant_id,
+                subtype_id,
+                soc_rev,
+                pmic_rev,
+                data: vec![],
+            });
+        }
+
+        if read_u32(&mut reader, "terminator")? != 0 {
+            return Err(Error::MissingTerminator);
+        }
+
+        let table_end = HEADER_SIZE + entry_count * entry_size(version) + 4;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+
+        for (i, (entry, (offset, size))) in entries.iter_mut().zip(&locations).enumerate() {
+            if *offset < table_end || u64::from(*offset) + u64::from(*size) > file_size {
+                return Err(Error::EntryOutOfBounds(i));
+            }
+
+            reader.seek(SeekFrom::Start(u64::from(*offset)))?;
+
+            entry.data.resize(*size as usize, 0);
+            reader
+                .read_exact(&mut entry.data)
+                .map_err(|e| Error::ReadFieldError("dtb", e))?;
+        }
+
+        // Offsets are never 0 because the table comes first.
+        let mut page_size = locations
+            .iter()
+            .map(|(o, _)| 1 << o.trailing_zeros())
+            .min()
+            .unwrap_or(DEFAULT_PAGE_SIZE);
+
+        // If every blob happens to be aligned to a larger power of two than the
+        // page size, the padding at the end of the file is the only thing that
+        // reveals the real page size.
+        let end_align = 1 << file_size.trailing_zeros().min(31);
+        if end_align < page_size && layout_matches(&locations, table_end, file_size, end_align) {
+            page_size = end_align;
+        }
+
+        Ok(Self {
+            version,
+            page_size,
+            entries,
+        })
+    }
+}
+
+impl<W: Write> ToWriter<W> for QcdtImage {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        self.validate()?;
+
+        let entry_count = self
+            .entries
+            .len()
+            .to_u32()
+            .filter(|n| *n <= MAX_ENTRIES)
+            .ok_or(Error::IntegerTooLarge("num_entries"))?;
+        let align = |offset: u64| {
+            padding::round(offset, self.page_size.into()).ok_or(Error::IntegerTooLarge("offset"))
+        };
+
+        // Like dtbTool, entries with identical device trees share one copy of
+        // the data.
+        let table_end = u64::from(HEADER_SIZE)
+            + u64::from(entry_count) * u64::from(entry_size(self.version))
+            + 4;
+        let mut offset = align(table_end)?;
+        let mut offsets = HashMap::<&[u8], u32>::new();
+        let mut blobs = vec![];
+        let mut locations = vec![];
+
+        for entry in &self.entries {
+            let size = entry
+                .data
+                .len()
+                .to_u32()
+                .ok_or(Error::IntegerTooLarge("size"))?;
+
+            let dtb_offset = match offsets.get(entry.data.as_slice()) {
+                Some(o) => *o,
+                None => {
+                    let o = offset.to_u32().ok_or(Error::IntegerTooLarge("offset"))?;
+                    offsets.insert(&entry.data, o);
+                    blobs.push(&entry.data);
+                    offset = align(offset + u64::from(size))?;
+                    o
+                }
+            };
+
+            locations.push((dtb_offset, size));
+        }
+
+        writer.write_all(&QCDT_MAGIC)?;
+        writer.write_u32::<LittleEndian>(self.version)?;
+        writer.write_u32::<LittleEndian>(entry_count)?;
+
+        for (entry, (dtb_offset, size)) in self.entries.iter().zip(&locations) {
+            writer.write_u32::<LittleEndian>(entry.platform_id)?;
+            writer.write_u32::<LittleEndian>(entry.variant_id)?;
+            if let Some(subtype_id) = entry.subtype_id {
+                writer.write_u32::<LittleEndian>(subtype_id)?;
+            }
+            writer.write_u32::<LittleEndian>(entry.soc_rev)?;
+            for value in entry.pmic_rev.iter().flatten() {
+                writer.write_u32::<LittleEndian>(*value)?;
+            }
+            writer.write_u32::<LittleEndian>(*dtb_offset)?;
+            writer.write_u32::<LittleEndian>(*size)?;
+        }
+
+        writer.write_u32::<LittleEndian>(0)?;
+
+        let mut pos = table_end;
+
+        for blob in blobs {
+            let dtb_offset = align(pos)?;
+            writer.write_zeros_exact(dtb_offset - pos)?;
+            writer.write_all(blob)?;
+            pos = dtb_offset + blob.len() as u64;
+        }
+
+        writer.write_zeros_exact(align(pos)? - pos)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Reader and writer for Android sparse images, as produced by `img2simg` and
+//! libsparse.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use thiserror::Error;
+
+use crate::stream::{FromReader, ReadDiscardExt, TeeWriter, ToWriter, WriteZerosExt};
+
+pub const SPARSE_MAGIC: u32 = 0xed26ff3a;
+
+pub const MAJOR_VERSION: u16 = 1;
+
+pub const CHUNK_TYPE_RAW: u16 = 0xcac1;
+pub const CHUNK_TYPE_FILL: u16 = 0xcac2;
+pub const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+pub const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+const HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unknown magic: {0:#010x}")]
+    UnknownMagic(u32),
+    #[error("Unsupported sparse image version: {0}.{1}")]
+    UnsupportedVersion(u16, u16),
+    #[error("{0:?} field: invalid value: {1}")]
+    InvalidFieldValue(&'static str, u32),
+    #[error("Chunk #{0} has unknown type: {1:#06x}")]
+    UnknownChunkType(u32, u16),
+    #[error("Chunk #{0} has invalid size: {1}")]
+    InvalidChunkSize(u32, u32),
+    #[error("Expected {0} blocks, but chunks contain {1} blocks")]
+    BlockCountMismatch(u32, u64),
+    #[error("Expected CRC32 {0:08x}, but have {1:08x}")]
+    CrcMismatch(u32, u32),
+    #[error("Image has too many {0}-byte blocks")]
+    TooManyBlocks(u32),
+    #[error("Image size {0} is not a multiple of the {1}-byte block size")]
+    UnalignedSize(u64, u32),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub file_header_size: u16,
+    pub chunk_header_size: u16,
+    pub block_size: u32,
+    pub num_blocks: u32,
+    pub num_chunks: u32,
+    /// CRC32 of the entire raw image. This is 0 if the producer did not record
+    /// one.
+    pub image_crc32: u32,
+}
+
+impl Header {
+    /// Size of the raw image after unsparsing.
+    pub fn raw_size(&self) -> u64 {
+        u64::from(self.num_blocks) * u64::from(self.block_size)
+    }
+}
+
+impl<R: Read> FromReader<R> for Header {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != SPARSE_MAGIC {
+            return Err(Error::UnknownMagic(magic));
+        }
+
+        let major_version = reader.read_u16::<LittleEndian>()?;
+        let minor_version = reader.read_u16::<LittleEndian>()?;
+        if major_version != MAJOR_VERSION {
+            return Err(Error::UnsupportedVersion(major_version, minor_version));
+        }
+
+        let file_header_size = reader.read_u16::<LittleEndian>()?;
+        if file_header_size < HEADER_SIZE {
+            return Err(Error::InvalidFieldValue(
+                "file_hdr_sz",
+                file_header_size.into(),
+            ));
+        }
+
+        let chunk_header_size = reader.read_u16::<LittleEndian>()?;
+        if chunk_header_size < CHUNK_HEADER_SIZE {
+            return Err(Error::InvalidFieldValue(
+                "chunk_hdr_sz",
+                chunk_header_size.into(),
+            ));
+        }
+
+        let block_size = reader.read_u32::<LittleEndian>()?;
+        if block_size == 0 || block_size % 4 != 0 {
+            return Err(Error::InvalidFieldValue("blk_sz", block_size));
+        }
+
+        let num_blocks = reader.read_u32::<LittleEndian>()?;
+        let num_chunks = reader.read_u32::<LittleEndian>()?;
+        let image_crc32 = reader.read_u32::<LittleEndian>()?;
+
+        // Newer producers may append fields that we don't know about.
+        reader.read_discard_exact((file_header_size - HEADER_SIZE).into())?;
+
+        Ok(Self {
+            major_version,
+            minor_version,
+            file_header_size,
+            chunk_header_size,
+            block_size,
+            num_blocks,
+            num_chunks,
+            image_crc32,
+        })
+    }
+}
+
+impl<W: Write> ToWriter<W> for Header {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        if self.file_header_size < HEADER_SIZE {
+            return Err(Error::InvalidFieldValue(
+                "file_hdr_sz",
+                self.file_header_size.into(),
+            ));
+        }
+
+        writer.write_u32::<LittleEndian>(SPARSE_MAGIC)?;
+        writer.write_u16::<LittleEndian>(self.major_version)?;
+        writer.write_u16::<LittleEndian>(self.minor_version)?;
+        writer.write_u16::<LittleEndian>(self.file_header_size)?;
+        writer.write_u16::<LittleEndian>(self.chunk_header_size)?;
+        writer.write_u32::<LittleEndian>(self.block_size)?;
+        writer.write_u32::<LittleEndian>(self.num_blocks)?;
+        writer.write_u32::<LittleEndian>(self.num_chunks)?;
+        writer.write_u32::<LittleEndian>(self.image_crc32)?;
+        writer.write_zeros_exact((self.file_header_size - HEADER_SIZE).into())?;
+
+        Ok(())
+    }
+}
+
+/// Size of the headers in a sparse image built by [`raw_segment()`].
+pub const RAW_SEGMENT_OVERHEAD: u64 = HEADER_SIZE as u64 + 3 * CHUNK_HEADER_SIZE as u64;
+
+fn write_chunk_header(
+    mut writer: impl Write,
+    chunk_type: u16,
+    blocks: u32,
+    data_size: u32,
+) -> io::Result<()> {
+    writer.write_u16::<LittleEndian>(chunk_type)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u32::<LittleEndian>(blocks)?;
+    writer.write_u32::<LittleEndian>(u32::from(CHUNK_HEADER_SIZE) + data_size)?;
+
+    Ok(())
+}
+
+/// Build the headers of a sparse image that covers `total_blocks` blocks, but
+/// only contains raw data for the `num_blocks` blocks starting at block
+/// `start`. The remaining blocks are don't care chunks, so they are left as-is
+/// when the image is flashed. This is how fastboot splits images that don't fit
+/// in the device's download buffer. Returns the data that goes before and after
+/// the raw data.
+pub fn raw_segment(
+    block_size: u32,
+    total_blocks: u32,
+    start: u32,
+    num_blocks: u32,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    if block_size == 0 || block_size % 4 != 0 {
+        return Err(Error::InvalidFieldValue("blk_sz", block_size));
+    }
+
+    let end = u64::from(start) + u64::from(num_blocks);
+    if end > u64::from(total_blocks) {
+        return Err(Error::BlockCountMismatch(total_blocks, end));
+    }
+
+    let data_size = num_blocks
+        .checked_mul(block_size)
+        .filter(|s| *s <= u32::MAX - u32::from(CHUNK_HEADER_SIZE))
+        .ok_or(Error::TooManyBlocks(block_size))?;
+    let remain = total_blocks - start - num_blocks;
+
+    let header = Header {
+        major_version: MAJOR_VERSION,
+        minor_version: 0,
+        file_header_size: HEADER_SIZE,
+        chunk_header_size: CHUNK_HEADER_SIZE,
+        block_size,
+        num_blocks: total_blocks,
+        num_chunks: 1 + u32::from(start > 0) + u32::from(remain > 0),
+        image_crc32: 0,
+    };
+
+    let mut before = vec![];
+    let mut after = vec![];
+
+    header.to_writer(&mut before)?;
+    if start > 0 {
+        write_chunk_header(&mut before, CHUNK_TYPE_DONT_CARE, start, 0)?;
+    }
+    write_chunk_header(&mut before, CHUNK_TYPE_RAW, num_blocks, data_size)?;
+    if remain > 0 {
+        write_chunk_header(&mut after, CHUNK_TYPE_DONT_CARE, remain, 0)?;
+    }
+
+    Ok((before, after))
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Raw,
+    Fill([u8; 4]),
+    DontCare,
+}
+
+/// A reader that unsparses an Android sparse image on the fly. This only
+/// requires [`Read`], so the data can be streamed.
+///
+/// Errors relating to the sparse format are returned as [`io::Error`] with
+/// [`io::ErrorKind::InvalidData`] and wrap an [`Error`].
+pub struct SparseReader<R: Read> {
+    inner: R,
+    header: Header,
+    crc: Option<Hasher>,
+    chunk: Chunk,
+    /// Bytes of the current chunk that were already read.
+    chunk_pos: u64,
+    /// Bytes remaining in the current chunk.
+    chunk_remain: u64,
+    chunks_read: u32,
+    blocks_read: u64,
+    done: bool,
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Create a reader that does not validate any CRC32 checksums.
+    pub fn new(inner: R) -> Result<Self> {
+        Self::new_internal(inner, false)
+    }
+
+    /// Create a reader that computes the CRC32 of the raw image data as it is
+    /// read. It is compared against every CRC32 chunk and, once the last chunk
+    /// is reached, against [`Header::image_crc32`] if it is present. A mismatch
+    /// is reported as [`Error::CrcMismatch`].
+    pub fn with_crc_validation(inner: R) -> Result<Self> {
+        Self::new_internal(inner, true)
+    }
+
+    fn new_internal(mut inner: R, validate_crc: bool) -> Result<Self> {
+        let header = Header::from_reader(&mut inner)?;
+
+        Ok(Self {
+            inner,
+            header,
+            crc: validate_crc.then(Hasher::new),
+            chunk: Chunk::DontCare,
+            chunk_pos: 0,
+            chunk_remain: 0,
+            chunks_read: 0,
+            blocks_read: 0,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn check_crc(&self, expected: u32) -> Result<()> {
+        if let Some(hasher) = &self.crc {
+            let actual = hasher.clone().finalize();
+            if actual != expected {
+                return Err(Error::CrcMismatch(expected, actual));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Advance to the next chunk that contains data. Returns false if there are
+    /// no more chunks.
+    fn next_chunk(&mut self) -> Result<bool> {
+        loop {
+            if self.chunks_read == self.header.num_chunks {
+                if self.blocks_read != u64::from(self.header.num_blocks) {
+                    return Err(Error::BlockCountMismatch(
+                        self.header.num_blocks,
+                        self.blocks_read,
+                    ));
+                }
+
+                // An image CRC of 0 means that there is none.
+                if self.header.image_crc32 != 0 {
+                    self.check_crc(self.header.image_crc32)?;
+                }
+
+                return Ok(false);
+            }
+
+            let index = self.chunks_read;
+            let chunk_type = self.inner.read_u16::<LittleEndian>()?;
+            let _reserved = self.inner.read_u16::<LittleEndian>()?;
+            let chunk_blocks = self.inner.read_u32::<LittleEndian>()?;
+            let total_size = self.inner.read_u32::<LittleEndian>()?;
+
+            self.inner
+                .read_discard_exact((self.header.chunk_header_size - CHUNK_HEADER_SIZE).into())?;
+            self.chunks_read += 1;
+
+            let data_size = total_size
+                .checked_sub(self.header.chunk_header_size.into())
+                .ok_or(Error::InvalidChunkSize(index, total_size))?;
+            let raw_size = u64::from(chunk_blocks) * u64::from(self.header.block_size);
+            let expected_data_size = match chunk_type {
+                CHUNK_TYPE_RAW => raw_size,
+                CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => 4,
+                CHUNK_TYPE_DONT_CARE => 0,
+                t => return Err(Error::UnknownChunkType(index, t)),
+            };
+
+            if u64::from(data_size) != expected_data_size {
+                return Err(Error::InvalidChunkSize(index, total_size));
+            }
+
+            self.chunk = match chunk_type {
+                CHUNK_TYPE_RAW => Chunk::Raw,
+                CHUNK_TYPE_FILL => {
+                    let mut pattern = [0u8; 4];
+                    self.inner.read_exact(&mut pattern)?;
+                    Chunk::Fill(pattern)
+                }
+                CHUNK_TYPE_DONT_CARE => Chunk::DontCare,
+                CHUNK_TYPE_CRC32 => {
+                    // This covers all raw data up to this point.
+                    let expected = self.inner.read_u32::<LittleEndian>()?;
+                    self.check_crc(expected)?;
+                    continue;
+                }
+                _ => unreachable!(),
+            };
+
+            self.blocks_read += u64::from(chunk_blocks);
+            self.chunk_pos = 0;
+            self.chunk_remain = raw_size;
+
+            if raw_size != 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn read_internal(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() || self.done {
+            return Ok(0);
+        }
+
+        if self.chunk_remain == 0 && !self.next_chunk()? {
+            self.done = true;
+            return Ok(0);
+        }
+
+        let to_read = self.chunk_remain.min(buf.len() as u64) as usize;
+
+        let n = match self.chunk {
+            Chunk::Raw => {
+                let n = self.inner.read(&mut buf[..to_read])?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                n
+            }
+            Chunk::Fill(pattern) => {
+                for (i, b) in buf[..to_read].iter_mut().enumerate() {
+                    *b = pattern[(self.chunk_pos as usize + i) % pattern.len()];
+                }
+                to_read
+            }
+            Chunk::DontCare => {
+                buf[..to_read].fill(0);
+                to_read
+            }
+        };
+
+        if let Some(hasher) = &mut self.crc {
+            hasher.update(&buf[..n]);
+        }
+
+        self.chunk_pos += n as u64;
+        self.chunk_remain -= n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_internal(buf).map_err(to_io_error)
+    }
+}
+
+/// A writer that converts raw image data to an Android sparse image on the
+/// fly. Blocks where every 4-byte word is the same, including blocks of zeros,
+/// are written as fill chunks and everything else is written as raw chunks.
+/// Don't care chunks are never written because flashing them would leave the
+/// old data on the device in place. The header includes the CRC32 of the raw
+/// image.
+///
+/// The header and the size of each raw chunk are only known at the end, so
+/// they are written by seeking back. [`Self::finish()`] must be called to
+/// write the final chunk and the header. Sparse images can only represent whole
+/// blocks, so it fails if the raw data does not end on a block boundary. After
+/// an error, the sparse image is incomplete and the writer should be discarded.
+pub struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    header_offset: u64,
+    block_size: u32,
+    /// Partial block that has not been written yet.
+    buf: Vec<u8>,
+    crc: Hasher,
+    /// Current chunk and the number of blocks in it.
+    chunk: Option<(Chunk, u32)>,
+    /// Offset of the current raw chunk's header.
+    chunk_offset: u64,
+    num_blocks: u32,
+    num_chunks: u32,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Create a writer that starts writing a sparse image with the specified
+    /// block size at the current position of `inner`. The block size must be a
+    /// non-zero multiple of 4.
+    pub fn new(mut inner: W, block_size: u32) -> Result<Self> {
+        if block_size == 0 || block_size % 4 != 0 {
+            return Err(Error::InvalidFieldValue("blk_sz", block_size));
+        }
+
+        let header_offset = inner.stream_position()?;
+        inner.write_zeros_exact(HEADER_SIZE.into())?;
+
+        Ok(Self {
+            inner,
+            header_offset,
+            block_size,
+            buf: Vec::with_capacity(block_size as usize),
+            crc: Hasher::new(),
+            chunk: None,
+            chunk_offset: 0,
+            num_blocks: 0,
+            num_chunks: 0,
+        })
+    }
+
+    /// Maximum number of blocks in a raw chunk such that the chunk size fits in
+    /// the 32-bit size field.
+    fn max_raw_blocks(&self) -> u32 {
+        (u32::MAX - u32::from(CHUNK_HEADER_SIZE)) / self.block_size
+    }
+
+    /// Write out the current chunk. For raw chunks, the data was already
+    /// written, so only the header is filled in.
+    fn finish_chunk(&mut self) -> Result<()> {
+        match self.chunk.take() {
+            None => return Ok(()),
+            Some((Chunk::Raw, blocks)) => {
+                let end = self.inner.stream_position()?;
+                self.inner.seek(SeekFrom::Start(self.chunk_offset))?;
+                write_chunk_header(
+                    &mut self.inner,
+                    CHUNK_TYPE_RAW,
+                    blocks,
+                    blocks * self.block_size,
+                )?;
+                self.inner.seek(SeekFrom::Start(end))?;
+            }
+            Some((Chunk::Fill(pattern), blocks)) => {
+                write_chunk_header(&mut self.inner, CHUNK_TYPE_FILL, blocks, 4)?;
+                self.inner.write_all(&pattern)?;
+            }
+            Some((Chunk::DontCare, _)) => unreachable!(),
+        }
+
+        self.num_chunks += 1;
+
+        Ok(())
+    }
+
+    /// Write the full block in [`Self::buf`].
+    fn write_block(&mut self) -> Result<()> {
+        if self.num_blocks == u32::MAX {
+            return Err(Error::TooManyBlocks(self.block_size));
+        }
+
+        self.crc.update(&self.buf);
+
+        let pattern: [u8; 4] = self.buf[..4].try_into().unwrap();
+        let chunk = if self.buf.chunks_exact(4).all(|w| *w == pattern) {
+            Chunk::Fill(pattern)
+        } else {
+            Chunk::Raw
+        };
+
+        let max_blocks = match chunk {
+            Chunk::Raw => self.max_raw_blocks(),
+            _ => u32::MAX,
+        };
+
+        match self.chunk {
+            Some((c, blocks)) if c == chunk && blocks < max_blocks => {}
+            _ => {
+                self.finish_chunk()?;
+
+                if chunk == Chunk::Raw {
+                    self.chunk_offset = self.inner.stream_position()?;
+                    self.inner.write_zeros_exact(CHUNK_HEADER_SIZE.into())?;
+                }
+
+                self.chunk = Some((chunk, 0));
+            }
+        }
+
+        if chunk == Chunk::Raw {
+            self.inner.write_all(&self.buf)?;
+        }
+
+        self.chunk.as_mut().unwrap().1 += 1;
+        self.num_blocks += 1;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    fn write_internal(&mut self, buf: &[u8]) -> Result<usize> {
+        let to_write = (self.block_size as usize - self.buf.len()).min(buf.len());
+        self.buf.extend_from_slice(&buf[..to_write]);
+
+        if self.buf.len() == self.block_size as usize {
+            self.write_block()?;
+        }
+
+        Ok(to_write)
+    }
+
+    /// Write the final chunk and fill in the header. The underlying writer is
+    /// left positioned at the end of the sparse image.
+    pub fn finish(mut self) -> Result<W> {
+        if !self.buf.is_empty() {
+            let size =
+                u64::from(self.num_blocks) * u64::from(self.block_size) + self.buf.len() as u64;
+            return Err(Error::UnalignedSize(size, self.block_size));
+        }
+
+        self.finish_chunk()?;
+
+        let header = Header {
+            major_version: MAJOR_VERSION,
+            minor_version: 0,
+            file_header_size: HEADER_SIZE,
+            chunk_header_size: CHUNK_HEADER_SIZE,
+            block_size: self.block_size,
+            num_blocks: self.num_blocks,
+            num_chunks: self.num_chunks,
+            image_crc32: self.crc.finalize(),
+        };
+
+        let end = self.inner.stream_position()?;
+        self.inner.seek(SeekFrom::Start(self.header_offset))?;
+        header.to_writer(&mut self.inner)?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_internal(buf).map_err(to_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer that produces both a raw image and a sparse image from the same
+/// data in a single pass. This is useful when the data is expensive to
+/// generate, since the sparse image does not need to be created from the raw
+/// image afterwards.
+pub struct RawAndSparseWriter<W1: Write, W2: Write + Seek> {
+    inner: TeeWriter<W1, SparseWriter<W2>>,
+}
+
+impl<W1: Write, W2: Write + Seek> RawAndSparseWriter<W1, W2> {
+    /// Create a writer that writes the raw data to `raw` and a sparse image
+    /// with the specified block size to `sparse`.
+    pub fn new(raw: W1, sparse: W2, block_size: u32) -> Result<Self> {
+        let sparse = SparseWriter::new(sparse, block_size)?;
+
+        Ok(Self {
+            inner: TeeWriter::new(raw, sparse),
+        })
+    }
+
+    /// Finish writing the sparse image and return the two writers. Like
+    /// [`SparseWriter::finish()`], this fails if the data does not end on a
+    /// block boundary. The raw data is written in full regardless.
+    pub fn finish(self) -> Result<(W1, W2)> {
+        let (mut raw, sparse) = self.inner.finish();
+        raw.flush()?;
+        let sparse = sparse.finish()?;
+
+        Ok((raw, sparse))
+    }
+}
+
+impl<W1: Write, W2: Write + Seek> Write for RawAndSparseWriter<W1, W2> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, Read, Seek},
+    sync::LazyLock,
+};
+
+use regex::bytes::Regex;
+use thiserror::Error;
+use zip::{result::ZipError, ZipArchive};
+
+/// Partitions that may contain their own VINTF manifests.
+pub const PARTITIONS: &[&str] = &["odm", "product", "system", "system_ext", "vendor"];
+
+static ROOT_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(manifest|compatibility-matrix)[ \t\r\n]([^>]*)>").unwrap());
+static ATTR_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"([A-Za-z0-9_-]+)[ \t\r\n]*=[ \t\r\n]*"([^"]*)""#).unwrap());
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("No VINTF manifest or compatibility matrix found in: {0}")]
+    NoRootElement(String),
+    #[error("{0} targets FCM level {1}, but framework matrices only exist for: {2:?}")]
+    UnsupportedTargetLevel(String, String, BTreeSet<String>),
+    #[error("Zip error")]
+    Zip(#[from] ZipError),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VintfKind {
+    Manifest,
+    CompatibilityMatrix,
+}
+
+impl fmt::Display for VintfKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Manifest => "manifest",
+            Self::CompatibilityMatrix => "compatibility matrix",
+        })
+    }
+}
+
+/// The attributes of the root element of a VINTF manifest or compatibility
+/// matrix. This is not a full VINTF parser. It only extracts what is needed to
+/// identify the FCM (framework compatibility matrix) levels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VintfInfo {
+    pub name: String,
+    pub kind: VintfKind,
+    /// Either `device` or `framework`.
+    pub vintf_type: Option<String>,
+    /// The `target-level` attribute for manifests or the `level` attribute for
+    /// compatibility matrices.
+    pub level: Option<String>,
+}
+
+impl VintfInfo {
+    fn is(&self, kind: VintfKind, vintf_type: &str) -> bool {
+        self.kind == kind && self.vintf_type.as_deref() == Some(vintf_type)
+    }
+}
+
+impl fmt::Display for VintfInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}, FCM level {}",
+            self.name,
+            self.vintf_type.as_deref().unwrap_or("unknown"),
+            self.kind,
+            self.level.as_deref().unwrap_or("unspecified"),
+        )
+    }
+}
+
+/// Parse the root element of a VINTF XML file.
+pub fn parse_xml(name: &str, data: &[u8]) -> Result<VintfInfo> {
+    let captures = ROOT_REGEX
+        .captures(data)
+        .ok_or_else(|| Error::NoRootElement(name.to_owned()))?;
+
+    let kind = if &captures[1] == b"manifest" {
+        VintfKind::Manifest
+    } else {
+        VintfKind::CompatibilityMatrix
+    };
+    let level_attr: &[u8] = match kind {
+        VintfKind::Manifest => b"target-level",
+        VintfKind::CompatibilityMatrix => b"level",
+    };
+
+    let mut vintf_type = None;
+    let mut level = None;
+
+    for attr in ATTR_REGEX.captures_iter(&captures[2]) {
+        let value = String::from_utf8_lossy(&attr[2]).into_owned();
+
+        if &attr[1] == b"type" {
+            vintf_type = Some(value);
+        } else if &attr[1] == level_attr {
+            level = Some(value);
+        }
+    }
+
+    Ok(VintfInfo {
+        name: name.to_owned(),
+        kind,
+        vintf_type,
+        level,
+    })
+}
+
+/// Load the VINTF metadata from every XML file in an OTA's
+/// `compatibility.zip`.
+pub fn load_compatibility_zip(reader: impl Read + Seek) -> Result<Vec<VintfInfo>> {
+    let mut zip = ZipArchive::new(reader)?;
+    let mut result = vec![];
+
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        if !entry.name().ends_with(".xml") {
+            continue;
+        }
+
+        let name = entry.name().to_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+
+        result.push(parse_xml(&name, &data)?);
+    }
+
+    Ok(result)
+}
+
+/// Check that every device manifest's target FCM level is supported by one of
+/// the framework compatibility matrices. If there are no framework
+/// compatibility matrices, then nothing is checked.
+pub fn check_compatibility(infos: &[VintfInfo]) -> Result<()> {
+    let framework_levels = infos
+        .iter()
+        .filter(|i| i.is(VintfKind::CompatibilityMatrix, "framework"))
+        .filter_map(|i| i.level.clone())
+        .collect::<BTreeSet<_>>();
+
+    if framework_levels.is_empty() {
+        return Ok(());
+    }
+
+    for info in infos.iter().filter(|i| i.is(VintfKind::Manifest, "device")) {
+        if let Some(level) = &info.level {
+            if !framework_levels.contains(level) {
+                return Err(Error::UnsupportedTargetLevel(
+                    info.name.clone(),
+                    level.clone(),
+                    framework_levels,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
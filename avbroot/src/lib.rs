@@ -9,14 +9,34 @@
 //!
 //! The CLI source files use concrete types wherever possible for simplicity,
 //! while the "library"-style source files aim to be generic.
+//!
+//! The CLI is only built with the `cli` feature, which is enabled by default.
+//! Disabling default features builds just the library without the
+//! dependencies that only the CLI needs.
+//!
+//! The `native` feature, which `cli` enables, covers everything that needs the
+//! filesystem, threads, or C libraries. Without it, only the format parsers,
+//! the stream helpers, and the pure-Rust hashing and signing code are built,
+//! which is enough to target `wasm32-unknown-unknown`. The [`boot`],
+//! [`analyze`], and [`pipeline`] modules are not available in this mode.
 
 // We use pb-rs' nostd mode. See build.rs.
 extern crate alloc;
 
+pub mod adb;
+#[cfg(feature = "native")]
+pub mod analyze;
+pub mod attestation;
+#[cfg(feature = "native")]
 pub mod boot;
+#[cfg(feature = "cli")]
 pub mod cli;
 pub mod crypto;
+pub mod fastboot;
 pub mod format;
+#[cfg(feature = "native")]
+pub mod pipeline;
 pub mod protobuf;
 pub mod stream;
 pub mod util;
+pub mod warning;
@@ -0,0 +1,141 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+type Outcome<R, E> = thread::Result<Result<R, E>>;
+
+struct State<T, R, E> {
+    items: Vec<Option<T>>,
+    /// Index of the next item to be processed.
+    next: usize,
+    /// Number of results that have been fully consumed.
+    consumed: usize,
+    /// Results that are waiting to be consumed.
+    results: BTreeMap<usize, Outcome<R, E>>,
+    /// Set when the consumer stops early, so workers don't start new items.
+    stopped: bool,
+}
+
+/// Run `process` on each item in a pool of up to `jobs` threads and pass the
+/// results to `consume` in the same order as the items. `consume` runs on the
+/// calling thread.
+///
+/// An item is only started if it is fewer than `jobs` positions ahead of the
+/// result currently being consumed. At most `jobs` results exist at once,
+/// whether they are in progress, waiting, or being consumed. Memory usage is
+/// therefore bounded by `jobs` times the size of a single result, even if one
+/// slow item holds up the items after it.
+///
+/// If `process` or `consume` fails, no new items are started and the first
+/// error in item order is returned after the in-progress items finish. A panic
+/// in `process` is propagated to the caller.
+pub fn run_ordered<T, R, E>(
+    jobs: NonZeroUsize,
+    items: impl IntoIterator<Item = T>,
+    process: impl Fn(T) -> Result<R, E> + Sync,
+    mut consume: impl FnMut(R) -> Result<(), E>,
+) -> Result<(), E>
+where
+    T: Send,
+    R: Send,
+    E: Send,
+{
+    let items = items.into_iter().map(Some).collect::<Vec<_>>();
+    let num_items = items.len();
+    let jobs = jobs.get();
+
+    let state = Mutex::new(State {
+        items,
+        next: 0,
+        consumed: 0,
+        results: BTreeMap::new(),
+        stopped: false,
+    });
+    let cond = Condvar::new();
+
+    let worker = || loop {
+        let (index, item) = {
+            let mut s = state.lock().unwrap();
+
+            loop {
+                if s.stopped || s.next == num_items {
+                    return;
+                } else if s.next < s.consumed + jobs {
+                    break;
+                }
+
+                s = cond.wait(s).unwrap();
+            }
+
+            let index = s.next;
+            s.next += 1;
+
+            (index, s.items[index].take().unwrap())
+        };
+
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| process(item)));
+
+        state.lock().unwrap().results.insert(index, outcome);
+        cond.notify_all();
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(num_items) {
+            scope.spawn(worker);
+        }
+
+        let stop = |s: &mut State<T, R, E>| {
+            s.stopped = true;
+            cond.notify_all();
+        };
+        let mut panic_payload: Option<Box<dyn Any + Send>> = None;
+        let mut result = Ok(());
+
+        for index in 0..num_items {
+            let outcome = {
+                let mut s = state.lock().unwrap();
+
+                loop {
+                    if let Some(o) = s.results.remove(&index) {
+                        break o;
+                    }
+
+                    s = cond.wait(s).unwrap();
+                }
+            };
+
+            match outcome.map(|r| r.and_then(&mut consume)) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    result = Err(e);
+                    stop(&mut state.lock().unwrap());
+                    break;
+                }
+                Err(p) => {
+                    panic_payload = Some(p);
+                    stop(&mut state.lock().unwrap());
+                    break;
+                }
+            }
+
+            state.lock().unwrap().consumed = index + 1;
+            cond.notify_all();
+        }
+
+        if let Some(p) = panic_payload {
+            panic::resume_unwind(p);
+        }
+
+        result
+    })
+}
@@ -4,13 +4,20 @@
  */
 
 use std::{
-    fs::File,
+    collections::VecDeque,
     io::{self, Cursor, Read, Seek, SeekFrom, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex, RwLock,
+        Arc, Mutex,
     },
 };
+#[cfg(feature = "native")]
+use std::{
+    fs::File,
+    sync::RwLock,
+    thread,
+    time::{Duration, Instant},
+};
 
 use num_traits::ToPrimitive;
 use ring::digest::Context;
@@ -299,7 +306,105 @@ impl<W: Write> Write for HashingWriter<W> {
     }
 }
 
+/// A writer wrapper that writes the same data to two writers. This allows eg.
+/// writing compressed data to a file and hashing it at the same time with a
+/// [`HashingWriter`] without compressing twice. The second writer always
+/// receives exactly the data that the first writer accepted. An error from
+/// either writer is returned as-is, after which the two may be out of sync.
+pub struct TeeWriter<W1: Write, W2: Write> {
+    first: W1,
+    second: W2,
+}
+
+impl<W1: Write, W2: Write> TeeWriter<W1, W2> {
+    pub fn new(first: W1, second: W2) -> Self {
+        Self { first, second }
+    }
+
+    pub fn finish(self) -> (W1, W2) {
+        (self.first, self.second)
+    }
+}
+
+impl<W1: Write, W2: Write> Write for TeeWriter<W1, W2> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.first.write(buf)?;
+        self.second.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+/// A writer wrapper that calls `callback` with the total number of bytes
+/// written each time another `interval` bytes have passed through. If a single
+/// write crosses multiple intervals, the callback is only called once. The
+/// wrapper is otherwise transparent, so it can be placed on top of a
+/// [`CompressedWriter`](crate::format::compression::CompressedWriter) to report
+/// progress in uncompressed bytes or underneath it to report progress in
+/// compressed bytes.
+pub struct ProgressWriter<W: Write, F: FnMut(u64)> {
+    inner: W,
+    callback: F,
+    interval: u64,
+    total: u64,
+    next: u64,
+}
+
+impl<W: Write, F: FnMut(u64)> ProgressWriter<W, F> {
+    /// Panics if `interval` is 0.
+    pub fn new(inner: W, interval: u64, callback: F) -> Self {
+        assert!(interval > 0, "Progress interval cannot be 0");
+
+        Self {
+            inner,
+            callback,
+            interval,
+            total: 0,
+            next: interval,
+        }
+    }
+
+    pub fn finish(self) -> (W, u64) {
+        (self.inner, self.total)
+    }
+}
+
+impl<W: Write, F: FnMut(u64)> Write for ProgressWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.total += n as u64;
+
+        if self.total >= self.next {
+            (self.callback)(self.total);
+            self.next = (self.total / self.interval + 1) * self.interval;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// A reader wrapper that only allows reading a specific section of a file.
+/// Reads never go past the end of the section, even if the underlying file has
+/// more data. Offsets are relative to the start of the section.
+///
+/// Unlike [`io::Take`], the section is seekable. It can be rewound and wrapped
+/// by readers that need to seek, like a
+/// [`CompressedReader`](crate::format::compression::CompressedReader) for the
+/// data of a single payload operation, without reading into the next region.
+///
+/// The wrapper assumes that it has exclusive control over the underlying file
+/// position. To have multiple independent views into the same file, wrap a
+/// reader like [`PSeekFile`], where each clone has its own file offset. Cloning
+/// the [`SectionReader`] then also produces an independent view.
+#[derive(Clone)]
 pub struct SectionReader<R: Read + Seek> {
     inner: R,
     start: u64,
@@ -309,6 +414,13 @@ pub struct SectionReader<R: Read + Seek> {
 
 impl<R: Read + Seek> SectionReader<R> {
     pub fn new(mut inner: R, start: u64, size: u64) -> io::Result<Self> {
+        if start.checked_add(size).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Section end offset is too large",
+            ));
+        }
+
         inner.seek(SeekFrom::Start(start))?;
 
         Ok(Self {
@@ -335,7 +447,7 @@ impl<R: Read + Seek> Read for SectionReader<R> {
 
 impl<R: Read + Seek> Seek for SectionReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = match pos {
+        let new_pos = match pos {
             SeekFrom::Start(o) => o,
             SeekFrom::End(o) => self
                 .size
@@ -361,8 +473,146 @@ impl<R: Read + Seek> Seek for SectionReader<R> {
                 })?,
         };
 
-        let raw_pos = self.inner.seek(SeekFrom::Start(self.start + self.pos))?;
-        Ok(raw_pos - self.start)
+        let raw_pos = self
+            .start
+            .checked_add(new_pos)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Offset is too large"))?;
+
+        // Only commit the new position if the underlying file agrees so that
+        // the two can never get out of sync.
+        self.inner.seek(SeekFrom::Start(raw_pos))?;
+        self.pos = new_pos;
+
+        Ok(new_pos)
+    }
+}
+
+/// A reader that concatenates multiple readers. Reading proceeds through each
+/// reader in order until it reaches EOF.
+///
+/// If the reader is constructed with [`Self::new_seekable`], the size of each
+/// reader is computed upfront and seeking across reader boundaries is
+/// supported.
+pub struct ChainedReader<R> {
+    readers: Vec<R>,
+    /// Size of each reader. This is only known if the readers are seekable.
+    sizes: Option<Vec<u64>>,
+    index: usize,
+    pos: u64,
+}
+
+impl<R: Read> ChainedReader<R> {
+    pub fn new(readers: Vec<R>) -> Self {
+        Self {
+            readers,
+            sizes: None,
+            index: 0,
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<R> {
+        self.readers
+    }
+}
+
+impl<R: Read + Seek> ChainedReader<R> {
+    /// Create a seekable reader. Each reader is rewound to the beginning.
+    pub fn new_seekable(mut readers: Vec<R>) -> io::Result<Self> {
+        let mut sizes = Vec::with_capacity(readers.len());
+
+        for reader in &mut readers {
+            sizes.push(reader.seek(SeekFrom::End(0))?);
+            reader.rewind()?;
+        }
+
+        Ok(Self {
+            readers,
+            sizes: Some(sizes),
+            index: 0,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for ChainedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while let Some(reader) = self.readers.get_mut(self.index) {
+            let n = reader.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+
+            self.index += 1;
+        }
+
+        Ok(0)
+    }
+}
+
+impl<R: Read + Seek> Seek for ChainedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let Some(sizes) = &self.sizes else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Sizes of the chained readers are not known",
+            ));
+        };
+
+        let total: u64 = sizes.iter().sum();
+
+        let new_pos = match pos {
+            SeekFrom::Start(o) => Some(o),
+            SeekFrom::End(o) => total
+                .to_i64()
+                .and_then(|s| s.checked_add(o))
+                .and_then(|s| s.to_u64()),
+            SeekFrom::Current(o) => self
+                .pos
+                .to_i64()
+                .and_then(|s| s.checked_add(o))
+                .and_then(|s| s.to_u64()),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Offset would be before the start of the file",
+            )
+        })?;
+
+        // Find the reader containing the new position. Seeking past the end
+        // leaves every reader at EOF.
+        let mut start = 0;
+        let mut index = sizes.len();
+        let mut offset = 0;
+
+        for (i, size) in sizes.iter().enumerate() {
+            if new_pos < start + size {
+                index = i;
+                offset = new_pos - start;
+                break;
+            }
+
+            start += size;
+        }
+
+        for (i, reader) in self.readers.iter_mut().enumerate() {
+            if i == index {
+                reader.seek(SeekFrom::Start(offset))?;
+            } else if i > index {
+                reader.rewind()?;
+            }
+        }
+
+        self.index = index;
+        self.pos = new_pos;
+
+        Ok(new_pos)
     }
 }
 
@@ -398,8 +648,134 @@ impl<W: Write + Seek> Write for HolePunchingWriter<W> {
     }
 }
 
+/// Tracks the number of bytes transferred in the current window and how long
+/// to sleep to stay under the rate limit.
+#[cfg(feature = "native")]
+struct Throttle {
+    bytes_per_sec: u64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+#[cfg(feature = "native")]
+impl Throttle {
+    /// Rate limiting is averaged over this duration. Waiting longer than this
+    /// between transfers does not allow for larger bursts afterwards.
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "Rate limit must be non-zero");
+
+        Self {
+            bytes_per_sec,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Limit the size of a single transfer to a tenth of the window so that
+    /// the rate doesn't fluctuate too much within a window.
+    fn limit(&self, len: usize) -> usize {
+        let max = (self.bytes_per_sec / 10).max(1);
+        len.min(max.to_usize().unwrap_or(usize::MAX))
+    }
+
+    /// Account for `n` bytes that were just transferred and sleep until the
+    /// average rate over the window is no longer above the limit.
+    fn consume(&mut self, n: usize) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Self::WINDOW {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+
+        self.window_bytes += n as u64;
+
+        let expected =
+            Duration::from_secs_f64(self.window_bytes as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.window_start.elapsed();
+
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+/// A reader wrapper that limits the read throughput to a maximum number of
+/// bytes per second. The rate is averaged over a one second window, so short
+/// bursts are possible.
+#[cfg(feature = "native")]
+pub struct ThrottledReader<R: Read> {
+    inner: R,
+    throttle: Throttle,
+}
+
+#[cfg(feature = "native")]
+impl<R: Read> ThrottledReader<R> {
+    /// Panics if `bytes_per_sec` is 0.
+    pub fn new(inner: R, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(bytes_per_sec),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(feature = "native")]
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = self.throttle.limit(buf.len());
+        let n = self.inner.read(&mut buf[..to_read])?;
+        self.throttle.consume(n);
+        Ok(n)
+    }
+}
+
+/// A writer wrapper that limits the write throughput to a maximum number of
+/// bytes per second. The rate is averaged over a one second window, so short
+/// bursts are possible.
+#[cfg(feature = "native")]
+pub struct ThrottledWriter<W: Write> {
+    inner: W,
+    throttle: Throttle,
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> ThrottledWriter<W> {
+    /// Panics if `bytes_per_sec` is 0.
+    pub fn new(inner: W, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(bytes_per_sec),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(feature = "native")]
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let to_write = self.throttle.limit(buf.len());
+        let n = self.inner.write(&buf[..to_write])?;
+        self.throttle.consume(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// A file wrapper that uses a userspace file offset. A cloned instances uses
 /// the same underlying kernel file descriptor, but a new userspace file offset.
+#[cfg(feature = "native")]
 #[derive(Clone)]
 pub struct PSeekFile {
     // The lock is needed because flush() takes a `&mut self`.
@@ -407,6 +783,7 @@ pub struct PSeekFile {
     offset: u64,
 }
 
+#[cfg(feature = "native")]
 impl PSeekFile {
     pub fn new(file: File) -> Self {
         Self {
@@ -449,6 +826,7 @@ impl PSeekFile {
     }
 }
 
+#[cfg(feature = "native")]
 impl Read for PSeekFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let n = self.read_at(buf)?;
@@ -457,6 +835,7 @@ impl Read for PSeekFile {
     }
 }
 
+#[cfg(feature = "native")]
 impl Write for PSeekFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.write_at(buf)?;
@@ -469,6 +848,7 @@ impl Write for PSeekFile {
     }
 }
 
+#[cfg(feature = "native")]
 impl Seek for PSeekFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.offset = match pos {
@@ -521,6 +901,18 @@ impl SharedCursor {
         new.offset = 0;
         new
     }
+
+    /// Truncate or extend the underlying buffer with zeros.
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        let size = size.to_usize().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Size exceeds memory bounds")
+        })?;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.get_mut().resize(size, 0);
+
+        Ok(())
+    }
 }
 
 impl Read for SharedCursor {
@@ -560,6 +952,107 @@ impl Seek for SharedCursor {
     }
 }
 
+struct RingBufferState {
+    data: VecDeque<u8>,
+    capacity: usize,
+    closed: bool,
+}
+
+/// A bounded FIFO buffer that can be shared between a producer and a consumer,
+/// for example, when streaming compressed data to a socket.
+///
+/// This type is non-blocking. Writes only accept as many bytes as there is
+/// free space for and fail with [`io::ErrorKind::WouldBlock`] if the buffer is
+/// completely full. Similarly, reads fail with [`io::ErrorKind::WouldBlock`]
+/// if the buffer is empty, unless [`Self::close()`] has been called, in which
+/// case EOF is reported. When [`io::ErrorKind::WouldBlock`] is returned, no
+/// data was consumed and the operation can be retried once the other side has
+/// made progress.
+#[derive(Clone)]
+pub struct RingBuffer {
+    inner: Arc<Mutex<RingBufferState>>,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(RingBufferState {
+                data: VecDeque::with_capacity(capacity),
+                capacity,
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().unwrap().capacity
+    }
+
+    /// Number of bytes currently buffered.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Mark the end of the stream. Further writes will fail and reads will
+    /// report EOF once the remaining data has been consumed.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+    }
+}
+
+impl Read for RingBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.data.is_empty() {
+            if inner.closed || buf.is_empty() {
+                return Ok(0);
+            }
+
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Ring buffer is empty",
+            ));
+        }
+
+        inner.data.read(buf)
+    }
+}
+
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Ring buffer is closed",
+            ));
+        }
+
+        let available = inner.capacity - inner.data.len();
+        if available == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Ring buffer is full",
+            ));
+        }
+
+        let n = available.min(buf.len());
+        inner.data.extend(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Copy exactly `size` bytes from `reader` to `writer`, invoking `inspect`
 /// after every buffer read iteration. If either `reader` or `writer` reaches
 /// EOF before `size` bytes are copied, an error is returned. The operation is
@@ -638,6 +1131,8 @@ pub fn copy(
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "native")]
+    use std::time::{Duration, Instant};
     use std::{
         io::{self, Cursor, Read, Seek, SeekFrom, Write},
         sync::{
@@ -649,10 +1144,12 @@ mod tests {
     use ring::digest::Context;
 
     use super::{
-        CountingReader, CountingWriter, HashingReader, HashingWriter, HolePunchingWriter,
-        PSeekFile, ReadDiscardExt, ReadStringExt, SectionReader, SharedCursor, WriteStringExt,
-        WriteZerosExt,
+        ChainedReader, CountingReader, CountingWriter, HashingReader, HashingWriter,
+        HolePunchingWriter, ReadDiscardExt, ReadStringExt, RingBuffer, SectionReader, SharedCursor,
+        TeeWriter, WriteStringExt, WriteZerosExt,
     };
+    #[cfg(feature = "native")]
+    use super::{PSeekFile, ThrottledReader, ThrottledWriter};
 
     const FOOBAR_SHA256: [u8; 32] = [
         0xc3, 0xab, 0x8f, 0xf1, 0x37, 0x20, 0xe8, 0xad, 0x90, 0x47, 0xdd, 0x39, 0x46, 0x6b, 0x3c,
@@ -778,6 +1275,38 @@ mod tests {
         assert_eq!(context.finish().as_ref(), FOOBAR_SHA256);
     }
 
+    #[test]
+    fn tee_writer() {
+        let hashing_writer =
+            HashingWriter::new(Cursor::new(vec![]), Context::new(&ring::digest::SHA256));
+        let mut writer = TeeWriter::new(Cursor::new(vec![]), hashing_writer);
+
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"foo").unwrap();
+        writer.write_all(b"bar").unwrap();
+        writer.flush().unwrap();
+
+        let (first, second) = writer.finish();
+        let (second, context) = second.finish();
+        assert_eq!(first.into_inner(), b"foobar");
+        assert_eq!(second.into_inner(), b"foobar");
+        assert_eq!(context.finish().as_ref(), FOOBAR_SHA256);
+
+        // Errors from either writer are returned. If the first writer stops
+        // accepting data, the second writer still has the same data.
+        let mut writer = TeeWriter::new(Cursor::new([0u8; 6]), Cursor::new([0u8; 4]));
+        let err = writer.write_all(b"foobar").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        let mut writer = TeeWriter::new(Cursor::new([0u8; 4]), Cursor::new(vec![]));
+        let err = writer.write_all(b"foobar").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        let (first, second) = writer.finish();
+        assert_eq!(&first.into_inner(), b"foob");
+        assert_eq!(second.into_inner(), b"foob");
+    }
+
     #[test]
     fn section_reader() {
         let raw_reader = Cursor::new(b"fooinnerbar");
@@ -811,6 +1340,132 @@ mod tests {
         assert_eq!(raw_reader.stream_position().unwrap(), 6);
     }
 
+    /// Compare [`SectionReader`] against a [`Cursor`] over a copy of the
+    /// section for random sections and random sequences of operations.
+    #[test]
+    fn section_reader_random() {
+        let data = (0..512u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut next = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+
+        for _ in 0..200 {
+            let start = next(data.len() as u64 + 1);
+            let size = next(data.len() as u64 - start + 1);
+            let section = &data[start as usize..(start + size) as usize];
+
+            let mut reader = SectionReader::new(Cursor::new(&data), start, size).unwrap();
+            let mut expected = Cursor::new(section);
+
+            for _ in 0..20 {
+                match next(4) {
+                    0 => {
+                        let pos = SeekFrom::Start(next(size + 8));
+                        assert_eq!(reader.seek(pos).unwrap(), expected.seek(pos).unwrap());
+                    }
+                    1 => {
+                        let pos = SeekFrom::End(next(size + 1) as i64 - size as i64);
+                        assert_eq!(reader.seek(pos).unwrap(), expected.seek(pos).unwrap());
+                    }
+                    2 => {
+                        let pos = SeekFrom::Current(next(9) as i64 - 4);
+                        match expected.seek(pos) {
+                            Ok(p) => assert_eq!(reader.seek(pos).unwrap(), p),
+                            Err(_) => assert!(reader.seek(pos).is_err()),
+                        }
+                    }
+                    _ => {
+                        // Reads may straddle or start past the end.
+                        let mut buf = vec![0u8; next(size + 16) as usize];
+                        let mut expected_buf = buf.clone();
+
+                        let n = reader.read(&mut buf).unwrap();
+                        assert_eq!(n, expected.read(&mut expected_buf).unwrap());
+                        assert_eq!(buf[..n], expected_buf[..n]);
+                    }
+                }
+
+                assert_eq!(reader.stream_position().unwrap(), expected.position());
+            }
+
+            // Clones continue from the same position independently.
+            let mut clone = reader.clone();
+            let mut rest = vec![];
+            let mut clone_rest = vec![];
+            reader.read_to_end(&mut rest).unwrap();
+            clone.read_to_end(&mut clone_rest).unwrap();
+            assert_eq!(rest, clone_rest);
+        }
+    }
+
+    #[test]
+    fn section_reader_bounds() {
+        // The section may extend past the end of the underlying file.
+        let mut reader = SectionReader::new(Cursor::new(b"foobar"), 3, 10).unwrap();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bar");
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 10);
+
+        assert!(SectionReader::new(Cursor::new(b""), u64::MAX, 1).is_err());
+
+        let mut reader = SectionReader::new(Cursor::new(b"foobar"), 3, 3).unwrap();
+        assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+        assert!(reader.seek(SeekFrom::Start(u64::MAX)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 0);
+    }
+
+    #[test]
+    fn chained_reader() {
+        let readers = vec![
+            Cursor::new(b"foo".as_slice()),
+            Cursor::new(b"".as_slice()),
+            Cursor::new(b"inner".as_slice()),
+            Cursor::new(b"bar".as_slice()),
+        ];
+
+        let mut data = vec![];
+        ChainedReader::new(readers.clone())
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"fooinnerbar");
+
+        let mut reader = ChainedReader::new_seekable(readers).unwrap();
+
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"fooin");
+
+        buf = *b"\0\0\0\0\0";
+        reader.seek(SeekFrom::Start(2)).unwrap();
+        reader.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"oinn");
+
+        buf = *b"\0\0\0\0\0";
+        reader.seek(SeekFrom::End(-4)).unwrap();
+        reader.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"rbar");
+
+        buf = *b"\0\0\0\0\0";
+        reader.seek(SeekFrom::Current(-8)).unwrap();
+        reader.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"inn");
+
+        assert_eq!(reader.seek(SeekFrom::End(1)).unwrap(), 12);
+        let n = reader.read_discard(1).unwrap();
+        assert_eq!(n, 0);
+
+        let mut reader = ChainedReader::new(vec![Cursor::new(b"foo")]);
+        assert_eq!(
+            reader.seek(SeekFrom::Start(0)).unwrap_err().kind(),
+            io::ErrorKind::Unsupported,
+        );
+    }
+
     #[test]
     fn hole_punching_writer() {
         let raw_writer = Cursor::new(b"foobar foobar".to_owned());
@@ -826,6 +1481,45 @@ mod tests {
         assert_eq!(&raw_writer.into_inner(), b"hellor fworld");
     }
 
+    #[cfg(feature = "native")]
+    #[test]
+    fn throttled_reader() {
+        let data = vec![0u8; 64 * 1024];
+        let mut reader = ThrottledReader::new(Cursor::new(&data), 256 * 1024);
+
+        let start = Instant::now();
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(buf, data);
+        // 64 KiB at 256 KiB/s should take 250ms.
+        assert!(
+            elapsed >= Duration::from_millis(225),
+            "Too fast: {elapsed:?}"
+        );
+        assert!(elapsed < Duration::from_secs(2), "Too slow: {elapsed:?}");
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn throttled_writer() {
+        let data = vec![0u8; 64 * 1024];
+        let mut writer = ThrottledWriter::new(Cursor::new(Vec::new()), 256 * 1024);
+
+        let start = Instant::now();
+        writer.write_all(&data).unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(writer.into_inner().into_inner(), data);
+        assert!(
+            elapsed >= Duration::from_millis(225),
+            "Too fast: {elapsed:?}"
+        );
+        assert!(elapsed < Duration::from_secs(2), "Too slow: {elapsed:?}");
+    }
+
+    #[cfg(feature = "native")]
     #[test]
     fn pseek_file() {
         let raw_file = tempfile::tempfile().unwrap();
@@ -867,6 +1561,36 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    #[test]
+    fn ring_buffer() {
+        let mut writer = RingBuffer::new(4);
+        let mut reader = writer.clone();
+
+        let mut buf = [0u8; 3];
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Partial write when there's not enough space.
+        assert_eq!(writer.write(b"foobar").unwrap(), 4);
+        let err = writer.write(b"bar").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(writer.len(), 4);
+
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo");
+
+        assert_eq!(writer.write(b"bar").unwrap(), 3);
+        writer.close();
+
+        let err = writer.write(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bbar");
+        assert!(reader.is_empty());
+    }
+
     #[test]
     fn copy() {
         let cancel_signal = Arc::new(AtomicBool::new(false));
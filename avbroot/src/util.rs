@@ -3,12 +3,87 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::fmt;
+use std::{
+    env, fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
 
 pub const ZEROS: [u8; 16384] = [0u8; 16384];
 
+/// The parallel iterator traits used by the hashing and payload code. Without
+/// the `native` feature, there are no threads, so these are replaced by shims
+/// that run the same iterator chains sequentially.
+#[cfg(feature = "native")]
+pub mod par {
+    pub use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+}
+
+#[cfg(not(feature = "native"))]
+pub mod par {
+    pub use std::iter::Iterator as ParallelIterator;
+
+    pub trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<I: IntoIterator> IntoParallelIterator for I {}
+
+    pub trait IntoParallelRefIterator<'data> {
+        type Iter: Iterator;
+
+        fn par_iter(&'data self) -> Self::Iter;
+    }
+
+    impl<'data, I: 'data + ?Sized> IntoParallelRefIterator<'data> for I
+    where
+        &'data I: IntoIterator,
+    {
+        type Iter = <&'data I as IntoIterator>::IntoIter;
+
+        fn par_iter(&'data self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+}
+
+/// Environment variable that overrides the current time for timestamps that
+/// end up in generated files. See
+/// <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub const SOURCE_DATE_EPOCH_ENV: &str = "SOURCE_DATE_EPOCH";
+
+/// Whether [`now()`] returns the fixed time from [`SOURCE_DATE_EPOCH_ENV`]
+/// instead of the system clock. An empty value is treated as unset.
+pub fn is_clock_fixed() -> bool {
+    env::var_os(SOURCE_DATE_EPOCH_ENV).map_or(false, |v| !v.is_empty())
+}
+
+/// Get the current time for timestamps that end up in generated files. If
+/// [`SOURCE_DATE_EPOCH_ENV`] is set, it is parsed as a Unix timestamp and used
+/// instead of the system clock. Invalid values are rejected instead of being
+/// silently ignored.
+pub fn now() -> io::Result<SystemTime> {
+    if !is_clock_fixed() {
+        return Ok(SystemTime::now());
+    }
+
+    let value = env::var_os(SOURCE_DATE_EPOCH_ENV).unwrap_or_default();
+
+    value
+        .to_str()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid {SOURCE_DATE_EPOCH_ENV} value: {value:?}"),
+            )
+        })
+}
+
 /// A small wrapper to format a number as a size in bytes.
 #[derive(Clone, Copy)]
 pub struct NumBytes(pub usize);
@@ -0,0 +1,151 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+/// Stable identifier for a kind of warning. The string representation is part
+/// of the CLI's output and must never be changed for an existing variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    MagiskUnsupportedVersion,
+    MagiskMissingPreinitDevice,
+    PrepatchedIncompatible,
+    VbmetaHasFooter,
+    UnprotectedPartitions,
+    VintfUnchecked,
+    RamdiskCompressionUnchecked,
+    OtaCertIssue,
+    RamdiskChecksumMismatch,
+    PssZipSignature,
+    MagiskRepatched,
+    AvbResigningSkipped,
+    VerifyCheckIgnored,
+    PartitionsExcluded,
+    VbmetaStubRebuilt,
+    LowFreeSpace,
+    VbmetaFlagsKept,
+    SignatureTrustUnknown,
+    PartitionImageMissing,
+    UnusedIgnoreOption,
+    RamdiskTrailingData,
+}
+
+impl WarningCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MagiskUnsupportedVersion => "magisk_unsupported_version",
+            Self::MagiskMissingPreinitDevice => "magisk_missing_preinit_device",
+            Self::PrepatchedIncompatible => "prepatched_incompatible",
+            Self::VbmetaHasFooter => "vbmeta_has_footer",
+            Self::UnprotectedPartitions => "unprotected_partitions",
+            Self::VintfUnchecked => "vintf_unchecked",
+            Self::RamdiskCompressionUnchecked => "ramdisk_compression_unchecked",
+            Self::OtaCertIssue => "ota_cert_issue",
+            Self::RamdiskChecksumMismatch => "ramdisk_checksum_mismatch",
+            Self::PssZipSignature => "pss_zip_signature",
+            Self::MagiskRepatched => "magisk_repatched",
+            Self::AvbResigningSkipped => "avb_resigning_skipped",
+            Self::VerifyCheckIgnored => "verify_check_ignored",
+            Self::PartitionsExcluded => "partitions_excluded",
+            Self::VbmetaStubRebuilt => "vbmeta_stub_rebuilt",
+            Self::LowFreeSpace => "low_free_space",
+            Self::VbmetaFlagsKept => "vbmeta_flags_kept",
+            Self::SignatureTrustUnknown => "signature_trust_unknown",
+            Self::PartitionImageMissing => "partition_image_missing",
+            Self::UnusedIgnoreOption => "unused_ignore_option",
+            Self::RamdiskTrailingData => "ramdisk_trailing_data",
+        }
+    }
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Purely informational. The output is very likely fine.
+    Low,
+    /// The output may not work as expected.
+    Medium,
+    /// The output is likely to not work or was produced with safety checks
+    /// disabled.
+    High,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub code: WarningCode,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}/{}] {}", self.code, self.severity, self.message)
+    }
+}
+
+/// A thread-safe collector for warnings. Clones share the same list of
+/// warnings, so a collector can be handed to every part of a pipeline and the
+/// caller can inspect all of the warnings at the end.
+#[derive(Clone, Default)]
+pub struct WarningCollector {
+    warnings: Arc<Mutex<Vec<Warning>>>,
+    emit_fn: Option<Arc<dyn Fn(&Warning) + Send + Sync>>,
+}
+
+impl WarningCollector {
+    /// Create a collector that also calls `emit_fn` as soon as a warning is
+    /// emitted.
+    pub fn new(emit_fn: impl Fn(&Warning) + Send + Sync + 'static) -> Self {
+        Self {
+            warnings: Arc::default(),
+            emit_fn: Some(Arc::new(emit_fn)),
+        }
+    }
+
+    pub fn emit(&self, code: WarningCode, severity: Severity, message: impl Into<String>) {
+        let warning = Warning {
+            code,
+            severity,
+            message: message.into(),
+        };
+
+        if let Some(f) = &self.emit_fn {
+            f(&warning);
+        }
+
+        self.warnings.lock().unwrap().push(warning);
+    }
+
+    /// Get a snapshot of all warnings emitted so far, in order.
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings.lock().unwrap().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.warnings.lock().unwrap().is_empty()
+    }
+}
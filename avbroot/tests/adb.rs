@@ -0,0 +1,207 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor, Read, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    adb::{self, AdbConnection, Message, SIDELOAD_BLOCK_SIZE},
+    stream::{FromReader, ToWriter},
+};
+
+const A_CNXN: u32 = 0x4e584e43;
+const A_OPEN: u32 = 0x4e45504f;
+const A_OKAY: u32 = 0x59414b4f;
+const A_WRTE: u32 = 0x45545257;
+
+const DEVICE_ID: u32 = 100;
+const DEVICE_MAX_PAYLOAD: u32 = 4096;
+
+/// Fake recovery in sideload mode. It requests the blocks in `requests` in
+/// order and then reports `result`.
+struct MockDevice {
+    size: u64,
+    requests: VecDeque<u64>,
+    result: &'static [u8; 8],
+    from_host: Vec<u8>,
+    to_host: VecDeque<u8>,
+    host_id: u32,
+    pending: Vec<u8>,
+    received: Vec<(u64, Vec<u8>)>,
+}
+
+impl MockDevice {
+    fn new(size: u64, requests: &[u64], result: &'static [u8; 8]) -> Self {
+        Self {
+            size,
+            requests: requests.iter().copied().collect(),
+            result,
+            from_host: vec![],
+            to_host: VecDeque::new(),
+            host_id: 0,
+            pending: vec![],
+            received: vec![],
+        }
+    }
+
+    fn send(&mut self, command: u32, data: &[u8]) {
+        let message = Message::new(command, DEVICE_ID, self.host_id, data);
+        let mut buf = vec![];
+        message.to_writer(&mut buf).unwrap();
+        self.to_host.extend(buf);
+    }
+
+    fn request_next(&mut self) {
+        let request = match self.requests.front() {
+            Some(block) => format!("{block:08}").into_bytes(),
+            None => self.result.to_vec(),
+        };
+
+        self.send(A_WRTE, &request);
+    }
+
+    fn expected_len(&self) -> usize {
+        let block = self.requests[0];
+        (self.size - block * SIDELOAD_BLOCK_SIZE).min(SIDELOAD_BLOCK_SIZE) as usize
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message.command {
+            A_CNXN => {
+                let message = Message::new(A_CNXN, 0x01000001, DEVICE_MAX_PAYLOAD, *b"sideload::");
+                let mut buf = vec![];
+                message.to_writer(&mut buf).unwrap();
+                self.to_host.extend(buf);
+            }
+            A_OPEN => {
+                let service = format!("sideload-host:{}:{SIDELOAD_BLOCK_SIZE}\0", self.size);
+                assert_eq!(message.data, service.as_bytes());
+
+                self.host_id = message.arg0;
+                self.send(A_OKAY, &[]);
+                self.request_next();
+            }
+            A_WRTE => {
+                assert!(message.data.len() <= DEVICE_MAX_PAYLOAD as usize);
+                self.pending.extend_from_slice(&message.data);
+                self.send(A_OKAY, &[]);
+
+                if self.pending.len() == self.expected_len() {
+                    let block = self.requests.pop_front().unwrap();
+                    self.received.push((block, self.pending.split_off(0)));
+                    self.request_next();
+                }
+            }
+            A_OKAY => {}
+            c => panic!("Unexpected command: {c:#010x}"),
+        }
+    }
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.to_host.read(buf)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.from_host.extend_from_slice(buf);
+
+        loop {
+            let mut reader = Cursor::new(&self.from_host);
+            let Ok(message) = Message::from_reader(&mut reader) else {
+                break;
+            };
+
+            let consumed = reader.position() as usize;
+            self.from_host.drain(..consumed);
+            self.handle(message);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn package(size: u64) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn sideload_blocks() {
+    let size = SIDELOAD_BLOCK_SIZE + 1000;
+    let data = package(size);
+    let device = MockDevice::new(size, &[1, 0, 1], b"DONEDONE");
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    let mut conn = AdbConnection::connect(device, None).unwrap();
+    assert_eq!(conn.banner(), "sideload::");
+
+    let mut progress = vec![];
+    adb::sideload(
+        &mut conn,
+        Cursor::new(&data),
+        size,
+        |sent, total| progress.push((sent, total)),
+        &cancel_signal,
+    )
+    .unwrap();
+
+    // Repeated requests do not count towards the progress.
+    assert_eq!(progress, [(1, 2), (2, 2)]);
+
+    let device = conn.into_inner();
+    let block_size = SIDELOAD_BLOCK_SIZE as usize;
+    assert_eq!(
+        device.received,
+        [
+            (1, data[block_size..].to_vec()),
+            (0, data[..block_size].to_vec()),
+            (1, data[block_size..].to_vec()),
+        ],
+    );
+}
+
+#[test]
+fn sideload_failure() {
+    let size = 1000;
+    let device = MockDevice::new(size, &[0], b"FAILFAIL");
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    let mut conn = AdbConnection::connect(device, None).unwrap();
+    let result = adb::sideload(
+        &mut conn,
+        Cursor::new(package(size)),
+        size,
+        |_, _| {},
+        &cancel_signal,
+    );
+    assert_matches!(result, Err(adb::Error::SideloadFailed));
+}
+
+#[test]
+fn sideload_out_of_bounds() {
+    let size = 1000;
+    let device = MockDevice::new(size, &[5], b"DONEDONE");
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    let mut conn = AdbConnection::connect(device, None).unwrap();
+    let result = adb::sideload(
+        &mut conn,
+        Cursor::new(package(size)),
+        size,
+        |_, _| {},
+        &cancel_signal,
+    );
+    assert_matches!(result, Err(adb::Error::BlockOutOfBounds(5)));
+}
@@ -0,0 +1,138 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use avbroot::{
+    analyze,
+    format::{
+        compression::CompressedFormat,
+        ota,
+        payload::{CompressedPartitionWriter, PayloadHeader, PayloadWriter},
+    },
+    protobuf::chromeos_update_engine::{
+        mod_InstallOperation::Type, DeltaArchiveManifest, Extent, InstallOperation, PartitionUpdate,
+    },
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const BLOCK_SIZE: u32 = 4096;
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+/// Build an OTA zip containing a payload with a boot image and an empty system
+/// image.
+fn build_ota() -> (Vec<u8>, u64) {
+    let mut boot = PartitionUpdate {
+        partition_name: "boot".to_owned(),
+        ..Default::default()
+    };
+    let mut writer = CompressedPartitionWriter::new(Vec::new(), BLOCK_SIZE).unwrap();
+    writer
+        .write_all(include_bytes!("data/boot_v4.img"))
+        .unwrap();
+    let boot_blob = writer.finish(&mut boot).unwrap();
+
+    let system = PartitionUpdate {
+        partition_name: "system".to_owned(),
+        operations: vec![InstallOperation {
+            type_pb: Type::ZERO,
+            dst_extents: vec![Extent {
+                start_block: Some(0),
+                num_blocks: Some(4),
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions: vec![system, boot],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    let mut writer = PayloadWriter::new(Cursor::new(Vec::new()), header, get_test_key()).unwrap();
+    while writer.begin_next_operation().unwrap() {
+        if writer.operation().unwrap().data_length.is_some() {
+            writer.write_all(&boot_blob).unwrap();
+        }
+    }
+    let (writer, _, _) = writer.finish().unwrap();
+
+    let mut zip_writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip_writer.start_file(ota::PATH_PAYLOAD, options).unwrap();
+    zip_writer.write_all(&writer.into_inner()).unwrap();
+
+    let data = zip_writer.finish().unwrap().into_inner();
+
+    (data, boot_blob.len() as u64)
+}
+
+#[test]
+fn compression_report() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (data, boot_blob_size) = build_ota();
+
+    let reports = analyze::compression_report(Cursor::new(&data), &cancel_signal).unwrap();
+    assert_eq!(
+        reports.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+        ["boot", "system"],
+    );
+
+    let boot = &reports[0];
+    assert_eq!(boot.size, 12288);
+    assert_eq!(boot.payload_size, boot_blob_size);
+    assert_eq!(boot.operation_types, ["REPLACE_XZ"]);
+    assert_eq!(
+        boot.estimates.iter().map(|e| e.format).collect::<Vec<_>>(),
+        [
+            CompressedFormat::Gzip,
+            CompressedFormat::Lz4Legacy,
+            CompressedFormat::Xz,
+        ],
+    );
+    assert!(boot
+        .estimates
+        .iter()
+        .all(|e| e.size > 0 && e.size < boot.size));
+
+    // The test ramdisk is not compressed.
+    assert_eq!(boot.ramdisks.len(), 1);
+    assert_eq!(boot.ramdisks[0].format, CompressedFormat::None);
+    assert_eq!(boot.ramdisks[0].compressed_size, 12);
+    assert_eq!(boot.ramdisks[0].uncompressed_size, 12);
+    assert_eq!(boot.ramdisks[0].estimates.len(), 3);
+
+    let system = &reports[1];
+    assert_eq!(system.size, 4 * u64::from(BLOCK_SIZE));
+    assert_eq!(system.payload_size, 0);
+    assert_eq!(system.operation_types, ["ZERO"]);
+    assert!(system.ramdisks.is_empty());
+}
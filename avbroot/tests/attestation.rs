@@ -0,0 +1,159 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    attestation,
+    format::{
+        ota,
+        payload::{CompressedPartitionWriter, PayloadHeader, PayloadWriter},
+    },
+    protobuf::chromeos_update_engine::{DeltaArchiveManifest, PartitionUpdate},
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const BLOCK_SIZE: u32 = 4096;
+
+// Test images with unsigned vbmeta headers. vbmeta chains to vbmeta_system and
+// boot, which has an appended vbmeta footer.
+static VBMETA: &[u8] = include_bytes!("data/attestation_vbmeta.img");
+static VBMETA_SYSTEM: &[u8] = include_bytes!("data/attestation_vbmeta_system.img");
+static BOOT: &[u8] = include_bytes!("data/attestation_boot.img");
+
+/// Reference digest computed outside of avbroot by hashing the test images the
+/// same way as `avbtool calculate_vbmeta_digest --image vbmeta.img`.
+const EXPECTED_DIGEST: &str = "81880e5782a0adeea5f3145f485d275dfe85bfc666d2a258ecb65d1bb35050ac";
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+/// Build a full OTA zip containing the specified partition images.
+fn build_ota(images: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut partitions = vec![];
+    let mut blobs = vec![];
+
+    for (name, data) in images {
+        let mut partition = PartitionUpdate {
+            partition_name: (*name).to_owned(),
+            ..Default::default()
+        };
+        let mut writer = CompressedPartitionWriter::new(Vec::new(), BLOCK_SIZE).unwrap();
+        writer.write_all(data).unwrap();
+        blobs.push(writer.finish(&mut partition).unwrap());
+        partitions.push(partition);
+    }
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions,
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    let mut writer = PayloadWriter::new(Cursor::new(Vec::new()), header, get_test_key()).unwrap();
+    while writer.begin_next_operation().unwrap() {
+        let index = writer.partition_index().unwrap();
+        writer.write_all(&blobs[index]).unwrap();
+    }
+    let (writer, _, _) = writer.finish().unwrap();
+
+    let mut zip_writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip_writer.start_file(ota::PATH_PAYLOAD, options).unwrap();
+    zip_writer.write_all(&writer.into_inner()).unwrap();
+
+    zip_writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn expected_vbmeta_digest() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    // The payload order does not matter, only the descriptor order.
+    let data = build_ota(&[
+        ("boot", BOOT),
+        ("system", &[0u8; 8192][..]),
+        ("vbmeta", VBMETA),
+        ("vbmeta_system", VBMETA_SYSTEM),
+    ]);
+
+    let digest = attestation::expected_vbmeta_digest(Cursor::new(&data), &cancel_signal).unwrap();
+    assert_eq!(hex::encode(digest), EXPECTED_DIGEST);
+
+    // This is not simply the digest of the vbmeta partition.
+    let root_digest = ring::digest::digest(&ring::digest::SHA256, VBMETA);
+    assert_ne!(hex::encode(root_digest), EXPECTED_DIGEST);
+}
+
+#[test]
+fn missing_chained_partition() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = build_ota(&[("boot", BOOT), ("vbmeta", VBMETA)]);
+
+    assert_matches!(
+        attestation::expected_vbmeta_digest(Cursor::new(&data), &cancel_signal),
+        Err(attestation::Error::MissingPartition(p)) if p == "vbmeta_system"
+    );
+}
+
+#[test]
+fn nested_chain_partition() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    // vbmeta_system chains to itself via a copy of the top-level vbmeta image.
+    let data = build_ota(&[
+        ("boot", BOOT),
+        ("vbmeta", VBMETA),
+        ("vbmeta_system", VBMETA),
+    ]);
+
+    assert_matches!(
+        attestation::expected_vbmeta_digest(Cursor::new(&data), &cancel_signal),
+        Err(attestation::Error::NestedChainPartition(p, n))
+            if p == "vbmeta_system" && n == "vbmeta_system"
+    );
+}
+
+#[test]
+fn verify_vbmeta_digest() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = build_ota(&[
+        ("boot", BOOT),
+        ("vbmeta", VBMETA),
+        ("vbmeta_system", VBMETA_SYSTEM),
+    ]);
+    let mut expected = hex::decode(EXPECTED_DIGEST).unwrap();
+
+    attestation::verify_vbmeta_digest(Cursor::new(&data), &expected, &cancel_signal).unwrap();
+
+    expected[0] ^= 0xff;
+    assert_matches!(
+        attestation::verify_vbmeta_digest(Cursor::new(&data), &expected, &cancel_signal),
+        Err(attestation::Error::VbmetaDigestMismatch(e, a))
+            if e == hex::encode(&expected) && a == EXPECTED_DIGEST
+    );
+}
@@ -3,13 +3,28 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::{
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 use assert_matches::assert_matches;
 use pkcs8::DecodePrivateKey;
 use rsa::RsaPrivateKey;
 
-use avbroot::{self, format::avb};
+use avbroot::{
+    self, crypto,
+    format::{
+        avb::{
+            self, ChainPartitionDescriptor, ChainedPartition, Descriptor, HashDescriptor, HashTree,
+            HashtreeDescriptor, Header, IncrementalHashTree, KernelCmdlineDescriptor,
+            PropertyDescriptor,
+        },
+        fec,
+    },
+    stream::{FromReader, ReadSeek, ToWriter},
+};
 
 fn get_test_key() -> RsaPrivateKey {
     let data = include_str!(concat!(
@@ -24,6 +39,20 @@ fn get_test_key() -> RsaPrivateKey {
     RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
 }
 
+/// A different key of the same size as [`get_test_key()`].
+fn get_other_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
 #[test]
 fn round_trip_root_image() {
     let data = include_bytes!(concat!(
@@ -49,6 +78,103 @@ fn round_trip_root_image() {
     assert_eq!(data, new_data.as_slice());
 }
 
+#[test]
+fn round_trip_unordered_descriptors() {
+    // Unsigned copy of vbmeta_root.img with the descriptors reordered to chain
+    // partition, hashtree, property, hash, and kernel cmdline.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_unordered.img",
+    ));
+    let root_data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+
+    let header = Header::from_reader(Cursor::new(data)).unwrap();
+    let root_header = Header::from_reader(Cursor::new(root_data)).unwrap();
+
+    assert_matches!(
+        header.descriptors.as_slice(),
+        [
+            Descriptor::ChainPartition(_),
+            Descriptor::Hashtree(_),
+            Descriptor::Property(_),
+            Descriptor::Hash(_),
+            Descriptor::KernelCmdline(_),
+        ]
+    );
+    for (i, root_i) in [4, 1, 0, 2, 3].into_iter().enumerate() {
+        assert_eq!(header.descriptors[i], root_header.descriptors[root_i]);
+    }
+
+    let mut writer = Cursor::new(Vec::new());
+    header.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data);
+}
+
+#[test]
+fn reject_overflowing_header_offsets() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+
+    // Each offset is followed by its size. Offsets that overflow when adding
+    // the size must not be treated as in bounds.
+    for (offset, offset_field, size_field) in [
+        (32, "hash_offset", "hash_size"),
+        (48, "signature_offset", "signature_size"),
+        (64, "public_key_offset", "public_key_size"),
+        (96, "descriptors_offset", "descriptors_size"),
+    ] {
+        let mut data = data.to_vec();
+        data[offset..offset + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        assert_matches!(
+            Header::from_reader(Cursor::new(&data)),
+            Err(avb::Error::OutOfBounds(o, s)) if o == offset_field && s == size_field
+        );
+    }
+}
+
+#[test]
+fn resign_keeps_header_flags() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    header.set_hashtree_disabled(true);
+    // An unknown bit must not be normalized away either.
+    let unknown_flag = 1 << 31;
+    header.flags |= unknown_flag;
+    assert!(header.is_hashtree_disabled());
+    assert!(!header.is_verification_disabled());
+
+    let key = get_test_key();
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 64).unwrap();
+
+    let (mut new_header, _, _) = avb::load_image(Cursor::new(writer.into_inner())).unwrap();
+    assert_eq!(
+        new_header.flags,
+        avb::HEADER_FLAG_HASHTREE_DISABLED | unknown_flag,
+    );
+    assert!(!new_header.descriptors.is_empty());
+    new_header.verify().unwrap();
+
+    // Re-signing again without changes keeps the flags.
+    new_header.sign(&key).unwrap();
+    assert_eq!(new_header.flags, header.flags);
+
+    new_header.set_hashtree_disabled(false);
+    assert_eq!(new_header.flags, unknown_flag);
+}
+
 #[test]
 fn round_trip_appended_image() {
     let data = include_bytes!(concat!(
@@ -80,3 +206,1276 @@ fn round_trip_appended_image() {
 
     assert_eq!(data, new_data.as_slice());
 }
+
+#[test]
+fn footer_reserved_bytes() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    let mut data = data.to_vec();
+    let footer_offset = data.len() - avb::Footer::SIZE;
+    // The reserved field is at the end of the footer.
+    let reserved = data.len() - 28;
+    for (i, b) in data[reserved..].iter_mut().enumerate() {
+        *b = i as u8 + 1;
+    }
+
+    let (footer, offset) = avb::find_footer(Cursor::new(&data)).unwrap().unwrap();
+    assert_eq!(offset, footer_offset as u64);
+    assert_eq!(footer.reserved, data[reserved..]);
+
+    let mut footer_raw = vec![];
+    footer.to_writer(&mut footer_raw).unwrap();
+    assert_eq!(footer_raw, data[footer_offset..]);
+
+    // Re-signing keeps the reserved bytes too.
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut writer = Cursor::new(Vec::new());
+    avb::resign_footer_in_place(
+        Cursor::new(&data),
+        &get_test_key(),
+        &mut writer,
+        &cancel_signal,
+    )
+    .unwrap();
+    let new_data = writer.into_inner();
+    assert_eq!(new_data[reserved..], data[reserved..]);
+}
+
+#[test]
+fn find_footer_after_padding() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    // Same image, but padded to a 16 KiB block size after the footer.
+    let padded = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended_padded.img",
+    ));
+    let footer_offset = (data.len() - avb::Footer::SIZE) as u64;
+
+    let (footer, offset) = avb::find_footer(Cursor::new(data)).unwrap().unwrap();
+    assert_eq!(offset, footer_offset);
+
+    let (padded_footer, padded_offset) = avb::find_footer(Cursor::new(padded)).unwrap().unwrap();
+    assert_eq!(padded_offset, footer_offset);
+    assert_eq!(padded_footer, footer);
+
+    let (header, padded_header_footer, image_size) = avb::load_image(Cursor::new(padded)).unwrap();
+    assert_eq!(padded_header_footer, Some(footer.clone()));
+    assert_eq!(image_size, padded.len() as u64);
+
+    // Write the footer back at the original offset and restore the padding.
+    let mut writer = Cursor::new(Vec::new());
+    io::copy(
+        &mut Cursor::new(&padded[..footer.original_image_size as usize]),
+        &mut writer,
+    )
+    .unwrap();
+    avb::write_appended_image(&mut writer, &header, &footer, padded_offset + 64).unwrap();
+    writer.get_mut().resize(padded.len(), 0);
+    assert_eq!(writer.get_ref().as_slice(), padded);
+
+    // A footer magic followed by data is not a footer.
+    let mut corrupt = padded.to_vec();
+    *corrupt.last_mut().unwrap() = 1;
+    assert_matches!(avb::find_footer(Cursor::new(&corrupt)), Ok(None));
+    assert_matches!(
+        avb::load_image(Cursor::new(&corrupt)),
+        Err(avb::Error::InvalidHeaderMagic(_))
+    );
+
+    // A stray footer magic that is followed by zeros, but with fields that
+    // point past it, is not a footer either.
+    let mut stray = padded.to_vec();
+    let stray_offset = padded.len() - 2 * avb::Footer::SIZE;
+    assert!(stray_offset >= footer_offset as usize + avb::Footer::SIZE);
+    stray[stray_offset..][..4].copy_from_slice(b"AVBf");
+    stray[stray_offset + 12..][..8].copy_from_slice(&u64::MAX.to_be_bytes());
+    assert_matches!(avb::find_footer(Cursor::new(&stray)), Ok(None));
+
+    // Padding beyond the search window is not searched.
+    let mut far = data.to_vec();
+    far.resize(data.len() + avb::FOOTER_SEARCH_WINDOW as usize, 0);
+    assert_matches!(avb::find_footer(Cursor::new(&far)), Ok(None));
+}
+
+#[test]
+fn replace_appended_header() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    let (mut header, footer, image_size) = avb::load_image(Cursor::new(data)).unwrap();
+    let footer = footer.unwrap();
+    let key = get_test_key();
+
+    // Grow the header beyond the space reserved for it.
+    let descriptor = Descriptor::KernelCmdline(KernelCmdlineDescriptor {
+        flags: 0,
+        cmdline: "x".repeat(8192),
+    });
+    header.descriptors.push(descriptor);
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(data.to_vec());
+    let new_size =
+        avb::replace_appended_header(&mut writer, &header, &footer, image_size, 4096).unwrap();
+    let new_data = writer.into_inner();
+    assert_eq!(new_size, new_data.len() as u64);
+    assert!(new_size > image_size);
+    assert_eq!(new_size % 4096, 0);
+
+    // Everything before the header is untouched.
+    let vbmeta_offset = footer.vbmeta_offset as usize;
+    assert_eq!(new_data[..vbmeta_offset], data[..vbmeta_offset]);
+
+    let (new_header, new_footer, _) = avb::load_image(Cursor::new(&new_data)).unwrap();
+    let new_footer = new_footer.unwrap();
+    assert_eq!(new_header, header);
+    assert_eq!(new_footer.original_image_size, footer.original_image_size);
+    assert_eq!(new_footer.vbmeta_offset, footer.vbmeta_offset);
+    assert!(new_header.verify().unwrap().is_some());
+}
+
+#[test]
+fn resign_footer_in_place() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    let padded = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended_padded.img",
+    ));
+    let (header, footer, _) = avb::load_image(Cursor::new(data)).unwrap();
+    let footer = footer.unwrap();
+    let key = get_other_key();
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    let mut writer = Cursor::new(Vec::new());
+    let new_header =
+        avb::resign_footer_in_place(Cursor::new(data), &key, &mut writer, &cancel_signal).unwrap();
+    let new_data = writer.into_inner();
+    assert_eq!(new_data.len(), data.len());
+
+    // The data, hash tree, and FEC data are copied verbatim.
+    let vbmeta_offset = footer.vbmeta_offset as usize;
+    assert_eq!(new_data[..vbmeta_offset], data[..vbmeta_offset]);
+    assert_ne!(new_data, data);
+
+    let (loaded_header, loaded_footer, _) = avb::load_image(Cursor::new(&new_data)).unwrap();
+    assert_eq!(loaded_header, new_header);
+    assert_eq!(loaded_footer, Some(footer));
+    assert_eq!(loaded_header.descriptors, header.descriptors);
+    assert_eq!(loaded_header.verify().unwrap(), Some(key.to_public_key()));
+
+    for descriptor in &loaded_header.descriptors {
+        if let Descriptor::Hash(d) = descriptor {
+            d.verify(
+                Cursor::new(&new_data[..d.image_size as usize]),
+                &cancel_signal,
+            )
+            .unwrap();
+        }
+    }
+
+    // Padding after the footer is kept.
+    let mut writer = Cursor::new(Vec::new());
+    avb::resign_footer_in_place(Cursor::new(padded), &key, &mut writer, &cancel_signal).unwrap();
+    let new_padded = writer.into_inner();
+    assert_eq!(new_padded.len(), padded.len());
+    assert_eq!(new_padded[..new_data.len()], new_data);
+    assert!(new_padded[new_data.len()..].iter().all(|b| *b == 0));
+
+    // The algorithm type is kept, so the key size cannot change.
+    let other_size = crypto::read_pem_key(
+        include_bytes!("data/key_rsa3072.pem").as_slice(),
+        &crypto::PassphraseSource::EnvVar("AVBROOT_UNUSED".into()),
+    )
+    .unwrap();
+    assert_matches!(
+        avb::resign_footer_in_place(Cursor::new(data), &other_size, io::sink(), &cancel_signal),
+        Err(avb::Error::IncorrectKeySize(..))
+    );
+
+    let root = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+    assert_matches!(
+        avb::resign_footer_in_place(Cursor::new(root), &key, io::sink(), &cancel_signal),
+        Err(avb::Error::MissingFooter)
+    );
+}
+
+const TREE_BLOCK_SIZE: u32 = 4096;
+const TREE_SALT: &[u8] = b"avbroot";
+/// Computed independently with Python's hashlib.
+const TREE_ROOT_DIGEST: &str = "1bbf3945dee34cf6eab749a9bc56828e81829f2fa356cc490d031d2d7f9bc5ab";
+const TREE_DIGEST: &str = "8f57cea28be2edec8549fd7cacd619f21b5048f18db58b6d3716642d96bee719";
+
+/// 130 blocks, which is enough for a two-level tree with SHA256.
+fn hash_tree_data() -> Vec<u8> {
+    (0..130u32)
+        .flat_map(|i| vec![(i % 251) as u8; TREE_BLOCK_SIZE as usize])
+        .collect()
+}
+
+fn calculate_hash_tree(data: &[u8]) -> HashTree {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = data.to_vec();
+
+    HashTree::calculate(
+        || Ok(Box::new(Cursor::new(data.clone()))),
+        "sha256",
+        TREE_BLOCK_SIZE,
+        data.len() as u64,
+        TREE_SALT,
+        &cancel_signal,
+    )
+    .unwrap()
+}
+
+#[test]
+fn hash_tree_metrics() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = hash_tree_data();
+
+    let (tree, metrics) = HashTree::calculate_with_metrics(
+        || Ok(Box::new(Cursor::new(data.clone()))),
+        "sha256",
+        TREE_BLOCK_SIZE,
+        data.len() as u64,
+        TREE_SALT,
+        &cancel_signal,
+    )
+    .unwrap();
+    assert_eq!(tree, calculate_hash_tree(&data));
+
+    // One duration per level above the leaves.
+    assert_eq!(metrics.levels.len(), tree.num_levels() - 1);
+    assert!(metrics.leaf_hashing > Duration::ZERO);
+    assert!(
+        metrics.total
+            >= metrics.leaf_hashing + metrics.levels.iter().sum::<Duration>() + metrics.root
+    );
+}
+
+#[test]
+fn hash_tree_known_layout() {
+    let data = hash_tree_data();
+    let tree = calculate_hash_tree(&data);
+
+    assert_eq!(hex::encode(tree.root_digest()), TREE_ROOT_DIGEST);
+    assert_eq!(
+        hex::encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            &tree.to_bytes()
+        )),
+        TREE_DIGEST,
+    );
+    assert_eq!(tree.num_levels(), 2);
+    assert_eq!(tree.num_blocks(), 130);
+
+    // Leaves are the hashes of the data blocks.
+    for i in [0u64, 1, 127, 128, 129] {
+        let start = i as usize * TREE_BLOCK_SIZE as usize;
+        let block = &data[start..start + TREE_BLOCK_SIZE as usize];
+
+        assert_eq!(
+            tree.node_at(0, i),
+            Some(tree.hash_block(block).unwrap().as_ref())
+        );
+    }
+    assert_eq!(tree.node_at(0, 130), None);
+
+    // Level 1 has one node per block of level 0.
+    assert!(tree.node_at(1, 1).is_some());
+    assert_eq!(tree.node_at(1, 2), None);
+    assert_eq!(tree.node_at(2, 0), None);
+
+    // Loading the serialized tree gives the same result.
+    let loaded = HashTree::new(
+        "sha256",
+        TREE_BLOCK_SIZE,
+        data.len() as u64,
+        TREE_SALT,
+        tree.root_digest(),
+        &tree.to_bytes(),
+    )
+    .unwrap();
+    assert_eq!(loaded, tree);
+}
+
+#[test]
+fn hash_tree_verify_block() {
+    let data = hash_tree_data();
+    let tree = calculate_hash_tree(&data);
+    let block_size = TREE_BLOCK_SIZE as usize;
+
+    for (i, block) in data.chunks_exact(block_size).enumerate() {
+        tree.verify_block(i as u64, block).unwrap();
+    }
+
+    let mut bad_block = data[..block_size].to_vec();
+    bad_block[0] ^= 0xff;
+    assert_matches!(
+        tree.verify_block(0, &bad_block),
+        Err(avb::Error::InvalidBlockDigest(0, 0))
+    );
+    assert_matches!(
+        tree.verify_block(0, &bad_block[..10]),
+        Err(avb::Error::IncorrectBlockSize(0, 4096, 10))
+    );
+    assert_matches!(
+        tree.verify_block(130, &data[..block_size]),
+        Err(avb::Error::BlockOutOfBounds(130))
+    );
+
+    // Corrupting the upper level is detected too. The first node of the top
+    // level covers block 0 and the padding after the two nodes is only covered
+    // by the root digest.
+    for (offset, level) in [(0, Some(1)), (100, None)] {
+        let mut tree_data = tree.to_bytes();
+        tree_data[offset] ^= 0xff;
+
+        let bad_tree = HashTree::new(
+            "sha256",
+            TREE_BLOCK_SIZE,
+            data.len() as u64,
+            TREE_SALT,
+            tree.root_digest(),
+            &tree_data,
+        )
+        .unwrap();
+        let result = bad_tree.verify_block(0, &data[..block_size]);
+
+        match level {
+            Some(l) => assert_matches!(result, Err(avb::Error::InvalidBlockDigest(0, n)) if n == l),
+            None => assert_matches!(result, Err(avb::Error::InvalidRootDigest(_, _))),
+        }
+    }
+}
+
+#[test]
+fn hash_tree_invalid_block_size() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = hash_tree_data();
+
+    for block_size in [0, 3000, 16] {
+        let results = [
+            HashTree::new(
+                "sha256",
+                block_size,
+                data.len() as u64,
+                TREE_SALT,
+                &[0u8; 32],
+                &[],
+            )
+            .map(|_| ()),
+            HashTree::calculate(
+                || Ok(Box::new(Cursor::new(data.clone()))),
+                "sha256",
+                block_size,
+                data.len() as u64,
+                TREE_SALT,
+                &cancel_signal,
+            )
+            .map(|_| ()),
+            IncrementalHashTree::new("sha256", block_size, TREE_SALT).map(|_| ()),
+        ];
+
+        for result in results {
+            if block_size == 0 {
+                assert_matches!(result, Err(avb::Error::ZeroBlockSize("block_size")));
+            } else {
+                assert_matches!(
+                    result,
+                    Err(avb::Error::InvalidBlockSize(s, 32)) if s == block_size
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn incremental_hash_tree() {
+    let block_size = TREE_BLOCK_SIZE as usize;
+    let data = hash_tree_data();
+
+    // Multiple levels, a single level, and an undersized last block.
+    for len in [data.len(), 2 * block_size, data.len() - 100] {
+        let data = &data[..len];
+        let mut incremental =
+            IncrementalHashTree::new("sha256", TREE_BLOCK_SIZE, TREE_SALT).unwrap();
+
+        for block in data.chunks(block_size) {
+            incremental.update(block).unwrap();
+        }
+        assert_eq!(incremental.image_size(), len as u64);
+
+        assert_eq!(
+            incremental.finalize().unwrap(),
+            calculate_hash_tree(data),
+            "Length: {len}"
+        );
+    }
+
+    // A single block has no tree.
+    let mut incremental = IncrementalHashTree::new("sha256", TREE_BLOCK_SIZE, TREE_SALT).unwrap();
+    incremental.update(&data[..block_size]).unwrap();
+    let tree = incremental.finalize().unwrap();
+    assert_eq!(tree.num_levels(), 0);
+    assert_eq!(
+        tree.root_digest(),
+        tree.hash_block(&data[..block_size]).unwrap().as_ref()
+    );
+    assert_matches!(
+        tree.hash_block(&data[..block_size + 1]),
+        Err(avb::Error::BlockTooLarge(4097, 4096))
+    );
+
+    // Only the last block can be undersized.
+    let mut incremental = IncrementalHashTree::new("sha256", TREE_BLOCK_SIZE, TREE_SALT).unwrap();
+    incremental.update(&data[..10]).unwrap();
+    assert_matches!(
+        incremental.update(&data[..block_size]),
+        Err(avb::Error::IncorrectBlockSize(1, 4096, 4096))
+    );
+    assert_matches!(
+        incremental.update(&data[..block_size + 1]),
+        Err(avb::Error::IncorrectBlockSize(1, 4096, 4097))
+    );
+}
+
+#[test]
+fn update_hash_descriptor() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = b"raw firmware image".repeat(100);
+
+    let mut descriptor = HashDescriptor {
+        image_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: "pvmfw".to_owned(),
+        salt: b"avbroot".to_vec(),
+        root_digest: vec![],
+        flags: 0,
+        reserved: [0u8; 60],
+    };
+    descriptor
+        .update(Cursor::new(&data), data.len() as u64, &cancel_signal)
+        .unwrap();
+
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(b"avbroot");
+    context.update(&data);
+    assert_eq!(descriptor.image_size, data.len() as u64);
+    assert_eq!(descriptor.root_digest, context.finish().as_ref());
+
+    descriptor
+        .verify(Cursor::new(&data), &cancel_signal)
+        .unwrap();
+
+    let mut bad_data = data.clone();
+    bad_data[0] ^= 0xff;
+    assert_matches!(
+        descriptor.verify(Cursor::new(&bad_data), &cancel_signal),
+        Err(avb::Error::InvalidRootDigest(_, _))
+    );
+}
+
+/// Computed independently with Python's hashlib and a separate Reed-Solomon
+/// implementation.
+const FEC_ROOT_DIGEST: &str = "26f7d4f203138ffa1adb22ceca48d910bfd16be6433cd071e30cb7cf8937854a";
+const FEC_DIGEST: &str = "1a248b8057cd95297c478ecd90393b8b00775b71e4b3151c67c18d7cb1e46a23";
+
+#[test]
+fn update_hashtree_descriptor_with_fec() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut data = hash_tree_data();
+    // Not a multiple of the block size so that the tree offset is padded.
+    data.truncate(data.len() - 1000);
+
+    let mut descriptor = HashtreeDescriptor {
+        dm_verity_version: 1,
+        image_size: 0,
+        tree_offset: 0,
+        tree_size: 0,
+        data_block_size: TREE_BLOCK_SIZE,
+        hash_block_size: TREE_BLOCK_SIZE,
+        fec_num_roots: 2,
+        fec_offset: 0,
+        fec_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: "system".to_owned(),
+        salt: TREE_SALT.to_vec(),
+        root_digest: vec![],
+        flags: 0,
+        reserved: [0u8; 60],
+    };
+    let (tree, fec_data) = {
+        let data = data.clone();
+        descriptor
+            .update(
+                || Ok(Box::new(Cursor::new(data.clone()))),
+                data.len() as u64,
+                &cancel_signal,
+            )
+            .unwrap()
+    };
+
+    assert_eq!(descriptor.image_size, data.len() as u64);
+    assert_eq!(descriptor.tree_offset, 130 * u64::from(TREE_BLOCK_SIZE));
+    assert_eq!(descriptor.tree_size, tree.len() as u64);
+    assert_eq!(
+        descriptor.fec_offset,
+        descriptor.tree_offset + descriptor.tree_size
+    );
+    assert_eq!(descriptor.fec_size, fec_data.len() as u64);
+    assert_eq!(
+        descriptor.fec_size,
+        fec::fec_size(descriptor.fec_offset, descriptor.fec_num_roots).unwrap(),
+    );
+    assert_eq!(hex::encode(&descriptor.root_digest), FEC_ROOT_DIGEST);
+    assert_eq!(
+        hex::encode(ring::digest::digest(&ring::digest::SHA256, &fec_data)),
+        FEC_DIGEST,
+    );
+
+    // The header block records what the FEC data covers.
+    let header_offset = fec_data.len() - fec::FEC_BLOCK_SIZE as usize;
+    let header = fec::Header::from_reader(&fec_data[header_offset..]).unwrap();
+    assert_eq!(header.roots, descriptor.fec_num_roots);
+    assert_eq!(header.input_size, descriptor.fec_offset);
+    assert_eq!(
+        u64::from(header.fec_size),
+        descriptor.fec_size - fec::FEC_BLOCK_SIZE
+    );
+
+    // Assemble the image like avbtool does and verify it.
+    let mut image = data.clone();
+    image.resize(descriptor.tree_offset as usize, 0);
+    image.extend_from_slice(&tree);
+    image.extend_from_slice(&fec_data);
+
+    let verify = |image: &[u8]| {
+        let image = image.to_vec();
+        descriptor.verify(|| Ok(Box::new(Cursor::new(image.clone()))), &cancel_signal)
+    };
+
+    verify(&image).unwrap();
+
+    // Corrupt the FEC data.
+    let mut bad_image = image.clone();
+    bad_image[descriptor.fec_offset as usize] ^= 0xff;
+    assert_matches!(
+        verify(&bad_image),
+        Err(avb::Error::FecError(fec::Error::InvalidDigest(_, _)))
+    );
+
+    // Without FEC roots, nothing is generated.
+    descriptor.fec_num_roots = 0;
+    let (_, fec_data) = descriptor
+        .update(
+            || Ok(Box::new(Cursor::new(data.clone()))),
+            data.len() as u64,
+            &cancel_signal,
+        )
+        .unwrap();
+    assert!(fec_data.is_empty());
+    assert_eq!(descriptor.fec_offset, 0);
+    assert_eq!(descriptor.fec_size, 0);
+}
+
+#[test]
+fn enable_hashtree_fec() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = hash_tree_data();
+
+    let mut descriptor = HashtreeDescriptor {
+        dm_verity_version: 1,
+        image_size: 0,
+        tree_offset: 0,
+        tree_size: 0,
+        data_block_size: TREE_BLOCK_SIZE,
+        hash_block_size: TREE_BLOCK_SIZE,
+        fec_num_roots: 0,
+        fec_offset: 0,
+        fec_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: "system".to_owned(),
+        salt: TREE_SALT.to_vec(),
+        root_digest: vec![],
+        flags: 0,
+        reserved: [0u8; 60],
+    };
+
+    for roots in [fec::MIN_ROOTS - 1, fec::MAX_ROOTS + 1] {
+        assert_matches!(
+            descriptor.enable_fec(roots),
+            Err(avb::Error::FecError(fec::Error::InvalidRoots(r))) if r == roots
+        );
+        assert_matches!(
+            fec::add_fec(|| Ok(Cursor::new(&data)), data.len() as u64, roots, &cancel_signal),
+            Err(fec::Error::InvalidRoots(r)) if r == roots
+        );
+    }
+    assert_eq!(descriptor.fec_num_roots, 0);
+
+    descriptor.enable_fec(2).unwrap();
+    let (tree, fec_data) = {
+        let data = data.clone();
+        descriptor
+            .update(
+                || Ok(Box::new(Cursor::new(data.clone()))),
+                data.len() as u64,
+                &cancel_signal,
+            )
+            .unwrap()
+    };
+
+    let mut image = data.clone();
+    image.extend_from_slice(&tree);
+    assert_eq!(descriptor.fec_offset, image.len() as u64);
+    assert_eq!(descriptor.fec_size, fec_data.len() as u64);
+
+    // Same result as generating the FEC data directly.
+    let (direct, offset, size) = fec::add_fec(
+        || Ok(Cursor::new(&image)),
+        image.len() as u64,
+        2,
+        &cancel_signal,
+    )
+    .unwrap();
+    assert!(direct == fec_data);
+    assert_eq!(offset, descriptor.fec_offset);
+    assert_eq!(size, descriptor.fec_size);
+
+    let fec_input = image.clone();
+    image.extend_from_slice(&fec_data);
+    {
+        let image = image.clone();
+        descriptor
+            .verify(|| Ok(Box::new(Cursor::new(image.clone()))), &cancel_signal)
+            .unwrap();
+    }
+
+    // A bogus FEC size is rejected before the FEC data is read.
+    {
+        let image = image.clone();
+        let mut bad_descriptor = descriptor.clone();
+        bad_descriptor.fec_size = u64::MAX;
+        assert_matches!(
+            bad_descriptor.verify(|| Ok(Box::new(Cursor::new(image.clone()))), &cancel_signal),
+            Err(avb::Error::IncorrectFecSize(e, u64::MAX)) if e == fec_data.len() as u64
+        );
+    }
+
+    // Corrupt a whole block and repair it.
+    let block_size = TREE_BLOCK_SIZE as usize;
+    let mut bad_input = fec_input.clone();
+    for byte in &mut bad_input[5 * block_size..][..block_size] {
+        *byte = !*byte;
+    }
+
+    let mut cursor = Cursor::new(&mut bad_input);
+    let corrected = fec::correct(
+        &mut cursor,
+        fec_input.len() as u64,
+        descriptor.fec_num_roots,
+        &fec_data,
+        &cancel_signal,
+    )
+    .unwrap();
+    assert_eq!(corrected, block_size as u64);
+    assert!(bad_input == fec_input);
+
+    // Nothing to do for intact data.
+    let corrected = fec::correct(
+        Cursor::new(&mut bad_input),
+        fec_input.len() as u64,
+        descriptor.fec_num_roots,
+        &fec_data,
+        &cancel_signal,
+    )
+    .unwrap();
+    assert_eq!(corrected, 0);
+}
+
+/// Lay out an appended image with the data, the hash tree, the vbmeta header,
+/// and the footer, like avbtool does for a partition of `image_size` bytes.
+fn hashtree_image(image_size: u64) -> Vec<u8> {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = hash_tree_data();
+
+    let mut descriptor = HashtreeDescriptor {
+        dm_verity_version: 1,
+        image_size: 0,
+        tree_offset: 0,
+        tree_size: 0,
+        data_block_size: TREE_BLOCK_SIZE,
+        hash_block_size: TREE_BLOCK_SIZE,
+        fec_num_roots: 0,
+        fec_offset: 0,
+        fec_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: "system".to_owned(),
+        salt: TREE_SALT.to_vec(),
+        root_digest: vec![],
+        flags: 0,
+        reserved: [0u8; 60],
+    };
+    let (tree, _) = {
+        let data = data.clone();
+        descriptor
+            .update(
+                || Ok(Box::new(Cursor::new(data.clone()))),
+                data.len() as u64,
+                &cancel_signal,
+            )
+            .unwrap()
+    };
+
+    let fixture = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    let (mut header, footer, _) = avb::load_image(Cursor::new(fixture)).unwrap();
+    let mut footer = footer.unwrap();
+    header.descriptors = vec![Descriptor::Hashtree(descriptor)];
+    header.sign(&get_test_key()).unwrap();
+
+    let mut image = data.clone();
+    image.extend_from_slice(&tree);
+    image.resize(image.len().next_multiple_of(4096), 0);
+
+    footer.original_image_size = data.len() as u64;
+    footer.vbmeta_offset = image.len() as u64;
+    header.to_writer(&mut image).unwrap();
+    footer.vbmeta_size = image.len() as u64 - footer.vbmeta_offset;
+
+    image.resize(image_size as usize - avb::Footer::SIZE, 0);
+    footer.to_writer(&mut image).unwrap();
+
+    image
+}
+
+#[test]
+fn update_hashtree_image_enable_fec() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let key = get_other_key();
+    let image = hashtree_image(1024 * 1024);
+
+    let mut writer = Cursor::new(Vec::new());
+    let new_header = {
+        let image = image.clone();
+        avb::update_hashtree_image(
+            || Ok(Box::new(Cursor::new(image.clone()))),
+            &mut writer,
+            &key,
+            Some(2),
+            &cancel_signal,
+        )
+        .unwrap()
+    };
+    let new_image = writer.into_inner();
+    assert_eq!(new_image.len(), image.len());
+
+    let (header, footer, _) = avb::load_image(Cursor::new(&new_image)).unwrap();
+    assert_eq!(header, new_header);
+    assert_eq!(header.verify().unwrap(), Some(key.to_public_key()));
+
+    let Some(Descriptor::Hashtree(descriptor)) = header.descriptors.first() else {
+        panic!("Missing hashtree descriptor");
+    };
+    assert_eq!(descriptor.fec_num_roots, 2);
+    assert_ne!(descriptor.fec_size, 0);
+    assert_eq!(
+        descriptor.fec_offset,
+        descriptor.tree_offset + descriptor.tree_size
+    );
+
+    let footer = footer.unwrap();
+    assert!(footer.vbmeta_offset >= descriptor.fec_offset + descriptor.fec_size);
+    assert_eq!(footer.vbmeta_offset % 4096, 0);
+
+    descriptor
+        .verify(
+            || Ok(Box::new(Cursor::new(new_image.clone()))),
+            &cancel_signal,
+        )
+        .unwrap();
+}
+
+#[test]
+fn update_hashtree_image_insufficient_space() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let key = get_test_key();
+    // Room for the data, the tree, one block for the header, and one block for
+    // the footer, but not for FEC data.
+    let (_, footer, _) = avb::load_image(Cursor::new(hashtree_image(1024 * 1024))).unwrap();
+    let image_size = footer.unwrap().vbmeta_offset + 2 * 4096;
+    let image = hashtree_image(image_size);
+
+    let open_input =
+        || -> io::Result<Box<dyn ReadSeek>> { Ok(Box::new(Cursor::new(image.clone()))) };
+
+    assert_matches!(
+        avb::update_hashtree_image(open_input, io::sink(), &key, Some(2), &cancel_signal),
+        Err(avb::Error::InsufficientSpace(_, s)) if s == image_size
+    );
+
+    // Without FEC data, everything still fits.
+    let mut writer = Cursor::new(Vec::new());
+    avb::update_hashtree_image(open_input, &mut writer, &key, None, &cancel_signal).unwrap();
+    assert_eq!(writer.get_ref().len(), image.len());
+
+    // Images without a hashtree descriptor are rejected.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_appended.img",
+    ));
+    assert_matches!(
+        avb::update_hashtree_image(
+            || Ok(Box::new(Cursor::new(data))),
+            io::sink(),
+            &key,
+            None,
+            &cancel_signal,
+        ),
+        Err(avb::Error::MissingHashtreeDescriptor)
+    );
+}
+
+#[test]
+fn verify_hashtree_descriptor_tree_size() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = hash_tree_data();
+
+    let mut descriptor = HashtreeDescriptor {
+        dm_verity_version: 1,
+        image_size: 0,
+        tree_offset: 0,
+        tree_size: 0,
+        data_block_size: TREE_BLOCK_SIZE,
+        hash_block_size: TREE_BLOCK_SIZE,
+        fec_num_roots: 0,
+        fec_offset: 0,
+        fec_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: "system".to_owned(),
+        salt: TREE_SALT.to_vec(),
+        root_digest: vec![],
+        flags: 0,
+        reserved: [0u8; 60],
+    };
+    let (tree, _) = {
+        let data = data.clone();
+        descriptor
+            .update(
+                || Ok(Box::new(Cursor::new(data.clone()))),
+                data.len() as u64,
+                &cancel_signal,
+            )
+            .unwrap()
+    };
+    assert_eq!(descriptor.expected_tree_size().unwrap(), tree.len() as u64);
+
+    // The image must not be read if the descriptor is inconsistent.
+    let verify_unread = |descriptor: &HashtreeDescriptor| {
+        descriptor.verify(
+            || -> io::Result<Box<dyn ReadSeek>> { panic!("Image should not be read") },
+            &cancel_signal,
+        )
+    };
+
+    let mut bad_descriptor = descriptor.clone();
+    bad_descriptor.tree_size += u64::from(TREE_BLOCK_SIZE);
+    assert_matches!(
+        verify_unread(&bad_descriptor),
+        Err(avb::Error::IncorrectTreeSize(e, a))
+            if e == tree.len() as u64 && a == tree.len() + TREE_BLOCK_SIZE as usize
+    );
+
+    let mut bad_descriptor = descriptor.clone();
+    bad_descriptor.tree_size = 0;
+    assert_matches!(
+        verify_unread(&bad_descriptor),
+        Err(avb::Error::IncorrectTreeSize(_, 0))
+    );
+
+    let mut bad_descriptor = descriptor.clone();
+    bad_descriptor.hash_block_size = 0;
+    assert_matches!(
+        verify_unread(&bad_descriptor),
+        Err(avb::Error::ZeroBlockSize("hash_block_size"))
+    );
+
+    // Images that fit in a single block have no tree.
+    let mut small_descriptor = descriptor.clone();
+    small_descriptor.image_size = u64::from(TREE_BLOCK_SIZE);
+    assert_eq!(small_descriptor.expected_tree_size().unwrap(), 0);
+}
+
+#[test]
+fn edit_kernel_cmdline_descriptors() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    let orig_descriptors = header.descriptors.clone();
+
+    let verity = KernelCmdlineDescriptor {
+        flags: avb::KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED,
+        cmdline: "androidboot.veritymode=enforcing".to_owned(),
+    };
+    let other = KernelCmdlineDescriptor {
+        flags: 0,
+        cmdline: "console=ttyMSM0".to_owned(),
+    };
+
+    // Put the descriptor to be removed in between the original ones.
+    header
+        .descriptors
+        .insert(1, Descriptor::KernelCmdline(verity.clone()));
+    header
+        .descriptors
+        .push(Descriptor::KernelCmdline(other.clone()));
+
+    let removed = header.remove_kernel_cmdlines(|d| d.cmdline.contains("veritymode"));
+    assert_eq!(removed, [verity]);
+
+    let eio = KernelCmdlineDescriptor {
+        flags: avb::KERNEL_CMDLINE_FLAG_USE_ONLY_IF_HASHTREE_NOT_DISABLED,
+        cmdline: "androidboot.veritymode=eio".to_owned(),
+    };
+    header
+        .descriptors
+        .push(Descriptor::KernelCmdline(eio.clone()));
+
+    let mut expected = orig_descriptors;
+    expected.push(Descriptor::KernelCmdline(other));
+    expected.push(Descriptor::KernelCmdline(eio.clone()));
+    assert_eq!(header.descriptors, expected);
+
+    let key = get_test_key();
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 64).unwrap();
+    let new_data = writer.into_inner();
+
+    let (new_header, _, _) = avb::load_image(Cursor::new(&new_data)).unwrap();
+    assert_eq!(new_header, header);
+    assert!(new_header.verify().unwrap().is_some());
+
+    // tag + num_bytes_following + flags + cmdline length + 26-byte cmdline,
+    // with the data following the first two fields padded to 8 bytes.
+    let mut writer = Cursor::new(Vec::new());
+    Descriptor::KernelCmdline(eio)
+        .to_writer(&mut writer)
+        .unwrap();
+    let raw = writer.into_inner();
+    assert_eq!(raw.len(), 56);
+    assert_eq!(u64::from_be_bytes(raw[8..16].try_into().unwrap()), 40);
+}
+
+#[test]
+fn edit_property_descriptors() {
+    // Generated by avbtool with --prop and its default release string.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/attestation_vbmeta.img",
+    ));
+    let key = "com.android.build.vendor.fingerprint";
+
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    assert_eq!(header.release_string, "avbtool 1.2.0");
+
+    let index = header
+        .descriptors
+        .iter()
+        .position(|d| matches!(d, Descriptor::Property(p) if p.key == key))
+        .unwrap();
+    let Descriptor::Property(prop) = header.descriptors[index].clone() else {
+        unreachable!();
+    };
+
+    // tag + num_bytes_following + key and value lengths + 36-byte key + 46-byte
+    // value, each null terminated, padded to 8 bytes. This is exactly what
+    // avbtool wrote.
+    let mut writer = Cursor::new(Vec::new());
+    Descriptor::Property(prop.clone())
+        .to_writer(&mut writer)
+        .unwrap();
+    let raw = writer.into_inner();
+    assert_eq!(raw.len(), 120);
+    assert_eq!(u64::from_be_bytes(raw[8..16].try_into().unwrap()), 104);
+    assert!(data.windows(raw.len()).any(|w| w == raw));
+
+    // Re-signing keeps the properties and the release string.
+    let signing_key = get_test_key();
+    let orig = header.clone();
+    header.algorithm_type = crypto::validate_avb_key(&signing_key).unwrap();
+    header.sign(&signing_key).unwrap();
+
+    let resign = |header: &Header| {
+        let mut writer = Cursor::new(Vec::new());
+        avb::write_root_image(&mut writer, header, 4096).unwrap();
+        avb::load_image(Cursor::new(writer.into_inner())).unwrap().0
+    };
+
+    let new_header = resign(&header);
+    assert_eq!(new_header.release_string, orig.release_string);
+    assert_eq!(new_header.descriptors, orig.descriptors);
+
+    // Existing keys are replaced in place and new keys are appended.
+    let old = header.set_property(PropertyDescriptor {
+        key: key.to_owned(),
+        value: b"new".to_vec(),
+    });
+    assert_eq!(old, Some(prop.value.clone()));
+    assert_matches!(
+        &header.descriptors[index],
+        Descriptor::Property(p) if p.value == b"new"
+    );
+
+    let foo = PropertyDescriptor {
+        key: "foo".to_owned(),
+        value: b"bar".to_vec(),
+    };
+    assert_eq!(header.set_property(foo.clone()), None);
+    assert_eq!(header.descriptors.len(), orig.descriptors.len() + 1);
+
+    let removed = header.remove_properties(|p| p.key == key);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(header.descriptors.len(), orig.descriptors.len());
+
+    header.sign(&signing_key).unwrap();
+    let new_header = resign(&header);
+    assert_eq!(new_header, header);
+    assert_eq!(new_header.release_string, orig.release_string);
+    assert_eq!(
+        new_header.descriptors.last(),
+        Some(&Descriptor::Property(foo))
+    );
+}
+
+#[test]
+fn unknown_descriptor_round_trip() {
+    // Tag that libavb doesn't define. The data is already a multiple of 8
+    // bytes, so no padding is added.
+    let mut raw = vec![];
+    raw.extend(0x1000u64.to_be_bytes());
+    raw.extend(16u64.to_be_bytes());
+    raw.extend(b"vendor-specific\0");
+
+    let descriptor = Descriptor::from_reader(Cursor::new(&raw)).unwrap();
+    assert_eq!(descriptor, Descriptor::Unknown(0x1000, raw[16..].to_vec()));
+    assert_eq!(descriptor.partition_name(), None);
+
+    let mut writer = Cursor::new(Vec::new());
+    descriptor.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), raw);
+
+    // Re-signing keeps the descriptor in its original position.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    assert!(!header.descriptors.is_empty());
+    header.descriptors.insert(1, descriptor.clone());
+
+    let key = get_test_key();
+    header.algorithm_type = crypto::validate_avb_key(&key).unwrap();
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 4096).unwrap();
+    let new_data = writer.into_inner();
+    assert!(new_data.windows(raw.len()).any(|w| w == raw));
+
+    let (new_header, _, _) = avb::load_image(Cursor::new(&new_data)).unwrap();
+    assert_eq!(new_header, header);
+    assert_eq!(new_header.descriptors[1], descriptor);
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &new_header, 4096).unwrap();
+    assert!(writer.into_inner() == new_data);
+}
+
+/// Build a raw hash descriptor with the given digest length field, followed by
+/// `digest` as the actual digest data.
+fn raw_hash_descriptor(hash_algorithm: &str, digest_len: u32, digest: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend(4096u64.to_be_bytes());
+    let mut algorithm = [0u8; 32];
+    algorithm[..hash_algorithm.len()].copy_from_slice(hash_algorithm.as_bytes());
+    data.extend(algorithm);
+    data.extend(4u32.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend(digest_len.to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    data.extend([0u8; 60]);
+    data.extend(b"boot");
+    data.extend(digest);
+    data.resize(data.len().div_ceil(8) * 8, 0);
+
+    let mut raw = vec![];
+    raw.extend(2u64.to_be_bytes());
+    raw.extend((data.len() as u64).to_be_bytes());
+    raw.extend(data);
+
+    raw
+}
+
+#[test]
+fn hash_descriptor_digest_size() {
+    let raw = raw_hash_descriptor("sha256", 32, &[0xaa; 32]);
+    let descriptor = Descriptor::from_reader(Cursor::new(&raw)).unwrap();
+    assert_matches!(descriptor, Descriptor::Hash(d) if d.root_digest == [0xaa; 32]);
+
+    // Persistent digests are empty.
+    let raw = raw_hash_descriptor("sha256", 0, &[]);
+    let descriptor = Descriptor::from_reader(Cursor::new(&raw)).unwrap();
+    assert_matches!(descriptor, Descriptor::Hash(d) if d.root_digest.is_empty());
+
+    // Digest size does not match the algorithm.
+    let raw = raw_hash_descriptor("sha256", 20, &[0xaa; 20]);
+    assert_matches!(
+        Descriptor::from_reader(Cursor::new(&raw)),
+        Err(avb::Error::IncorrectDigestSize(20, a, 32)) if a == "sha256"
+    );
+
+    // Digest size is larger than the descriptor.
+    let raw = raw_hash_descriptor("sha512", 64, &[0xaa; 32]);
+    assert_matches!(
+        Descriptor::from_reader(Cursor::new(&raw)),
+        Err(avb::Error::FieldTooLong("root_digest_len", 64))
+    );
+
+    // The size can't be checked for unknown algorithms, but it still can't
+    // exceed the descriptor.
+    let raw = raw_hash_descriptor("blake3", u32::MAX, &[0xaa; 32]);
+    assert_matches!(
+        Descriptor::from_reader(Cursor::new(&raw)),
+        Err(avb::Error::FieldTooLong("root_digest_len", u32::MAX))
+    );
+}
+
+#[test]
+fn slotted_descriptors() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    let key = get_test_key();
+
+    header.flags = avb::HEADER_FLAG_HASHTREE_DISABLED;
+    header.descriptors = vec![
+        Descriptor::ChainPartition(ChainPartitionDescriptor {
+            rollback_index_location: 1,
+            partition_name: "boot".to_owned(),
+            public_key: avb::encode_public_key(&key.to_public_key()).unwrap(),
+            flags: 0,
+            reserved: [0u8; 60],
+        }),
+        Descriptor::Hash(HashDescriptor {
+            image_size: 4096,
+            hash_algorithm: "sha256".to_owned(),
+            partition_name: "dtbo".to_owned(),
+            salt: vec![0u8; 32],
+            root_digest: vec![0u8; 32],
+            flags: avb::HASH_FLAG_DO_NOT_USE_AB,
+            reserved: [0u8; 60],
+        }),
+    ];
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 64).unwrap();
+    let new_data = writer.into_inner();
+
+    let (new_header, _, _) = avb::load_image(Cursor::new(&new_data)).unwrap();
+    assert_eq!(new_header, header);
+    assert!(new_header.is_hashtree_disabled());
+    assert!(!new_header.is_verification_disabled());
+
+    let names = new_header
+        .descriptors
+        .iter()
+        .map(|d| (d.uses_ab_suffix(), d.partition_name_for_slot("_b").unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [(true, "boot_b".to_owned()), (false, "dtbo".to_owned())]
+    );
+
+    assert_eq!(avb::split_slot_suffix("boot_a"), ("boot", Some("_a")));
+    assert_eq!(avb::split_slot_suffix("vendor_boot"), ("vendor_boot", None));
+    assert_eq!(avb::split_slot_suffix("_a"), ("_a", None));
+}
+
+#[test]
+fn new_chained_header() {
+    let key = get_test_key();
+    let public_key = key.to_public_key();
+    let chained = |name: &str, location| ChainedPartition {
+        partition_name: name.to_owned(),
+        rollback_index_location: location,
+        public_key: public_key.clone(),
+    };
+
+    let algorithm_type = crypto::validate_avb_key(&key).unwrap();
+    let mut header = Header::new_chained(
+        algorithm_type,
+        &[chained("boot", 1), chained("vbmeta_system", 2)],
+    )
+    .unwrap();
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 4096).unwrap();
+    let data = writer.into_inner();
+
+    let (new_header, footer, _) = avb::load_image(Cursor::new(&data)).unwrap();
+    assert_matches!(footer, None);
+    assert_eq!(new_header, header);
+    assert_eq!(new_header.verify().unwrap(), Some(public_key.clone()));
+
+    let descriptors = new_header
+        .descriptors
+        .iter()
+        .map(|d| match d {
+            Descriptor::ChainPartition(c) => c,
+            d => panic!("Unexpected descriptor: {d:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(descriptors.len(), 2);
+
+    for (d, (name, location)) in descriptors.iter().zip([("boot", 1), ("vbmeta_system", 2)]) {
+        assert_eq!(d.partition_name, name);
+        assert_eq!(d.rollback_index_location, location);
+        assert!(!d.do_not_use_ab());
+        assert_eq!(avb::decode_public_key(&d.public_key).unwrap(), public_key);
+    }
+
+    assert_matches!(
+        Header::new_chained(algorithm_type, &[chained("boot", 0)]),
+        Err(avb::Error::InvalidChainedPartition(p, _)) if p == "boot"
+    );
+    assert_matches!(
+        Header::new_chained(algorithm_type, &[chained("boot", 1), chained("boot", 2)]),
+        Err(avb::Error::InvalidChainedPartition(p, _)) if p == "boot"
+    );
+    assert_matches!(
+        Header::new_chained(algorithm_type, &[chained("boot", 1), chained("dtbo", 1)]),
+        Err(avb::Error::InvalidChainedPartition(p, _)) if p == "dtbo"
+    );
+}
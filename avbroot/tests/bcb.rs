@@ -0,0 +1,129 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::Cursor;
+
+use assert_matches::assert_matches;
+use avbroot::format::bcb::{self, BootloaderMessage, Layout};
+
+/// Build a raw bootloader message by placing each field at its offset.
+fn raw_message(size: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut data = vec![0u8; size];
+
+    for (offset, value) in fields {
+        data[*offset..][..value.len()].copy_from_slice(value);
+    }
+
+    data
+}
+
+#[test]
+fn round_trip_current() {
+    let message = BootloaderMessage::boot_recovery(["--update_package=/data/update.zip"]);
+    let expected = raw_message(
+        2048,
+        &[
+            (0, b"boot-recovery"),
+            (64, b"recovery\n--update_package=/data/update.zip\n"),
+        ],
+    );
+
+    let mut data = vec![];
+    message
+        .to_writer_layout(&mut data, Layout::Current)
+        .unwrap();
+    assert_eq!(data, expected);
+
+    let new_message =
+        BootloaderMessage::from_reader_layout(Cursor::new(&data), Layout::Current).unwrap();
+    assert_eq!(new_message, message);
+    assert_eq!(
+        new_message.recovery_args(),
+        ["--update_package=/data/update.zip"],
+    );
+}
+
+#[test]
+fn round_trip_legacy() {
+    let message = BootloaderMessage {
+        command: "boot-recovery".to_owned(),
+        status: "OKAY".to_owned(),
+        recovery: "recovery\n--wipe_data\n".to_owned(),
+        stage: "1/2".to_owned(),
+        slot_suffix: "_a".to_owned(),
+    };
+    let expected = raw_message(
+        1088,
+        &[
+            (0, b"boot-recovery"),
+            (32, b"OKAY"),
+            (64, b"recovery\n--wipe_data\n"),
+            (832, b"1/2"),
+            (864, b"_a"),
+        ],
+    );
+
+    let mut data = vec![];
+    message.to_writer_layout(&mut data, Layout::Legacy).unwrap();
+    assert_eq!(data, expected);
+
+    let new_message =
+        BootloaderMessage::from_reader_layout(Cursor::new(&data), Layout::Legacy).unwrap();
+    assert_eq!(new_message, message);
+}
+
+#[test]
+fn read_ignores_garbage() {
+    // Data after the NUL terminator and in the reserved space is ignored.
+    let data = raw_message(
+        2048,
+        &[(0, b"bootonce-bootloader\0xyz"), (896, b"vendor data")],
+    );
+
+    let message =
+        BootloaderMessage::from_reader_layout(Cursor::new(&data), Layout::Current).unwrap();
+    assert_eq!(
+        message,
+        BootloaderMessage {
+            command: "bootonce-bootloader".to_owned(),
+            ..Default::default()
+        },
+    );
+
+    assert_matches!(
+        BootloaderMessage::from_reader_layout(Cursor::new(&data[..1088]), Layout::Current),
+        Err(bcb::Error::ReadFieldError("reserved", _))
+    );
+}
+
+#[test]
+fn write_invalid_fields() {
+    let message = BootloaderMessage {
+        command: "c".repeat(32),
+        ..Default::default()
+    };
+    assert_matches!(
+        message.to_writer_layout(&mut vec![], Layout::Current),
+        Err(bcb::Error::FieldTooLong("command", 31))
+    );
+
+    let message = BootloaderMessage {
+        recovery: "recovery\n\0".to_owned(),
+        ..Default::default()
+    };
+    assert_matches!(
+        message.to_writer_layout(&mut vec![], Layout::Current),
+        Err(bcb::Error::FieldHasNul("recovery"))
+    );
+
+    let message = BootloaderMessage {
+        slot_suffix: "_b".to_owned(),
+        ..Default::default()
+    };
+    assert_matches!(
+        message.to_writer_layout(&mut vec![], Layout::Current),
+        Err(bcb::Error::FieldNotInLayout("slot_suffix", Layout::Current))
+    );
+}
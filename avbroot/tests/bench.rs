@@ -0,0 +1,92 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::sync::{atomic::AtomicBool, Arc};
+
+use avbroot::cli::bench::{self, Measurement, Resource};
+
+#[test]
+fn test_data_is_stable() {
+    let data = bench::test_data(64 * 1024);
+    assert_eq!(data.len(), 64 * 1024);
+    assert_eq!(data, bench::test_data(64 * 1024));
+
+    // Zero blocks, random blocks, and repetitive blocks are all present.
+    assert!(data[..4096].iter().all(|b| *b == 0));
+    assert!(data[4096..8192].iter().any(|b| *b != 0));
+    assert!(data[8192..12288]
+        .windows(21)
+        .any(|w| w == b"ro.build.fingerprint="));
+}
+
+#[test]
+fn run_benchmarks() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut reported = vec![];
+
+    let measurements = bench::run_benchmarks(
+        1024 * 1024,
+        1,
+        temp_dir.path(),
+        |m| reported.push(m.name),
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        reported,
+        measurements.iter().map(|m| m.name).collect::<Vec<_>>(),
+    );
+    assert!(reported.contains(&"sha256"));
+    assert!(reported.contains(&"disk_read"));
+    assert!(reported.contains(&"rsa4096_sign"));
+    assert!(measurements.iter().all(|m| m.rate() > 0.0));
+
+    // The temporary file is cleaned up.
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn cancel_benchmarks() {
+    let cancel_signal = Arc::new(AtomicBool::new(true));
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    assert!(bench::run_benchmarks(4096, 1, temp_dir.path(), |_| {}, &cancel_signal).is_err());
+}
+
+fn measurement(name: &'static str, resource: Resource, rate: u64) -> Measurement {
+    Measurement {
+        name,
+        resource,
+        amount: rate,
+        seconds: 1.0,
+    }
+}
+
+#[test]
+fn estimate() {
+    const MIB: u64 = 1024 * 1024;
+
+    let mut measurements = vec![
+        measurement("sha256", Resource::Cpu, 1024 * MIB),
+        measurement("disk_read", Resource::Io, 1024 * MIB),
+        measurement("disk_write", Resource::Io, 512 * MIB),
+    ];
+
+    // 2 GiB hashed at 1 GiB/s. 2 GiB read at 1 GiB/s and 1 GiB written at
+    // 512 MiB/s.
+    let estimate = bench::estimate(&measurements, 1024 * MIB);
+    assert_eq!(estimate.cpu_seconds, 2.0);
+    assert_eq!(estimate.io_seconds, 4.0);
+    assert_eq!(estimate.total_seconds, 6.0);
+    assert_eq!(estimate.bound, Resource::Io);
+
+    // Slow signing makes the estimate CPU-bound.
+    measurements.push(measurement("rsa4096_sign", Resource::Cpu, 1));
+    let estimate = bench::estimate(&measurements, 1024 * MIB);
+    assert_eq!(estimate.cpu_seconds, 8.0);
+    assert_eq!(estimate.bound, Resource::Cpu);
+}
@@ -0,0 +1,79 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Read},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use avbroot::format::{
+    blockmap,
+    compression::{CompressedFormat, CompressedReader, CompressedWriter},
+    ota::BlockRange,
+};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Image with data in blocks 2-4 and 7, and a trailing partial block of zeros.
+fn image() -> Vec<u8> {
+    let mut data = vec![0u8; 8 * BLOCK_SIZE + 100];
+    data[2 * BLOCK_SIZE] = 1;
+    data[4 * BLOCK_SIZE + 511] = 1;
+    data[3 * BLOCK_SIZE + 7] = 1;
+    data[7 * BLOCK_SIZE + 1] = 1;
+    data
+}
+
+#[test]
+fn generate_block_map() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let data = image();
+
+    let ranges = blockmap::generate(Cursor::new(&data), BLOCK_SIZE as u32, &cancel_signal).unwrap();
+    assert_eq!(
+        ranges,
+        [
+            BlockRange { start: 2, end: 5 },
+            BlockRange { start: 7, end: 8 },
+        ],
+    );
+    assert_eq!(
+        blockmap::invert(&ranges, 9),
+        [
+            BlockRange { start: 0, end: 2 },
+            BlockRange { start: 5, end: 7 },
+            BlockRange { start: 8, end: 9 },
+        ],
+    );
+
+    let mut map = vec![];
+    blockmap::write_map(&mut map, &ranges, 9).unwrap();
+    assert_eq!(
+        String::from_utf8(map).unwrap(),
+        "__ZERO 0-1 5-6 8\n__NONZERO-0 2-4 7\n",
+    );
+
+    // An empty image has no lines at all.
+    let ranges = blockmap::generate(Cursor::new(Vec::new()), 4096, &cancel_signal).unwrap();
+    assert!(ranges.is_empty());
+    let mut map = vec![];
+    blockmap::write_map(&mut map, &ranges, 0).unwrap();
+    assert!(map.is_empty());
+}
+
+#[test]
+fn write_compressed_block_map() {
+    let ranges = [BlockRange { start: 0, end: 3 }];
+
+    let mut writer =
+        CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Gzip).unwrap();
+    blockmap::write_map(&mut writer, &ranges, 4).unwrap();
+    let compressed = writer.finish().unwrap().into_inner();
+
+    let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    let mut map = String::new();
+    reader.read_to_string(&mut map).unwrap();
+    assert_eq!(map, "__ZERO 3\n__NONZERO-0 0-2\n");
+}
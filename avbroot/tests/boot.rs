@@ -0,0 +1,955 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Cursor, Write},
+    path::Path,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use avbroot::{
+    boot::{
+        self, fstab, BootImagePatcher, KmiMismatch, MagiskOptions, MagiskRootPatcher,
+        OtaCertLocation, OtaCertPatcher, RamdiskCompressionDecision, RamdiskCompressionPatcher,
+        RamdiskCompressionTarget,
+    },
+    crypto,
+    format::{
+        bootimage::{self, BootImage},
+        compression::{CompressedFormat, CompressedReader, CompressedWriter},
+        cpio::{self, CpioEntryNew},
+    },
+    stream::FromReader,
+    warning::{WarningCode, WarningCollector},
+};
+use x509_cert::Certificate;
+use zip::{write::FileOptions, ZipWriter};
+
+static DLKM_RAMDISK: &[u8] = include_bytes!("data/dlkm_ramdisk.cpio.gz");
+
+#[test]
+fn load_kernel_modules() {
+    let modules = boot::load_kernel_modules(DLKM_RAMDISK).unwrap();
+
+    // Directories, symlinks, and non-module files are skipped.
+    let names = modules
+        .iter()
+        .map(|e| e.name.as_slice())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            b"lib/modules/alpha.ko".as_slice(),
+            b"lib/modules/beta.ko".as_slice(),
+        ],
+    );
+
+    assert_eq!(modules[0].content, b"\x7fELFalpha module\n");
+    assert_eq!(modules[1].content, b"\x7fELFbeta module\n");
+
+    // Uncompressed ramdisks are accepted, but only if they are cpio archives.
+    let raw = single_file_ramdisk(b"lib/modules/raw.ko", b"\x7fELFraw".to_vec());
+    let modules = boot::load_kernel_modules(&raw).unwrap();
+    assert_eq!(modules.len(), 1);
+    assert_eq!(modules[0].content, b"\x7fELFraw");
+
+    assert!(boot::load_kernel_modules(b"not a ramdisk").is_err());
+}
+
+/// Remove the `avb` flag from the fs_mgr flags (fifth field) of an fstab line.
+fn remove_avb_flag(line: &str) -> String {
+    let mut fields = line.split(' ').map(str::to_owned).collect::<Vec<_>>();
+
+    if let Some(flags) = fields.get_mut(4) {
+        *flags = flags
+            .split(',')
+            .filter(|f| *f != "avb" && !f.starts_with("avb="))
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+
+    fields.join(" ")
+}
+
+#[test]
+fn patch_fstab() {
+    let mut fstab = CpioEntryNew::new_file(b"first_stage_ramdisk/fstab.test");
+    fstab.mode |= 0o640;
+    fstab.uid = 1000;
+    fstab.gid = 1000;
+    fstab.content = b"system /system ext4 ro wait,avb=vbmeta,first_stage_mount\n\
+        vendor /vendor ext4 ro wait,avb,first_stage_mount\n"
+        .to_vec();
+
+    let mut other = CpioEntryNew::new_file(b"init.rc");
+    other.mode |= 0o644;
+    other.content = b"avb\n".to_vec();
+
+    let mut entries = vec![fstab, other];
+    cpio::reassign_inodes(&mut entries);
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Gzip).unwrap();
+    cpio::save(&mut writer, &entries, false).unwrap();
+    let ramdisk = writer.finish().unwrap().into_inner();
+
+    let patched = fstab::patch_fstab(&ramdisk, |content| {
+        content
+            .lines()
+            .map(|line| remove_avb_flag(line) + "\n")
+            .collect()
+    })
+    .unwrap();
+
+    let mut reader = CompressedReader::new(Cursor::new(&patched), false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Gzip);
+    let loaded = cpio::load(&mut reader, false).unwrap();
+
+    assert_eq!(
+        loaded[0].content,
+        b"system /system ext4 ro wait,first_stage_mount\n\
+        vendor /vendor ext4 ro wait,first_stage_mount\n",
+    );
+    assert_eq!(loaded[0].mode, entries[0].mode);
+    assert_eq!(loaded[0].uid, 1000);
+    assert_eq!(loaded[0].gid, 1000);
+
+    // Files that are not fstabs are untouched.
+    assert_eq!(loaded[1], entries[1]);
+}
+
+#[test]
+fn patch_props() {
+    let mut props = CpioEntryNew::new_file(b"prop.default");
+    props.mode |= 0o600;
+    props.content = b"# Comment\n\
+        ro.debuggable=0\n\
+        ro.adb.secure=1\n\
+        persist.sys.usb.config=mtp"
+        .to_vec();
+
+    let symlink = CpioEntryNew::new_symlink(b"prop.default", b"default.prop");
+
+    let mut entries = vec![props, symlink];
+    cpio::reassign_inodes(&mut entries);
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Lz4Legacy).unwrap();
+    cpio::save(&mut writer, &entries, false).unwrap();
+    let ramdisk = writer.finish().unwrap().into_inner();
+
+    let changes = BTreeMap::from([
+        ("ro.debuggable".to_owned(), "1".to_owned()),
+        ("ro.secure".to_owned(), "0".to_owned()),
+    ]);
+    let patched = boot::patch_props(&ramdisk, &changes).unwrap();
+
+    let mut reader = CompressedReader::new(Cursor::new(&patched), false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Lz4Legacy);
+    let loaded = cpio::load(&mut reader, false).unwrap();
+
+    // Existing keys are replaced in place and new keys are appended.
+    assert_eq!(
+        loaded[0].content,
+        b"# Comment\n\
+        ro.debuggable=1\n\
+        ro.adb.secure=1\n\
+        persist.sys.usb.config=mtp\n\
+        ro.secure=0\n",
+    );
+    assert_eq!(loaded[0].mode, entries[0].mode);
+
+    // Symlinks are not followed.
+    assert_eq!(loaded[1], entries[1]);
+
+    // Toggling the property back only changes that line.
+    let changes = BTreeMap::from([("ro.debuggable".to_owned(), "0".to_owned())]);
+    let reverted = boot::patch_props(&patched, &changes).unwrap();
+
+    let mut reader = CompressedReader::new(Cursor::new(&reverted), false).unwrap();
+    let loaded = cpio::load(&mut reader, false).unwrap();
+    assert_eq!(
+        loaded[0].content,
+        b"# Comment\n\
+        ro.debuggable=0\n\
+        ro.adb.secure=1\n\
+        persist.sys.usb.config=mtp\n\
+        ro.secure=0\n",
+    );
+}
+
+/// Encode text with a byte order mark (if any) the same way a vendor might.
+fn encode_text(text: &str, bom: &[u8]) -> Vec<u8> {
+    let mut data = bom.to_vec();
+
+    match bom {
+        b"\xff\xfe" => data.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+        b"\xfe\xff" => data.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+        _ => data.extend_from_slice(text.as_bytes()),
+    }
+
+    data
+}
+
+fn single_file_ramdisk(name: &[u8], content: Vec<u8>) -> Vec<u8> {
+    let mut entry = CpioEntryNew::new_file(name);
+    entry.mode |= 0o644;
+    entry.content = content;
+
+    let mut entries = vec![entry];
+    cpio::reassign_inodes(&mut entries);
+
+    let mut writer = Cursor::new(Vec::new());
+    cpio::save(&mut writer, &entries, false).unwrap();
+    writer.into_inner()
+}
+
+fn single_file_content(ramdisk: &[u8]) -> Vec<u8> {
+    let mut reader = CompressedReader::new(Cursor::new(ramdisk), true).unwrap();
+    let mut loaded = cpio::load(&mut reader, false).unwrap();
+    assert_eq!(loaded.len(), 1);
+
+    loaded.remove(0).content
+}
+
+#[test]
+fn patch_text_preserves_style() {
+    let boms: [&[u8]; 4] = [b"", b"\xef\xbb\xbf", b"\xff\xfe", b"\xfe\xff"];
+    // The last style has one LF line in an otherwise CRLF file.
+    let styles: [[&str; 3]; 4] = [
+        ["\n", "\n", "\n"],
+        ["\r\n", "\r\n", "\r\n"],
+        ["\r\n", "\n", "\r\n"],
+        ["\r\n", "\r\n", ""],
+    ];
+
+    for bom in boms {
+        for [e1, e2, e3] in styles {
+            // The inserted line uses the line ending of the majority of lines.
+            let ending = if e1 == "\r\n" { "\r\n" } else { "\n" };
+            let separator = if e3.is_empty() { ending } else { "" };
+
+            let original = format!("# Comment{e1}ro.debuggable=0{e2}ro.adb.secure=1{e3}");
+            let ramdisk = single_file_ramdisk(b"prop.default", encode_text(&original, bom));
+
+            let changes = BTreeMap::from([
+                ("ro.debuggable".to_owned(), "1".to_owned()),
+                ("ro.secure".to_owned(), "0".to_owned()),
+            ]);
+            let patched = boot::patch_props(&ramdisk, &changes).unwrap();
+
+            let expected = format!(
+                "# Comment{e1}ro.debuggable=1{e2}ro.adb.secure=1{e3}{separator}ro.secure=0{ending}"
+            );
+            assert_eq!(
+                single_file_content(&patched),
+                encode_text(&expected, bom),
+                "{bom:?}, {original:?}",
+            );
+
+            // Nothing is rewritten if nothing changed.
+            let changes = BTreeMap::from([("ro.adb.secure".to_owned(), "1".to_owned())]);
+            let unchanged = boot::patch_props(&ramdisk, &changes).unwrap();
+            assert_eq!(
+                single_file_content(&unchanged),
+                encode_text(&original, bom),
+                "{bom:?}, {original:?}",
+            );
+
+            let original = format!("system /system ext4 ro wait,avb=vbmeta{e1}# Comment{e2}");
+            let ramdisk = single_file_ramdisk(b"fstab.test", encode_text(&original, bom));
+            let patched =
+                fstab::patch_fstab(&ramdisk, |content| content.replace(",avb=vbmeta", "")).unwrap();
+
+            let expected = format!("system /system ext4 ro wait{e1}# Comment{e2}");
+            assert_eq!(
+                single_file_content(&patched),
+                encode_text(&expected, bom),
+                "{bom:?}, {original:?}",
+            );
+        }
+    }
+}
+
+#[test]
+fn patch_text_unsupported_encoding() {
+    let changes = BTreeMap::from([("ro.debuggable".to_owned(), "1".to_owned())]);
+
+    for (content, reason) in [
+        (b"ro.debuggable=\xff\n".as_slice(), "is not valid UTF-8"),
+        (b"\xff\xferxo", "is not valid UTF-16"),
+        (b"\xff\xfe\x00\xd8", "is not valid UTF-16"),
+        (b"\xff\xfe\0\0r\0\0\0", "is UTF-32, which is not supported"),
+    ] {
+        let ramdisk = single_file_ramdisk(b"prop.default", content.to_vec());
+
+        let error = boot::patch_props(&ramdisk, &changes).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Validation error: Properties file {reason}: \"prop.default\""),
+        );
+    }
+}
+
+/// Build a fake kernel with an embedded config, like CONFIG_IKCONFIG produces.
+fn kernel_with_config(config: &str) -> Vec<u8> {
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Gzip).unwrap();
+    writer.write_all(config.as_bytes()).unwrap();
+    let config_gz = writer.finish().unwrap().into_inner();
+
+    let mut kernel = b"\x7fELFkernel".to_vec();
+    kernel.extend_from_slice(b"IKCFG_ST");
+    kernel.extend_from_slice(&config_gz);
+    kernel.extend_from_slice(b"IKCFG_ED");
+
+    kernel
+}
+
+#[test]
+fn kernel_config() {
+    let config = "CONFIG_RD_GZIP=y\n# CONFIG_RD_XZ is not set\nCONFIG_RD_LZ4=m\n";
+    let kernel = kernel_with_config(config);
+
+    assert_eq!(boot::kernel_config(&kernel).as_deref(), Some(config));
+    assert_eq!(boot::kernel_config(b"\x7fELFkernel"), None);
+
+    // Modules cannot be loaded before the ramdisk is unpacked.
+    assert!(boot::kernel_supports_ramdisk_format(
+        config,
+        CompressedFormat::None
+    ));
+    assert!(boot::kernel_supports_ramdisk_format(
+        config,
+        CompressedFormat::Gzip
+    ));
+    assert!(!boot::kernel_supports_ramdisk_format(
+        config,
+        CompressedFormat::Lz4Legacy
+    ));
+    assert!(!boot::kernel_supports_ramdisk_format(
+        config,
+        CompressedFormat::Xz
+    ));
+}
+
+#[test]
+fn check_kmi() {
+    let mut boot_image = BootImage::from_reader(Cursor::new(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    ))))
+    .unwrap();
+    let mut vendor_boot_image = BootImage::from_reader(Cursor::new(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4.img",
+    ))))
+    .unwrap();
+
+    let BootImage::V3Through4(b) = &mut boot_image else {
+        panic!("Not a v3-v4 boot image");
+    };
+    b.kernel =
+        b"\x7fELFkernel\0Linux version 5.15.123-android14-11-g0123abcd (builder@host)\0".to_vec();
+
+    let mut entries = [
+        ("alpha.ko", "5.15.123-android14-11-g0123abcd SMP"),
+        ("beta.ko", "5.10.198-android12-9-g4567cdef SMP"),
+        ("gamma.ko", "5.15.123 SMP"),
+    ]
+    .into_iter()
+    .map(|(name, vermagic)| {
+        let mut entry = CpioEntryNew::new_file(format!("lib/modules/{name}").as_bytes());
+        entry.mode |= 0o644;
+        entry.content = format!("\x7fELF\0vermagic={vermagic}\0").into_bytes();
+        entry
+    })
+    .collect::<Vec<_>>();
+
+    // Modules without a vermagic are skipped.
+    let mut delta = CpioEntryNew::new_file(b"lib/modules/delta.ko");
+    delta.mode |= 0o644;
+    delta.content = b"\x7fELF\0".to_vec();
+    entries.push(delta);
+    cpio::reassign_inodes(&mut entries);
+
+    let mut writer = Cursor::new(Vec::new());
+    cpio::save(&mut writer, &entries, false).unwrap();
+
+    let BootImage::VendorV3Through4(v) = &mut vendor_boot_image else {
+        panic!("Not a vendor v3-v4 boot image");
+    };
+    v.ramdisks[0] = writer.into_inner();
+
+    let check = boot::check_kmi(&boot_image, &vendor_boot_image)
+        .unwrap()
+        .unwrap();
+    assert_eq!(check.kernel_kmi, "5.15-android14-11");
+    assert_eq!(check.modules_checked, 3);
+    assert_eq!(
+        check.mismatches,
+        [
+            KmiMismatch {
+                module: "lib/modules/beta.ko".to_owned(),
+                release: "5.10.198-android12-9-g4567cdef".to_owned(),
+            },
+            KmiMismatch {
+                module: "lib/modules/gamma.ko".to_owned(),
+                release: "5.15.123".to_owned(),
+            },
+        ],
+    );
+
+    // The second image must be a vendor boot image.
+    assert_eq!(boot::check_kmi(&boot_image, &boot_image).unwrap(), None);
+
+    // Non-GKI kernels have no KMI.
+    let BootImage::V3Through4(b) = &mut boot_image else {
+        unreachable!();
+    };
+    b.kernel = b"\x7fELFkernel\0Linux version 5.15.123 (builder@host)\0".to_vec();
+    assert_eq!(
+        boot::check_kmi(&boot_image, &vendor_boot_image).unwrap(),
+        None,
+    );
+}
+
+#[test]
+fn canonicalize_ramdisk() {
+    let mut init = CpioEntryNew::new_file(b"init");
+    init.mode |= 0o750;
+    init.mtime = 1234;
+    init.content = b"init".to_vec();
+
+    let mut system = CpioEntryNew::new_directory(b"system");
+    system.mode |= 0o755;
+    system.mtime = 5678;
+
+    let mut entries_a = vec![init.clone(), system.clone()];
+    cpio::reassign_inodes(&mut entries_a);
+
+    init.mtime = 0;
+    let mut entries_b = vec![system, init];
+    cpio::reassign_inodes(&mut entries_b);
+
+    let mut ramdisk_a = vec![];
+    cpio::save(&mut ramdisk_a, &entries_a, false).unwrap();
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Gzip).unwrap();
+    cpio::save(&mut writer, &entries_b, true).unwrap();
+    let ramdisk_b = writer.finish().unwrap().into_inner();
+
+    assert_ne!(ramdisk_a, ramdisk_b);
+
+    let canonical_a = boot::canonicalize_ramdisk(&ramdisk_a).unwrap();
+    let canonical_b = boot::canonicalize_ramdisk(&ramdisk_b).unwrap();
+    assert_eq!(canonical_a, canonical_b);
+
+    let loaded = cpio::load(Cursor::new(&canonical_a), false).unwrap();
+    let names = loaded.iter().map(|e| e.name.as_slice()).collect::<Vec<_>>();
+    assert_eq!(names, [b"init".as_slice(), b"system".as_slice()]);
+    assert!(loaded.iter().all(|e| e.mtime == 0));
+}
+
+#[test]
+fn recompress_ramdisk_smallest() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let mut entry = CpioEntryNew::new_file(b"init");
+    entry.mode |= 0o750;
+    entry.content = b"compressible".repeat(1000);
+    let mut uncompressed = Cursor::new(Vec::new());
+    cpio::save(&mut uncompressed, &[entry], false).unwrap();
+    let uncompressed = uncompressed.into_inner();
+
+    let BootImage::V0Through2(b) = &mut image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    b.kernel = kernel_with_config("CONFIG_RD_GZIP=y\n");
+    b.ramdisk = uncompressed.clone();
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let decisions = Arc::new(Mutex::new(Vec::<RamdiskCompressionDecision>::new()));
+    let patcher = {
+        let decisions = decisions.clone();
+        RamdiskCompressionPatcher::new(
+            RamdiskCompressionTarget::Smallest {
+                min_savings_ratio: 0.05,
+            },
+            move |d| decisions.lock().unwrap().push(*d),
+            WarningCollector::default(),
+        )
+    };
+
+    // Gzip is the only format the kernel supports and it is much smaller.
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+    let compressed = b.ramdisk.clone();
+    let reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Gzip);
+
+    // Recompressing with gzip again saves nothing, so the bytes are kept.
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+    assert_eq!(b.ramdisk, compressed);
+
+    assert_eq!(
+        *decisions.lock().unwrap(),
+        [
+            RamdiskCompressionDecision {
+                index: 0,
+                original_format: CompressedFormat::None,
+                original_size: uncompressed.len(),
+                format: CompressedFormat::Gzip,
+                size: compressed.len(),
+                kept: false,
+            },
+            RamdiskCompressionDecision {
+                index: 0,
+                original_format: CompressedFormat::Gzip,
+                original_size: compressed.len(),
+                format: CompressedFormat::Gzip,
+                size: compressed.len(),
+                kept: true,
+            },
+        ],
+    );
+}
+
+#[test]
+fn recompress_ramdisk_bad_checksum() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let BootImage::V0Through2(b) = &mut image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    b.ramdisk = include_bytes!("data/ramdisk_bad_crc.cpio.gz").to_vec();
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let warnings = WarningCollector::default();
+    let patcher = RamdiskCompressionPatcher::new(
+        RamdiskCompressionTarget::Format(CompressedFormat::None),
+        |_| {},
+        warnings.clone(),
+    );
+
+    // The bad gzip CRC32 is reported, but does not prevent repacking.
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::RamdiskChecksumMismatch]);
+
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+    let entries = cpio::load(Cursor::new(&b.ramdisk), false).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, b"init");
+    assert_eq!(entries[0].content, b"#!/system/bin/sh\n");
+}
+
+#[test]
+fn recompress_ramdisk_trailing_data() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let BootImage::V0Through2(b) = &mut image else {
+        panic!("Not a v0-v2 boot image");
+    };
+
+    let ramdisk = single_file_ramdisk(b"init", b"#!/system/bin/sh\n".to_vec());
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Lz4Legacy).unwrap();
+    writer.write_all(&ramdisk).unwrap();
+    b.ramdisk = writer.finish().unwrap().into_inner();
+    // An LZ4 block that does not decompress, after the end of the cpio archive.
+    b.ramdisk
+        .extend_from_slice(b"\x08\x00\x00\x00\xff\xff\xff\xff\xff\xff\xff\xff");
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let warnings = WarningCollector::default();
+    let patcher = RamdiskCompressionPatcher::new(
+        RamdiskCompressionTarget::Format(CompressedFormat::None),
+        |_| {},
+        warnings.clone(),
+    );
+
+    // The garbage is reported, but does not prevent repacking.
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::RamdiskTrailingData]);
+
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+    let entries = cpio::load(Cursor::new(&b.ramdisk), false).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, b"init");
+    assert_eq!(entries[0].content, b"#!/system/bin/sh\n");
+}
+
+/// Patch otacerts in `image` and return the ramdisk that was patched.
+fn patch_otacerts(image: &mut BootImage, cert: &Certificate) -> OtaCertLocation {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let locations = Arc::new(Mutex::new(Vec::<OtaCertLocation>::new()));
+    let patcher = {
+        let locations = locations.clone();
+        OtaCertPatcher::new(
+            cert.clone(),
+            None,
+            move |l| locations.lock().unwrap().push(l.clone()),
+            WarningCollector::default(),
+        )
+    };
+
+    patcher.patch(image, &cancel_signal).unwrap();
+
+    let mut locations = locations.lock().unwrap();
+    assert_eq!(locations.len(), 1);
+    locations.pop().unwrap()
+}
+
+#[test]
+fn patch_otacerts_recovery_fragment() {
+    // Both the platform and recovery fragments contain otacerts.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4_recovery.img",
+    ));
+    let cert = crypto::read_pem_cert(
+        include_str!(concat!(
+            env!("CARGO_WORKSPACE_DIR"),
+            "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.crt",
+        ))
+        .as_bytes(),
+    )
+    .unwrap();
+
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::VendorV3Through4(b) = &image else {
+        panic!("Not a vendor v3-v4 boot image");
+    };
+    let original = b.ramdisks.clone();
+    let stock_certs = OtaCertPatcher::get_certificates(&image).unwrap();
+    assert_eq!(stock_certs.len(), 2);
+
+    // The recovery fragment is picked even though it is not the first one.
+    assert_eq!(
+        patch_otacerts(&mut image, &cert),
+        OtaCertLocation {
+            index: 1,
+            fragment: Some("recovery".to_owned()),
+        },
+    );
+
+    let BootImage::VendorV3Through4(b) = &image else {
+        unreachable!();
+    };
+    assert_eq!(b.ramdisks[0], original[0]);
+    assert_ne!(b.ramdisks[1], original[1]);
+    assert_eq!(
+        OtaCertPatcher::get_certificates(&image).unwrap(),
+        [stock_certs[0].clone(), cert.clone()],
+    );
+
+    // Without a recovery fragment, the first fragment with otacerts is used.
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::VendorV3Through4(b) = &mut image else {
+        unreachable!();
+    };
+    let meta = &mut b.v4_extra.as_mut().unwrap().ramdisk_metas[1];
+    meta.ramdisk_type = bootimage::VENDOR_RAMDISK_TYPE_PLATFORM;
+    meta.ramdisk_name = "other".to_owned();
+
+    assert_eq!(
+        patch_otacerts(&mut image, &cert),
+        OtaCertLocation {
+            index: 0,
+            fragment: Some("platform".to_owned()),
+        },
+    );
+
+    let BootImage::VendorV3Through4(b) = &image else {
+        unreachable!();
+    };
+    assert_ne!(b.ramdisks[0], original[0]);
+    assert_eq!(b.ramdisks[1], original[1]);
+}
+
+/// Create a minimal Magisk APK that only contains what the patcher reads.
+fn fake_magisk_apk(path: &Path) {
+    let mut writer = ZipWriter::new(File::create(path).unwrap());
+
+    for (name, data) in [
+        (
+            "assets/util_functions.sh",
+            b"MAGISK_VER_CODE=25200\n".as_slice(),
+        ),
+        ("lib/arm64-v8a/libmagiskinit.so", b"magiskinit"),
+        ("lib/armeabi-v7a/libmagisk32.so", b"magisk32"),
+        ("lib/arm64-v8a/libmagisk64.so", b"magisk64"),
+    ] {
+        writer.start_file(name, FileOptions::default()).unwrap();
+        writer.write_all(data).unwrap();
+    }
+
+    writer.finish().unwrap();
+}
+
+fn ramdisk_entries(image: &BootImage) -> Vec<CpioEntryNew> {
+    let BootImage::V0Through2(b) = image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    let reader = CompressedReader::new(Cursor::new(&b.ramdisk), false).unwrap();
+
+    cpio::load(reader, false).unwrap()
+}
+
+#[test]
+fn magisk_repatch() {
+    let dir = tempfile::tempdir().unwrap();
+    let apk_path = dir.path().join("magisk.apk");
+    fake_magisk_apk(&apk_path);
+
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let mut init = CpioEntryNew::new_file(b"init");
+    init.mode |= 0o750;
+    init.content = b"stock init".to_vec();
+    let mut init_rc = CpioEntryNew::new_file(b"init.rc");
+    init_rc.mode |= 0o644;
+    init_rc.content = b"on init\n".to_vec();
+    let mut entries = vec![init, init_rc];
+    cpio::reassign_inodes(&mut entries);
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Gzip).unwrap();
+    cpio::save(&mut writer, &entries, false).unwrap();
+
+    let BootImage::V0Through2(b) = &mut image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    b.ramdisk = writer.finish().unwrap().into_inner();
+    let mut stock_entries = ramdisk_entries(&image);
+    cpio::reassign_inodes(&mut stock_entries);
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let warnings = WarningCollector::default();
+    let patcher = MagiskRootPatcher::new(
+        &apk_path,
+        None,
+        None,
+        MagiskOptions::default(),
+        false,
+        false,
+        &warnings,
+    )
+    .unwrap();
+
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+    let patched = image.clone();
+    assert!(warnings.is_empty());
+
+    // Restoring the backup gives back the stock ramdisk.
+    let mut entries = ramdisk_entries(&patched);
+    assert!(MagiskRootPatcher::restore_magisk_backup(&mut entries));
+    cpio::reassign_inodes(&mut entries);
+    assert_eq!(entries, stock_entries);
+    assert!(!MagiskRootPatcher::restore_magisk_backup(&mut entries));
+
+    // Patching again replaces the previous patch instead of stacking on it.
+    patcher.patch(&mut image, &cancel_signal).unwrap();
+    assert_eq!(image, patched);
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::MagiskRepatched]);
+
+    let strict = MagiskRootPatcher::new(
+        &apk_path,
+        None,
+        None,
+        MagiskOptions::default(),
+        false,
+        true,
+        &warnings,
+    )
+    .unwrap();
+    assert!(strict.patch(&mut image, &cancel_signal).is_err());
+}
+
+#[test]
+fn magisk_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let apk_path = dir.path().join("magisk.apk");
+    fake_magisk_apk(&apk_path);
+
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut stock_image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let fstab_content = "# avb comment\n\
+        system /system ext4 ro wait,avb=vbmeta,verify\n\
+        /dev/block/userdata  /data  f2fs  noatime  latemount,fileencryption=aes-256-xts,quota\n\
+        /dev/block/metadata /metadata ext4 noatime avb\n";
+    let mut fstab = CpioEntryNew::new_file(b"fstab.test");
+    fstab.mode |= 0o640;
+    fstab.content = fstab_content.as_bytes().to_vec();
+    let mut verity_key = CpioEntryNew::new_file(b"verity_key");
+    verity_key.mode |= 0o644;
+    verity_key.content = b"key".to_vec();
+    let mut entries = vec![fstab, verity_key];
+    cpio::reassign_inodes(&mut entries);
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Gzip).unwrap();
+    cpio::save(&mut writer, &entries, false).unwrap();
+
+    let BootImage::V0Through2(b) = &mut stock_image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    b.ramdisk = writer.finish().unwrap().into_inner();
+    b.kernel = b"\x7fELFkernel skip_initramfs".to_vec();
+
+    let patch = |options: MagiskOptions| {
+        let cancel_signal = Arc::new(AtomicBool::new(false));
+        let warnings = WarningCollector::default();
+        let patcher =
+            MagiskRootPatcher::new(&apk_path, None, None, options, false, false, &warnings)
+                .unwrap();
+
+        let mut image = stock_image.clone();
+        patcher.patch(&mut image, &cancel_signal).unwrap();
+
+        let entries = ramdisk_entries(&image);
+        let file = |name: &[u8]| {
+            entries
+                .iter()
+                .find(|e| e.name == name)
+                .map(|e| String::from_utf8(e.content.clone()).unwrap())
+        };
+        let config = file(b".backup/.magisk").unwrap();
+        let fstab = file(b"fstab.test").unwrap();
+        let has_verity_key = file(b"verity_key").is_some();
+        let BootImage::V0Through2(b) = image else {
+            unreachable!();
+        };
+
+        (config, fstab, has_verity_key, b.kernel)
+    };
+
+    // The defaults are what avbroot has always done.
+    let (config, fstab, has_verity_key, kernel) = patch(MagiskOptions::default());
+    assert!(config.starts_with(
+        "KEEPVERITY=true\n\
+        KEEPFORCEENCRYPT=true\n\
+        PATCHVBMETAFLAG=false\n\
+        RECOVERYMODE=false\n"
+    ));
+    assert_eq!(fstab, fstab_content);
+    assert!(has_verity_key);
+    assert_eq!(kernel, b"\x7fELFkernel skip_initramfs");
+
+    let (config, fstab, has_verity_key, _) = patch(MagiskOptions {
+        keep_verity: false,
+        ..Default::default()
+    });
+    assert!(config.starts_with("KEEPVERITY=false\nKEEPFORCEENCRYPT=true\n"));
+    assert_eq!(
+        fstab,
+        "# avb comment\n\
+        system /system ext4 ro wait\n\
+        /dev/block/userdata  /data  f2fs  noatime  latemount,fileencryption=aes-256-xts,quota\n\
+        /dev/block/metadata /metadata ext4 noatime defaults\n",
+    );
+    assert!(!has_verity_key);
+
+    let (config, fstab, has_verity_key, _) = patch(MagiskOptions {
+        keep_force_encrypt: false,
+        ..Default::default()
+    });
+    assert!(config.starts_with("KEEPVERITY=true\nKEEPFORCEENCRYPT=false\n"));
+    assert_eq!(
+        fstab,
+        "# avb comment\n\
+        system /system ext4 ro wait,avb=vbmeta,verify\n\
+        /dev/block/userdata  /data  f2fs  noatime  latemount,encryptable=aes-256-xts,quota\n\
+        /dev/block/metadata /metadata ext4 noatime avb\n",
+    );
+    assert!(has_verity_key);
+
+    let (config, fstab, _, _) = patch(MagiskOptions {
+        recovery_mode: true,
+        ..Default::default()
+    });
+    assert!(config.contains("\nRECOVERYMODE=true\n"));
+    assert_eq!(fstab, fstab_content);
+
+    let (_, fstab, _, kernel) = patch(MagiskOptions {
+        legacy_sar: true,
+        ..Default::default()
+    });
+    assert_eq!(fstab, fstab_content);
+    assert_eq!(kernel, b"\x7fELFkernel want_initramfs");
+
+    // The kernel patch is not silently skipped.
+    let BootImage::V0Through2(b) = &mut stock_image else {
+        unreachable!();
+    };
+    b.kernel = b"\x7fELFkernel".to_vec();
+    let patcher = MagiskRootPatcher::new(
+        &apk_path,
+        None,
+        None,
+        MagiskOptions {
+            legacy_sar: true,
+            ..Default::default()
+        },
+        false,
+        false,
+        &WarningCollector::default(),
+    )
+    .unwrap();
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    assert!(patcher.patch(&mut stock_image, &cancel_signal).is_err());
+}
@@ -3,15 +3,23 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::Cursor;
+use std::{fs, io::Cursor, path::Path};
 
+use assert_matches::assert_matches;
 use avbroot::{
     self,
-    format::bootimage::{BootImage, BootImageExt},
+    format::{
+        avb::Descriptor,
+        bootimage::{
+            self, BootContainer, BootImage, BootImageExt, IdAlgorithm, OptionalSection,
+            OsPatchLevel, OsVersion,
+        },
+    },
     stream::{FromReader, ToWriter},
 };
 use pkcs8::DecodePrivateKey;
 use rsa::RsaPrivateKey;
+use serde_json::Value;
 
 fn get_test_key() -> RsaPrivateKey {
     let data = include_str!(concat!(
@@ -106,6 +114,123 @@ fn round_trip_v4_vts() {
     round_trip(data, 4);
 }
 
+#[test]
+fn round_trip_v4_gki() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_gki.img",
+    ));
+    round_trip(data, 4);
+}
+
+#[test]
+fn round_trip_v4_16k() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_16k.img",
+    ));
+    round_trip(data, 4);
+}
+
+#[test]
+fn round_trip_v4_empty() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_empty.img",
+    ));
+    round_trip(data, 4);
+}
+
+#[test]
+fn detect_16k_alignment() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_16k.img",
+    ));
+    let image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V3Through4(b) = &image else {
+        panic!("Not a v3/v4 boot image");
+    };
+
+    assert_eq!(b.page_size, bootimage::LARGE_PAGE_SIZE);
+    assert_eq!(b.kernel, &data[16384..16384 + b.kernel.len()]);
+    assert_eq!(b.ramdisk, b"RMDK".repeat(25));
+
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    ));
+    let BootImage::V3Through4(b) = BootImage::from_reader(Cursor::new(data)).unwrap() else {
+        panic!("Not a v3/v4 boot image");
+    };
+    assert_eq!(b.page_size, bootimage::PAGE_SIZE);
+}
+
+#[test]
+fn regenerate_gki_signature() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_gki.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V3Through4(b) = &mut image else {
+        panic!("Not a v3/v4 boot image");
+    };
+
+    b.kernel = b"new kernel data".to_vec();
+    assert!(b.sign(&get_test_key()).unwrap());
+
+    let v4 = b.v4_extra.as_ref().unwrap();
+    assert!(v4.signature.is_some());
+    assert_eq!(v4.extra_signatures.len(), 1);
+
+    let Descriptor::Hash(d) = &v4.extra_signatures[0].descriptors[0] else {
+        panic!("Not a hash descriptor");
+    };
+    assert_eq!(d.partition_name, bootimage::GKI_KERNEL_PARTITION_NAME);
+    assert_eq!(d.image_size, b.kernel.len() as u64);
+
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(&d.salt);
+    context.update(&b.kernel);
+    assert_eq!(d.root_digest, context.finish().as_ref());
+
+    // The boot signature is still parseable after the kernel size changed.
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    let new_image = BootImage::from_reader(Cursor::new(writer.into_inner())).unwrap();
+    assert_eq!(new_image, image);
+}
+
+#[test]
+fn preserve_gki_signature_size() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_gki_16k.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V3Through4(b) = &mut image else {
+        panic!("Not a v3/v4 boot image");
+    };
+
+    assert_eq!(b.v4_extra.as_ref().unwrap().signature_size, 16384);
+
+    b.kernel = b"new kernel data".to_vec();
+    assert!(b.sign(&get_test_key()).unwrap());
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    let new_data = writer.into_inner();
+
+    // The signature size field and the length of the signature region must
+    // both be unchanged.
+    assert_eq!(new_data.len(), data.len());
+    assert_eq!(new_data[1580..1584], 16384u32.to_le_bytes());
+
+    let new_image = BootImage::from_reader(Cursor::new(new_data)).unwrap();
+    assert_eq!(new_image, image);
+}
+
 #[test]
 fn round_trip_vendor_v3() {
     let data = include_bytes!(concat!(
@@ -123,3 +248,592 @@ fn round_trip_vendor_v4() {
     ));
     round_trip(data, 4);
 }
+
+#[test]
+fn round_trip_vendor_v3_empty() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v3_empty.img",
+    ));
+    round_trip(data, 3);
+}
+
+#[test]
+fn round_trip_vendor_v4_empty() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4_empty.img",
+    ));
+    round_trip(data, 4);
+}
+
+#[test]
+fn round_trip_vendor_v4_no_dtb() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4_no_dtb.img",
+    ));
+    round_trip(data, 4);
+}
+
+#[test]
+fn remove_vendor_dtb() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4.img",
+    ));
+    let expected = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4_no_dtb.img",
+    ));
+    let BootImage::VendorV3Through4(mut b) = BootImage::from_reader(Cursor::new(data)).unwrap()
+    else {
+        panic!("Not a vendor v3/v4 boot image");
+    };
+    assert!(!b.dtb.is_empty());
+
+    // Some devices load the DTB from a separate partition. An empty DTB must
+    // not take up any space or else the following sections are shifted.
+    b.dtb.clear();
+
+    let mut writer = Cursor::new(Vec::new());
+    b.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.get_ref(), expected);
+
+    let BootImage::VendorV3Through4(b) = BootImage::from_reader(Cursor::new(expected)).unwrap()
+    else {
+        panic!("Not a vendor v3/v4 boot image");
+    };
+    assert!(b.dtb.is_empty());
+    assert_eq!(b.ramdisks.len(), 4);
+    assert_eq!(b.v4_extra.unwrap().bootconfig, "bootconfig data");
+}
+
+/// Parsing and writing an image without any changes must be lossless.
+#[test]
+fn round_trip_untouched() {
+    let names = [
+        "boot_v0.img",
+        "boot_v1.img",
+        "boot_v2.img",
+        "boot_v3.img",
+        "boot_v4.img",
+        "boot_v4_16k.img",
+        "boot_v4_empty.img",
+        "boot_v4_gki.img",
+        "boot_v4_gki_16k.img",
+        "boot_v4_vts.img",
+        "vendor_v3.img",
+        "vendor_v3_empty.img",
+        "vendor_v4.img",
+        "vendor_v4_empty.img",
+        "vendor_v4_no_dtb.img",
+    ];
+    let data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+
+    for name in names {
+        let data = fs::read(data_dir.join(name)).unwrap();
+        let image = BootImage::from_reader(Cursor::new(&data)).unwrap();
+
+        let mut writer = Cursor::new(Vec::new());
+        image.to_writer(&mut writer).unwrap();
+
+        assert_eq!(writer.into_inner(), data, "{name}");
+    }
+}
+
+#[test]
+fn vendor_bootconfig_page_size() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4.img",
+    ));
+    let BootImage::VendorV3Through4(mut b) = BootImage::from_reader(Cursor::new(data)).unwrap()
+    else {
+        panic!("Not a vendor v3/v4 boot image");
+    };
+    assert_eq!(b.bootconfig_page_size(), None);
+
+    b.v4_extra.as_mut().unwrap().bootconfig =
+        "androidboot.hardware = \"cuttlefish\"\nandroidboot.page_size = 16384\n".to_owned();
+    assert_eq!(b.bootconfig_page_size(), Some(16384));
+}
+
+#[test]
+fn load_container_lz4_frame() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img.lz4",
+    ));
+    let plain_data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+
+    let (image, container) = BootContainer::load(Cursor::new(data)).unwrap();
+    assert_eq!(container, BootContainer::Lz4Frame);
+
+    let plain_image = BootImage::from_reader(Cursor::new(plain_data)).unwrap();
+    assert_eq!(image, plain_image);
+
+    // The LZ4 output is not byte-for-byte identical, so ensure it round trips
+    // back to the same image instead.
+    let mut writer = Cursor::new(Vec::new());
+    container.save(&mut writer, &image).unwrap();
+    writer.set_position(0);
+
+    let (new_image, new_container) = BootContainer::load(writer).unwrap();
+    assert_eq!(new_container, BootContainer::Lz4Frame);
+    assert_eq!(new_image, image);
+}
+
+#[test]
+fn round_trip_container_mtk() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2_mtk.img",
+    ));
+
+    let (image, container) = BootContainer::load(Cursor::new(data)).unwrap();
+    assert_matches!(&container, BootContainer::Mtk(h) if h.name == "BOOTIMG");
+    assert_eq!(image.header_version(), 2);
+
+    let mut writer = Cursor::new(Vec::new());
+    container.save(&mut writer, &image).unwrap();
+    let new_data = writer.into_inner();
+
+    assert_eq!(data.as_slice(), new_data);
+}
+
+#[test]
+fn load_container_none() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    ));
+
+    let (_, container) = BootContainer::load(Cursor::new(data)).unwrap();
+    assert_eq!(container, BootContainer::None);
+}
+
+/// Format a v0-v2 boot image ID as it is laid out in the header.
+fn id_hex(id: [u32; 8]) -> String {
+    id.iter()
+        .flat_map(|w| w.to_le_bytes())
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[test]
+fn compute_id_v0_through_v2() {
+    // Known-good values computed independently of avbroot by hashing each
+    // section of the fixtures, followed by its size, like mkbootimg does.
+    let vectors = [
+        (
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/boot_v0.img",
+            ))
+            .as_slice(),
+            "efae82a41180eca5d39a72af82d4fcd5b8336170",
+            "9a3c2ad41b40f4dde588a4ae56fdcade8a77b157b5caa6bd5e7552de0cec128c",
+        ),
+        (
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/boot_v1.img",
+            ))
+            .as_slice(),
+            "ab8dd142721692505778643b3b1509c680abb930",
+            "c2f6adff973903da69e8a2d3578485ae0860b36a7e937fc30d5592a33c36241b",
+        ),
+        (
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/boot_v2.img",
+            ))
+            .as_slice(),
+            "97d10da8db615300010ccbdae7786bfbe84bc48b",
+            "71c94c8da147fa63905eca87904b6db942e65eca403d570a38bc7e15d2d3dbd5",
+        ),
+    ];
+
+    for (data, sha1, sha256) in vectors {
+        let BootImage::V0Through2(mut b) = BootImage::from_reader(Cursor::new(data)).unwrap()
+        else {
+            panic!("Not a v0-v2 boot image");
+        };
+
+        let id = b.compute_id(IdAlgorithm::Sha1).unwrap();
+        assert_eq!(id_hex(id), format!("{sha1}{}", "0".repeat(24)));
+
+        let id = b.compute_id(IdAlgorithm::Sha256).unwrap();
+        assert_eq!(id_hex(id), sha256);
+
+        // The fixture IDs fill all 32 bytes, so they're treated as SHA-256.
+        assert_eq!(b.id_algorithm(), IdAlgorithm::Sha256);
+        b.update_id().unwrap();
+        assert_eq!(id_hex(b.id), sha256);
+
+        b.id = [0; 8];
+        assert_eq!(b.id_algorithm(), IdAlgorithm::Sha1);
+        b.update_id().unwrap();
+        assert_eq!(id_hex(b.id), format!("{sha1}{}", "0".repeat(24)));
+    }
+}
+
+#[test]
+fn strip_optional_sections() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v1.img",
+    ));
+    let BootImage::V0Through2(mut b) = BootImage::from_reader(Cursor::new(data)).unwrap() else {
+        panic!("Not a v0-v2 boot image");
+    };
+    b.second = vec![0xaa; 5000];
+    let v1 = b.v1_extra.as_mut().unwrap();
+    v1.recovery_dtbo = vec![0xbb; 100];
+    v1.recovery_dtbo_offset = 0x10000;
+    b.update_id().unwrap();
+
+    let page_size = u64::from(b.page_size);
+    let padded = |n: u64| n.div_ceil(page_size) * page_size;
+    let mut image = BootImage::V0Through2(b);
+
+    // Nothing is removed unless requested.
+    assert_eq!(image.strip(&[]).unwrap(), 0);
+
+    // The recovery DTBO offset is kept unless the DTBO is removed.
+    let mut only_second = image.clone();
+    only_second.strip(&[OptionalSection::Second]).unwrap();
+    let BootImage::V0Through2(b) = &only_second else {
+        unreachable!();
+    };
+    assert_eq!(b.v1_extra.as_ref().unwrap().recovery_dtbo_offset, 0x10000);
+
+    let saved = image
+        .strip(&[
+            OptionalSection::Second,
+            OptionalSection::RecoveryDtbo,
+            OptionalSection::BootSignature,
+        ])
+        .unwrap();
+    assert_eq!(saved, padded(5000) + padded(100));
+
+    // The stripped image still parses and has a valid ID.
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    let stripped = BootImage::from_reader(Cursor::new(writer.get_ref())).unwrap();
+    assert_eq!(stripped, image);
+    assert_eq!(stripped.header_version(), 1);
+
+    let BootImage::V0Through2(b) = &stripped else {
+        unreachable!();
+    };
+    assert!(b.second.is_empty());
+    assert!(!b.kernel.is_empty());
+    // SHA-256 of the kernel and ramdisk, followed by four section sizes.
+    assert_eq!(
+        id_hex(b.id),
+        "ef743715527cf17bed61169a0b88cef8f76d9cabc7c620f368d832606041b8c4",
+    );
+
+    let v1 = b.v1_extra.as_ref().unwrap();
+    assert!(v1.recovery_dtbo.is_empty());
+    assert_eq!(v1.recovery_dtbo_offset, 0);
+
+    // Stripping again saves nothing.
+    assert_eq!(image.strip(&[OptionalSection::Second]).unwrap(), 0);
+}
+
+#[test]
+fn strip_boot_signature() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_vts.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let original = image.clone();
+
+    // Only applies to v0-v2 images.
+    assert_eq!(image.strip(&[OptionalSection::Second]).unwrap(), 0);
+    assert_eq!(image, original);
+
+    let saved = image.strip(&[OptionalSection::BootSignature]).unwrap();
+    assert!(saved > 0);
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.get_ref().len() as u64, data.len() as u64 - saved);
+
+    let stripped = BootImage::from_reader(Cursor::new(writer.get_ref())).unwrap();
+    assert_eq!(stripped.header_version(), 4);
+
+    let BootImage::V3Through4(b) = &stripped else {
+        panic!("Not a v3-v4 boot image");
+    };
+    let v4 = b.v4_extra.as_ref().unwrap();
+    assert!(v4.signatures().next().is_none());
+    assert!(!b.kernel.is_empty());
+}
+
+#[test]
+fn ramdisk_budget() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    ));
+    let partition_size = data.len() as u64;
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V3Through4(b) = &image else {
+        panic!("Not a v3-v4 boot image");
+    };
+    let page_size = u64::from(b.page_size);
+
+    let budget = bootimage::ramdisk_budget(&image, partition_size).unwrap();
+    assert_eq!(budget % page_size, 0);
+    assert!(image.ramdisk_size() <= budget);
+
+    // A ramdisk that uses the whole budget fills the partition exactly.
+    let BootImage::V3Through4(b) = &mut image else {
+        unreachable!();
+    };
+    b.ramdisk = vec![0xaa; budget as usize];
+    assert_eq!(
+        bootimage::ramdisk_budget(&image, partition_size).unwrap(),
+        budget
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.get_ref().len() as u64, partition_size);
+
+    // With a tighter budget, the same ramdisk no longer fits.
+    let tight = bootimage::ramdisk_budget(&image, partition_size - 1).unwrap();
+    assert_eq!(tight, budget - page_size);
+    assert!(image.ramdisk_size() > tight);
+
+    // Not even the other sections fit.
+    assert_eq!(bootimage::ramdisk_budget(&image, page_size).unwrap(), 0);
+}
+
+#[test]
+fn cmdline_spans_both_fields() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+
+    let cmdline = (0..100)
+        .map(|i| format!("param{i}=value"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    assert!(cmdline.len() > bootimage::BOOT_ARGS_SIZE);
+    image.set_cmdline(&cmdline).unwrap();
+
+    let BootImage::V0Through2(b) = &image else {
+        panic!("Not a v0-v2 boot image");
+    };
+    assert_eq!(b.cmdline.len(), bootimage::BOOT_ARGS_SIZE - 1);
+    assert_eq!(format!("{}{}", b.cmdline, b.extra_cmdline), cmdline);
+
+    // The split survives a round trip.
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    let mut image = BootImage::from_reader(Cursor::new(writer.into_inner())).unwrap();
+    assert_eq!(image.cmdline(), cmdline);
+
+    // Multi-byte characters are never split.
+    let cmdline = format!("{}é{}", "a".repeat(bootimage::BOOT_ARGS_SIZE - 2), "b");
+    image.set_cmdline(&cmdline).unwrap();
+
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+    assert_eq!(b.cmdline.len(), bootimage::BOOT_ARGS_SIZE - 2);
+    assert_eq!(b.extra_cmdline, "éb");
+
+    // Both fields need room for the NUL terminator.
+    let max_size = bootimage::BOOT_ARGS_SIZE + bootimage::BOOT_EXTRA_ARGS_SIZE - 2;
+    image.set_cmdline(&"a".repeat(max_size)).unwrap();
+    assert_matches!(
+        image.set_cmdline(&"a".repeat(max_size + 1)),
+        Err(bootimage::Error::CmdlineTooLong(s, m)) if s == max_size + 1 && m == max_size
+    );
+    assert_eq!(image.cmdline(), "a".repeat(max_size));
+
+    // v3+ images have a single field.
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    ));
+    let mut image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    image.set_cmdline(&cmdline).unwrap();
+    assert_eq!(image.cmdline(), cmdline);
+}
+
+fn extracted_files(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn read_manifest(dir: &Path) -> Value {
+    let data = fs::read_to_string(dir.join(bootimage::EXTRACT_MANIFEST_NAME)).unwrap();
+    serde_json::from_str(&data).unwrap()
+}
+
+#[test]
+fn extract_all() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    ));
+    let image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V0Through2(b) = &image else {
+        unreachable!();
+    };
+
+    let dir = temp_dir.path().join("boot");
+    bootimage::extract_all(&image, &dir).unwrap();
+
+    // Empty sections are still written so that the file names only depend on
+    // the header version.
+    assert_eq!(
+        extracted_files(&dir),
+        [
+            "dtb.img",
+            "kernel.img",
+            bootimage::EXTRACT_MANIFEST_NAME,
+            "ramdisk.img.0",
+            "recovery_dtbo.img",
+            "second.img",
+        ],
+    );
+    assert_eq!(fs::read(dir.join("kernel.img")).unwrap(), b.kernel);
+    assert_eq!(fs::read(dir.join("ramdisk.img.0")).unwrap(), b.ramdisk);
+    assert_eq!(
+        fs::read(dir.join("dtb.img")).unwrap(),
+        b.v2_extra.as_ref().unwrap().dtb,
+    );
+
+    let manifest = read_manifest(&dir);
+    assert_eq!(manifest["type"], "boot");
+    assert_eq!(manifest["header_version"], 2);
+    assert_eq!(manifest["page_size"], b.page_size);
+    assert_eq!(manifest["cmdline"], b.cmdline);
+    assert_eq!(manifest["sections"][0]["name"], "kernel");
+    assert_eq!(manifest["sections"][0]["file"], "kernel.img");
+    assert_eq!(manifest["sections"][0]["size"], b.kernel.len());
+
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4.img",
+    ));
+    let image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::VendorV3Through4(b) = &image else {
+        unreachable!();
+    };
+
+    let dir = temp_dir.path().join("vendor_boot");
+    bootimage::extract_all(&image, &dir).unwrap();
+
+    let mut expected = vec![
+        "bootconfig.txt".to_owned(),
+        "dtb.img".to_owned(),
+        bootimage::EXTRACT_MANIFEST_NAME.to_owned(),
+    ];
+    expected.extend((0..b.ramdisks.len()).map(|i| format!("ramdisk.img.{i}")));
+    assert_eq!(extracted_files(&dir), expected);
+
+    for (i, ramdisk) in b.ramdisks.iter().enumerate() {
+        let path = dir.join(format!("ramdisk.img.{i}"));
+        assert_eq!(&fs::read(path).unwrap(), ramdisk);
+    }
+
+    let manifest = read_manifest(&dir);
+    assert_eq!(manifest["type"], "vendor_boot");
+    assert_eq!(manifest["header_version"], 4);
+    let metas = manifest["v4_extra"]["ramdisk_metas"].as_array().unwrap();
+    assert_eq!(metas.len(), b.ramdisks.len());
+}
+
+#[test]
+fn os_version_and_patch_level() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4_16k.img",
+    ));
+    let image = BootImage::from_reader(Cursor::new(data)).unwrap();
+    let BootImage::V3Through4(b) = image else {
+        panic!("Not a v3-v4 boot image");
+    };
+
+    assert_eq!(b.os_version, 0x16000187);
+    assert_eq!(
+        bootimage::os_version(b.os_version),
+        Some(OsVersion {
+            major: 11,
+            minor: 0,
+            patch: 0,
+        }),
+    );
+    assert_eq!(
+        bootimage::os_patch_level(b.os_version),
+        Some(OsPatchLevel {
+            year: 2024,
+            month: 7,
+        }),
+    );
+    let info = b.to_string();
+    assert!(info.contains("- OS version:        0x16000187 (11.0.0, 2024-07)\n"));
+
+    // Each half is set independently.
+    let mut field = b.os_version;
+    let level = OsPatchLevel {
+        year: 2025,
+        month: 12,
+    };
+    bootimage::set_os_patch_level(&mut field, level).unwrap();
+    assert_eq!(field, 0x1600019c);
+    assert_eq!(bootimage::os_patch_level(field), Some(level));
+
+    let version = OsVersion {
+        major: 127,
+        minor: 1,
+        patch: 2,
+    };
+    bootimage::set_os_version(&mut field, version).unwrap();
+    assert_eq!(field, 0xfe04119c);
+    assert_eq!(bootimage::os_version(field), Some(version));
+    assert_eq!(bootimage::os_patch_level(field), Some(level));
+
+    let mut field = 0;
+    assert_eq!(bootimage::os_version(field), None);
+    assert_eq!(bootimage::os_patch_level(field), None);
+    assert_matches!(
+        bootimage::set_os_version(
+            &mut field,
+            OsVersion {
+                major: 128,
+                minor: 0,
+                patch: 0,
+            },
+        ),
+        Err(bootimage::Error::InvalidFieldValue("os_version", 128))
+    );
+    for (year, month) in [(1999, 1), (2128, 1), (2024, 0), (2024, 13)] {
+        assert_matches!(
+            bootimage::set_os_patch_level(&mut field, OsPatchLevel { year, month }),
+            Err(bootimage::Error::InvalidFieldValue("os_patch_level", _))
+        );
+    }
+    assert_eq!(field, 0);
+}
@@ -0,0 +1,113 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    io::Cursor,
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use avbroot::{
+    cli::avb as cli_avb,
+    format::avb::{self, Descriptor, HashDescriptor, Header},
+    warning::WarningCollector,
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn hash_descriptor(name: &str, data: &[u8], flags: u32) -> Descriptor {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut descriptor = HashDescriptor {
+        image_size: 0,
+        hash_algorithm: "sha256".to_owned(),
+        partition_name: name.to_owned(),
+        salt: b"avbroot".to_vec(),
+        root_digest: vec![],
+        flags,
+        reserved: [0u8; 60],
+    };
+    descriptor
+        .update(Cursor::new(data), data.len() as u64, &cancel_signal)
+        .unwrap();
+
+    Descriptor::Hash(descriptor)
+}
+
+fn verify_slot(directory: &Path, suffix: &str, key: &RsaPrivateKey) -> anyhow::Result<()> {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut seen = HashSet::new();
+    let mut descriptors = HashMap::new();
+    let warnings = WarningCollector::default();
+
+    cli_avb::verify_headers(
+        directory,
+        &format!("vbmeta{suffix}"),
+        Some(suffix),
+        Some(&key.to_public_key()),
+        false,
+        &mut seen,
+        &mut descriptors,
+        &warnings,
+    )?;
+    cli_avb::verify_descriptors(directory, &descriptors, &warnings, &cancel_signal)
+}
+
+#[test]
+fn write_slot_images() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let directory = temp_dir.path();
+    let key = get_test_key();
+
+    let boot = b"boot image".repeat(100);
+    let dtbo = b"dtbo image".repeat(100);
+
+    let algorithm_type = avbroot::crypto::validate_avb_key(&key).unwrap();
+    let mut header = Header::new_chained(algorithm_type, &[]).unwrap();
+    header.descriptors = vec![
+        hash_descriptor("boot", &boot, 0),
+        hash_descriptor("dtbo", &dtbo, avb::HASH_FLAG_DO_NOT_USE_AB),
+    ];
+    header.sign(&key).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 4096).unwrap();
+
+    fs::write(directory.join("vbmeta.img"), writer.into_inner()).unwrap();
+    fs::write(directory.join("boot.img"), &boot).unwrap();
+    fs::write(directory.join("dtbo.img"), &dtbo).unwrap();
+
+    let images = BTreeSet::from(["vbmeta", "boot", "dtbo"].map(|n| n.to_owned()));
+    let written = cli_avb::write_slot_images(directory, &images, "vbmeta", &["_a", "_b"]).unwrap();
+    assert_eq!(
+        written,
+        ["boot_a", "boot_b", "dtbo", "vbmeta_a", "vbmeta_b"],
+    );
+    assert!(!directory.join("boot.img").exists());
+    assert!(!directory.join("vbmeta.img").exists());
+    assert_eq!(fs::read(directory.join("boot_b.img")).unwrap(), boot);
+
+    verify_slot(directory, "_a", &key).unwrap();
+    verify_slot(directory, "_b", &key).unwrap();
+
+    // Each slot only uses its own images.
+    fs::write(directory.join("boot_b.img"), &dtbo).unwrap();
+    verify_slot(directory, "_a", &key).unwrap();
+    assert!(verify_slot(directory, "_b", &key).is_err());
+}
@@ -0,0 +1,376 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use avbroot::{
+    cli::device::{self, FlashAction, FlashOptions},
+    fastboot::{self, Fastboot},
+    format::sparse,
+    stream::FromReader,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+type Result<T> = std::result::Result<T, fastboot::Error>;
+
+/// Fake device that keeps the contents of its partitions in memory.
+#[derive(Default)]
+struct MockDevice {
+    vars: HashMap<String, String>,
+    partitions: HashMap<String, Vec<u8>>,
+    downloaded: Vec<u8>,
+    can_fetch: bool,
+    /// Partition that stores corrupted data when flashed.
+    corrupt: Option<String>,
+    commands: Vec<String>,
+}
+
+impl MockDevice {
+    fn new(vars: &[(&str, &str)]) -> Self {
+        Self {
+            vars: vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            can_fetch: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Write the raw chunks of a sparse image like a bootloader would. Don't care
+/// chunks leave the existing data as-is.
+fn apply_sparse(data: &mut Vec<u8>, mut image: &[u8]) {
+    let header = sparse::Header::from_reader(&mut image).unwrap();
+    let block_size = header.block_size as usize;
+    let mut offset = 0;
+
+    if data.len() < header.raw_size() as usize {
+        data.resize(header.raw_size() as usize, 0);
+    }
+
+    for _ in 0..header.num_chunks {
+        let chunk_type = image.read_u16::<LittleEndian>().unwrap();
+        image.read_u16::<LittleEndian>().unwrap();
+        let size = image.read_u32::<LittleEndian>().unwrap() as usize * block_size;
+        image.read_u32::<LittleEndian>().unwrap();
+
+        match chunk_type {
+            sparse::CHUNK_TYPE_RAW => {
+                let (raw, rest) = image.split_at(size);
+                data[offset..][..size].copy_from_slice(raw);
+                image = rest;
+            }
+            sparse::CHUNK_TYPE_DONT_CARE => {}
+            t => panic!("Unexpected chunk type: {t:#06x}"),
+        }
+
+        offset += size;
+    }
+
+    assert!(image.is_empty());
+}
+
+impl Fastboot for MockDevice {
+    fn getvar(&mut self, name: &str) -> Result<String> {
+        self.vars.get(name).cloned().ok_or_else(|| {
+            fastboot::Error::CommandFailed(format!("getvar:{name}"), "unknown".into())
+        })
+    }
+
+    fn download(&mut self, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let max_size = fastboot::parse_int(&self.vars["max-download-size"]).unwrap();
+        assert!(size <= max_size, "{size} exceeds {max_size}");
+
+        self.downloaded.clear();
+        reader.take(size).read_to_end(&mut self.downloaded)?;
+        if self.downloaded.len() as u64 != size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(())
+    }
+
+    fn flash(&mut self, partition: &str) -> Result<()> {
+        self.commands.push(format!("flash:{partition}"));
+        let mut data = if self
+            .downloaded
+            .starts_with(&sparse::SPARSE_MAGIC.to_le_bytes())
+        {
+            let mut data = self.partitions.remove(partition).unwrap_or_default();
+            apply_sparse(&mut data, &self.downloaded);
+            data
+        } else {
+            self.downloaded.clone()
+        };
+        if self.corrupt.as_deref() == Some(partition) {
+            data[0] ^= 0xff;
+        }
+        self.partitions.insert(partition.to_owned(), data);
+        Ok(())
+    }
+
+    fn erase(&mut self, partition: &str) -> Result<()> {
+        self.commands.push(format!("erase:{partition}"));
+        self.partitions.remove(partition);
+        Ok(())
+    }
+
+    fn set_active(&mut self, slot: &str) -> Result<()> {
+        self.commands.push(format!("set_active:{slot}"));
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<()> {
+        self.commands.push("reboot".to_owned());
+        Ok(())
+    }
+
+    fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Option<Vec<u8>>> {
+        if !self.can_fetch {
+            return Ok(None);
+        }
+
+        let data = &self.partitions[partition];
+        Ok(Some(data[offset as usize..][..size as usize].to_vec()))
+    }
+}
+
+fn slotted_device() -> MockDevice {
+    MockDevice::new(&[
+        ("unlocked", "yes"),
+        ("current-slot", "b"),
+        ("max-download-size", "0x2000"),
+        ("has-slot:boot", "yes"),
+        ("has-slot:init_boot", "yes"),
+        ("has-slot:vbmeta", "yes"),
+        ("has-slot:avb_custom_key", "no"),
+        ("partition-size:boot_b", "0x100"),
+    ])
+}
+
+#[test]
+fn plan_and_flash() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("boot.img"), b"boot").unwrap();
+    fs::write(dir.join("vbmeta.img"), b"vbmeta").unwrap();
+    fs::write(dir.join("init_boot_a.img"), b"init_boot").unwrap();
+    fs::write(dir.join("avb_pkmd.bin"), b"key").unwrap();
+
+    let mut device = slotted_device();
+    let options = FlashOptions {
+        avb_custom_key: Some(dir.join("avb_pkmd.bin")),
+        set_active: true,
+        reboot: true,
+        ..Default::default()
+    };
+    let actions = device::plan_flash(&mut device, dir, &options).unwrap();
+    assert_eq!(
+        actions,
+        [
+            FlashAction::Erase("avb_custom_key".to_owned()),
+            FlashAction::Flash {
+                partition: "avb_custom_key".to_owned(),
+                path: dir.join("avb_pkmd.bin"),
+            },
+            FlashAction::Flash {
+                partition: "boot_b".to_owned(),
+                path: dir.join("boot.img"),
+            },
+            // Already suffixed.
+            FlashAction::Flash {
+                partition: "init_boot_a".to_owned(),
+                path: dir.join("init_boot_a.img"),
+            },
+            FlashAction::Flash {
+                partition: "vbmeta_b".to_owned(),
+                path: dir.join("vbmeta.img"),
+            },
+            FlashAction::SetActive("b".to_owned()),
+            FlashAction::Reboot,
+        ],
+    );
+    // Planning doesn't modify the device.
+    assert!(device.commands.is_empty());
+
+    let unverified = device::execute_flash(&mut device, &actions).unwrap();
+    assert!(unverified.is_empty());
+    assert_eq!(device.partitions["boot_b"], b"boot");
+    assert_eq!(device.partitions["avb_custom_key"], b"key");
+    assert_eq!(
+        device.commands,
+        [
+            "erase:avb_custom_key",
+            "flash:avb_custom_key",
+            "flash:boot_b",
+            "flash:init_boot_a",
+            "flash:vbmeta_b",
+            "set_active:b",
+            "reboot",
+        ],
+    );
+}
+
+#[test]
+fn plan_flash_options() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("boot.img"), b"boot").unwrap();
+    fs::write(dir.join("dtbo.img"), b"dtbo").unwrap();
+
+    // Explicit slot and partitions. Unslotted partitions have no suffix.
+    let mut device = slotted_device();
+    let options = FlashOptions {
+        slot: Some("_a".to_owned()),
+        partitions: vec!["boot".to_owned(), "dtbo".to_owned()],
+        ..Default::default()
+    };
+    let actions = device::plan_flash(&mut device, dir, &options).unwrap();
+    let partitions = actions
+        .iter()
+        .map(|a| match a {
+            FlashAction::Flash { partition, .. } => partition.as_str(),
+            _ => panic!("Unexpected action: {a}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(partitions, ["boot_a", "dtbo"]);
+
+    let options = FlashOptions {
+        partitions: vec!["vendor_boot".to_owned()],
+        ..Default::default()
+    };
+    assert!(device::plan_flash(&mut device, dir, &options).is_err());
+
+    // Image larger than the partition.
+    fs::write(dir.join("boot.img"), [0u8; 0x101]).unwrap();
+    assert!(device::plan_flash(&mut device, dir, &FlashOptions::default()).is_err());
+
+    // Images larger than the download buffer are split, unless they are
+    // already sparse or the buffer is too small.
+    fs::write(dir.join("boot.img"), b"boot").unwrap();
+    fs::write(dir.join("dtbo.img"), [0u8; 0x2001]).unwrap();
+    device::plan_flash(&mut device, dir, &FlashOptions::default()).unwrap();
+
+    let mut sparse_image = sparse::SPARSE_MAGIC.to_le_bytes().to_vec();
+    sparse_image.resize(0x2001, 0);
+    fs::write(dir.join("dtbo.img"), sparse_image).unwrap();
+    assert!(device::plan_flash(&mut device, dir, &FlashOptions::default()).is_err());
+
+    fs::write(dir.join("dtbo.img"), [0u8; 0x1001]).unwrap();
+    device
+        .vars
+        .insert("max-download-size".to_owned(), "0x1000".to_owned());
+    assert!(device::plan_flash(&mut device, dir, &FlashOptions::default()).is_err());
+    device
+        .vars
+        .insert("max-download-size".to_owned(), "0x2000".to_owned());
+
+    // avb_custom_key can't be flashed from fastbootd.
+    fs::write(dir.join("avb_pkmd.bin"), b"key").unwrap();
+    let options = FlashOptions {
+        avb_custom_key: Some(dir.join("avb_pkmd.bin")),
+        ..Default::default()
+    };
+    device::plan_flash(&mut device, dir, &options).unwrap();
+    device
+        .vars
+        .insert("is-userspace".to_owned(), "yes".to_owned());
+    assert!(device::plan_flash(&mut device, dir, &options).is_err());
+    device.vars.remove("is-userspace");
+
+    fs::write(dir.join("dtbo.img"), b"dtbo").unwrap();
+    device.vars.insert("unlocked".to_owned(), "no".to_owned());
+    assert!(device::plan_flash(&mut device, dir, &FlashOptions::default()).is_err());
+}
+
+#[test]
+fn execute_flash_verify() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("boot.img");
+    fs::write(&path, b"boot").unwrap();
+    let actions = [FlashAction::Flash {
+        partition: "boot_a".to_owned(),
+        path: PathBuf::from(&path),
+    }];
+
+    let mut device = slotted_device();
+    device.can_fetch = false;
+    assert_eq!(
+        device::execute_flash(&mut device, &actions).unwrap(),
+        ["boot_a"],
+    );
+
+    device.can_fetch = true;
+    device.corrupt = Some("boot_a".to_owned());
+    assert!(device::execute_flash(&mut device, &actions).is_err());
+}
+
+#[test]
+fn execute_flash_split() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("dtbo.img");
+    // Each sparse image only fits one 4096-byte block. The last block is
+    // partial.
+    let data = (0..0x2801).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(&path, &data).unwrap();
+    let actions = [FlashAction::Flash {
+        partition: "dtbo".to_owned(),
+        path: PathBuf::from(&path),
+    }];
+
+    let mut device = slotted_device();
+    device
+        .partitions
+        .insert("dtbo".to_owned(), vec![0xff; 0x4000]);
+    let unverified = device::execute_flash(&mut device, &actions).unwrap();
+    assert!(unverified.is_empty());
+    assert_eq!(device.commands, ["flash:dtbo", "flash:dtbo", "flash:dtbo"]);
+    assert_eq!(device.partitions["dtbo"][..data.len()], data);
+    // The last block is padded with zeros.
+    assert!(device.partitions["dtbo"][data.len()..0x3000]
+        .iter()
+        .all(|b| *b == 0));
+    assert!(device.partitions["dtbo"][0x3000..]
+        .iter()
+        .all(|b| *b == 0xff));
+}
+
+#[test]
+fn parse_update_engine_status() {
+    // update_engine_client logs to stderr with the libchrome log prefix.
+    let need_reboot = "\
+[INFO:update_engine_client_android.cc(174)] onStatusUpdate(UPDATED_NEED_REBOOT (6), 1)
+";
+    assert_eq!(
+        device::parse_update_engine_status(need_reboot),
+        Some("UPDATED_NEED_REBOOT"),
+    );
+
+    // The last status wins if the status changed while following.
+    let finalizing = "\
+[INFO:update_engine_client_android.cc(174)] onStatusUpdate(DOWNLOADING (3), 0.98)
+[INFO:update_engine_client_android.cc(174)] onStatusUpdate(FINALIZING (5), 0)
+";
+    assert_eq!(
+        device::parse_update_engine_status(finalizing),
+        Some("FINALIZING"),
+    );
+
+    let idle = "[INFO:update_engine_client_android.cc(174)] onStatusUpdate(IDLE (0), 0)\n";
+    assert_eq!(device::parse_update_engine_status(idle), Some("IDLE"));
+
+    // update_engine is not running.
+    let unbound = "[ERROR:update_engine_client_android.cc(142)] \
+        Failed to get IUpdateEngine binder from service manager: android.os.UpdateEngineService\n";
+    assert_eq!(device::parse_update_engine_status(unbound), None);
+    assert_eq!(device::parse_update_engine_status("onStatusUpdate("), None);
+    assert_eq!(device::parse_update_engine_status(""), None);
+}
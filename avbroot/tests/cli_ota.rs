@@ -0,0 +1,760 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{Cursor, Read, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::anyhow;
+use assert_matches::assert_matches;
+use avbroot::{
+    cli::ota::{
+        self, CheckFailure, CheckStatus, VbmetaAction, VbmetaOptions, VbmetaPlanEntry, VerifyReport,
+    },
+    crypto,
+    format::{
+        avb::{self, AlgorithmType, ChainPartitionDescriptor, Descriptor, HashDescriptor, Header},
+        bootimage::{self, BootImage},
+        ota::{self as ota_format, SigningWriter},
+    },
+    protobuf::build::tools::releasetools::{DeviceState, OtaMetadata},
+    stream::FromReader,
+    warning::{Severity, WarningCode, WarningCollector},
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+use serde_json::{json, Value};
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+#[test]
+fn verify_report() {
+    let warnings = WarningCollector::default();
+    let mut report = VerifyReport::default();
+
+    report
+        .record("property_files", None, Ok(()), false, &warnings)
+        .unwrap();
+
+    // Digest mismatches are found anywhere in the chain of causes.
+    let error = anyhow::Error::from(avb::Error::InvalidRootDigest("aa".into(), "bb".into()))
+        .context("Failed to verify hash descriptor for: boot");
+    report
+        .record(
+            "avb_digest",
+            Some("boot"),
+            Err(CheckFailure::from(error)),
+            true,
+            &warnings,
+        )
+        .unwrap();
+
+    // Only mismatches can be ignored.
+    let error = anyhow!("Failed to open for reading: \"system.img\"");
+    report
+        .record(
+            "avb_digest",
+            Some("system"),
+            Err(CheckFailure::from(error)),
+            true,
+            &warnings,
+        )
+        .unwrap_err();
+
+    let statuses = report.checks.iter().map(|c| c.status).collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        [
+            CheckStatus::Passed,
+            CheckStatus::FailedButIgnored,
+            CheckStatus::Failed,
+        ],
+    );
+    assert_eq!(report.ignored(), 1);
+    assert_eq!(report.checks[1].expected.as_deref(), Some("aa"));
+    assert_eq!(report.checks[1].actual.as_deref(), Some("bb"));
+    assert_eq!(report.checks[2].expected, None);
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::VerifyCheckIgnored]);
+
+    let json = report
+        .to_json(Some(&anyhow!("Bad \"system\"")), &warnings.warnings())
+        .unwrap();
+    assert!(json.ends_with("}\n"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&json).unwrap(),
+        json!({
+            "status": "failed",
+            "error": "Bad \"system\"",
+            "checks": [
+                {"name": "property_files", "status": "passed"},
+                {
+                    "name": "avb_digest",
+                    "partition": "boot",
+                    "status": "failed-but-ignored",
+                    "expected": "aa",
+                    "actual": "bb",
+                    "message": "Failed to verify hash descriptor for: boot: \
+                        Expected root digest aa, but have bb",
+                },
+                {
+                    "name": "avb_digest",
+                    "partition": "system",
+                    "status": "failed",
+                    "message": "Failed to open for reading: \"system.img\"",
+                },
+            ],
+            "warnings": [
+                {
+                    "code": "verify_check_ignored",
+                    "severity": "high",
+                    "message": "Ignoring failed check: avb_digest: Failed to verify hash \
+                        descriptor for: boot: Expected root digest aa, but have bb",
+                },
+            ],
+        }),
+    );
+
+    let json = VerifyReport::default().to_json(None, &[]).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&json).unwrap(),
+        json!({"status": "passed", "checks": [], "warnings": []}),
+    );
+}
+
+#[test]
+fn patch_report_json() {
+    let warnings = WarningCollector::default();
+
+    let json = ota::patch_report_json(None, &warnings.warnings()).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&json).unwrap(),
+        json!({"status": "passed", "warnings": []}),
+    );
+
+    warnings.emit(
+        WarningCode::LowFreeSpace,
+        Severity::Medium,
+        "\"/tmp\" may not have enough space",
+    );
+    warnings.emit(WarningCode::VbmetaFlagsKept, Severity::High, "vbmeta");
+
+    let json = ota::patch_report_json(
+        Some(&anyhow!("1 warning(s) were emitted")),
+        &warnings.warnings(),
+    )
+    .unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&json).unwrap(),
+        json!({
+            "status": "failed",
+            "error": "1 warning(s) were emitted",
+            "warnings": [
+                {
+                    "code": "low_free_space",
+                    "severity": "medium",
+                    "message": "\"/tmp\" may not have enough space",
+                },
+                {"code": "vbmeta_flags_kept", "severity": "high", "message": "vbmeta"},
+            ],
+        }),
+    );
+}
+
+#[test]
+fn copy_raw_entries() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let firmware = b"firmware blob".repeat(1000);
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, options) in [
+        ("/firmware\\modem.img", deflated),
+        ("stored.txt", stored),
+        // Regenerated by avbroot, so never copied as-is.
+        (ota_format::PATH_PAYLOAD, deflated),
+    ] {
+        writer.start_file(name, options).unwrap();
+        writer.write_all(&firmware).unwrap();
+    }
+    let input = writer.finish().unwrap().into_inner();
+
+    let mut zip_reader = ZipArchive::new(Cursor::new(&input)).unwrap();
+    let paths = ota_format::sanitized_entry_names(&zip_reader).unwrap();
+    let mut zip_writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let raw_paths =
+        ota::copy_raw_entries(&mut zip_reader, &mut zip_writer, &paths, &cancel_signal).unwrap();
+    assert_eq!(raw_paths, HashSet::from(["firmware/modem.img".to_owned()]));
+
+    let output = zip_writer.finish().unwrap().into_inner();
+    let mut output_zip = ZipArchive::new(Cursor::new(&output)).unwrap();
+    assert_eq!(output_zip.len(), 1);
+
+    // The compressed data is passed through under the sanitized name.
+    let (crc32, compressed_size) = {
+        let entry = zip_reader.by_index_raw(0).unwrap();
+        (entry.crc32(), entry.compressed_size())
+    };
+    let entry = output_zip.by_index_raw(0).unwrap();
+    assert_eq!(entry.name(), "firmware/modem.img");
+    assert_eq!(entry.compression(), CompressionMethod::Deflated);
+    assert_eq!(entry.crc32(), crc32);
+    assert_eq!(entry.compressed_size(), compressed_size);
+    drop(entry);
+
+    let mut data = vec![];
+    output_zip
+        .by_index(0)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, firmware);
+}
+
+fn load_boot_image(data: &[u8]) -> BootImage {
+    BootImage::from_reader(Cursor::new(data)).unwrap()
+}
+
+#[test]
+fn has_recovery_ramdisk() {
+    let boot_v2 = load_boot_image(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v2.img",
+    )));
+    let boot_v4 = load_boot_image(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/boot_v4.img",
+    )));
+    let vendor_v3 = load_boot_image(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v3.img",
+    )));
+    let mut vendor_v4 = load_boot_image(include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vendor_v4.img",
+    )));
+
+    // Before GKI, the boot ramdisk is also the recovery ramdisk.
+    assert!(ota::has_recovery_ramdisk("boot", &boot_v2));
+
+    // GKI boot images only have a recovery ramdisk in the recovery partition.
+    assert!(!ota::has_recovery_ramdisk("boot", &boot_v4));
+    assert!(ota::has_recovery_ramdisk("recovery", &boot_v4));
+
+    assert!(ota::has_recovery_ramdisk("vendor_boot", &vendor_v3));
+    assert!(ota::has_recovery_ramdisk("vendor_boot", &vendor_v4));
+
+    // v4 vendor boot images need a recovery fragment.
+    let BootImage::VendorV3Through4(b) = &mut vendor_v4 else {
+        panic!("Not a vendor v3-v4 boot image");
+    };
+    let v4 = b.v4_extra.as_mut().unwrap();
+    v4.ramdisk_metas
+        .retain(|m| m.ramdisk_type != bootimage::VENDOR_RAMDISK_TYPE_RECOVERY);
+    assert!(!ota::has_recovery_ramdisk("vendor_boot", &vendor_v4));
+}
+
+fn vbmeta_header(flags: u32, hashes: &[&str], chains: &[&str]) -> Header {
+    let mut header = Header::new_chained(AlgorithmType::None, &[]).unwrap();
+    header.flags = flags;
+
+    for name in hashes {
+        header.descriptors.push(Descriptor::Hash(HashDescriptor {
+            image_size: 0,
+            hash_algorithm: "sha256".to_owned(),
+            partition_name: (*name).to_owned(),
+            salt: vec![],
+            root_digest: vec![],
+            flags: 0,
+            reserved: [0u8; 60],
+        }));
+    }
+
+    for (i, name) in chains.iter().enumerate() {
+        header
+            .descriptors
+            .push(Descriptor::ChainPartition(ChainPartitionDescriptor {
+                rollback_index_location: i as u32 + 1,
+                partition_name: (*name).to_owned(),
+                public_key: vec![],
+                flags: 0,
+                reserved: [0u8; 60],
+            }));
+    }
+
+    header
+}
+
+fn vbmeta_headers(system_flags: u32) -> BTreeMap<String, Header> {
+    BTreeMap::from([
+        (
+            "vbmeta".to_owned(),
+            vbmeta_header(0, &["boot"], &["vbmeta_system", "vbmeta_vendor"]),
+        ),
+        (
+            "vbmeta_system".to_owned(),
+            vbmeta_header(system_flags, &["system", "product"], &[]),
+        ),
+        (
+            "vbmeta_vendor".to_owned(),
+            vbmeta_header(0, &["vendor"], &[]),
+        ),
+    ])
+}
+
+fn modified() -> HashSet<String> {
+    ["boot", "system", "vbmeta", "vbmeta_system", "vbmeta_vendor"]
+        .map(|n| n.to_owned())
+        .into_iter()
+        .collect()
+}
+
+fn plan_vbmeta(system_flags: u32, options: &VbmetaOptions) -> anyhow::Result<Vec<VbmetaPlanEntry>> {
+    ota::plan_vbmeta(
+        &vbmeta_headers(system_flags),
+        &modified(),
+        options,
+        &WarningCollector::default(),
+    )
+}
+
+fn sign(deps: &[&str], edit_cmdline: bool) -> VbmetaAction {
+    VbmetaAction::Sign {
+        deps: deps
+            .iter()
+            .map(|d| (*d).to_owned())
+            .collect::<BTreeSet<_>>(),
+        edit_cmdline,
+        edit_props: false,
+    }
+}
+
+fn plan_summary(plan: &[VbmetaPlanEntry]) -> Vec<(&str, &VbmetaAction, u32)> {
+    plan.iter()
+        .map(|e| (e.name.as_str(), &e.action, e.new_flags))
+        .collect()
+}
+
+#[test]
+fn plan_vbmeta_order() {
+    let plan = plan_vbmeta(0, &VbmetaOptions::default()).unwrap();
+    assert_eq!(
+        plan_summary(&plan),
+        [
+            ("vbmeta_system", &sign(&["system"], false), 0),
+            ("vbmeta", &sign(&["boot", "vbmeta_system"], false), 0),
+            ("vbmeta_vendor", &VbmetaAction::Unchanged, 0),
+        ],
+    );
+    assert_eq!(plan[0].to_string(), "vbmeta_system: re-sign, update system");
+    assert_eq!(
+        plan[2].to_string(),
+        "vbmeta_vendor: pass through (unmodified)"
+    );
+
+    // Only the root vbmeta image has its kernel cmdline edited.
+    let options = VbmetaOptions {
+        edit_cmdline: true,
+        ..Default::default()
+    };
+    let plan = plan_vbmeta(0, &options).unwrap();
+    assert_eq!(plan[0].action, sign(&["system"], false));
+    assert_eq!(plan[1].action, sign(&["boot", "vbmeta_system"], true));
+    assert_eq!(
+        plan[1].to_string(),
+        "vbmeta: re-sign, update boot, vbmeta_system, edit kernel cmdline",
+    );
+
+    // Same for property descriptors.
+    let options = VbmetaOptions {
+        edit_props: true,
+        ..Default::default()
+    };
+    let plan = plan_vbmeta(0, &options).unwrap();
+    assert_eq!(plan[0].action, sign(&["system"], false));
+    assert_matches!(
+        &plan[1].action,
+        VbmetaAction::Sign {
+            edit_cmdline: false,
+            edit_props: true,
+            ..
+        }
+    );
+    assert_eq!(
+        plan[1].to_string(),
+        "vbmeta: re-sign, update boot, vbmeta_system, edit properties",
+    );
+}
+
+#[test]
+fn plan_vbmeta_skip_avb() {
+    let warnings = WarningCollector::default();
+    let modified = ["boot", "system", "vbmeta", "vbmeta_system"]
+        .map(|n| n.to_owned())
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let options = VbmetaOptions {
+        skip_avb: HashSet::from(["vbmeta_system".to_owned()]),
+        ..Default::default()
+    };
+
+    let plan = ota::plan_vbmeta(&vbmeta_headers(0), &modified, &options, &warnings).unwrap();
+    assert_eq!(
+        plan_summary(&plan),
+        [
+            ("vbmeta", &sign(&["boot"], false), 0),
+            ("vbmeta_system", &VbmetaAction::Skip, 0),
+            ("vbmeta_vendor", &VbmetaAction::Unchanged, 0),
+        ],
+    );
+    assert_eq!(
+        plan[1].to_string(),
+        "vbmeta_system: pass through (--skip-avb)"
+    );
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::AvbResigningSkipped]);
+}
+
+#[test]
+fn plan_vbmeta_skip_avb_chained() {
+    let modified = ["boot", "vendor", "vbmeta", "vbmeta_vendor"]
+        .map(|n| n.to_owned())
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    // Without --skip-avb, the chained image is re-signed and its parent's chain
+    // descriptor is updated.
+    let warnings = WarningCollector::default();
+    let plan = ota::plan_vbmeta(
+        &vbmeta_headers(0),
+        &modified,
+        &VbmetaOptions::default(),
+        &warnings,
+    )
+    .unwrap();
+    assert_eq!(
+        plan_summary(&plan),
+        [
+            ("vbmeta_vendor", &sign(&["vendor"], false), 0),
+            ("vbmeta", &sign(&["boot", "vbmeta_vendor"], false), 0),
+            ("vbmeta_system", &VbmetaAction::Unchanged, 0),
+        ],
+    );
+    assert!(warnings.warnings().is_empty());
+
+    // With --skip-avb, the parent no longer depends on it, so the original
+    // chain descriptor is kept. The stale vendor descriptor is reported.
+    let warnings = WarningCollector::default();
+    let options = VbmetaOptions {
+        skip_avb: HashSet::from(["vbmeta_vendor".to_owned()]),
+        ..Default::default()
+    };
+    let plan = ota::plan_vbmeta(&vbmeta_headers(0), &modified, &options, &warnings).unwrap();
+    assert_eq!(
+        plan_summary(&plan),
+        [
+            ("vbmeta", &sign(&["boot"], false), 0),
+            ("vbmeta_system", &VbmetaAction::Unchanged, 0),
+            ("vbmeta_vendor", &VbmetaAction::Skip, 0),
+        ],
+    );
+
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::AvbResigningSkipped]);
+}
+
+#[test]
+fn check_skip_avb() {
+    let all_partitions = HashSet::from(["boot", "vbmeta", "vbmeta_vendor", "vendor"]);
+    let required_images = HashMap::from([
+        ("@gki_ramdisk".to_owned(), "boot".to_owned()),
+        ("@vbmeta:vbmeta".to_owned(), "vbmeta".to_owned()),
+        (
+            "@vbmeta:vbmeta_vendor".to_owned(),
+            "vbmeta_vendor".to_owned(),
+        ),
+    ]);
+    let check = |names: &[&str]| {
+        let skip_avb = names.iter().map(|n| (*n).to_owned()).collect();
+        ota::check_skip_avb(&skip_avb, &all_partitions, &required_images)
+    };
+
+    check(&[]).unwrap();
+    check(&["vbmeta_vendor", "vendor"]).unwrap();
+
+    let error = check(&["odm"]).unwrap_err();
+    assert!(error.to_string().contains("non-existent odm partition"));
+
+    let error = check(&["boot"]).unwrap_err();
+    assert!(error.to_string().contains("patched as @gki_ramdisk"));
+}
+
+#[test]
+fn plan_vbmeta_flags() {
+    // Flags that disable AVB must be explicitly cleared or kept.
+    assert!(plan_vbmeta(3, &VbmetaOptions::default()).is_err());
+
+    let options = VbmetaOptions {
+        clear_flags: true,
+        ..Default::default()
+    };
+    let plan = plan_vbmeta(3, &options).unwrap();
+    assert_eq!((plan[0].old_flags, plan[0].new_flags), (3, 0));
+    assert_eq!(
+        plan[0].to_string(),
+        "vbmeta_system: re-sign, update system, flags 0x3 -> 0x0",
+    );
+
+    let options = VbmetaOptions {
+        keep_flags: true,
+        ..Default::default()
+    };
+    let warnings = WarningCollector::default();
+    let plan = ota::plan_vbmeta(&vbmeta_headers(3), &modified(), &options, &warnings).unwrap();
+    assert_eq!((plan[0].old_flags, plan[0].new_flags), (3, 3));
+    assert_eq!(
+        plan[0].to_string(),
+        "vbmeta_system: re-sign, update system, flags 0x3 kept",
+    );
+
+    // Kept flags that disable AVB are reported.
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes, [WarningCode::VbmetaFlagsKept]);
+
+    let options = VbmetaOptions {
+        clear_flags: true,
+        keep_flags: true,
+        ..Default::default()
+    };
+    assert!(plan_vbmeta(0, &options).is_err());
+}
+
+#[test]
+fn plan_vbmeta_conflicts() {
+    // Header flags cannot be cleared without re-signing.
+    let options = VbmetaOptions {
+        clear_flags: true,
+        skip_avb: HashSet::from(["vbmeta_system".to_owned()]),
+        ..Default::default()
+    };
+    assert!(plan_vbmeta(3, &options).is_err());
+    // But there's nothing to clear if the flags are unset.
+    assert!(plan_vbmeta(0, &options).is_ok());
+
+    // Kernel cmdline descriptors cannot be edited without re-signing.
+    let options = VbmetaOptions {
+        edit_cmdline: true,
+        skip_avb: HashSet::from(["vbmeta".to_owned()]),
+        ..Default::default()
+    };
+    assert!(plan_vbmeta(0, &options).is_err());
+
+    let options = VbmetaOptions {
+        edit_cmdline: true,
+        ..Default::default()
+    };
+    let plan = ota::plan_vbmeta(
+        &vbmeta_headers(0),
+        &HashSet::from(["vbmeta".to_owned()]),
+        &options,
+        &WarningCollector::default(),
+    );
+    assert!(plan.is_err());
+}
+
+#[test]
+fn plan_vbmeta_property_conflicts() {
+    // Property descriptors cannot be edited without re-signing either.
+    let options = VbmetaOptions {
+        edit_props: true,
+        skip_avb: HashSet::from(["vbmeta".to_owned()]),
+        ..Default::default()
+    };
+    assert!(plan_vbmeta(0, &options).is_err());
+
+    // Skipping a chained image is fine since it's not edited.
+    let options = VbmetaOptions {
+        edit_props: true,
+        skip_avb: HashSet::from(["vbmeta_system".to_owned()]),
+        ..Default::default()
+    };
+    assert!(plan_vbmeta(0, &options).is_ok());
+}
+
+#[test]
+fn rebuild_stub_vbmeta() {
+    let mut system = vbmeta_header(0, &["system"], &[]);
+    system.public_key = b"system key".to_vec();
+    system.rollback_index_location = 2;
+    let mut init_boot = vbmeta_header(0, &[], &[]);
+    init_boot.public_key = b"init_boot key".to_vec();
+    init_boot.rollback_index_location = 2;
+
+    let mut headers = BTreeMap::from([
+        ("vbmeta".to_owned(), vbmeta_header(3, &[], &[])),
+        ("vbmeta_system".to_owned(), system),
+    ]);
+    let images = [
+        ("boot".to_owned(), Some(vbmeta_header(0, &["boot"], &[]))),
+        ("init_boot".to_owned(), Some(init_boot)),
+        ("dtbo".to_owned(), None),
+        // Unmodified images without AVB metadata are not covered.
+        ("abl".to_owned(), None),
+        // Already covered by vbmeta_system.
+        (
+            "system".to_owned(),
+            Some(vbmeta_header(0, &["system"], &[])),
+        ),
+    ];
+    let modified = HashSet::from(["boot".to_owned(), "dtbo".to_owned()]);
+
+    let added = ota::rebuild_stub_vbmeta("vbmeta", &mut headers, &images, &modified, false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(added, ["vbmeta_system", "boot", "init_boot", "dtbo"]);
+
+    let header = &headers["vbmeta"];
+    assert_eq!(header.flags, 0);
+    assert_matches!(
+        &header.descriptors[0],
+        Descriptor::ChainPartition(d)
+            if d.rollback_index_location == 2 && d.public_key == b"system key"
+    );
+    assert_eq!(
+        header.descriptors[1],
+        images[0].1.as_ref().unwrap().descriptors[0]
+    );
+    // The location from the header is already used.
+    assert_matches!(
+        &header.descriptors[2],
+        Descriptor::ChainPartition(d) if d.rollback_index_location == 1
+    );
+    assert_matches!(
+        &header.descriptors[3],
+        Descriptor::Hash(d) if d.salt.len() == 32 && d.root_digest.is_empty()
+    );
+
+    // Images with descriptors are left alone.
+    assert_matches!(
+        ota::rebuild_stub_vbmeta("vbmeta", &mut headers, &images, &modified, false),
+        Ok(None)
+    );
+
+    let mut headers = BTreeMap::from([("vbmeta".to_owned(), vbmeta_header(3, &[], &[]))]);
+    ota::rebuild_stub_vbmeta("vbmeta", &mut headers, &images, &modified, true).unwrap();
+    assert_eq!(headers["vbmeta"].flags, 3);
+
+    // Chained vbmeta images must be signed.
+    let mut headers = BTreeMap::from([
+        ("vbmeta".to_owned(), vbmeta_header(3, &[], &[])),
+        (
+            "vbmeta_vendor".to_owned(),
+            vbmeta_header(0, &["vendor"], &[]),
+        ),
+    ]);
+    assert!(ota::rebuild_stub_vbmeta("vbmeta", &mut headers, &images, &modified, false).is_err());
+}
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn get_test_cert() -> Certificate {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.crt",
+    ));
+
+    crypto::read_pem_cert(data.as_bytes()).unwrap()
+}
+
+#[test]
+fn sign_output_zip() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let metadata = OtaMetadata {
+        postcondition: Some(DeviceState {
+            build: vec!["google/device/device:14/AP1A/1:user/release-keys".to_owned()],
+            timestamp: 1_700_000_000,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let auto = ota_format::provenance_comment(&metadata);
+
+    for (arg, message) in [
+        (None, "signed by avbroot"),
+        (Some("auto"), auto.as_str()),
+        (Some("none"), "signed by avbroot"),
+        (Some("custom label"), "custom label"),
+    ] {
+        let output_comment = arg.map(|a| ota::parse_output_comment(a).unwrap());
+
+        let signing_writer = SigningWriter::new(Cursor::new(Vec::new()));
+        let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip_writer.start_file("message.txt", options).unwrap();
+        zip_writer.write_all(b"avbroot signature test\n").unwrap();
+
+        let data = ota::sign_output_zip(
+            zip_writer.finish().unwrap(),
+            output_comment.as_ref(),
+            &metadata,
+            &get_test_key(),
+            &get_test_cert(),
+        )
+        .unwrap()
+        .into_inner();
+
+        // The message comes first and the signature footer last.
+        let zip = ZipArchive::new(Cursor::new(&data)).unwrap();
+        let comment = zip.comment();
+        assert!(
+            comment.starts_with(format!("{message}\0").as_bytes()),
+            "{arg:?}",
+        );
+        let footer = &comment[comment.len() - 6..];
+        assert_eq!(&footer[2..4], b"\xff\xff", "{arg:?}");
+        assert_eq!(
+            usize::from(u16::from_le_bytes([footer[4], footer[5]])),
+            comment.len(),
+            "{arg:?}",
+        );
+
+        let cert = ota_format::verify_ota(Cursor::new(&data), &cancel_signal).unwrap();
+        assert_eq!(cert, get_test_cert(), "{arg:?}");
+    }
+
+    assert!(ota::parse_output_comment("a\0b").is_err());
+}
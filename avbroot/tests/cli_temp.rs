@@ -0,0 +1,101 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{ffi::OsStr, fs, io::Write};
+
+use avbroot::{
+    cli::temp::{self, TempPolicy},
+    warning::{WarningCode, WarningCollector},
+};
+use tempfile::{NamedTempFile, TempDir};
+
+#[test]
+fn check_available_space() {
+    let dir = TempDir::new().unwrap();
+
+    let warnings = WarningCollector::default();
+    temp::check_available_space(dir.path(), 0, &warnings);
+    assert!(warnings.warnings().is_empty());
+
+    // Low free space is only a warning because it is not reliable everywhere.
+    let warnings = WarningCollector::default();
+    temp::check_available_space(dir.path(), u64::MAX, &warnings);
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    if cfg!(unix) {
+        assert_eq!(codes, [WarningCode::LowFreeSpace]);
+    } else {
+        assert!(codes.is_empty());
+    }
+
+    // Same for failures to query the free space.
+    let warnings = WarningCollector::default();
+    temp::check_available_space(&dir.path().join("missing"), 0, &warnings);
+    let codes = warnings
+        .warnings()
+        .iter()
+        .map(|w| w.code)
+        .collect::<Vec<_>>();
+    if cfg!(unix) {
+        assert_eq!(codes, [WarningCode::LowFreeSpace]);
+    } else {
+        assert!(codes.is_empty());
+    }
+}
+
+#[test]
+fn temp_policy_peak_sizes() {
+    let dir = TempDir::new().unwrap();
+    let policy = TempPolicy::new(dir.path());
+
+    let warnings = WarningCollector::default();
+    policy.check_space(0, &warnings);
+    assert!(warnings.warnings().is_empty());
+
+    {
+        let mut stage = policy.stage("first stage").unwrap();
+        assert!(stage.path().starts_with(dir.path()));
+
+        let mut file = stage.named_file(OsStr::new("a")).unwrap();
+        file.write_all(&[0u8; 100]).unwrap();
+        assert_eq!(stage.update_peak_size().unwrap(), 100);
+
+        // The peak size is kept when files are removed.
+        drop(file);
+        assert_eq!(stage.update_peak_size().unwrap(), 0);
+
+        let mut file = stage.named_file(OsStr::new("b")).unwrap();
+        file.write_all(&[0u8; 10]).unwrap();
+        file.keep().unwrap();
+    }
+
+    {
+        let stage = policy.stage("second").unwrap();
+        fs::create_dir(stage.path().join("subdir")).unwrap();
+        fs::write(stage.path().join("subdir").join("c"), [0u8; 20]).unwrap();
+    }
+
+    assert_eq!(policy.peak_sizes(), [("first stage", 100), ("second", 20)]);
+
+    // Stage directories are removed when they are dropped.
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn persist() {
+    let temp_dir = TempDir::new().unwrap();
+    let output_dir = TempDir::new().unwrap();
+    let output = output_dir.path().join("output.zip");
+
+    let mut file = NamedTempFile::new_in(temp_dir.path()).unwrap();
+    file.write_all(b"foobar").unwrap();
+    temp::persist(file, &output).unwrap();
+
+    assert_eq!(fs::read(&output).unwrap(), b"foobar");
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
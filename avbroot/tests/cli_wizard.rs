@@ -0,0 +1,117 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use avbroot::{
+    cli::{
+        ota::PatchCli,
+        wizard::{self, RootMode, WizardConfig},
+    },
+    protobuf::build::tools::releasetools::{DeviceState, OtaMetadata},
+};
+use clap::Parser;
+
+fn full_config(root: RootMode) -> WizardConfig {
+    WizardConfig {
+        input: Some("ota.zip".into()),
+        output: Some("my ota.zip".into()),
+        key_avb: Some("avb.key".into()),
+        key_ota: Some("ota.key".into()),
+        cert_ota: Some("ota.crt".into()),
+        root,
+        magisk: Some("Magisk.apk".into()),
+        magisk_preinit_device: Some("metadata".into()),
+        prepatched: Some("boot.img".into()),
+    }
+}
+
+#[test]
+fn config_round_trip() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("config.toml");
+
+    let config = full_config(RootMode::Prepatched);
+    wizard::write_config(&path, &config).unwrap();
+    assert_eq!(wizard::read_config(&path).unwrap(), config);
+
+    // Missing answers use the defaults.
+    fs::write(&path, "").unwrap();
+    assert_eq!(wizard::read_config(&path).unwrap(), WizardConfig::default());
+
+    fs::write(&path, "root = \"foo\"").unwrap();
+    assert!(wizard::read_config(&path).is_err());
+}
+
+#[test]
+fn default_output() {
+    let config = full_config(RootMode::Rootless);
+
+    assert_eq!(
+        wizard::default_output(&config, Path::new("ota.zip")),
+        PathBuf::from("my ota.zip"),
+    );
+    assert_eq!(
+        wizard::default_output(&config, Path::new("other.zip")),
+        PathBuf::from("other.zip.patched"),
+    );
+}
+
+#[test]
+fn patch_args() {
+    let args = wizard::patch_args(&full_config(RootMode::Magisk), true, false).unwrap();
+    assert_eq!(
+        wizard::format_command(&args),
+        "avbroot ota patch --input ota.zip --output 'my ota.zip' --key-avb avb.key \
+        --key-ota ota.key --cert-ota ota.crt --magisk Magisk.apk \
+        --magisk-preinit-device metadata --pass-avb-env-var AVBROOT_PASS_AVB",
+    );
+
+    // The arguments must be accepted by `avbroot ota patch` as-is.
+    for root in [RootMode::Magisk, RootMode::Prepatched, RootMode::Rootless] {
+        let args = wizard::patch_args(&full_config(root), true, true).unwrap();
+        let cli = PatchCli::try_parse_from([OsString::from("patch")].into_iter().chain(args));
+        assert!(cli.is_ok(), "{root:?}: {cli:?}");
+    }
+
+    let args = wizard::patch_args(&full_config(RootMode::Rootless), false, false).unwrap();
+    assert!(!args.contains(&OsString::from("--magisk")));
+    assert!(!args.contains(&OsString::from("--pass-avb-env-var")));
+
+    let mut config = full_config(RootMode::Magisk);
+    config.magisk = None;
+    assert!(wizard::patch_args(&config, false, false).is_err());
+}
+
+#[test]
+fn check_device() {
+    let metadata = OtaMetadata {
+        precondition: Some(DeviceState {
+            device: vec!["cheetah".to_owned()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    wizard::check_device(&metadata, "cheetah", Some("fingerprint")).unwrap();
+    wizard::check_device(&metadata, "cheetah", None).unwrap();
+
+    let error = wizard::check_device(&metadata, "panther", None).unwrap_err();
+    assert!(error.to_string().contains("\"panther\""));
+}
+
+#[test]
+fn validate_ota() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("ota.zip");
+    fs::write(&path, b"not a zip").unwrap();
+
+    assert!(wizard::validate_ota(&path).is_err());
+    assert!(wizard::validate_ota(&temp_dir.path().join("missing.zip")).is_err());
+}
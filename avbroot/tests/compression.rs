@@ -3,12 +3,22 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::{Cursor, Read, Seek, Write};
+use std::{
+    io::{self, BufWriter, Cursor, IoSlice, Read, Seek, SeekFrom, Write},
+    iter, thread,
+    time::{Duration, Instant},
+};
 
+use assert_matches::assert_matches;
 use avbroot::{
     self,
-    format::compression::{CompressedFormat, CompressedReader, CompressedWriter},
+    format::compression::{
+        self, BlockIndexEntry, CompressedFormat, CompressedReader, CompressedWriter,
+        CompressionOptions, GzipOptions, Lz4LegacyEncoder, SegmentedCompressedReader,
+    },
+    stream::{ChainedReader, RingBuffer, SharedCursor, ThrottledReader, ThrottledWriter},
 };
+use lz4_flex::frame::{FrameEncoder, FrameInfo};
 
 fn round_trip(data: &[u8], format: CompressedFormat) {
     let raw_writer = Cursor::new(Vec::new());
@@ -31,9 +41,1349 @@ fn round_trip_gzip() {
     round_trip(b"gzip-compressed data", CompressedFormat::Gzip);
 }
 
+#[test]
+fn round_trip_xz() {
+    round_trip(b"xz-compressed data", CompressedFormat::Xz);
+}
+
 #[test]
 fn round_trip_lz4_legacy() {
     // Make sure we exceed the 8MiB block boundary.
     let data = b"Lz4Legacy".repeat(1024 * 1024);
     round_trip(&data, CompressedFormat::Lz4Legacy);
 }
+
+#[test]
+fn lz4_frame_content_size() {
+    let data = b"Lz4Frame".repeat(1024);
+
+    for content_size in [Some(data.len() as u64), None] {
+        let info = FrameInfo::new().content_size(content_size);
+        let mut encoder = FrameEncoder::with_frame_info(info, Vec::new());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+        assert_eq!(reader.format(), CompressedFormat::Lz4Frame);
+        assert_eq!(reader.uncompressed_size_hint(), content_size);
+
+        let (new_data, error) = reader.decompress_all().unwrap();
+        assert!(error.is_none());
+        assert_eq!(new_data, data);
+    }
+
+    // Legacy streams never store the size.
+    let mut writer =
+        CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Lz4Legacy).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap().into_inner();
+    let reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    assert_eq!(reader.uncompressed_size_hint(), None);
+
+    assert_matches!(
+        CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Lz4Frame).err(),
+        Some(compression::Error::UnsupportedWriteFormat(
+            CompressedFormat::Lz4Frame
+        ))
+    );
+}
+
+fn compress_with_options(data: &[u8], options: &CompressionOptions) -> Vec<u8> {
+    let mut writer = CompressedWriter::with_options(Vec::new(), options).unwrap();
+    assert_eq!(writer.format(), options.format);
+    writer.write_all(data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    assert_eq!(reader.format(), options.format);
+    let (new_data, error) = reader.decompress_all().unwrap();
+    assert!(error.is_none());
+    assert_eq!(new_data, data);
+
+    compressed
+}
+
+#[test]
+fn writer_from_options() {
+    let data = b"options".repeat(4096);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        // The defaults produce the same output as new().
+        let options = CompressionOptions::new(format);
+        let mut writer = CompressedWriter::new(Vec::new(), format).unwrap();
+        writer.write_all(&data).unwrap();
+        assert_eq!(
+            compress_with_options(&data, &options),
+            writer.finish().unwrap(),
+        );
+
+        // The same options can be reused for multiple writers.
+        let options = CompressionOptions {
+            level: Some(9),
+            ..options
+        };
+        let first = compress_with_options(&data, &options);
+        assert_eq!(compress_with_options(&data, &options), first);
+    }
+
+    let gzip_default =
+        compress_with_options(&data, &CompressionOptions::new(CompressedFormat::Gzip));
+    let gzip_fast = compress_with_options(
+        &data,
+        &CompressionOptions {
+            level: Some(1),
+            gzip: GzipOptions {
+                mtime: 1,
+                ..Default::default()
+            },
+            ..CompressionOptions::new(CompressedFormat::Gzip)
+        },
+    );
+    assert_ne!(gzip_fast, gzip_default);
+    assert_eq!(&gzip_fast[4..8], &1u32.to_le_bytes());
+
+    // Smaller LZ4 legacy blocks.
+    let lz4 = compress_with_options(
+        &data,
+        &CompressionOptions {
+            block_size: Some(4096),
+            ..CompressionOptions::new(CompressedFormat::Lz4Legacy)
+        },
+    );
+    let first_block_size = u32::from_le_bytes(lz4[4..8].try_into().unwrap()) as usize;
+    assert!(lz4.len() > 4 + 4 + first_block_size);
+
+    // xz without an integrity check.
+    let xz_default = compress_with_options(&data, &CompressionOptions::new(CompressedFormat::Xz));
+    let xz_no_check = compress_with_options(
+        &data,
+        &CompressionOptions {
+            checksum: false,
+            ..CompressionOptions::new(CompressedFormat::Xz)
+        },
+    );
+    assert!(xz_no_check.len() < xz_default.len());
+
+    // The kernel's xz decompressor only supports CRC32 or no check. The check
+    // type is stored in the second byte of the stream flags.
+    assert_eq!(xz_default[7], 0x01);
+    assert_eq!(xz_no_check[7], 0x00);
+}
+
+#[test]
+fn writer_options_limits() {
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Xz,
+    ] {
+        let options = CompressionOptions {
+            size_limit: Some(10),
+            ..CompressionOptions::new(format)
+        };
+        let mut writer = CompressedWriter::with_options(Vec::new(), &options).unwrap();
+        writer.write_all(b"0123456789").unwrap();
+        let error = writer.write_all(b"a").unwrap_err();
+        assert_matches!(
+            error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<compression::Error>()),
+            Some(compression::Error::InputTooLarge(10))
+        );
+    }
+
+    assert_matches!(
+        CompressedWriter::with_options(
+            Vec::new(),
+            &CompressionOptions {
+                level: Some(10),
+                ..CompressionOptions::new(CompressedFormat::Xz)
+            },
+        )
+        .err(),
+        Some(compression::Error::InvalidLevel(CompressedFormat::Xz, 10))
+    );
+    assert_matches!(
+        CompressedWriter::with_options(
+            Vec::new(),
+            &CompressionOptions {
+                block_size: Some(0),
+                ..CompressionOptions::new(CompressedFormat::Lz4Legacy)
+            },
+        )
+        .err(),
+        Some(compression::Error::InvalidBlockSize(0))
+    );
+}
+
+#[test]
+fn writer_options_deadline() {
+    let data = noise(4 * 1024 * 1024);
+
+    for format in [CompressedFormat::Gzip, CompressedFormat::Xz] {
+        let options = CompressionOptions {
+            deadline: Some(Instant::now()),
+            ..CompressionOptions::new(format)
+        };
+        let mut writer = CompressedWriter::with_options(Vec::new(), &options).unwrap();
+        let error = writer.write_all(&data).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut, "{format:?}");
+        assert_matches!(
+            error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<compression::Error>()),
+            Some(compression::Error::DeadlineExceeded)
+        );
+
+        // A distant deadline never trips.
+        let options = CompressionOptions {
+            deadline: Some(Instant::now() + Duration::from_secs(3600)),
+            ..CompressionOptions::new(format)
+        };
+        let mut writer = CompressedWriter::with_options(Vec::new(), &options).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+
+        // Finishing after the deadline fails even if every write was on time.
+        let options = CompressionOptions {
+            deadline: Some(Instant::now() + Duration::from_millis(100)),
+            ..CompressionOptions::new(format)
+        };
+        let mut writer = CompressedWriter::with_options(Vec::new(), &options).unwrap();
+        writer.write_all(b"foobar").unwrap();
+        thread::sleep(Duration::from_millis(150));
+        let error = writer.finish().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut, "{format:?}");
+    }
+}
+
+/// Compress into a small ring buffer with a consumer that only drains a few
+/// bytes whenever the producer is blocked.
+fn round_trip_slow_consumer(data: &[u8], format: CompressedFormat) {
+    let ring = RingBuffer::new(1024);
+    let mut consumer = ring.clone();
+    let mut writer = CompressedWriter::new(ring, format).unwrap();
+    let mut compressed = vec![];
+
+    let mut drain = |compressed: &mut Vec<u8>| {
+        let mut buf = [0u8; 100];
+        let n = consumer.read(&mut buf).unwrap();
+        compressed.extend_from_slice(&buf[..n]);
+    };
+
+    let mut remaining = data;
+
+    while !remaining.is_empty() {
+        match writer.write(remaining) {
+            Ok(n) => remaining = &remaining[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => drain(&mut compressed),
+            Err(e) => panic!("Unexpected error: {e}"),
+        }
+    }
+
+    loop {
+        match writer.try_finish() {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => drain(&mut compressed),
+            Err(e) => panic!("Unexpected error: {e}"),
+        }
+    }
+
+    let ring = writer.finish().unwrap();
+    assert!(ring.len() <= ring.capacity());
+    ring.close();
+
+    consumer.read_to_end(&mut compressed).unwrap();
+
+    let mut reader = CompressedReader::new(Cursor::new(compressed), false).unwrap();
+    assert_eq!(reader.format(), format);
+
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+
+    assert_eq!(data, new_data);
+}
+
+#[test]
+fn slow_consumer_gzip() {
+    let data = b"gzip-compressed data".repeat(64 * 1024);
+    round_trip_slow_consumer(&data, CompressedFormat::Gzip);
+}
+
+#[test]
+fn slow_consumer_lz4_legacy() {
+    let data = b"Lz4Legacy".repeat(1024 * 1024);
+    round_trip_slow_consumer(&data, CompressedFormat::Lz4Legacy);
+}
+
+#[test]
+fn lz4_legacy_empty_no_alloc() {
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.write_all(b"").unwrap();
+    assert_eq!(writer.buffer_capacity(), 0);
+    writer.try_finish().unwrap();
+    assert_eq!(writer.buffer_capacity(), 0);
+
+    let mut raw_reader = writer.finish().unwrap();
+    raw_reader.rewind().unwrap();
+    let mut reader = CompressedReader::new(raw_reader, false).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert!(new_data.is_empty());
+}
+
+#[test]
+fn lz4_legacy_block_index() {
+    let chunks: [&[u8]; 3] = [b"first chunk, ", b"second chunk, ", b"third chunk"];
+    let data = chunks.concat();
+
+    let mut writer =
+        CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Lz4Legacy).unwrap();
+    let mut index = vec![writer.flush_block_boundary().unwrap().unwrap()];
+
+    for chunk in chunks {
+        writer.write_all(chunk).unwrap();
+        index.push(writer.flush_block_boundary().unwrap().unwrap());
+    }
+
+    // No empty block is written if there is no new data.
+    assert_eq!(
+        writer.flush_block_boundary().unwrap().unwrap(),
+        *index.last().unwrap(),
+    );
+
+    let compressed = writer.finish().unwrap().into_inner();
+
+    assert_eq!(
+        index[0],
+        BlockIndexEntry {
+            compressed_offset: 4,
+            uncompressed_offset: 0,
+        },
+    );
+    assert_eq!(
+        index
+            .iter()
+            .map(|e| e.uncompressed_offset)
+            .collect::<Vec<_>>(),
+        [0, 13, 27, 38],
+    );
+
+    // The whole stream is still valid.
+    let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert_eq!(new_data, data);
+
+    // Decoding can start at any boundary.
+    for entry in &index {
+        let mut stream = compressed[..4].to_vec();
+        stream.extend_from_slice(&compressed[entry.compressed_offset as usize..]);
+
+        let mut reader = CompressedReader::new(Cursor::new(stream), false).unwrap();
+        let mut new_data = vec![];
+        reader.read_to_end(&mut new_data).unwrap();
+        assert_eq!(new_data, data[entry.uncompressed_offset as usize..]);
+    }
+
+    // Other formats have no block boundaries.
+    let mut writer =
+        CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Gzip).unwrap();
+    assert_eq!(writer.flush_block_boundary().unwrap(), None);
+}
+
+#[test]
+fn lz4_legacy_size_hint_same_output() {
+    let data = b"Lz4Legacy".repeat(1024 * 1024);
+
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.write_all(&data).unwrap();
+    let expected = writer.finish().unwrap().into_inner();
+
+    // The hint is intentionally too small.
+    let mut writer = Lz4LegacyEncoder::with_size_hint(Cursor::new(Vec::new()), 1024).unwrap();
+    writer.write_all(&data[..100]).unwrap();
+    assert!(writer.buffer_capacity() < 8 * 1024 * 1024);
+    writer.write_all(&data[100..]).unwrap();
+    assert!(writer.buffer_capacity() <= 8 * 1024 * 1024);
+    let actual = writer.finish().unwrap().into_inner();
+
+    assert_eq!(actual, expected);
+}
+
+/// Walk the frames of an LZ4 legacy stream and return the number of blocks and
+/// the number of bytes that are not compressed data. A trailing zero-length
+/// block is the end marker and is not counted as a block.
+fn lz4_legacy_framing(output: &[u8]) -> (u64, u64) {
+    let mut offset = 4;
+    let mut num_blocks = 0;
+    let mut compressed_len = 0;
+
+    while offset < output.len() {
+        let size = u32::from_le_bytes(output[offset..offset + 4].try_into().unwrap());
+        offset += 4 + size as usize;
+        if size != 0 {
+            num_blocks += 1;
+        } else {
+            assert_eq!(offset, output.len(), "Zero-length block before the end");
+        }
+        compressed_len += u64::from(size);
+    }
+
+    assert_eq!(offset, output.len());
+
+    (num_blocks, output.len() as u64 - compressed_len)
+}
+
+#[test]
+fn lz4_legacy_predict_output() {
+    const BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+    let default = Lz4LegacyEncoder::new(io::sink()).unwrap();
+    assert_eq!(default.predict_output(0, 1), (1, 8));
+    assert_eq!(default.predict_output(BLOCK_SIZE as u64, 1), (2, 12));
+
+    // Block size, minimum block fill, end marker, write size, and input sizes
+    // around the block boundaries for those options.
+    let cases: &[(Option<usize>, Option<usize>, bool, usize, &[usize])] = &[
+        (
+            None,
+            None,
+            false,
+            BLOCK_SIZE * 3,
+            &[
+                0,
+                1,
+                BLOCK_SIZE - 1,
+                BLOCK_SIZE,
+                BLOCK_SIZE + 1,
+                2 * BLOCK_SIZE,
+            ],
+        ),
+        (None, None, true, 4096, &[0, 1, 4096, BLOCK_SIZE]),
+        (
+            Some(1000),
+            None,
+            false,
+            4096,
+            &[0, 999, 1000, 1001, 3000, 4097],
+        ),
+        (Some(1000), None, true, 300, &[0, 1000, 2500]),
+        (
+            None,
+            Some(1000),
+            false,
+            300,
+            &[0, 299, 900, 1200, 1201, 5000],
+        ),
+        (None, Some(1000), false, 4096, &[0, 1000, 4096, 9000]),
+        (Some(700), Some(1000), true, 256, &[0, 700, 701, 2100, 5000]),
+        (
+            Some(1000),
+            Some(300),
+            false,
+            128,
+            &[0, 300, 384, 1000, 3333],
+        ),
+    ];
+
+    for (block_size, min_block_fill, end_marker, write_size, input_lens) in cases {
+        for input_len in *input_lens {
+            let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+            if let Some(size) = block_size {
+                writer.set_block_size(*size);
+            }
+            writer.set_min_block_fill(*min_block_fill);
+            writer.set_end_marker(*end_marker);
+
+            let predicted = writer.predict_output(*input_len as u64, *write_size as u64);
+
+            // Data that doesn't compress well, like real images.
+            let data = (0..*input_len)
+                .map(|i| (i as u32).wrapping_mul(2654435761).to_le_bytes()[3])
+                .collect::<Vec<_>>();
+            for chunk in data.chunks(*write_size) {
+                writer.write_all(chunk).unwrap();
+            }
+            let output = writer.finish().unwrap().into_inner();
+
+            assert_eq!(
+                predicted,
+                lz4_legacy_framing(&output),
+                "Block size: {block_size:?}, min block fill: {min_block_fill:?}, \
+                end marker: {end_marker}, write size: {write_size}, input length: {input_len}",
+            );
+        }
+    }
+}
+
+#[test]
+fn lz4_legacy_end_marker() {
+    let data = b"Lz4Legacy".repeat(1024 * 1024);
+
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.write_all(&data).unwrap();
+    let without_marker = writer.finish().unwrap().into_inner();
+
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.set_end_marker(true);
+    writer.write_all(&data).unwrap();
+    let with_marker = writer.finish().unwrap().into_inner();
+
+    assert_eq!(with_marker.len(), without_marker.len() + 4);
+    assert_eq!(with_marker[..without_marker.len()], without_marker);
+    assert_eq!(with_marker[without_marker.len()..], [0u8; 4]);
+
+    let mut reader = CompressedReader::new(Cursor::new(&with_marker), false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Lz4Legacy);
+    let mut decompressed = Vec::new();
+    reader.read_to_end(&mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+}
+
+#[test]
+fn open_standalone_compressed() {
+    let data = b"standalone image".repeat(1024);
+    let dir = tempfile::tempdir().unwrap();
+
+    for (name, format) in [
+        ("image.img", CompressedFormat::None),
+        ("image.img.gz", CompressedFormat::Gzip),
+        ("image.img.xz", CompressedFormat::Xz),
+    ] {
+        let path = dir.path().join(name);
+        assert_eq!(CompressedFormat::from_extension(&path), format);
+
+        let mut writer = CompressedWriter::new(Vec::new(), format).unwrap();
+        writer.write_all(&data).unwrap();
+        std::fs::write(&path, writer.finish().unwrap()).unwrap();
+
+        let mut reader = compression::open_standalone(&path).unwrap();
+        let mut new_data = vec![];
+        reader.read_to_end(&mut new_data).unwrap();
+        assert_eq!(new_data, data);
+
+        // The result must be seekable.
+        reader.seek(io::SeekFrom::Start(10)).unwrap();
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b" image");
+    }
+}
+
+#[test]
+fn compress_decompress_file() {
+    let data = b"standalone image".repeat(1024);
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("image.img");
+    std::fs::write(&input, &data).unwrap();
+
+    for (name, format) in [
+        ("image.img.gz", CompressedFormat::Gzip),
+        ("image.img.lz4", CompressedFormat::Lz4Legacy),
+        ("image.img.xz", CompressedFormat::Xz),
+    ] {
+        let compressed = dir.path().join(name);
+        let n = compression::compress_file(&input, &compressed).unwrap();
+        assert_eq!(n, data.len() as u64);
+
+        let raw = std::fs::read(&compressed).unwrap();
+        let reader = CompressedReader::new(Cursor::new(&raw), false).unwrap();
+        assert_eq!(reader.format(), format);
+
+        let output = dir.path().join("output.img");
+        let n = compression::decompress_file(&compressed, &output).unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    let result = compression::compress_file(&input, &dir.path().join("image.bin"));
+    assert_matches!(
+        result,
+        Err(compression::Error::UnknownExtension(_, s)) if s == ".gz, .lz4, .xz"
+    );
+
+    // Uncompressed input is rejected.
+    let result = compression::decompress_file(&input, &dir.path().join("output.img"));
+    assert_matches!(result, Err(compression::Error::UnknownFormat));
+}
+
+#[test]
+fn gzip_header_options() {
+    let data = b"gzip header".repeat(1024);
+    let compress = |options: &GzipOptions| {
+        let mut writer = CompressedWriter::with_gzip_options(Vec::new(), options);
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap()
+    };
+
+    let default = compress(&GzipOptions::default());
+    assert_eq!(&default[..10], b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\xff");
+
+    let options = GzipOptions {
+        mtime: 0x5f5e1000,
+        os: 3,
+        xfl: Some(2),
+    };
+    let custom = compress(&options);
+    assert_eq!(&custom[..10], b"\x1f\x8b\x08\x00\x00\x10\x5e\x5f\x02\x03");
+    assert_eq!(GzipOptions::from_header(&custom), Some(options));
+
+    // Only the header differs.
+    assert_eq!(default[10..], custom[10..]);
+
+    let mut reader = CompressedReader::new(Cursor::new(&custom), false).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert_eq!(new_data, data);
+}
+
+#[test]
+fn gzip_across_chained_readers() {
+    let data = b"chained reader".repeat(1024);
+
+    let mut writer = CompressedWriter::new(Vec::new(), CompressedFormat::Gzip).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    // Split such that the gzip header itself spans two readers.
+    let (first, rest) = compressed.split_at(4);
+    let (second, third) = rest.split_at(rest.len() / 2);
+    let readers = vec![Cursor::new(first), Cursor::new(second), Cursor::new(third)];
+
+    let raw_reader = ChainedReader::new_seekable(readers).unwrap();
+    let mut reader = CompressedReader::new(raw_reader, false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Gzip);
+
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert_eq!(new_data, data);
+}
+
+#[test]
+fn throttled_round_trip() {
+    let data = b"throttled".repeat(1024);
+
+    // Throttle the compressed output.
+    let raw_writer = ThrottledWriter::new(Vec::new(), 16 * 1024 * 1024);
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Xz).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap().into_inner();
+
+    // Throttle the decompressed output.
+    let reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    let mut reader = ThrottledReader::new(reader, 16 * 1024 * 1024);
+
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert_eq!(new_data, data);
+}
+
+/// Write all of `chunks` with vectored writes, returning the number of calls.
+fn write_all_vectored(writer: &mut impl Write, chunks: &[&[u8]]) -> usize {
+    let mut index = 0;
+    let mut offset = 0;
+    let mut calls = 0;
+
+    while index < chunks.len() {
+        let bufs = iter::once(&chunks[index][offset..])
+            .chain(chunks[index + 1..].iter().copied())
+            .map(IoSlice::new)
+            .collect::<Vec<_>>();
+
+        let mut n = writer.write_vectored(&bufs).unwrap();
+        assert_ne!(n, 0);
+        calls += 1;
+
+        // Advance past the consumed bytes.
+        while n > 0 {
+            let remain = chunks[index].len() - offset;
+            if n < remain {
+                offset += n;
+                n = 0;
+            } else {
+                n -= remain;
+                index += 1;
+                offset = 0;
+            }
+        }
+    }
+
+    calls
+}
+
+fn round_trip_vectored(data: &[u8], format: CompressedFormat) {
+    let chunks = data.chunks(16).collect::<Vec<_>>();
+
+    let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format).unwrap();
+    let calls = write_all_vectored(&mut writer, &chunks);
+    let mut raw_reader = writer.finish().unwrap();
+
+    // Every format consumes more than one buffer per call.
+    assert!(calls * 16 < chunks.len(), "{format:?}: {calls} calls");
+
+    raw_reader.rewind().unwrap();
+    let mut reader = CompressedReader::new(raw_reader, false).unwrap();
+
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+
+    assert_eq!(data, new_data);
+}
+
+#[test]
+fn vectored_writes() {
+    let data = b"vectored writes".repeat(16 * 1024);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        round_trip_vectored(&data, format);
+    }
+}
+
+/// Number of write syscalls made by the current thread so far.
+#[cfg(target_os = "linux")]
+fn write_syscalls() -> u64 {
+    let io = std::fs::read_to_string("/proc/thread-self/io").unwrap();
+
+    io.lines()
+        .find_map(|l| l.strip_prefix("syscw: "))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn standalone_writer_syscalls() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let chunk = [0x5au8; 16];
+    let count = 4096;
+
+    let unbuffered_path = temp_dir.path().join("unbuffered.img");
+    let file = std::fs::File::create(&unbuffered_path).unwrap();
+    let mut writer = CompressedWriter::new(file, CompressedFormat::None).unwrap();
+
+    let before = write_syscalls();
+    for _ in 0..count {
+        writer.write_all(&chunk).unwrap();
+    }
+    writer.finish().unwrap();
+    let unbuffered = write_syscalls() - before;
+
+    let buffered_path = temp_dir.path().join("buffered.img");
+    let mut writer =
+        compression::create_standalone(&buffered_path, CompressedFormat::None).unwrap();
+
+    let before = write_syscalls();
+    for _ in 0..count {
+        writer.write_all(&chunk).unwrap();
+    }
+    writer.finish().unwrap().flush().unwrap();
+    let buffered = write_syscalls() - before;
+
+    assert_eq!(unbuffered, count);
+    assert_eq!(buffered, 1);
+    assert_eq!(
+        std::fs::read(unbuffered_path).unwrap(),
+        std::fs::read(buffered_path).unwrap(),
+    );
+}
+
+#[test]
+fn estimate_size_small_is_exact() {
+    let data = b"estimate size".repeat(4096);
+
+    for format in [
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let mut writer = CompressedWriter::new(Vec::new(), format).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        assert_eq!(
+            compression::estimate_size(&data, format).unwrap(),
+            compressed.len() as u64,
+        );
+    }
+}
+
+#[test]
+fn decompress_all_bad_checksum() {
+    let data = include_bytes!("data/ramdisk_bad_crc.cpio.gz");
+
+    // The CRC32 in the gzip trailer is wrong, but the data is complete.
+    let mut reader = CompressedReader::new(Cursor::new(data), false).unwrap();
+    let (decompressed, error) = reader.decompress_all().unwrap();
+    assert_eq!(decompressed.len(), 512);
+    assert_eq!(&decompressed[..6], b"070701");
+    assert_matches!(error, Some(compression::Error::ChecksumMismatch(512)));
+    assert!(error.unwrap().is_recoverable());
+
+    // Missing part of the trailer is fatal.
+    let mut reader = CompressedReader::new(Cursor::new(&data[..data.len() - 4]), false).unwrap();
+    assert_matches!(reader.decompress_all(), Err(compression::Error::Truncated));
+
+    // So is corruption in the deflate stream (reserved block type).
+    let mut corrupted = data.to_vec();
+    corrupted[10] |= 0b110;
+    let mut reader = CompressedReader::new(Cursor::new(&corrupted), false).unwrap();
+    assert_matches!(reader.decompress_all(), Err(compression::Error::Corrupt(_)));
+}
+
+#[test]
+fn gzip_header_crc() {
+    // Has FNAME and FHCRC set. The header CRC is at offset 20.
+    let data = include_bytes!("data/gzip_fhcrc.gz");
+    let expected = b"avbroot gzip FHCRC test\n".repeat(4);
+
+    for lenient in [false, true] {
+        let mut reader = if lenient {
+            CompressedReader::new_lenient(Cursor::new(data), false).unwrap()
+        } else {
+            CompressedReader::new(Cursor::new(data), false).unwrap()
+        };
+        let (decompressed, error) = reader.decompress_all().unwrap();
+        assert_eq!(decompressed, expected);
+        assert!(error.is_none());
+    }
+
+    let mut corrupted = data.to_vec();
+    corrupted[20] ^= 0xff;
+
+    // Nothing was decompressed, so the strict error is not recoverable.
+    let error = CompressedReader::new(Cursor::new(&corrupted), false)
+        .err()
+        .unwrap();
+    assert_matches!(error, compression::Error::HeaderChecksumMismatch);
+    assert!(!error.is_recoverable());
+
+    let mut reader = CompressedReader::new_lenient(Cursor::new(&corrupted), false).unwrap();
+    let (decompressed, error) = reader.decompress_all().unwrap();
+    assert_eq!(decompressed, expected);
+    assert_matches!(error, Some(compression::Error::ChecksumMismatch(96)));
+    assert!(error.unwrap().is_recoverable());
+
+    // The raw reader is returned with the original data.
+    let (raw_reader, consumed) = reader.into_parts().unwrap();
+    assert_eq!(consumed, corrupted.len() as u64);
+    assert_eq!(raw_reader.into_inner(), &corrupted);
+
+    // Decoders created without detection always check the header CRC.
+    let mut reader = CompressedReader::reader_for(CompressedFormat::Gzip, Cursor::new(&corrupted));
+    assert_matches!(reader.decompress_all(), Err(compression::Error::Corrupt(_)));
+}
+
+#[test]
+fn drain() {
+    let data = b"data to skip".repeat(10000);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format).unwrap();
+        writer.write_all(&data).unwrap();
+        let mut raw_reader = writer.finish().unwrap();
+        raw_reader.rewind().unwrap();
+
+        let mut reader = CompressedReader::new(raw_reader, true).unwrap();
+        let mut header = [0u8; 100];
+        reader.read_exact(&mut header).unwrap();
+
+        assert_eq!(reader.drain().unwrap(), data.len() as u64 - 100);
+        assert_eq!(reader.drain().unwrap(), 0);
+    }
+
+    // The checksum at the end of the stream is still verified.
+    let data = include_bytes!("data/ramdisk_bad_crc.cpio.gz");
+    let mut reader = CompressedReader::new(Cursor::new(data), false).unwrap();
+    assert!(reader.drain().is_err());
+}
+
+/// Deterministic incompressible data so that the compressed size tracks the
+/// input size.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1du64;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+fn compress(data: &[u8], format: CompressedFormat) -> Vec<u8> {
+    let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format).unwrap();
+    writer.write_all(data).unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn into_parts_end_of_stream() {
+    let data = noise(100_000);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let compressed = compress(&data, format);
+
+        let mut reader = CompressedReader::new(Cursor::new(&compressed), true).unwrap();
+        assert_eq!(reader.drain().unwrap(), data.len() as u64);
+
+        let (mut raw_reader, consumed) = reader.into_parts().unwrap();
+        assert_eq!(consumed, compressed.len() as u64, "{format:?}");
+        assert_eq!(raw_reader.stream_position().unwrap(), consumed);
+    }
+}
+
+#[test]
+fn into_parts_gzip_trailing_data() {
+    let trailing = b"\x1f\x8bnext archive";
+    let mut hit_boundary = false;
+
+    // The decoder reads 32 KiB at a time. Make sure the stream ends right
+    // before, exactly at, and right after the end of each buffer fill.
+    for len in (32 * 1024 - 64..32 * 1024).chain(64 * 1024 - 64..64 * 1024) {
+        let mut compressed = compress(&noise(len), CompressedFormat::Gzip);
+        let compressed_len = compressed.len() as u64;
+        hit_boundary |= compressed_len % (32 * 1024) == 0;
+        compressed.extend_from_slice(trailing);
+
+        let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+        assert_eq!(reader.drain().unwrap(), len as u64);
+
+        let (mut raw_reader, consumed) = reader.into_parts().unwrap();
+        assert_eq!(consumed, compressed_len, "Input size: {len}");
+
+        let mut remaining = vec![];
+        raw_reader.read_to_end(&mut remaining).unwrap();
+        assert_eq!(remaining, trailing, "Input size: {len}");
+    }
+
+    assert!(hit_boundary);
+}
+
+#[test]
+fn into_parts_lz4_legacy_block_boundary() {
+    let block_size = 8 * 1024 * 1024;
+    let data = noise(block_size + 100);
+    let compressed = compress(&data, CompressedFormat::Lz4Legacy);
+    let first_block_len = u32::from_le_bytes(compressed[4..8].try_into().unwrap());
+
+    // Stop right after the first block.
+    let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    let mut buf = vec![0u8; block_size];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[..block_size]);
+
+    let (mut raw_reader, consumed) = reader.into_parts().unwrap();
+    assert_eq!(consumed, 8 + u64::from(first_block_len));
+    assert_eq!(raw_reader.stream_position().unwrap(), consumed);
+
+    // The rest of the raw stream is the second block.
+    let mut remaining = vec![];
+    raw_reader.read_to_end(&mut remaining).unwrap();
+    assert_eq!(remaining, compressed[consumed as usize..]);
+    assert_eq!(
+        u32::from_le_bytes(remaining[..4].try_into().unwrap()) as usize,
+        remaining.len() - 4,
+    );
+}
+
+#[test]
+fn new_at_offset() {
+    let data = noise(10_000);
+    let mut file = vec![0xaa; 100];
+    file.extend(compress(&data, CompressedFormat::Gzip));
+    let end = file.len() as u64;
+    file.extend_from_slice(b"trailing data");
+
+    let mut reader = CompressedReader::new_at(Cursor::new(&file), 100, false).unwrap();
+    assert_eq!(reader.format(), CompressedFormat::Gzip);
+    let (decompressed, error) = reader.decompress_all().unwrap();
+    assert_eq!(decompressed, data);
+    assert!(error.is_none());
+
+    let (_, consumed) = reader.into_parts().unwrap();
+    assert_eq!(consumed, end);
+
+    // The header CRC is checked at the right position.
+    let fhcrc = include_bytes!("data/gzip_fhcrc.gz");
+    let mut file = vec![0u8; 100];
+    file.extend_from_slice(fhcrc);
+    assert!(CompressedReader::new_at(Cursor::new(&file), 100, false).is_ok());
+
+    file[120] ^= 0xff;
+    assert_matches!(
+        CompressedReader::new_at(Cursor::new(&file), 100, false),
+        Err(compression::Error::HeaderChecksumMismatch)
+    );
+
+    assert_matches!(
+        CompressedReader::new_at(Cursor::new(&file), 1000, true),
+        Err(compression::Error::OffsetOutOfBounds(1000, 159))
+    );
+}
+
+#[test]
+fn chunks_reassemble() {
+    let data = noise(100_000);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let compressed = compress(&data, format);
+
+        for chunk_size in [1000, 100_000, 200_000] {
+            let reader = CompressedReader::new(Cursor::new(&compressed), true).unwrap();
+            let chunks = reader
+                .chunks(chunk_size)
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+
+            assert_eq!(chunks.len(), data.len().div_ceil(chunk_size), "{format:?}");
+            assert!(chunks[..chunks.len() - 1]
+                .iter()
+                .all(|c| c.len() == chunk_size));
+            assert_eq!(chunks.concat(), data, "{format:?}");
+        }
+    }
+}
+
+#[test]
+fn chunks_error_is_last_item() {
+    let data = include_bytes!("data/ramdisk_bad_crc.cpio.gz");
+
+    let reader = CompressedReader::new(Cursor::new(data), false).unwrap();
+    let mut items = reader.chunks(100).collect::<Vec<_>>();
+
+    assert!(items.pop().unwrap().is_err());
+    let chunks = items.into_iter().collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(chunks.concat().len(), 512);
+}
+
+#[test]
+fn read_up_to_limit() {
+    let data = noise(10_000);
+
+    // Small LZ4 blocks so that reads cross block boundaries.
+    let mut encoder = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    encoder.set_block_size(1000);
+    encoder.write_all(&data).unwrap();
+    let lz4_blocks = encoder.finish().unwrap().into_inner();
+
+    let mut inputs = [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ]
+    .map(|f| compress(&data, f))
+    .to_vec();
+    inputs.push(lz4_blocks);
+
+    for compressed in inputs {
+        for max in [1, 300, 4096] {
+            let mut reader = CompressedReader::new(Cursor::new(&compressed), true).unwrap();
+            let mut buf = vec![0u8; 16384];
+            let mut new_data = vec![];
+
+            loop {
+                let n = reader.read_up_to(max, &mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+
+                assert!(n <= max, "{:?}: {n} > {max}", reader.format());
+                new_data.extend_from_slice(&buf[..n]);
+            }
+
+            assert_eq!(new_data, data, "{:?}", reader.format());
+        }
+    }
+
+    let mut reader = CompressedReader::new(Cursor::new(&data), true).unwrap();
+    assert_eq!(reader.read_up_to(0, &mut [0u8; 16]).unwrap(), 0);
+}
+
+/// A writer that records how many times it was written to.
+#[derive(Default)]
+struct CountingCallsWriter {
+    data: Vec<u8>,
+    calls: usize,
+}
+
+impl Write for CountingCallsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.calls += 1;
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn finish_flushes_buffered_writer() {
+    let data = b"buffered output".repeat(4096);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let raw_writer = BufWriter::with_capacity(16384, CountingCallsWriter::default());
+        let mut writer = CompressedWriter::new(raw_writer, format).unwrap();
+
+        for chunk in data.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+
+        let raw_writer = writer.finish().unwrap();
+        assert!(raw_writer.buffer().is_empty(), "{format:?}");
+
+        // Without buffering, there would be at least one write per chunk.
+        let inner = raw_writer.get_ref();
+        assert!(inner.calls <= data.len().div_ceil(16384) + 1, "{format:?}");
+
+        let mut reader = CompressedReader::new(Cursor::new(&inner.data), true).unwrap();
+        let mut new_data = vec![];
+        reader.read_to_end(&mut new_data).unwrap();
+        assert_eq!(new_data, data, "{format:?}");
+    }
+}
+
+#[test]
+fn segmented_gzip_then_lz4_legacy() {
+    let first = noise(5000);
+    let second = b"Lz4Legacy".repeat(10_000);
+
+    let gzip = compress(&first, CompressedFormat::Gzip);
+    let lz4 = compress(&second, CompressedFormat::Lz4Legacy);
+    let file = [&gzip[..], &lz4].concat();
+    let expected = [&first[..], &second].concat();
+
+    for formats in [
+        [None, None],
+        [
+            Some(CompressedFormat::Gzip),
+            Some(CompressedFormat::Lz4Legacy),
+        ],
+    ] {
+        let segments = [(0, formats[0]), (gzip.len() as u64, formats[1])];
+        let mut reader = SegmentedCompressedReader::new(Cursor::new(&file), &segments).unwrap();
+        let mut new_data = vec![];
+        reader.read_to_end(&mut new_data).unwrap();
+        assert!(new_data == expected, "{formats:?}");
+    }
+
+    // A forced raw segment is read as is.
+    let segments = [(0, Some(CompressedFormat::None))];
+    let mut reader = SegmentedCompressedReader::new(Cursor::new(&gzip), &segments).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert!(new_data == gzip);
+
+    let segments = [(0, Some(CompressedFormat::Xz))];
+    let mut reader = SegmentedCompressedReader::new(Cursor::new(&file), &segments).unwrap();
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+
+    assert_matches!(
+        SegmentedCompressedReader::new(Cursor::new(&file), &[(10, None), (10, None)]).err(),
+        Some(compression::Error::UnorderedSegment(10))
+    );
+    assert_matches!(
+        SegmentedCompressedReader::new(Cursor::new(&file), &[(u64::MAX, None)]).err(),
+        Some(compression::Error::OffsetOutOfBounds(u64::MAX, _))
+    );
+    let segments = [(0, None), (file.len() as u64, None)];
+    assert_matches!(
+        SegmentedCompressedReader::new(Cursor::new(&file), &segments).err(),
+        Some(compression::Error::EmptySegment(n)) if n == file.len() as u64
+    );
+}
+
+#[test]
+fn lz4_legacy_verify_blocks() {
+    let data = noise(10_000);
+
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.set_block_size(1000);
+    writer.write_all(&data).unwrap();
+    let plain = writer.finish().unwrap().into_inner();
+
+    let mut writer = Lz4LegacyEncoder::new(Cursor::new(Vec::new())).unwrap();
+    writer.set_block_size(1000);
+    writer.set_verify_blocks(true);
+    writer.write_all(&data).unwrap();
+    writer.flush_block_boundary().unwrap();
+    writer.write_all(&data[..10]).unwrap();
+    let verified = writer.finish().unwrap().into_inner();
+
+    // Verification doesn't change the output.
+    assert!(verified.starts_with(&plain[..plain.len() - 4]));
+
+    let mut reader = CompressedReader::new(Cursor::new(&verified), false).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert!(new_data == [&data[..], &data[..10]].concat());
+}
+
+/// Write `data` in chunks of `chunk_size` bytes. Returns the amount of input
+/// written when each block was emitted and the compressed data.
+fn lz4_legacy_block_emissions(
+    data: &[u8],
+    chunk_size: usize,
+    min_block_fill: Option<usize>,
+) -> (Vec<usize>, Vec<u8>) {
+    let output = SharedCursor::default();
+    let output_len = || output.clone().seek(SeekFrom::End(0)).unwrap();
+
+    let mut encoder = Lz4LegacyEncoder::new(output.clone()).unwrap();
+    encoder.set_min_block_fill(min_block_fill);
+
+    let mut emissions = vec![];
+    let mut written = 0;
+
+    for chunk in data.chunks(chunk_size) {
+        let before = output_len();
+        encoder.write_all(chunk).unwrap();
+        written += chunk.len();
+
+        // The magic is written by the first write.
+        if output_len() > before.max(4) {
+            emissions.push(written);
+        }
+    }
+
+    encoder.finish().unwrap();
+
+    let mut compressed = vec![];
+    output.clone_rewind().read_to_end(&mut compressed).unwrap();
+
+    (emissions, compressed)
+}
+
+#[test]
+fn lz4_legacy_min_block_fill() {
+    let data = noise(10_000);
+
+    // Each block is emitted by the first write that reaches the minimum fill.
+    let (emissions, compressed) = lz4_legacy_block_emissions(&data, 300, Some(1000));
+    assert_eq!(emissions, (1..=8).map(|n| n * 1200).collect::<Vec<_>>());
+
+    let mut reader = CompressedReader::new(Cursor::new(&compressed), false).unwrap();
+    let mut new_data = vec![];
+    reader.read_to_end(&mut new_data).unwrap();
+    assert!(new_data == data);
+
+    // By default, nothing is emitted until the 8 MiB block is full.
+    let (emissions, _) = lz4_legacy_block_emissions(&data, 300, None);
+    assert!(emissions.is_empty());
+}
+
+#[test]
+fn reader_for_detected_format() {
+    let first = noise(5000);
+    let second = b"second stream".repeat(1000);
+
+    for format in [
+        CompressedFormat::None,
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let sniffed = compress(&first, format);
+        let detected = CompressedReader::new(Cursor::new(&sniffed), true)
+            .unwrap()
+            .format();
+        assert_eq!(detected, format);
+
+        // A plain slice is not seekable.
+        let compressed = compress(&second, format);
+        let mut reader = CompressedReader::reader_for(detected, compressed.as_slice());
+        assert_eq!(reader.format(), format);
+
+        let mut new_data = vec![];
+        reader.read_to_end(&mut new_data).unwrap();
+        assert!(new_data == second, "{format:?}");
+    }
+
+    // Data in a different format is only rejected when it is read.
+    let compressed = compress(&second, CompressedFormat::Xz);
+    let mut reader = CompressedReader::reader_for(CompressedFormat::Gzip, compressed.as_slice());
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+}
+
+#[test]
+fn normalize_is_idempotent() {
+    let data = b"normalized".repeat(1000);
+
+    for format in [
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        // Start from non-default parameters.
+        let options = CompressionOptions {
+            level: Some(1),
+            block_size: Some(4096),
+            gzip: GzipOptions {
+                mtime: 1234,
+                os: 3,
+                xfl: None,
+            },
+            ..CompressionOptions::new(format)
+        };
+        let mut writer = CompressedWriter::with_options(Vec::new(), &options).unwrap();
+        writer.write_all(&data).unwrap();
+        let original = writer.finish().unwrap();
+
+        let normalized = compression::normalize(&original).unwrap();
+        assert!(normalized != original, "{format:?}");
+        let renormalized = compression::normalize(&normalized).unwrap();
+        assert!(renormalized == normalized, "{format:?}");
+
+        let mut reader = CompressedReader::new(Cursor::new(&normalized), false).unwrap();
+        assert_eq!(reader.format(), format);
+        assert!(reader.decompress_all().unwrap().0 == data, "{format:?}");
+    }
+
+    assert_eq!(compression::normalize(b"plain").unwrap(), b"plain");
+
+    let mut encoder = FrameEncoder::new(Vec::new());
+    encoder.write_all(&data).unwrap();
+    let lz4_frame = encoder.finish().unwrap();
+    assert_matches!(
+        compression::normalize(&lz4_frame),
+        Err(compression::Error::UnsupportedWriteFormat(
+            CompressedFormat::Lz4Frame
+        ))
+    );
+}
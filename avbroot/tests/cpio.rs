@@ -0,0 +1,147 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::{Cursor, Read};
+
+use assert_matches::assert_matches;
+use avbroot::format::cpio::{self, CpioEntryNew};
+
+// Uncompressed newcx archive where every entry has an SELinux label, except
+// for a symlink with an empty list of xattrs. init also has file capabilities.
+// This was assembled by hand following the layout from the proposed kernel
+// patches because no released tool, including AOSP's mkbootfs, can write the
+// newcx format.
+const RAMDISK_XATTRS: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/ramdisk_xattrs.cpio",
+));
+
+fn save(entries: &[CpioEntryNew]) -> Vec<u8> {
+    let mut writer = Cursor::new(Vec::new());
+    cpio::save(&mut writer, entries, false).unwrap();
+    writer.into_inner()
+}
+
+#[test]
+fn round_trip_xattrs() {
+    let entries = cpio::load(Cursor::new(RAMDISK_XATTRS), false).unwrap();
+    assert_eq!(entries.len(), 4);
+    assert!(cpio::has_xattrs(&entries));
+
+    let labels = entries
+        .iter()
+        .map(|e| e.selinux_label())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        [
+            Some(b"u:object_r:rootfs:s0".as_slice()),
+            Some(b"u:object_r:vendor_configs_file:s0".as_slice()),
+            Some(b"u:object_r:init_exec:s0".as_slice()),
+            None,
+        ],
+    );
+    let capability = entries[2].xattr(cpio::XATTR_CAPABILITY).unwrap();
+    assert_eq!(capability.len(), 20);
+    assert_eq!(&capability[..4], b"\x02\x00\x00\x02");
+    assert_eq!(entries[3].xattrs, Some(vec![]));
+
+    assert_eq!(save(&entries), RAMDISK_XATTRS);
+
+    let listing = entries[2].to_string();
+    assert!(listing.contains("Label:     u:object_r:init_exec:s0\n"));
+    assert!(listing.contains("Xattr:     security.capability=\""));
+}
+
+#[test]
+fn label_new_entries() {
+    let mut entries = cpio::load(Cursor::new(RAMDISK_XATTRS), false).unwrap();
+    entries.push(CpioEntryNew::new_directory(b"overlay.d"));
+
+    cpio::label_new_entries(&mut entries, cpio::ROOTFS_LABEL).unwrap();
+    assert_eq!(entries[4].selinux_label(), Some(cpio::ROOTFS_LABEL));
+    // Existing entries are untouched.
+    assert_eq!(entries[3].xattrs, Some(vec![]));
+
+    let loaded = cpio::load(Cursor::new(save(&entries)), false).unwrap();
+    assert_eq!(loaded, entries);
+
+    // Archives without xattrs stay in the newc format.
+    let mut entries = vec![CpioEntryNew::new_file(b"init")];
+    cpio::label_new_entries(&mut entries, cpio::ROOTFS_LABEL).unwrap();
+    assert_eq!(entries[0].xattrs, None);
+    assert_eq!(&save(&entries)[..6], b"070701");
+}
+
+#[test]
+fn set_selinux_label() {
+    let mut entry = CpioEntryNew::new_file(b"init");
+    // Mainline kernels can't unpack newcx entries, so this must be opted into.
+    assert_matches!(
+        entry.set_selinux_label(cpio::ROOTFS_LABEL),
+        Err(cpio::Error::XattrsNotEnabled(_))
+    );
+    assert_eq!(entry.xattrs, None);
+
+    entry.enable_xattrs();
+    entry.set_selinux_label(b"u:object_r:rootfs:s0").unwrap();
+    entry.set_selinux_label(cpio::ROOTFS_LABEL).unwrap();
+
+    assert_eq!(
+        entry.xattrs,
+        Some(vec![(
+            cpio::XATTR_SELINUX.to_vec(),
+            b"u:object_r:rootfs:s0\0".to_vec(),
+        )]),
+    );
+}
+
+#[test]
+fn invalid_xattrs() {
+    // The first xattr record of the first entry claims to be larger than the
+    // xattr data.
+    let mut data = RAMDISK_XATTRS.to_vec();
+    data[0x8c..0x94].copy_from_slice(b"000000ff");
+
+    assert_matches!(
+        cpio::load(Cursor::new(&data), false),
+        Err(cpio::Error::InvalidXattrs(_))
+    );
+}
+
+#[test]
+fn open_entry() {
+    let mut entries = vec![CpioEntryNew::new_directory(b"first")];
+    for (name, content) in [(b"a", b"odd".as_slice()), (b"b", b"content of b")] {
+        let mut entry = CpioEntryNew::new_file(name);
+        entry.content = content.to_vec();
+        entries.push(entry);
+    }
+    entries.push(CpioEntryNew::new_trailer());
+    let data = save(&entries);
+
+    let (entry, mut reader) = cpio::open_entry(Cursor::new(&data), b"b").unwrap().unwrap();
+    assert_eq!(entry.name, b"b");
+    assert!(entry.content.is_empty());
+
+    let mut content = vec![];
+    reader.read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"content of b");
+
+    assert!(cpio::open_entry(Cursor::new(&data), b"c")
+        .unwrap()
+        .is_none());
+
+    // Entries with xattrs can be skipped too.
+    let entries = cpio::load(Cursor::new(RAMDISK_XATTRS), false).unwrap();
+    let (entry, mut reader) = cpio::open_entry(Cursor::new(RAMDISK_XATTRS), &entries[2].name)
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.xattrs, entries[2].xattrs);
+
+    let mut content = vec![];
+    reader.read_to_end(&mut content).unwrap();
+    assert_eq!(content, entries[2].content);
+}
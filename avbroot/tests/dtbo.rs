@@ -0,0 +1,212 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    boot,
+    format::dtbo::{self, DtboEntry, DtboImage},
+    stream::{FromReader, ToWriter},
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn be_words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+/// Two entries with 5 and 3 bytes of data, packed right after the table.
+fn raw_image(version: u32) -> Vec<u8> {
+    let mut data = be_words(&[dtbo::DTBO_MAGIC, 104, 32, 32, 2, 32, 2048, version]);
+    data.extend(be_words(&[5, 96, 0x10, 0x1, 0xa, 0xb, 0xc, 0xd]));
+    data.extend(be_words(&[3, 101, 0x20, 0x2, 0xe, 0xf, 0x0, 0x1]));
+    data.extend(b"first");
+    data.extend(b"two");
+    data
+}
+
+#[test]
+fn round_trip_v0() {
+    let data = raw_image(0);
+    let image = DtboImage::from_reader(Cursor::new(&data)).unwrap();
+
+    assert_eq!(
+        image,
+        DtboImage {
+            version: 0,
+            page_size: 2048,
+            align_entries: false,
+            duplicate_entries: false,
+            entries: vec![
+                DtboEntry {
+                    id: 0x10,
+                    rev: 0x1,
+                    flags: None,
+                    custom: vec![0xa, 0xb, 0xc, 0xd],
+                    data: b"first".to_vec(),
+                },
+                DtboEntry {
+                    id: 0x20,
+                    rev: 0x2,
+                    flags: None,
+                    custom: vec![0xe, 0xf, 0x0, 0x1],
+                    data: b"two".to_vec(),
+                },
+            ],
+        },
+    );
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data);
+}
+
+#[test]
+fn round_trip_v1() {
+    let data = raw_image(1);
+    let image = DtboImage::from_reader(Cursor::new(&data)).unwrap();
+
+    assert_eq!(image.version, 1);
+    assert_eq!(image.entries[0].flags, Some(0xa));
+    assert_eq!(image.entries[0].custom, [0xb, 0xc, 0xd]);
+    assert_eq!(image.entries[1].flags, Some(0xe));
+    assert_eq!(image.entries[1].custom, [0xf, 0x0, 0x1]);
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data);
+
+    // Version 0 entries can't be written as version 1.
+    let mut image = DtboImage::from_reader(Cursor::new(raw_image(0))).unwrap();
+    image.version = 1;
+    assert_matches!(
+        image.to_writer(Cursor::new(Vec::new())),
+        Err(dtbo::Error::InvalidEntry(0, 1, _))
+    );
+}
+
+#[test]
+fn round_trip_aligned() {
+    let mut image = DtboImage::from_reader(Cursor::new(raw_image(0))).unwrap();
+    image.page_size = 64;
+    image.align_entries = true;
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    let data = writer.into_inner();
+
+    // Every entry starts at a page boundary and the image is padded to the page
+    // size.
+    assert_eq!(data.len(), 256);
+    assert_eq!(&data[4..8], 256u32.to_be_bytes());
+    assert_eq!(&data[36..40], 128u32.to_be_bytes());
+    assert_eq!(&data[68..72], 192u32.to_be_bytes());
+    assert_eq!(&data[128..133], b"first");
+    assert_eq!(&data[192..195], b"two");
+
+    // The alignment is detected when reading.
+    let new_image = DtboImage::from_reader(Cursor::new(&data)).unwrap();
+    assert_eq!(new_image, image);
+
+    let mut writer = Cursor::new(Vec::new());
+    new_image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data);
+}
+
+#[test]
+fn round_trip_dedup() {
+    // Entries 0 and 2 share the same blob, like mkdtboimg writes them.
+    let mut data = be_words(&[dtbo::DTBO_MAGIC, 136, 32, 32, 3, 32, 2048, 0]);
+    data.extend(be_words(&[5, 128, 0x10, 0x1, 0xa, 0xb, 0xc, 0xd]));
+    data.extend(be_words(&[3, 133, 0x20, 0x2, 0xe, 0xf, 0x0, 0x1]));
+    data.extend(be_words(&[5, 128, 0x30, 0x3, 0x0, 0x0, 0x0, 0x0]));
+    data.extend(b"first");
+    data.extend(b"two");
+
+    let image = DtboImage::from_reader(Cursor::new(&data)).unwrap();
+    assert!(!image.duplicate_entries);
+    assert_eq!(image.entries[2].data, b"first");
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), data);
+
+    // The same entries, but with a separate copy of the shared blob.
+    let mut separate = data.clone();
+    separate[4..8].copy_from_slice(&141u32.to_be_bytes());
+    separate[100..104].copy_from_slice(&136u32.to_be_bytes());
+    separate.extend(b"first");
+
+    let image = DtboImage::from_reader(Cursor::new(&separate)).unwrap();
+    assert!(image.duplicate_entries);
+
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    assert_eq!(writer.into_inner(), separate);
+}
+
+#[test]
+fn invalid_image() {
+    let mut data = raw_image(0);
+    data[0] ^= 0xff;
+    assert_matches!(
+        DtboImage::from_reader(Cursor::new(&data)),
+        Err(dtbo::Error::InvalidMagic(_))
+    );
+
+    let mut data = raw_image(0);
+    data[31] = 2;
+    assert_matches!(
+        DtboImage::from_reader(Cursor::new(&data)),
+        Err(dtbo::Error::UnsupportedVersion(2))
+    );
+
+    // Second entry extends past the total size.
+    let mut data = raw_image(0);
+    data[67] = 4;
+    assert_matches!(
+        DtboImage::from_reader(Cursor::new(&data)),
+        Err(dtbo::Error::EntryOutOfBounds(1))
+    );
+}
+
+#[test]
+fn patch_entries() {
+    let key = get_test_key();
+    let replacements = BTreeMap::from([(0, b"replaced".to_vec())]);
+
+    let mut writer = Cursor::new(Vec::new());
+    boot::patch_dtbo(Cursor::new(raw_image(1)), &mut writer, &key, &replacements).unwrap();
+
+    let image = DtboImage::from_reader(Cursor::new(writer.into_inner())).unwrap();
+    let mut expected = DtboImage::from_reader(Cursor::new(raw_image(1))).unwrap();
+    expected.entries[0].data = b"replaced".to_vec();
+    assert_eq!(image, expected);
+
+    let replacements = BTreeMap::from([(2, vec![])]);
+    assert_matches!(
+        boot::patch_dtbo(
+            Cursor::new(raw_image(1)),
+            Cursor::new(Vec::new()),
+            &key,
+            &replacements,
+        ),
+        Err(boot::Error::Dtbo(dtbo::Error::EntryIndexOutOfBounds(2, 2)))
+    );
+}
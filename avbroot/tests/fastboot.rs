@@ -0,0 +1,184 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+};
+
+use assert_matches::assert_matches;
+use avbroot::fastboot::{self, Fastboot, FastbootConnection};
+
+/// Fake device that replies with a fixed script of packets regardless of what
+/// the host sends.
+struct MockDevice {
+    from_host: Vec<u8>,
+    to_host: VecDeque<u8>,
+}
+
+impl MockDevice {
+    fn new(handshake: &[u8; 4], packets: &[&[u8]]) -> Self {
+        let mut to_host = handshake.to_vec();
+        for packet in packets {
+            to_host.extend((packet.len() as u64).to_be_bytes());
+            to_host.extend(*packet);
+        }
+
+        Self {
+            from_host: vec![],
+            to_host: to_host.into(),
+        }
+    }
+
+    /// Split the host's output into the handshake and packets.
+    fn host_packets(&self) -> (&[u8], Vec<&[u8]>) {
+        let (handshake, mut data) = self.from_host.split_at(4);
+        let mut packets = vec![];
+
+        while !data.is_empty() {
+            let size = u64::from_be_bytes(data[..8].try_into().unwrap()) as usize;
+            packets.push(&data[8..8 + size]);
+            data = &data[8 + size..];
+        }
+
+        (handshake, packets)
+    }
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.to_host.read(buf)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.from_host.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn commands() {
+    let device = MockDevice::new(
+        b"FB01",
+        &[
+            b"OKAYa",
+            b"INFOerasing",
+            b"OKAY",
+            b"DATA00000006",
+            b"OKAY",
+            b"OKAY",
+            b"FAILunknown command",
+        ],
+    );
+    let mut conn = FastbootConnection::connect(device).unwrap();
+
+    assert_eq!(conn.getvar("current-slot").unwrap(), "a");
+    conn.erase("avb_custom_key").unwrap();
+    assert_eq!(conn.take_messages(), ["erasing"]);
+    conn.download(&mut &b"foobar"[..], 6).unwrap();
+    conn.flash("boot_a").unwrap();
+    assert_matches!(
+        conn.set_active("c"),
+        Err(fastboot::Error::CommandFailed(c, m))
+            if c == "set_active:c" && m == "unknown command"
+    );
+
+    let device = conn.into_inner().into_inner();
+    let (handshake, packets) = device.host_packets();
+    assert_eq!(handshake, b"FB01");
+    assert_eq!(
+        packets,
+        [
+            &b"getvar:current-slot"[..],
+            b"erase:avb_custom_key",
+            b"download:00000006",
+            b"foobar",
+            b"flash:boot_a",
+            b"set_active:c",
+        ],
+    );
+}
+
+#[test]
+fn fetch() {
+    let device = MockDevice::new(
+        b"FB01",
+        &[
+            b"DATA00000006",
+            b"foo",
+            b"bar",
+            b"OKAY",
+            b"FAILnot supported",
+        ],
+    );
+    let mut conn = FastbootConnection::connect(device).unwrap();
+
+    assert_eq!(
+        conn.fetch("boot_a", 0, 6).unwrap(),
+        Some(b"foobar".to_vec()),
+    );
+    // Read back is optional.
+    assert_eq!(conn.fetch("boot_a", 0, 6).unwrap(), None);
+
+    let device = conn.into_inner().into_inner();
+    let (_, packets) = device.host_packets();
+    assert_eq!(packets[0], b"fetch:boot_a:0x00000000:0x00000006");
+}
+
+#[test]
+fn invalid_responses() {
+    assert_matches!(
+        FastbootConnection::connect(MockDevice::new(b"XX01", &[])).err(),
+        Some(fastboot::Error::InvalidHandshake(h)) if &h == b"XX01"
+    );
+
+    let device = MockDevice::new(b"FB01", &[b"DATA00000005", b"BOGUS"]);
+    let mut conn = FastbootConnection::connect(device).unwrap();
+    assert_matches!(
+        conn.download(&mut &b"foobar"[..], 6),
+        Err(fastboot::Error::DataSizeMismatch(6, 5))
+    );
+    assert_matches!(
+        conn.getvar("product"),
+        Err(fastboot::Error::InvalidResponse(r)) if r == "BOGUS"
+    );
+}
+
+#[test]
+fn download_streamed() {
+    let data = (0..3 * 1024 * 1024 / 2)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    let device = MockDevice::new(b"FB01", &[b"DATA00180000", b"OKAY", b"DATA00000006"]);
+    let mut conn = FastbootConnection::connect(device).unwrap();
+
+    conn.download(&mut data.as_slice(), data.len() as u64)
+        .unwrap();
+    // The reader must provide the full size.
+    assert_matches!(
+        conn.download(&mut &b"foo"[..], 6),
+        Err(fastboot::Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+    );
+
+    let device = conn.into_inner().into_inner();
+    let (_, packets) = device.host_packets();
+    assert_eq!(packets[0], b"download:00180000");
+    // Sent in 1 MiB chunks.
+    assert_eq!(packets[1].len(), 1024 * 1024);
+    assert_eq!([packets[1], packets[2]].concat(), data);
+}
+
+#[test]
+fn parse_int() {
+    assert_eq!(fastboot::parse_int("0x10000000"), Some(0x10000000));
+    assert_eq!(fastboot::parse_int("4096"), Some(4096));
+    assert_eq!(fastboot::parse_int("yes"), None);
+}
@@ -0,0 +1,68 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::Cursor;
+
+use avbroot::format::filesystem::{self, FsType};
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn ext4_size() {
+    let mut data = vec![0u8; 4096];
+    let sb = &mut data[1024..];
+    // 4096-byte blocks.
+    put_u32(sb, 4, 300);
+    put_u32(sb, 24, 2);
+    sb[56..58].copy_from_slice(&0xef53u16.to_le_bytes());
+
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(&data)).unwrap(),
+        Some((FsType::Ext4, 300 * 4096)),
+    );
+
+    // The high bits of the block count are only used with the 64bit feature.
+    put_u32(&mut data[1024..], 336, 1);
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(&data)).unwrap(),
+        Some((FsType::Ext4, 300 * 4096)),
+    );
+
+    put_u32(&mut data[1024..], 96, 0x80);
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(&data)).unwrap(),
+        Some((FsType::Ext4, ((1 << 32) + 300) * 4096)),
+    );
+}
+
+#[test]
+fn erofs_size() {
+    let mut data = vec![0u8; 4096];
+    let sb = &mut data[1024..];
+    put_u32(sb, 0, 0xe0f5e1e2);
+    sb[12] = 12;
+    put_u32(sb, 36, 1234);
+
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(&data)).unwrap(),
+        Some((FsType::Erofs, 1234 * 4096)),
+    );
+}
+
+#[test]
+fn unknown_filesystem() {
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(vec![0u8; 4096])).unwrap(),
+        None,
+    );
+
+    // Too small to contain a superblock.
+    assert_eq!(
+        filesystem::declared_size(Cursor::new(vec![0u8; 1500])).unwrap(),
+        None,
+    );
+}
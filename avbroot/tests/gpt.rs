@@ -0,0 +1,169 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    format::gpt::{self, Gpt, Guid},
+    stream::FromReader,
+};
+
+static GPT_IMG: &[u8] = include_bytes!("data/gpt.img");
+
+const SECTOR_SIZE: usize = 512;
+/// Offset of the primary GPT header.
+const PRIMARY_HEADER_OFFSET: usize = SECTOR_SIZE;
+/// Offset of the primary partition entries.
+const PRIMARY_ENTRIES_OFFSET: usize = 2 * SECTOR_SIZE;
+/// Offset of the backup GPT header.
+const BACKUP_HEADER_OFFSET: usize = 15 * SECTOR_SIZE;
+
+fn read_partition(data: &[u8], name: &str) -> Vec<u8> {
+    let gpt = Gpt::from_reader(Cursor::new(data)).unwrap();
+    let mut reader = gpt.open_partition(Cursor::new(data), name).unwrap();
+
+    let mut result = vec![];
+    reader.read_to_end(&mut result).unwrap();
+
+    result
+}
+
+#[test]
+fn parse_gpt() {
+    let gpt = Gpt::from_reader(Cursor::new(GPT_IMG)).unwrap();
+
+    assert_eq!(gpt.sector_size, 512);
+    assert_eq!(
+        gpt.disk_guid.to_string(),
+        "A1B2C3D4-E5F6-4718-9A0B-1C2D3E4F5A6B",
+    );
+    assert_eq!((gpt.first_usable_lba, gpt.last_usable_lba), (3, 13));
+    assert!(!gpt.from_backup);
+
+    let names = gpt
+        .partitions
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["boot_a", "vbmeta_a"]);
+
+    let boot = gpt.partition("boot_a").unwrap();
+    assert_eq!(gpt.partition_range(boot), (2048, 2048));
+
+    let type_guid = "4b7a15d6-322c-42ac-8110-88b7da0c5d77"
+        .parse::<Guid>()
+        .unwrap();
+    assert_eq!(gpt.partition_by_type(&type_guid).unwrap().name, "vbmeta_a");
+}
+
+#[test]
+fn read_gpt_partitions() {
+    assert_eq!(read_partition(GPT_IMG, "boot_a"), b"BOOT".repeat(512));
+    assert_eq!(read_partition(GPT_IMG, "vbmeta_a"), b"VBMT".repeat(256));
+
+    let gpt = Gpt::from_reader(Cursor::new(GPT_IMG)).unwrap();
+    assert_matches!(
+        gpt.open_partition(Cursor::new(GPT_IMG), "system_a"),
+        Err(gpt::Error::PartitionNotFound(n)) if n == "system_a"
+    );
+
+    // The reader is limited to the partition.
+    let mut reader = gpt.open_partition(Cursor::new(GPT_IMG), "boot_a").unwrap();
+    reader.seek(SeekFrom::End(-4)).unwrap();
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"BOOT");
+}
+
+#[test]
+fn fall_back_to_backup_gpt() {
+    for offset in [PRIMARY_HEADER_OFFSET + 24, PRIMARY_ENTRIES_OFFSET] {
+        let mut data = GPT_IMG.to_vec();
+        data[offset] ^= 0xff;
+
+        let gpt = Gpt::from_reader(Cursor::new(&data)).unwrap();
+        assert!(gpt.from_backup);
+        assert_eq!(gpt.partitions.len(), 2);
+        assert_eq!(read_partition(&data, "vbmeta_a"), b"VBMT".repeat(256));
+    }
+}
+
+#[test]
+fn reject_corrupt_gpt() {
+    let mut data = GPT_IMG.to_vec();
+    data[PRIMARY_HEADER_OFFSET + 24] ^= 0xff;
+    data[BACKUP_HEADER_OFFSET + 24] ^= 0xff;
+    assert_matches!(
+        Gpt::from_reader(Cursor::new(&data)),
+        Err(gpt::Error::InvalidChecksum("header", _, _))
+    );
+
+    assert_matches!(
+        Gpt::from_reader(Cursor::new(vec![0u8; 8192])),
+        Err(gpt::Error::InvalidSignature(_))
+    );
+
+    assert_matches!(
+        "not-a-guid".parse::<Guid>(),
+        Err(gpt::Error::InvalidGuid(_))
+    );
+}
+
+#[test]
+fn replace_gpt_partition() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut data = GPT_IMG.to_vec();
+    let new_boot = b"NEW!".repeat(512);
+
+    gpt::replace_partition(
+        Cursor::new(&mut data),
+        "boot_a",
+        new_boot.as_slice(),
+        new_boot.len() as u64,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(read_partition(&data, "boot_a"), new_boot);
+    assert_eq!(read_partition(&data, "vbmeta_a"), b"VBMT".repeat(256));
+
+    // Both copies of the GPT are untouched.
+    let original = Gpt::from_reader(Cursor::new(GPT_IMG)).unwrap();
+    assert_eq!(Gpt::from_reader(Cursor::new(&data)).unwrap(), original);
+    assert_eq!(
+        data[BACKUP_HEADER_OFFSET - SECTOR_SIZE..],
+        GPT_IMG[BACKUP_HEADER_OFFSET - SECTOR_SIZE..],
+    );
+
+    // Smaller data is padded with zeros.
+    gpt::replace_partition(
+        Cursor::new(&mut data),
+        "boot_a",
+        b"tiny".as_slice(),
+        4,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let mut expected = vec![0u8; 2048];
+    expected[..4].copy_from_slice(b"tiny");
+    assert_eq!(read_partition(&data, "boot_a"), expected);
+
+    assert_matches!(
+        gpt::replace_partition(
+            Cursor::new(&mut data),
+            "vbmeta_a",
+            [0u8; 1025].as_slice(),
+            1025,
+            &cancel_signal,
+        ),
+        Err(gpt::Error::PartitionTooSmall(_, 1025, 1024))
+    );
+    assert_eq!(read_partition(&data, "vbmeta_a"), b"VBMT".repeat(256));
+}
@@ -0,0 +1,258 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    format::lp::{self, Metadata, PartitionReader},
+    stream::FromReader,
+};
+
+static SUPER_IMG: &[u8] = include_bytes!("data/super.img");
+
+/// Offset of the primary metadata for slot 0.
+const PRIMARY_METADATA_OFFSET: usize = 12288;
+/// Offset of the backup metadata for slot 0.
+const BACKUP_METADATA_OFFSET: usize = PRIMARY_METADATA_OFFSET + 2 * 4096;
+
+/// Offsets of the primary and backup metadata for both slots.
+const METADATA_OFFSETS: [usize; 4] = [12288, 16384, 20480, 24576];
+
+/// Change the target sector of the first extent in every copy of the metadata
+/// and update the checksums accordingly.
+fn set_first_extent_target(data: &mut [u8], sector: u64) {
+    let read_u32 = |data: &[u8], offset: usize| {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+    };
+
+    for offset in METADATA_OFFSETS {
+        let header_size = read_u32(data, offset + 8);
+        let tables_size = read_u32(data, offset + 44);
+        let extents_offset = read_u32(data, offset + 92);
+        let tables_start = offset + header_size;
+
+        let target_offset = tables_start + extents_offset + 12;
+        data[target_offset..target_offset + 8].copy_from_slice(&sector.to_le_bytes());
+
+        let tables = &data[tables_start..tables_start + tables_size];
+        let digest = ring::digest::digest(&ring::digest::SHA256, tables);
+        data[offset + 48..offset + 80].copy_from_slice(digest.as_ref());
+
+        data[offset + 12..offset + 44].fill(0);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &data[offset..tables_start]);
+        data[offset + 12..offset + 44].copy_from_slice(digest.as_ref());
+    }
+}
+
+fn read_partition(data: &[u8], name: &str) -> Vec<u8> {
+    let metadata = Metadata::from_reader(Cursor::new(data)).unwrap();
+    let mut reader = PartitionReader::new(Cursor::new(data), &metadata, name).unwrap();
+
+    let mut result = vec![];
+    reader.read_to_end(&mut result).unwrap();
+    assert_eq!(result.len() as u64, reader.size());
+
+    result
+}
+
+#[test]
+fn parse_metadata() {
+    let metadata = Metadata::from_reader(Cursor::new(SUPER_IMG)).unwrap();
+
+    assert_eq!(metadata.geometry.metadata_max_size, 4096);
+    assert_eq!(metadata.geometry.metadata_slot_count, 2);
+    assert_eq!((metadata.major_version, metadata.minor_version), (10, 0));
+
+    let names = metadata
+        .partitions
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["system_a", "vendor_a"]);
+
+    for partition in &metadata.partitions {
+        assert_eq!(metadata.partition_size(partition).unwrap(), 8192);
+        assert_eq!(
+            metadata.groups[partition.group_index as usize].name,
+            "main_a"
+        );
+    }
+
+    assert_eq!(metadata.block_devices[0].partition_name, "super");
+}
+
+#[test]
+fn overflowing_partition_size() {
+    let mut metadata = Metadata::from_reader(Cursor::new(SUPER_IMG)).unwrap();
+    metadata.extents[0].num_sectors = u64::MAX;
+
+    assert_matches!(
+        metadata.partition_size(&metadata.partitions[0]),
+        Err(lp::Error::ExtentsOutOfBounds(n)) if n == "system_a"
+    );
+    assert!(metadata
+        .to_string()
+        .contains("- system_a: <invalid> size, group main_a, 2 extent(s)"));
+}
+
+#[test]
+fn read_logical_partitions() {
+    let system = read_partition(SUPER_IMG, "system_a");
+    assert_eq!(
+        system,
+        [b"SYS0".repeat(1024), b"SYS1".repeat(1024)].concat()
+    );
+
+    let vendor = read_partition(SUPER_IMG, "vendor_a");
+    assert_eq!(vendor, [b"VNDR".repeat(1024), vec![0u8; 4096]].concat());
+}
+
+#[test]
+fn seek_across_extents() {
+    let metadata = Metadata::from_reader(Cursor::new(SUPER_IMG)).unwrap();
+    let mut reader = PartitionReader::new(Cursor::new(SUPER_IMG), &metadata, "system_a").unwrap();
+
+    reader.seek(SeekFrom::Start(4094)).unwrap();
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"S0SY");
+
+    reader.seek(SeekFrom::End(-4)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"SYS1");
+
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn missing_partition() {
+    let metadata = Metadata::from_reader(Cursor::new(SUPER_IMG)).unwrap();
+
+    assert_matches!(
+        PartitionReader::new(Cursor::new(SUPER_IMG), &metadata, "product_a"),
+        Err(lp::Error::PartitionNotFound(_))
+    );
+}
+
+#[test]
+fn fall_back_to_backup_metadata() {
+    let mut data = SUPER_IMG.to_vec();
+    data[PRIMARY_METADATA_OFFSET + 200] ^= 0xff;
+
+    let system = read_partition(&data, "system_a");
+    assert_eq!(
+        system,
+        [b"SYS0".repeat(1024), b"SYS1".repeat(1024)].concat()
+    );
+
+    data[BACKUP_METADATA_OFFSET + 200] ^= 0xff;
+
+    assert_matches!(
+        Metadata::from_reader(Cursor::new(&data)),
+        Err(lp::Error::InvalidChecksum("tables", _, _))
+    );
+}
+
+#[test]
+fn replace_logical_partition() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut data = SUPER_IMG.to_vec();
+    let new_system = b"NEW!".repeat(2048);
+
+    lp::replace_partition(
+        Cursor::new(&mut data),
+        "system_a",
+        new_system.as_slice(),
+        new_system.len() as u64,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(read_partition(&data, "system_a"), new_system);
+    assert_eq!(
+        read_partition(&data, "vendor_a"),
+        read_partition(SUPER_IMG, "vendor_a"),
+    );
+
+    // Metadata is untouched.
+    assert_eq!(
+        Metadata::from_reader(Cursor::new(&data)).unwrap(),
+        Metadata::from_reader(Cursor::new(SUPER_IMG)).unwrap(),
+    );
+
+    // Smaller data is padded with zeros.
+    lp::replace_partition(
+        Cursor::new(&mut data),
+        "system_a",
+        b"tiny".as_slice(),
+        4,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let mut expected = vec![0u8; 8192];
+    expected[..4].copy_from_slice(b"tiny");
+    assert_eq!(read_partition(&data, "system_a"), expected);
+}
+
+#[test]
+fn replace_logical_partition_too_large() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut data = SUPER_IMG.to_vec();
+    let new_system = vec![0u8; 8193];
+
+    assert_matches!(
+        lp::replace_partition(
+            Cursor::new(&mut data),
+            "system_a",
+            new_system.as_slice(),
+            new_system.len() as u64,
+            &cancel_signal,
+        ),
+        Err(lp::Error::PartitionTooSmall(_, 8193, 8192))
+    );
+
+    // The zero extent at the end of vendor_a can't hold data.
+    let new_vendor = vec![0xffu8; 8192];
+
+    assert_matches!(
+        lp::replace_partition(
+            Cursor::new(&mut data),
+            "vendor_a",
+            new_vendor.as_slice(),
+            new_vendor.len() as u64,
+            &cancel_signal,
+        ),
+        Err(lp::Error::DataInZeroExtent(_, 4096))
+    );
+}
+
+#[test]
+fn replace_logical_partition_bad_extents() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let new_system = b"NEW!".repeat(2048);
+
+    // Inside the metadata region, and past the end of the block device.
+    for sector in [0, 84] {
+        let mut data = SUPER_IMG.to_vec();
+        set_first_extent_target(&mut data, sector);
+        Metadata::from_reader(Cursor::new(&data)).unwrap();
+
+        assert_matches!(
+            lp::replace_partition(
+                Cursor::new(&mut data),
+                "system_a",
+                new_system.as_slice(),
+                new_system.len() as u64,
+                &cancel_signal,
+            ),
+            Err(lp::Error::ExtentsOutOfBounds(n)) if n == "system_a"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+// Unsigned mode is process-wide, so these tests live in their own test binary
+// to avoid affecting other tests that run in parallel.
+
+use std::{
+    env,
+    io::{Cursor, Write},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    crypto,
+    format::{
+        avb,
+        ota::{self, SigningWriter},
+    },
+};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+fn get_avb_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn get_ota_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn get_test_cert() -> Certificate {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.crt",
+    ));
+
+    crypto::read_pem_cert(data.as_bytes()).unwrap()
+}
+
+fn build_zip() -> Vec<u8> {
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(Cursor::new(Vec::new())));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip_writer.start_file("message.txt", options).unwrap();
+    zip_writer.write_all(b"avbroot signature test\n").unwrap();
+
+    zip_writer
+        .finish()
+        .unwrap()
+        .finish(&get_ota_key(), &get_test_cert())
+        .unwrap()
+        .into_inner()
+}
+
+/// Get the offset of the archive comment. The comment ends with its own size.
+fn comment_offset(data: &[u8]) -> usize {
+    let size = u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    data.len() - usize::from(size)
+}
+
+#[test]
+fn unsigned_output_only_differs_in_signatures() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/vbmeta_root.img",
+    ));
+    let (mut header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+    let key = get_avb_key();
+
+    header.sign(&key).unwrap();
+    let signed_header = header.clone();
+    let signed_zip = build_zip();
+
+    // The flag alone is not enough.
+    env::remove_var(crypto::UNSAFE_NO_SIGN_ENV);
+    assert_matches!(
+        crypto::enable_unsafe_no_sign(),
+        Err(crypto::Error::UnsafeNoSignNotAllowed)
+    );
+    assert!(!crypto::is_unsafe_no_sign());
+
+    env::set_var(crypto::UNSAFE_NO_SIGN_ENV, "1");
+    crypto::enable_unsafe_no_sign().unwrap();
+    assert!(crypto::is_unsafe_no_sign());
+
+    header.sign(&key).unwrap();
+    assert_eq!(header.signature.len(), signed_header.signature.len());
+    assert!(header.signature.iter().all(|b| *b == 0));
+    assert_eq!(header.hash, signed_header.hash);
+
+    let mut expected_header = signed_header;
+    expected_header.signature.fill(0);
+    assert_eq!(header, expected_header);
+
+    // The zip data is unchanged, but the comment is marked as unsigned and the
+    // signature no longer verifies. The EOCD's comment size field right before
+    // the comment differs because the message is longer.
+    let unsigned_zip = build_zip();
+    let signed_offset = comment_offset(&signed_zip);
+    let unsigned_offset = comment_offset(&unsigned_zip);
+    assert_eq!(unsigned_offset, signed_offset);
+    assert_eq!(
+        unsigned_zip[..unsigned_offset - 2],
+        signed_zip[..signed_offset - 2],
+    );
+    assert!(unsigned_zip[unsigned_offset..].starts_with(b"UNSIGNED by avbroot"));
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    ota::verify_ota(Cursor::new(&signed_zip), &cancel_signal).unwrap();
+    assert!(ota::verify_ota(Cursor::new(&unsigned_zip), &cancel_signal).is_err());
+}
@@ -0,0 +1,1312 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Cursor, Read, Write},
+    path::{Component, Path},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    self,
+    crypto::{self, RsaPadding},
+    format::{
+        avb::{self, Descriptor},
+        ota::{
+            self, BlockRange, CompatibilityResult, ExternalSignature, OtaSignature,
+            OutputSizeInputs, OutputSizeOptions, SignatureAlgorithm, SigningWriter,
+            UpdateDescriptor,
+        },
+        payload::{self, CompressedPartitionWriter, PayloadHeader, PayloadWriter},
+    },
+    protobuf::{
+        android::care_map::{mod_CareMap::PartitionInfo, CareMap},
+        build::tools::releasetools::{DeviceState, OtaMetadata},
+        chromeos_update_engine::{
+            mod_InstallOperation::Type, DeltaArchiveManifest, Extent, InstallOperation,
+            PartitionInfo, PartitionUpdate,
+        },
+    },
+    util,
+};
+use pkcs8::DecodePrivateKey;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn get_test_cert() -> Certificate {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.crt",
+    ));
+
+    crypto::read_pem_cert(data.as_bytes()).unwrap()
+}
+
+/// Build a signed payload with no partitions.
+fn empty_payload() -> Vec<u8> {
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: 4096,
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+    let mut writer = PayloadWriter::new(Cursor::new(Vec::new()), header, get_test_key()).unwrap();
+    assert!(!writer.begin_next_operation().unwrap());
+    let (writer, _, _) = writer.finish().unwrap();
+
+    writer.into_inner()
+}
+
+/// Build a sideloadable OTA zip where the payload is first and there is no
+/// `payload_properties.txt`.
+fn sideloadable_ota(payload: &[u8]) -> Vec<u8> {
+    sideloadable_ota_with_entry(payload, "care_map.pb")
+}
+
+/// Like [`sideloadable_ota()`], but with a custom name for the extra entry.
+fn sideloadable_ota_with_entry(payload: &[u8], extra_name: &str) -> Vec<u8> {
+    sideloadable_ota_with_metadata(payload, extra_name, &OtaMetadata::default())
+}
+
+/// Like [`sideloadable_ota_with_entry()`], but with custom OTA metadata.
+fn sideloadable_ota_with_metadata(
+    payload: &[u8],
+    extra_name: &str,
+    metadata: &OtaMetadata,
+) -> Vec<u8> {
+    let metadata_pb = util::write_protobuf(metadata).unwrap();
+    sideloadable_ota_with_metadata_pb(payload, extra_name, &metadata_pb)
+}
+
+/// Like [`sideloadable_ota_with_metadata`], but with a raw `metadata.pb`.
+fn sideloadable_ota_with_metadata_pb(
+    payload: &[u8],
+    extra_name: &str,
+    metadata_pb: &[u8],
+) -> Vec<u8> {
+    let mut cert_pem = vec![];
+    crypto::write_pem_cert(&mut cert_pem, &get_test_cert()).unwrap();
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for (name, data) in [
+        (ota::PATH_PAYLOAD, payload),
+        (extra_name, b"care_map".as_slice()),
+        (ota::PATH_OTACERT, &cert_pem),
+        (ota::PATH_METADATA_PB, metadata_pb),
+    ] {
+        writer.start_file(name, options).unwrap();
+        writer.write_all(data).unwrap();
+    }
+
+    writer.finish().unwrap().into_inner()
+}
+
+/// Build an OTA zip that only contains the metadata, with the given
+/// precondition.
+fn metadata_only_ota(precondition: DeviceState) -> Vec<u8> {
+    let metadata = OtaMetadata {
+        precondition: Some(precondition),
+        ..Default::default()
+    };
+    let metadata_pb = util::write_protobuf(&metadata).unwrap();
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    writer.start_file(ota::PATH_METADATA_PB, options).unwrap();
+    writer.write_all(&metadata_pb).unwrap();
+
+    writer.finish().unwrap().into_inner()
+}
+
+/// Build an OTA zip with the streaming layout, with the given contents for
+/// `payload_properties.txt`.
+fn streaming_ota(payload: &[u8], properties: Option<&str>) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    if let Some(p) = properties {
+        writer.start_file(ota::PATH_PROPERTIES, options).unwrap();
+        writer.write_all(p.as_bytes()).unwrap();
+    }
+
+    writer.start_file(ota::PATH_PAYLOAD, options).unwrap();
+    writer.write_all(payload).unwrap();
+
+    writer.finish().unwrap().into_inner()
+}
+
+/// Build a small signed zip, optionally with a custom archive comment message.
+fn signed_zip(message: Option<&str>, padding: RsaPadding) -> Result<Vec<u8>, ota::Error> {
+    let mut signing_writer = SigningWriter::new(Cursor::new(Vec::new()));
+    signing_writer.set_padding(padding)?;
+
+    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip_writer.start_file("message.txt", options).unwrap();
+    zip_writer.write_all(b"avbroot signature test\n").unwrap();
+
+    let mut signing_writer = zip_writer.finish().unwrap();
+    if let Some(m) = message {
+        signing_writer.set_comment_message(m)?;
+    }
+
+    Ok(signing_writer
+        .finish(&get_test_key(), &get_test_cert())?
+        .into_inner())
+}
+
+/// Get the names of the zip entries in the order of their data offsets.
+fn entry_order(data: &[u8]) -> Vec<String> {
+    let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+    let mut entries = (0..zip.len())
+        .map(|i| {
+            let entry = zip.by_index(i).unwrap();
+            (entry.data_start(), entry.name().to_owned())
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    entries.into_iter().map(|(_, n)| n).collect()
+}
+
+#[test]
+fn signed_zip_reproducible() {
+    // Entries use a fixed timestamp and PKCS#1 v1.5 signatures are
+    // deterministic, so the output only depends on the inputs. PSS signatures
+    // have a random salt and are excluded.
+    let data = signed_zip(Some("avbroot"), RsaPadding::Pkcs1v15).unwrap();
+    assert_eq!(
+        signed_zip(Some("avbroot"), RsaPadding::Pkcs1v15).unwrap(),
+        data
+    );
+}
+
+#[test]
+fn convert_to_streaming() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let payload = empty_payload();
+    let input = sideloadable_ota(&payload);
+
+    let mut writer = Cursor::new(Vec::new());
+    let converted = ota::to_streaming(
+        Cursor::new(&input),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    assert!(converted);
+    let output = writer.into_inner();
+
+    assert_eq!(
+        entry_order(&output),
+        [
+            "META-INF/com/android/otacert",
+            "care_map.pb",
+            ota::PATH_PROPERTIES,
+            ota::PATH_PAYLOAD,
+            ota::PATH_METADATA,
+            ota::PATH_METADATA_PB,
+        ],
+    );
+
+    let cert = ota::verify_ota(Cursor::new(&output), &cancel_signal).unwrap();
+    assert_eq!(cert, get_test_cert());
+
+    let (metadata, _, header, properties) = ota::parse_zip_ota_info(Cursor::new(&output)).unwrap();
+    ota::verify_metadata(Cursor::new(&output), &metadata, header.blob_offset).unwrap();
+    assert_eq!(
+        properties,
+        payload::compute_properties(payload.as_slice(), &cancel_signal).unwrap(),
+    );
+
+    let mut zip = ZipArchive::new(Cursor::new(&output)).unwrap();
+    let mut new_payload = vec![];
+    zip.by_name(ota::PATH_PAYLOAD)
+        .unwrap()
+        .read_to_end(&mut new_payload)
+        .unwrap();
+    assert_eq!(new_payload, payload);
+}
+
+#[test]
+fn convert_to_streaming_unchanged() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let input = sideloadable_ota(&empty_payload());
+
+    let mut writer = Cursor::new(Vec::new());
+    ota::to_streaming(
+        Cursor::new(&input),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    let streaming = writer.into_inner();
+
+    // Already in the streaming layout, so it should be copied verbatim.
+    let mut writer = Cursor::new(Vec::new());
+    let converted = ota::to_streaming(
+        Cursor::new(&streaming),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    assert!(!converted);
+    assert_eq!(writer.into_inner(), streaming);
+}
+
+#[test]
+fn verify_zip_properties() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let payload = empty_payload();
+    let properties = payload::compute_properties(payload.as_slice(), &cancel_signal).unwrap();
+
+    let consistent = streaming_ota(&payload, Some(&properties));
+    ota::verify_zip_properties(Cursor::new(&consistent), &cancel_signal).unwrap();
+
+    // Properties that don't match the payload.
+    let file_size = format!("FILE_SIZE={}\n", payload.len());
+    let tampered_properties = properties.replace(&file_size, "FILE_SIZE=1\n");
+    assert_ne!(tampered_properties, properties);
+    let tampered = streaming_ota(&payload, Some(&tampered_properties));
+    assert_matches!(
+        ota::verify_zip_properties(Cursor::new(&tampered), &cancel_signal),
+        Err(ota::Error::Payload(payload::Error::InvalidProperty(k, _, Some(v))))
+            if k == "FILE_SIZE" && v == "1"
+    );
+
+    // Payload that doesn't match the properties. The last byte is part of the
+    // signature, so only the file hash changes.
+    let mut tampered_payload = payload.clone();
+    *tampered_payload.last_mut().unwrap() ^= 0xff;
+    let tampered = streaming_ota(&tampered_payload, Some(&properties));
+    assert_matches!(
+        ota::verify_zip_properties(Cursor::new(&tampered), &cancel_signal),
+        Err(ota::Error::Payload(payload::Error::InvalidProperty(k, _, _))) if k == "FILE_HASH"
+    );
+
+    let missing = streaming_ota(&payload, None);
+    assert_matches!(
+        ota::verify_zip_properties(Cursor::new(&missing), &cancel_signal),
+        Err(ota::Error::MissingZipEntry(ota::PATH_PROPERTIES))
+    );
+}
+
+#[test]
+fn sanitize_entry_names() {
+    for (name, expected) in [
+        ("payload.bin", "payload.bin"),
+        (
+            "META-INF/com/android/metadata",
+            "META-INF/com/android/metadata",
+        ),
+        (
+            "META-INF\\com\\android\\otacert",
+            "META-INF/com/android/otacert",
+        ),
+        ("/absolute/file.txt", "absolute/file.txt"),
+        ("\\\\server\\file.txt", "server/file.txt"),
+        ("directory/", "directory/"),
+        ("file..txt", "file..txt"),
+    ] {
+        assert_eq!(ota::sanitize_entry_name(name).unwrap(), expected);
+    }
+
+    for name in [
+        "",
+        "/",
+        "../evil.txt",
+        "..\\evil.txt",
+        "a/../../evil.txt",
+        "a\\..\\..\\evil.txt",
+        "/..",
+        "C:\\evil.txt",
+        "C:evil.txt",
+        "nul\0byte.txt",
+    ] {
+        assert_matches!(
+            ota::sanitize_entry_name(name),
+            Err(ota::Error::UnsafeEntryName(n)) if n == name
+        );
+    }
+}
+
+#[test]
+fn convert_to_streaming_sanitizes_names() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let payload = empty_payload();
+
+    for name in ["../evil.txt", "..\\evil.txt", "/tmp/../../evil.txt"] {
+        let input = sideloadable_ota_with_entry(&payload, name);
+
+        assert_matches!(
+            ota::to_streaming(
+                Cursor::new(&input),
+                io::sink(),
+                &get_test_key(),
+                &get_test_cert(),
+                &cancel_signal,
+            ),
+            Err(ota::Error::UnsafeEntryName(n)) if n == name
+        );
+    }
+
+    // Names that are unambiguous after normalization are rewritten.
+    let input = sideloadable_ota_with_entry(&payload, "\\odd\\care_map.pb");
+    let mut writer = Cursor::new(Vec::new());
+    ota::to_streaming(
+        Cursor::new(&input),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    let output = writer.into_inner();
+
+    let names = entry_order(&output);
+    assert!(names.contains(&"odd/care_map.pb".to_owned()));
+
+    // No entry can escape the directory it is extracted to.
+    for name in &names {
+        assert!(Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_))));
+    }
+
+    // Two entries that are the same after normalization are ambiguous.
+    let mut zip = ZipWriter::new_append(Cursor::new(input)).unwrap();
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("odd/care_map.pb", options).unwrap();
+    let input = zip.finish().unwrap().into_inner();
+
+    assert_matches!(
+        ota::to_streaming(
+            Cursor::new(&input),
+            io::sink(),
+            &get_test_key(),
+            &get_test_cert(),
+            &cancel_signal,
+        ),
+        Err(ota::Error::DuplicateEntryName(n)) if n == "odd/care_map.pb"
+    );
+}
+
+/// Get the raw data, CRC32, compressed size, size, and compression method of an
+/// entry as stored in the zip.
+fn raw_entry(data: &[u8], name: &str) -> (Vec<u8>, u32, u64, u64, CompressionMethod) {
+    let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+    let index = (0..zip.len())
+        .find(|i| zip.by_index_raw(*i).unwrap().name() == name)
+        .unwrap();
+    let mut entry = zip.by_index_raw(index).unwrap();
+
+    let mut buf = vec![];
+    entry.read_to_end(&mut buf).unwrap();
+
+    (
+        buf,
+        entry.crc32(),
+        entry.compressed_size(),
+        entry.size(),
+        entry.compression(),
+    )
+}
+
+#[test]
+fn convert_to_streaming_copies_compressed_entries() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let payload = empty_payload();
+    let firmware_name = "firmware/modem.img";
+
+    let mut zip = ZipWriter::new_append(Cursor::new(sideloadable_ota(&payload))).unwrap();
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(firmware_name, options).unwrap();
+    zip.write_all(&b"firmware blob".repeat(1000)).unwrap();
+    // Listed in the property files, so it must be stored in the output.
+    zip.start_file(ota::PATH_COMPATIBILITY, options).unwrap();
+    zip.write_all(b"compatibility").unwrap();
+    let input = zip.finish().unwrap().into_inner();
+
+    let convert = |input: &[u8]| {
+        let mut writer = Cursor::new(Vec::new());
+        ota::to_streaming(
+            Cursor::new(input),
+            &mut writer,
+            &get_test_key(),
+            &get_test_cert(),
+            &cancel_signal,
+        )
+        .map(|converted| (converted, writer.into_inner()))
+    };
+
+    let (converted, output) = convert(&input).unwrap();
+    assert!(converted);
+
+    // Passed through byte-for-byte without recompression.
+    let original = raw_entry(&input, firmware_name);
+    assert_eq!(original.4, CompressionMethod::Deflated);
+    assert!(raw_entry(&output, firmware_name) == original);
+    assert_eq!(entry_order(&output)[0], firmware_name);
+
+    let (data, _, _, _, compression) = raw_entry(&output, ota::PATH_COMPATIBILITY);
+    assert_eq!(compression, CompressionMethod::Stored);
+    assert_eq!(data, b"compatibility");
+
+    ota::verify_ota(Cursor::new(&output), &cancel_signal).unwrap();
+    let (metadata, _, header, _) = ota::parse_zip_ota_info(Cursor::new(&output)).unwrap();
+    ota::verify_metadata(Cursor::new(&output), &metadata, header.blob_offset).unwrap();
+
+    // The output already has the streaming layout.
+    let (converted, _) = convert(&output).unwrap();
+    assert!(!converted);
+
+    // Corrupted data is not passed through.
+    let data_start = ZipArchive::new(Cursor::new(&input))
+        .unwrap()
+        .by_name(firmware_name)
+        .unwrap()
+        .data_start();
+    let mut bad_input = input.clone();
+    bad_input[data_start as usize + original.2 as usize / 2] ^= 0xff;
+    assert!(convert(&bad_input).is_err());
+}
+
+/// Sink that can only be appended to, so anything that needs to seek back or
+/// re-read the output fails to compile.
+struct AppendOnly(Vec<u8>);
+
+impl Write for AppendOnly {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn signed_zip_single_pass() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    // The signature is computed while the data passes through, including the
+    // central directory, which the streaming zip writer emits after all entry
+    // sizes are known.
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(AppendOnly(vec![])));
+    for (name, method) in [
+        ("stored.txt", CompressionMethod::Stored),
+        ("deflated.txt", CompressionMethod::Deflated),
+    ] {
+        let options = FileOptions::default().compression_method(method);
+        zip_writer.start_file(name, options).unwrap();
+        zip_writer.write_all(&b"avbroot".repeat(1000)).unwrap();
+    }
+    let data = zip_writer
+        .finish()
+        .unwrap()
+        .finish(&get_test_key(), &get_test_cert())
+        .unwrap()
+        .0;
+
+    let cert = ota::verify_ota(Cursor::new(&data), &cancel_signal).unwrap();
+    assert_eq!(cert, get_test_cert());
+}
+
+#[test]
+fn verify_signature_algorithms() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    // RSA PKCS#1 v1.5, as produced by avbroot and signapk.
+    let pkcs1 = signed_zip(None, RsaPadding::Pkcs1v15).unwrap();
+
+    let signature = OtaSignature::from_zip(Cursor::new(&pkcs1)).unwrap();
+    assert_eq!(
+        signature.signature_algorithm(),
+        SignatureAlgorithm::RsaPkcs1v15
+    );
+    let cert = ota::verify_ota(Cursor::new(&pkcs1), &cancel_signal).unwrap();
+    assert_eq!(cert, get_test_cert());
+
+    // RSA PSS with SHA256 and a 32 byte salt, as produced by avbroot.
+    let pss = signed_zip(None, RsaPadding::Pss).unwrap();
+
+    let signature = OtaSignature::from_zip(Cursor::new(&pss)).unwrap();
+    assert_eq!(
+        signature.signature_algorithm(),
+        SignatureAlgorithm::RsaPss { salt_len: 32 },
+    );
+    let cert = ota::verify_ota(Cursor::new(&pss), &cancel_signal).unwrap();
+    assert_eq!(cert, get_test_cert());
+
+    // The same, but produced by a different implementation.
+    let mut pss = include_bytes!("data/ota_rsa_pss.zip").to_vec();
+
+    let signature = OtaSignature::from_zip(Cursor::new(&pss)).unwrap();
+    assert_eq!(
+        signature.signature_algorithm(),
+        SignatureAlgorithm::RsaPss { salt_len: 32 },
+    );
+    let cert = ota::verify_ota(Cursor::new(&pss), &cancel_signal).unwrap();
+    assert_eq!(cert, get_test_cert());
+
+    let offset = memchr::memmem::find(&pss, b"avbroot signature test").unwrap();
+    pss[offset] ^= 0xff;
+    assert_matches!(
+        ota::verify_ota(Cursor::new(&pss), &cancel_signal),
+        Err(ota::Error::Rsa(_))
+    );
+
+    // ECDSA with SHA256.
+    let ecdsa = include_bytes!("data/ota_ecdsa.zip");
+    assert_matches!(
+        ota::verify_ota(Cursor::new(ecdsa), &cancel_signal),
+        Err(ota::Error::UnsupportedSignatureAlgorithm(_))
+    );
+}
+
+#[test]
+fn generate_care_map() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let block_size = ota::CARE_MAP_BLOCK_SIZE as usize;
+
+    // Blocks 0-1 have data, 2-4 are a zero gap, 5 has data, and the trailing
+    // partial block has data.
+    let mut data = vec![0u8; 6 * block_size + 100];
+    data[0] = 1;
+    data[2 * block_size - 1] = 1;
+    data[5 * block_size + 10] = 1;
+    data[6 * block_size + 99] = 1;
+
+    let ranges = ota::generate_care_map(Cursor::new(&data), &cancel_signal).unwrap();
+    assert_eq!(
+        ranges,
+        [
+            BlockRange { start: 0, end: 2 },
+            BlockRange { start: 5, end: 7 },
+        ],
+    );
+    assert_eq!(ota::format_range_set(&ranges), "4,0,2,5,7");
+
+    let ranges =
+        ota::generate_care_map(Cursor::new(vec![0u8; 3 * block_size]), &cancel_signal).unwrap();
+    assert!(ranges.is_empty());
+    assert_eq!(ota::format_range_set(&ranges), "0");
+}
+
+#[test]
+fn update_care_map() {
+    let ranges_for = |name: &str| -> Result<_, ota::Error> {
+        Ok((name == "system").then(|| vec![BlockRange { start: 0, end: 10 }]))
+    };
+
+    let care_map = CareMap {
+        partitions: vec![
+            PartitionInfo {
+                name: "system".to_owned(),
+                ranges: "2,0,5".to_owned(),
+                id: "system_id".to_owned(),
+                fingerprint: "system_fingerprint".to_owned(),
+            },
+            PartitionInfo {
+                name: "vendor".to_owned(),
+                ranges: "2,0,3".to_owned(),
+                id: "vendor_id".to_owned(),
+                fingerprint: "vendor_fingerprint".to_owned(),
+            },
+        ],
+    };
+    let data = util::write_protobuf(&care_map).unwrap();
+
+    let none = BTreeSet::new();
+    let new_data = ota::update_care_map_pb(&data, &none, ranges_for).unwrap();
+    let new_care_map: CareMap = util::read_protobuf(&new_data).unwrap();
+    let mut expected = care_map;
+    expected.partitions[0].ranges = "2,0,10".to_owned();
+    assert_eq!(new_care_map, expected);
+
+    let txt = "system\n2,0,5\nvendor\n2,0,3\n";
+    let new_txt = ota::update_care_map_txt(txt, &none, ranges_for).unwrap();
+    assert_eq!(new_txt, "system\n2,0,10\nvendor\n2,0,3\n");
+
+    // Removed partitions are dropped.
+    let system = BTreeSet::from(["system".to_owned()]);
+    let new_data = ota::update_care_map_pb(&data, &system, ranges_for).unwrap();
+    let new_care_map: CareMap = util::read_protobuf(&new_data).unwrap();
+    expected.partitions.remove(0);
+    assert_eq!(new_care_map, expected);
+
+    let new_txt = ota::update_care_map_txt(txt, &system, ranges_for).unwrap();
+    assert_eq!(new_txt, "vendor\n2,0,3\n");
+
+    assert_matches!(
+        ota::update_care_map_txt("system\n2,0,5\nvendor\n", &none, ranges_for),
+        Err(ota::Error::CareMapMissingRanges(n)) if n == "vendor"
+    );
+}
+
+/// Encode a length-delimited field. Only small field numbers and sizes are
+/// supported.
+fn len_field(number: u8, data: &[u8]) -> Vec<u8> {
+    assert!(number < 16 && data.len() < 128);
+
+    let mut field = vec![(number << 3) | 2, data.len() as u8];
+    field.extend_from_slice(data);
+    field
+}
+
+#[test]
+fn update_care_map_unknown_fields() {
+    let ranges_for = |name: &str| -> Result<_, ota::Error> {
+        Ok((name == "system").then(|| vec![BlockRange { start: 0, end: 10 }]))
+    };
+    let info = |name: &str, ranges: &str, unknown: &[u8]| {
+        let info = PartitionInfo {
+            name: name.to_owned(),
+            ranges: ranges.to_owned(),
+            ..Default::default()
+        };
+
+        let mut info_raw = util::write_protobuf(&info).unwrap();
+        info_raw.extend_from_slice(unknown);
+        len_field(1, &info_raw)
+    };
+    let build = |infos: &[Vec<u8>]| {
+        // Unknown fields in the care map and each partition's info.
+        let mut data = infos.concat();
+        data.extend_from_slice(b"\x12\x03abc");
+        data
+    };
+
+    let none = BTreeSet::new();
+    let data = build(&[info("system", "2,0,5", b"\x28\x07")]);
+    let new_data = ota::update_care_map_pb(&data, &none, ranges_for).unwrap();
+    assert_eq!(new_data, build(&[info("system", "2,0,10", b"\x28\x07")]));
+
+    // The unknown fields stay with their partition when one is removed.
+    let data = build(&[
+        info("vendor", "2,0,3", b"\x28\x01"),
+        info("system", "2,0,5", b"\x28\x07"),
+    ]);
+    let vendor = BTreeSet::from(["vendor".to_owned()]);
+    let new_data = ota::update_care_map_pb(&data, &vendor, ranges_for).unwrap();
+    assert_eq!(new_data, build(&[info("system", "2,0,10", b"\x28\x07")]));
+}
+
+#[test]
+fn round_trip_unknown_metadata_fields() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let postcondition = DeviceState {
+        device: vec!["cheetah".to_owned()],
+        ..Default::default()
+    };
+
+    // Unknown fields in the metadata and the postcondition.
+    let unknown_metadata = b"\x98\x06\x01";
+    let unknown_postcondition = b"\xa2\x01\x03new";
+
+    let mut postcondition_raw = util::write_protobuf(&postcondition).unwrap();
+    postcondition_raw.extend_from_slice(unknown_postcondition);
+    let mut metadata_pb = len_field(6, &postcondition_raw);
+    metadata_pb.extend_from_slice(unknown_metadata);
+
+    let sideloadable =
+        sideloadable_ota_with_metadata_pb(&empty_payload(), "care_map.pb", &metadata_pb);
+
+    let mut writer = Cursor::new(Vec::new());
+    ota::to_streaming(
+        Cursor::new(&sideloadable),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    let streaming = writer.into_inner();
+
+    let mut zip = ZipArchive::new(Cursor::new(&streaming)).unwrap();
+    let mut new_metadata_pb = vec![];
+    zip.by_name(ota::PATH_METADATA_PB)
+        .unwrap()
+        .read_to_end(&mut new_metadata_pb)
+        .unwrap();
+
+    // The unknown fields are written after the known fields of each message.
+    let new_metadata: OtaMetadata = util::read_protobuf(&new_metadata_pb).unwrap();
+    assert_eq!(new_metadata.postcondition.as_ref(), Some(&postcondition));
+    assert!(!new_metadata.property_files.is_empty());
+
+    let mut expected = util::write_protobuf(&OtaMetadata {
+        postcondition: None,
+        ..new_metadata
+    })
+    .unwrap();
+    expected.extend(len_field(6, &postcondition_raw));
+    expected.extend_from_slice(unknown_metadata);
+    assert_eq!(new_metadata_pb, expected);
+
+    ota::verify_ota(Cursor::new(&streaming), &cancel_signal).unwrap();
+    let (metadata, _, header, _) = ota::parse_zip_ota_info(Cursor::new(&streaming)).unwrap();
+    ota::verify_metadata(Cursor::new(&streaming), &metadata, header.blob_offset).unwrap();
+}
+
+#[test]
+fn archive_comment() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    let metadata = OtaMetadata {
+        postcondition: Some(DeviceState {
+            build: vec!["google/device/device:14/AP1A/1:user/release-keys".to_owned()],
+            timestamp: 1_700_000_000,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let auto = ota::provenance_comment(&metadata);
+    assert_eq!(
+        auto,
+        format!(
+            "patched by avbroot v{} from build {} on 2023-11-14",
+            env!("CARGO_PKG_VERSION"),
+            "google/device/device:14/AP1A/1:user/release-keys",
+        ),
+    );
+    assert_eq!(
+        ota::provenance_comment(&OtaMetadata::default()),
+        format!("patched by avbroot v{}", env!("CARGO_PKG_VERSION")),
+    );
+
+    // Every message must keep the zip readable and the signature verifiable.
+    for (message, prefix) in [
+        (None, b"signed by avbroot\0".to_vec()),
+        (Some(""), vec![]),
+        (Some("custom label"), b"custom label\0".to_vec()),
+        (Some(auto.as_str()), format!("{auto}\0").into_bytes()),
+    ] {
+        let data = signed_zip(message, RsaPadding::Pkcs1v15).unwrap();
+
+        let zip = ZipArchive::new(Cursor::new(&data)).unwrap();
+        assert!(zip.comment().starts_with(&prefix), "{message:?}");
+        assert_eq!(
+            ota::is_avbroot_comment(zip.comment()),
+            !matches!(message, Some("" | "custom label")),
+            "{message:?}",
+        );
+        if prefix.is_empty() {
+            // The CMS signature begins immediately.
+            assert_eq!(zip.comment()[0], 0x30);
+        }
+
+        let cert = ota::verify_ota(Cursor::new(&data), &cancel_signal).unwrap();
+        assert_eq!(cert, get_test_cert());
+    }
+
+    // Messages that would break the signature footer or recovery's EOCD search
+    // are rejected.
+    assert_matches!(
+        signed_zip(Some("a\0b"), RsaPadding::Pkcs1v15),
+        Err(ota::Error::CommentMessageHasNul)
+    );
+    assert_matches!(
+        signed_zip(Some("PK\x05\x06"), RsaPadding::Pkcs1v15),
+        Err(ota::Error::CommentMessageHasEocdMagic)
+    );
+    assert_matches!(
+        signed_zip(
+            Some(&"a".repeat(ota::COMMENT_MESSAGE_MAX_SIZE + 1)),
+            RsaPadding::Pkcs1v15,
+        ),
+        Err(ota::Error::CommentMessageTooLong(n)) if n == ota::COMMENT_MESSAGE_MAX_SIZE + 1
+    );
+    signed_zip(
+        Some(&"a".repeat(ota::COMMENT_MESSAGE_MAX_SIZE)),
+        RsaPadding::Pkcs1v15,
+    )
+    .unwrap();
+}
+
+#[test]
+fn check_compatibility() {
+    const SOURCE: &str = "google/cheetah/cheetah:14/UQ1A.240105.004/11206848:user/release-keys";
+
+    let full_ota = metadata_only_ota(DeviceState {
+        device: vec!["cheetah".to_owned()],
+        ..Default::default()
+    });
+    let incremental_ota = metadata_only_ota(DeviceState {
+        device: vec!["cheetah".to_owned()],
+        build: vec![SOURCE.to_owned()],
+        ..Default::default()
+    });
+
+    let result = ota::check_compatibility(Cursor::new(&full_ota), "cheetah", None).unwrap();
+    assert!(result.is_compatible());
+
+    let result = ota::check_compatibility(Cursor::new(&full_ota), "panther", None).unwrap();
+    assert_eq!(
+        result,
+        CompatibilityResult::UnsupportedDevice {
+            device: "panther".to_owned(),
+            supported: vec!["cheetah".to_owned()],
+        }
+    );
+    assert_eq!(
+        result.to_string(),
+        "OTA is for cheetah, but the device is \"panther\"",
+    );
+
+    let result =
+        ota::check_compatibility(Cursor::new(&incremental_ota), "cheetah", Some(SOURCE)).unwrap();
+    assert!(result.is_compatible());
+
+    for current in [
+        None,
+        Some("google/cheetah/cheetah:14/AP1A.240305.019/11301510:user"),
+    ] {
+        let result =
+            ota::check_compatibility(Cursor::new(&incremental_ota), "cheetah", current).unwrap();
+        assert_eq!(
+            result,
+            CompatibilityResult::WrongSourceBuild {
+                current: current.map(|c| c.to_owned()),
+                required: vec![SOURCE.to_owned()],
+            }
+        );
+        assert!(result.to_string().contains(SOURCE));
+    }
+
+    // The device is checked before the source build.
+    let result =
+        ota::check_compatibility(Cursor::new(&incremental_ota), "panther", Some(SOURCE)).unwrap();
+    assert_matches!(result, CompatibilityResult::UnsupportedDevice { .. });
+}
+
+#[test]
+fn export_update_descriptor() {
+    const FINGERPRINT: &str =
+        "google/cheetah/cheetah:14/UQ1A.240105.004/11206848:user/release-keys";
+    const URL: &str = "https://example.com/ota/cheetah.zip";
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let metadata = OtaMetadata {
+        postcondition: Some(DeviceState {
+            device: vec!["cheetah".to_owned()],
+            build: vec![FINGERPRINT.to_owned()],
+            timestamp: 1704412800,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let sideloadable = sideloadable_ota_with_metadata(&empty_payload(), "care_map.pb", &metadata);
+
+    let mut writer = Cursor::new(Vec::new());
+    ota::to_streaming(
+        Cursor::new(&sideloadable),
+        &mut writer,
+        &get_test_key(),
+        &get_test_cert(),
+        &cancel_signal,
+    )
+    .unwrap();
+    let streaming = writer.into_inner();
+
+    let descriptor =
+        UpdateDescriptor::from_zip(Cursor::new(&streaming), URL, Some("beta"), &cancel_signal)
+            .unwrap();
+    let (new_metadata, _, _, _) = ota::parse_zip_ota_info(Cursor::new(&streaming)).unwrap();
+    let property_files = new_metadata.property_files[ota::PF_STREAMING_NAME].trim_end();
+    let sha256 = ring::digest::digest(&ring::digest::SHA256, &streaming);
+
+    assert_eq!(
+        descriptor,
+        UpdateDescriptor {
+            channel: Some("beta".to_owned()),
+            devices: vec!["cheetah".to_owned()],
+            fingerprint: FINGERPRINT.to_owned(),
+            post_timestamp: 1704412800,
+            cert_not_after: crypto::cert_not_after(&get_test_cert()),
+            size: streaming.len() as u64,
+            sha256: sha256.as_ref().try_into().unwrap(),
+            url: URL.to_owned(),
+            property_files: property_files.to_owned(),
+        },
+    );
+    assert!(!property_files.is_empty());
+
+    let json = descriptor.to_json().unwrap();
+    assert!(json.ends_with("}\n"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&json).unwrap(),
+        json!({
+            "schema_version": ota::UPDATE_DESCRIPTOR_VERSION,
+            "channel": "beta",
+            "devices": ["cheetah"],
+            "fingerprint": FINGERPRINT,
+            "post_timestamp": 1704412800,
+            "cert_not_after": descriptor.cert_not_after,
+            "size": streaming.len(),
+            "sha256": hex::encode(sha256),
+            "url": URL,
+            "property_files": property_files,
+        }),
+    );
+
+    // The channel is omitted when there is none.
+    let mut no_channel = descriptor.clone();
+    no_channel.channel = None;
+    let json = serde_json::from_str::<Value>(&no_channel.to_json().unwrap()).unwrap();
+    assert!(json.get("channel").is_none());
+
+    // An OTA without property files cannot be streamed.
+    assert_matches!(
+        UpdateDescriptor::from_zip(Cursor::new(&sideloadable), URL, None, &cancel_signal),
+        Err(ota::Error::MissingMetadataField(ota::PF_STREAMING_NAME))
+    );
+
+    // Moving the entries makes the property files stale.
+    let mut zip = ZipArchive::new(Cursor::new(&streaming)).unwrap();
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    writer.start_file("padding.txt", options).unwrap();
+    writer.write_all(b"padding").unwrap();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).unwrap();
+        writer.start_file(entry.name(), options).unwrap();
+        io::copy(&mut entry, &mut writer).unwrap();
+    }
+    let moved = writer.finish().unwrap().into_inner();
+
+    assert_matches!(
+        UpdateDescriptor::from_zip(Cursor::new(&moved), URL, None, &cancel_signal),
+        Err(ota::Error::MismatchedPropertyFiles(_, _))
+    );
+}
+
+#[test]
+fn estimate_output_size() {
+    const BLOCK_SIZE: u32 = 4096;
+
+    // Large enough for the compressed size to be estimated from samples. Every
+    // 64 KiB chunk is half noise and half zeros so that the samples are
+    // representative of the whole image.
+    let mut image = vec![0u8; 9 * 1024 * 1024];
+    let mut state = 0x2545f4914f6cdd1du64;
+    for chunk in image.chunks_mut(64 * 1024) {
+        for b in &mut chunk[..32 * 1024] {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *b = state as u8;
+        }
+    }
+
+    let partition = |name: &str, data_length| PartitionUpdate {
+        partition_name: name.to_owned(),
+        operations: vec![InstallOperation {
+            type_pb: Type::REPLACE,
+            data_length: Some(data_length),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions: vec![partition("boot", 4096), partition("system", 65536)],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    // Patch the payload for real.
+    let mut new_header = header.clone();
+    let mut writer = CompressedPartitionWriter::new(Vec::new(), BLOCK_SIZE).unwrap();
+    writer.write_all(&image).unwrap();
+    let compressed = writer
+        .finish(&mut new_header.manifest.partitions[0])
+        .unwrap();
+
+    let mut writer =
+        PayloadWriter::new(Cursor::new(Vec::new()), new_header, get_test_key()).unwrap();
+    while writer.begin_next_operation().unwrap() {
+        if writer.partition_index() == Some(0) {
+            writer.write_all(&compressed).unwrap();
+        } else {
+            let length = writer.operation().unwrap().data_length.unwrap();
+            writer.write_all(&vec![0xaa; length as usize]).unwrap();
+        }
+    }
+    let payload = writer.finish().unwrap().0.into_inner();
+
+    let mut cert_pem = vec![];
+    crypto::write_pem_cert(&mut cert_pem, &get_test_cert()).unwrap();
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(Cursor::new(Vec::new())));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, data) in [
+        (ota::PATH_OTACERT, cert_pem.as_slice()),
+        (ota::PATH_CARE_MAP_PB, b"care_map".as_slice()),
+        (ota::PATH_PAYLOAD, payload.as_slice()),
+    ] {
+        zip_writer.start_file(name, options).unwrap();
+        zip_writer.write_all(data).unwrap();
+    }
+    let actual_size = zip_writer
+        .finish()
+        .unwrap()
+        .finish(&get_test_key(), &get_test_cert())
+        .unwrap()
+        .into_inner()
+        .len() as u64;
+
+    let key = get_test_key();
+    let cert = get_test_cert();
+    let estimate = ota::estimate_output_size(
+        &OutputSizeInputs {
+            header: &header,
+            images: &[("boot", image.as_slice())],
+            entries: &[
+                (ota::PATH_OTACERT, cert_pem.len() as u64),
+                (ota::PATH_CARE_MAP_PB, 8),
+            ],
+        },
+        &OutputSizeOptions {
+            key_payload: &key,
+            key_ota: &key,
+            cert_ota: &cert,
+            padding: RsaPadding::Pkcs1v15,
+            comment_message: None,
+        },
+    )
+    .unwrap();
+
+    // The noise is incompressible, so the estimate should be close.
+    assert!(
+        estimate.abs_diff(actual_size) < actual_size / 50,
+        "Estimate {estimate} is too far from actual size {actual_size}",
+    );
+
+    assert_matches!(
+        ota::estimate_output_size(
+            &OutputSizeInputs {
+                header: &header,
+                images: &[("vendor_boot", image.as_slice())],
+                entries: &[],
+            },
+            &OutputSizeOptions {
+                key_payload: &key,
+                key_ota: &key,
+                cert_ota: &cert,
+                padding: RsaPadding::Pkcs1v15,
+                comment_message: None,
+            },
+        ),
+        Err(ota::Error::PartitionNotFound(p)) if p == "vendor_boot"
+    );
+}
+
+/// Build a signed payload with one REPLACE operation per block of each image.
+/// The images are padded to a multiple of the block size.
+fn payload_with_images(images: &[(&str, &[u8])]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 4096;
+
+    let mut blob = vec![];
+    let mut partitions = vec![];
+
+    for (name, data) in images {
+        let mut data = data.to_vec();
+        data.resize(data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+
+        let operations = data
+            .chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, block)| {
+                let digest = ring::digest::digest(&ring::digest::SHA256, block);
+                let op = InstallOperation {
+                    type_pb: Type::REPLACE,
+                    data_offset: Some(blob.len() as u64),
+                    data_length: Some(block.len() as u64),
+                    dst_extents: vec![Extent {
+                        start_block: Some(i as u64),
+                        num_blocks: Some(1),
+                    }],
+                    data_sha256_hash: Some(digest.as_ref().to_vec()),
+                    ..Default::default()
+                };
+                blob.extend_from_slice(block);
+                op
+            })
+            .collect();
+
+        partitions.push(PartitionUpdate {
+            partition_name: (*name).to_owned(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(data.len() as u64),
+                hash: None,
+            }),
+            operations,
+            ..Default::default()
+        });
+    }
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE as u32,
+            partitions,
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    let mut writer = PayloadWriter::new(Cursor::new(Vec::new()), header, get_test_key()).unwrap();
+    while writer.begin_next_operation().unwrap() {
+        let op = writer.operation().unwrap();
+        let offset = op.data_offset.unwrap() as usize;
+        let length = op.data_length.unwrap() as usize;
+        writer.write_all(&blob[offset..offset + length]).unwrap();
+    }
+
+    writer.finish().unwrap().0.into_inner()
+}
+
+#[test]
+fn descriptor_for_partition() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let appended = include_bytes!("data/vbmeta_appended.img");
+    let root = include_bytes!("data/vbmeta_root.img");
+
+    let payload = payload_with_images(&[
+        ("vbmeta_appended", appended.as_slice()),
+        ("vbmeta", root.as_slice()),
+    ]);
+    let data = sideloadable_ota(&payload);
+    let find = |name| ota::descriptor_for_partition(Cursor::new(&data), name, &cancel_signal);
+
+    // Descriptor from the partition's own footer.
+    let (header, _, _) = avb::load_image(Cursor::new(appended)).unwrap();
+    let expected = header
+        .descriptors
+        .iter()
+        .find(|d| d.partition_name() == Some("vbmeta_appended"))
+        .unwrap();
+    assert_eq!(find("vbmeta_appended").unwrap().as_ref(), Some(expected));
+
+    // Descriptors from the vbmeta partition.
+    assert_matches!(
+        find("hashed_partition").unwrap(),
+        Some(Descriptor::Hash(d)) if d.partition_name == "hashed_partition"
+    );
+    assert_matches!(
+        find("chained_partition").unwrap(),
+        Some(Descriptor::ChainPartition(d)) if d.partition_name == "chained_partition"
+    );
+
+    assert_eq!(find("system").unwrap(), None);
+
+    // A footer with a huge vbmeta size is rejected before anything is read.
+    let mut bad_appended = appended.to_vec();
+    let size_offset = bad_appended.len() - avb::Footer::SIZE + 28;
+    bad_appended[size_offset..size_offset + 8]
+        .copy_from_slice(&(avb::VBMETA_MAX_SIZE + 1).to_be_bytes());
+
+    let payload = payload_with_images(&[("vbmeta_appended", bad_appended.as_slice())]);
+    let data = sideloadable_ota(&payload);
+    let find = |name| ota::descriptor_for_partition(Cursor::new(&data), name, &cancel_signal);
+    assert_matches!(
+        find("vbmeta_appended"),
+        Err(ota::Error::VbmetaTooLarge(_, s)) if s == avb::VBMETA_MAX_SIZE + 1
+    );
+}
+
+#[test]
+fn external_signing() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let unsigned = streaming_ota(b"payload", None);
+    let signed = signed_zip(None, RsaPadding::Pkcs1v15).unwrap();
+
+    // This is the only step that needs the key.
+    let sign = |digest: &[u8]| {
+        let scheme = Pkcs1v15Sign::new::<Sha256>();
+        ExternalSignature {
+            signature: crypto::rsa_sign(&get_test_key(), scheme, digest).unwrap(),
+            padding: RsaPadding::Pkcs1v15,
+            cert: get_test_cert(),
+        }
+    };
+
+    for data in [&unsigned, &signed] {
+        let request = ota::prepare_signing(Cursor::new(data), &cancel_signal).unwrap();
+        let signature = sign(&request.digest);
+
+        let mut output = vec![];
+        ota::finalize_signing(Cursor::new(data), &mut output, &signature, &cancel_signal).unwrap();
+
+        let cert = ota::verify_ota(Cursor::new(&output), &cancel_signal).unwrap();
+        assert_eq!(cert, get_test_cert());
+        assert_eq!(
+            OtaSignature::from_zip(Cursor::new(&output))
+                .unwrap()
+                .hashed_size(),
+            request.hashed_size,
+        );
+
+        // Re-signing with the same key reproduces the original file.
+        if data == &signed {
+            assert!(output == signed);
+        }
+    }
+
+    // Signatures for any other data are rejected before anything is written.
+    let request = ota::prepare_signing(Cursor::new(&signed), &cancel_signal).unwrap();
+    let mut output = vec![];
+    assert_matches!(
+        ota::finalize_signing(
+            Cursor::new(&unsigned),
+            &mut output,
+            &sign(&request.digest),
+            &cancel_signal,
+        ),
+        Err(ota::Error::Rsa(_))
+    );
+    assert!(output.is_empty());
+}
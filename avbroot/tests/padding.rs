@@ -0,0 +1,36 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::Cursor,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use avbroot::format::padding;
+
+#[test]
+fn data_end() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    // The trailing zeros span multiple read buffers.
+    let mut data = vec![0u8; 100000];
+    data[0] = 1;
+    data[12345] = 1;
+    assert_eq!(
+        padding::data_end(Cursor::new(&data), &cancel_signal).unwrap(),
+        12346,
+    );
+
+    data[99999] = 1;
+    assert_eq!(
+        padding::data_end(Cursor::new(&data), &cancel_signal).unwrap(),
+        100000,
+    );
+
+    assert_eq!(
+        padding::data_end(Cursor::new(vec![0u8; 5000]), &cancel_signal).unwrap(),
+        0,
+    );
+}
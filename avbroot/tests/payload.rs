@@ -0,0 +1,1012 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, Read, Write},
+    num::NonZeroUsize,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    self, crypto,
+    format::payload::{
+        self, CompressedPartitionWriter, ExtentDesc, ExtractOptions, OperationDesc, PayloadHeader,
+        PayloadKind, PayloadWriter, PostinstallConfig, RawPayloadHeader,
+    },
+    protobuf::chromeos_update_engine::{
+        mod_InstallOperation::Type, DeltaArchiveManifest, DynamicPartitionGroup,
+        DynamicPartitionMetadata, Extent, InstallOperation, PartitionInfo, PartitionUpdate,
+    },
+    stream::{FromReader, SharedCursor, WriteSeek},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pkcs8::DecodePrivateKey;
+use rsa::RsaPrivateKey;
+use x509_cert::Certificate;
+
+const BLOCK_SIZE: u32 = 4;
+
+fn get_test_key() -> RsaPrivateKey {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.key",
+    ));
+    let passphrase = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.passphrase",
+    ));
+
+    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+}
+
+fn get_test_cert() -> Certificate {
+    let data = include_str!(concat!(
+        env!("CARGO_WORKSPACE_DIR"),
+        "/e2e/keys/TEST_KEY_DO_NOT_USE_ota.crt",
+    ));
+
+    crypto::read_pem_cert(data.as_bytes()).unwrap()
+}
+
+fn extent(start_block: u64, num_blocks: u64) -> Extent {
+    Extent {
+        start_block: Some(start_block),
+        num_blocks: Some(num_blocks),
+    }
+}
+
+fn replace_op(blob: &mut Vec<u8>, data: &[u8], dst_extents: Vec<Extent>) -> InstallOperation {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+
+    let op = InstallOperation {
+        type_pb: Type::REPLACE,
+        data_offset: Some(blob.len() as u64),
+        data_length: Some(data.len() as u64),
+        dst_extents,
+        data_sha256_hash: Some(digest.as_ref().to_vec()),
+        ..Default::default()
+    };
+
+    blob.extend_from_slice(data);
+
+    op
+}
+
+fn zero_op(dst_extents: Vec<Extent>) -> InstallOperation {
+    InstallOperation {
+        type_pb: Type::ZERO,
+        dst_extents,
+        ..Default::default()
+    }
+}
+
+/// Build a payload where the operations and their extents are not in order.
+fn shuffled_payload() -> (PayloadHeader, Vec<u8>) {
+    let mut blob = vec![];
+    let operations = vec![
+        replace_op(&mut blob, b"CCCCAAAA", vec![extent(2, 1), extent(0, 1)]),
+        zero_op(vec![extent(3, 1)]),
+        replace_op(
+            &mut blob,
+            b"EEEEDDDDBBBB",
+            vec![extent(5, 1), extent(4, 1), extent(1, 1)],
+        ),
+    ];
+
+    let partition = PartitionUpdate {
+        partition_name: "test".to_owned(),
+        new_partition_info: Some(PartitionInfo {
+            size: Some(6 * u64::from(BLOCK_SIZE)),
+            hash: None,
+        }),
+        operations,
+        ..Default::default()
+    };
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions: vec![partition],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    (header, blob)
+}
+
+#[test]
+fn extract_shuffled_extents() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (header, blob) = shuffled_payload();
+
+    let cursor = payload::extract_image_to_memory(
+        || Ok(Box::new(Cursor::new(blob.clone()))),
+        &header,
+        "test",
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let mut data = vec![];
+    cursor.clone_rewind().read_to_end(&mut data).unwrap();
+
+    assert_eq!(data, b"AAAABBBBCCCC\0\0\0\0DDDDEEEE");
+}
+
+#[test]
+fn extract_corrupted_operation() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (header, mut blob) = shuffled_payload();
+    // Corrupt the data of the last operation.
+    blob[8] = b'X';
+
+    let result = payload::extract_image_to_memory(
+        || Ok(Box::new(Cursor::new(blob.clone()))),
+        &header,
+        "test",
+        &cancel_signal,
+    );
+    assert_matches!(
+        result,
+        Err(payload::Error::OperationHashMismatch { partition, op_index: 2, .. })
+            if partition == "test"
+    );
+
+    let cursor = SharedCursor::default();
+    let open_output = || Ok(Box::new(cursor.clone()) as Box<dyn WriteSeek>);
+
+    let result = payload::extract_images(
+        || Ok(Box::new(Cursor::new(blob.clone()))),
+        |_| open_output(),
+        &header,
+        ["test"],
+        true,
+        &cancel_signal,
+    );
+    assert_matches!(
+        result,
+        Err(payload::Error::OperationHashMismatch { op_index: 2, .. })
+    );
+
+    // The corruption goes unnoticed if verification is skipped.
+    payload::extract_images(
+        || Ok(Box::new(Cursor::new(blob.clone()))),
+        |_| open_output(),
+        &header,
+        ["test"],
+        false,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let mut data = vec![];
+    cursor.clone_rewind().read_to_end(&mut data).unwrap();
+
+    assert_eq!(data, b"AAAABBBBCCCC\0\0\0\0DDDDXEEE");
+}
+
+/// Build a payload with several partitions, each with one operation per block
+/// written in reverse order.
+fn multi_partition_payload() -> (PayloadHeader, Vec<u8>) {
+    let mut blob = vec![];
+    let mut partitions = vec![];
+
+    for (name, num_blocks) in [("a", 64), ("b", 1), ("c", 32), ("empty", 0)] {
+        let operations = (0..num_blocks)
+            .rev()
+            .map(|i| {
+                let data = format!("{name}{i:03}");
+                replace_op(&mut blob, data.as_bytes(), vec![extent(i, 1)])
+            })
+            .collect();
+
+        partitions.push(PartitionUpdate {
+            partition_name: name.to_owned(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(num_blocks * u64::from(BLOCK_SIZE)),
+                hash: None,
+            }),
+            operations,
+            ..Default::default()
+        });
+    }
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions,
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+        unknown_fields: Default::default(),
+        partition_unknown_fields: Default::default(),
+    };
+
+    (header, blob)
+}
+
+/// Extract every partition and return the data and the order that the
+/// partitions finished in.
+fn extract_all(
+    header: &PayloadHeader,
+    blob: &[u8],
+    options: &ExtractOptions,
+) -> (BTreeMap<String, Vec<u8>>, Vec<String>) {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let names = ["a", "b", "c", "empty"];
+    let cursors = names
+        .iter()
+        .map(|&n| (n, SharedCursor::default()))
+        .collect::<BTreeMap<_, _>>();
+    let finished = Mutex::new(vec![]);
+
+    payload::extract_images_with_options(
+        || Ok(Box::new(Cursor::new(blob.to_vec()))),
+        |name| Ok(Box::new(cursors[name].clone())),
+        header,
+        names,
+        options,
+        |name| finished.lock().unwrap().push(name.to_owned()),
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let outputs = cursors
+        .into_iter()
+        .map(|(name, cursor)| {
+            let mut data = vec![];
+            cursor.clone_rewind().read_to_end(&mut data).unwrap();
+            (name.to_owned(), data)
+        })
+        .collect();
+
+    (outputs, finished.into_inner().unwrap())
+}
+
+#[test]
+fn extract_parallel_matches_serial() {
+    let (header, blob) = multi_partition_payload();
+
+    let serial_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let serial_options = ExtractOptions {
+        max_readers: NonZeroUsize::new(1),
+        ..Default::default()
+    };
+    let (serial, _) = serial_pool.install(|| extract_all(&header, &blob, &serial_options));
+
+    assert_eq!(serial["b"], b"b000");
+    assert_eq!(&serial["c"][..8], b"c000c001");
+    assert!(serial["empty"].is_empty());
+
+    let parallel_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap();
+
+    for max_readers in [None, NonZeroUsize::new(2)] {
+        let options = ExtractOptions {
+            max_readers,
+            ..Default::default()
+        };
+        let (parallel, mut finished) =
+            parallel_pool.install(|| extract_all(&header, &blob, &options));
+
+        assert_eq!(parallel, serial, "max_readers: {max_readers:?}");
+
+        // Each partition is reported exactly once.
+        finished.sort();
+        assert_eq!(finished, ["a", "b", "c", "empty"]);
+    }
+}
+
+#[test]
+fn partial_update_with_full_operations() {
+    let (mut header, _) = shuffled_payload();
+    assert!(header.is_full_ota());
+    assert!(!header.is_partial_update());
+
+    header.manifest.partial_update = Some(true);
+    assert!(header.is_partial_update());
+
+    // Listing the old partition info is fine as long as no operation reads
+    // from the source partition.
+    let partition = &mut header.manifest.partitions[0];
+    partition.old_partition_info = Some(PartitionInfo {
+        size: Some(6 * u64::from(BLOCK_SIZE)),
+        hash: Some(vec![0u8; 32]),
+    });
+    partition.version = Some("1700000000".to_owned());
+    assert!(header.is_full_ota());
+
+    let mut delta_header = header.clone();
+    delta_header.manifest.partitions[0].operations[1].type_pb = Type::SOURCE_COPY;
+    assert!(!delta_header.is_full_ota());
+
+    // Replacing the partition only changes its data.
+    let partition = &mut header.manifest.partitions[0];
+    let mut writer = CompressedPartitionWriter::new(Vec::new(), BLOCK_SIZE).unwrap();
+    writer.write_all(b"AAAABBBB").unwrap();
+    writer.finish(partition).unwrap();
+
+    assert_eq!(partition.old_partition_info, None);
+    assert_eq!(partition.version.as_deref(), Some("1700000000"));
+    assert_eq!(partition.operations.len(), 1);
+    assert!(header.is_full_ota());
+    assert!(header.is_partial_update());
+}
+
+#[test]
+fn apply_zero_operation() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let op = zero_op(vec![extent(2, 1), extent(0, 1)]);
+    let mut writer = Cursor::new(vec![0xffu8; 16]);
+
+    payload::apply_operation(
+        Cursor::new(b""),
+        &mut writer,
+        BLOCK_SIZE,
+        0,
+        &op,
+        true,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        writer.into_inner(),
+        b"\0\0\0\0\xff\xff\xff\xff\0\0\0\0\xff\xff\xff\xff",
+    );
+}
+
+#[test]
+fn apply_operation_bad_digest() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let mut blob = vec![];
+    let mut op = replace_op(&mut blob, b"AAAA", vec![extent(0, 1)]);
+    op.data_sha256_hash = Some(vec![0u8; 32]);
+
+    let result = payload::apply_operation(
+        Cursor::new(&blob),
+        Cursor::new(vec![0u8; 4]),
+        BLOCK_SIZE,
+        0,
+        &op,
+        true,
+        &cancel_signal,
+    );
+
+    assert_matches!(result, Err(payload::Error::MismatchedDigest(_, _)));
+}
+
+/// Write a signed payload and return its data and `payload_properties.txt`.
+fn signed_payload() -> (Vec<u8>, String) {
+    let (header, blob) = shuffled_payload();
+    write_payload(header, &blob)
+}
+
+fn write_payload(header: PayloadHeader, blob: &[u8]) -> (Vec<u8>, String) {
+    let mut writer = PayloadWriter::new(Cursor::new(Vec::new()), header, get_test_key()).unwrap();
+
+    while writer.begin_next_operation().unwrap() {
+        let op = writer.operation().unwrap();
+        let Some(offset) = op.data_offset else {
+            continue;
+        };
+        let range = offset as usize..(offset + op.data_length.unwrap()) as usize;
+
+        writer.write_all(&blob[range]).unwrap();
+    }
+
+    let (writer, properties, _) = writer.finish().unwrap();
+
+    (writer.into_inner(), properties)
+}
+
+#[test]
+fn verify_against_images() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (payload, _) = signed_payload();
+    let verify = |image: &[u8]| {
+        payload::verify_against(
+            Cursor::new(&payload),
+            &mut [("test", Cursor::new(image))],
+            &cancel_signal,
+        )
+    };
+
+    verify(b"AAAABBBBCCCC\0\0\0\0DDDDEEEE").unwrap();
+
+    // The last operation writes blocks 5, 4, and 1 in that order.
+    assert_matches!(
+        verify(b"AAAAXBBBCCCC\0\0\0\0DDDXEEEE"),
+        Err(payload::Error::ImageMismatch { partition, op_index: 2, block: 4 })
+            if partition == "test"
+    );
+    assert_matches!(
+        verify(b"AAAABBBBCCCC\0\0\0\0DDDDEEEEFFFF"),
+        Err(payload::Error::ImageSizeMismatch(_, 24, 28))
+    );
+    assert_matches!(
+        payload::verify_against(
+            Cursor::new(&payload),
+            &mut [("other", Cursor::new(b""))],
+            &cancel_signal,
+        ),
+        Err(payload::Error::MissingPartition(p)) if p == "other"
+    );
+}
+
+#[test]
+fn payload_size_matches_writer() {
+    let (header, _) = shuffled_payload();
+    let (data, _) = signed_payload();
+
+    assert_eq!(
+        payload::payload_size(&header, &get_test_key()).unwrap(),
+        data.len() as u64,
+    );
+}
+
+#[test]
+fn metadata_hash_matches_properties() {
+    let (data, properties) = signed_payload();
+
+    let digest = payload::metadata_hash(Cursor::new(&data), &ring::digest::SHA256).unwrap();
+    let encoded = STANDARD.encode(digest);
+    assert!(properties
+        .lines()
+        .any(|l| l == format!("METADATA_HASH={encoded}")));
+
+    payload::verify_metadata_hash(Cursor::new(&data), &properties).unwrap();
+
+    // Only the metadata should be needed.
+    let metadata_size = properties
+        .lines()
+        .find_map(|l| l.strip_prefix("METADATA_SIZE="))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    payload::verify_metadata_hash(Cursor::new(&data[..metadata_size]), &properties).unwrap();
+}
+
+#[test]
+fn metadata_hash_mismatch() {
+    let (mut data, properties) = signed_payload();
+
+    // Corrupt the last byte of the manifest.
+    let metadata_size = properties
+        .lines()
+        .find_map(|l| l.strip_prefix("METADATA_SIZE="))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    data[metadata_size - 1] ^= 0xff;
+
+    let result = payload::verify_metadata_hash(Cursor::new(&data), &properties);
+    assert_matches!(result, Err(payload::Error::InvalidProperty(k, _, _)) if k == "METADATA_HASH");
+}
+
+#[test]
+fn verify_payload_streaming() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (data, properties) = signed_payload();
+
+    // A plain slice is not seekable.
+    payload::verify_payload(&data[..], &get_test_cert(), &properties, &cancel_signal).unwrap();
+}
+
+#[test]
+fn verify_payload_bad_operation_data() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (mut data, properties) = signed_payload();
+    let header = PayloadHeader::from_reader(Cursor::new(&data)).unwrap();
+
+    // Corrupt the first byte of the blob.
+    data[header.blob_offset as usize] ^= 0xff;
+
+    let result = payload::verify_payload(&data[..], &get_test_cert(), &properties, &cancel_signal);
+    assert_matches!(result, Err(payload::Error::MismatchedDigest(_, _)));
+}
+
+#[test]
+fn edit_postinstall() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    let (mut header, blob) = shuffled_payload();
+
+    let config = PostinstallConfig {
+        run_postinstall: Some(true),
+        postinstall_path: Some("bin/otapreopt_script".to_owned()),
+        filesystem_type: Some("ext4".to_owned()),
+        postinstall_optional: Some(true),
+    };
+    header.set_postinstall("test", config.clone()).unwrap();
+    let (data, _) = write_payload(header, &blob);
+
+    let mut header = PayloadHeader::from_reader(Cursor::new(&data)).unwrap();
+    assert_eq!(header.postinstall("test").unwrap(), config);
+    assert_matches!(
+        header.postinstall("missing"),
+        Err(payload::Error::MissingPartition(n)) if n == "missing"
+    );
+
+    let new_config = PostinstallConfig {
+        run_postinstall: Some(false),
+        ..config
+    };
+    header.set_postinstall("test", new_config.clone()).unwrap();
+    let (new_data, properties) = write_payload(header, &blob);
+    assert!(new_data != data);
+
+    payload::verify_payload(&new_data[..], &get_test_cert(), &properties, &cancel_signal).unwrap();
+
+    let header = PayloadHeader::from_reader(Cursor::new(&new_data)).unwrap();
+    let actual = header.postinstall("test").unwrap();
+    assert!(!actual.is_enabled());
+    assert_eq!(actual, new_config);
+}
+
+/// Encode a length-delimited field with a single byte tag and size.
+fn len_field(number: u8, data: &[u8]) -> Vec<u8> {
+    assert!(number < 16 && data.len() < 128);
+
+    let mut field = vec![(number << 3) | 2, data.len() as u8];
+    field.extend_from_slice(data);
+    field
+}
+
+#[test]
+fn round_trip_unknown_manifest_fields() {
+    let partition = PartitionUpdate {
+        partition_name: "test".to_owned(),
+        ..Default::default()
+    };
+    let info = PartitionInfo {
+        size: Some(0),
+        hash: None,
+    };
+    let manifest = DeltaArchiveManifest {
+        block_size: BLOCK_SIZE,
+        ..Default::default()
+    };
+
+    // Unknown fields in the manifest, a partition, and a partition's info.
+    let unknown_manifest = b"\x98\x06\x01";
+    let unknown_partition = b"\xa2\x01\x03new";
+    let unknown_info = b"\x18\x05";
+
+    let mut info_raw = avbroot::util::write_protobuf(&info).unwrap();
+    info_raw.extend_from_slice(unknown_info);
+    let mut partition_raw = avbroot::util::write_protobuf(&partition).unwrap();
+    partition_raw.extend(len_field(7, &info_raw));
+    partition_raw.extend_from_slice(unknown_partition);
+    let partitions_field = len_field(13, &partition_raw);
+
+    let mut manifest_raw = avbroot::util::write_protobuf(&manifest).unwrap();
+    manifest_raw.extend_from_slice(&partitions_field);
+    manifest_raw.extend_from_slice(unknown_manifest);
+
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(manifest_raw.len() as u64).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&manifest_raw);
+
+    let header = PayloadHeader::from_reader(Cursor::new(&data)).unwrap();
+    assert_eq!(header.manifest.partitions[0].partition_name, "test");
+    assert_eq!(
+        header.unknown_field_paths(),
+        ["99", "13[0].20", "13[0].7[0].3"],
+    );
+
+    let writer =
+        PayloadWriter::new(Cursor::new(Vec::new()), header.clone(), get_test_key()).unwrap();
+    let (writer, _, _) = writer.finish().unwrap();
+    let new_data = writer.into_inner();
+
+    let new_header = PayloadHeader::from_reader(Cursor::new(&new_data)).unwrap();
+    assert_eq!(new_header.unknown_fields, header.unknown_fields);
+    assert_eq!(
+        new_header.partition_unknown_fields,
+        header.partition_unknown_fields,
+    );
+
+    // The only difference should be the new signature fields.
+    let mut expected = avbroot::util::write_protobuf(&DeltaArchiveManifest {
+        signatures_offset: new_header.manifest.signatures_offset,
+        signatures_size: new_header.manifest.signatures_size,
+        ..manifest
+    })
+    .unwrap();
+    expected.extend_from_slice(&partitions_field);
+    expected.extend_from_slice(unknown_manifest);
+
+    assert_eq!(&new_data[24..24 + expected.len()], expected);
+    assert_eq!(
+        u64::from_be_bytes(new_data[12..20].try_into().unwrap()),
+        expected.len() as u64,
+    );
+}
+
+#[test]
+fn replaced_partition_drops_unknown_data_fields() {
+    let mut partition = PartitionUpdate {
+        partition_name: "test".to_owned(),
+        ..Default::default()
+    };
+    let mut partition_raw = avbroot::util::write_protobuf(&partition).unwrap();
+    partition_raw.extend(len_field(7, b"\x18\x05"));
+    partition_raw.extend_from_slice(b"\xa2\x01\x03new");
+
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(partition_raw.len() as u64 + 2).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend(len_field(13, &partition_raw));
+
+    let mut header = PayloadHeader::from_reader(Cursor::new(&data)).unwrap();
+
+    let mut writer = CompressedPartitionWriter::new(Cursor::new(Vec::new()), BLOCK_SIZE).unwrap();
+    writer.write_all(b"AAAA").unwrap();
+    writer.finish(&mut header.manifest.partitions[0]).unwrap();
+    header.clear_unknown_data_fields("test");
+
+    // The unknown field of the partition itself is kept.
+    assert_eq!(header.unknown_field_paths(), ["13[0].20"]);
+
+    // The hash tree and FEC fields describe the old data, so they are cleared.
+    partition.hash_tree_data_extent = Some(extent(0, 1));
+    partition.hash_tree_extent = Some(extent(1, 1));
+    partition.hash_tree_algorithm = Some("sha256".to_owned());
+    partition.hash_tree_salt = Some(vec![0u8; 32]);
+    partition.fec_data_extent = Some(extent(0, 2));
+    partition.fec_extent = Some(extent(2, 1));
+    partition.fec_roots = 4;
+
+    let mut writer = CompressedPartitionWriter::new(Cursor::new(Vec::new()), BLOCK_SIZE).unwrap();
+    writer.write_all(b"AAAA").unwrap();
+    writer.finish(&mut partition).unwrap();
+
+    assert_eq!(
+        partition,
+        PartitionUpdate {
+            partition_name: "test".to_owned(),
+            new_partition_info: partition.new_partition_info.clone(),
+            operations: partition.operations.clone(),
+            fec_roots: 2,
+            ..Default::default()
+        },
+    );
+
+    // Merge operations can't be recomputed.
+    partition.merge_operations = vec![Default::default()];
+    let writer = CompressedPartitionWriter::new(Cursor::new(Vec::new()), BLOCK_SIZE).unwrap();
+    assert_matches!(
+        writer.finish(&mut partition),
+        Err(payload::Error::UnsupportedPartitionField(n, "merge_operations")) if n == "test"
+    );
+}
+
+#[test]
+fn remove_partition() {
+    let manifest = DeltaArchiveManifest {
+        block_size: BLOCK_SIZE,
+        max_timestamp: Some(1234),
+        dynamic_partition_metadata: Some(DynamicPartitionMetadata {
+            groups: vec![DynamicPartitionGroup {
+                name: "group".to_owned(),
+                partition_names: vec!["a".to_owned(), "b".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut manifest_raw = avbroot::util::write_protobuf(&manifest).unwrap();
+
+    // Each partition has an unknown field with its own name.
+    for (name, version) in [("a", None), ("b", None), ("c", Some("5678"))] {
+        let partition = PartitionUpdate {
+            partition_name: name.to_owned(),
+            version: version.map(|v| v.to_owned()),
+            ..Default::default()
+        };
+        let mut partition_raw = avbroot::util::write_protobuf(&partition).unwrap();
+        partition_raw.extend_from_slice(b"\xa2\x01\x01");
+        partition_raw.extend_from_slice(name.as_bytes());
+        manifest_raw.extend(len_field(13, &partition_raw));
+    }
+
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(manifest_raw.len() as u64).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&manifest_raw);
+
+    let mut header = PayloadHeader::from_reader(Cursor::new(&data)).unwrap();
+    assert!(!header.is_partial_update());
+
+    header.remove_partition("a").unwrap();
+    assert_matches!(
+        header.remove_partition("a"),
+        Err(payload::Error::MissingPartition(n)) if n == "a"
+    );
+
+    assert!(header.is_partial_update());
+    let partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| (p.partition_name.as_str(), p.version.as_deref()))
+        .collect::<Vec<_>>();
+    assert_eq!(partitions, [("b", Some("1234")), ("c", Some("5678"))]);
+    let dpm = header.manifest.dynamic_partition_metadata.as_ref().unwrap();
+    assert_eq!(dpm.groups[0].partition_names, ["b"]);
+
+    // The unknown fields still belong to the same partitions.
+    let writer =
+        PayloadWriter::new(Cursor::new(Vec::new()), header.clone(), get_test_key()).unwrap();
+    let (writer, _, _) = writer.finish().unwrap();
+    let new_data = writer.into_inner();
+
+    let new_header = PayloadHeader::from_reader(Cursor::new(&new_data)).unwrap();
+    assert_eq!(new_header.unknown_fields, header.unknown_fields);
+    assert_eq!(
+        new_header.partition_unknown_fields,
+        header.partition_unknown_fields,
+    );
+    assert_eq!(new_header.unknown_field_paths(), ["13[0].20", "13[1].20"]);
+
+    let manifest_raw = new_header.manifest_raw().unwrap();
+    let contains = |needle: &[u8]| manifest_raw.windows(needle.len()).any(|w| w == needle);
+    assert!(!contains(b"\xa2\x01\x01a"));
+    assert!(contains(b"\xa2\x01\x01b"));
+    assert!(contains(b"\xa2\x01\x01c"));
+
+    // The unknown fields follow the partitions when they are reordered.
+    let mut header = new_header;
+    header.manifest.partitions.reverse();
+
+    let reordered =
+        PayloadHeader::from_reader(Cursor::new(raw_header_with_unknown(&header))).unwrap();
+    assert_eq!(reordered.manifest.partitions[0].partition_name, "c");
+    assert_eq!(
+        reordered.partition_unknown_fields,
+        header.partition_unknown_fields,
+    );
+}
+
+/// Serialize just the payload header, including the unknown fields.
+fn raw_header_with_unknown(header: &PayloadHeader) -> Vec<u8> {
+    let manifest_raw = header.manifest_raw().unwrap();
+
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(manifest_raw.len() as u64).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&manifest_raw);
+    data
+}
+
+/// Serialize just the payload header, which is all that is needed to inspect
+/// the manifest.
+fn raw_header(manifest: &DeltaArchiveManifest) -> Vec<u8> {
+    let manifest_raw = avbroot::util::write_protobuf(manifest).unwrap();
+
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(manifest_raw.len() as u64).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&manifest_raw);
+    data
+}
+
+#[test]
+fn detect_payload_kind() {
+    let partition = |name: &str, types: &[Type]| PartitionUpdate {
+        partition_name: name.to_owned(),
+        operations: types
+            .iter()
+            .map(|t| InstallOperation {
+                type_pb: *t,
+                dst_extents: vec![extent(0, 1)],
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let kind = |partitions: Vec<PartitionUpdate>| {
+        let manifest = DeltaArchiveManifest {
+            block_size: BLOCK_SIZE,
+            partitions,
+            ..Default::default()
+        };
+
+        payload::payload_kind(Cursor::new(raw_header(&manifest))).unwrap()
+    };
+
+    assert_eq!(
+        kind(vec![
+            partition("boot", &[Type::REPLACE_XZ, Type::ZERO]),
+            partition("system", &[Type::REPLACE, Type::REPLACE_BZ]),
+        ]),
+        PayloadKind::Full,
+    );
+
+    // Partitions with new data still use full operations for some blocks.
+    assert_eq!(
+        kind(vec![
+            partition("boot", &[Type::SOURCE_BSDIFF, Type::REPLACE]),
+            partition("system", &[Type::SOURCE_COPY]),
+            partition("vendor", &[]),
+        ]),
+        PayloadKind::Incremental,
+    );
+
+    assert_eq!(
+        kind(vec![
+            partition("boot", &[Type::REPLACE]),
+            partition("system", &[Type::SOURCE_COPY, Type::PUFFDIFF]),
+        ]),
+        PayloadKind::Mixed,
+    );
+
+    assert_eq!(kind(vec![]), PayloadKind::Full);
+
+    assert_matches!(
+        payload::payload_kind(Cursor::new(b"CrAU")),
+        Err(payload::Error::Io(_))
+    );
+}
+
+#[test]
+fn describe_incremental_operations() {
+    let manifest = DeltaArchiveManifest {
+        block_size: BLOCK_SIZE,
+        partitions: vec![PartitionUpdate {
+            partition_name: "system".to_owned(),
+            old_partition_info: Some(PartitionInfo {
+                size: Some(8 * u64::from(BLOCK_SIZE)),
+                hash: None,
+            }),
+            operations: vec![
+                InstallOperation {
+                    type_pb: Type::SOURCE_COPY,
+                    src_extents: vec![extent(4, 2)],
+                    dst_extents: vec![extent(0, 2)],
+                    ..Default::default()
+                },
+                InstallOperation {
+                    type_pb: Type::SOURCE_BSDIFF,
+                    data_offset: Some(0),
+                    data_length: Some(10),
+                    src_extents: vec![extent(0, 1), extent(7, 1)],
+                    dst_extents: vec![extent(2, 3)],
+                    ..Default::default()
+                },
+                zero_op(vec![extent(5, 3)]),
+            ],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let data = raw_header(&manifest);
+
+    let ext = |start_block, num_blocks| ExtentDesc {
+        start_block,
+        num_blocks,
+    };
+
+    assert_eq!(
+        payload::describe_operations(Cursor::new(&data), "system").unwrap(),
+        [
+            OperationDesc {
+                index: 0,
+                op_type: "SOURCE_COPY".to_owned(),
+                data_offset: None,
+                data_length: None,
+                src_extents: vec![ext(4, 2)],
+                dst_extents: vec![ext(0, 2)],
+            },
+            OperationDesc {
+                index: 1,
+                op_type: "SOURCE_BSDIFF".to_owned(),
+                data_offset: Some(0),
+                data_length: Some(10),
+                src_extents: vec![ext(0, 1), ext(7, 1)],
+                dst_extents: vec![ext(2, 3)],
+            },
+            OperationDesc {
+                index: 2,
+                op_type: "ZERO".to_owned(),
+                data_offset: None,
+                data_length: None,
+                src_extents: vec![],
+                dst_extents: vec![ext(5, 3)],
+            },
+        ],
+    );
+
+    assert_matches!(
+        payload::describe_operations(Cursor::new(&data), "vendor"),
+        Err(payload::Error::MissingPartition(p)) if p == "vendor"
+    );
+}
+
+#[test]
+fn future_payload_version() {
+    let data = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/payload_v3.bin",
+    ));
+
+    // The fixed-size fields are still readable for diagnostics.
+    let raw_header = RawPayloadHeader::from_reader(Cursor::new(data)).unwrap();
+    assert_eq!(
+        raw_header,
+        RawPayloadHeader {
+            version: 3,
+            manifest_size: 3,
+            metadata_signature_size: 0,
+        },
+    );
+
+    let err = PayloadHeader::from_reader(Cursor::new(data)).unwrap_err();
+    assert_matches!(
+        err,
+        payload::Error::UnsupportedVersion {
+            version: 3,
+            min: 2,
+            max: 2,
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Unsupported payload version: 3 (supported: 2 to 2)",
+    );
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    assert_matches!(
+        payload::verify_payload(Cursor::new(data), &get_test_cert(), "", &cancel_signal),
+        Err(payload::Error::UnsupportedVersion { version: 3, .. })
+    );
+    assert_matches!(
+        payload::metadata_hash(Cursor::new(data), &ring::digest::SHA256),
+        Err(payload::Error::UnsupportedVersion { version: 3, .. })
+    );
+}
+
+#[test]
+fn truncated_manifest_size() {
+    // A manifest size that is far larger than the file fails cleanly instead
+    // of trying to allocate a buffer of that size.
+    let mut data = b"CrAU".to_vec();
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&u64::MAX.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(b"\x18\x04");
+
+    assert_matches!(
+        PayloadHeader::from_reader(Cursor::new(&data)),
+        Err(payload::Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+    );
+
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+    assert_matches!(
+        payload::verify_payload(Cursor::new(&data), &get_test_cert(), "", &cancel_signal),
+        Err(payload::Error::IntegerTooLarge(_))
+    );
+}
@@ -0,0 +1,194 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Write},
+    num::NonZeroUsize,
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use avbroot::{
+    format::payload::{self, CompressedPartitionWriter},
+    pipeline,
+    protobuf::chromeos_update_engine::PartitionUpdate,
+};
+
+fn jobs(n: usize) -> NonZeroUsize {
+    NonZeroUsize::new(n).unwrap()
+}
+
+/// Run the pipeline and collect the results in the order they are consumed.
+/// Earlier items take longer so that they finish out of order.
+fn run_collect(n: usize, num_items: u64) -> Vec<u64> {
+    let mut results = vec![];
+
+    pipeline::run_ordered(
+        jobs(n),
+        0..num_items,
+        |i| -> Result<_, ()> {
+            thread::sleep(Duration::from_millis((num_items - i) % 4));
+            Ok(i * i)
+        },
+        |r| {
+            results.push(r);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    results
+}
+
+#[test]
+fn results_in_order() {
+    let serial = run_collect(1, 32);
+    assert_eq!(serial, (0..32).map(|i| i * i).collect::<Vec<_>>());
+
+    for n in [2, 4, 64] {
+        assert_eq!(run_collect(n, 32), serial);
+    }
+
+    assert!(run_collect(4, 0).is_empty());
+}
+
+#[test]
+fn bounded_results() {
+    for n in [1, 3] {
+        let alive = AtomicUsize::new(0);
+        let max_alive = AtomicUsize::new(0);
+
+        pipeline::run_ordered(
+            jobs(n),
+            0..20,
+            |i| -> Result<_, ()> {
+                let now = alive.fetch_add(1, Ordering::SeqCst) + 1;
+                max_alive.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+                Ok(i)
+            },
+            |_| {
+                // A slow consumer must not let finished results pile up.
+                thread::sleep(Duration::from_millis(3));
+                alive.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert!(max_alive.load(Ordering::SeqCst) <= n);
+    }
+}
+
+#[test]
+fn errors_stop_pipeline() {
+    let started = Mutex::new(vec![]);
+    let mut consumed = vec![];
+
+    let result = pipeline::run_ordered(
+        jobs(2),
+        0..100,
+        |i| {
+            started.lock().unwrap().push(i);
+            if i == 5 || i == 7 {
+                Err(i)
+            } else {
+                Ok(i)
+            }
+        },
+        |r| {
+            consumed.push(r);
+            Ok(())
+        },
+    );
+
+    // The first error in item order wins and nothing after it is consumed.
+    assert_eq!(result, Err(5));
+    assert_eq!(consumed, [0, 1, 2, 3, 4]);
+    assert!(started.lock().unwrap().len() < 100);
+
+    let result = pipeline::run_ordered(jobs(2), 0..10, Ok::<_, u32>, |r| {
+        if r == 3 {
+            Err(100)
+        } else {
+            Ok(())
+        }
+    });
+    assert_eq!(result, Err(100));
+}
+
+#[test]
+fn panics_are_propagated() {
+    let result = panic::catch_unwind(|| {
+        pipeline::run_ordered(
+            jobs(3),
+            0..10,
+            |i| -> Result<_, ()> {
+                assert_ne!(i, 4, "worker panic");
+                Ok(i)
+            },
+            |_| Ok(()),
+        )
+    });
+
+    assert!(result.is_err());
+}
+
+/// Compress a set of partition images and return the compressed data and the
+/// updated partition metadata in order.
+fn compress_all(n: usize, images: &[Vec<u8>]) -> Vec<(Vec<u8>, PartitionUpdate)> {
+    let mut results = vec![];
+
+    pipeline::run_ordered(
+        jobs(n),
+        images.iter().enumerate(),
+        |(i, data)| -> Result<_, payload::Error> {
+            let mut partition = PartitionUpdate {
+                partition_name: format!("image{i}"),
+                ..Default::default()
+            };
+
+            let mut writer = CompressedPartitionWriter::new(Cursor::new(Vec::new()), 4096)?;
+            writer.write_all(data).unwrap();
+            let writer = writer.finish(&mut partition)?;
+
+            Ok((writer.into_inner(), partition))
+        },
+        |r| {
+            results.push(r);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    results
+}
+
+#[test]
+fn compression_matches_serial() {
+    let images = (0..6u8)
+        .map(|i| {
+            let mut data = vec![0u8; 4096 * (usize::from(i) + 1) * 8];
+            for (j, b) in data.iter_mut().enumerate().step_by(usize::from(i) + 3) {
+                *b = (j % 251) as u8 ^ i;
+            }
+            data
+        })
+        .collect::<Vec<_>>();
+
+    let serial = compress_all(1, &images);
+    assert_eq!(serial.len(), images.len());
+
+    for (i, (_, partition)) in serial.iter().enumerate() {
+        assert_eq!(partition.partition_name, format!("image{i}"));
+    }
+
+    assert_eq!(compress_all(4, &images), serial);
+}
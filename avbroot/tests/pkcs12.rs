@@ -0,0 +1,180 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::path::Path;
+
+use assert_matches::assert_matches;
+use avbroot::{
+    crypto::{self, PassphraseSource},
+    format::pkcs12::{self, Pkcs12},
+};
+use rsa::RsaPrivateKey;
+use x509_cert::Certificate;
+
+const KEY_PKCS8: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/key_pkcs8.pem",
+));
+// Created by OpenSSL 3.x with `-legacy`, which matches the OpenSSL 1.1
+// defaults: RC2-40 for the certificates, 3DES for the key, and a SHA-1 MAC.
+const P12_OPENSSL1: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_openssl1.p12",
+));
+// OpenSSL 3.x defaults: PBES2 with AES-256-CBC and a SHA-256 MAC.
+const P12_OPENSSL3: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_openssl3.p12",
+));
+const P12_KEY_ONLY: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_key_only.pfx",
+));
+// The key and its certificate, plus an unrelated certificate named "extra".
+const P12_MULTIPLE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_multiple.p12",
+));
+// Certificates named "test" and "extra", but no key.
+const P12_CERTS_ONLY: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_certs_only.p12",
+));
+const P12_RC4: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/pkcs12_rc4.p12",
+));
+
+const PASSPHRASE: &str = "avbroot";
+
+fn expected_key() -> RsaPrivateKey {
+    let source = PassphraseSource::EnvVar("AVBROOT_UNUSED".into());
+    crypto::read_pem_key(KEY_PKCS8, &source).unwrap()
+}
+
+fn subject(cert: &Certificate) -> String {
+    cert.tbs_certificate.subject.to_string()
+}
+
+#[test]
+fn load_openssl_defaults() {
+    let expected = expected_key();
+
+    for data in [P12_OPENSSL1, P12_OPENSSL3] {
+        let bundle = Pkcs12::parse(data, PASSPHRASE).unwrap();
+        assert_eq!(bundle.keys.len(), 1);
+        assert_eq!(bundle.certs.len(), 1);
+        assert_eq!(bundle.keys[0].friendly_name.as_deref(), Some("test"));
+
+        let key = bundle.key(None).unwrap();
+        assert_eq!(key, &expected);
+
+        let cert = bundle.cert(None).unwrap();
+        assert_eq!(subject(cert), "CN=avbroot-test");
+        assert!(crypto::cert_matches_key(cert, key).unwrap());
+    }
+}
+
+#[test]
+fn load_key_only() {
+    let bundle = Pkcs12::parse(P12_KEY_ONLY, PASSPHRASE).unwrap();
+    assert_eq!(bundle.key(None).unwrap(), &expected_key());
+    assert_matches!(
+        bundle.cert(None),
+        Err(pkcs12::Error::MissingEntry("certificates"))
+    );
+}
+
+#[test]
+fn select_by_alias() {
+    // The certificate for the only key is picked, even with other certificates.
+    let bundle = Pkcs12::parse(P12_MULTIPLE, PASSPHRASE).unwrap();
+    assert_eq!(bundle.certs.len(), 2);
+    assert_eq!(subject(bundle.cert(None).unwrap()), "CN=avbroot-test");
+    assert_eq!(
+        subject(bundle.cert(Some("extra")).unwrap()),
+        "CN=avbroot-test",
+    );
+
+    let bundle = Pkcs12::parse(P12_CERTS_ONLY, PASSPHRASE).unwrap();
+    assert_matches!(
+        bundle.cert(None),
+        Err(pkcs12::Error::AmbiguousEntry(_, names)) if names == ["test", "extra"]
+    );
+    assert_eq!(
+        subject(bundle.cert(Some("extra")).unwrap()),
+        "CN=avbroot-extra",
+    );
+    assert_eq!(
+        subject(bundle.cert(Some("test")).unwrap()),
+        "CN=avbroot-test",
+    );
+    assert_matches!(
+        bundle.cert(Some("missing")),
+        Err(pkcs12::Error::AliasNotFound(_, a)) if a == "missing"
+    );
+}
+
+#[test]
+fn wrong_passphrase() {
+    for data in [P12_OPENSSL1, P12_OPENSSL3] {
+        assert_matches!(
+            Pkcs12::parse(data, "wrong"),
+            Err(pkcs12::Error::MacMismatch)
+        );
+    }
+}
+
+#[test]
+fn unsupported_encryption() {
+    // pbeWithSHAAnd128BitRC4
+    assert_matches!(
+        Pkcs12::parse(P12_RC4, PASSPHRASE),
+        Err(pkcs12::Error::UnsupportedEncryption(o)) if o.to_string() == "1.2.840.113549.1.12.1.1"
+    );
+}
+
+#[test]
+fn too_many_iterations() {
+    // Replace the MAC iteration count of 2048 at the end of the file with
+    // 10,000,001. The new INTEGER is 2 bytes longer, so the lengths of the MAC
+    // data and the outer PFX sequences grow by 2 too.
+    let mut data = P12_OPENSSL3.to_vec();
+    assert_eq!(&data[..4], b"\x30\x82\x0a\x28");
+    data[3] += 2;
+
+    let mac_data = data.len() - 0x43;
+    assert_eq!(&data[mac_data..mac_data + 2], b"\x30\x41");
+    data[mac_data + 1] += 2;
+
+    data.truncate(data.len() - 4);
+    data.extend_from_slice(b"\x02\x04\x00\x98\x96\x81");
+
+    assert_matches!(
+        Pkcs12::parse(&data, PASSPHRASE),
+        Err(pkcs12::Error::TooManyIterations("MAC", 10_000_001))
+    );
+}
+
+#[test]
+fn read_key_and_cert_files() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let source = PassphraseSource::EnvVar("AVBROOT_TEST_P12_PASSPHRASE".into());
+    std::env::set_var("AVBROOT_TEST_P12_PASSPHRASE", PASSPHRASE);
+
+    assert!(crypto::is_pkcs12_path(Path::new("ota.P12")));
+    assert!(crypto::is_pkcs12_path(Path::new("ota.pfx")));
+    assert!(!crypto::is_pkcs12_path(Path::new("ota.key")));
+
+    let key = crypto::read_key_file(&dir.join("pkcs12_key_only.pfx"), &source, None).unwrap();
+    assert_eq!(key, expected_key());
+
+    let cert = crypto::read_cert_file(&dir.join("pkcs12_openssl3.p12"), &source, None).unwrap();
+    assert!(crypto::cert_matches_key(&cert, &key).unwrap());
+
+    // PEM files are still loaded as before.
+    let key = crypto::read_key_file(&dir.join("key_pkcs8.pem"), &source, Some("test")).unwrap();
+    assert_eq!(key, expected_key());
+}
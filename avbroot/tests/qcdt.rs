@@ -0,0 +1,161 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::Cursor;
+
+use assert_matches::assert_matches;
+use avbroot::{
+    format::qcdt::{self, QcdtEntry, QcdtImage},
+    stream::{FromReader, ToWriter},
+};
+
+// Version 2 image with a 2048-byte page size and two entries for different
+// variants. Each entry points to its own minimal device tree.
+const QCDT_V2: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/qcdt_v2.img",
+));
+
+fn write_image(image: &QcdtImage) -> Vec<u8> {
+    let mut writer = Cursor::new(Vec::new());
+    image.to_writer(&mut writer).unwrap();
+    writer.into_inner()
+}
+
+fn le_word(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn round_trip_v2() {
+    let image = QcdtImage::from_reader(Cursor::new(QCDT_V2)).unwrap();
+
+    assert_eq!(image.version, 2);
+    assert_eq!(image.page_size, 2048);
+    assert_eq!(image.entries.len(), 2);
+
+    for (entry, variant_id) in image.entries.iter().zip([8, 11]) {
+        assert_eq!(entry.platform_id, 126);
+        assert_eq!(entry.variant_id, variant_id);
+        assert_eq!(entry.subtype_id, Some(0));
+        assert_eq!(entry.soc_rev, 0x20000);
+        assert_eq!(entry.pmic_rev, None);
+        assert_eq!(entry.data.len(), 72);
+        assert_eq!(&entry.data[..4], b"\xd0\x0d\xfe\xed");
+    }
+    assert_ne!(image.entries[0].data, image.entries[1].data);
+
+    assert_eq!(write_image(&image), QCDT_V2);
+}
+
+#[test]
+fn round_trip_v1_v3() {
+    for version in [1, 3] {
+        let image = QcdtImage {
+            version,
+            page_size: 64,
+            entries: vec![QcdtEntry {
+                platform_id: 0x10,
+                variant_id: 0x20,
+                subtype_id: (version >= 2).then_some(0x30),
+                soc_rev: 0x40,
+                pmic_rev: (version >= 3).then_some([1, 2, 3, 4]),
+                data: b"dtb".to_vec(),
+            }],
+        };
+
+        let data = write_image(&image);
+        assert_eq!(data.len(), 128);
+        assert_eq!(le_word(&data, 8), 1);
+
+        let new_image = QcdtImage::from_reader(Cursor::new(&data)).unwrap();
+        assert_eq!(new_image, image);
+    }
+}
+
+#[test]
+fn replace_entries() {
+    let mut image = QcdtImage::from_reader(Cursor::new(QCDT_V2)).unwrap();
+    let original = image.clone();
+
+    // The replacement is larger than a page, so the offsets and sizes in the
+    // table must be updated.
+    let replacement = vec![0xaa; 3000];
+    assert_eq!(image.replace_entries(126, 11, &replacement).unwrap(), 1);
+
+    let data = write_image(&image);
+    assert_eq!(data.len(), 8192);
+    // Offset and size fields of both entries.
+    assert_eq!(le_word(&data, 28), 2048);
+    assert_eq!(le_word(&data, 32), 72);
+    assert_eq!(le_word(&data, 52), 4096);
+    assert_eq!(le_word(&data, 56), 3000);
+    assert_eq!(&data[2048..2048 + 72], &QCDT_V2[2048..2048 + 72]);
+
+    let new_image = QcdtImage::from_reader(Cursor::new(&data)).unwrap();
+    assert_eq!(new_image.entries[0], original.entries[0]);
+    assert_eq!(new_image.entries[1].data, replacement);
+    assert_eq!(new_image, image);
+
+    assert_matches!(
+        image.replace_entries(126, 12, &replacement),
+        Err(qcdt::Error::EntryNotFound(126, 12))
+    );
+}
+
+#[test]
+fn deduplicate_entries() {
+    let mut image = QcdtImage::from_reader(Cursor::new(QCDT_V2)).unwrap();
+    let blob = image.entries[0].data.clone();
+    image.replace_entries(126, 11, &blob).unwrap();
+
+    // Both entries point to the same copy of the data.
+    let data = write_image(&image);
+    assert_eq!(data.len(), 4096);
+    assert_eq!(le_word(&data, 28), 2048);
+    assert_eq!(le_word(&data, 52), 2048);
+
+    let new_image = QcdtImage::from_reader(Cursor::new(&data)).unwrap();
+    assert_eq!(new_image, image);
+}
+
+#[test]
+fn invalid_image() {
+    let mut data = QCDT_V2.to_vec();
+    data[0] = b'X';
+    assert_matches!(
+        QcdtImage::from_reader(Cursor::new(&data)),
+        Err(qcdt::Error::InvalidMagic(m)) if &m == b"XCDT"
+    );
+
+    let mut data = QCDT_V2.to_vec();
+    data[4] = 4;
+    assert_matches!(
+        QcdtImage::from_reader(Cursor::new(&data)),
+        Err(qcdt::Error::UnsupportedVersion(4))
+    );
+
+    let mut data = QCDT_V2.to_vec();
+    data[60] = 1;
+    assert_matches!(
+        QcdtImage::from_reader(Cursor::new(&data)),
+        Err(qcdt::Error::MissingTerminator)
+    );
+
+    // Second entry extends past the end of the file.
+    let data = &QCDT_V2[..4096 + 71];
+    assert_matches!(
+        QcdtImage::from_reader(Cursor::new(data)),
+        Err(qcdt::Error::EntryOutOfBounds(1))
+    );
+
+    // Version 2 entries can't be written as version 1.
+    let mut image = QcdtImage::from_reader(Cursor::new(QCDT_V2)).unwrap();
+    image.version = 1;
+    assert_matches!(
+        image.to_writer(Cursor::new(Vec::new())),
+        Err(qcdt::Error::InvalidEntry(0, 1, _))
+    );
+}
@@ -0,0 +1,334 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+// Every fixture in tests/data that has a writer is checked to be reproduced
+// byte-for-byte after parsing. The checks are selected by file name, so new
+// fixtures are picked up without changes here. The property tests check the
+// opposite direction: that generated structures survive being written and
+// parsed again.
+//
+// LP metadata (super.img), GPT disk images, and sparse images are not checked
+// because avbroot can only read them.
+
+use std::{
+    fmt, fs,
+    io::{Cursor, Read},
+    panic,
+};
+
+use avbroot::{
+    format::{
+        avb::{self, Footer},
+        bootimage::{BootContainer, BootImage, BootImageV0Through2, V1Extra, V2Extra},
+        compression::CompressedReader,
+        cpio::{self, CpioEntryNew},
+        payload::{PayloadHeader, RawPayloadHeader},
+        qcdt::{QcdtEntry, QcdtImage},
+    },
+    stream::{FromReader, ToWriter},
+};
+use lz4_flex::frame::FrameDecoder;
+
+const DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+/// Number of structures generated for each property test.
+const CASES: u64 = 256;
+
+fn write<T, E: fmt::Debug>(value: &T) -> Vec<u8>
+where
+    T: for<'a> ToWriter<&'a mut Vec<u8>, Error = E>,
+{
+    let mut data = vec![];
+    value.to_writer(&mut data).unwrap();
+    data
+}
+
+fn lz4_decompress(data: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    FrameDecoder::new(data).read_to_end(&mut result).unwrap();
+    result
+}
+
+fn check_boot_image(data: &[u8]) {
+    let (image, container) = BootContainer::load(Cursor::new(data)).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    container.save(&mut writer, &image).unwrap();
+    let new_data = writer.into_inner();
+
+    if container == BootContainer::Lz4Frame {
+        // The LZ4 encoder settings are not stored, so only the boot image
+        // inside the frame is reproduced exactly.
+        assert!(lz4_decompress(&new_data) == lz4_decompress(data));
+    } else {
+        assert!(new_data == data);
+    }
+}
+
+fn check_qcdt(data: &[u8]) {
+    let image = QcdtImage::from_reader(Cursor::new(data)).unwrap();
+
+    assert!(write(&image) == data);
+}
+
+fn check_avb(data: &[u8]) {
+    let (header, footer, _) = avb::load_image(Cursor::new(data)).unwrap();
+    let header_data = write(&header);
+
+    if let Some((footer, footer_offset)) = avb::find_footer(Cursor::new(data)).unwrap() {
+        let vbmeta = &data[footer.vbmeta_offset as usize..][..footer.vbmeta_size as usize];
+        assert!(header_data == vbmeta);
+
+        let footer_data = &data[footer_offset as usize..][..Footer::SIZE];
+        assert!(write(&footer) == footer_data);
+    } else {
+        assert!(footer.is_none());
+        assert!(header_data == data[..header_data.len()]);
+        // Root images are padded with zeros.
+        assert!(data[header_data.len()..].iter().all(|b| *b == 0));
+    }
+}
+
+fn check_cpio(data: &[u8]) {
+    // The compression settings are not stored, so only the archive itself is
+    // checked. Recoverable errors, like a bad checksum in the gzip trailer, do
+    // not affect the archive.
+    let mut reader = CompressedReader::new(Cursor::new(data), true).unwrap();
+    let (raw, _) = reader.decompress_all().unwrap();
+
+    let entries = cpio::load(Cursor::new(&raw), true).unwrap();
+
+    let mut writer = Cursor::new(Vec::new());
+    cpio::save(&mut writer, &entries, raw.len() % 512 == 0).unwrap();
+
+    assert!(writer.into_inner() == raw);
+}
+
+fn check_payload_manifest(data: &[u8]) {
+    let raw_header = RawPayloadHeader::from_reader(Cursor::new(data)).unwrap();
+    if raw_header.check_version().is_err() {
+        // Only used for testing how unsupported versions are reported.
+        return;
+    }
+
+    let header = PayloadHeader::from_reader(Cursor::new(data)).unwrap();
+    let manifest_offset = raw_header.metadata_size().unwrap() - raw_header.manifest_size;
+    let manifest_raw = &data[manifest_offset as usize..][..raw_header.manifest_size as usize];
+
+    assert!(header.manifest_raw().unwrap() == manifest_raw);
+}
+
+/// Select the check for a fixture by its file name.
+fn golden_check(name: &str) -> Option<fn(&[u8])> {
+    if name.starts_with("boot_") || name.starts_with("vendor_") {
+        Some(check_boot_image)
+    } else if name.starts_with("qcdt_") {
+        Some(check_qcdt)
+    } else if name.starts_with("vbmeta_") || name.starts_with("attestation_") {
+        Some(check_avb)
+    } else if name.ends_with(".cpio") || name.ends_with(".cpio.gz") {
+        Some(check_cpio)
+    } else if name.starts_with("payload_") {
+        Some(check_payload_manifest)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn golden_files() {
+    let mut paths = fs::read_dir(DATA_DIR)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut checked = 0;
+    let mut failed = vec![];
+
+    for path in &paths {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let Some(check) = golden_check(name) else {
+            continue;
+        };
+
+        let data = fs::read(path).unwrap();
+        if panic::catch_unwind(|| check(&data)).is_err() {
+            failed.push(name);
+        }
+
+        checked += 1;
+    }
+
+    assert!(checked > 0);
+    assert!(failed.is_empty(), "Not reproduced exactly: {failed:?}");
+}
+
+/// Small xorshift PRNG so that the property tests are reproducible without
+/// additional dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The multiplier is odd, so the state is never 0.
+        Self(seed.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_bool(&mut self) -> bool {
+        self.next_u64() & 1 != 0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    fn bytes(&mut self, max_len: u64) -> Vec<u8> {
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+
+    /// Non-empty string of ASCII letters and digits.
+    fn name(&mut self, max_len: u64) -> String {
+        const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+        let len = 1 + self.below(max_len);
+        (0..len)
+            .map(|_| CHARS[self.below(CHARS.len() as u64) as usize] as char)
+            .collect()
+    }
+}
+
+fn random_cpio_entry(rng: &mut Rng) -> CpioEntryNew {
+    let is_dir = rng.next_bool();
+    let xattrs = rng.next_bool().then(|| {
+        (0..rng.below(3))
+            .map(|_| (rng.name(16).into_bytes(), rng.bytes(32)))
+            .collect()
+    });
+    // The newcx format has no CRC variant.
+    let crc = xattrs.is_none() && rng.next_bool();
+
+    CpioEntryNew {
+        ino: rng.next_u32(),
+        mode: if is_dir { 0o40755 } else { 0o100644 },
+        uid: rng.next_u32(),
+        gid: rng.next_u32(),
+        nlink: if is_dir { 1 + rng.below(4) as u32 } else { 1 },
+        mtime: rng.next_u32(),
+        dev_maj: rng.next_u32(),
+        dev_min: rng.next_u32(),
+        rdev_maj: rng.next_u32(),
+        rdev_min: rng.next_u32(),
+        chksum: if crc { rng.next_u32() } else { 0 },
+        name: rng.name(32).into_bytes(),
+        content: if is_dir { vec![] } else { rng.bytes(64) },
+        xattrs,
+        crc,
+    }
+}
+
+#[test]
+fn cpio_properties() {
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let entries = (0..rng.below(8))
+            .map(|_| random_cpio_entry(&mut rng))
+            .collect::<Vec<_>>();
+
+        let mut writer = Cursor::new(Vec::new());
+        cpio::save(&mut writer, &entries, rng.next_bool()).unwrap();
+        let data = writer.into_inner();
+
+        let new_entries = cpio::load(Cursor::new(&data), false).unwrap();
+        assert_eq!(new_entries, entries, "Seed: {seed}");
+
+        // Loading with the trailer reproduces the archive exactly.
+        let with_trailer = cpio::load(Cursor::new(&data), true).unwrap();
+        let mut writer = Cursor::new(Vec::new());
+        cpio::save(&mut writer, &with_trailer, data.len() % 512 == 0).unwrap();
+        assert!(writer.into_inner() == data, "Seed: {seed}");
+    }
+}
+
+#[test]
+fn qcdt_properties() {
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let version = 1 + rng.below(3) as u32;
+        let entries = (0..1 + rng.below(6))
+            .map(|_| QcdtEntry {
+                platform_id: rng.next_u32(),
+                variant_id: rng.next_u32(),
+                subtype_id: (version >= 2).then(|| rng.next_u32()),
+                soc_rev: rng.next_u32(),
+                pmic_rev: (version >= 3).then(|| std::array::from_fn(|_| rng.next_u32())),
+                // Short blobs from a small alphabet so that some entries share
+                // the same data.
+                data: (0..1 + rng.below(4)).map(|_| rng.below(2) as u8).collect(),
+            })
+            .collect();
+        let image = QcdtImage {
+            version,
+            page_size: 1 << (4 + rng.below(9)),
+            entries,
+        };
+
+        let data = write(&image);
+        let new_image = QcdtImage::from_reader(Cursor::new(&data)).unwrap();
+
+        // The page size is inferred from the offsets, so it may be larger than
+        // the original, but the layout is always the same.
+        assert_eq!(new_image.version, image.version, "Seed: {seed}");
+        assert_eq!(new_image.entries, image.entries, "Seed: {seed}");
+        assert!(write(&new_image) == data, "Seed: {seed}");
+    }
+}
+
+#[test]
+fn boot_image_v0_through_v2_properties() {
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let version = rng.below(3);
+
+        let image = BootImage::V0Through2(BootImageV0Through2 {
+            kernel_addr: rng.next_u32(),
+            ramdisk_addr: rng.next_u32(),
+            second_addr: rng.next_u32(),
+            tags_addr: rng.next_u32(),
+            page_size: [2048, 4096, 16384][rng.below(3) as usize],
+            os_version: rng.next_u32(),
+            name: rng.name(15),
+            cmdline: rng.name(64),
+            id: std::array::from_fn(|_| rng.next_u32()),
+            extra_cmdline: rng.name(64),
+            kernel: rng.bytes(5000),
+            ramdisk: rng.bytes(5000),
+            second: rng.bytes(100),
+            v1_extra: (version >= 1).then(|| V1Extra {
+                recovery_dtbo_offset: rng.next_u64(),
+                recovery_dtbo: rng.bytes(100),
+            }),
+            v2_extra: (version == 2).then(|| V2Extra {
+                dtb_addr: rng.next_u64(),
+                dtb: rng.bytes(100),
+            }),
+        });
+
+        let data = write(&image);
+        let new_image = BootImage::from_reader(Cursor::new(&data)).unwrap();
+        assert_eq!(new_image, image, "Seed: {seed}");
+    }
+}
@@ -0,0 +1,19 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::sync::{atomic::AtomicBool, Arc};
+
+use avbroot::cli::selftest;
+
+#[test]
+fn known_answer_tests() {
+    let cancel_signal = Arc::new(AtomicBool::new(false));
+
+    for (name, test) in selftest::SUBSYSTEMS {
+        if let Err(e) = test(&cancel_signal) {
+            panic!("{name}: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::{self, Cursor, Read, Write};
+
+use assert_matches::assert_matches;
+use avbroot::format::sparse::{self, RawAndSparseWriter, SparseReader, SparseWriter};
+
+static SPARSE_IMG: &[u8] = include_bytes!("data/sparse.img");
+
+const BLOCK_SIZE: usize = 4096;
+/// Offset of the image CRC32 field in the sparse header.
+const IMAGE_CRC32_OFFSET: usize = 24;
+/// Offset of the data in the first raw chunk.
+const FIRST_RAW_OFFSET: usize = 28 + 12;
+/// Offset of the data in the last raw chunk. This follows the fill, don't care,
+/// and CRC32 chunks.
+const LAST_RAW_OFFSET: usize = FIRST_RAW_OFFSET + BLOCK_SIZE + 16 + 12 + 16 + 12;
+
+fn expected_raw() -> Vec<u8> {
+    let mut data = vec![];
+    data.extend((0..BLOCK_SIZE).map(|i| ((i * 13 + 5) % 256) as u8));
+    data.extend(b"\xde\xad\xbe\xef".repeat(2 * BLOCK_SIZE / 4));
+    data.extend(vec![0u8; BLOCK_SIZE]);
+    data.extend((0..BLOCK_SIZE).map(|i| ((i * 29 + 3) % 256) as u8));
+    data
+}
+
+fn unsparse(data: &[u8], validate_crc: bool) -> io::Result<Vec<u8>> {
+    let mut reader = if validate_crc {
+        SparseReader::with_crc_validation(data).unwrap()
+    } else {
+        SparseReader::new(data).unwrap()
+    };
+
+    let mut result = vec![];
+    reader.read_to_end(&mut result)?;
+
+    Ok(result)
+}
+
+fn sparse_error(e: io::Error) -> sparse::Error {
+    *e.into_inner().unwrap().downcast::<sparse::Error>().unwrap()
+}
+
+#[test]
+fn read_sparse_image() {
+    let reader = SparseReader::new(SPARSE_IMG).unwrap();
+    assert_eq!(reader.header().raw_size(), 5 * BLOCK_SIZE as u64);
+    assert_eq!(reader.header().num_chunks, 5);
+
+    assert_eq!(unsparse(SPARSE_IMG, false).unwrap(), expected_raw());
+    assert_eq!(unsparse(SPARSE_IMG, true).unwrap(), expected_raw());
+}
+
+#[test]
+fn validate_image_crc() {
+    // Corrupting data after the CRC32 chunk is only caught by the image CRC.
+    let mut data = SPARSE_IMG.to_vec();
+    data[LAST_RAW_OFFSET] ^= 0xff;
+
+    assert_matches!(
+        unsparse(&data, true).map_err(sparse_error),
+        Err(sparse::Error::CrcMismatch(0xf975d558, _))
+    );
+    assert!(unsparse(&data, false).is_ok());
+
+    // Without an image CRC, there is nothing to compare against.
+    data[IMAGE_CRC32_OFFSET..IMAGE_CRC32_OFFSET + 4].fill(0);
+    assert!(unsparse(&data, true).is_ok());
+}
+
+#[test]
+fn validate_chunk_crc() {
+    let mut data = SPARSE_IMG.to_vec();
+    data[FIRST_RAW_OFFSET] ^= 0xff;
+    data[IMAGE_CRC32_OFFSET..IMAGE_CRC32_OFFSET + 4].fill(0);
+
+    assert_matches!(
+        unsparse(&data, true).map_err(sparse_error),
+        Err(sparse::Error::CrcMismatch(0x1d8ff4db, _))
+    );
+}
+
+#[test]
+fn write_raw_and_sparse() {
+    let data = expected_raw();
+    let mut writer =
+        RawAndSparseWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), 4096).unwrap();
+
+    // Writes that are not aligned to blocks.
+    for chunk in data.chunks(1000) {
+        writer.write_all(chunk).unwrap();
+    }
+
+    let (raw, sparse) = writer.finish().unwrap();
+    let (raw, sparse) = (raw.into_inner(), sparse.into_inner());
+    assert!(raw == data);
+    assert!(unsparse(&sparse, true).unwrap() == data);
+
+    // Raw, fill (0xdeadbeef), fill (0x00000000), and raw chunks.
+    let reader = SparseReader::new(sparse.as_slice()).unwrap();
+    assert_eq!(reader.header().num_chunks, 4);
+    assert_eq!(reader.header().image_crc32, crc32fast::hash(&data));
+    assert_eq!(sparse.len(), 28 + 2 * (12 + BLOCK_SIZE) + 2 * 16);
+}
+
+#[test]
+fn write_sparse_partial_block() {
+    let mut writer = SparseWriter::new(Cursor::new(Vec::new()), 4096).unwrap();
+    writer.write_all(b"foobar").unwrap();
+    assert_matches!(
+        writer.finish().err(),
+        Some(sparse::Error::UnalignedSize(6, 4096))
+    );
+
+    // Padding the data would make the sparse image differ from the raw image.
+    let mut data = expected_raw();
+    data.truncate(data.len() - 100);
+    let mut writer =
+        RawAndSparseWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), 4096).unwrap();
+    writer.write_all(&data).unwrap();
+    assert_matches!(
+        writer.finish().err(),
+        Some(sparse::Error::UnalignedSize(s, 4096)) if s == data.len() as u64
+    );
+
+    assert_matches!(
+        SparseWriter::new(Cursor::new(Vec::new()), 4098).err(),
+        Some(sparse::Error::InvalidFieldValue("blk_sz", 4098))
+    );
+}
+
+#[test]
+fn raw_segment() {
+    let data = expected_raw();
+    let mut merged = vec![0xffu8; data.len()];
+
+    // Flashing every segment in order writes the whole image.
+    for start in 0..5u32 {
+        let (before, after) = sparse::raw_segment(4096, 5, start, 1).unwrap();
+        let offset = start as usize * BLOCK_SIZE;
+        let mut segment = before;
+        segment.extend(&data[offset..][..BLOCK_SIZE]);
+        segment.extend(after);
+
+        let reader = SparseReader::new(segment.as_slice()).unwrap();
+        assert_eq!(reader.header().raw_size(), 5 * BLOCK_SIZE as u64);
+
+        // Don't care chunks are unsparsed as zeros.
+        let raw = unsparse(&segment, false).unwrap();
+        assert!(raw[..offset].iter().all(|b| *b == 0));
+        assert!(raw[offset + BLOCK_SIZE..].iter().all(|b| *b == 0));
+        merged[offset..][..BLOCK_SIZE].copy_from_slice(&raw[offset..][..BLOCK_SIZE]);
+    }
+
+    assert!(merged == data);
+
+    let (before, after) = sparse::raw_segment(4096, 5, 0, 5).unwrap();
+    assert_eq!(before.len() as u64 + after.len() as u64, 28 + 12);
+    assert_matches!(
+        sparse::raw_segment(4096, 5, 4, 2),
+        Err(sparse::Error::BlockCountMismatch(5, 6))
+    );
+}
@@ -0,0 +1,130 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use avbroot::{
+    format::compression::{CompressedFormat, CompressedReader, CompressedWriter},
+    stream::{ProgressWriter, SectionReader},
+};
+
+fn compress(data: &[u8], format: CompressedFormat) -> Vec<u8> {
+    let mut writer = CompressedWriter::new(Cursor::new(Vec::new()), format).unwrap();
+    writer.write_all(data).unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn section_reader_limit() {
+    let data = (0..100).collect::<Vec<u8>>();
+    let mut reader = SectionReader::new(Cursor::new(&data), 10, 20).unwrap();
+
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, &data[10..30]);
+
+    // Reads past the end of the section return EOF, even though the
+    // underlying reader has more data.
+    assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+    assert_eq!(reader.seek(SeekFrom::Start(25)).unwrap(), 25);
+    assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+
+    // Seeking is relative to the start of the section.
+    reader.seek(SeekFrom::End(-5)).unwrap();
+    let mut buf = [0u8; 10];
+    assert_eq!(reader.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], &data[25..30]);
+
+    reader.rewind().unwrap();
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, &data[10..30]);
+}
+
+#[test]
+fn section_reader_compressed() {
+    // Two adjacent compressed regions, like the data for consecutive payload
+    // operations. Format detection rewinds the reader, which must go back to
+    // the start of the section, not the start of the file.
+    let first = b"first operation".repeat(100);
+    let second = b"second operation".repeat(100);
+
+    for format in [
+        CompressedFormat::Gzip,
+        CompressedFormat::Lz4Legacy,
+        CompressedFormat::Xz,
+    ] {
+        let first_compressed = compress(&first, format);
+        let second_compressed = compress(&second, format);
+
+        let mut blob = first_compressed.clone();
+        blob.extend_from_slice(&second_compressed);
+
+        for (offset, size, expected) in [
+            (0, first_compressed.len(), &first),
+            (first_compressed.len(), second_compressed.len(), &second),
+        ] {
+            let reader =
+                SectionReader::new(Cursor::new(&blob), offset as u64, size as u64).unwrap();
+            let mut reader = CompressedReader::new(reader, false).unwrap();
+            assert_eq!(reader.format(), format);
+
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf, expected, "{format:?} at {offset}");
+        }
+    }
+}
+
+#[test]
+fn progress_writer_intervals() {
+    let mut totals = vec![];
+    let mut writer = ProgressWriter::new(Cursor::new(Vec::new()), 10, |n| totals.push(n));
+
+    for _ in 0..10 {
+        writer.write_all(b"abc").unwrap();
+    }
+    // Crosses two intervals at once.
+    writer.write_all(&[0u8; 25]).unwrap();
+
+    let (inner, total) = writer.finish();
+    assert_eq!(inner.into_inner().len(), 55);
+    assert_eq!(total, 55);
+    assert_eq!(totals, [12, 21, 30, 55]);
+}
+
+#[test]
+fn progress_writer_compressed() {
+    let data = b"progress".repeat(1000);
+    let compressed = compress(&data, CompressedFormat::Gzip);
+
+    // On top of the compressor, the totals are in uncompressed bytes.
+    let mut totals = vec![];
+    let writer = CompressedWriter::new(Cursor::new(Vec::new()), CompressedFormat::Gzip).unwrap();
+    let mut writer = ProgressWriter::new(writer, 1000, |n| totals.push(n));
+    for chunk in data.chunks(100) {
+        writer.write_all(chunk).unwrap();
+    }
+    let (writer, total) = writer.finish();
+    let raw = writer.finish().unwrap().into_inner();
+    let mut buf = vec![];
+    CompressedReader::new(Cursor::new(raw), false)
+        .unwrap()
+        .read_to_end(&mut buf)
+        .unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(total, data.len() as u64);
+    assert_eq!(totals, (1..=8).map(|i| i * 1000).collect::<Vec<_>>());
+
+    // Underneath the compressor, the totals are in compressed bytes.
+    let mut totals = vec![];
+    let writer = ProgressWriter::new(Cursor::new(Vec::new()), 1, |n| totals.push(n));
+    let mut writer = CompressedWriter::new(writer, CompressedFormat::Gzip).unwrap();
+    writer.write_all(&data).unwrap();
+    let (inner, total) = writer.finish().unwrap().finish();
+    assert_eq!(inner.into_inner(), compressed);
+    assert_eq!(total, compressed.len() as u64);
+    assert_eq!(totals.last(), Some(&total));
+}
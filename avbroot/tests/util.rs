@@ -0,0 +1,65 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+// SOURCE_DATE_EPOCH is process-wide, so these tests live in their own test
+// binary to avoid affecting other tests that run in parallel.
+
+use std::{
+    env,
+    io::ErrorKind,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use avbroot::{
+    crypto::{self, PassphraseSource},
+    util,
+};
+
+const KEY_PKCS8: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/key_pkcs8.pem",
+));
+
+#[test]
+fn source_date_epoch() {
+    let source = PassphraseSource::File("/nonexistent".into());
+    let key = crypto::read_pem_key(KEY_PKCS8, &source).unwrap();
+    let validity = Duration::from_secs(365 * 24 * 60 * 60);
+
+    env::remove_var(util::SOURCE_DATE_EPOCH_ENV);
+    assert!(!util::is_clock_fixed());
+    let now = SystemTime::now();
+    let diff = util::now().unwrap().duration_since(now).unwrap_or_default();
+    assert!(diff < Duration::from_secs(60));
+
+    // An empty value is treated as unset.
+    env::set_var(util::SOURCE_DATE_EPOCH_ENV, "");
+    assert!(!util::is_clock_fixed());
+
+    env::set_var(util::SOURCE_DATE_EPOCH_ENV, "1700000000");
+    assert!(util::is_clock_fixed());
+    assert_eq!(
+        util::now().unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+    );
+
+    // Two runs with the same epoch produce identical certificates.
+    let serial = crypto::serial_from_public_key(&key.to_public_key()).unwrap();
+    let cert = crypto::generate_cert(&key, serial, validity, "CN=avbroot test").unwrap();
+    assert_eq!(
+        crypto::generate_cert(&key, serial, validity, "CN=avbroot test").unwrap(),
+        cert,
+    );
+    assert_eq!(
+        crypto::cert_not_after(&cert),
+        1_700_000_000 + validity.as_secs()
+    );
+
+    env::set_var(util::SOURCE_DATE_EPOCH_ENV, "yesterday");
+    assert_eq!(util::now().unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(crypto::generate_cert(&key, serial, validity, "CN=avbroot test").is_err());
+
+    env::remove_var(util::SOURCE_DATE_EPOCH_ENV);
+}
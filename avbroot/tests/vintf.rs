@@ -0,0 +1,77 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::io::{Cursor, Write};
+
+use assert_matches::assert_matches;
+use avbroot::format::vintf::{self, VintfKind};
+use zip::{write::FileOptions, ZipWriter};
+
+const DEVICE_MANIFEST: &[u8] = br#"<?xml version="1.0" encoding="utf-8"?>
+<!-- Device manifest -->
+<manifest version="2.0" type="device" target-level="6">
+    <hal format="aidl">
+        <name>android.hardware.health</name>
+    </hal>
+</manifest>
+"#;
+
+fn framework_matrix(level: &str) -> Vec<u8> {
+    format!(
+        "<compatibility-matrix version=\"1.0\"\n    type=\"framework\" level=\"{level}\">\n\
+         </compatibility-matrix>\n"
+    )
+    .into_bytes()
+}
+
+fn compatibility_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for (name, data) in files {
+        writer.start_file(*name, FileOptions::default()).unwrap();
+        writer.write_all(data).unwrap();
+    }
+
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn parse_root_element() {
+    let info = vintf::parse_xml("vendor_manifest.xml", DEVICE_MANIFEST).unwrap();
+    assert_eq!(info.kind, VintfKind::Manifest);
+    assert_eq!(info.vintf_type.as_deref(), Some("device"));
+    assert_eq!(info.level.as_deref(), Some("6"));
+
+    let info = vintf::parse_xml("system_matrix.xml", &framework_matrix("7")).unwrap();
+    assert_eq!(info.kind, VintfKind::CompatibilityMatrix);
+    assert_eq!(info.vintf_type.as_deref(), Some("framework"));
+    assert_eq!(info.level.as_deref(), Some("7"));
+
+    let result = vintf::parse_xml("bogus.xml", b"<hal></hal>");
+    assert_matches!(result, Err(vintf::Error::NoRootElement(_)));
+}
+
+#[test]
+fn check_compatibility_zip() {
+    let compatible = compatibility_zip(&[
+        ("vendor_manifest.xml", DEVICE_MANIFEST),
+        ("system_matrix.6.xml", &framework_matrix("6")),
+        ("system_matrix.7.xml", &framework_matrix("7")),
+        ("README", b"not xml"),
+    ]);
+    let infos = vintf::load_compatibility_zip(Cursor::new(compatible)).unwrap();
+    assert_eq!(infos.len(), 3);
+    vintf::check_compatibility(&infos).unwrap();
+
+    let incompatible = compatibility_zip(&[
+        ("vendor_manifest.xml", DEVICE_MANIFEST),
+        ("system_matrix.7.xml", &framework_matrix("7")),
+    ]);
+    let infos = vintf::load_compatibility_zip(Cursor::new(incompatible)).unwrap();
+    assert_matches!(
+        vintf::check_compatibility(&infos),
+        Err(vintf::Error::UnsupportedTargetLevel(n, l, _)) if n == "vendor_manifest.xml" && l == "6"
+    );
+}
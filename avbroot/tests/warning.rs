@@ -0,0 +1,68 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::sync::{Arc, Mutex};
+
+use avbroot::warning::{Severity, Warning, WarningCode, WarningCollector};
+use serde_json::json;
+
+#[test]
+fn collect_warnings() {
+    let emitted = Arc::new(Mutex::new(vec![]));
+    let emitted_clone = emitted.clone();
+    let warnings =
+        WarningCollector::new(move |w| emitted_clone.lock().unwrap().push(w.to_string()));
+    assert!(warnings.is_empty());
+
+    // Clones share the same list.
+    warnings
+        .clone()
+        .emit(WarningCode::MagiskRepatched, Severity::Low, "foo");
+    warnings.emit(WarningCode::OtaCertIssue, Severity::High, "bar");
+    assert!(!warnings.is_empty());
+
+    assert_eq!(
+        warnings.warnings(),
+        [
+            Warning {
+                code: WarningCode::MagiskRepatched,
+                severity: Severity::Low,
+                message: "foo".to_owned(),
+            },
+            Warning {
+                code: WarningCode::OtaCertIssue,
+                severity: Severity::High,
+                message: "bar".to_owned(),
+            },
+        ],
+    );
+    assert_eq!(
+        *emitted.lock().unwrap(),
+        ["[magisk_repatched/low] foo", "[ota_cert_issue/high] bar"],
+    );
+}
+
+#[test]
+fn warnings_to_json() {
+    let warning = Warning {
+        code: WarningCode::PartitionImageMissing,
+        severity: Severity::Medium,
+        message: "Partition image does not exist: \"odm.img\"".to_owned(),
+    };
+    assert_eq!(
+        serde_json::to_value(&warning).unwrap(),
+        json!({
+            "code": "partition_image_missing",
+            "severity": "medium",
+            "message": "Partition image does not exist: \"odm.img\"",
+        }),
+    );
+
+    // The serialized code must match the stable string representation.
+    assert_eq!(
+        serde_json::to_value(WarningCode::UnusedIgnoreOption).unwrap(),
+        json!(WarningCode::UnusedIgnoreOption.as_str()),
+    );
+}